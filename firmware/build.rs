@@ -1,16 +1,78 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::PathBuf;
 
+// The files served by the HTTP server (see src/http.rs)
+const ASSETS: &[&str] = &["index.html"];
+
 fn main() {
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+
+    // An update is linked to run from the second bank instead of the first. The linker looks for
+    // memory.x in the working directory before the search path, so neither bank's layout can be
+    // named that in the crate's root.
+    let memory: &[u8] = match env::var_os("CARGO_FEATURE_SLOT_B").is_some() {
+        true => include_bytes!("memory-slot-b.x"),
+        false => include_bytes!("memory-slot-a.x"),
+    };
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(memory)
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
+    // Images are checked against the raw Ed25519 public key named by POE_SIGNING_KEY, if there is
+    // one (see src/signing.rs)
+    let key = match env::var_os("POE_SIGNING_KEY") {
+        Some(path) => {
+            println!("cargo:rerun-if-changed={}", PathBuf::from(&path).display());
+            fs::read(&path).expect("reading POE_SIGNING_KEY")
+        }
+        None => Vec::new(),
+    };
+    assert!(
+        key.is_empty() || key.len() == 32,
+        "POE_SIGNING_KEY must name a raw, 32-byte Ed25519 public key"
+    );
+    File::create(out.join("signing-key.pub"))
+        .unwrap()
+        .write_all(&key)
+        .unwrap();
+
+    // Each asset is embedded both as it is and gzipped, and all of them are tagged with a hash of
+    // their contents and the version, so that clients cache them until the firmware changes
+    let mut hash = fnv1a(FNV_OFFSET, env!("CARGO_PKG_VERSION").as_bytes());
+    for asset in ASSETS {
+        let path = PathBuf::from("assets").join(asset);
+        println!("cargo:rerun-if-changed={}", path.display());
+        let contents = fs::read(&path).expect("reading asset");
+        hash = fnv1a(hash, &contents);
+
+        let mut gzipped = GzEncoder::new(Vec::new(), Compression::best());
+        gzipped.write_all(&contents).unwrap();
+        fs::write(out.join(asset), &contents).unwrap();
+        fs::write(out.join(format!("{}.gz", asset)), gzipped.finish().unwrap()).unwrap();
+    }
+    println!("cargo:rustc-env=POE_ASSETS_HASH={:016x}", hash);
+
+    // defmt needs its own linker script to place the interned strings
+    if env::var_os("CARGO_FEATURE_DEFMT").is_some() {
+        println!("cargo:rustc-link-arg=-Tdefmt.x");
+    }
+
     println!("cargo:rerun-if-changed=build.rs");
-    println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=memory-slot-a.x");
+    println!("cargo:rerun-if-changed=memory-slot-b.x");
+    println!("cargo:rerun-if-env-changed=POE_SIGNING_KEY");
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
 }
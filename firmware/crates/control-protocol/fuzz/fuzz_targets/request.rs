@@ -1,4 +1,4 @@
-// Copyright 2018 Alex Crawford
+// Copyright 2023 Alex Crawford
 //
 // This program is free software: you can redistribute it and/or modify
 // it under the terms of the GNU General Public License as published by
@@ -13,9 +13,23 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::phy::Register;
+#![no_main]
 
-pub trait Mdio {
-    fn read(&self, address: u8, register: Register) -> u16;
-    fn write(&mut self, address: u8, register: Register, data: u16);
-}
+// Parses whatever a client might send to the control port, checking that the pieces of the
+// request all come from it.
+
+use control_protocol::Request;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let request = Request::parse(data);
+    request.privileged();
+
+    assert!(data.ends_with(request.argument));
+    if let Some(token) = request.token {
+        assert!(data[1..].starts_with(token.as_bytes()));
+    }
+    if let Some(command) = request.command {
+        assert!(data[..data.len() - request.argument.len()].ends_with(&[command]));
+    }
+});
@@ -0,0 +1,230 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+#![no_std]
+
+// The parts of the control port that don't depend on the network stack: how a connection moves
+// through its session, and how a request is split into its token, command, and argument. The
+// firmware maps the socket's TCP state onto a `Session` and carries out each `Step`.
+
+/// The stages of a control connection. The control port has a single socket, so it can only take
+/// a new connection once it's listening again.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Session {
+    /// Not listening, either at boot or after a connection was aborted.
+    Closed,
+    /// Waiting for a connection, which may be partway through its handshake. A half-open
+    /// handshake that's reset (e.g. by a SYN scan) returns here.
+    Listening,
+    /// Connected, and waiting for the command.
+    Open,
+    /// The client has finished sending (half-closed), with or without a command.
+    HalfClosed,
+    /// The reply has been sent, and the connection is closing.
+    Closing,
+    /// Closed, but held so that stray segments from the connection die out. The port doesn't
+    /// accept connections in the meantime (and appears closed to a scan), so this is cut short.
+    TimeWait,
+}
+
+/// What to do with the socket next.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Step {
+    /// Listen for the next connection.
+    Listen,
+    /// Leave the socket as it is.
+    Wait,
+    /// Read the command and reply to it, then close the connection.
+    Read,
+    /// Close the connection without a reply.
+    Close,
+}
+
+impl Session {
+    /// Returns the next step for a connection in this session, given whether a command can be
+    /// read from it.
+    pub fn step(self, readable: bool) -> Step {
+        match (self, readable) {
+            // In TIME-WAIT, the client has already acknowledged the reply and the close, so the
+            // port listens again right away
+            (Session::Closed, _) | (Session::TimeWait, _) => Step::Listen,
+            (Session::Listening, _) | (Session::Closing, _) => Step::Wait,
+            (Session::Open, true) | (Session::HalfClosed, true) => Step::Read,
+            // The command may not have arrived yet, in which case the connection is left open
+            // (until it's reaped), unless the client has finished sending without one
+            (Session::Open, false) => Step::Wait,
+            (Session::HalfClosed, false) => Step::Close,
+        }
+    }
+
+    /// Returns true if a client is connected (i.e. a command may yet be read).
+    pub fn connected(self) -> bool {
+        matches!(self, Session::Open | Session::HalfClosed)
+    }
+}
+
+/// A control request. Once a password is set, commands that change state need a token, which is
+/// given ahead of the command (e.g. "@<token> P").
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Request<'a> {
+    pub token: Option<&'a str>,
+    pub command: Option<u8>,
+    pub argument: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    pub fn parse(buffer: &'a [u8]) -> Request<'a> {
+        let (token, request) = match buffer.strip_prefix(b"@") {
+            Some(rest) => {
+                let end = rest.iter().position(|&b| b == b' ').unwrap_or(rest.len());
+                let token = core::str::from_utf8(&rest[..end]).ok();
+                (token, rest.get(end + 1..).unwrap_or_default())
+            }
+            None => (None, buffer),
+        };
+        let (command, argument) = match request.split_first() {
+            Some((command, argument)) => (Some(*command), argument),
+            None => (None, &[][..]),
+        };
+
+        Request {
+            token,
+            command,
+            argument,
+        }
+    }
+
+    /// Returns true if the command changes state, and so needs a token once a password is set.
+    pub fn privileged(&self) -> bool {
+        matches!(self.command, Some(b'0' | b'1' | b'P' | b'p' | b'c' | b'w'))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_command() {
+        assert_eq!(
+            Request::parse(b"s"),
+            Request {
+                token: None,
+                command: Some(b's'),
+                argument: b"",
+            }
+        );
+        assert_eq!(
+            Request::parse(b"1 sos\n"),
+            Request {
+                token: None,
+                command: Some(b'1'),
+                argument: b" sos\n",
+            }
+        );
+    }
+
+    #[test]
+    fn parse_token() {
+        assert_eq!(
+            Request::parse(b"@abc123 P"),
+            Request {
+                token: Some("abc123"),
+                command: Some(b'P'),
+                argument: b"",
+            }
+        );
+        assert_eq!(
+            Request::parse(b"@abc123 wff:ff:ff:ff:ff:ff"),
+            Request {
+                token: Some("abc123"),
+                command: Some(b'w'),
+                argument: b"ff:ff:ff:ff:ff:ff",
+            }
+        );
+    }
+
+    #[test]
+    fn parse_token_without_command() {
+        assert_eq!(
+            Request::parse(b"@abc123"),
+            Request {
+                token: Some("abc123"),
+                command: None,
+                argument: b"",
+            }
+        );
+        assert_eq!(
+            Request::parse(b"@abc123 "),
+            Request {
+                token: Some("abc123"),
+                command: None,
+                argument: b"",
+            }
+        );
+    }
+
+    #[test]
+    fn parse_invalid_token() {
+        assert_eq!(Request::parse(b"@\xFF P").token, None);
+        assert_eq!(Request::parse(b"@\xFF P").command, Some(b'P'));
+    }
+
+    #[test]
+    fn parse_empty() {
+        assert_eq!(Request::parse(b"").command, None);
+    }
+
+    #[test]
+    fn parse_any() {
+        // Every request of up to three bytes, from the bytes that matter to the parser and a few
+        // that don't
+        const BYTES: [u8; 6] = [b'@', b' ', b'P', b's', b'\n', 0xFF];
+        let mut buffer = [0; 3];
+        for len in 0..=buffer.len() {
+            for n in 0..BYTES.len().pow(len as u32) {
+                for (i, b) in buffer[..len].iter_mut().enumerate() {
+                    *b = BYTES[n / BYTES.len().pow(i as u32) % BYTES.len()];
+                }
+
+                let data = &buffer[..len];
+                let request = Request::parse(data);
+                assert!(data.ends_with(request.argument), "{:?}", data);
+                if let Some(token) = request.token {
+                    assert!(data[1..].starts_with(token.as_bytes()), "{:?}", data);
+                }
+                if let Some(command) = request.command {
+                    let rest = &data[..data.len() - request.argument.len()];
+                    assert!(rest.ends_with(&[command]), "{:?}", data);
+                }
+                if data.first() != Some(&b'@') {
+                    assert_eq!(request.token, None);
+                    assert_eq!(request.command, data.first().copied());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn privileged() {
+        for command in b"01Ppcw" {
+            assert!(Request::parse(&[*command]).privileged());
+        }
+        for command in b"aest" {
+            assert!(!Request::parse(&[*command]).privileged());
+        }
+        assert!(!Request::parse(b"").privileged());
+    }
+}
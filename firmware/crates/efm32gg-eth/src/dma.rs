@@ -26,11 +26,48 @@ macro_rules! test_status_bit_fn {
     };
 }
 
+// By default, received frames are spread across many small buffers, which makes the most of the
+// memory set aside for them. With the "rx-full-frames" feature, each buffer holds an entire frame
+// instead, so frames can be handled without being copied out of the buffers.
+#[cfg(not(feature = "rx-full-frames"))]
+pub const RX_BUFFER_SIZE: usize = 128;
+#[cfg(not(feature = "rx-full-frames"))]
+pub const RX_BUFFERS: usize = 12;
+
+#[cfg(feature = "rx-full-frames")]
+pub const RX_BUFFER_SIZE: usize = 1536;
+#[cfg(feature = "rx-full-frames")]
+pub const RX_BUFFERS: usize = 4;
+
 #[repr(align(4))]
-pub struct RxRegion(pub [u8; 1536]);
+pub struct RxRegion(pub [u8; RX_BUFFER_SIZE * RX_BUFFERS]);
+
+impl RxRegion {
+    pub const fn new() -> RxRegion {
+        RxRegion([0; RX_BUFFER_SIZE * RX_BUFFERS])
+    }
+}
+
+impl Default for RxRegion {
+    fn default() -> RxRegion {
+        RxRegion::new()
+    }
+}
+
+/// The size of each TX buffer. Frames are sent from as many as they need.
+pub const TX_BUFFER_SIZE: usize = 128;
+
 #[repr(align(4))]
 pub struct TxRegion(pub [u8; 1536]);
 
+/// How urgently a frame needs to be sent. Normal frames leave a few TX descriptors free for
+/// high-priority ones (which are small), so those never have to wait for the ring to drain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TxPriority {
+    Normal,
+    High,
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum BufferDescriptorOwnership {
     Software,
@@ -98,23 +135,17 @@ pub struct RxBuffer<'a> {
 }
 
 impl<'a> RxBuffer<'a> {
-    #[allow(clippy::identity_op, clippy::erasing_op)]
     pub fn new(
         mut region: Pin<&'a mut RxRegion>,
         mut descriptors: Pin<&'a mut RxDescriptors>,
     ) -> RxBuffer<'a> {
-        descriptors.0[0] = RxBufferDescriptor::new(&mut region.0[128 * 0..][..128]);
-        descriptors.0[1] = RxBufferDescriptor::new(&mut region.0[128 * 1..][..128]);
-        descriptors.0[2] = RxBufferDescriptor::new(&mut region.0[128 * 2..][..128]);
-        descriptors.0[3] = RxBufferDescriptor::new(&mut region.0[128 * 3..][..128]);
-        descriptors.0[4] = RxBufferDescriptor::new(&mut region.0[128 * 4..][..128]);
-        descriptors.0[5] = RxBufferDescriptor::new(&mut region.0[128 * 5..][..128]);
-        descriptors.0[6] = RxBufferDescriptor::new(&mut region.0[128 * 6..][..128]);
-        descriptors.0[7] = RxBufferDescriptor::new(&mut region.0[128 * 7..][..128]);
-        descriptors.0[8] = RxBufferDescriptor::new(&mut region.0[128 * 8..][..128]);
-        descriptors.0[9] = RxBufferDescriptor::new(&mut region.0[128 * 9..][..128]);
-        descriptors.0[10] = RxBufferDescriptor::new(&mut region.0[128 * 10..][..128]);
-        descriptors.0[11] = RxBufferDescriptor::new(&mut region.0[128 * 11..][..128]).end_of_list();
+        let buffers = region.0.chunks_exact_mut(RX_BUFFER_SIZE);
+        for (i, (d, buffer)) in descriptors.0.iter_mut().zip(buffers).enumerate() {
+            *d = match i == RX_BUFFERS - 1 {
+                true => RxBufferDescriptor::new(buffer).end_of_list(),
+                false => RxBufferDescriptor::new(buffer),
+            };
+        }
 
         RxBuffer {
             descriptors,
@@ -135,60 +166,25 @@ impl<'a> RxBuffer<'a> {
     }
 }
 
-pub struct RxDescriptors([RxBufferDescriptor; 12]);
+pub struct RxDescriptors([RxBufferDescriptor; RX_BUFFERS]);
 
 impl RxDescriptors {
     pub const fn new() -> RxDescriptors {
-        RxDescriptors([
-            RxBufferDescriptor {
-                address: UnsafeCell::new(0),
-                status: UnsafeCell::new(0),
-            },
-            RxBufferDescriptor {
-                address: UnsafeCell::new(0),
-                status: UnsafeCell::new(0),
-            },
-            RxBufferDescriptor {
-                address: UnsafeCell::new(0),
-                status: UnsafeCell::new(0),
-            },
-            RxBufferDescriptor {
-                address: UnsafeCell::new(0),
-                status: UnsafeCell::new(0),
-            },
-            RxBufferDescriptor {
-                address: UnsafeCell::new(0),
-                status: UnsafeCell::new(0),
-            },
-            RxBufferDescriptor {
-                address: UnsafeCell::new(0),
-                status: UnsafeCell::new(0),
-            },
-            RxBufferDescriptor {
-                address: UnsafeCell::new(0),
-                status: UnsafeCell::new(0),
-            },
-            RxBufferDescriptor {
-                address: UnsafeCell::new(0),
-                status: UnsafeCell::new(0),
-            },
-            RxBufferDescriptor {
-                address: UnsafeCell::new(0),
-                status: UnsafeCell::new(0),
-            },
-            RxBufferDescriptor {
-                address: UnsafeCell::new(0),
-                status: UnsafeCell::new(0),
-            },
-            RxBufferDescriptor {
-                address: UnsafeCell::new(0),
-                status: UnsafeCell::new(0),
-            },
-            RxBufferDescriptor {
-                address: UnsafeCell::new(0),
-                status: UnsafeCell::new(0),
-            },
-        ])
+        // The constant is only used to initialize the array, which gets its own copy of it for
+        // each descriptor, so none of the cells are shared
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY: RxBufferDescriptor = RxBufferDescriptor {
+            address: UnsafeCell::new(0),
+            status: UnsafeCell::new(0),
+        };
+
+        RxDescriptors([EMPTY; RX_BUFFERS])
+    }
+}
+
+impl Default for RxDescriptors {
+    fn default() -> RxDescriptors {
+        RxDescriptors::new()
     }
 }
 
@@ -259,7 +255,20 @@ impl BufferDescriptor for RxBufferDescriptor {
 
 impl RxBufferDescriptor {
     pub fn as_slice(&self) -> &[u8] {
-        unsafe { slice::from_raw_parts(self.address() as *const u8, 128) }
+        unsafe { slice::from_raw_parts(self.address() as *const u8, RX_BUFFER_SIZE) }
+    }
+
+    pub fn as_slice_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.address() as *mut u8, RX_BUFFER_SIZE) }
+    }
+
+    /// Returns the length of the received frame, which is only recorded in the descriptor of the
+    /// frame's last buffer.
+    pub fn frame_len(&self) -> Option<usize> {
+        match self.end_of_frame() {
+            true => Some((unsafe { *self.status.get() } & 0x1FFF) as usize),
+            false => None,
+        }
     }
 
     test_status_bit_fn!(pub start_of_frame, 14);
@@ -323,6 +332,10 @@ impl<'a> TxBuffer<'a> {
         }
     }
 
+    pub fn descriptors(&self) -> &[TxBufferDescriptor] {
+        &self.descriptors.0
+    }
+
     pub fn descriptors_mut(&mut self) -> &mut [TxBufferDescriptor] {
         &mut self.descriptors.0
     }
@@ -332,6 +345,27 @@ impl<'a> TxBuffer<'a> {
     }
 }
 
+/// Returns the buffers of `count` descriptors, starting at `start`, as a single slice. This is only
+/// possible if the run doesn't wrap around the end of the list and the buffers are laid out back to
+/// back, as they are by `TxBuffer::new`.
+pub fn contiguous_mut(
+    descriptors: &mut [TxBufferDescriptor],
+    start: usize,
+    count: usize,
+) -> Option<&mut [u8]> {
+    let run = descriptors.get(start..start + count)?;
+    let base = run.first()?.address();
+    if run
+        .iter()
+        .enumerate()
+        .any(|(i, d)| d.address() != base + (TX_BUFFER_SIZE * i) as u32)
+    {
+        return None;
+    }
+
+    Some(unsafe { slice::from_raw_parts_mut(base as *mut u8, TX_BUFFER_SIZE * count) })
+}
+
 pub struct TxDescriptors([TxBufferDescriptor; 12]);
 
 impl TxDescriptors {
@@ -389,6 +423,12 @@ impl TxDescriptors {
     }
 }
 
+impl Default for TxDescriptors {
+    fn default() -> TxDescriptors {
+        TxDescriptors::new()
+    }
+}
+
 #[repr(C, align(8))]
 pub struct TxBufferDescriptor {
     address: u32,
@@ -485,7 +525,7 @@ impl TxBufferDescriptor {
 
     pub fn error_checksum_generation(&self) -> Option<TxChecksumGenerationError> {
         use TxChecksumGenerationError::*;
-        match (unsafe { *self.status.get() } & (0b111 << 20)) {
+        match (unsafe { *self.status.get() } >> 20) & 0b111 {
             0b001 => Some(VlanBadHeader),
             0b010 => Some(SnapBadHeader),
             0b011 => Some(IpBadPacket),
@@ -525,3 +565,171 @@ impl TxBufferDescriptor {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The buffer addresses are kept as u32s, which truncates host pointers, so these tests only
+    // look at the descriptors and never touch the buffers through them
+
+    fn tx_descriptor(address: u32, status: u32) -> TxBufferDescriptor {
+        TxBufferDescriptor {
+            address,
+            status: UnsafeCell::new(status),
+        }
+    }
+
+    fn rx_descriptor(address: u32, status: u32) -> RxBufferDescriptor {
+        RxBufferDescriptor {
+            address: UnsafeCell::new(address),
+            status: UnsafeCell::new(status),
+        }
+    }
+
+    #[test]
+    fn rx_ring() {
+        let mut region = RxRegion::new();
+        let mut descriptors = RxDescriptors::new();
+        let buffer = RxBuffer::new(Pin::new(&mut region), Pin::new(&mut descriptors));
+
+        let descriptors = buffer.descriptors();
+        let base = descriptors[0].address();
+        for (i, d) in descriptors.iter().enumerate() {
+            assert_eq!(d.address().wrapping_sub(base), (RX_BUFFER_SIZE * i) as u32);
+            assert_eq!(d.ownership(), BufferDescriptorOwnership::Hardware);
+            let wrapping = match i == RX_BUFFERS - 1 {
+                true => BufferDescriptorListWrap::Wrap,
+                false => BufferDescriptorListWrap::NoWrap,
+            };
+            assert_eq!(d.wrapping(), wrapping, "descriptor {}", i);
+        }
+    }
+
+    #[test]
+    fn rx_release() {
+        // The hardware hands a buffer over by setting the ownership bit in the address word
+        let mut d = rx_descriptor(0x2000_0100 | 0b11, 0);
+        assert_eq!(d.ownership(), BufferDescriptorOwnership::Software);
+        assert_eq!(d.address(), 0x2000_0100);
+
+        d.release();
+        assert_eq!(d.ownership(), BufferDescriptorOwnership::Hardware);
+        assert_eq!(d.wrapping(), BufferDescriptorListWrap::Wrap);
+        assert_eq!(d.address(), 0x2000_0100);
+    }
+
+    #[test]
+    fn rx_frame() {
+        let start = rx_descriptor(0x2000_0000, 1 << 14);
+        assert!(start.start_of_frame());
+        assert!(!start.end_of_frame());
+        assert_eq!(start.frame_len(), None);
+
+        let end = rx_descriptor(0x2000_0080, 1 << 15 | 1 << 13 | 1514);
+        assert!(!end.start_of_frame());
+        assert_eq!(end.frame_len(), Some(1514));
+    }
+
+    #[test]
+    fn tx_ring() {
+        let mut region = TxRegion([0; 1536]);
+        let mut descriptors = TxDescriptors::new();
+        let buffer = TxBuffer::new(Pin::new(&mut region), Pin::new(&mut descriptors));
+
+        let descriptors = buffer.descriptors();
+        let base = descriptors[0].address();
+        for (i, d) in descriptors.iter().enumerate() {
+            assert_eq!(d.address().wrapping_sub(base), 128 * i as u32);
+            assert_eq!(d.ownership(), BufferDescriptorOwnership::Software);
+            let wrapping = match i == descriptors.len() - 1 {
+                true => BufferDescriptorListWrap::Wrap,
+                false => BufferDescriptorListWrap::NoWrap,
+            };
+            assert_eq!(d.wrapping(), wrapping, "descriptor {}", i);
+        }
+    }
+
+    #[test]
+    fn tx_release_and_claim() {
+        let mut d = tx_descriptor(0x2000_0000, 0).end_of_list();
+        d.claim();
+        assert_eq!(d.ownership(), BufferDescriptorOwnership::Software);
+
+        d.set_length(100);
+        d.set_last_buffer(true);
+        d.release();
+        assert_eq!(d.ownership(), BufferDescriptorOwnership::Hardware);
+        assert_eq!(d.wrapping(), BufferDescriptorListWrap::Wrap);
+        assert_eq!(d.length(), 100);
+        assert!(d.end_of_frame());
+
+        d.claim();
+        assert_eq!(d.ownership(), BufferDescriptorOwnership::Software);
+        assert_eq!(d.length(), 100);
+    }
+
+    #[test]
+    fn tx_length() {
+        let mut d = tx_descriptor(0x2000_0000, 0xC000_8000);
+        d.set_length(128);
+        assert_eq!(d.length(), 128);
+
+        // The length field is 14 bits wide and mustn't spill into the flags
+        d.set_length(0x4000 | 60);
+        assert_eq!(d.length(), 60);
+        assert_eq!(d.ownership(), BufferDescriptorOwnership::Software);
+        assert_eq!(d.wrapping(), BufferDescriptorListWrap::Wrap);
+        assert!(d.end_of_frame());
+
+        d.set_last_buffer(false);
+        assert!(!d.end_of_frame());
+        assert_eq!(d.length(), 60);
+    }
+
+    #[test]
+    fn tx_errors() {
+        let d = tx_descriptor(0, 0);
+        assert!(!d.error_retry_limit());
+        assert!(!d.error_tx_underrun());
+        assert!(!d.error_frame_corrupt());
+        assert!(!d.error_late_collision());
+        assert_eq!(d.error_checksum_generation(), None);
+
+        assert!(tx_descriptor(0, 1 << 29).error_retry_limit());
+        assert!(tx_descriptor(0, 1 << 28).error_tx_underrun());
+        assert!(tx_descriptor(0, 1 << 27).error_frame_corrupt());
+        assert!(tx_descriptor(0, 1 << 26).error_late_collision());
+        assert_eq!(
+            tx_descriptor(0, 0b011 << 20).error_checksum_generation(),
+            Some(TxChecksumGenerationError::IpBadPacket)
+        );
+        assert_eq!(
+            tx_descriptor(0, 0b111 << 20).error_checksum_generation(),
+            Some(TxChecksumGenerationError::EndOfPacket)
+        );
+    }
+
+    #[test]
+    fn contiguous_out_of_range() {
+        let mut descriptors = [
+            tx_descriptor(0x2000_0000, 0),
+            tx_descriptor(0x2000_0080, 0),
+            tx_descriptor(0x2000_0100, 0),
+        ];
+        assert!(contiguous_mut(&mut descriptors, 2, 2).is_none());
+        assert!(contiguous_mut(&mut descriptors, 3, 1).is_none());
+        assert!(contiguous_mut(&mut descriptors, 0, 0).is_none());
+    }
+
+    #[test]
+    fn contiguous_gap() {
+        let mut descriptors = [
+            tx_descriptor(0x2000_0000, 0),
+            tx_descriptor(0x2000_0100, 0),
+            tx_descriptor(0x2000_0080, 0),
+        ];
+        assert!(contiguous_mut(&mut descriptors, 0, 2).is_none());
+        assert!(contiguous_mut(&mut descriptors, 1, 2).is_none());
+    }
+}
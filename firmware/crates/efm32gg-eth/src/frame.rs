@@ -0,0 +1,142 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Received frames, held until they're handed over. A frame that fits in a single RX buffer is
+// left in place (and the buffer released once it's consumed); otherwise it's gathered out of the
+// buffers it spans.
+
+use crate::dma::{BufferDescriptor, RxBufferDescriptor, RX_BUFFER_SIZE};
+use core::cmp;
+
+/// The longest frame that the MAC receives or sends.
+pub const MAX_FRAME_LEN: usize = 1536;
+
+// There's no heap to box the copy into, and a frame only lives on the stack while it's handled
+#[allow(clippy::large_enum_variant)]
+pub enum RxFrame {
+    /// The frame is in the RX buffer at the index, which is released once it's consumed.
+    InPlace { index: usize, len: usize },
+    /// The frame was copied out of the RX buffers (or made by the firmware).
+    Copied {
+        data: [u8; MAX_FRAME_LEN],
+        len: usize,
+    },
+}
+
+impl RxFrame {
+    /// Takes the frame spanning the descriptors from `start` to `end` (inclusive, wrapping around
+    /// the end of the list). Unless it's left in place, the buffers are released as it's copied
+    /// out.
+    pub fn take(descriptors: &mut [RxBufferDescriptor], start: usize, end: usize) -> RxFrame {
+        let len = descriptors[end]
+            .frame_len()
+            .unwrap_or(MAX_FRAME_LEN)
+            .min(MAX_FRAME_LEN);
+
+        // When each buffer holds an entire frame, the frame is handled in place
+        if RX_BUFFER_SIZE >= MAX_FRAME_LEN {
+            return RxFrame::InPlace { index: start, len };
+        }
+
+        let mut data = [0; MAX_FRAME_LEN];
+
+        let mut orig = start;
+        let mut dest = 0;
+
+        loop {
+            let d = &mut descriptors[orig];
+            data[(dest * RX_BUFFER_SIZE)..][..RX_BUFFER_SIZE].copy_from_slice(d.as_slice());
+            d.release();
+
+            if orig == end {
+                break;
+            }
+
+            orig = (orig + 1) % descriptors.len();
+            dest += 1;
+        }
+
+        RxFrame::Copied { data, len }
+    }
+
+    /// Makes a frame that didn't come from the MAC (e.g. one the firmware made up on a neighbor's
+    /// behalf).
+    pub fn injected(frame: &[u8]) -> RxFrame {
+        let mut data = [0; MAX_FRAME_LEN];
+        data[..frame.len()].copy_from_slice(frame);
+        RxFrame::Copied {
+            data,
+            len: frame.len(),
+        }
+    }
+
+    pub fn data<'a>(&'a mut self, descriptors: &'a mut [RxBufferDescriptor]) -> &'a mut [u8] {
+        match self {
+            RxFrame::InPlace { index, len } => &mut descriptors[*index].as_slice_mut()[..*len],
+            RxFrame::Copied { data, len } => &mut data[..*len],
+        }
+    }
+
+    /// Cuts the frame short (e.g. once its VLAN tag has been removed).
+    pub fn truncated(self, new_len: usize) -> RxFrame {
+        match self {
+            RxFrame::InPlace { index, len } => RxFrame::InPlace {
+                index,
+                len: cmp::min(len, new_len),
+            },
+            RxFrame::Copied { data, len } => RxFrame::Copied {
+                data,
+                len: cmp::min(len, new_len),
+            },
+        }
+    }
+
+    /// Hands the frame's buffer back to the MAC, if it's still in one.
+    pub fn release(self, descriptors: &mut [RxBufferDescriptor]) {
+        if let RxFrame::InPlace { index, .. } = self {
+            descriptors[index].release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Copied frames never touch the descriptors, which can't be pointed at host memory (see dma)
+
+    #[test]
+    fn injected() {
+        let mut frame = RxFrame::injected(&[1, 2, 3, 4]);
+        assert_eq!(frame.data(&mut []), &[1, 2, 3, 4]);
+
+        let mut frame = frame.truncated(2);
+        assert_eq!(frame.data(&mut []), &[1, 2]);
+
+        // Frames are only ever cut short
+        let mut frame = frame.truncated(3);
+        assert_eq!(frame.data(&mut []), &[1, 2]);
+        frame.release(&mut []);
+    }
+
+    #[test]
+    fn truncated_in_place() {
+        let frame = RxFrame::InPlace { index: 1, len: 60 };
+        match frame.truncated(56) {
+            RxFrame::InPlace { index, len } => assert_eq!((index, len), (1, 56)),
+            RxFrame::Copied { .. } => panic!("frame was copied"),
+        }
+    }
+}
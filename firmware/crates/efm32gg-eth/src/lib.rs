@@ -0,0 +1,30 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+#![no_std]
+
+// Support for the EFM32GG11's Ethernet MAC that doesn't depend on the rest of the firmware: the
+// DMA descriptor rings and the buffers they point into, the frames taken out of them, and (with
+// the "smoltcp" feature) the tokens that hand those frames to and from smoltcp.
+//
+// The rest of the driver is still part of the firmware. Bringing up the MAC and servicing its
+// interrupt depend on the firmware's PTP, health, and work queue, and which frames smoltcp sees
+// (and what happens to the ones it sends) is up to the firmware's own protocol handlers, so the
+// smoltcp device itself is implemented there, on top of these tokens.
+
+pub mod dma;
+pub mod frame;
+#[cfg(feature = "smoltcp")]
+pub mod phy;
@@ -0,0 +1,187 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The tokens through which smoltcp receives and sends frames (with the "smoltcp" feature). The
+// device that hands them out stays with the driver, since it decides which received frames smoltcp
+// gets to see. Whatever else needs to happen to a frame as it's sent (e.g. tagging it, or starting
+// the MAC's transmitter) is left to the driver's `Hooks`.
+
+use crate::dma::{
+    self, BufferDescriptor, RxBufferDescriptor, TxBufferDescriptor, TxPriority, TX_BUFFER_SIZE,
+};
+use crate::frame::{RxFrame, MAX_FRAME_LEN};
+use core::cmp;
+use smoltcp::{phy, time};
+
+/// The driver's part in sending a frame. Each token is given its own hooks, so anything they
+/// depend on (e.g. whether the frame is tagged) stays the same while the frame is built.
+pub trait Hooks {
+    /// Returns the space to leave in front of the frame (e.g. for a VLAN tag).
+    fn headroom(&self) -> usize;
+
+    /// Returns the space to leave after the frame, which `finish` may grow it into.
+    fn tailroom(&self) -> usize;
+
+    /// Finishes the frame that smoltcp built after `headroom` bytes of the buffer, returning the
+    /// length of the finished frame (from the start of the buffer).
+    fn finish(self, timestamp: time::Instant, buffer: &mut [u8], len: usize) -> usize;
+
+    /// Starts the MAC transmitting the frames that have been handed over.
+    fn start_transmit();
+}
+
+pub struct RxToken<'a> {
+    /// The list of allocated RX buffer descriptors.
+    descriptors: &'a mut [RxBufferDescriptor],
+
+    /// The frame to be handed over.
+    frame: RxFrame,
+}
+
+impl<'a> RxToken<'a> {
+    pub fn new(descriptors: &'a mut [RxBufferDescriptor], frame: RxFrame) -> RxToken<'a> {
+        RxToken { descriptors, frame }
+    }
+}
+
+impl<'a> phy::RxToken for RxToken<'a> {
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let result = f(self.frame.data(self.descriptors));
+        self.frame.release(self.descriptors);
+        result
+    }
+}
+
+pub struct TxToken<'a, H: Hooks> {
+    /// The list of allocated TX buffer descriptors.
+    descriptors: &'a mut [TxBufferDescriptor],
+
+    /// The number of descriptors queued for transmission, which the token adds to.
+    pending: &'a mut usize,
+
+    /// The priority of the frame, which determined the size of the window.
+    priority: TxPriority,
+
+    /// The index of the starting TX buffer descriptor.
+    start: usize,
+
+    /// The length of the token, in TX buffers.
+    length: usize,
+
+    /// The time at which the token was made.
+    timestamp: time::Instant,
+
+    /// The driver's part in sending the frame.
+    hooks: H,
+}
+
+impl<'a, H: Hooks> TxToken<'a, H> {
+    /// Makes a token for the window of `length` free descriptors, starting at `start`.
+    pub fn new(
+        descriptors: &'a mut [TxBufferDescriptor],
+        pending: &'a mut usize,
+        priority: TxPriority,
+        (start, length): (usize, usize),
+        timestamp: time::Instant,
+        hooks: H,
+    ) -> TxToken<'a, H> {
+        TxToken {
+            descriptors,
+            pending,
+            priority,
+            start,
+            length,
+            timestamp,
+            hooks,
+        }
+    }
+
+    /// Returns the space available to the frame, in bytes.
+    pub fn capacity(&self) -> usize {
+        self.length * TX_BUFFER_SIZE
+    }
+}
+
+impl<'a, H: Hooks> phy::TxToken for TxToken<'a, H> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let capacity = self.capacity();
+        let timestamp = self.timestamp;
+        let hooks = self.hooks;
+        let headroom = hooks.headroom();
+        if len + headroom > capacity {
+            log::warn!(
+                "TX exhausted ({:?}): buffer={} token={}",
+                self.priority,
+                len,
+                capacity
+            );
+
+            // The frame has to be built all the same, so it's built on the stack and dropped
+            let mut data = [0; MAX_FRAME_LEN];
+            return f(&mut data[..len]);
+        }
+
+        debug_assert!(len > 0);
+        // Room is left for the frame to grow, if the token (and the stack buffer below) allow
+        let room = cmp::min(
+            len + headroom + hooks.tailroom(),
+            cmp::min(capacity, MAX_FRAME_LEN),
+        );
+        let buffers = (room - 1) / TX_BUFFER_SIZE + 1;
+
+        let build = |buffer: &mut [u8]| -> (R, usize) {
+            let buffer = &mut buffer[..buffers * TX_BUFFER_SIZE];
+            let result = f(&mut buffer[headroom..][..len]);
+            (result, hooks.finish(timestamp, buffer, len))
+        };
+
+        // Unless the window wraps around the end of the ring, the frame can be built in place.
+        // Otherwise, it's built on the stack and then copied into the buffers.
+        let (result, len) = match dma::contiguous_mut(&mut *self.descriptors, self.start, buffers) {
+            Some(buffer) => build(buffer),
+            None => {
+                let mut data = [0; MAX_FRAME_LEN];
+                let (result, len) = build(&mut data);
+                for i in 0..buffers {
+                    let d = &mut self.descriptors[(self.start + i) % self.descriptors.len()];
+                    d.as_slice_mut()
+                        .copy_from_slice(&data[(i * TX_BUFFER_SIZE)..][..TX_BUFFER_SIZE]);
+                }
+                (result, len)
+            }
+        };
+        let last_buffer = (len - 1) / TX_BUFFER_SIZE;
+
+        for i in 0..=last_buffer {
+            let d = &mut self.descriptors[(self.start + i) % self.descriptors.len()];
+            let buffer_len = cmp::min(TX_BUFFER_SIZE, len - i * TX_BUFFER_SIZE);
+
+            d.set_length(buffer_len);
+            d.set_last_buffer(i == last_buffer);
+            d.release();
+        }
+        *self.pending += last_buffer + 1;
+
+        H::start_transmit();
+
+        result
+    }
+}
@@ -0,0 +1,447 @@
+// Copyright (C) 2018 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+#![no_std]
+
+// The interface between an Ethernet MAC and its PHY: the clause 22 management registers (IEEE
+// 802.3) and the operations that a MAC driver needs from a PHY driver.
+//
+// XXX: Figure out error handling
+
+use core::fmt;
+use core::str::FromStr;
+
+/// Access to the PHYs on a management (MDIO) bus.
+pub trait Mdio {
+    fn read(&self, address: u8, register: Register) -> u16;
+    fn write(&mut self, address: u8, register: Register, data: u16);
+}
+
+pub trait Phy {
+    fn oui(&self, mac: &dyn Mdio) -> Oui;
+    fn link_state(&self, mac: &dyn Mdio) -> Option<LinkState>;
+    /// Forces the speed and duplex of the link, or autonegotiates them if `state` is `None`.
+    fn set_link_state(&mut self, mac: &mut dyn Mdio, state: Option<LinkState>);
+    fn set_mdix(&mut self, mac: &mut dyn Mdio, mdix: Mdix);
+    /// Handles an interrupt from the PHY, passing each fault that it reports to `faults`.
+    fn irq(&mut self, mac: &mut dyn Mdio, faults: &mut dyn FnMut(Fault));
+    fn set_loopback(&mut self, mac: &mut dyn Mdio, enabled: bool);
+    fn set_eee(&mut self, mac: &mut dyn Mdio, enabled: bool);
+    fn eee_negotiated(&self, mac: &mut dyn Mdio) -> bool;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinkState {
+    pub speed: LinkSpeed,
+    pub duplex: LinkDuplex,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkSpeed {
+    TenMbps,
+    HundredMbps,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkDuplex {
+    HalfDuplex,
+    FullDuplex,
+}
+
+impl fmt::Display for LinkState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.speed, self.duplex) {
+            (LinkSpeed::TenMbps, LinkDuplex::HalfDuplex) => f.pad("10-half"),
+            (LinkSpeed::TenMbps, LinkDuplex::FullDuplex) => f.pad("10-full"),
+            (LinkSpeed::HundredMbps, LinkDuplex::HalfDuplex) => f.pad("100-half"),
+            (LinkSpeed::HundredMbps, LinkDuplex::FullDuplex) => f.pad("100-full"),
+        }
+    }
+}
+
+impl FromStr for LinkState {
+    type Err = &'static str;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        let (speed, duplex) = match mode {
+            "10-half" => (LinkSpeed::TenMbps, LinkDuplex::HalfDuplex),
+            "10-full" => (LinkSpeed::TenMbps, LinkDuplex::FullDuplex),
+            "100-half" => (LinkSpeed::HundredMbps, LinkDuplex::HalfDuplex),
+            "100-full" => (LinkSpeed::HundredMbps, LinkDuplex::FullDuplex),
+            _ => return Err("mode must be 10-half, 10-full, 100-half, or 100-full"),
+        };
+        Ok(LinkState { speed, duplex })
+    }
+}
+
+/// A fault on the link, as reported by the PHY.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    /// The PHY transmitted for longer than the longest legal frame.
+    Jabber,
+    /// The PHY received an invalid symbol.
+    ReceiveError,
+    /// Autonegotiation failed and the link partner's mode couldn't be detected either.
+    ParallelDetect,
+    /// The link partner reported a fault on its end of the link.
+    RemoteFault,
+}
+
+impl Fault {
+    pub const ALL: [Fault; 4] = [
+        Fault::Jabber,
+        Fault::ReceiveError,
+        Fault::ParallelDetect,
+        Fault::RemoteFault,
+    ];
+
+    /// The number of times within a minute that the fault may occur before it's worth recording
+    /// as an event.
+    pub fn threshold(&self) -> u32 {
+        match self {
+            Fault::ReceiveError => 100,
+            Fault::Jabber | Fault::ParallelDetect | Fault::RemoteFault => 1,
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        match self {
+            Fault::Jabber => 0,
+            Fault::ReceiveError => 1,
+            Fault::ParallelDetect => 2,
+            Fault::RemoteFault => 3,
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Fault::Jabber => "jabber",
+            Fault::ReceiveError => "receive error",
+            Fault::ParallelDetect => "parallel detect fault",
+            Fault::RemoteFault => "remote fault",
+        })
+    }
+}
+
+/// The wiring of the link's pairs, which is normally detected automatically.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mdix {
+    Auto,
+    /// Transmit on pins 1 and 2, as a host does.
+    Mdi,
+    /// Transmit on pins 3 and 6, as a switch does.
+    MdiX,
+}
+
+impl fmt::Display for Mdix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mdix::Auto => f.pad("auto"),
+            Mdix::Mdi => f.pad("MDI"),
+            Mdix::MdiX => f.pad("MDI-X"),
+        }
+    }
+}
+
+impl FromStr for Mdix {
+    type Err = &'static str;
+
+    fn from_str(mdix: &str) -> Result<Self, Self::Err> {
+        match mdix {
+            "auto" => Ok(Mdix::Auto),
+            "mdi" => Ok(Mdix::Mdi),
+            "mdix" => Ok(Mdix::MdiX),
+            _ => Err("wiring must be auto, mdi, or mdix"),
+        }
+    }
+}
+
+pub struct Oui(pub [u8; 3]);
+
+impl fmt::Display for Oui {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02X}-{:02X}-{:02X}", self.0[0], self.0[1], self.0[2])
+    }
+}
+
+#[derive(Debug)]
+pub enum Register {
+    BasicControl,
+    BasicStatus,
+    PhyId1,
+    PhyId2,
+    AutoAdvertisement,
+    AutoPartnerAbility,
+    AutoExpansion,
+    AutoNextPage,
+    AutoPartnerNextPageAbility,
+    MmdControl,
+    MmdRegisterData,
+    Vendor(u8),
+}
+
+impl From<Register> for u8 {
+    fn from(register: Register) -> u8 {
+        match register {
+            Register::BasicControl => 0x00,
+            Register::BasicStatus => 0x01,
+            Register::PhyId1 => 0x02,
+            Register::PhyId2 => 0x03,
+            Register::AutoAdvertisement => 0x04,
+            Register::AutoPartnerAbility => 0x05,
+            Register::AutoExpansion => 0x06,
+            Register::AutoNextPage => 0x07,
+            Register::AutoPartnerNextPageAbility => 0x08,
+            Register::MmdControl => 0x0D,
+            Register::MmdRegisterData => 0x0E,
+            Register::Vendor(addr) => addr,
+        }
+    }
+}
+
+impl From<u8> for Register {
+    fn from(addr: u8) -> Register {
+        match addr {
+            0x00 => Register::BasicControl,
+            0x01 => Register::BasicStatus,
+            0x02 => Register::PhyId1,
+            0x03 => Register::PhyId2,
+            0x04 => Register::AutoAdvertisement,
+            0x05 => Register::AutoPartnerAbility,
+            0x06 => Register::AutoExpansion,
+            0x07 => Register::AutoNextPage,
+            0x08 => Register::AutoPartnerNextPageAbility,
+            0x0D => Register::MmdControl,
+            0x0E => Register::MmdRegisterData,
+            addr => Register::Vendor(addr),
+        }
+    }
+}
+
+// Selects data (rather than an address) in the MMD access control register, without incrementing
+// the address after each access
+const MMD_FUNCTION_DATA: u16 = 0b01 << 14;
+
+/// Reads a register of one of the PHY's MDIO manageable devices (MMDs), through the clause 22
+/// access registers (IEEE 802.3, annex 22D).
+pub fn mmd_read(mdio: &mut dyn Mdio, address: u8, device: u8, register: u16) -> u16 {
+    select_mmd(mdio, address, device, register);
+    mdio.read(address, Register::MmdRegisterData)
+}
+
+/// Writes a register of one of the PHY's MMDs (see `mmd_read`).
+pub fn mmd_write(mdio: &mut dyn Mdio, address: u8, device: u8, register: u16, data: u16) {
+    select_mmd(mdio, address, device, register);
+    mdio.write(address, Register::MmdRegisterData, data)
+}
+
+fn select_mmd(mdio: &mut dyn Mdio, address: u8, device: u8, register: u16) {
+    mdio.write(address, Register::MmdControl, u16::from(device));
+    mdio.write(address, Register::MmdRegisterData, register);
+    mdio.write(
+        address,
+        Register::MmdControl,
+        MMD_FUNCTION_DATA | u16::from(device),
+    );
+}
+
+/// Returns the lowest address at which a PHY answers. A PHY is only considered present if both of
+/// its identifier registers hold plausible values.
+pub fn probe_addr<M: Mdio>(mdio: &M) -> Option<u8> {
+    (0..32).find(|addr| {
+        plausible_id(mdio.read(*addr, Register::PhyId1))
+            && plausible_id(mdio.read(*addr, Register::PhyId2))
+    })
+}
+
+// Nothing drives the bus at an address without a PHY, so reads return all ones (from the pull-up)
+// or all zeros (without one). Some MACs also lose the first two bits of the turnaround, turning
+// all ones into 0x3FFF.
+fn plausible_id(id: u16) -> bool {
+    !matches!(id, 0x0000 | 0x3FFF | 0xFFFF)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use core::cell::RefCell;
+    use std::format;
+    use std::string::ToString;
+    use std::vec::Vec;
+
+    // A bus with a PHY at each address that has identifiers, which reads back `idle` everywhere
+    // else, and logs every write
+    struct MockMdio {
+        phys: Vec<(u8, u16, u16)>,
+        idle: u16,
+        reads: RefCell<Vec<u8>>,
+        writes: Vec<(u8, u8, u16)>,
+    }
+
+    impl MockMdio {
+        fn new(idle: u16, phys: &[(u8, u16, u16)]) -> MockMdio {
+            MockMdio {
+                phys: phys.to_vec(),
+                idle,
+                reads: RefCell::new(Vec::new()),
+                writes: Vec::new(),
+            }
+        }
+    }
+
+    impl Mdio for MockMdio {
+        fn read(&self, address: u8, register: Register) -> u16 {
+            self.reads.borrow_mut().push(address);
+            let phy = self.phys.iter().find(|(addr, _, _)| *addr == address);
+            match (phy, u8::from(register)) {
+                (Some((_, id1, _)), 0x02) => *id1,
+                (Some((_, _, id2)), 0x03) => *id2,
+                (Some(_), _) => 0,
+                (None, _) => self.idle,
+            }
+        }
+
+        fn write(&mut self, address: u8, register: Register, data: u16) {
+            self.writes.push((address, u8::from(register), data));
+        }
+    }
+
+    // The KSZ8091's identifiers
+    const ID1: u16 = 0x0022;
+    const ID2: u16 = 0x1560;
+
+    #[test]
+    fn probe_absent() {
+        for idle in [0x0000, 0x3FFF, 0xFFFF] {
+            let mdio = MockMdio::new(idle, &[]);
+            assert_eq!(probe_addr(&mdio), None, "idle {:#06X}", idle);
+            assert_eq!(*mdio.reads.borrow(), (0..32).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn probe_finds_odd_address() {
+        let mdio = MockMdio::new(0xFFFF, &[(3, ID1, ID2)]);
+        assert_eq!(probe_addr(&mdio), Some(3));
+    }
+
+    #[test]
+    fn probe_finds_lowest_address() {
+        let mdio = MockMdio::new(0xFFFF, &[(17, ID1, ID2), (5, ID1, ID2)]);
+        assert_eq!(probe_addr(&mdio), Some(5));
+    }
+
+    #[test]
+    fn probe_finds_address_zero() {
+        let mdio = MockMdio::new(0x0000, &[(0, ID1, ID2)]);
+        assert_eq!(probe_addr(&mdio), Some(0));
+    }
+
+    #[test]
+    fn probe_requires_both_ids() {
+        for (id1, id2) in [
+            (0xFFFF, ID2),
+            (ID1, 0xFFFF),
+            (0x0000, ID2),
+            (ID1, 0x0000),
+            (0x3FFF, ID2),
+            (ID1, 0x3FFF),
+        ] {
+            let mdio = MockMdio::new(0xFFFF, &[(1, id1, id2), (9, ID1, ID2)]);
+            assert_eq!(probe_addr(&mdio), Some(9), "{:#06X} {:#06X}", id1, id2);
+        }
+    }
+
+    #[test]
+    fn plausible_ids() {
+        assert!(!plausible_id(0x0000));
+        assert!(!plausible_id(0x3FFF));
+        assert!(!plausible_id(0xFFFF));
+        assert!(plausible_id(ID1));
+        assert!(plausible_id(ID2));
+        assert!(plausible_id(0x7FFF));
+    }
+
+    #[test]
+    fn mmd_access() {
+        let mut mdio = MockMdio::new(0xFFFF, &[(1, ID1, ID2)]);
+        mmd_write(&mut mdio, 1, 0x07, 0x3C, 0x0006);
+        assert_eq!(
+            mdio.writes,
+            [
+                (1, 0x0D, 0x0007),
+                (1, 0x0E, 0x003C),
+                (1, 0x0D, 0x4007),
+                (1, 0x0E, 0x0006),
+            ]
+        );
+    }
+
+    #[test]
+    fn link_state_round_trip() {
+        for mode in ["10-half", "10-full", "100-half", "100-full"] {
+            let state = mode.parse::<LinkState>().unwrap();
+            assert_eq!(state.to_string(), mode);
+        }
+        assert_eq!(
+            "100-full".parse(),
+            Ok(LinkState {
+                speed: LinkSpeed::HundredMbps,
+                duplex: LinkDuplex::FullDuplex,
+            })
+        );
+    }
+
+    #[test]
+    fn link_state_invalid() {
+        for mode in ["", "100", "100-Full", "1000-full", " 10-half"] {
+            assert!(mode.parse::<LinkState>().is_err(), "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn mdix() {
+        assert_eq!("auto".parse(), Ok(Mdix::Auto));
+        assert_eq!("mdi".parse(), Ok(Mdix::Mdi));
+        assert_eq!("mdix".parse(), Ok(Mdix::MdiX));
+        assert!("MDI-X".parse::<Mdix>().is_err());
+
+        assert_eq!(Mdix::Mdi.to_string(), "MDI");
+        assert_eq!(Mdix::MdiX.to_string(), "MDI-X");
+        assert_eq!(format!("{:>6}", Mdix::Auto), "  auto");
+    }
+
+    #[test]
+    fn register_round_trip() {
+        for addr in 0..32 {
+            assert_eq!(u8::from(Register::from(addr)), addr);
+        }
+        assert!(matches!(Register::from(0x1F), Register::Vendor(0x1F)));
+        assert!(matches!(Register::from(0x0D), Register::MmdControl));
+    }
+
+    #[test]
+    fn faults() {
+        for (i, fault) in Fault::ALL.iter().enumerate() {
+            assert_eq!(fault.index(), i);
+            assert!(fault.threshold() > 0);
+        }
+        assert_eq!(Oui([0x00, 0x08, 0x5C]).to_string(), "00-08-5C");
+    }
+}
@@ -0,0 +1,432 @@
+// Copyright 2018 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+#![no_std]
+
+// A driver for the Microchip KSZ8091 10BASE-T/100BASE-TX PHY.
+
+pub mod registers;
+
+use ethernet_phy::{Fault, LinkDuplex, LinkSpeed, LinkState, Mdio, Mdix, Oui, Phy, Register};
+
+// The EEE registers are in the autonegotiation MMD
+const MMD_AUTONEGOTIATION: u8 = 7;
+const EEE_ADVERTISEMENT: u16 = 0x3C;
+const EEE_PARTNER_ABILITY: u16 = 0x3D;
+const EEE_100BASE_TX: u16 = 1 << 1;
+
+const PHY_CONTROL_2: u8 = 0x1F;
+const PHY_CONTROL_2_MDIX_DISABLE: u16 = 1 << 13;
+const PHY_CONTROL_2_MDI_SELECT: u16 = 1 << 14;
+
+pub struct KSZ8091 {
+    address: u8,
+    forced: Option<LinkState>,
+}
+
+impl KSZ8091 {
+    pub fn new(address: u8, mdio: &mut dyn Mdio) -> KSZ8091 {
+        // Enable interrupts for link-up and link-down, and for the faults that are counted (jabber,
+        // receive error, parallel detect fault, and remote fault)
+        mdio.write(
+            address,
+            Register::Vendor(registers::INTERRUPT_CONTROL),
+            0xD700,
+        );
+
+        KSZ8091 {
+            address,
+            forced: None,
+        }
+    }
+
+    // Returns the basic control register's value for normal operation: either the forced speed
+    // and duplex, or autonegotiation (restarted, so that any changes take effect)
+    fn control(&self) -> u16 {
+        match self.forced {
+            Some(LinkState { speed, duplex }) => {
+                let speed = match speed {
+                    LinkSpeed::TenMbps => 0,
+                    LinkSpeed::HundredMbps => 1 << 13,
+                };
+                let duplex = match duplex {
+                    LinkDuplex::HalfDuplex => 0,
+                    LinkDuplex::FullDuplex => 1 << 8,
+                };
+                speed | duplex
+            }
+            None => 1 << 12 | 1 << 9,
+        }
+    }
+}
+
+impl Phy for KSZ8091 {
+    fn oui(&self, mdio: &dyn Mdio) -> Oui {
+        // Bits [2:17] of the Oui are in bits [15:0] of PHY ID 1.
+        // Bits [18:23] of the Oui are in bits [15:10] of PHY ID 2.
+        // Concatenating these two gives the Oui in bit-reverse order
+        // (e.g. 0b00 [2:17] [18:23] 0000 0000).
+        let id1 = u32::from(mdio.read(self.address, Register::PhyId1));
+        let id2 = u32::from(mdio.read(self.address, Register::PhyId2));
+
+        let oui = u32::reverse_bits(id1 << 14 | id2 >> 2);
+        Oui([(oui as u8), ((oui >> 8) as u8), ((oui >> 16) as u8)])
+    }
+
+    fn link_state(&self, mdio: &dyn Mdio) -> Option<LinkState> {
+        let phy_ctrl1 = mdio.read(self.address, Register::Vendor(0x1E));
+
+        match phy_ctrl1 & 0b111 {
+            0b000 => None,
+            0b001 => Some(LinkState {
+                speed: LinkSpeed::TenMbps,
+                duplex: LinkDuplex::HalfDuplex,
+            }),
+            0b010 => Some(LinkState {
+                speed: LinkSpeed::HundredMbps,
+                duplex: LinkDuplex::HalfDuplex,
+            }),
+            0b101 => Some(LinkState {
+                speed: LinkSpeed::TenMbps,
+                duplex: LinkDuplex::FullDuplex,
+            }),
+            0b110 => Some(LinkState {
+                speed: LinkSpeed::HundredMbps,
+                duplex: LinkDuplex::FullDuplex,
+            }),
+            _ => None,
+        }
+    }
+
+    fn set_link_state(&mut self, mdio: &mut dyn Mdio, state: Option<LinkState>) {
+        self.forced = state;
+        mdio.write(self.address, Register::BasicControl, self.control());
+    }
+
+    fn set_mdix(&mut self, mdio: &mut dyn Mdio, mdix: Mdix) {
+        let control = mdio.read(self.address, Register::Vendor(PHY_CONTROL_2))
+            & !(PHY_CONTROL_2_MDIX_DISABLE | PHY_CONTROL_2_MDI_SELECT);
+        let control = match mdix {
+            Mdix::Auto => control,
+            Mdix::Mdi => control | PHY_CONTROL_2_MDIX_DISABLE | PHY_CONTROL_2_MDI_SELECT,
+            Mdix::MdiX => control | PHY_CONTROL_2_MDIX_DISABLE,
+        };
+        mdio.write(self.address, Register::Vendor(PHY_CONTROL_2), control);
+    }
+
+    fn set_loopback(&mut self, mdio: &mut dyn Mdio, enabled: bool) {
+        // Loopback only works at a fixed speed, so autonegotiation is turned off while it's
+        // enabled
+        let control = match enabled {
+            true => 1 << 14 | 1 << 13 | 1 << 8,
+            false => self.control(),
+        };
+        mdio.write(self.address, Register::BasicControl, control);
+    }
+
+    fn set_eee(&mut self, mdio: &mut dyn Mdio, enabled: bool) {
+        let advertisement = match enabled {
+            true => EEE_100BASE_TX,
+            false => 0,
+        };
+        ethernet_phy::mmd_write(
+            mdio,
+            self.address,
+            MMD_AUTONEGOTIATION,
+            EEE_ADVERTISEMENT,
+            advertisement,
+        );
+
+        // The advertisement only takes effect once autonegotiation is restarted
+        mdio.write(self.address, Register::BasicControl, self.control());
+    }
+
+    fn eee_negotiated(&self, mdio: &mut dyn Mdio) -> bool {
+        let advertised =
+            ethernet_phy::mmd_read(mdio, self.address, MMD_AUTONEGOTIATION, EEE_ADVERTISEMENT);
+        let partner =
+            ethernet_phy::mmd_read(mdio, self.address, MMD_AUTONEGOTIATION, EEE_PARTNER_ABILITY);
+        advertised & partner & EEE_100BASE_TX != 0
+    }
+
+    fn irq(&mut self, mdio: &mut dyn Mdio, faults: &mut dyn FnMut(Fault)) {
+        let status = mdio.read(self.address, Register::Vendor(registers::INTERRUPT_CONTROL)) as u8;
+
+        macro_rules! bit_str {
+            ($pos:literal, $str:expr) => {
+                match status & (1 << $pos) {
+                    0 => "",
+                    _ => $str,
+                }
+            };
+        }
+
+        log::trace!(
+            "PHY IRQ:{}{}{}{}{}{}{}{}",
+            bit_str!(7, " jabber"),
+            bit_str!(6, " receive-error"),
+            bit_str!(5, " page-received"),
+            bit_str!(4, " parallel-detect-fault"),
+            bit_str!(3, " link-partner-ack"),
+            bit_str!(2, " link-down"),
+            bit_str!(1, " remote-fault"),
+            bit_str!(0, " link-up"),
+        );
+
+        [
+            (7, Fault::Jabber),
+            (6, Fault::ReceiveError),
+            (4, Fault::ParallelDetect),
+            (1, Fault::RemoteFault),
+        ]
+        .iter()
+        .filter(|(pos, _)| status & (1 << pos) != 0)
+        .for_each(|(_, fault)| faults(*fault));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use core::cell::RefCell;
+    use std::string::ToString;
+    use std::vec::Vec;
+
+    const ADDRESS: u8 = 1;
+
+    // A KSZ8091 at ADDRESS, with its registers (and those of its MMDs) held in memory
+    struct MockMdio {
+        registers: RefCell<[u16; 32]>,
+        mmd: Vec<(u8, u16, u16)>,
+        // The MMD register selected through the access registers (see ethernet_phy::mmd_read)
+        mmd_register: u16,
+        writes: Vec<(u8, u16)>,
+    }
+
+    impl MockMdio {
+        fn new() -> MockMdio {
+            let mut registers = [0; 32];
+            registers[0x02] = 0x0022;
+            registers[0x03] = 0x1560;
+            MockMdio {
+                registers: RefCell::new(registers),
+                mmd: Vec::new(),
+                mmd_register: 0,
+                writes: Vec::new(),
+            }
+        }
+
+        fn set(&self, register: u8, value: u16) {
+            self.registers.borrow_mut()[usize::from(register)] = value;
+        }
+
+        fn get(&self, register: u8) -> u16 {
+            self.registers.borrow()[usize::from(register)]
+        }
+
+        fn set_mmd(&mut self, device: u8, register: u16, value: u16) {
+            self.mmd.retain(|(d, r, _)| (*d, *r) != (device, register));
+            self.mmd.push((device, register, value));
+        }
+
+        fn selected_mmd(&self) -> (u8, u16) {
+            ((self.get(0x0D) & 0x1F) as u8, self.mmd_register)
+        }
+    }
+
+    impl Mdio for MockMdio {
+        fn read(&self, address: u8, register: Register) -> u16 {
+            assert_eq!(address, ADDRESS);
+            match u8::from(register) {
+                0x0E => {
+                    let (device, register) = self.selected_mmd();
+                    self.mmd
+                        .iter()
+                        .find(|(d, r, _)| (*d, *r) == (device, register))
+                        .map_or(0, |(_, _, value)| *value)
+                }
+                register => self.get(register),
+            }
+        }
+
+        fn write(&mut self, address: u8, register: Register, data: u16) {
+            assert_eq!(address, ADDRESS);
+            let register = u8::from(register);
+            self.writes.push((register, data));
+            match (register, self.get(0x0D) >> 14) {
+                (0x0E, 0b00) => self.mmd_register = data,
+                (0x0E, _) => {
+                    let (device, register) = self.selected_mmd();
+                    self.set_mmd(device, register, data);
+                }
+                (register, _) => self.set(register, data),
+            }
+        }
+    }
+
+    fn phy(mdio: &mut MockMdio) -> KSZ8091 {
+        let phy = KSZ8091::new(ADDRESS, mdio);
+        mdio.writes.clear();
+        phy
+    }
+
+    #[test]
+    fn enables_interrupts() {
+        let mut mdio = MockMdio::new();
+        KSZ8091::new(ADDRESS, &mut mdio);
+        assert_eq!(mdio.writes, [(registers::INTERRUPT_CONTROL, 0xD700)]);
+    }
+
+    #[test]
+    fn oui() {
+        let mut mdio = MockMdio::new();
+        let phy = phy(&mut mdio);
+        assert_eq!(phy.oui(&mdio).to_string(), "00-10-A1");
+    }
+
+    #[test]
+    fn link_state() {
+        let mut mdio = MockMdio::new();
+        let phy = phy(&mut mdio);
+        for (mode, state) in [
+            (0b000, None),
+            (0b001, Some("10-half")),
+            (0b010, Some("100-half")),
+            (0b101, Some("10-full")),
+            (0b110, Some("100-full")),
+            (0b111, None),
+        ] {
+            // The other bits of the register (e.g. link and MDI-X) are ignored
+            mdio.set(0x1E, 0x0120 | mode);
+            assert_eq!(
+                phy.link_state(&mdio),
+                state.map(|state| state.parse().unwrap()),
+                "mode {:#05b}",
+                mode
+            );
+        }
+    }
+
+    #[test]
+    fn set_link_state() {
+        let mut mdio = MockMdio::new();
+        let mut phy = phy(&mut mdio);
+        phy.set_link_state(&mut mdio, Some("100-full".parse().unwrap()));
+        phy.set_link_state(&mut mdio, Some("10-half".parse().unwrap()));
+        phy.set_link_state(&mut mdio, None);
+        assert_eq!(
+            mdio.writes,
+            [(0x00, 0x2100), (0x00, 0x0000), (0x00, 0x1200)]
+        );
+    }
+
+    #[test]
+    fn loopback_restores_forced_state() {
+        let mut mdio = MockMdio::new();
+        let mut phy = phy(&mut mdio);
+        phy.set_link_state(&mut mdio, Some("10-full".parse().unwrap()));
+        phy.set_loopback(&mut mdio, true);
+        assert_eq!(mdio.get(0x00), 0x6100);
+        phy.set_loopback(&mut mdio, false);
+        assert_eq!(mdio.get(0x00), 0x0100);
+    }
+
+    #[test]
+    fn set_mdix() {
+        let mut mdio = MockMdio::new();
+        let mut phy = phy(&mut mdio);
+        // The other bits (HP Auto MDI/MDI-X and the LED mode) are left as they are
+        mdio.set(PHY_CONTROL_2, 0x8010);
+        phy.set_mdix(&mut mdio, Mdix::Mdi);
+        assert_eq!(mdio.get(PHY_CONTROL_2), 0xE010);
+        phy.set_mdix(&mut mdio, Mdix::MdiX);
+        assert_eq!(mdio.get(PHY_CONTROL_2), 0xA010);
+        phy.set_mdix(&mut mdio, Mdix::Auto);
+        assert_eq!(mdio.get(PHY_CONTROL_2), 0x8010);
+    }
+
+    #[test]
+    fn eee() {
+        let mut mdio = MockMdio::new();
+        let mut phy = phy(&mut mdio);
+        mdio.set_mmd(MMD_AUTONEGOTIATION, EEE_PARTNER_ABILITY, EEE_100BASE_TX);
+        assert!(!phy.eee_negotiated(&mut mdio));
+
+        phy.set_eee(&mut mdio, true);
+        assert!(phy.eee_negotiated(&mut mdio));
+        // Autonegotiation is restarted for the advertisement to take effect
+        assert_eq!(mdio.get(0x00), 0x1200);
+
+        mdio.set_mmd(MMD_AUTONEGOTIATION, EEE_PARTNER_ABILITY, 0);
+        assert!(!phy.eee_negotiated(&mut mdio));
+
+        phy.set_eee(&mut mdio, false);
+        mdio.set_mmd(MMD_AUTONEGOTIATION, EEE_PARTNER_ABILITY, EEE_100BASE_TX);
+        assert!(!phy.eee_negotiated(&mut mdio));
+    }
+
+    #[test]
+    fn irq_faults() {
+        let mut mdio = MockMdio::new();
+        let mut phy = phy(&mut mdio);
+        let mut faults = Vec::new();
+
+        // Link-up, page-received, and link-partner-ack aren't faults
+        mdio.set(registers::INTERRUPT_CONTROL, 0xD700 | 0b0010_1001);
+        phy.irq(&mut mdio, &mut |fault| faults.push(fault));
+        assert!(faults.is_empty());
+
+        mdio.set(registers::INTERRUPT_CONTROL, 0xD700 | 0b1101_0110);
+        phy.irq(&mut mdio, &mut |fault| faults.push(fault));
+        assert_eq!(
+            faults,
+            [
+                Fault::Jabber,
+                Fault::ReceiveError,
+                Fault::ParallelDetect,
+                Fault::RemoteFault
+            ]
+        );
+    }
+
+    #[test]
+    fn fields() {
+        let fields = |register, value| registers::Fields { register, value }.to_string();
+        assert_eq!(fields(0x00, 0x3100), "speed=100 duplex=full autoneg");
+        assert_eq!(
+            fields(0x01, 0x7849),
+            "link=down 100-full 100-half 10-full 10-half"
+        );
+        assert_eq!(
+            fields(0x01, 0x782D),
+            "link=up 100-full 100-half 10-full 10-half autoneg-complete"
+        );
+        assert_eq!(fields(0x15, 42), "receive-errors=42");
+        assert_eq!(fields(0x1E, 0x0126), "mode=100-full MDI-X link");
+        assert_eq!(fields(0x1F, 0x8110), "mdix=auto led-mode=1 hp-mdix jabber");
+        assert_eq!(fields(0x1F, 0x6000), "mdix=MDI led-mode=0");
+        assert_eq!(fields(0x10, 0xFFFF), "");
+    }
+
+    #[test]
+    fn registers_are_sorted() {
+        assert!(registers::REGISTERS.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(registers::REGISTERS
+            .iter()
+            .all(|(address, _)| *address != registers::INTERRUPT_CONTROL));
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Names and field decoding for the KSZ8091's registers, for dumping them during bring-up.
+
+use core::fmt;
+
+/// The address of the interrupt control/status register. Reading it clears the pending interrupts,
+/// so it's left out of REGISTERS.
+pub const INTERRUPT_CONTROL: u8 = 0x1B;
+
+/// The standard and vendor-specific registers, by address.
+pub const REGISTERS: [(u8, &str); 20] = [
+    (0x00, "Basic Control"),
+    (0x01, "Basic Status"),
+    (0x02, "PHY Identifier 1"),
+    (0x03, "PHY Identifier 2"),
+    (0x04, "Auto-Negotiation Advertisement"),
+    (0x05, "Auto-Negotiation Link Partner Ability"),
+    (0x06, "Auto-Negotiation Expansion"),
+    (0x07, "Auto-Negotiation Next Page"),
+    (0x08, "Link Partner Next Page Ability"),
+    (0x0D, "MMD Access Control"),
+    (0x0E, "MMD Access Register/Data"),
+    (0x10, "Digital Reserved Control"),
+    (0x11, "AFE Control 1"),
+    (0x15, "RXER Counter"),
+    (0x16, "Operation Mode Strap Override"),
+    (0x17, "Operation Mode Strap Status"),
+    (0x18, "Expanded Control"),
+    (0x1D, "LinkMD Control/Status"),
+    (0x1E, "PHY Control 1"),
+    (0x1F, "PHY Control 2"),
+];
+
+/// The decoded fields of a register's value.
+pub struct Fields {
+    pub register: u8,
+    pub value: u16,
+}
+
+impl fmt::Display for Fields {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = self.value;
+        let bit = |pos: u16| value & (1 << pos) != 0;
+        let flags = |f: &mut fmt::Formatter, names: &[(u16, &str)]| {
+            names
+                .iter()
+                .filter(|(pos, _)| bit(*pos))
+                .try_for_each(|(_, name)| write!(f, " {}", name))
+        };
+
+        match self.register {
+            0x00 => {
+                let speed = match bit(13) {
+                    true => 100,
+                    false => 10,
+                };
+                let duplex = match bit(8) {
+                    true => "full",
+                    false => "half",
+                };
+                write!(f, "speed={} duplex={}", speed, duplex)?;
+                flags(
+                    f,
+                    &[
+                        (15, "reset"),
+                        (14, "loopback"),
+                        (12, "autoneg"),
+                        (11, "power-down"),
+                        (10, "isolate"),
+                        (9, "restart-autoneg"),
+                    ],
+                )
+            }
+            0x01 => {
+                let link = match bit(2) {
+                    true => "up",
+                    false => "down",
+                };
+                write!(f, "link={}", link)?;
+                flags(
+                    f,
+                    &[
+                        (14, "100-full"),
+                        (13, "100-half"),
+                        (12, "10-full"),
+                        (11, "10-half"),
+                        (5, "autoneg-complete"),
+                        (4, "remote-fault"),
+                        (1, "jabber"),
+                    ],
+                )
+            }
+            0x04 | 0x05 => {
+                write!(f, "pause={}", (value >> 10) & 0b11)?;
+                flags(
+                    f,
+                    &[
+                        (15, "next-page"),
+                        (13, "remote-fault"),
+                        (8, "100-full"),
+                        (7, "100-half"),
+                        (6, "10-full"),
+                        (5, "10-half"),
+                    ],
+                )
+            }
+            0x06 => flags(
+                f,
+                &[
+                    (4, "parallel-detect-fault"),
+                    (3, "partner-next-page"),
+                    (2, "next-page"),
+                    (1, "page-received"),
+                    (0, "partner-autoneg"),
+                ],
+            ),
+            0x15 => write!(f, "receive-errors={}", value),
+            0x1E => {
+                let mode = match value & 0b111 {
+                    0b000 => "autoneg",
+                    0b001 => "10-half",
+                    0b010 => "100-half",
+                    0b101 => "10-full",
+                    0b110 => "100-full",
+                    _ => "reserved",
+                };
+                let mdi = match bit(5) {
+                    true => "MDI-X",
+                    false => "MDI",
+                };
+                write!(f, "mode={} {}", mode, mdi)?;
+                flags(
+                    f,
+                    &[
+                        (9, "pause"),
+                        (8, "link"),
+                        (7, "polarity-reversed"),
+                        (4, "energy-detected"),
+                        (3, "isolated"),
+                    ],
+                )
+            }
+            0x1F => {
+                let mdix = match (bit(13), bit(14)) {
+                    (false, _) => "auto",
+                    (true, false) => "MDI-X",
+                    (true, true) => "MDI",
+                };
+                write!(f, "mdix={} led-mode={}", mdix, (value >> 4) & 0b11)?;
+                flags(
+                    f,
+                    &[
+                        (15, "hp-mdix"),
+                        (11, "force-link"),
+                        (10, "power-saving"),
+                        (8, "jabber"),
+                        (7, "50mhz-clock"),
+                        (3, "transmitter-disabled"),
+                        (2, "remote-loopback"),
+                        (0, "scrambler-disabled"),
+                    ],
+                )
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,83 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+#![no_main]
+
+// Types whatever might arrive on a terminal, a byte at a time, and checks each line that's entered
+// as the interpreter sees it: with its variables substituted, and sorted into the privileged
+// commands that are audited. The commands themselves aren't run, since most of them reach into the
+// hardware, other than those that set a variable (so that later lines have something to
+// substitute).
+
+use core::fmt::{self, Write};
+use heapless::String;
+use libfuzzer_sys::fuzz_target;
+use poe::console::{Line, Privileged, Variables, LINE_LEN};
+
+// Takes the echo, which isn't checked
+struct Discard;
+
+impl Write for Discard {
+    fn write_str(&mut self, _: &str) -> fmt::Result {
+        Ok(())
+    }
+}
+
+// $prog is found from the running bank and the linker's symbols, neither of which exist here
+fn names_prog(line: &str) -> bool {
+    line.split('$').skip(1).any(|rest| {
+        rest.strip_prefix("prog").map_or(false, |rest| {
+            !rest.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+        })
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut line = Line::new();
+    let mut variables = Variables::new();
+
+    for &byte in data {
+        let entered = match line.push(byte, &mut Discard) {
+            Some(entered) => entered,
+            None => continue,
+        };
+        assert!(entered.len() <= LINE_LEN);
+        assert!(entered.bytes().all(|b| (0x20..=0x7E).contains(&b)));
+        if names_prog(entered) {
+            continue;
+        }
+
+        let mut expanded = String::<LINE_LEN>::new();
+        if variables.expand(entered, &mut expanded).is_err() {
+            continue;
+        }
+        if !entered.contains('$') {
+            assert_eq!(expanded, entered);
+        }
+
+        let command = expanded.trim();
+        Privileged::parse(command);
+        if let Some(secret) = Privileged::secret(command) {
+            assert!(command.starts_with(secret));
+        }
+
+        let mut tokens = command.split(' ');
+        if let (Some("let"), Some(name), value, None) =
+            (tokens.next(), tokens.next(), tokens.next(), tokens.next())
+        {
+            variables.set(name, value).ok();
+        }
+    }
+});
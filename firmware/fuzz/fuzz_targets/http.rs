@@ -0,0 +1,46 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+#![no_main]
+
+// Hands whatever a client might send to the web server to the handler, as it would be received
+// (up to the longest request that's buffered), both before and after the client has finished
+// sending. A response may only be held back while more of the request can still arrive, and it's
+// always a whole HTTP response.
+
+use libfuzzer_sys::fuzz_target;
+use poe::http::{self, Context, MAX_REQUEST_LEN, MAX_RESPONSE_LEN};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, IpAddress, Ipv4Address};
+
+fuzz_target!(|data: &[u8]| {
+    let context = Context {
+        now: Instant::from_millis(0),
+        hardware_addr: EthernetAddress([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]),
+        address: Some(Ipv4Address::new(10, 0, 0, 2)),
+        link: None,
+        identifying: false,
+    };
+    let source = IpAddress::v4(10, 0, 0, 1);
+    let request = &data[..data.len().min(MAX_REQUEST_LEN)];
+
+    for eof in [false, true] {
+        let mut response = [0; MAX_RESPONSE_LEN];
+        match http::handle(request, eof, &mut response, &context, source, &mut |_| {}) {
+            Some((len, _)) => assert!(response[..len].starts_with(b"HTTP/1.1 ")),
+            None => assert!(!eof && request.len() < MAX_REQUEST_LEN),
+        }
+    }
+});
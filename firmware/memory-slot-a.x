@@ -0,0 +1,8 @@
+MEMORY
+{
+	/* Only the first bank of flash (the lower megabyte) holds the firmware, leaving the second
+	 * bank free for data that's written at runtime (see src/efm32gg/msc.rs), including updates
+	 * (see src/boot.rs and memory-slot-b.x) */
+	FLASH (rx) : ORIGIN = 0x00000000, LENGTH = 1M
+	RAM (rwx)  : ORIGIN = 0x20000000, LENGTH = 512K
+}
@@ -0,0 +1,7 @@
+MEMORY
+{
+	/* An update that's booted from the second bank of flash (see src/boot.rs), up to the pages
+	 * that hold the settings (see src/store.rs) */
+	FLASH (rx) : ORIGIN = 0x00100000, LENGTH = 1004K
+	RAM (rwx)  : ORIGIN = 0x20000000, LENGTH = 512K
+}
@@ -1,5 +0,0 @@
-MEMORY
-{
-	FLASH (rx) : ORIGIN = 0x00000000, LENGTH = 2M
-	RAM (rwx)  : ORIGIN = 0x20000000, LENGTH = 512K
-}
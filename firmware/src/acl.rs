@@ -0,0 +1,230 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Access control for the management services. Each service can be disabled outright, and requests
+// to those that are enabled are only accepted from the allowed source prefixes. An empty list
+// allows every source, so that a freshly booted device can still be reached.
+//
+// The rules are changed through the configuration (see `config`), which rolls back changes that
+// lock the manager out and keeps the committed rules in the store.
+
+use core::cell::RefCell;
+use core::fmt;
+use core::str::FromStr;
+use cortex_m::interrupt::{self, Mutex};
+use smoltcp::wire::{IpAddress, IpCidr, Ipv4Address, Ipv4Cidr, Ipv6Address, Ipv6Cidr};
+
+/// The maximum number of allowed source prefixes.
+pub const MAX_PREFIXES: usize = 4;
+
+/// The length of the rules' stored form (see `Rules::encode`).
+pub const ENCODED_LEN: usize = MAX_PREFIXES * PREFIX_LEN + 1;
+
+// Each prefix is stored as the length of its address (zero for an empty slot), its prefix length,
+// and its address, padded out to the length of an IPv6 address
+const PREFIX_LEN: usize = 18;
+
+pub const SERVICES: [Service; 8] = [
+    Service::Control,
+    Service::Fleet,
+    Service::Snmp,
+    Service::Coap,
+    Service::Discovery,
+    Service::Http,
+    Service::Modbus,
+    Service::Tftp,
+];
+
+static STATE: Mutex<RefCell<Rules>> = Mutex::new(RefCell::new(Rules::DEFAULT));
+
+/// The allowed source prefixes and the enabled services, which can be copied out and restored
+/// whole (see `config`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rules {
+    prefixes: [Option<IpCidr>; MAX_PREFIXES],
+    enabled: [bool; SERVICES.len()],
+}
+
+impl Rules {
+    /// Every service enabled, and requests allowed from every source.
+    pub const DEFAULT: Rules = Rules {
+        prefixes: [None; MAX_PREFIXES],
+        enabled: [true; SERVICES.len()],
+    };
+
+    pub fn set_enabled(&mut self, service: Service, enabled: bool) {
+        self.enabled[service as usize] = enabled;
+    }
+
+    pub fn enabled(&self, service: Service) -> bool {
+        self.enabled[service as usize]
+    }
+
+    /// Allows requests from the given prefix.
+    pub fn allow(&mut self, prefix: IpCidr) -> Result<(), &'static str> {
+        if self.prefixes.contains(&Some(prefix)) {
+            return Ok(());
+        }
+
+        let slot = self
+            .prefixes
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or("too many prefixes")?;
+        *slot = Some(prefix);
+        Ok(())
+    }
+
+    /// Stops allowing requests from the given prefix, returning false if it wasn't allowed.
+    pub fn remove(&mut self, prefix: IpCidr) -> bool {
+        match self.prefixes.iter_mut().find(|slot| **slot == Some(prefix)) {
+            Some(slot) => {
+                *slot = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns an iterator over the allowed prefixes.
+    pub fn prefixes(&self) -> impl Iterator<Item = IpCidr> + '_ {
+        self.prefixes.iter().flatten().copied()
+    }
+
+    /// Returns true if requests from the given address are accepted.
+    pub fn permits(&self, addr: IpAddress) -> bool {
+        let mut prefixes = self.prefixes().peekable();
+        prefixes.peek().is_none() || prefixes.any(|prefix| prefix.contains_addr(&addr))
+    }
+
+    /// Returns the stored form of the rules: each of the prefixes, then a bit for each enabled
+    /// service.
+    pub fn encode(&self) -> [u8; ENCODED_LEN] {
+        let mut value = [0; ENCODED_LEN];
+        for (prefix, encoded) in self.prefixes.iter().zip(value.chunks_mut(PREFIX_LEN)) {
+            if let Some(prefix) = prefix {
+                let address = prefix.address();
+                let address = address.as_bytes();
+                encoded[0] = address.len() as u8;
+                encoded[1] = prefix.prefix_len();
+                encoded[2..2 + address.len()].copy_from_slice(address);
+            }
+        }
+        value[ENCODED_LEN - 1] = self
+            .enabled
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (i, enabled)| bits | (u8::from(*enabled) << i));
+        value
+    }
+
+    /// Parses the stored form of the rules, returning `None` if it's malformed.
+    pub fn decode(value: &[u8]) -> Option<Rules> {
+        if value.len() != ENCODED_LEN {
+            return None;
+        }
+
+        let mut rules = Rules::DEFAULT;
+        for (prefix, encoded) in rules.prefixes.iter_mut().zip(value.chunks(PREFIX_LEN)) {
+            let (len, prefix_len) = (encoded[0], encoded[1]);
+            *prefix = match len {
+                0 => None,
+                4 if prefix_len <= 32 => Some(IpCidr::Ipv4(Ipv4Cidr::new(
+                    Ipv4Address::from_bytes(&encoded[2..6]),
+                    prefix_len,
+                ))),
+                16 if prefix_len <= 128 => Some(IpCidr::Ipv6(Ipv6Cidr::new(
+                    Ipv6Address::from_bytes(&encoded[2..18]),
+                    prefix_len,
+                ))),
+                _ => return None,
+            };
+        }
+        let bits = value[ENCODED_LEN - 1];
+        for (i, enabled) in rules.enabled.iter_mut().enumerate() {
+            *enabled = bits & (1 << i) != 0;
+        }
+        Some(rules)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Service {
+    Control,
+    Fleet,
+    Snmp,
+    Coap,
+    Discovery,
+    Http,
+    Modbus,
+    Tftp,
+}
+
+impl Service {
+    fn name(&self) -> &'static str {
+        match self {
+            Service::Control => "control",
+            Service::Fleet => "fleet",
+            Service::Snmp => "snmp",
+            Service::Coap => "coap",
+            Service::Discovery => "discovery",
+            Service::Http => "http",
+            Service::Modbus => "modbus",
+            Service::Tftp => "tftp",
+        }
+    }
+}
+
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+impl FromStr for Service {
+    type Err = &'static str;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        SERVICES
+            .iter()
+            .copied()
+            .find(|service| service.name() == name)
+            .ok_or("unknown service")
+    }
+}
+
+pub fn enabled(service: Service) -> bool {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().enabled(service))
+}
+
+/// Calls `f` with each of the allowed prefixes.
+pub fn for_each<F: FnMut(IpCidr)>(f: F) {
+    rules().prefixes().for_each(f);
+}
+
+/// Returns true if requests from the given address are accepted.
+pub fn permits(addr: IpAddress) -> bool {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().permits(addr))
+}
+
+/// Returns a copy of the current rules.
+pub fn rules() -> Rules {
+    interrupt::free(|cs| *STATE.borrow(cs).borrow())
+}
+
+/// Replaces the current rules.
+pub fn set_rules(rules: Rules) {
+    interrupt::free(|cs| *STATE.borrow(cs).borrow_mut() = rules)
+}
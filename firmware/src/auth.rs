@@ -0,0 +1,340 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Bearer tokens that guard the state-changing parts of the HTTP API and the control protocol.
+// Once a password is set (from the terminal), a client logs in with it (see `login`) to get a
+// token, which it then presents with each request until the token expires. Without a password,
+// nothing is required, as before.
+//
+// The password is kept in the settings store (see `store`) as the SHA-256 hash of the part's
+// unique number followed by the password, so the password itself never touches flash. Tokens are
+// derived from the TRNG's seed, the hash, and a counter, and are only kept in RAM, so a reset logs
+// every client out.
+//
+// Each source address gets a few free guesses (see `FREE_ATTEMPTS`), whether at the password or at
+// a token, after which it has to wait before the next one is even checked. The wait doubles with
+// every further failure, up to `MAX_BACKOFF`, and is forgotten once a login succeeds or the source
+// stays quiet for that long. Only the most recent `MAX_SOURCES` sources are tracked.
+
+use crate::store::{self, Key};
+use core::cell::RefCell;
+use core::fmt;
+use core::str::FromStr;
+use cortex_m::interrupt::{self, Mutex};
+use sha2::{Digest, Sha256};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::IpAddress;
+
+/// How long a token is accepted after it's issued.
+pub const LIFETIME: Duration = Duration::from_secs(15 * 60);
+
+const TOKEN_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+const MAX_SESSIONS: usize = 4;
+
+/// How many failures a source is allowed before it has to back off.
+const FREE_ATTEMPTS: u8 = 3;
+/// How long a source waits after its first failure beyond the free ones.
+const BACKOFF: Duration = Duration::from_secs(1);
+/// The longest a source waits, which is also how long its failures are remembered.
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+const MAX_SOURCES: usize = 8;
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    seed: 0,
+    issued: 0,
+    credential: None,
+    sessions: [None; MAX_SESSIONS],
+    attempts: [None; MAX_SOURCES],
+}));
+
+struct State {
+    seed: u64,
+    issued: u32,
+    credential: Option<[u8; HASH_LEN]>,
+    sessions: [Option<Session>; MAX_SESSIONS],
+    attempts: [Option<Attempts>; MAX_SOURCES],
+}
+
+#[derive(Clone, Copy)]
+struct Session {
+    token: Token,
+    expires: Instant,
+}
+
+/// The failed attempts made from one source.
+#[derive(Clone, Copy)]
+struct Attempts {
+    source: IpAddress,
+    failures: u8,
+    last: Instant,
+    until: Instant,
+}
+
+impl State {
+    /// Returns true if the source still has to wait before its next attempt is checked.
+    fn backing_off(&self, source: IpAddress, now: Instant) -> bool {
+        self.attempts
+            .iter()
+            .flatten()
+            .any(|attempts| attempts.source == source && now < attempts.until)
+    }
+
+    /// Counts a failure against the source, replacing the source whose last failure is oldest if
+    /// there's no room.
+    fn fail(&mut self, source: IpAddress, now: Instant) {
+        let slot = match self
+            .attempts
+            .iter()
+            .position(|slot| matches!(slot, Some(attempts) if attempts.source == source))
+        {
+            Some(index) => &mut self.attempts[index],
+            None => match self
+                .attempts
+                .iter_mut()
+                .min_by_key(|slot| slot.map(|attempts| attempts.last))
+            {
+                Some(slot) => slot,
+                None => return,
+            },
+        };
+
+        let failures = match slot {
+            Some(attempts) if now < attempts.last + MAX_BACKOFF => {
+                attempts.failures.saturating_add(1)
+            }
+            _ => 1,
+        };
+        let until = match failures.checked_sub(FREE_ATTEMPTS) {
+            None | Some(0) => now,
+            Some(excess) => now + (BACKOFF * (1 << u32::from(excess - 1).min(16))).min(MAX_BACKOFF),
+        };
+        *slot = Some(Attempts {
+            source,
+            failures,
+            last: now,
+            until,
+        });
+    }
+
+    /// Forgets the source's failures.
+    fn succeed(&mut self, source: IpAddress) {
+        for slot in self.attempts.iter_mut() {
+            if matches!(slot, Some(attempts) if attempts.source == source) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+/// A token that was issued by `login`, formatted as hex.
+#[derive(Clone, Copy, Debug)]
+pub struct Token([u8; TOKEN_LEN]);
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+impl FromStr for Token {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Token, Self::Err> {
+        if s.len() != 2 * TOKEN_LEN {
+            return Err("invalid token");
+        }
+
+        let mut token = [0; TOKEN_LEN];
+        for (byte, digits) in token.iter_mut().zip(s.as_bytes().chunks(2)) {
+            *byte = core::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or("invalid token")?;
+        }
+        Ok(Token(token))
+    }
+}
+
+/// Loads the password's hash from the store. This must be called once at boot, with a random
+/// seed (see the TRNG).
+pub fn init(seed: u64) {
+    let mut hash = [0; HASH_LEN];
+    let credential = match store::get(Key::Credential, &mut hash) {
+        Some(HASH_LEN) => Some(hash),
+        Some(_) => {
+            log::warn!("Ignoring malformed credential");
+            None
+        }
+        None => None,
+    };
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.seed = seed;
+        state.credential = credential;
+    })
+}
+
+/// Returns true if a password has been set, in which case state-changing requests need a token.
+pub fn is_required() -> bool {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().credential.is_some())
+}
+
+/// Sets the password, or removes it so that no token is required. Either way, every token that
+/// had been issued is revoked.
+pub fn set_password(password: Option<&str>) -> Result<(), &'static str> {
+    let credential = match password {
+        Some("") => return Err("the password can't be empty"),
+        Some(password) => {
+            let hash = hash(password);
+            store::set(Key::Credential, &hash)?;
+            Some(hash)
+        }
+        None => {
+            store::remove(Key::Credential)?;
+            None
+        }
+    };
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.credential = credential;
+        state.sessions = [None; MAX_SESSIONS];
+    });
+    Ok(())
+}
+
+/// Checks the password and, if it's correct, issues a new token. A source that has failed too
+/// often is refused without checking.
+pub fn login(password: &str, source: IpAddress, now: Instant) -> Result<Token, &'static str> {
+    let candidate = hash(password);
+    let correct = interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        let credential = state.credential.ok_or("no password is set")?;
+        if state.backing_off(source, now) {
+            return Err("too many attempts");
+        }
+
+        let correct = same(&candidate, &credential);
+        match correct {
+            true => state.succeed(source),
+            false => state.fail(source, now),
+        }
+        Ok(correct)
+    })?;
+
+    match correct {
+        true => issue(now),
+        false => {
+            log::warn!("Rejected login from {} with an incorrect password", source);
+            Err("incorrect password")
+        }
+    }
+}
+
+/// Issues a new token without checking the password (e.g. for the terminal, which can already
+/// change it), replacing the oldest if there are already as many as can be kept.
+pub fn issue(now: Instant) -> Result<Token, &'static str> {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        let credential = state.credential.ok_or("no password is set")?;
+
+        let mut digest = Sha256::new();
+        digest.update(state.seed.to_le_bytes());
+        digest.update(credential);
+        digest.update(state.issued.to_le_bytes());
+        digest.update(now.total_millis().to_le_bytes());
+        let mut token = [0; TOKEN_LEN];
+        token.copy_from_slice(&digest.finalize()[..TOKEN_LEN]);
+        state.issued = state.issued.wrapping_add(1);
+
+        let session = Session {
+            token: Token(token),
+            expires: now + LIFETIME,
+        };
+        let slot = state
+            .sessions
+            .iter_mut()
+            .min_by_key(|slot| slot.map(|session| session.expires))
+            .ok_or("no sessions")?;
+        *slot = Some(session);
+
+        Ok(session.token)
+    })
+}
+
+/// Returns true if the request may change state: either no password is set, or the token was
+/// issued and hasn't expired. A token that's presented but not valid counts against the source
+/// like an incorrect password does.
+pub fn verify(token: Option<&str>, source: IpAddress, now: Instant) -> bool {
+    if !is_required() {
+        return true;
+    }
+    let token = match token {
+        Some(token) => token.parse::<Token>().ok(),
+        None => return false,
+    };
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        if state.backing_off(source, now) {
+            return false;
+        }
+
+        let mut valid = false;
+        for slot in state.sessions.iter_mut() {
+            match (*slot, token) {
+                (Some(session), _) if now >= session.expires => *slot = None,
+                (Some(session), Some(token)) => valid |= same(&session.token.0, &token.0),
+                _ => {}
+            }
+        }
+        if !valid {
+            state.fail(source, now);
+        }
+        valid
+    })
+}
+
+/// Returns the number of tokens that haven't expired.
+pub fn sessions(now: Instant) -> usize {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        state
+            .sessions
+            .iter()
+            .flatten()
+            .filter(|session| now < session.expires)
+            .count()
+    })
+}
+
+/// Revokes every token that has been issued.
+pub fn revoke_all() {
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().sessions = [None; MAX_SESSIONS])
+}
+
+// Compares every byte, so that the time taken doesn't reveal how much of a guess was right
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn hash(password: &str) -> [u8; HASH_LEN] {
+    let mut digest = Sha256::new();
+    digest.update(crate::efm32gg::devinfo::unique().to_le_bytes());
+    digest.update(password.as_bytes());
+    digest.finalize().into()
+}
@@ -20,25 +20,77 @@
 ///
 /// This firmware implements the following:
 /// - identify - Send a "0" or a "1" over TCP to the control port to disable or enable,
-///              respectively, the flashing "Identify" LED.
-use cortex_m::{asm, interrupt, peripheral};
+///              respectively, the flashing "Identify" LED. The "1" may be followed by a pattern
+///              (e.g. "1sos" or "1blink 4 25 10"; see poe::identify). The same can be sent over
+///              UDP to the fleet multicast group (239.255.80.69) to identify every device at once.
+/// - temperature - Send a "t" over TCP to the control port to read the internal temperature, in
+///                 degrees Celsius.
+/// - status - Send an "s" over TCP to the control port to read the identify state, link state,
+///            and IPv4 address in a fixed binary layout (see poe::network). Commands that don't
+///            otherwise reply are answered with an ACK (0x06), and failures with a NAK (0x15)
+///            followed by the reason.
+/// - port power - Send a "P" or a "p" over TCP to the control port to enable or disable,
+///                respectively, power to the downstream port, or a "c" to power cycle it. The
+///                port is only powered once the upstream link is up. It can also be power cycled
+///                on a schedule, or when the downstream device stops responding to pings (see
+///                the terminal's "port" commands).
+/// - power negotiation - Request power from the upstream PSE using LLDP-MED, and limit the
+///                       downstream port to what's left of the allocation.
+/// - energy - Send an "e" over TCP to the control port to read the power drawn by the downstream
+///            port, in milliwatts, and the energy delivered to it, in milliwatt-hours.
+/// - snmp - Answer SNMPv2c requests for the system and interfaces groups, and for the state of
+///          the downstream port.
+/// - coap - Serve the identify, port power, status, and statistics resources over CoAP, notifying
+///          observers of the status when the link changes.
+/// - ipv6 - Configure a link-local address, and a global address from router advertisements. The
+///          control port and the UDP services answer on both IPv4 and IPv6.
+/// - discovery - Answer "discover" probes sent to the discovery port (51901) with the device's
+///               type, version, and addresses, so that a host can enumerate every device on a
+///               subnet. Beacons can also be broadcast once an address is first acquired.
+/// - http - Serve the status as JSON at /api/status, and accept identify and port power changes
+///          at /api/identify and /api/port (see poe::http). /api/events streams the status and
+///          operational events as they change, and / serves a page that shows them.
+/// - modbus - Serve the identify state, port power, current, temperature, and counters as Modbus
+///            TCP registers on port 502 (see poe::modbus).
+/// - tftp - Serve the event log, the last fault, the running configuration, and the frames kept in
+///          the capture buffer as read-only files over TFTP (see poe::tftp).
+/// - usb - With the "usb" feature, offer the terminal (and the log) over a USB serial port, for
+///         when the network is down.
+/// - updates - Boot an update staged in the second bank of flash (see the terminal's "boot"
+///             commands), falling back to this image if the update doesn't stay up.
+/// - configuration - Change the address (static or DHCP), management VLAN, and access control
+///                   list together, rolling the change back unless a management connection is
+///                   made soon after (see the terminal's "config" commands).
+/// - authentication - Once a password is set (see the terminal's "auth" commands), require a
+///                    token for the HTTP API's POST endpoints and for the control port's
+///                    state-changing commands. Send "a<password>" to the control port to get a
+///                    token, and prefix commands with "@<token> ".
+use cortex_m::interrupt;
 use efm32gg_hal::cmu::CMUExt;
 use efm32gg_hal::gpio::{pins, EFM32Pin, GPIOExt, Output};
 use led::mono::{self, CommonAnodeLED};
-use smoltcp::time::Instant;
 
 type IdentifyLed = CommonAnodeLED<pins::PE4<Output>>;
 type NetworkLed = CommonAnodeLED<pins::PE5<Output>>;
 
+#[cfg(feature = "usb")]
+type UsbTerminal = poe::log::usb::Terminal;
+// RTIC asserts that every resource is Send without regard to its #[cfg], so this needs to name
+// some type even without the feature
+#[cfg(not(feature = "usb"))]
+type UsbTerminal = ();
+
 #[rtic::app(
     dispatchers = [ CAN0, CAN1, LCD ],
     device = efm32gg11b820,
     peripherals = true,
 )]
 mod app {
+    use ksz8091::KSZ8091;
     use poe::efm32gg::{self, dma, EFM32GG};
-    use poe::ksz8091::KSZ8091;
+    use poe::led_manager::{Indicator, Mode};
     use poe::network;
+    use poe::work::Event;
 
     use core::pin::Pin;
     use cortex_m::{delay::Delay, interrupt};
@@ -46,18 +98,26 @@ mod app {
     use efm32gg_hal::cmu::CMUExt;
     use efm32gg_hal::gpio::{EFM32Pin, GPIOExt};
     use ignore_result::Ignore;
-    use led::mono::{self, CommonAnodeLED};
-    use smoltcp::iface::{InterfaceBuilder, Neighbor, NeighborCache, Route, Routes, SocketStorage};
-    use smoltcp::socket::{Dhcpv4Socket, TcpSocket, TcpSocketBuffer};
+    use led::mono::CommonAnodeLED;
+    use smoltcp::iface::{Config, Interface};
+    use smoltcp::socket::{dhcpv4, icmp, raw, tcp, udp};
     use smoltcp::time::Instant;
-    use smoltcp::wire::{IpAddress, IpCidr, Ipv4Address, Ipv4Cidr};
+    use smoltcp::wire::{
+        HardwareAddress, IpCidr, IpProtocol, IpVersion, Ipv4Address, Ipv4Cidr, Ipv6Address,
+        Ipv6Cidr,
+    };
+
+    // The core clock's frequency
+    const CORE_HZ: u32 = 25_000_000;
 
     #[monotonic(binds = SysTick, default = true)]
-    type Monotonic = dwt_systick_monotonic::DwtSystick<25_000_000>; // 25 MHz
+    type Monotonic = dwt_systick_monotonic::DwtSystick<CORE_HZ>;
 
     macro_rules! schedule {
         ($name:ident, $duration:expr) => {
-            $name::spawn_after($duration).expect(concat!("scheduling ", stringify!($name)))
+            $name::spawn_after($duration)
+                .map_err(|_| log::error!(concat!("Failed to schedule ", stringify!($name))))
+                .ok()
         };
     }
 
@@ -66,7 +126,6 @@ mod app {
         led_identify: IdentifyLed,
         led_network: NetworkLed,
         network: network::Resources,
-        rtc: efm32gg11b820::RTC,
     }
 
     #[local]
@@ -75,141 +134,172 @@ mod app {
 
         #[cfg(feature = "rtt")]
         terminal: &'static mut poe::log::rtt::Terminal,
+
+        #[cfg(feature = "usb")]
+        usb_terminal: crate::UsbTerminal,
     }
 
     pub struct IdentifyLed {
-        led: crate::IdentifyLed,
-        state: mono::State,
+        indicator: Indicator<crate::IdentifyLed>,
         spawn: Option<flash_identify_led::SpawnHandle>,
     }
 
     impl IdentifyLed {
         fn new(led: crate::IdentifyLed) -> IdentifyLed {
             IdentifyLed {
+                indicator: Indicator::new(led, Mode::Solid(false)),
                 spawn: None,
-                led,
-                state: mono::State::Off,
             }
         }
 
-        fn enable(&mut self, en: bool) {
-            match en {
-                true => flash_identify_led::spawn().expect("spawning flash_identify_led"),
-                false => {
-                    if let Some(handle) = self.spawn.take() {
-                        handle.cancel().expect("cancelling flash_identify_led");
-                    }
-                    self.led.set(mono::State::Off);
-                    self.state = mono::State::Off;
-                }
+        fn enable(&mut self, pattern: Option<poe::identify::Pattern>) {
+            poe::identify::set_active(pattern);
+            self.indicator.show(match pattern {
+                Some(pattern) => Mode::Flash(pattern),
+                None => Mode::Solid(false),
+            });
+
+            if let Some(handle) = self.spawn.take() {
+                handle.cancel().ignore();
             }
-            self.led.set(mono::State::Off);
-            self.state = mono::State::Off;
+            // A flash that's already queued will pick up the new pattern
+            flash_identify_led::spawn().ignore();
         }
     }
 
     #[task(priority = 8, shared = [led_identify])]
     fn flash_identify_led(mut cx: flash_identify_led::Context) {
-        use mono::State::*;
-
+        let _timing = poe::efm32gg::timing::start("flash_identify_led");
         cx.shared.led_identify.lock(|id| {
-            id.state = match id.state {
-                On => Off,
-                Off => On,
-            };
-            id.led.set(id.state);
-            id.spawn = Some(schedule!(flash_identify_led, 250u32.millis()));
+            id.spawn = match id.indicator.step() {
+                Some(hold) => schedule!(flash_identify_led, (hold.total_millis() as u32).millis()),
+                None => {
+                    poe::identify::set_active(None);
+                    None
+                }
+            }
         });
     }
 
     pub struct NetworkLed {
+        indicator: Indicator<crate::NetworkLed>,
         spawn: Option<occult_network_led::SpawnHandle>,
-        led: crate::NetworkLed,
-        state: mono::State,
-        network: network::State,
-        flashes: u8,
     }
 
     impl NetworkLed {
         fn new(led: crate::NetworkLed) -> NetworkLed {
             NetworkLed {
+                indicator: Indicator::new(led, Mode::network(network::State::Uninit)),
                 spawn: None,
-                led,
-                state: mono::State::On,
-                network: network::State::Uninit,
-                flashes: 0,
             }
         }
 
         // This can race - link drops (NoLink) and then DHCP is handled (NoDhcp).
         // Break this into two functions that check direction.
         fn show(&mut self, state: network::State) {
-            self.network = state;
-            self.flashes = 0;
+            self.indicator.show(Mode::network(state));
 
             if let Some(handle) = self.spawn.take() {
                 handle.cancel().ignore();
             }
-            occult_network_led::spawn().expect("spawning occult_network_led");
+            occult_network_led::spawn().ignore();
         }
     }
 
     #[task(priority = 8, shared = [led_network])]
     fn occult_network_led(mut cx: occult_network_led::Context) {
-        use mono::State::*;
-        use network::State::*;
-
+        let _timing = poe::efm32gg::timing::start("occult_network_led");
         cx.shared.led_network.lock(|net| {
-            match (net.network, net.flashes) {
-                (Uninit, _) => net.state = On,
-                (Operational, _) => net.state = Off,
-                (network, 0) => {
-                    net.flashes = match network {
-                        Uninit | Operational => 0,
-                        NoLink => 1,
-                        NoDhcp => 2,
-                        NoGateway => 3,
-                    };
-                    net.state = On;
-                    net.spawn = Some(schedule!(occult_network_led, 1000u32.millis()));
-                }
-                (_, flashes) => match net.state {
-                    Off => {
-                        net.state = On;
-                        net.flashes = flashes.saturating_sub(1);
-                        net.spawn = Some(schedule!(occult_network_led, 250u32.millis()));
-                    }
-                    On => {
-                        net.state = Off;
-                        net.spawn = Some(schedule!(occult_network_led, 250u32.millis()));
-                    }
-                },
-            }
-            net.led.set(net.state);
+            net.spawn = net.indicator.step().and_then(|hold| {
+                schedule!(occult_network_led, (hold.total_millis() as u32).millis())
+            })
         });
     }
 
+    // Follows the link on the network LED (see `poe::network::events`). Changes to the address
+    // are shown as they're handled, since they also depend on DHCP's progress.
+    fn show_link(_: Instant, event: network::events::Event) {
+        use network::events::Event;
+
+        let state = match event {
+            Event::LinkUp => match poe::config::addressing().address {
+                Some(_) => network::State::Operational,
+                None => network::State::NoDhcp,
+            },
+            Event::LinkDown => network::State::NoLink,
+            Event::AddressAcquired(_) | Event::AddressLost => return,
+        };
+        show_network_led::spawn(state).ignore();
+    }
+
+    #[task(capacity = 2, priority = 8, shared = [led_network])]
+    fn show_network_led(mut cx: show_network_led::Context, state: network::State) {
+        let _timing = poe::efm32gg::timing::start("show_network_led");
+        cx.shared.led_network.lock(|led| led.show(state));
+    }
+
     #[init(
         local = [
-            eth_rx_region: dma::RxRegion = dma::RxRegion([0; 1536]),
+            eth_rx_region: dma::RxRegion = dma::RxRegion::new(),
             eth_tx_region: dma::TxRegion = dma::TxRegion([0; 1536]),
             eth_rx_descriptors: dma::RxDescriptors = dma::RxDescriptors::new(),
             eth_tx_descriptors: dma::TxDescriptors = dma::TxDescriptors::new(),
             tcp_rx_payload: [u8; 128] = [0; 128],
             tcp_tx_payload: [u8; 128] = [0; 128],
-            http_rx_payload: [u8; 128] = [0; 128],
-            http_tx_payload: [u8; 1024] = [0; 1024],
-
-            neighbors: [Option<(IpAddress, Neighbor)>; 8] = [None; 8],
-            sockets: [SocketStorage<'static>; 3] = [SocketStorage::EMPTY; 3],
-            ip_addresses: [IpCidr; 1] =
-                [IpCidr::Ipv4(Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, 0))],
-            routes: [Option<(IpCidr, Route)>; 4] = [None; 4],
+            syslog_rx_metadata: [udp::PacketMetadata; 1] = [udp::PacketMetadata::EMPTY; 1],
+            syslog_rx_payload: [u8; 0] = [0; 0],
+            syslog_tx_metadata: [udp::PacketMetadata; 4] = [udp::PacketMetadata::EMPTY; 4],
+            syslog_tx_payload: [u8; 1024] = [0; 1024],
+            probe_rx_metadata: [icmp::PacketMetadata; 1] = [icmp::PacketMetadata::EMPTY; 1],
+            probe_rx_payload: [u8; 64] = [0; 64],
+            probe_tx_metadata: [icmp::PacketMetadata; 1] = [icmp::PacketMetadata::EMPTY; 1],
+            probe_tx_payload: [u8; 64] = [0; 64],
+            snmp_rx_metadata: [udp::PacketMetadata; 2] = [udp::PacketMetadata::EMPTY; 2],
+            snmp_rx_payload: [u8; 1024] = [0; 1024],
+            snmp_tx_metadata: [udp::PacketMetadata; 2] = [udp::PacketMetadata::EMPTY; 2],
+            snmp_tx_payload: [u8; 1024] = [0; 1024],
+            coap_rx_metadata: [udp::PacketMetadata; 2] = [udp::PacketMetadata::EMPTY; 2],
+            coap_rx_payload: [u8; 512] = [0; 512],
+            coap_tx_metadata: [udp::PacketMetadata; 4] = [udp::PacketMetadata::EMPTY; 4],
+            coap_tx_payload: [u8; 1024] = [0; 1024],
+            ndisc_rx_metadata: [raw::PacketMetadata; 2] = [raw::PacketMetadata::EMPTY; 2],
+            ndisc_rx_payload: [u8; 512] = [0; 512],
+            ndisc_tx_metadata: [raw::PacketMetadata; 1] = [raw::PacketMetadata::EMPTY; 1],
+            ndisc_tx_payload: [u8; 64] = [0; 64],
+            fleet_rx_metadata: [udp::PacketMetadata; 2] = [udp::PacketMetadata::EMPTY; 2],
+            fleet_rx_payload: [u8; 64] = [0; 64],
+            fleet_tx_metadata: [udp::PacketMetadata; 1] = [udp::PacketMetadata::EMPTY; 1],
+            fleet_tx_payload: [u8; 0] = [0; 0],
+            discovery_rx_metadata: [udp::PacketMetadata; 2] = [udp::PacketMetadata::EMPTY; 2],
+            discovery_rx_payload: [u8; 64] = [0; 64],
+            discovery_tx_metadata: [udp::PacketMetadata; 2] = [udp::PacketMetadata::EMPTY; 2],
+            discovery_tx_payload: [u8; 256] = [0; 256],
+            capture_rx_payload: [u8; 64] = [0; 64],
+            capture_tx_payload: [u8; 2048] = [0; 2048],
+            modbus_rx_payload: [u8; 512] = [0; 512],
+            modbus_tx_payload: [u8; 512] = [0; 512],
+            tftp_rx_metadata: [udp::PacketMetadata; 2] = [udp::PacketMetadata::EMPTY; 2],
+            tftp_rx_payload: [u8; 512] = [0; 512],
+            tftp_tx_metadata: [udp::PacketMetadata; 2] = [udp::PacketMetadata::EMPTY; 2],
+            tftp_tx_payload: [u8; 256] = [0; 256],
+            tftp_transfer_rx_metadata: [udp::PacketMetadata; 4] = [udp::PacketMetadata::EMPTY; 4],
+            tftp_transfer_rx_payload: [u8; 64] = [0; 64],
+            tftp_transfer_tx_metadata: [udp::PacketMetadata; 2] = [udp::PacketMetadata::EMPTY; 2],
+            tftp_transfer_tx_payload: [u8; 1024] = [0; 1024],
+            http_rx_payload: [[u8; 1024]; poe::http::MAX_CONNECTIONS] =
+                [[0; 1024]; poe::http::MAX_CONNECTIONS],
+            http_tx_payload: [[u8; 1024]; poe::http::MAX_CONNECTIONS] =
+                [[0; 1024]; poe::http::MAX_CONNECTIONS],
+
+            sockets: network::SocketPool<15> = network::SocketPool::new(),
         ]
     )]
     fn init(mut cx: init::Context) -> (SharedResources, LocalResources, init::Monotonics) {
         use log::LevelFilter::*;
 
+        // Boot the update in bank B instead, if there is one
+        poe::boot::select();
+
         let cmu = cx.device.CMU;
         let emu = cx.device.EMU;
         let gpio = cx.device.GPIO;
@@ -217,12 +307,30 @@ mod app {
 
         // Initialize logging
         let logger = poe::log::init();
+        logger.add_memory(poe::log::memory::new(Info));
+        logger.add_syslog(poe::log::syslog::new(Info));
         #[cfg(feature = "rtt")]
         logger.add_rtt(poe::log::rtt::new(Debug));
+        #[cfg(feature = "usb")]
+        logger.add_usb(poe::log::usb::new(Info));
+        #[cfg(feature = "defmt")]
+        logger.add_defmt(poe::log::defmt::new(Debug));
+
+        poe::fault::init();
+        poe::efm32gg::rmu::init(&cx.device.RMU);
+        poe::events::init(Instant::from_millis(0));
+        poe::port::meter::init();
+        poe::port::protect::init();
+        poe::stack::init(&mut cx.core.MPU);
+        poe::efm32gg::devinfo::init();
 
         // Switch to Power Configuration 1 (section 9.3.4.2) - power the digital LDO from DVDD
         emu.pwrctrl.write(|reg| reg.regpwrsel().set_bit());
 
+        // Watch for sagging supplies (e.g. from a marginal PoE power budget)
+        poe::efm32gg::vmon::init(&emu);
+        poe::efm32gg::sleep::init(CORE_HZ);
+
         // Enable the HFRCO
         cmu.oscencmd.write(|reg| reg.hfrcoen().set_bit());
         while cmu.status.read().hfrcoens().bit_is_clear() {}
@@ -237,48 +345,9 @@ mod app {
         #[cfg(feature = "itm")]
         logger.add_itm(poe::log::itm::new(Info, &cmu, &gpio, cx.core.ITM));
 
-        // Configure the HFXO's tuning capacitance to 10 pF
-        cmu.hfxostartupctrl
-            .modify(|_, w| unsafe { w.ctune().bits(15) });
-        cmu.hfxosteadystatectrl
-            .modify(|_, w| unsafe { w.ctune().bits(15) });
-
-        // Enable the HFXO
-        log::trace!("Enabling HFXO...");
-        cmu.oscencmd.write(|reg| reg.hfxoen().set_bit());
-        while cmu.status.read().hfxoens().bit_is_clear() {}
-
-        // Wait for HFX0 to stabilize
-        log::trace!("Waiting for HFXO to stabilize...");
-        while cmu.status.read().hfxordy().bit_is_clear() {}
-
-        log::trace!("Waiting for HXFO tuning...");
-        while cmu.status.read().hfxopeakdetrdy().bit_is_clear() {}
-
-        log::trace!("Waiting for valid IBTRIMXOCORE...");
-        while cmu.hfxotrimstatus.read().valid().bit_is_clear() {}
-        let hfxotrim = cmu.hfxotrimstatus.read().ibtrimxocore().bits();
-        // TODO: Expect to see 0x532 (5*1.28 mA + 50*2 μA)
-        log::debug!("IBTRIMXOCORE: 0x{:04X}", hfxotrim);
-
-        log::trace!("Disabling HFXO...");
-        cmu.oscencmd.write(|reg| reg.hfxodis().set_bit());
-        while cmu.status.read().hfxoens().bit_is_set() {}
-        while cmu.status.read().hfxordy().bit_is_set() {}
-
-        log::trace!("Applying HXFO trim...");
-        cmu.hfxoctrl.modify(|_, w| w.peakdetmode().cmd());
-        cmu.hfxosteadystatectrl
-            .modify(|_, w| unsafe { w.ibtrimxocore().bits(hfxotrim) });
-
-        log::trace!("Re-enabling HFXO...");
-        cmu.oscencmd.write(|reg| reg.hfxoen().set_bit());
-        while cmu.status.read().hfxoens().bit_is_clear() {}
-
-        // Wait for HFX0 to stabilize
-        log::trace!("Waiting for HFXO to stabilize...");
-        while cmu.status.read().hfxordy().bit_is_clear() {}
-        log::trace!("HFXO configured!");
+        // Start the HFXO, which the core runs from. If it never stabilizes, the core stays on the
+        // HFRCO instead (see poe::efm32gg::clock).
+        let hfxo = crate::start_hfxo(&cmu);
 
         // Update the EMU configuration
         let _ = cmu.status.read().bits();
@@ -289,18 +358,39 @@ mod app {
         // Set the appropriate read delay for flash
         cx.device.MSC.readctrl.write(|reg| reg.mode().ws2());
 
-        // Switch to high frequency oscillator
-        log::trace!("Switiching to HFXO...");
-        cmu.hfclksel.write(|reg| reg.hf().hfxo());
-        log::trace!("Using HFXO");
+        match hfxo {
+            Ok(()) => {
+                // Switch to high frequency oscillator
+                log::trace!("Switiching to HFXO...");
+                cmu.hfclksel.write(|reg| reg.hf().hfxo());
+                log::trace!("Using HFXO");
+            }
+            Err(err) => {
+                log::error!(
+                    "HFXO failed ({}); falling back to the HFRCO at 10 Mbps",
+                    err
+                );
+                efm32gg::clock::fall_back(&cmu);
+            }
+        }
 
         // Update the EMU configuration
         let _ = cmu.status.read().bits();
 
+        poe::efm32gg::adc::init(cx.device.ADC0, &cmu, CORE_HZ);
+
         // Enable the RTC and set it to 1000Hz
         cmu.lfaclksel.write(|reg| reg.lfa().ulfrco());
         cmu.lfaclken0.write(|reg| reg.rtc().set_bit());
         rtc.ctrl.write(|reg| reg.en().set_bit());
+        poe::time::init();
+
+        if efm32gg::clock::source() == efm32gg::clock::Source::Hfrco {
+            poe::events::record(poe::time::now(), poe::events::Event::ClockFallback);
+        }
+        if poe::boot::rolled_back() {
+            poe::events::record(poe::time::now(), poe::events::Event::ImageRejected);
+        }
 
         // Enable the TRNG and generate a random seed
         let seed = {
@@ -319,6 +409,14 @@ mod app {
 
             seed
         };
+        poe::auth::init(seed);
+        poe::dhcp::init();
+        poe::routes::init();
+        poe::lifetime::init();
+        poe::config::init();
+        network::events::init();
+        network::events::subscribe(show_link).ignore();
+        poe::hostname::init();
 
         let mut gpio_clk = cmu.constrain().split().gpio;
         gpio_clk.enable();
@@ -338,13 +436,21 @@ mod app {
         let gpio = gpio.split(gpio_clk);
         let _swo = gpio.pf2.as_output();
 
+        // TODO: LOAD_EN isn't routed to the MCU on this revision of the board. Until it is, it
+        // needs to be wired to PE12 (pad 61).
+        let _load_en = gpio.pe12.as_output();
+        poe::port::init(poe::port::Pin {
+            port: poe::port::GpioPort::E,
+            pin: 12,
+        });
+
         let mut led_identify = IdentifyLed::new(CommonAnodeLED::new(gpio.pe4.as_opendrain()));
         let mut led_network = NetworkLed::new(CommonAnodeLED::new(gpio.pe5.as_opendrain()));
 
-        led_identify.enable(false);
+        led_identify.enable(poe::identify::restore());
 
         let mut delay = Delay::new(cx.core.SYST, 19_000_000);
-        let (mac_phy, mac_addr) = EFM32GG::new(
+        let (mut device, mac_addr) = EFM32GG::new(
             dma::RxBuffer::new(
                 Pin::new(cx.local.eth_rx_region),
                 Pin::new(cx.local.eth_rx_descriptors),
@@ -372,25 +478,167 @@ mod app {
         )
         .expect("unable to create MAC/PHY");
 
-        let mut interface = InterfaceBuilder::new(mac_phy, cx.local.sockets.as_mut())
-            .hardware_addr(mac_addr.into())
-            .neighbor_cache(NeighborCache::new(cx.local.neighbors.as_mut()))
-            .ip_addrs(cx.local.ip_addresses.as_mut())
-            .routes(Routes::new(cx.local.routes.as_mut()))
-            .random_seed(seed)
-            .finalize();
-
-        let tcp_handle = interface.add_socket(TcpSocket::new(
-            TcpSocketBuffer::new(cx.local.tcp_rx_payload.as_mut()),
-            TcpSocketBuffer::new(cx.local.tcp_tx_payload.as_mut()),
-        ));
-
-        let dhcp_handle = interface.add_socket(Dhcpv4Socket::new());
+        let mut config = Config::new(HardwareAddress::Ethernet(mac_addr));
+        config.random_seed = seed;
+        let mut interface = Interface::new(config, &mut device, poe::time::now());
+        // The IPv4 address comes first, followed by the link-local and global IPv6 addresses
+        interface.update_ip_addrs(|addrs| {
+            let unspecified = IpCidr::Ipv6(Ipv6Cidr::new(Ipv6Address::UNSPECIFIED, 0));
+            addrs
+                .push(IpCidr::Ipv4(Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, 0)))
+                .and_then(|_| addrs.push(unspecified))
+                .and_then(|_| addrs.push(unspecified))
+                .ignore()
+        });
+        network::enable_ipv6(&mut interface, &mut device);
+
+        let [http_rx_0, http_rx_1] = cx.local.http_rx_payload;
+        let [http_tx_0, http_tx_1] = cx.local.http_tx_payload;
+        poe::add_sockets!(cx.local.sockets => sockets {
+            Control: tcp_handle = tcp::Socket::new(
+                tcp::SocketBuffer::new(cx.local.tcp_rx_payload.as_mut()),
+                tcp::SocketBuffer::new(cx.local.tcp_tx_payload.as_mut()),
+            ),
+            Syslog: syslog_handle = udp::Socket::new(
+                udp::PacketBuffer::new(
+                    cx.local.syslog_rx_metadata.as_mut(),
+                    cx.local.syslog_rx_payload.as_mut(),
+                ),
+                udp::PacketBuffer::new(
+                    cx.local.syslog_tx_metadata.as_mut(),
+                    cx.local.syslog_tx_payload.as_mut(),
+                ),
+            ),
+            Probe: probe_handle = icmp::Socket::new(
+                icmp::PacketBuffer::new(
+                    cx.local.probe_rx_metadata.as_mut(),
+                    cx.local.probe_rx_payload.as_mut(),
+                ),
+                icmp::PacketBuffer::new(
+                    cx.local.probe_tx_metadata.as_mut(),
+                    cx.local.probe_tx_payload.as_mut(),
+                ),
+            ),
+            Snmp: snmp_handle = udp::Socket::new(
+                udp::PacketBuffer::new(
+                    cx.local.snmp_rx_metadata.as_mut(),
+                    cx.local.snmp_rx_payload.as_mut(),
+                ),
+                udp::PacketBuffer::new(
+                    cx.local.snmp_tx_metadata.as_mut(),
+                    cx.local.snmp_tx_payload.as_mut(),
+                ),
+            ),
+            Coap: coap_handle = udp::Socket::new(
+                udp::PacketBuffer::new(
+                    cx.local.coap_rx_metadata.as_mut(),
+                    cx.local.coap_rx_payload.as_mut(),
+                ),
+                udp::PacketBuffer::new(
+                    cx.local.coap_tx_metadata.as_mut(),
+                    cx.local.coap_tx_payload.as_mut(),
+                ),
+            ),
+            NeighborDiscovery: ndisc_handle = raw::Socket::new(
+                IpVersion::Ipv6,
+                IpProtocol::Icmpv6,
+                raw::PacketBuffer::new(
+                    cx.local.ndisc_rx_metadata.as_mut(),
+                    cx.local.ndisc_rx_payload.as_mut(),
+                ),
+                raw::PacketBuffer::new(
+                    cx.local.ndisc_tx_metadata.as_mut(),
+                    cx.local.ndisc_tx_payload.as_mut(),
+                ),
+            ),
+            Fleet: fleet_handle = udp::Socket::new(
+                udp::PacketBuffer::new(
+                    cx.local.fleet_rx_metadata.as_mut(),
+                    cx.local.fleet_rx_payload.as_mut(),
+                ),
+                udp::PacketBuffer::new(
+                    cx.local.fleet_tx_metadata.as_mut(),
+                    cx.local.fleet_tx_payload.as_mut(),
+                ),
+            ),
+            Discovery: discovery_handle = udp::Socket::new(
+                udp::PacketBuffer::new(
+                    cx.local.discovery_rx_metadata.as_mut(),
+                    cx.local.discovery_rx_payload.as_mut(),
+                ),
+                udp::PacketBuffer::new(
+                    cx.local.discovery_tx_metadata.as_mut(),
+                    cx.local.discovery_tx_payload.as_mut(),
+                ),
+            ),
+            Capture: capture_handle = tcp::Socket::new(
+                tcp::SocketBuffer::new(cx.local.capture_rx_payload.as_mut()),
+                tcp::SocketBuffer::new(cx.local.capture_tx_payload.as_mut()),
+            ),
+            Http: http_handles = [
+                tcp::Socket::new(
+                    tcp::SocketBuffer::new(http_rx_0.as_mut()),
+                    tcp::SocketBuffer::new(http_tx_0.as_mut()),
+                ),
+                tcp::Socket::new(
+                    tcp::SocketBuffer::new(http_rx_1.as_mut()),
+                    tcp::SocketBuffer::new(http_tx_1.as_mut()),
+                ),
+            ],
+            Modbus: modbus_handle = tcp::Socket::new(
+                tcp::SocketBuffer::new(cx.local.modbus_rx_payload.as_mut()),
+                tcp::SocketBuffer::new(cx.local.modbus_tx_payload.as_mut()),
+            ),
+            Tftp: tftp_handle = udp::Socket::new(
+                udp::PacketBuffer::new(
+                    cx.local.tftp_rx_metadata.as_mut(),
+                    cx.local.tftp_rx_payload.as_mut(),
+                ),
+                udp::PacketBuffer::new(
+                    cx.local.tftp_tx_metadata.as_mut(),
+                    cx.local.tftp_tx_payload.as_mut(),
+                ),
+            ),
+            TftpTransfer: tftp_transfer_handle = udp::Socket::new(
+                udp::PacketBuffer::new(
+                    cx.local.tftp_transfer_rx_metadata.as_mut(),
+                    cx.local.tftp_transfer_rx_payload.as_mut(),
+                ),
+                udp::PacketBuffer::new(
+                    cx.local.tftp_transfer_tx_metadata.as_mut(),
+                    cx.local.tftp_transfer_tx_payload.as_mut(),
+                ),
+            ),
+            Dhcp: dhcp_handle = dhcpv4::Socket::new(),
+        });
         led_network.show(network::State::NoLink);
 
         #[cfg(feature = "rtt")]
         handle_terminal::spawn().expect("spawn handle_terminal");
 
+        // Offer the terminal over USB
+        #[cfg(feature = "usb")]
+        let usb_terminal = {
+            use poe::efm32gg::usb::{self, UsbBus};
+            use usb_device::class_prelude::UsbBusAllocator;
+
+            let memory = cortex_m::singleton!(: [u32; 1024] = [0; 1024]).expect("USB memory");
+            let allocator = UsbBus::new(usb::new(cx.device.USB, CORE_HZ), memory);
+            let bus = cortex_m::singleton!(: UsbBusAllocator<UsbBus> = allocator).expect("USB bus");
+            poe::log::usb::Terminal::new(bus)
+        };
+
+        report_stack::spawn().expect("spawning report_stack");
+        poll_sensors::spawn().expect("spawning poll_sensors");
+        poll_port::spawn().expect("spawning poll_port");
+        schedule!(
+            confirm_image,
+            (poe::boot::HEALTHY_AFTER_SECS * 1000).millis()
+        );
+
+        // From here on, write out log records from a low-priority task
+        logger.defer(|| flush_logs::spawn().ignore());
+
         let syst = delay.free();
         (
             SharedResources {
@@ -398,157 +646,316 @@ mod app {
                 led_network,
                 network: network::Resources {
                     interface,
+                    device,
+                    sockets,
                     dhcp_handle,
                     tcp_handle,
+                    syslog_handle: Some(syslog_handle),
+                    probe_handle: Some(probe_handle),
+                    snmp_handle: Some(snmp_handle),
+                    coap_handle: Some(coap_handle),
+                    ndisc_handle: Some(ndisc_handle),
+                    fleet_handle: Some(fleet_handle),
+                    discovery_handle: Some(discovery_handle),
+                    capture_handle: Some(capture_handle),
+                    http_handles: Some(http_handles),
+                    modbus_handle: Some(modbus_handle),
+                    tftp_handle: Some(tftp_handle),
+                    tftp_transfer_handle: Some(tftp_transfer_handle),
                 },
-                rtc,
             },
             LocalResources {
                 spawn: None,
 
                 #[cfg(feature = "rtt")]
                 terminal: poe::log::rtt::Terminal::new(),
+
+                #[cfg(feature = "usb")]
+                usb_terminal,
             },
-            init::Monotonics(Monotonic::new(
-                &mut cx.core.DCB,
-                cx.core.DWT,
-                syst,
-                25_000_000,
-            )),
+            init::Monotonics(Monotonic::new(&mut cx.core.DCB, cx.core.DWT, syst, CORE_HZ)),
         )
     }
 
-    #[task(capacity = 2, local = [spawn], shared = [led_identify, led_network, network, rtc])]
-    fn handle_network(mut cx: handle_network::Context) {
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        poe::efm32gg::sleep::idle()
+    }
+
+    #[task(capacity = 2, local = [spawn], shared = [led_identify, led_network, network])]
+    fn handle_network(cx: handle_network::Context) {
+        let _timing = poe::efm32gg::timing::start("handle_network");
         log::trace!("Handling network...");
 
-        let timestamp = Instant::from_millis(cx.shared.rtc.lock(|rtc| rtc.cnt.read().cnt().bits()));
+        let timestamp = poe::time::now();
         let spawn = cx.local.spawn;
         let mut led_id = cx.shared.led_identify;
         let mut led_net = cx.shared.led_network;
         let mut network = cx.shared.network;
 
-        match network.lock(|network| network.interface.poll(timestamp)) {
-            Ok(true) => {
-                log::trace!("Handling sockets...");
-
-                network.lock(|network| {
-                    network.handle_sockets(
-                        |state| led_net.lock(|led| led.show(state)),
-                        |en| led_id.lock(|led| led.enable(en)),
-                    )
-                });
+        while let Some(event) = poe::work::take() {
+            log::trace!("Handling {}", event);
+            match event {
+                // Frames are picked up (and their buffers reused) by the poll below
+                Event::RxComplete | Event::TxComplete => {}
+                Event::PhyIrq => {
+                    let settle = network.lock(|network| network.device.phy_irq(poe::time::now()));
+
+                    // If the link is already being debounced, that task reschedules itself as
+                    // needed
+                    debounce_link::spawn_after((settle.total_millis() as u32).millis()).ignore();
+                }
             }
-            Ok(false) => log::trace!("Nothing to do"),
-            Err(err) => log::error!("Failed to poll network interface: {}", err),
         }
 
-        if let Some(delay) = network.lock(|network| network.interface.poll_delay(timestamp)) {
+        let changed = network.lock(|network| {
+            network.handle_syslog(timestamp);
+            network.handle_capture();
+            network.handle_probe(timestamp);
+            network.handle_lldp(timestamp);
+            network.handle_wol(timestamp);
+            network.handle_announce(timestamp);
+            network.handle_ptp(timestamp);
+            network.handle_self_test(timestamp);
+            network.handle_eee();
+            network.handle_media();
+            network.handle_config(timestamp, |state| led_net.lock(|led| led.show(state)));
+            network.handle_dhcp_retry(timestamp);
+            network.handle_routes();
+            network.handle_beacons(timestamp);
+            network.handle_traps(timestamp);
+            network.handle_coap_observers();
+            network.handle_http_streams();
+            network.handle_tftp_transfer(timestamp);
+            network.handle_slaac(timestamp);
+            network.poll(timestamp)
+        });
+        if changed {
+            log::trace!("Handling sockets...");
+
+            network.lock(|network| {
+                network.handle_sockets(
+                    timestamp,
+                    |state| led_net.lock(|led| led.show(state)),
+                    |pattern| led_id.lock(|led| led.enable(pattern)),
+                )
+            });
+        } else {
+            log::trace!("Nothing to do");
+        }
+
+        if let Some(delay) = network.lock(|network| network.poll_delay(timestamp)) {
             log::trace!("Scheduling network handling in {}", delay);
 
             let delay = (delay.total_millis() as u32).millis();
             *spawn = spawn
                 .take()
                 .and_then(|h| h.reschedule_after(delay).ok())
-                .or_else(|| Some(schedule!(handle_network, delay)));
+                .or_else(|| schedule!(handle_network, delay));
         }
 
         log::trace!("Handled sockets: {}", timestamp);
     }
 
+    #[task]
+    fn flush_logs(_: flush_logs::Context) {
+        let _timing = poe::efm32gg::timing::start("flush_logs");
+        poe::log::flush_deferred();
+    }
+
+    #[task(local = [reported: usize = 0])]
+    fn report_stack(cx: report_stack::Context) {
+        let _timing = poe::efm32gg::timing::start("report_stack");
+        let used = poe::stack::high_water();
+        if used > *cx.local.reported {
+            log::info!(
+                "Stack high-water mark: {} of {} bytes",
+                used,
+                poe::stack::size()
+            );
+            *cx.local.reported = used;
+        }
+        schedule!(report_stack, 60_000u32.millis());
+    }
+
+    #[task]
+    fn confirm_image(_: confirm_image::Context) {
+        let _timing = poe::efm32gg::timing::start("confirm_image");
+        if let Err(err) = poe::boot::confirm() {
+            log::error!("Failed to mark the image healthy: {}", err);
+        }
+    }
+
+    #[task]
+    fn poll_sensors(_: poll_sensors::Context) {
+        let _timing = poe::efm32gg::timing::start("poll_sensors");
+        poe::sensors::poll();
+        poe::identify::persist(poe::time::now());
+        poe::lifetime::persist(poe::time::now());
+        poe::config::persist();
+        poe::dhcp::persist();
+        poe::port::meter::persist(poe::time::now());
+        schedule!(poll_sensors, 10_000u32.millis());
+    }
+
+    #[task]
+    fn poll_port(_: poll_port::Context) {
+        let _timing = poe::efm32gg::timing::start("poll_port");
+        poe::port::poll();
+        poe::port::meter::sample(poe::time::now());
+        poe::port::protect::poll(poe::time::now());
+        poe::events::flush();
+        let probe = poe::port::schedule::poll(poe::time::now());
+        let lldp = poe::lldp::poll(poe::time::now());
+        let slaac = poe::slaac::due(poe::time::now());
+        let beacon = poe::discovery::due(poe::time::now());
+        let transfer = poe::tftp::due(poe::time::now());
+        let trap = poe::events::trap_pending() && poe::snmp::trap_receiver().is_some();
+        poe::ptp::poll(poe::time::now());
+        poe::efm32gg::traffic::tick(poe::time::now());
+        poe::config::poll(poe::time::now());
+        if probe
+            || lldp
+            || slaac
+            || beacon
+            || trap
+            || transfer
+            || poe::wol::pending()
+            || poe::ptp::due()
+            || poe::selftest::pending()
+            || poe::capture::pending()
+            || poe::eee::pending()
+            || poe::media::pending()
+            || poe::config::pending()
+            || poe::http::streaming()
+            || poe::work::pending()
+            || poe::dhcp::due(poe::time::now())
+            || network::announcement_pending()
+            || poe::neighbors::refresh_due(poe::time::now())
+            || poe::routes::pending()
+        {
+            handle_network::spawn().ignore();
+        }
+        schedule!(poll_port, 100u32.millis());
+    }
+
+    #[task(binds = EMU)]
+    fn emu_irq(_: emu_irq::Context) {
+        let _timing = poe::efm32gg::timing::start("emu_irq");
+        poe::efm32gg::vmon::irq();
+    }
+
+    #[task(binds = RTC)]
+    fn rtc_irq(_: rtc_irq::Context) {
+        let _timing = poe::efm32gg::timing::start("rtc_irq");
+        poe::time::irq();
+    }
+
     #[task(binds = ETH, shared = [network])]
     fn eth_irq(mut cx: eth_irq::Context) {
+        let _timing = poe::efm32gg::timing::start("eth_irq");
         interrupt::free(|_| {
             cx.shared.network.lock(|network| {
-                network.interface.device_mut().mac_irq();
+                network.device.mac_irq();
             })
         });
 
         handle_network::spawn().ignore();
     }
 
-    #[task(binds = GPIO_ODD, shared = [led_network, network])]
-    fn gpio_odd_irq(mut cx: gpio_odd_irq::Context) {
-        use network::State::*;
-
+    #[task(binds = GPIO_ODD)]
+    fn gpio_odd_irq(_: gpio_odd_irq::Context) {
+        let _timing = poe::efm32gg::timing::start("gpio_odd_irq");
         // Clear the PHY interrupt
         (unsafe { &*efm32gg11b820::GPIO::ptr() })
             .ifc
             .write(|w| unsafe { w.ext().bits(1 << 13) });
 
-        // TODO: This probably should be deferred since it's reading from the PHY
-        let mut led = cx.shared.led_network;
-        cx.shared.network.lock(|network| {
-            led.lock(|led| {
-                let device = network.interface.device_mut();
-                device.phy_irq();
-
-                match (device.link_state().is_some(), led.network) {
-                    (true, NoLink) => {
-                        log::debug!("Link acquired");
-                        led.show(NoDhcp);
-                        network.reset_dhcp();
-                    }
-                    (false, _) => {
-                        log::debug!("Link lost");
-                        led.show(NoLink);
-                    }
-                    _ => {}
-                }
-            });
+        // Reading the PHY's interrupt status takes a few MDIO transactions, so leave it to the
+        // network task
+        poe::work::post(Event::PhyIrq);
+        handle_network::spawn().ignore();
+    }
+
+    #[task(shared = [network])]
+    fn debounce_link(mut cx: debounce_link::Context) {
+        use poe::efm32gg::LinkEvent;
+
+        let _timing = poe::efm32gg::timing::start("debounce_link");
+
+        let event = cx.shared.network.lock(|network| {
+            let event = network.device.poll_link(poe::time::now());
+            match event {
+                LinkEvent::Up => network.link_changed(poe::time::now(), true),
+                LinkEvent::Down => network.link_changed(poe::time::now(), false),
+                LinkEvent::Settling(_) | LinkEvent::Unchanged => {}
+            }
+            event
         });
-        // TODO: Why is the one-second delay necessary? 100 ms doesn't work.
-        handle_network::spawn_after(1000u32.millis()).ignore();
+
+        match event {
+            LinkEvent::Settling(delay) => {
+                debounce_link::spawn_after((delay.total_millis() as u32).millis()).ignore()
+            }
+            LinkEvent::Up | LinkEvent::Down => handle_network::spawn().ignore(),
+            LinkEvent::Unchanged => {}
+        }
     }
 
     #[cfg(feature = "rtt")]
-    #[task(local = [terminal])]
-    fn handle_terminal(cx: handle_terminal::Context) {
+    #[task(local = [terminal], shared = [led_identify])]
+    fn handle_terminal(mut cx: handle_terminal::Context) {
+        let _timing = poe::efm32gg::timing::start("handle_terminal");
         cx.local.terminal.poll();
-        handle_terminal::spawn_after(100u32.millis()).expect("schedule handle_terminal");
+        if let Some(pattern) = poe::identify::take_request() {
+            cx.shared.led_identify.lock(|led| led.enable(pattern));
+        }
+        schedule!(handle_terminal, 100u32.millis());
+    }
+
+    #[cfg(feature = "usb")]
+    #[task(binds = USB, local = [usb_terminal], shared = [led_identify])]
+    fn usb_irq(mut cx: usb_irq::Context) {
+        let _timing = poe::efm32gg::timing::start("usb_irq");
+        cx.local.usb_terminal.poll();
+        if let Some(pattern) = poe::identify::take_request() {
+            cx.shared.led_identify.lock(|led| led.enable(pattern));
+        }
     }
 }
 
-// Light up both LEDs, trigger a breakpoint, and loop
+// Light up both LEDs, record the fault, and break or reset
 #[cortex_m_rt::exception]
 fn DefaultHandler(irqn: i16) {
     use mono::State::*;
 
     interrupt::disable();
+    poe::log::disable_deferral();
 
     log::error!("Default Handler: irq {}", irqn);
+    poe::fault::record_unhandled_irq(poe::time::now(), irqn);
     let (mut id, mut net) = unsafe { steal_leds() };
     id.set(On);
     net.set(On);
 
-    if peripheral::DCB::is_debugger_attached() {
-        asm::bkpt();
-    }
-
-    loop {
-        asm::wfe();
-    }
+    poe::fault::end()
 }
 
-// Light up both LEDs, trigger a breakpoint, and loop
+// Light up both LEDs, record the fault, and break or reset
 #[cortex_m_rt::exception]
 fn HardFault(frame: &cortex_m_rt::ExceptionFrame) -> ! {
     use mono::State::*;
 
     interrupt::disable();
+    poe::log::disable_deferral();
 
     log::error!("Hard Fault: {:?}", frame);
+    poe::fault::record_hard_fault(poe::time::now(), frame);
     let (mut id, mut net) = unsafe { steal_leds() };
     id.set(On);
     net.set(On);
 
-    if peripheral::DCB::is_debugger_attached() {
-        asm::bkpt();
-    }
-
-    loop {
-        asm::wfe();
-    }
+    poe::fault::end()
 }
 
 /// Steals the LEDs so they may be used directly.
@@ -566,25 +973,83 @@ pub unsafe fn steal_leds() -> (IdentifyLed, NetworkLed) {
     (id, net)
 }
 
+// Starts the HFXO and applies its core bias trim, giving up if any step of its startup times out.
+// The core is still running from the HFRCO's reset band (19 MHz).
+fn start_hfxo(cmu: &efm32gg11b820::CMU) -> Result<(), &'static str> {
+    use poe::efm32gg::clock;
+
+    const HFRCO_HZ: u32 = 19_000_000;
+
+    // Configure the HFXO's tuning capacitance to 10 pF
+    cmu.hfxostartupctrl
+        .modify(|_, w| unsafe { w.ctune().bits(15) });
+    cmu.hfxosteadystatectrl
+        .modify(|_, w| unsafe { w.ctune().bits(15) });
+
+    // Enable the HFXO
+    log::trace!("Enabling HFXO...");
+    cmu.oscencmd.write(|reg| reg.hfxoen().set_bit());
+    clock::wait(HFRCO_HZ, || cmu.status.read().hfxoens().bit_is_set())
+        .map_err(|_| "not enabled")?;
+
+    // Wait for HFX0 to stabilize
+    log::trace!("Waiting for HFXO to stabilize...");
+    clock::wait(HFRCO_HZ, || cmu.status.read().hfxordy().bit_is_set())
+        .map_err(|_| "never stabilized")?;
+
+    log::trace!("Waiting for HXFO tuning...");
+    clock::wait(HFRCO_HZ, || cmu.status.read().hfxopeakdetrdy().bit_is_set())
+        .map_err(|_| "peak detection never finished")?;
+
+    log::trace!("Waiting for valid IBTRIMXOCORE...");
+    clock::wait(HFRCO_HZ, || cmu.hfxotrimstatus.read().valid().bit_is_set())
+        .map_err(|_| "no valid IBTRIMXOCORE")?;
+    let hfxotrim = cmu.hfxotrimstatus.read().ibtrimxocore().bits();
+    // TODO: Expect to see 0x532 (5*1.28 mA + 50*2 μA)
+    log::debug!("IBTRIMXOCORE: 0x{:04X}", hfxotrim);
+
+    log::trace!("Disabling HFXO...");
+    cmu.oscencmd.write(|reg| reg.hfxodis().set_bit());
+    clock::wait(HFRCO_HZ, || {
+        let status = cmu.status.read();
+        status.hfxoens().bit_is_clear() && status.hfxordy().bit_is_clear()
+    })
+    .map_err(|_| "not disabled")?;
+
+    log::trace!("Applying HXFO trim...");
+    cmu.hfxoctrl.modify(|_, w| w.peakdetmode().cmd());
+    cmu.hfxosteadystatectrl
+        .modify(|_, w| unsafe { w.ibtrimxocore().bits(hfxotrim) });
+
+    log::trace!("Re-enabling HFXO...");
+    cmu.oscencmd.write(|reg| reg.hfxoen().set_bit());
+    clock::wait(HFRCO_HZ, || cmu.status.read().hfxoens().bit_is_set())
+        .map_err(|_| "not re-enabled")?;
+
+    // Wait for HFX0 to stabilize
+    log::trace!("Waiting for HFXO to stabilize...");
+    clock::wait(HFRCO_HZ, || cmu.status.read().hfxordy().bit_is_set())
+        .map_err(|_| "never stabilized after trimming")?;
+    log::trace!("HFXO configured!");
+
+    Ok(())
+}
+
+#[cfg(not(feature = "panic-never"))]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     use mono::State::*;
 
     cortex_m::interrupt::disable();
+    poe::log::disable_deferral();
 
-    let rtc = unsafe { &*efm32gg11b820::RTC::ptr() };
-    let now = Instant::from_millis(rtc.cnt.read().cnt().bits());
-
-    log::error!("Panic at {}", now);
+    log::error!("Panic at {}", poe::time::now());
     log::error!("{}", info);
+    poe::fault::record_panic(poe::time::now(), info);
 
     let (mut id, mut net) = unsafe { steal_leds() };
     id.set(On);
     net.set(On);
 
-    if cortex_m::peripheral::DCB::is_debugger_attached() {
-        asm::bkpt();
-    }
-
-    loop {}
+    poe::fault::end()
 }
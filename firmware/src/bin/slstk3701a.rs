@@ -17,26 +17,50 @@
 #![no_std]
 
 /// Sandbox for development on the SLSTK3701A dev board
-use cortex_m::{asm, interrupt, peripheral};
+use cortex_m::interrupt;
 use efm32gg_hal::cmu::CMUExt;
 use efm32gg_hal::gpio::{pins, EFM32Pin, GPIOExt, Output};
 use ignore_result::Ignore;
 use led::rgb::{self, Color};
-use smoltcp::time::Instant;
 
 type LED0 = rgb::CommonAnodeLED<pins::PH10<Output>, pins::PH11<Output>, pins::PH12<Output>, ()>;
 type LED1 = rgb::CommonAnodeLED<pins::PH13<Output>, pins::PH14<Output>, pins::PH15<Output>, ()>;
 
+#[cfg(feature = "uart")]
+type UartTerminal = poe::log::uart::Terminal<efm32gg11b820::USART4>;
+// RTIC asserts that every resource is Send without regard to its #[cfg], so this needs to name
+// some type even without the feature
+#[cfg(not(feature = "uart"))]
+type UartTerminal = ();
+
+/// LED0, lit yellow while identifying
+pub struct IdentifyLight(LED0);
+
+impl poe::led_manager::Light for IdentifyLight {
+    fn set_lit(&mut self, lit: bool) {
+        self.0
+            .set(match lit {
+                true => Color::Yellow,
+                false => Color::Black,
+            })
+            .ignore()
+    }
+}
+
 #[rtic::app(
     dispatchers = [ CAN0, CAN1 ],
     device = efm32gg11b820,
     peripherals = true,
 )]
 mod app {
+    use ksz8091::KSZ8091;
+    use poe::button::{Button, Press};
     use poe::efm32gg::{self, dma};
-    use poe::ksz8091::KSZ8091;
+    use poe::led_manager::{Indicator, Mode};
     use poe::network;
+    use poe::work::Event;
 
+    use core::cmp;
     use core::pin::Pin;
     use cortex_m::{delay::Delay, interrupt};
     use efm32gg_hal::cmu::CMUExt;
@@ -44,20 +68,25 @@ mod app {
     use embedded_hal::digital::v2::OutputPin;
     use ignore_result::Ignore;
     use led::rgb::{self, Color};
-    use smoltcp::iface::{InterfaceBuilder, Neighbor, NeighborCache, Route, Routes, SocketStorage};
-    use smoltcp::socket::{Dhcpv4Socket, TcpSocket, TcpSocketBuffer};
-    use smoltcp::time::{Duration, Instant};
-    use smoltcp::wire::{IpAddress, IpCidr, Ipv4Address, Ipv4Cidr};
+    use smoltcp::iface::{Config, Interface};
+    use smoltcp::socket::{dhcpv4, tcp, udp};
+    use smoltcp::time::Duration;
+    use smoltcp::wire::{HardwareAddress, IpCidr, Ipv4Address, Ipv4Cidr};
+
+    // The core clock's frequency
+    const CORE_HZ: u32 = 50_000_000;
 
     #[monotonic(binds = SysTick, default = true)]
-    type Monotonic = dwt_systick_monotonic::DwtSystick<50_000_000>; // 50 MHz
+    type Monotonic = dwt_systick_monotonic::DwtSystick<CORE_HZ>;
 
     #[shared]
     struct SharedResources {
-        led0: crate::LED0,
+        led_identify: IdentifyLed,
         led1: crate::LED1,
         network: network::Resources,
-        rtc: efm32gg11b820::RTC,
+        #[cfg(feature = "uart")]
+        #[lock_free]
+        uart_terminal: crate::UartTerminal,
     }
 
     #[local]
@@ -67,25 +96,38 @@ mod app {
 
     #[init(
         local = [
-             eth_rx_region: dma::RxRegion = dma::RxRegion([0; 1536]),
+             eth_rx_region: dma::RxRegion = dma::RxRegion::new(),
              eth_tx_region: dma::TxRegion = dma::TxRegion([0; 1536]),
              eth_rx_descriptors: dma::RxDescriptors = dma::RxDescriptors::new(),
              eth_tx_descriptors: dma::TxDescriptors = dma::TxDescriptors::new(),
              tcp_rx_payload: [u8; 1024] = [0; 1024],
              tcp_tx_payload: [u8; 1024] = [0; 1024],
+             syslog_rx_metadata: [udp::PacketMetadata; 1] = [udp::PacketMetadata::EMPTY; 1],
+             syslog_rx_payload: [u8; 0] = [0; 0],
+             syslog_tx_metadata: [udp::PacketMetadata; 4] = [udp::PacketMetadata::EMPTY; 4],
+             syslog_tx_payload: [u8; 1024] = [0; 1024],
 
-             neighbors: [Option<(IpAddress, Neighbor)>; 8] = [None; 8],
-             sockets: [SocketStorage<'static>; 2] = [SocketStorage::EMPTY; 2],
-             ip_addresses: [IpCidr; 1] =
-                [IpCidr::Ipv4(Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, 0))],
-            routes: [Option<(IpCidr, Route)>; 1] = [None; 1],
+             sockets: network::SocketPool<3> = network::SocketPool::new(),
         ]
     )]
     fn init(mut cx: init::Context) -> (SharedResources, LocalResources, init::Monotonics) {
         // Initialize logging
         let logger = poe::log::init();
+        logger.add_memory(poe::log::memory::new(log::LevelFilter::Debug));
+        logger.add_syslog(poe::log::syslog::new(log::LevelFilter::Info));
         #[cfg(feature = "rtt")]
         logger.add_rtt(poe::log::rtt::new(log::LevelFilter::Debug));
+        #[cfg(feature = "defmt")]
+        logger.add_defmt(poe::log::defmt::new(log::LevelFilter::Debug));
+        #[cfg(feature = "uart")]
+        logger.add_uart(poe::log::uart::new(log::LevelFilter::Info));
+
+        poe::fault::init();
+        poe::efm32gg::rmu::init(&cx.device.RMU);
+        poe::stack::init(&mut cx.core.MPU);
+        poe::efm32gg::devinfo::init();
+        poe::efm32gg::vmon::init(&cx.device.EMU);
+        poe::efm32gg::sleep::init(CORE_HZ);
 
         // Enable the HFXO
         cx.device.CMU.oscencmd.write(|reg| reg.hfxoen().set_bit());
@@ -127,10 +169,13 @@ mod app {
             cx.core.ITM,
         ));
 
+        poe::efm32gg::adc::init(cx.device.ADC0, &cx.device.CMU, CORE_HZ);
+
         // Enable the RTC and set it to 1000Hz
         cx.device.CMU.lfaclksel.write(|reg| reg.lfa().ulfrco());
         cx.device.CMU.lfaclken0.write(|reg| reg.rtc().set_bit());
         cx.device.RTC.ctrl.write(|reg| reg.en().set_bit());
+        poe::time::init();
 
         // Enable the TRNG and generate a random seed
         let seed = {
@@ -148,6 +193,30 @@ mod app {
 
             seed
         };
+        poe::auth::init(seed);
+        poe::dhcp::init();
+        poe::routes::init();
+        poe::lifetime::init();
+        poe::config::init();
+        network::events::init();
+        poe::hostname::init();
+
+        // The virtual COM port is USART4 at location 4, with TX on PH4 and RX on PH5. The pins are
+        // configured once the GPIOs are split, below.
+        #[cfg(feature = "uart")]
+        let usart = {
+            const TX_LOC: u8 = 4;
+            const RX_LOC: u8 = 4;
+
+            poe::efm32gg::usart::Usart::new(
+                cx.device.USART4,
+                &cx.device.CMU,
+                CORE_HZ,
+                poe::log::uart::BAUD,
+                TX_LOC,
+                RX_LOC,
+            )
+        };
 
         let mut gpio_clk = cx.device.CMU.constrain().split().gpio;
         gpio_clk.enable();
@@ -178,6 +247,35 @@ mod app {
             .ien
             .write(|w| unsafe { w.ext().bits(1 << 15) });
 
+        // Configure PC8 as an input and enable interrupts on both edges. This is connected to BTN0,
+        // which is pulled up and pressed low.
+        cx.device.GPIO.pc_modeh.modify(|_, w| w.mode8().input());
+        cx.device
+            .GPIO
+            .extipselh
+            .modify(|_, w| w.extipsel8().portc());
+        cx.device
+            .GPIO
+            .extipinselh
+            .modify(|_, w| w.extipinsel8().pin8());
+        cx.device
+            .GPIO
+            .extirise
+            .modify(|r, w| unsafe { w.extirise().bits(r.extirise().bits() | 1 << 8) });
+        cx.device
+            .GPIO
+            .extifall
+            .modify(|r, w| unsafe { w.extifall().bits(r.extifall().bits() | 1 << 8) });
+        cx.device
+            .GPIO
+            .ifc
+            .write(|w| unsafe { w.ext().bits(1 << 8) });
+        efm32gg11b820::NVIC::unpend(efm32gg11b820::Interrupt::GPIO_EVEN);
+        cx.device
+            .GPIO
+            .ien
+            .modify(|r, w| unsafe { w.ext().bits(r.ext().bits() | 1 << 8) });
+
         let gpio = cx.device.GPIO.split(gpio_clk);
 
         let mut led0 = rgb::CommonAnodeLED::new(
@@ -213,11 +311,22 @@ mod app {
             log::info!("Logger online!");
         };
 
+        // Run the terminal on the board controller's virtual COM port. PE1 enables the board
+        // controller's side of the port.
+        #[cfg(feature = "uart")]
+        let uart_terminal = {
+            gpio.ph4.as_output().set_high().ignore();
+            gpio.ph5.as_input();
+            gpio.pe1.as_output().set_high().ignore();
+
+            poe::log::uart::Terminal::new(usart)
+        };
+
         // Power up the PHY module
         gpio.pi10.as_output().set_high().ignore();
 
-        let mut delay = Delay::new(cx.core.SYST, 50_000_000);
-        let (mac_phy, mac_addr) = efm32gg::EFM32GG::new(
+        let mut delay = Delay::new(cx.core.SYST, CORE_HZ);
+        let (mut device, mac_addr) = efm32gg::EFM32GG::new(
             dma::RxBuffer::new(
                 Pin::new(cx.local.eth_rx_region),
                 Pin::new(cx.local.eth_rx_descriptors),
@@ -245,83 +354,149 @@ mod app {
         )
         .expect("unable to create MAC/PHY");
 
-        let mut interface = InterfaceBuilder::new(mac_phy, cx.local.sockets.as_mut())
-            .hardware_addr(mac_addr.into())
-            .neighbor_cache(NeighborCache::new(cx.local.neighbors.as_mut()))
-            .ip_addrs(cx.local.ip_addresses.as_mut())
-            .routes(Routes::new(cx.local.routes.as_mut()))
-            .random_seed(seed)
-            .finalize();
-
-        let tcp_handle = interface.add_socket(TcpSocket::new(
-            TcpSocketBuffer::new(cx.local.tcp_rx_payload.as_mut()),
-            TcpSocketBuffer::new(cx.local.tcp_tx_payload.as_mut()),
-        ));
+        let mut config = Config::new(HardwareAddress::Ethernet(mac_addr));
+        config.random_seed = seed;
+        let mut interface = Interface::new(config, &mut device, poe::time::now());
+        interface.update_ip_addrs(|addrs| {
+            addrs
+                .push(IpCidr::Ipv4(Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, 0)))
+                .ignore()
+        });
 
-        let mut dhcp_socket = Dhcpv4Socket::new();
+        let mut dhcp_socket = dhcpv4::Socket::new();
         // XXX: just for testing
         dhcp_socket.set_max_lease_duration(Some(Duration::from_secs(60)));
-        let dhcp_handle = interface.add_socket(dhcp_socket);
+
+        poe::add_sockets!(cx.local.sockets => sockets {
+            Control: tcp_handle = tcp::Socket::new(
+                tcp::SocketBuffer::new(cx.local.tcp_rx_payload.as_mut()),
+                tcp::SocketBuffer::new(cx.local.tcp_tx_payload.as_mut()),
+            ),
+            Syslog: syslog_handle = udp::Socket::new(
+                udp::PacketBuffer::new(
+                    cx.local.syslog_rx_metadata.as_mut(),
+                    cx.local.syslog_rx_payload.as_mut(),
+                ),
+                udp::PacketBuffer::new(
+                    cx.local.syslog_tx_metadata.as_mut(),
+                    cx.local.syslog_tx_payload.as_mut(),
+                ),
+            ),
+            Dhcp: dhcp_handle = dhcp_socket,
+        });
+
+        report_stack::spawn().expect("spawning report_stack");
+        poll_sensors::spawn().expect("spawning poll_sensors");
+
+        // From here on, write out log records from a low-priority task
+        logger.defer(|| flush_logs::spawn().ignore());
+
+        let mut led_identify = IdentifyLed {
+            indicator: Indicator::new(crate::IdentifyLight(led0), Mode::Solid(false)),
+            spawn: None,
+        };
+        led_identify.enable(poe::identify::restore());
 
         let syst = delay.free();
         (
             SharedResources {
-                led0,
+                led_identify,
                 led1,
                 network: network::Resources {
                     interface,
+                    device,
+                    sockets,
                     tcp_handle,
                     dhcp_handle,
+                    syslog_handle: Some(syslog_handle),
+                    probe_handle: None,
+                    snmp_handle: None,
+                    coap_handle: None,
+                    ndisc_handle: None,
+                    fleet_handle: None,
+                    discovery_handle: None,
+                    capture_handle: None,
+                    http_handles: None,
+                    modbus_handle: None,
+                    tftp_handle: None,
+                    tftp_transfer_handle: None,
                 },
-                rtc: cx.device.RTC,
+                #[cfg(feature = "uart")]
+                uart_terminal,
             },
             LocalResources { spawn_handle: None },
-            init::Monotonics(Monotonic::new(
-                &mut cx.core.DCB,
-                cx.core.DWT,
-                syst,
-                50_000_000,
-            )),
+            init::Monotonics(Monotonic::new(&mut cx.core.DCB, cx.core.DWT, syst, CORE_HZ)),
         )
     }
 
-    #[task(capacity = 2, local = [spawn_handle], shared = [led0, led1, network, rtc])]
-    fn handle_network(mut cx: handle_network::Context) {
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        poe::efm32gg::sleep::idle()
+    }
+
+    #[task(
+        capacity = 2,
+        local = [spawn_handle],
+        shared = [led_identify, led1, network]
+    )]
+    fn handle_network(cx: handle_network::Context) {
+        let _timing = poe::efm32gg::timing::start("handle_network");
         log::trace!("Handling network...");
 
-        let timestamp = Instant::from_millis(cx.shared.rtc.lock(|rtc| rtc.cnt.read().cnt().bits()));
+        let timestamp = poe::time::now();
         let spawn_handle = cx.local.spawn_handle;
-        let mut led0 = cx.shared.led0;
+        let mut led_id = cx.shared.led_identify;
         let mut led1 = cx.shared.led1;
         let mut network = cx.shared.network;
 
-        match network.lock(|network| network.interface.poll(timestamp)) {
-            Ok(true) => {
-                log::trace!("Handling sockets...");
-
-                network.lock(|network| {
-                    network.handle_sockets(
-                        |state| {
-                            led1.lock(|led| {
-                                led.set(match state {
-                                    network::State::Operational => Color::Black,
-                                    _ => Color::Red,
-                                })
-                                .ignore()
-                            })
-                        },
-                        |en| match en {
-                            false => led0.lock(|led| led.set(Color::Black).ignore()),
-                            true => led0.lock(|led| led.set(Color::Yellow).ignore()),
-                        },
-                    )
-                });
+        // How long the link needs to settle after a PHY interrupt, before it's checked again
+        let mut settle = None;
+        while let Some(event) = poe::work::take() {
+            log::trace!("Handling {}", event);
+            match event {
+                // Frames are picked up (and their buffers reused) by the poll below
+                Event::RxComplete | Event::TxComplete => {}
+                Event::PhyIrq => {
+                    settle = Some(network.lock(|network| network.device.phy_irq(poe::time::now())))
+                }
             }
-            Ok(false) => log::trace!("Nothing to do"),
-            Err(err) => log::error!("Failed to poll network interface: {}", err),
         }
 
-        if let Some(delay) = network.lock(|network| network.interface.poll_delay(timestamp)) {
+        let changed = network.lock(|network| {
+            network.handle_syslog(timestamp);
+            network.handle_announce(timestamp);
+            network.handle_dhcp_retry(timestamp);
+            network.handle_routes();
+            network.poll(timestamp)
+        });
+        if changed {
+            log::trace!("Handling sockets...");
+
+            network.lock(|network| {
+                network.handle_sockets(
+                    timestamp,
+                    |state| {
+                        led1.lock(|led| {
+                            led.set(match state {
+                                network::State::Operational => Color::Black,
+                                _ => Color::Red,
+                            })
+                            .ignore()
+                        })
+                    },
+                    |pattern| led_id.lock(|led| led.enable(pattern)),
+                )
+            });
+        } else {
+            log::trace!("Nothing to do");
+        }
+
+        let delay = network.lock(|network| network.poll_delay(timestamp));
+        let delay = match (delay, settle) {
+            (Some(delay), Some(settle)) => Some(cmp::min(delay, settle)),
+            (delay, settle) => delay.or(settle),
+        };
+        if let Some(delay) = delay {
             use dwt_systick_monotonic::fugit::ExtU32;
             log::trace!("Scheduling network handling in {}", delay);
 
@@ -330,76 +505,247 @@ mod app {
                 .take()
                 .and_then(|h| h.reschedule_after(delay).ok())
                 .or_else(|| {
-                    Some(handle_network::spawn_after(delay).expect("spawning handle_network"))
+                    handle_network::spawn_after(delay)
+                        .map_err(|_| log::error!("Failed to schedule handle_network"))
+                        .ok()
                 });
         }
 
         log::trace!("Handled sockets: {}", timestamp);
     }
 
+    pub struct IdentifyLed {
+        indicator: Indicator<crate::IdentifyLight>,
+        spawn: Option<flash_identify_led::SpawnHandle>,
+    }
+
+    impl IdentifyLed {
+        fn enable(&mut self, pattern: Option<poe::identify::Pattern>) {
+            poe::identify::set_active(pattern);
+            self.indicator.show(match pattern {
+                Some(pattern) => Mode::Flash(pattern),
+                None => Mode::Solid(false),
+            });
+
+            if let Some(handle) = self.spawn.take() {
+                handle.cancel().ignore();
+            }
+            // A flash that's already queued will pick up the new pattern
+            flash_identify_led::spawn().ignore();
+        }
+    }
+
+    #[task(shared = [led_identify])]
+    fn flash_identify_led(mut cx: flash_identify_led::Context) {
+        use dwt_systick_monotonic::fugit::ExtU32;
+
+        let _timing = poe::efm32gg::timing::start("flash_identify_led");
+
+        cx.shared.led_identify.lock(|id| {
+            id.spawn = match id.indicator.step() {
+                Some(hold) => {
+                    flash_identify_led::spawn_after((hold.total_millis() as u32).millis())
+                        .map_err(|_| log::error!("Failed to schedule flash_identify_led"))
+                        .ok()
+                }
+                None => {
+                    poe::identify::set_active(None);
+                    None
+                }
+            }
+        });
+    }
+
+    #[task]
+    fn flush_logs(_: flush_logs::Context) {
+        let _timing = poe::efm32gg::timing::start("flush_logs");
+        poe::log::flush_deferred();
+    }
+
+    #[task(local = [reported: usize = 0])]
+    fn report_stack(cx: report_stack::Context) {
+        use dwt_systick_monotonic::fugit::ExtU32;
+
+        let _timing = poe::efm32gg::timing::start("report_stack");
+
+        let used = poe::stack::high_water();
+        if used > *cx.local.reported {
+            log::info!(
+                "Stack high-water mark: {} of {} bytes",
+                used,
+                poe::stack::size()
+            );
+            *cx.local.reported = used;
+        }
+        if report_stack::spawn_after(60_000u32.millis()).is_err() {
+            log::error!("Failed to schedule report_stack");
+        }
+    }
+
+    #[task]
+    fn poll_sensors(_: poll_sensors::Context) {
+        use dwt_systick_monotonic::fugit::ExtU32;
+
+        let _timing = poe::efm32gg::timing::start("poll_sensors");
+
+        poe::sensors::poll();
+        poe::identify::persist(poe::time::now());
+        poe::lifetime::persist(poe::time::now());
+        poe::config::persist();
+        poe::dhcp::persist();
+        if poll_sensors::spawn_after(10_000u32.millis()).is_err() {
+            log::error!("Failed to schedule poll_sensors");
+        }
+    }
+
+    #[task(binds = EMU)]
+    fn emu_irq(_: emu_irq::Context) {
+        let _timing = poe::efm32gg::timing::start("emu_irq");
+        poe::efm32gg::vmon::irq();
+    }
+
+    #[task(binds = RTC)]
+    fn rtc_irq(_: rtc_irq::Context) {
+        let _timing = poe::efm32gg::timing::start("rtc_irq");
+        poe::time::irq();
+    }
+
+    #[cfg(feature = "uart")]
+    #[task(binds = USART4_RX, shared = [led_identify, uart_terminal])]
+    fn usart4_rx_irq(cx: usart4_rx_irq::Context) {
+        let _timing = poe::efm32gg::timing::start("usart4_rx_irq");
+        poll_uart_terminal(cx.shared.led_identify, cx.shared.uart_terminal);
+    }
+
+    #[cfg(feature = "uart")]
+    #[task(binds = USART4_TX, shared = [led_identify, uart_terminal])]
+    fn usart4_tx_irq(cx: usart4_tx_irq::Context) {
+        let _timing = poe::efm32gg::timing::start("usart4_tx_irq");
+        poll_uart_terminal(cx.shared.led_identify, cx.shared.uart_terminal);
+    }
+
+    #[cfg(feature = "uart")]
+    fn poll_uart_terminal(
+        mut led_identify: impl rtic::Mutex<T = IdentifyLed>,
+        terminal: &mut crate::UartTerminal,
+    ) {
+        terminal.poll();
+        if let Some(pattern) = poe::identify::take_request() {
+            led_identify.lock(|led| led.enable(pattern));
+        }
+    }
+
     #[task(binds = ETH, shared = [network])]
     fn eth_irq(mut cx: eth_irq::Context) {
+        let _timing = poe::efm32gg::timing::start("eth_irq");
         interrupt::free(|_| {
             cx.shared.network.lock(|network| {
-                network.interface.device_mut().mac_irq();
+                network.device.mac_irq();
             });
         });
 
         handle_network::spawn().ignore();
     }
 
-    #[task(binds = GPIO_ODD, shared = [network])]
-    fn gpio_odd_irq(mut cx: gpio_odd_irq::Context) {
-        use dwt_systick_monotonic::fugit::ExtU32;
+    #[task(binds = GPIO_ODD)]
+    fn gpio_odd_irq(_: gpio_odd_irq::Context) {
+        let _timing = poe::efm32gg::timing::start("gpio_odd_irq");
 
         (unsafe { &*efm32gg11b820::GPIO::ptr() })
             .ifc
             .write(|w| unsafe { w.ext().bits(1 << 15) });
 
-        cx.shared.network.lock(|network| {
-            network.interface.device_mut().phy_irq();
-        });
+        poe::work::post(Event::PhyIrq);
+        handle_network::spawn().ignore();
+    }
+
+    #[task(binds = GPIO_EVEN, local = [spawn: Option<debounce_button::SpawnHandle> = None])]
+    fn gpio_even_irq(cx: gpio_even_irq::Context) {
+        use dwt_systick_monotonic::fugit::ExtU32;
+
+        let _timing = poe::efm32gg::timing::start("gpio_even_irq");
+
+        // Clear the button interrupt
+        (unsafe { &*efm32gg11b820::GPIO::ptr() })
+            .ifc
+            .write(|w| unsafe { w.ext().bits(1 << 8) });
+
+        // Sample the button once it stops bouncing
+        let delay = (poe::button::DEBOUNCE.total_millis() as u32).millis();
+        let spawn = cx.local.spawn;
+        *spawn = spawn
+            .take()
+            .and_then(|h| h.reschedule_after(delay).ok())
+            .or_else(|| debounce_button::spawn_after(delay).ok());
+    }
+
+    #[task(local = [button: Button = Button::new()], shared = [led_identify, network])]
+    fn debounce_button(mut cx: debounce_button::Context) {
+        let _timing = poe::efm32gg::timing::start("debounce_button");
+        let gpio = unsafe { &*efm32gg11b820::GPIO::ptr() };
+        let pressed = gpio.pc_din.read().bits() & (1 << 8) == 0;
+        let press = match cx.local.button.sample(poe::time::now(), pressed) {
+            Some(press) => press,
+            None => return,
+        };
 
-        // TODO: Why is the one-second delay necessary? 100 ms doesn't work.
-        handle_network::spawn_after(1000u32.millis()).ignore()
+        log::info!("Button: {} press", press);
+        match press {
+            Press::Short => cx.shared.led_identify.lock(|led| {
+                led.enable(match poe::identify::active() {
+                    Some(_) => None,
+                    None => Some(poe::identify::DEFAULT),
+                })
+            }),
+            Press::Long => {
+                cx.shared
+                    .network
+                    .lock(|network| network.reset_dhcp(poe::time::now()));
+                handle_network::spawn().ignore();
+            }
+            Press::VeryLong => {
+                // Every setting returns to its default once the store is erased and the device
+                // restarts
+                log::warn!("Restoring factory settings");
+                if let Err(err) = poe::store::erase() {
+                    log::error!("Failed to erase the store: {}", err);
+                }
+                poe::log::flush_deferred();
+                cortex_m::peripheral::SCB::sys_reset();
+            }
+        }
     }
 }
 
-// Light up both LEDs red, trigger a breakpoint, and loop
+// Light up both LEDs red, record the fault, and break or reset
 #[cortex_m_rt::exception]
 fn DefaultHandler(irqn: i16) {
     interrupt::disable();
+    poe::log::disable_deferral();
 
     log::error!("Default Handler: irq {}", irqn);
+    poe::fault::record_unhandled_irq(poe::time::now(), irqn);
     let (mut led0, mut led1) = unsafe { steal_leds() };
     led0.set(Color::Red).ignore();
     led1.set(Color::Red).ignore();
 
-    if peripheral::DCB::is_debugger_attached() {
-        asm::bkpt();
-    }
-
-    loop {
-        asm::wfe();
-    }
+    poe::fault::end()
 }
 
-// Light up both LEDs red, trigger a breakpoint, and loop
+// Light up both LEDs red, record the fault, and break or reset
 #[cortex_m_rt::exception]
-fn HardFault(_frame: &cortex_m_rt::ExceptionFrame) -> ! {
+fn HardFault(frame: &cortex_m_rt::ExceptionFrame) -> ! {
     interrupt::disable();
+    poe::log::disable_deferral();
+
+    log::error!("Hard Fault: {:?}", frame);
+    poe::fault::record_hard_fault(poe::time::now(), frame);
 
     let (mut led0, mut led1) = unsafe { steal_leds() };
     led0.set(Color::Red).ignore();
     led1.set(Color::Red).ignore();
 
-    if peripheral::DCB::is_debugger_attached() {
-        asm::bkpt();
-    }
-
-    loop {
-        asm::wfe();
-    }
+    poe::fault::end()
 }
 
 /// Steals the LEDs so they may be used directly.
@@ -425,19 +771,14 @@ pub unsafe fn steal_leds() -> (LED0, LED1) {
     (led0, led1)
 }
 
+#[cfg(not(feature = "panic-never"))]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
-    let rtc = unsafe { &*efm32gg11b820::RTC::ptr() };
-
     cortex_m::interrupt::disable();
+    poe::log::disable_deferral();
 
-    let now = Instant::from_millis(rtc.cnt.read().cnt().bits());
-
-    log::error!("Panic at {}: {}", now, info);
-
-    if cortex_m::peripheral::DCB::is_debugger_attached() {
-        asm::bkpt();
-    }
+    log::error!("Panic at {}: {}", poe::time::now(), info);
+    poe::fault::record_panic(poe::time::now(), info);
 
-    loop {}
+    poe::fault::end()
 }
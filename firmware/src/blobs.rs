@@ -0,0 +1,529 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Named blobs (e.g. web assets, or firmware images being staged) in the external SPI NOR flash
+// (see nor), for things that are too large for the internal flash.
+//
+// Each blob occupies a contiguous run of sectors. The first two sectors hold the directory: a log
+// of fixed-size entries, each naming a blob and where it lives, with a sequence number. The latest
+// entry for a name wins, and an entry without any sectors removes the blob. Replacing a blob
+// writes the new contents into free sectors and only then appends its entry, so the old contents
+// stay intact until the new ones are complete.
+//
+// The directory's sectors are used as a ring. Once one fills up, the other is erased, every blob's
+// entry is copied into it, and a checkpoint is appended, after which the entries from before the
+// copy are ignored. Until the checkpoint is written, the older entries still describe every blob,
+// so an interrupted copy loses nothing.
+//
+// The directory is read the first time that it's needed, since the bus is attached by the binary.
+// Reads and writes block on the bus, so blobs should only be used from low-priority tasks.
+
+use crate::nor::{self, SECTOR_SIZE};
+use core::cell::RefCell;
+use core::str;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::interrupt::{self, Mutex};
+
+/// The most blobs that can be stored.
+pub const MAX_BLOBS: usize = 16;
+
+/// The longest name that a blob can have, in bytes.
+pub const MAX_NAME_LEN: usize = 24;
+
+const DIRECTORY_SECTORS: u32 = 2;
+const ENTRY_SIZE: u32 = 64;
+const ENTRIES_PER_SECTOR: u32 = SECTOR_SIZE / ENTRY_SIZE;
+const ENTRIES: u32 = DIRECTORY_SECTORS * ENTRIES_PER_SECTOR;
+
+const MAGIC: u32 = 0xB10B_5001;
+const CHECKPOINT: u16 = 0xFFFF;
+const ERASED: u32 = 0xFFFF_FFFF;
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    directory: None,
+    reserved: None,
+}));
+
+// Held for the duration of each change to the directory, so that changes from different tasks
+// can't interleave
+static BUSY: AtomicBool = AtomicBool::new(false);
+
+struct State {
+    // The directory, or `None` until it has been read
+    directory: Option<Directory>,
+    // The sectors of the blob being written, if any
+    reserved: Option<(u32, u32)>,
+}
+
+#[derive(Clone, Copy)]
+struct Directory {
+    sectors: u32,
+    // The offset (in entries) of the next entry to be written
+    position: u32,
+    next: u32,
+    blobs: [Option<Blob>; MAX_BLOBS],
+}
+
+/// A blob's size and location.
+#[derive(Clone, Copy, Debug)]
+pub struct Blob {
+    name: [u8; MAX_NAME_LEN],
+    start: u32,
+    sectors: u32,
+    len: u32,
+}
+
+impl Blob {
+    pub fn name(&self) -> &str {
+        let len = self
+            .name
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(MAX_NAME_LEN);
+        str::from_utf8(&self.name[..len]).unwrap_or("?")
+    }
+
+    /// The length of the blob's contents, in bytes.
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn overlaps(&self, start: u32, sectors: u32) -> bool {
+        self.start < start + sectors && start < self.start + self.sectors
+    }
+}
+
+// A decoded directory entry
+struct Entry {
+    sequence: u32,
+    start: u32,
+    // The number of sectors, `CHECKPOINT` for a checkpoint, or zero for a removal
+    sectors: u16,
+    len: u32,
+    name: [u8; MAX_NAME_LEN],
+}
+
+impl Entry {
+    fn decode(bytes: &[u8; ENTRY_SIZE as usize]) -> Option<Entry> {
+        let word =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        if word(0) != MAGIC {
+            return None;
+        }
+
+        let mut name = [0; MAX_NAME_LEN];
+        name.copy_from_slice(&bytes[20..20 + MAX_NAME_LEN]);
+        Some(Entry {
+            sequence: word(4),
+            start: word(8),
+            sectors: word(12) as u16,
+            len: word(16),
+            name,
+        })
+    }
+
+    // The entry, without the magic; the magic is written last, so that a torn entry is ignored
+    fn encode(&self) -> [u8; ENTRY_SIZE as usize] {
+        let mut bytes = [0xFF; ENTRY_SIZE as usize];
+        bytes[4..8].copy_from_slice(&self.sequence.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.start.to_le_bytes());
+        bytes[12..16].copy_from_slice(&u32::from(self.sectors).to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.len.to_le_bytes());
+        bytes[20..20 + MAX_NAME_LEN].copy_from_slice(&self.name);
+        bytes
+    }
+}
+
+fn encode_name(name: &str) -> Result<[u8; MAX_NAME_LEN], &'static str> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN || name.bytes().any(|b| b == 0) {
+        return Err("invalid name");
+    }
+
+    let mut bytes = [0; MAX_NAME_LEN];
+    bytes[..name.len()].copy_from_slice(name.as_bytes());
+    Ok(bytes)
+}
+
+// Holds BUSY for the duration of `f`
+fn exclusive<R, F>(f: F) -> Result<R, &'static str>
+where
+    F: FnOnce() -> Result<R, &'static str>,
+{
+    if BUSY.swap(true, Ordering::Acquire) {
+        return Err("storage busy");
+    }
+    let result = f();
+    BUSY.store(false, Ordering::Release);
+    result
+}
+
+fn read_entry(index: u32) -> Result<[u8; ENTRY_SIZE as usize], &'static str> {
+    let mut bytes = [0; ENTRY_SIZE as usize];
+    nor::read(index * ENTRY_SIZE, &mut bytes)?;
+    Ok(bytes)
+}
+
+fn write_entry(index: u32, entry: &Entry) -> Result<(), &'static str> {
+    let addr = index * ENTRY_SIZE;
+    nor::program(addr + 4, &entry.encode()[4..])?;
+    nor::program(addr, &MAGIC.to_le_bytes())
+}
+
+// Reads the directory from flash
+fn scan() -> Result<Directory, &'static str> {
+    let sectors = nor::capacity()? / SECTOR_SIZE;
+
+    // Find the latest checkpoint, and the latest entry overall
+    let mut checkpoint = 0;
+    let mut latest: Option<(u32, u32)> = None;
+    for index in 0..ENTRIES {
+        if let Some(entry) = Entry::decode(&read_entry(index)?) {
+            if entry.sectors == CHECKPOINT {
+                checkpoint = checkpoint.max(entry.len);
+            }
+            match latest {
+                Some((sequence, _)) if sequence >= entry.sequence => {}
+                _ => latest = Some((entry.sequence, index)),
+            }
+        }
+    }
+
+    // Keep the latest entry for each name since the checkpoint
+    let mut directory = Directory {
+        sectors,
+        position: latest.map_or(0, |(_, index)| (index + 1) % ENTRIES),
+        next: latest.map_or(0, |(sequence, _)| sequence.wrapping_add(1)),
+        blobs: [None; MAX_BLOBS],
+    };
+    let mut sequences = [0u32; MAX_BLOBS];
+    for index in 0..ENTRIES {
+        let entry = match Entry::decode(&read_entry(index)?) {
+            Some(entry) if entry.sectors != CHECKPOINT && entry.sequence >= checkpoint => entry,
+            _ => continue,
+        };
+
+        let slot = directory
+            .blobs
+            .iter()
+            .position(|blob| matches!(blob, Some(blob) if blob.name == entry.name));
+        match slot {
+            Some(slot) if sequences[slot] > entry.sequence => {}
+            Some(slot) => {
+                sequences[slot] = entry.sequence;
+                directory.blobs[slot] = Some(blob(&entry));
+            }
+            None => match directory.blobs.iter().position(Option::is_none) {
+                Some(slot) => {
+                    sequences[slot] = entry.sequence;
+                    directory.blobs[slot] = Some(blob(&entry));
+                }
+                None => log::warn!("Too many blobs in the directory"),
+            },
+        }
+    }
+
+    // Removals leave an empty placeholder during the scan, so that older entries for the same name
+    // aren't resurrected; drop them now
+    for blob in directory.blobs.iter_mut() {
+        if matches!(blob, Some(b) if b.sectors == 0) {
+            *blob = None;
+        }
+    }
+
+    Ok(directory)
+}
+
+impl Directory {
+    fn find(&self, name: &[u8; MAX_NAME_LEN]) -> Option<Blob> {
+        self.blobs
+            .iter()
+            .flatten()
+            .find(|blob| blob.name == *name)
+            .copied()
+    }
+
+    // Finds the first run of free sectors that's long enough, avoiding any that are reserved
+    fn allocate(&self, sectors: u32, reserved: Option<(u32, u32)>) -> Result<u32, &'static str> {
+        let mut start = DIRECTORY_SECTORS;
+        while start + sectors <= self.sectors {
+            let conflict = self
+                .blobs
+                .iter()
+                .flatten()
+                .filter(|blob| blob.overlaps(start, sectors))
+                .map(|blob| blob.start + blob.sectors)
+                .chain(
+                    reserved
+                        .filter(|(s, n)| *s < start + sectors && start < s + n)
+                        .map(|(s, n)| s + n),
+                )
+                .max();
+            match conflict {
+                Some(end) => start = end,
+                None => return Ok(start),
+            }
+        }
+
+        Err("not enough space")
+    }
+
+    // Records a blob (or its removal, with no sectors) in the directory on flash and in RAM
+    fn append(&mut self, blob: Blob) -> Result<(), &'static str> {
+        let existing = self
+            .blobs
+            .iter()
+            .position(|b| matches!(b, Some(b) if b.name == blob.name));
+        let slot = match (existing, blob.sectors) {
+            (Some(slot), _) => slot,
+            (None, 0) => return Ok(()),
+            (None, _) => self
+                .blobs
+                .iter()
+                .position(Option::is_none)
+                .ok_or("too many blobs")?,
+        };
+
+        let mut updated = *self;
+        updated.blobs[slot] = match blob.sectors {
+            0 => None,
+            _ => Some(blob),
+        };
+
+        // Start a new sector (by copying every blob into it) if this one is full, or if the next
+        // entry has somehow already been written
+        let head = read_entry(self.position)?;
+        let erased = u32::from_le_bytes([head[0], head[1], head[2], head[3]]) == ERASED;
+        match (self.position % ENTRIES_PER_SECTOR == 0, erased) {
+            (false, true) => {
+                write_entry(self.position, &entry(&blob, self.next))?;
+                updated.position = (self.position + 1) % ENTRIES;
+                updated.next = self.next.wrapping_add(1);
+            }
+            _ => updated.compact()?,
+        }
+
+        *self = updated;
+        Ok(())
+    }
+
+    // Copies every blob into the next directory sector, followed by a checkpoint
+    fn compact(&mut self) -> Result<(), &'static str> {
+        let sector = match self.position % ENTRIES_PER_SECTOR {
+            0 => self.position / ENTRIES_PER_SECTOR,
+            _ => (self.position / ENTRIES_PER_SECTOR + 1) % DIRECTORY_SECTORS,
+        };
+        nor::erase_sector(sector * SECTOR_SIZE)?;
+
+        let first = self.next;
+        let mut position = sector * ENTRIES_PER_SECTOR;
+        for blob in self.blobs.iter().flatten() {
+            write_entry(position, &entry(blob, self.next))?;
+            position += 1;
+            self.next = self.next.wrapping_add(1);
+        }
+
+        write_entry(
+            position,
+            &Entry {
+                sequence: self.next,
+                start: 0,
+                sectors: CHECKPOINT,
+                len: first,
+                name: [0; MAX_NAME_LEN],
+            },
+        )?;
+        self.position = (position + 1) % ENTRIES;
+        self.next = self.next.wrapping_add(1);
+        Ok(())
+    }
+}
+
+fn blob(entry: &Entry) -> Blob {
+    Blob {
+        name: entry.name,
+        start: entry.start,
+        sectors: u32::from(entry.sectors),
+        len: entry.len,
+    }
+}
+
+fn entry(blob: &Blob, sequence: u32) -> Entry {
+    Entry {
+        sequence,
+        start: blob.start,
+        sectors: blob.sectors as u16,
+        len: blob.len,
+        name: blob.name,
+    }
+}
+
+// Runs `f` with a copy of the directory, reading it from flash if it hasn't been yet, and then
+// saves any changes that `f` made
+fn with_directory<R, F>(f: F) -> Result<R, &'static str>
+where
+    F: FnOnce(&mut Directory) -> Result<R, &'static str>,
+{
+    exclusive(|| {
+        let mut directory = match interrupt::free(|cs| STATE.borrow(cs).borrow().directory) {
+            Some(directory) => directory,
+            None => scan()?,
+        };
+        let result = f(&mut directory);
+        interrupt::free(|cs| STATE.borrow(cs).borrow_mut().directory = Some(directory));
+        result
+    })
+}
+
+/// Returns the named blob, if it exists.
+pub fn find(name: &str) -> Result<Option<Blob>, &'static str> {
+    let name = encode_name(name)?;
+    with_directory(|directory| Ok(directory.find(&name)))
+}
+
+/// Calls `f` with each blob.
+pub fn for_each<F: FnMut(&Blob)>(f: F) -> Result<(), &'static str> {
+    let blobs = with_directory(|directory| Ok(directory.blobs))?;
+    blobs.iter().flatten().for_each(f);
+    Ok(())
+}
+
+/// Returns the number of bytes that are free for new blobs.
+pub fn free() -> Result<u32, &'static str> {
+    with_directory(|directory| {
+        let used: u32 = directory
+            .blobs
+            .iter()
+            .flatten()
+            .map(|blob| blob.sectors)
+            .sum();
+        Ok((directory.sectors - DIRECTORY_SECTORS - used) * SECTOR_SIZE)
+    })
+}
+
+/// Reads from the blob, starting at `offset`, returning the number of bytes read (which is short
+/// at the end of the blob).
+pub fn read(blob: &Blob, offset: u32, buffer: &mut [u8]) -> Result<usize, &'static str> {
+    let len = buffer.len().min(blob.len.saturating_sub(offset) as usize);
+    nor::read(blob.start * SECTOR_SIZE + offset, &mut buffer[..len])?;
+    Ok(len)
+}
+
+/// Removes the named blob, if it exists.
+pub fn remove(name: &str) -> Result<(), &'static str> {
+    let name = encode_name(name)?;
+    with_directory(|directory| {
+        directory.append(Blob {
+            name,
+            start: 0,
+            sectors: 0,
+            len: 0,
+        })
+    })
+}
+
+/// Erases the directory, removing every blob.
+pub fn format() -> Result<(), &'static str> {
+    exclusive(|| {
+        for sector in 0..DIRECTORY_SECTORS {
+            nor::erase_sector(sector * SECTOR_SIZE)?;
+        }
+        interrupt::free(|cs| STATE.borrow(cs).borrow_mut().directory = None);
+        Ok(())
+    })
+}
+
+/// Starts writing a blob of `len` bytes, which replaces any existing blob of the same name once
+/// it's finished. Only one blob can be written at a time.
+pub fn create(name: &str, len: u32) -> Result<Writer, &'static str> {
+    let name = encode_name(name)?;
+    let sectors = (len + SECTOR_SIZE - 1) / SECTOR_SIZE;
+    if sectors == 0 {
+        return Err("blob is empty");
+    }
+
+    let start = with_directory(|directory| {
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+            if state.reserved.is_some() {
+                return Err("another blob is being written");
+            }
+            let start = directory.allocate(sectors, state.reserved)?;
+            state.reserved = Some((start, sectors));
+            Ok(start)
+        })
+    })?;
+
+    Ok(Writer {
+        blob: Blob {
+            name,
+            start,
+            sectors,
+            len,
+        },
+        written: 0,
+    })
+}
+
+/// A blob that's being written. The sectors are erased as they're reached.
+pub struct Writer {
+    blob: Blob,
+    written: u32,
+}
+
+impl Writer {
+    /// Appends the bytes to the blob.
+    pub fn write(&mut self, bytes: &[u8]) -> Result<(), &'static str> {
+        if self.written + bytes.len() as u32 > self.blob.len {
+            return Err("blob is longer than declared");
+        }
+
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            let addr = self.blob.start * SECTOR_SIZE + self.written;
+            if self.written % SECTOR_SIZE == 0 {
+                nor::erase_sector(addr)?;
+            }
+
+            let len = bytes
+                .len()
+                .min((SECTOR_SIZE - self.written % SECTOR_SIZE) as usize);
+            let (chunk, rest) = bytes.split_at(len);
+            nor::program(addr, chunk)?;
+
+            self.written += len as u32;
+            bytes = rest;
+        }
+
+        Ok(())
+    }
+
+    /// Records the blob in the directory, replacing any existing blob of the same name.
+    pub fn finish(self) -> Result<(), &'static str> {
+        if self.written != self.blob.len {
+            return Err("blob is shorter than declared");
+        }
+        with_directory(|directory| directory.append(self.blob))
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        interrupt::free(|cs| STATE.borrow(cs).borrow_mut().reserved = None);
+    }
+}
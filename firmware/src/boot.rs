@@ -0,0 +1,411 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Selection between the two images in flash, with rollback. Bank A (the first bank) holds the
+// image that was flashed over the debugger, and is always bootable. Bank B (the second bank, which
+// is the only one that can be programmed at runtime; see efm32gg::msc) holds an update, built with
+// the "slot-b" feature so that it's linked to run from there.
+//
+// An update is written into bank B (see `write_update`, which the TFTP server uses to receive one)
+// and then staged (see `stage`), which checks it (including its signature; see `signing`) and
+// records its version, length, and CRC in a metadata page near the end of the bank. At boot, the
+// image in bank A runs `select` before anything else, which records an attempt, starts the
+// watchdog, and jumps to bank B if a staged image there hasn't used up its attempts. Once the
+// image in bank B has been running for a while (`HEALTHY_AFTER_SECS`), the binary marks it healthy
+// and stops the watchdog (see `confirm`), and it's booted from then on without counting attempts.
+// If it resets (or hangs, and the watchdog resets it) before then too many times, `select` rejects
+// it and the image in bank A keeps running.
+//
+// Flash words can only be written once between erases, so each state change writes its own word:
+// one per attempt, one for healthy, and one for rejected.
+//
+// While the image in bank B is running, it shares its bank with the pages that are written at
+// runtime (like this module's metadata and the event log), so the core stalls on instruction
+// fetches while those are erased or written.
+
+use crate::efm32gg::msc;
+use crate::signing::{self, SIGNATURE_LEN};
+use core::fmt;
+use core::ptr;
+use core::slice;
+use core::sync::atomic::{AtomicBool, Ordering};
+use ignore_result::Ignore;
+
+/// How long the image in bank B has to stay up before it's marked healthy. This must be shorter
+/// than the watchdog's period.
+pub const HEALTHY_AFTER_SECS: u32 = 120;
+
+/// The number of times that an unconfirmed image in bank B is booted before it's rejected.
+pub const MAX_ATTEMPTS: u32 = 3;
+
+const SLOT_B_START: u32 = 0x0010_0000;
+const METADATA: u32 = 0x001F_D000;
+const SLOT_B_LEN: u32 = crate::store::START - SLOT_B_START;
+
+const RAM_START: u32 = 0x2000_0000;
+const RAM_END: u32 = 0x2008_0000;
+
+const MAGIC: u32 = 0xB007_0001;
+const ERASED: u32 = 0xFFFF_FFFF;
+const SET: u32 = 0;
+
+const WORD_MAGIC: u32 = 0;
+const WORD_VERSION: u32 = 1;
+const WORD_LEN: u32 = 2;
+const WORD_CRC: u32 = 3;
+const WORD_HEALTHY: u32 = 4;
+const WORD_REJECTED: u32 = 5;
+const WORD_SIGNED: u32 = 6;
+const WORD_ATTEMPTS: u32 = 8;
+
+const WDOG_BASE: usize = 0x4005_2000;
+const WDOG_CTRL: usize = 0x000;
+const WDOG_CMD: usize = 0x004;
+const WDOG_SYNCBUSY: usize = 0x008;
+
+const WDOG_CTRL_EN: u32 = 1 << 0;
+const WDOG_CTRL_EM2RUN: u32 = 1 << 2;
+const WDOG_CTRL_EM3RUN: u32 = 1 << 3;
+// 256k cycles of the 1 kHz ULFRCO (the default clock), or about 262 seconds
+const WDOG_CTRL_PERSEL_MAX: u32 = 0xF << 8;
+const WDOG_CMD_CLEAR: u32 = 1 << 0;
+const WDOG_SYNCBUSY_CTRL: u32 = 1 << 0;
+const WDOG_SYNCBUSY_CMD: u32 = 1 << 1;
+
+static ROLLED_BACK: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Bank {
+    A,
+    B,
+}
+
+impl fmt::Display for Bank {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Bank::A => "A",
+            Bank::B => "B",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    Pending { attempts: u32 },
+    Healthy,
+    Rejected,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            State::Pending { attempts } => {
+                write!(f, "pending ({} of {} attempts)", attempts, MAX_ATTEMPTS)
+            }
+            State::Healthy => f.pad("healthy"),
+            State::Rejected => f.pad("rejected"),
+        }
+    }
+}
+
+/// The image staged in bank B.
+#[derive(Clone, Copy, Debug)]
+pub struct Image {
+    pub version: u32,
+    pub len: u32,
+    pub crc: u32,
+    pub signed: bool,
+    pub state: State,
+}
+
+fn read(word: u32) -> u32 {
+    unsafe { ptr::read_volatile((METADATA + 4 * word) as *const u32) }
+}
+
+fn set(word: u32) -> Result<(), &'static str> {
+    msc::write(METADATA + 4 * word, &[SET])
+}
+
+/// Returns the bank that the running image was linked for.
+pub fn running() -> Bank {
+    match (running as usize as u32) < SLOT_B_START {
+        true => Bank::A,
+        false => Bank::B,
+    }
+}
+
+/// Returns the image staged in bank B, if there is one.
+pub fn staged() -> Option<Image> {
+    if read(WORD_MAGIC) != MAGIC {
+        return None;
+    }
+
+    let attempts = (0..MAX_ATTEMPTS)
+        .take_while(|i| read(WORD_ATTEMPTS + i) != ERASED)
+        .count() as u32;
+    let state = match (read(WORD_REJECTED), read(WORD_HEALTHY)) {
+        (SET, _) => State::Rejected,
+        (_, SET) => State::Healthy,
+        _ => State::Pending { attempts },
+    };
+
+    Some(Image {
+        version: read(WORD_VERSION),
+        len: read(WORD_LEN),
+        crc: read(WORD_CRC),
+        signed: read(WORD_SIGNED) == SET,
+        state,
+    })
+}
+
+/// Boots the image in bank B, if it's staged and hasn't been rejected, rejecting it instead if it's
+/// corrupt or has used up its attempts. This must be the first thing that the binary does (while
+/// the peripherals are still in their reset state), and only returns if the running image should
+/// keep running.
+pub fn select() {
+    if running() == Bank::B {
+        return;
+    }
+
+    let image = match staged() {
+        Some(image) => image,
+        None => return,
+    };
+
+    // Checking the CRC takes a while, so it's only done before the first attempt; bank B can't be
+    // rewritten after that without staging it again
+    let reject = match image.state {
+        State::Rejected => return,
+        _ if signing::has_key() && !image.signed => true,
+        State::Healthy => false,
+        State::Pending { attempts: 0 } if crc(SLOT_B_START, image.len) != image.crc => true,
+        State::Pending { attempts } if attempts < MAX_ATTEMPTS => {
+            if set(WORD_ATTEMPTS + attempts).is_err() {
+                return;
+            }
+            false
+        }
+        State::Pending { .. } => true,
+    };
+
+    if reject {
+        set(WORD_REJECTED).ignore();
+        ROLLED_BACK.store(true, Ordering::Relaxed);
+        return;
+    }
+
+    if image.state != State::Healthy {
+        start_watchdog();
+    }
+    unsafe { jump(SLOT_B_START) }
+}
+
+/// Returns true if `select` rejected the image in bank B during this boot (so the image in bank A
+/// kept running).
+pub fn rolled_back() -> bool {
+    ROLLED_BACK.load(Ordering::Relaxed)
+}
+
+/// Marks the running image healthy and stops the watchdog, if it's the image staged in bank B.
+pub fn confirm() -> Result<(), &'static str> {
+    match (running(), staged().map(|image| image.state)) {
+        (Bank::B, Some(State::Pending { .. })) => {
+            set(WORD_HEALTHY)?;
+            stop_watchdog();
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Rejects the image in bank B, so that the image in bank A is booted from the next reset on.
+pub fn reject() -> Result<(), &'static str> {
+    match staged().map(|image| image.state) {
+        None => Err("no image is staged"),
+        Some(State::Rejected) => Ok(()),
+        Some(_) => set(WORD_REJECTED),
+    }
+}
+
+/// Stages the `len` bytes written at the start of bank B (followed by their signature) as an
+/// image of the given version, once it's been checked, replacing whatever was staged before. It's
+/// booted from the next reset on. Checking the image takes a few seconds.
+pub fn stage(version: u32, len: u32) -> Result<Image, &'static str> {
+    if running() == Bank::B {
+        return Err("can't replace the running image");
+    }
+    if len < 8 || len + SIGNATURE_LEN as u32 > SLOT_B_LEN {
+        return Err("invalid length");
+    }
+
+    // The vector table starts with the initial stack pointer and the reset handler
+    let image = |offset: u32| unsafe { ptr::read_volatile((SLOT_B_START + offset) as *const u32) };
+    if !(RAM_START..=RAM_END).contains(&image(0)) {
+        return Err("invalid initial stack pointer");
+    }
+    if !(SLOT_B_START..SLOT_B_START + len).contains(&(image(4) & !1)) {
+        return Err("invalid reset vector");
+    }
+
+    let bytes = unsafe { slice::from_raw_parts(SLOT_B_START as *const u8, len as usize) };
+    let signed = match signing::check(bytes, signature_at(SLOT_B_START + len)) {
+        signing::Status::Valid => true,
+        signing::Status::Unchecked => false,
+        signing::Status::Invalid => return Err("invalid signature"),
+        signing::Status::Unsigned => return Err("image isn't signed"),
+    };
+
+    let crc = crc(SLOT_B_START, len);
+    msc::erase_page(METADATA)?;
+    msc::write(METADATA, &[MAGIC, version, len, crc])?;
+    if signed {
+        set(WORD_SIGNED)?;
+    }
+
+    staged().ok_or("failed to stage image")
+}
+
+/// Writes part of an update into bank B at `offset`, erasing each page as it's reached. An update
+/// is written in order from the start of the bank, and writing its start unstages whatever was
+/// staged before, so that an update that's only partly written is never booted.
+pub fn write_update(offset: u32, data: &[u8]) -> Result<(), &'static str> {
+    if running() == Bank::B {
+        return Err("can't replace the running image");
+    }
+    if offset % 4 != 0 {
+        return Err("offset isn't word-aligned");
+    }
+    let len = data.len() as u32;
+    if offset + len > SLOT_B_LEN {
+        return Err("image is too large");
+    }
+    if offset == 0 && staged().is_some() {
+        msc::erase_page(METADATA)?;
+    }
+
+    let start = SLOT_B_START + offset;
+    let mut page = (start + msc::PAGE_SIZE - 1) / msc::PAGE_SIZE * msc::PAGE_SIZE;
+    while page < start + len {
+        msc::erase_page(page)?;
+        page += msc::PAGE_SIZE;
+    }
+
+    // The end of the last word is left erased
+    for (i, chunk) in data.chunks(4).enumerate() {
+        let mut word = ERASED.to_le_bytes();
+        word[..chunk.len()].copy_from_slice(chunk);
+        msc::write(start + 4 * i as u32, &[u32::from_le_bytes(word)])?;
+    }
+    Ok(())
+}
+
+/// Stages the update of `len` bytes that was written with `write_update` (see `stage`). When
+/// signatures are checked, the update ends with its signature (see `signing`).
+pub fn stage_update(version: u32, len: u32) -> Result<Image, &'static str> {
+    let len = match signing::has_key() {
+        true => len
+            .checked_sub(SIGNATURE_LEN as u32)
+            .ok_or("invalid length")?,
+        false => len,
+    };
+    stage(version, len)
+}
+
+/// Returns the running image, from its vector table through the end of the initial values of its
+/// static variables (the last thing that the linker places in flash).
+pub fn image() -> &'static [u8] {
+    extern "C" {
+        static __sidata: u32;
+        static __sdata: u32;
+        static __edata: u32;
+    }
+
+    let start = match running() {
+        Bank::A => 0,
+        Bank::B => SLOT_B_START,
+    };
+    unsafe {
+        let data = &__edata as *const u32 as u32 - &__sdata as *const u32 as u32;
+        let end = &__sidata as *const u32 as u32 + data;
+        slice::from_raw_parts(start as *const u8, (end - start) as usize)
+    }
+}
+
+/// Returns the signature that follows the running image in flash.
+pub fn signature() -> &'static [u8; SIGNATURE_LEN] {
+    let image = image();
+    signature_at(image.as_ptr() as u32 + image.len() as u32)
+}
+
+fn signature_at(addr: u32) -> &'static [u8; SIGNATURE_LEN] {
+    unsafe { &*(addr as *const [u8; SIGNATURE_LEN]) }
+}
+
+/// Returns the CRC-32 of the `len` bytes of flash starting at `start`.
+pub fn crc(start: u32, len: u32) -> u32 {
+    crate::efm32gg::gpcrc::crc32_at(start, len)
+}
+
+fn watchdog(offset: usize) -> *mut u32 {
+    (WDOG_BASE + offset) as *mut u32
+}
+
+// The watchdog's registers are in the low-energy domain, so writes take a few of its cycles to
+// take effect
+fn watchdog_sync(busy: u32) {
+    while unsafe { ptr::read_volatile(watchdog(WDOG_SYNCBUSY)) } & busy != 0 {}
+}
+
+// Resets the core unless `stop_watchdog` is called before the watchdog's period elapses. The
+// watchdog isn't locked, and it's paused while the core is halted by a debugger.
+fn start_watchdog() {
+    let cmu = unsafe { &*efm32gg11b820::CMU::ptr() };
+    cmu.hfbusclken0.modify(|_, reg| reg.le().set_bit());
+
+    watchdog_sync(WDOG_SYNCBUSY_CTRL | WDOG_SYNCBUSY_CMD);
+    unsafe {
+        ptr::write_volatile(watchdog(WDOG_CMD), WDOG_CMD_CLEAR);
+        ptr::write_volatile(
+            watchdog(WDOG_CTRL),
+            WDOG_CTRL_EN | WDOG_CTRL_EM2RUN | WDOG_CTRL_EM3RUN | WDOG_CTRL_PERSEL_MAX,
+        );
+    }
+    watchdog_sync(WDOG_SYNCBUSY_CTRL | WDOG_SYNCBUSY_CMD);
+}
+
+fn stop_watchdog() {
+    watchdog_sync(WDOG_SYNCBUSY_CTRL);
+    unsafe {
+        let ctrl = ptr::read_volatile(watchdog(WDOG_CTRL));
+        ptr::write_volatile(watchdog(WDOG_CTRL), ctrl & !WDOG_CTRL_EN);
+    }
+    watchdog_sync(WDOG_SYNCBUSY_CTRL);
+}
+
+// Starts the image whose vector table is at the given address, as though it had been reset. RTIC
+// has already enabled the interrupts that this image binds (but not unmasked them), so those are
+// disabled again first.
+unsafe fn jump(vector_table: u32) -> ! {
+    let nvic = &*cortex_m::peripheral::NVIC::PTR;
+    for i in 0..nvic.icer.len() {
+        nvic.icer[i].write(u32::MAX);
+        nvic.icpr[i].write(u32::MAX);
+    }
+
+    let scb = &*cortex_m::peripheral::SCB::PTR;
+    scb.vtor.write(vector_table);
+
+    cortex_m::asm::bootload(vector_table as *const u32)
+}
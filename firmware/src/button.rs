@@ -0,0 +1,77 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Pushbutton debouncing and gestures. The button's pin interrupts on both edges, and the binary
+// (re)schedules a sample for `DEBOUNCE` after each one, so that the button is only sampled once
+// its contacts have stopped bouncing. Each press is classified by how long the button was held
+// once it's released.
+
+use core::fmt;
+use smoltcp::time::{Duration, Instant};
+
+/// How long the button has to stop bouncing before it's sampled.
+pub const DEBOUNCE: Duration = Duration::from_millis(20);
+
+// The shortest long and very long presses
+const LONG: Duration = Duration::from_secs(2);
+const VERY_LONG: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Press {
+    Short,
+    Long,
+    VeryLong,
+}
+
+impl fmt::Display for Press {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Press::Short => "short",
+            Press::Long => "long",
+            Press::VeryLong => "very long",
+        })
+    }
+}
+
+pub struct Button {
+    // When the button was pressed, if it's being held
+    pressed: Option<Instant>,
+}
+
+impl Button {
+    pub const fn new() -> Button {
+        Button { pressed: None }
+    }
+
+    /// Samples the button, once it has settled (see `DEBOUNCE`), returning the press that just
+    /// ended, if any.
+    pub fn sample(&mut self, now: Instant, pressed: bool) -> Option<Press> {
+        match (self.pressed, pressed) {
+            (None, true) => {
+                self.pressed = Some(now);
+                None
+            }
+            (Some(start), false) => {
+                self.pressed = None;
+                Some(match now - start {
+                    held if held >= VERY_LONG => Press::VeryLong,
+                    held if held >= LONG => Press::Long,
+                    _ => Press::Short,
+                })
+            }
+            (None, false) | (Some(_), true) => None,
+        }
+    }
+}
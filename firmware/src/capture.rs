@@ -0,0 +1,351 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Frame capture, for debugging without a switch that can mirror the port. While a capture is
+// running, the driver passes each frame that it receives or transmits (as it appears on the wire,
+// VLAN tag and all) to `frame`, which appends the matching ones to a buffer as pcap records. The
+// buffer is drained by the sink: the terminal copies it into the "capture" RTT up-channel, and the
+// network task sends it to whoever is connected to `PORT` (e.g. `nc <host> 51902 | wireshark -k
+// -i -`). Each new stream starts with the pcap file header. Records that don't fit in the buffer
+// are dropped, and the capture's own TCP segments are never captured.
+//
+// The buffer can also be kept as it is, rather than drained, so that it holds a whole pcap file
+// that can be fetched later (see `tftp`). It stays until the next capture is started, even once
+// this one has been stopped.
+
+use core::cell::RefCell;
+use core::fmt;
+use core::str::FromStr;
+use cortex_m::interrupt::{self, Mutex};
+use smoltcp::time::Instant;
+use smoltcp::wire::{IpAddress, Ipv4Address, Ipv6Address};
+
+/// The TCP port that streams the capture.
+pub const PORT: u16 = 51902;
+
+/// The number of bytes of each frame that are captured.
+pub const SNAPLEN: usize = 256;
+
+const BUFFER_LEN: usize = 4096;
+
+const MAGIC: u32 = 0xA1B2_C3D4;
+const LINKTYPE_ETHERNET: u32 = 1;
+const FILE_HEADER_LEN: usize = 24;
+const RECORD_HEADER_LEN: usize = 16;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+const PROTOCOL_TCP: u8 = 6;
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    sink: None,
+    filter: None,
+    buffer: [0; BUFFER_LEN],
+    head: 0,
+    len: 0,
+    captured: 0,
+    dropped: 0,
+    kept: false,
+}));
+
+struct State {
+    sink: Option<Sink>,
+    filter: Option<Filter>,
+
+    // A ring of pcap records, starting at head
+    buffer: [u8; BUFFER_LEN],
+    head: usize,
+    len: usize,
+
+    captured: u32,
+    dropped: u32,
+
+    // Whether the buffer holds a whole file, from the header on
+    kept: bool,
+}
+
+impl State {
+    fn push(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.buffer[(self.head + self.len) % BUFFER_LEN] = *byte;
+            self.len += 1;
+        }
+    }
+
+    // Empties the buffer, leaving only the file header that starts a new stream
+    fn restart(&mut self) {
+        self.head = 0;
+        self.len = 0;
+
+        let mut header = [0; FILE_HEADER_LEN];
+        header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..6].copy_from_slice(&2u16.to_le_bytes());
+        header[6..8].copy_from_slice(&4u16.to_le_bytes());
+        header[16..20].copy_from_slice(&(SNAPLEN as u32).to_le_bytes());
+        header[20..24].copy_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        self.push(&header);
+    }
+}
+
+/// Where the capture is streamed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sink {
+    Rtt,
+    Tcp,
+    /// Keep the capture in the buffer, until it fills up.
+    Buffer,
+}
+
+impl fmt::Display for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Sink::Rtt => f.pad("RTT"),
+            Sink::Tcp => f.pad("TCP"),
+            Sink::Buffer => f.pad("the buffer"),
+        }
+    }
+}
+
+impl FromStr for Sink {
+    type Err = &'static str;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "rtt" => Ok(Sink::Rtt),
+            "tcp" => Ok(Sink::Tcp),
+            "buffer" => Ok(Sink::Buffer),
+            _ => Err("sink must be 'rtt', 'tcp', or 'buffer'"),
+        }
+    }
+}
+
+/// Limits the capture to certain frames.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    EtherType(u16),
+    /// IP packets to or from the address.
+    Address(IpAddress),
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Filter::EtherType(ethertype) => write!(f, "EtherType {:#06X}", ethertype),
+            Filter::Address(addr) => write!(f, "host {}", addr),
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = &'static str;
+
+    fn from_str(filter: &str) -> Result<Self, Self::Err> {
+        if let Some(ethertype) = filter.strip_prefix("0x") {
+            return u16::from_str_radix(ethertype, 16)
+                .map(Filter::EtherType)
+                .map_err(|_| "invalid EtherType");
+        }
+
+        filter
+            .parse()
+            .map(Filter::Address)
+            .map_err(|_| "filter must be an EtherType (e.g. 0x0800) or an IP address")
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Status {
+    pub sink: Option<Sink>,
+    pub filter: Option<Filter>,
+    pub captured: u32,
+    pub dropped: u32,
+}
+
+/// Starts capturing the frames that match the filter (or all of them), replacing any capture that
+/// is already running.
+pub fn start(sink: Sink, filter: Option<Filter>) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.sink = Some(sink);
+        state.filter = filter;
+        state.captured = 0;
+        state.dropped = 0;
+        state.kept = sink == Sink::Buffer;
+        state.restart();
+    })
+}
+
+pub fn stop() {
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().sink = None)
+}
+
+pub fn sink() -> Option<Sink> {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().sink)
+}
+
+pub fn status() -> Status {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        Status {
+            sink: state.sink,
+            filter: state.filter,
+            captured: state.captured,
+            dropped: state.dropped,
+        }
+    })
+}
+
+/// Returns true if there are records waiting to be sent to the TCP sink, in which case the network
+/// needs to be handled.
+pub fn pending() -> bool {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        state.sink == Some(Sink::Tcp) && state.len > FILE_HEADER_LEN
+    })
+}
+
+/// Discards everything that has been captured, so that the sink's next stream starts with a file
+/// header.
+pub fn restart() {
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().restart())
+}
+
+/// Captures the frame, if it matches the filter.
+pub fn frame(timestamp: Instant, frame: &[u8]) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        let sink = match state.sink {
+            Some(sink) => sink,
+            None => return,
+        };
+        if !matches(state.filter, sink, frame) {
+            return;
+        }
+
+        let len = frame.len().min(SNAPLEN);
+        if state.len + RECORD_HEADER_LEN + len > BUFFER_LEN {
+            state.dropped = state.dropped.wrapping_add(1);
+            return;
+        }
+
+        let micros = timestamp.total_micros();
+        let mut header = [0; RECORD_HEADER_LEN];
+        header[0..4].copy_from_slice(&((micros / 1_000_000) as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&((micros % 1_000_000) as u32).to_le_bytes());
+        header[8..12].copy_from_slice(&(len as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&(frame.len() as u32).to_le_bytes());
+        state.push(&header);
+        state.push(&frame[..len]);
+        state.captured = state.captured.wrapping_add(1);
+    })
+}
+
+/// Returns the length of the file held in the buffer, if the last capture was kept there.
+pub fn kept_len() -> Option<usize> {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        match state.kept {
+            true => Some(state.len),
+            false => None,
+        }
+    })
+}
+
+/// Copies the part of the kept file that starts at `offset` into `buffer`, returning the number
+/// of bytes copied.
+pub fn read_kept(offset: usize, buffer: &mut [u8]) -> usize {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        if !state.kept || offset >= state.len {
+            return 0;
+        }
+
+        let len = buffer.len().min(state.len - offset);
+        for (i, byte) in buffer[..len].iter_mut().enumerate() {
+            *byte = state.buffer[(state.head + offset + i) % BUFFER_LEN];
+        }
+        len
+    })
+}
+
+/// Passes the captured bytes to `f`, in order, removing however many it reports having consumed.
+pub fn drain<F: FnMut(&[u8]) -> usize>(mut f: F) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        while state.len > 0 {
+            let contiguous = state.len.min(BUFFER_LEN - state.head);
+            let consumed = f(&state.buffer[state.head..][..contiguous]).min(contiguous);
+            state.head = (state.head + consumed) % BUFFER_LEN;
+            state.len -= consumed;
+            if consumed < contiguous {
+                break;
+            }
+        }
+    })
+}
+
+fn matches(filter: Option<Filter>, sink: Sink, frame: &[u8]) -> bool {
+    if frame.len() < 14 {
+        return false;
+    }
+
+    // Look past a VLAN tag, if there is one
+    let (ethertype, payload) = match u16::from_be_bytes([frame[12], frame[13]]) {
+        crate::vlan::ETHERTYPE if frame.len() >= 18 => {
+            (u16::from_be_bytes([frame[16], frame[17]]), &frame[18..])
+        }
+        ethertype => (ethertype, &frame[14..]),
+    };
+    let packet = ip(ethertype, payload);
+
+    // Capturing the segments that carry the capture would never end
+    if let (Sink::Tcp, Some((_, _, PROTOCOL_TCP, segment))) = (sink, packet) {
+        if segment.len() >= 4
+            && (segment[0..2] == PORT.to_be_bytes() || segment[2..4] == PORT.to_be_bytes())
+        {
+            return false;
+        }
+    }
+
+    match filter {
+        None => true,
+        Some(Filter::EtherType(filter)) => ethertype == filter,
+        Some(Filter::Address(addr)) => {
+            matches!(packet, Some((src, dst, _, _)) if src == addr || dst == addr)
+        }
+    }
+}
+
+// Returns the source and destination addresses, protocol, and payload of an IP packet
+fn ip(ethertype: u16, packet: &[u8]) -> Option<(IpAddress, IpAddress, u8, &[u8])> {
+    match ethertype {
+        ETHERTYPE_IPV4 if packet.len() >= 20 => {
+            let header_len = usize::from(packet[0] & 0x0F) * 4;
+            Some((
+                Ipv4Address::from_bytes(&packet[12..16]).into(),
+                Ipv4Address::from_bytes(&packet[16..20]).into(),
+                packet[9],
+                packet.get(header_len..)?,
+            ))
+        }
+        ETHERTYPE_IPV6 if packet.len() >= 40 => Some((
+            Ipv6Address::from_bytes(&packet[8..24]).into(),
+            Ipv6Address::from_bytes(&packet[24..40]).into(),
+            packet[6],
+            &packet[40..],
+        )),
+        _ => None,
+    }
+}
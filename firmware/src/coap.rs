@@ -0,0 +1,528 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A small CoAP (RFC 7252) server, exposing the following resources:
+//
+//   /identify  GET returns "0" or "1"; PUT "0" or "1" to disable or enable identification, or a
+//              pattern (see `identify`) to flash
+//   /power     GET returns the state of the downstream port; PUT "on", "off", or "cycle"
+//   /status    GET returns the state of the link and the board; observable (RFC 7641)
+//   /stats     GET returns the MAC's frame and octet counts
+//
+// Representations are plain text, with one "key=value" pair per line. Confirmable requests are
+// answered with piggybacked responses. Observers of /status are sent a non-confirmable
+// notification whenever the link changes, and are forgotten if they reset one.
+
+use crate::efm32gg::Statistics;
+use crate::identify::Pattern;
+
+use core::cell::RefCell;
+use core::convert::TryFrom;
+use core::fmt::{self, Write};
+use cortex_m::interrupt::{self, Mutex};
+use ethernet_phy::{LinkDuplex, LinkSpeed, LinkState};
+use smoltcp::wire::IpEndpoint;
+
+pub const PORT: u16 = 5683;
+
+/// The largest message that's sent or received.
+pub const MAX_MESSAGE_LEN: usize = 256;
+
+const MAX_TOKEN_LEN: usize = 8;
+const MAX_PATH_SEGMENTS: usize = 2;
+const MAX_OBSERVERS: usize = 2;
+
+const VERSION: u8 = 1;
+
+const TYPE_CON: u8 = 0;
+const TYPE_NON: u8 = 1;
+const TYPE_ACK: u8 = 2;
+const TYPE_RST: u8 = 3;
+
+const CODE_EMPTY: u8 = 0x00;
+const CODE_GET: u8 = 0x01;
+const CODE_PUT: u8 = 0x03;
+const CODE_CHANGED: u8 = 0x44;
+const CODE_CONTENT: u8 = 0x45;
+const CODE_BAD_REQUEST: u8 = 0x80;
+const CODE_BAD_OPTION: u8 = 0x82;
+const CODE_NOT_FOUND: u8 = 0x84;
+const CODE_METHOD_NOT_ALLOWED: u8 = 0x85;
+const CODE_INTERNAL_SERVER_ERROR: u8 = 0xA0;
+
+const OPTION_OBSERVE: u16 = 6;
+const OPTION_URI_PATH: u16 = 11;
+const OPTION_CONTENT_FORMAT: u16 = 12;
+
+const FORMAT_TEXT: u32 = 0;
+const FORMAT_LINK: u32 = 40;
+
+const PAYLOAD_MARKER: u8 = 0xFF;
+
+const CORE_LINKS: &str = "</identify>,</power>,</status>;obs,</stats>";
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    observers: [None; MAX_OBSERVERS],
+    sequence: 0,
+    message_id: 0,
+}));
+
+struct State {
+    observers: [Option<Observer>; MAX_OBSERVERS],
+    sequence: u32,
+    message_id: u16,
+}
+
+impl State {
+    fn next_message_id(&mut self) -> u16 {
+        self.message_id = self.message_id.wrapping_add(1);
+        self.message_id
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Observer {
+    endpoint: IpEndpoint,
+    token: Token,
+    message_id: u16,
+    pending: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Token {
+    bytes: [u8; MAX_TOKEN_LEN],
+    len: usize,
+}
+
+impl Token {
+    fn new(token: &[u8]) -> Token {
+        let mut bytes = [0; MAX_TOKEN_LEN];
+        bytes[..token.len()].copy_from_slice(token);
+        Token {
+            bytes,
+            len: token.len(),
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Resource {
+    Core,
+    Identify,
+    Power,
+    Status,
+    Stats,
+}
+
+/// The state of the device, as needed to represent the resources.
+pub struct Context {
+    pub link: Option<LinkState>,
+    pub statistics: Statistics,
+    pub identifying: bool,
+}
+
+/// Marks each of the observers of /status as needing a notification.
+pub fn link_changed() {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.sequence = state.sequence.wrapping_add(1);
+        state
+            .observers
+            .iter_mut()
+            .flatten()
+            .for_each(|observer| observer.pending = true);
+    })
+}
+
+/// Returns true if any of the observers need a notification.
+pub fn notification_pending() -> bool {
+    interrupt::free(|cs| {
+        STATE
+            .borrow(cs)
+            .borrow()
+            .observers
+            .iter()
+            .flatten()
+            .any(|observer| observer.pending)
+    })
+}
+
+/// Writes the next pending notification into `buffer`, returning its destination and length.
+pub fn take_notification(buffer: &mut [u8], context: &Context) -> Option<(IpEndpoint, usize)> {
+    let (observer, sequence) = interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        let message_id = state.next_message_id();
+        let sequence = state.sequence;
+        let observer = state
+            .observers
+            .iter_mut()
+            .flatten()
+            .find(|observer| observer.pending)?;
+
+        observer.pending = false;
+        observer.message_id = message_id;
+        Some((*observer, sequence))
+    })?;
+
+    let mut message = Message::new(buffer);
+    message.header(TYPE_NON, CODE_CONTENT, observer.message_id, &observer.token)?;
+    message.uint_option(OPTION_OBSERVE, sequence & 0x00FF_FFFF)?;
+    message.uint_option(OPTION_CONTENT_FORMAT, FORMAT_TEXT)?;
+    message.put(&[PAYLOAD_MARKER])?;
+    represent(Resource::Status, &mut message, context).ok()?;
+
+    Some((observer.endpoint, message.len))
+}
+
+/// Handles a request from `source`, writing the response into `response`. Returns the length of
+/// the response, or `None` if there's nothing to send.
+pub fn handle(
+    request: &[u8],
+    source: IpEndpoint,
+    response: &mut [u8],
+    context: &Context,
+    identify: &mut dyn FnMut(Option<Pattern>),
+) -> Option<usize> {
+    let header = request.get(..4)?;
+    let (version, kind, token_len) = (
+        header[0] >> 6,
+        header[0] >> 4 & 0x03,
+        usize::from(header[0] & 0x0F),
+    );
+    let (code, message_id) = (header[1], u16::from_be_bytes([header[2], header[3]]));
+    if version != VERSION || token_len > MAX_TOKEN_LEN {
+        return None;
+    }
+    let token = Token::new(request.get(4..4 + token_len)?);
+
+    match (kind, code) {
+        (TYPE_RST, _) => {
+            forget(|observer| observer.endpoint == source && observer.message_id == message_id);
+            return None;
+        }
+        (TYPE_ACK, _) => return None,
+        // An empty confirmable message is a ping
+        (TYPE_CON, CODE_EMPTY) => {
+            let mut message = Message::new(response);
+            message.header(TYPE_RST, CODE_EMPTY, message_id, &Token::new(&[]))?;
+            return Some(message.len);
+        }
+        (_, CODE_EMPTY) => return None,
+        _ => {}
+    }
+
+    let mut path: [&[u8]; MAX_PATH_SEGMENTS + 1] = [&[]; MAX_PATH_SEGMENTS + 1];
+    let mut segments = 0;
+    let mut observe = None;
+    let mut bad_option = false;
+    let mut payload: &[u8] = &[];
+
+    let mut number: u16 = 0;
+    let mut options = &request[4 + token_len..];
+    while let Some((&byte, rest)) = options.split_first() {
+        if byte == PAYLOAD_MARKER {
+            payload = rest;
+            break;
+        }
+
+        let (delta, rest) = extended(byte >> 4, rest)?;
+        let (len, rest) = extended(byte & 0x0F, rest)?;
+        let value = rest.get(..len)?;
+        options = &rest[len..];
+
+        number = number.checked_add(u16::try_from(delta).ok()?)?;
+        match number {
+            OPTION_URI_PATH => {
+                path[segments.min(MAX_PATH_SEGMENTS)] = value;
+                segments += 1;
+            }
+            OPTION_OBSERVE => {
+                observe = Some(value.iter().fold(0, |acc, b| acc << 8 | u32::from(*b)))
+            }
+            // Unrecognized critical options (the odd ones) can't be ignored
+            number if number & 1 == 1 => bad_option = true,
+            _ => {}
+        }
+    }
+
+    let resource = match path.get(..segments) {
+        Some([b".well-known", b"core"]) => Some(Resource::Core),
+        Some([b"identify"]) => Some(Resource::Identify),
+        Some([b"power"]) => Some(Resource::Power),
+        Some([b"status"]) => Some(Resource::Status),
+        Some([b"stats"]) => Some(Resource::Stats),
+        _ => None,
+    };
+
+    let (kind, message_id) = match kind {
+        TYPE_CON => (TYPE_ACK, message_id),
+        _ => (
+            TYPE_NON,
+            interrupt::free(|cs| STATE.borrow(cs).borrow_mut().next_message_id()),
+        ),
+    };
+
+    let mut message = Message::new(response);
+    message.header(kind, CODE_CONTENT, message_id, &token)?;
+
+    let code = match (resource, code) {
+        (None, _) => CODE_NOT_FOUND,
+        (Some(_), _) if bad_option => CODE_BAD_OPTION,
+        (Some(Resource::Identify), CODE_PUT) => match payload {
+            b"0" => {
+                identify(None);
+                CODE_CHANGED
+            }
+            b"1" => {
+                identify(Some(crate::identify::DEFAULT));
+                CODE_CHANGED
+            }
+            pattern => match core::str::from_utf8(pattern).map(str::parse) {
+                Ok(Ok(pattern)) => {
+                    identify(Some(pattern));
+                    CODE_CHANGED
+                }
+                _ => CODE_BAD_REQUEST,
+            },
+        },
+        (Some(Resource::Power), CODE_PUT) => {
+            let result = match payload {
+                b"on" => crate::port::set_enabled(true),
+                b"off" => crate::port::set_enabled(false),
+                b"cycle" => crate::port::cycle(crate::port::DEFAULT_CYCLE_TIME),
+                _ => return message.finish(CODE_BAD_REQUEST),
+            };
+            match result {
+                Ok(()) => CODE_CHANGED,
+                Err(err) => {
+                    log::warn!("Failed to set port: {}", err);
+                    CODE_INTERNAL_SERVER_ERROR
+                }
+            }
+        }
+        (Some(resource), CODE_GET) => {
+            let sequence = match (resource, observe) {
+                (Resource::Status, Some(0)) => observe_status(source, token),
+                (Resource::Status, Some(1)) => {
+                    forget(|observer| observer.endpoint == source && observer.token == token);
+                    None
+                }
+                _ => None,
+            };
+            if let Some(sequence) = sequence {
+                message.uint_option(OPTION_OBSERVE, sequence & 0x00FF_FFFF)?;
+            }
+
+            let format = match resource {
+                Resource::Core => FORMAT_LINK,
+                _ => FORMAT_TEXT,
+            };
+            message.uint_option(OPTION_CONTENT_FORMAT, format)?;
+            message.put(&[PAYLOAD_MARKER])?;
+            represent(resource, &mut message, context).ok()?;
+            CODE_CONTENT
+        }
+        (Some(_), _) => CODE_METHOD_NOT_ALLOWED,
+    };
+
+    message.finish(code)
+}
+
+fn represent(resource: Resource, out: &mut Message, context: &Context) -> fmt::Result {
+    match resource {
+        Resource::Core => out.write_str(CORE_LINKS),
+        Resource::Identify => write!(out, "{}", context.identifying as u8),
+        Resource::Power => {
+            if let Some(status) = crate::port::status() {
+                writeln!(out, "enabled={}", status.enabled as u8)?;
+                writeln!(out, "powered={}", status.powered as u8)?;
+            }
+            if let Some(reading) = crate::port::meter::reading() {
+                writeln!(out, "current_ma={}", reading.current_ma)?;
+                writeln!(out, "power_mw={}", reading.power_mw)?;
+                writeln!(out, "energy_mwh={}", reading.energy_mwh)?;
+            }
+            let allocated = crate::lldp::status().allocated_mw.unwrap_or_default();
+            write!(out, "allocated_mw={}", allocated)
+        }
+        Resource::Status => {
+            match &context.link {
+                Some(LinkState { speed, duplex }) => {
+                    let speed = match speed {
+                        LinkSpeed::TenMbps => 10,
+                        LinkSpeed::HundredMbps => 100,
+                    };
+                    let duplex = match duplex {
+                        LinkDuplex::HalfDuplex => "half",
+                        LinkDuplex::FullDuplex => "full",
+                    };
+                    writeln!(out, "link=up\nspeed={}\nduplex={}", speed, duplex)?;
+                }
+                None => writeln!(out, "link=down")?,
+            }
+            if let Some(status) = crate::port::status() {
+                writeln!(out, "port={}", status.powered as u8)?;
+            }
+            if let Some(temperature) = crate::sensors::temperature_c() {
+                writeln!(out, "temperature={:.1}", temperature)?;
+            }
+            write!(out, "identify={}", context.identifying as u8)
+        }
+        Resource::Stats => {
+            let stats = &context.statistics;
+            writeln!(out, "rx_octets={}", stats.rx_octets)?;
+            writeln!(out, "rx_frames={}", stats.rx_frames)?;
+            writeln!(out, "rx_errors={}", stats.rx_errors)?;
+            writeln!(out, "tx_octets={}", stats.tx_octets)?;
+            writeln!(out, "tx_frames={}", stats.tx_frames)?;
+            writeln!(out, "tx_errors={}", stats.tx_errors)?;
+            let rates = crate::efm32gg::traffic::rates();
+            writeln!(out, "rx_frames_per_s={}", rates.rx_frames)?;
+            writeln!(out, "rx_octets_per_s={}", rates.rx_octets)?;
+            writeln!(out, "tx_frames_per_s={}", rates.tx_frames)?;
+            write!(out, "tx_octets_per_s={}", rates.tx_octets)
+        }
+    }
+}
+
+// Registers (or refreshes) an observer of /status, returning the current sequence number, or
+// `None` if there's no room for another observer
+fn observe_status(endpoint: IpEndpoint, token: Token) -> Option<u32> {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        let sequence = state.sequence;
+        let slot = match state.observers.iter().position(|slot| {
+            slot.map_or(false, |observer| {
+                observer.endpoint == endpoint && observer.token == token
+            })
+        }) {
+            Some(index) => index,
+            None => state.observers.iter().position(Option::is_none)?,
+        };
+
+        state.observers[slot] = Some(Observer {
+            endpoint,
+            token,
+            message_id: 0,
+            pending: false,
+        });
+        Some(sequence)
+    })
+}
+
+fn forget<F: Fn(&Observer) -> bool>(f: F) {
+    interrupt::free(|cs| {
+        for slot in STATE.borrow(cs).borrow_mut().observers.iter_mut() {
+            if slot.as_ref().map_or(false, &f) {
+                *slot = None;
+            }
+        }
+    })
+}
+
+// Decodes the extended form of an option's delta or length
+fn extended(nibble: u8, bytes: &[u8]) -> Option<(usize, &[u8])> {
+    match nibble {
+        0..=12 => Some((usize::from(nibble), bytes)),
+        13 => {
+            let (&ext, rest) = bytes.split_first()?;
+            Some((usize::from(ext) + 13, rest))
+        }
+        14 => {
+            let ext = bytes.get(0..2)?;
+            Some((
+                usize::from(u16::from_be_bytes([ext[0], ext[1]])) + 269,
+                &bytes[2..],
+            ))
+        }
+        _ => None,
+    }
+}
+
+struct Message<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+    option: u16,
+}
+
+impl<'a> Message<'a> {
+    fn new(buffer: &'a mut [u8]) -> Message<'a> {
+        Message {
+            buffer,
+            len: 0,
+            option: 0,
+        }
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> Option<()> {
+        self.buffer
+            .get_mut(self.len..self.len + bytes.len())?
+            .copy_from_slice(bytes);
+        self.len += bytes.len();
+        Some(())
+    }
+
+    fn header(&mut self, kind: u8, code: u8, message_id: u16, token: &Token) -> Option<()> {
+        self.put(&[VERSION << 6 | kind << 4 | token.len as u8, code])?;
+        self.put(&message_id.to_be_bytes())?;
+        self.put(token.as_bytes())
+    }
+
+    // Fills in the code in the header, returning the length of the message. Anything other than a
+    // success carries no options or payload.
+    fn finish(&mut self, code: u8) -> Option<usize> {
+        self.buffer[1] = code;
+        if code & 0xE0 != 0x40 {
+            self.len = 4 + usize::from(self.buffer[0] & 0x0F);
+        }
+        Some(self.len)
+    }
+
+    // Options need to be added in order, since each is encoded relative to the previous one
+    fn option(&mut self, number: u16, value: &[u8]) -> Option<()> {
+        let (delta, delta_ext, delta_len) = extend(number - self.option);
+        let (len, len_ext, len_len) = extend(value.len() as u16);
+        self.option = number;
+
+        self.put(&[delta << 4 | len])?;
+        self.put(&delta_ext[..delta_len])?;
+        self.put(&len_ext[..len_len])?;
+        self.put(value)
+    }
+
+    fn uint_option(&mut self, number: u16, value: u32) -> Option<()> {
+        let bytes = value.to_be_bytes();
+        self.option(number, &bytes[(value.leading_zeros() / 8) as usize..])
+    }
+}
+
+impl fmt::Write for Message<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.put(s.as_bytes()).ok_or(fmt::Error)
+    }
+}
+
+// Encodes an option's delta or length, returning the nibble and any extended bytes
+fn extend(value: u16) -> (u8, [u8; 2], usize) {
+    match value {
+        0..=12 => (value as u8, [0; 2], 0),
+        13..=268 => (13, [(value - 13) as u8, 0], 1),
+        _ => (14, (value - 269).to_be_bytes(), 2),
+    }
+}
@@ -0,0 +1,305 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Transactional changes to the management network's configuration: the IPv4 addressing (a static
+// address or DHCP), the management VLAN, and the access control list. Changes are staged one at a
+// time (see `stage`) and then applied together (see `apply`), which keeps a snapshot of the
+// configuration that was running. The change is committed once a management connection is
+// accepted (see `contacted`); if none is before the timeout expires, `poll` restores the snapshot,
+// so that a change which cuts the device off from its manager can't lock it out.
+//
+// Only committed configurations are kept in the store, and the binaries restore the last one at
+// boot, so a reset in the middle of a trial also rolls it back. Since store writes block, the
+// committed configuration is written later (by `persist`).
+
+use crate::acl::{self, Rules};
+use crate::store::{self, Key};
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+use ignore_result::Ignore;
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{Ipv4Address, Ipv4Cidr};
+
+/// How long to wait for a management connection after applying a change, by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+// A configuration is stored as its address and prefix length (zero for DHCP), its gateway
+// (unspecified for none), its VLAN ID (zero for none, little-endian), and its ACL
+const STORED_LEN: usize = 11 + acl::ENCODED_LEN;
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    addressing: Addressing {
+        address: None,
+        gateway: None,
+    },
+    staged: None,
+    trial: None,
+    pending: false,
+    committed: None,
+}));
+
+struct State {
+    addressing: Addressing,
+    staged: Option<Config>,
+    trial: Option<Trial>,
+    pending: bool,
+    // A committed configuration that hasn't been written to the store yet
+    committed: Option<Config>,
+}
+
+// A change that has been applied but not yet committed
+struct Trial {
+    previous: Config,
+    deadline: Instant,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Addressing {
+    /// The static address, or `None` to use DHCP.
+    pub address: Option<Ipv4Cidr>,
+    /// The default gateway, used with a static address.
+    pub gateway: Option<Ipv4Address>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Config {
+    pub addressing: Addressing,
+    pub vlan: Option<u16>,
+    pub acl: Rules,
+}
+
+impl Config {
+    /// Sets the management VLAN, or removes it (see `vlan::set_id`).
+    pub fn set_vlan(&mut self, id: Option<u16>) -> Result<(), &'static str> {
+        crate::vlan::check_id(id)?;
+        self.vlan = id;
+        Ok(())
+    }
+}
+
+/// Restores the last committed configuration from the store. This must be called once at boot.
+pub fn init() {
+    let mut value = [0; STORED_LEN];
+    let config = match store::get(Key::Config, &mut value) {
+        Some(len) => match decode(&value[..len]) {
+            Some(config) => config,
+            None => {
+                log::warn!("Ignoring malformed configuration");
+                return;
+            }
+        },
+        None => return,
+    };
+
+    log::info!("Restoring configuration");
+    set(config);
+}
+
+/// Returns the running configuration.
+pub fn running() -> Config {
+    Config {
+        addressing: addressing(),
+        vlan: crate::vlan::id(),
+        acl: acl::rules(),
+    }
+}
+
+/// Returns the staged configuration, if any changes have been staged.
+pub fn staged() -> Option<Config> {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().staged)
+}
+
+/// Returns the running IPv4 addressing.
+pub fn addressing() -> Addressing {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().addressing)
+}
+
+/// Stages a change, on top of any that are already staged (or the running configuration).
+pub fn stage<F>(f: F) -> Result<(), &'static str>
+where
+    F: FnOnce(&mut Config) -> Result<(), &'static str>,
+{
+    let mut config = staged().unwrap_or_else(running);
+    f(&mut config)?;
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().staged = Some(config));
+    Ok(())
+}
+
+/// Discards the staged changes.
+pub fn discard() {
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().staged = None)
+}
+
+/// Applies the staged changes, rolling them back unless a management connection is accepted
+/// within the timeout.
+pub fn apply(now: Instant, timeout: Duration) -> Result<(), &'static str> {
+    let previous = running();
+    let config = interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        if state.trial.is_some() {
+            return Err("the last change hasn't been committed yet");
+        }
+
+        let config = state.staged.take().ok_or("nothing is staged")?;
+        state.trial = Some(Trial {
+            previous,
+            deadline: now + timeout,
+        });
+        Ok(config)
+    })?;
+
+    log::info!("Applying configuration; rolling back in {}", timeout);
+    set(config);
+    Ok(())
+}
+
+/// Returns how long is left before the applied change is rolled back, if it hasn't been committed.
+pub fn remaining(now: Instant) -> Option<Duration> {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        state
+            .trial
+            .as_ref()
+            .map(|trial| match trial.deadline > now {
+                true => trial.deadline - now,
+                false => Duration::ZERO,
+            })
+    })
+}
+
+/// Commits the applied change, if there is one. This is called whenever a management connection is
+/// accepted.
+pub fn contacted() {
+    let committed = interrupt::free(|cs| STATE.borrow(cs).borrow_mut().trial.take().is_some());
+    if committed {
+        let config = running();
+        interrupt::free(|cs| STATE.borrow(cs).borrow_mut().committed = Some(config));
+        log::info!("Management connection accepted; committed configuration");
+    }
+}
+
+/// Writes the last committed configuration to the store, if it hasn't been yet. This blocks while
+/// the flash is written, so it should be called periodically from a low-priority task.
+pub fn persist() {
+    let config = match interrupt::free(|cs| STATE.borrow(cs).borrow_mut().committed.take()) {
+        Some(config) => config,
+        None => return,
+    };
+
+    if let Err(err) = store::set(Key::Config, &encode(&config)) {
+        log::warn!("Failed to save configuration: {}", err);
+        // Try again next time, unless another configuration has been committed since
+        interrupt::free(|cs| {
+            STATE
+                .borrow(cs)
+                .borrow_mut()
+                .committed
+                .get_or_insert(config);
+        });
+    }
+}
+
+/// Rolls the applied change back, if it hasn't been committed before the timeout.
+pub fn poll(now: Instant) {
+    let previous = interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        match &state.trial {
+            Some(trial) if now >= trial.deadline => state.trial.take().map(|trial| trial.previous),
+            _ => None,
+        }
+    });
+
+    if let Some(previous) = previous {
+        crate::events::record(now, crate::events::Event::ConfigRolledBack);
+        set(previous);
+    }
+}
+
+/// Returns true if the addressing needs to be applied, in which case the network needs to be
+/// handled.
+pub fn pending() -> bool {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().pending)
+}
+
+/// Returns the addressing to apply to the interface, if it has changed since it was last applied.
+pub fn take_change() -> Option<Addressing> {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        match state.pending {
+            true => {
+                state.pending = false;
+                Some(state.addressing)
+            }
+            false => None,
+        }
+    })
+}
+
+fn set(config: Config) {
+    // The VLAN ID was checked when it was staged
+    crate::vlan::set_id(config.vlan)
+        .map_err(|err| log::error!("Failed to set VLAN: {}", err))
+        .ignore();
+    acl::set_rules(config.acl);
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        if state.addressing != config.addressing {
+            state.addressing = config.addressing;
+            state.pending = true;
+        }
+    })
+}
+
+fn encode(config: &Config) -> [u8; STORED_LEN] {
+    let mut value = [0; STORED_LEN];
+    if let Some(address) = config.addressing.address {
+        value[0..4].copy_from_slice(address.address().as_bytes());
+        value[4] = address.prefix_len();
+    }
+    if let Some(gateway) = config.addressing.gateway {
+        value[5..9].copy_from_slice(gateway.as_bytes());
+    }
+    value[9..11].copy_from_slice(&config.vlan.unwrap_or(0).to_le_bytes());
+    value[11..].copy_from_slice(&config.acl.encode());
+    value
+}
+
+fn decode(value: &[u8]) -> Option<Config> {
+    if value.len() != STORED_LEN {
+        return None;
+    }
+
+    let address = match value[4] {
+        0 => None,
+        prefix_len @ 1..=31 => Some(Ipv4Cidr::new(
+            Ipv4Address::from_bytes(&value[0..4]),
+            prefix_len,
+        )),
+        _ => return None,
+    };
+    let gateway = Some(Ipv4Address::from_bytes(&value[5..9])).filter(|g| !g.is_unspecified());
+    let vlan = match u16::from_le_bytes([value[9], value[10]]) {
+        0 => None,
+        id => Some(id),
+    };
+    crate::vlan::check_id(vlan).ok()?;
+
+    Some(Config {
+        addressing: Addressing { address, gateway },
+        vlan,
+        acl: Rules::decode(&value[11..])?,
+    })
+}
@@ -0,0 +1,2446 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The command interpreter behind each of the terminals (e.g. RTT). Each terminal keeps a
+// `Session`, which it hands whatever it receives along with somewhere to write the response; the
+// session collects a line of input (for terminals that receive it a byte at a time), keeps the
+// previous command and any variables, and runs each line through an `Interpreter`. `Queue` holds
+// output for terminals that send it as the other end is ready for it.
+//
+// Privileged commands (every one that does more than display something) are audited: each is
+// recorded as an operational event (see `events`), naming the terminal it was entered on, and the
+// full command is logged under the "audit" target, so it reaches any syslog collector. Passwords
+// and SNMP communities are left out of the log.
+
+use crate::efm32gg::memory::Region;
+use crate::efm32gg::registers::Peripheral;
+use crate::efm32gg::SharedMdio;
+use core::cmp;
+use core::convert::TryFrom;
+use core::fmt::{self, Write};
+use core::iter;
+use core::mem;
+use core::str;
+use ethernet_phy::{Mdio, Register};
+use heapless::{String, Vec};
+use ignore_result::Ignore;
+use smoltcp::time::Duration;
+use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, IpEndpoint, Ipv4Address, Ipv4Cidr};
+
+macro_rules! output {
+    ($writer:expr, $fmt:literal) => {
+        write!($writer, $fmt)
+            .map_err(|err| log::warn!("terminal write failed: {err}"))
+            .ignore()
+    };
+    ($writer:expr, $str:expr) => {
+        write!($writer, "{}", $str)
+            .map_err(|err| log::warn!("terminal write failed: {err}"))
+            .ignore()
+    };
+}
+
+macro_rules! outputln {
+    ($writer:expr, $fmt:literal) => {{
+        output!($writer, $fmt);
+        outputln!($writer)
+    }};
+    ($writer:expr, $str:expr) => {{
+        output!($writer, $str);
+        outputln!($writer)
+    }};
+    ($writer:expr) => {
+        output!($writer, "\n\r")
+    };
+}
+
+/// The terminals that commands are entered on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Terminal {
+    Rtt,
+    Usb,
+    Uart,
+}
+
+impl Terminal {
+    pub const ALL: [Terminal; 3] = [Terminal::Rtt, Terminal::Usb, Terminal::Uart];
+
+    pub fn index(&self) -> usize {
+        match self {
+            Terminal::Rtt => 0,
+            Terminal::Usb => 1,
+            Terminal::Uart => 2,
+        }
+    }
+}
+
+impl fmt::Display for Terminal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Terminal::Rtt => "RTT",
+            Terminal::Usb => "USB",
+            Terminal::Uart => "UART",
+        })
+    }
+}
+
+/// The commands that are audited.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Privileged {
+    Set,
+    PhyWrite,
+    I2cWrite,
+    SpiXfer,
+    Blobs,
+    Boot,
+    Auth,
+    ConfigApply,
+    Memtest,
+    Port,
+    Phy,
+    Fault,
+    Log,
+    Snmp,
+    Net,
+    Config,
+    Hostname,
+    Identify,
+    Discovery,
+    Ptp,
+    Poe,
+    Modbus,
+    Capture,
+    Wol,
+}
+
+impl Privileged {
+    pub const ALL: [Privileged; 24] = [
+        Privileged::Set,
+        Privileged::PhyWrite,
+        Privileged::I2cWrite,
+        Privileged::SpiXfer,
+        Privileged::Blobs,
+        Privileged::Boot,
+        Privileged::Auth,
+        Privileged::ConfigApply,
+        Privileged::Memtest,
+        Privileged::Port,
+        Privileged::Phy,
+        Privileged::Fault,
+        Privileged::Log,
+        Privileged::Snmp,
+        Privileged::Net,
+        Privileged::Config,
+        Privileged::Hostname,
+        Privileged::Identify,
+        Privileged::Discovery,
+        Privileged::Ptp,
+        Privileged::Poe,
+        Privileged::Modbus,
+        Privileged::Capture,
+        Privileged::Wol,
+    ];
+
+    pub fn index(&self) -> usize {
+        match self {
+            Privileged::Set => 0,
+            Privileged::PhyWrite => 1,
+            Privileged::I2cWrite => 2,
+            Privileged::SpiXfer => 3,
+            Privileged::Blobs => 4,
+            Privileged::Boot => 5,
+            Privileged::Auth => 6,
+            Privileged::ConfigApply => 7,
+            Privileged::Memtest => 8,
+            Privileged::Port => 9,
+            Privileged::Phy => 10,
+            Privileged::Fault => 11,
+            Privileged::Log => 12,
+            Privileged::Snmp => 13,
+            Privileged::Net => 14,
+            Privileged::Config => 15,
+            Privileged::Hostname => 16,
+            Privileged::Identify => 17,
+            Privileged::Discovery => 18,
+            Privileged::Ptp => 19,
+            Privileged::Poe => 20,
+            Privileged::Modbus => 21,
+            Privileged::Capture => 22,
+            Privileged::Wol => 23,
+        }
+    }
+
+    /// Returns the privileged command that the line runs, if it runs one. Anything that isn't
+    /// known to only display something counts, so a mistyped argument is audited too.
+    pub fn parse(line: &str) -> Option<Privileged> {
+        let mut tokens = line.trim().split(' ');
+        Some(match (tokens.next()?, tokens.next(), tokens.next()) {
+            ("set", _, _) => Privileged::Set,
+            ("phy", Some("write"), _) => Privileged::PhyWrite,
+            ("phy", Some("force" | "eee"), Some(_)) | ("phy", Some("mdix"), _) => Privileged::Phy,
+            ("i2c", Some("write"), _) => Privileged::I2cWrite,
+            ("spi", Some("xfer"), _) => Privileged::SpiXfer,
+            ("blobs", Some("remove" | "format"), _) => Privileged::Blobs,
+            ("boot", Some("stage" | "revert"), _) => Privileged::Boot,
+            ("auth", Some(_), _) => Privileged::Auth,
+            ("config", Some("apply"), _) => Privileged::ConfigApply,
+            ("config", Some(_), _) => Privileged::Config,
+            ("memtest", Some(_), _) => Privileged::Memtest,
+            ("port", Some(_), _) => Privileged::Port,
+            ("fault", Some("monitor"), _) => Privileged::Fault,
+            ("log", Some("syslog"), _) | ("log", Some("level"), Some(_)) => Privileged::Log,
+            ("snmp", Some(_), _) => Privileged::Snmp,
+            ("net", Some("stats" | "neighbors"), _)
+            | ("net", Some("dhcp" | "selftest"), None)
+            | ("net", Some("route"), Some("show")) => return None,
+            ("net", Some(_), _) => Privileged::Net,
+            ("hostname", Some(_), _) => Privileged::Hostname,
+            ("identify", Some(_), _) => Privileged::Identify,
+            ("discovery", Some(_), _) => Privileged::Discovery,
+            ("ptp", Some(_), _) => Privileged::Ptp,
+            ("poe", Some("request"), _) => Privileged::Poe,
+            ("modbus", Some(_), _) => Privileged::Modbus,
+            ("capture", Some(_), _) => Privileged::Capture,
+            ("wol", Some(_), _) => Privileged::Wol,
+            _ => return None,
+        })
+    }
+
+    /// Returns the start of the line (e.g. "auth password") if the rest of it is a secret that
+    /// mustn't be logged.
+    pub fn secret(line: &str) -> Option<&'static str> {
+        ["auth password", "snmp community"]
+            .iter()
+            .find(|command| {
+                line.strip_prefix(**command)
+                    .map_or(false, |rest| rest.starts_with(' '))
+            })
+            .copied()
+    }
+}
+
+impl fmt::Display for Privileged {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Privileged::Set => "set",
+            Privileged::PhyWrite => "phy write",
+            Privileged::I2cWrite => "i2c write",
+            Privileged::SpiXfer => "spi xfer",
+            Privileged::Blobs => "blobs",
+            Privileged::Boot => "boot",
+            Privileged::Auth => "auth",
+            Privileged::ConfigApply => "config apply",
+            Privileged::Memtest => "memtest",
+            Privileged::Port => "port",
+            Privileged::Phy => "phy",
+            Privileged::Fault => "fault",
+            Privileged::Log => "log",
+            Privileged::Snmp => "snmp",
+            Privileged::Net => "net",
+            Privileged::Config => "config",
+            Privileged::Hostname => "hostname",
+            Privileged::Identify => "identify",
+            Privileged::Discovery => "discovery",
+            Privileged::Ptp => "ptp",
+            Privileged::Poe => "poe",
+            Privileged::Modbus => "modbus",
+            Privileged::Capture => "capture",
+            Privileged::Wol => "wol",
+        })
+    }
+}
+
+pub struct Interpreter<'a> {
+    output: &'a mut dyn Write,
+    terminal: Terminal,
+    variables: &'a mut Variables,
+}
+
+impl<'a> Interpreter<'a> {
+    const HELP_STR: &'static str = "Terminal Help
+
+Available commands:
+
+  acl                              Display the allowed source prefixes and enabled services
+  adc read <hex input>             Sample an ADC input (e.g. 24 for PC4), in millivolts
+  auth                             Display whether a password is set, and how many tokens are open
+  auth password <password>|off     Require a token (from logging in) to change state, or don't
+  auth token                       Issue a token without logging in
+  auth revoke                      Revoke every token that has been issued
+  blobs                            List the blobs in external flash
+  blobs remove <name>              Remove a blob from external flash
+  blobs format                     Erase every blob from external flash
+  boot                             Display the running bank and the update staged in bank B
+  boot stage <hex ver> <hex len>   Boot the image written to bank B from the next reset on
+  boot revert                      Reject the update in bank B, and boot bank A instead
+  boot image                       Display the running image's version, SHA-256, and signature
+  capture                          Display the state of the frame capture
+  capture rtt|tcp [<filter>]       Stream frames (or an EtherType or host's) as pcap
+  capture buffer [<filter>]        Keep frames in the buffer, to be fetched over TFTP
+  capture off                      Stop capturing frames
+  crc <hex address> <hex len>      Compute the CRC-32 of memory (e.g. an image in flash)
+  config                           Display the running and staged network configuration
+  config address <cidr>|dhcp       Stage a static address (e.g. 10.0.0.2/24), or DHCP
+  config gateway <address>|none    Stage the default gateway used with a static address
+  config vlan <id>|off             Stage the management VLAN
+  config allow|remove <prefix>     Stage a change to the allowed source prefixes
+  config <service> on|off          Stage enabling or disabling a service
+  config apply [<seconds>]         Apply the staged changes, rolling back unless contacted in time
+  config discard                   Discard the staged changes
+  get <hex address>                Read address
+  read <hex address> <hex len>     Display memory as hex and ASCII
+  dis <hex address> <hex len>      Disassemble Thumb instructions
+  set <hex address> <hex value>    Write value to address
+  discovery beacons on|off         Broadcast announcements once an address is first acquired
+  events show                      Display the operational event log
+  fault last                       Display the fault that ended the previous boot
+  fault monitor <ip address>|off   Send fault reports to a monitor before resetting
+  hostname                         Display the hostname
+  hostname <name>|default          Set the hostname, or go back to the one made from the unique ID
+  i2c scan                         List the addresses that acknowledge on the I2C bus
+  i2c read <addr> <reg> [<count>]  Read registers from an I2C device (all in hex)
+  i2c write <addr> <reg> <bytes>   Write registers on an I2C device (all in hex)
+  identify                         Display the pattern being flashed by the Identify LED
+  identify on|off                  Flash the default pattern, or stop flashing
+  identify sos                     Flash SOS
+  identify blink <Hz> [<%> [<n>]]  Blink at a rate and duty, a number of times
+  log show                         Display the recent log output
+  log syslog <ip address>|off      Forward log records to a syslog collector
+  log level                        List the per-target log levels
+  log level <target> <level>       Limit the log level of a target (or \"default\")
+  memtest                          Display the range of RAM that's free to test
+  memtest <addr> <len> [<pattern>] Test RAM with walking ones, addresses, a pattern (all in hex)
+  modbus                           Display the unit ID that Modbus requests are answered for
+  modbus unit <id>                 Answer Modbus requests addressed to another unit ID
+  net stats                        Display the traffic, ICMP, and RX counters, limits, and VLAN
+  net echo on|off|<per second>     Answer all, none, or a limited rate of echo requests
+  net limit <class> <n> [<burst>]  Limit echo, discovery, or accept to n per second
+  net limit <class> off            Lift the limit on a class of traffic
+  net idle <seconds>|off           Abort control connections that stay open for too long
+  net neighbors                    Display the neighbor (ARP) cache, with ages and evictions
+  net neighbor flush               Forget the cached neighbors, other than the static ones
+  net neighbor add <ip> <mac>      Keep a neighbor's hardware address in the cache
+  net neighbor remove <ip>         Stop keeping a neighbor in the cache
+  net route show                   Display the route table
+  net route add <cidr> <gateway>   Route a network through a gateway (kept across resets)
+  net route del <cidr>             Remove a static route
+  net dhcp                         Display the DHCP client's progress and retry settings
+  net dhcp retries <count>         Set the attempts given the full timeout, before backing off
+  net dhcp timeout <seconds>       Set how long an attempt has to acquire a lease
+  net selftest                     Display the result of the last loopback self-test
+  net selftest phy|mac             Loop test frames back through the PHY or the MAC
+  periph cmu|eth                   Display the clock or ethernet registers and their fields
+  periph gpio <port>               Display a GPIO port's registers and the mode of each pin
+  phy status                       Display the PHY's address and fault counts
+  phy dump                         Display the PHY's registers and their fields
+  phy read <hex reg>               Read a PHY register
+  phy write <hex reg> <hex value>  Write a PHY register
+  phy force                        Display the link's speed, duplex, and wiring overrides
+  phy force auto|<speed>-<duplex>  Autonegotiate or force the link (e.g. 100-full)
+  phy mdix auto|mdi|mdix           Detect the wiring or force it
+  phy eee                          Display the state of Energy Efficient Ethernet
+  phy eee on|off                   Enable or disable advertising Energy Efficient Ethernet
+  poe status                       Display the state of the power negotiation with the PSE
+  poe request <mW>                 Set the power requested from the PSE
+  port                             Display the state of the downstream port
+  port on|off                      Enable or disable power to the downstream port
+  port cycle [ms]                  Remove power from the downstream port for a while
+  port delay <ms>                  Set the delay between link-up and powering the port
+  port schedule <minutes>|off      Power cycle the downstream port at an interval
+  port probe <ip address>|off      Power cycle the downstream port when it stops answering pings
+  port failures <count>            Set the number of missed pings that trigger a power cycle
+  port energy reset                Clear the energy delivered to the downstream port
+  port limit <mA>|off              Set the current at which the downstream port is tripped
+  ptp                              Display the state of the PTP slave
+  ptp on|off                       Enable or disable synchronizing to a PTP master
+  snmp community <name>|off        Allow SNMP sets using a community, or disallow all sets
+  snmp trap <ip address>|off       Send operational events to an SNMP trap receiver
+  spi xfer <hex byte>...           Exchange bytes with the SPI device, displaying its reply
+  sysinfo                          Display the part, reset cause, CPU load, and device health
+  wol send <mac address>           Wake a host on the local network with a magic packet
+  !!                               Run the previous command again
+  let                              Display the variables, along with $prog, $sp, and $uptime
+  let <name> [<value>]             Set a variable, which is substituted for $name, or remove it
+  help                             Display this help text";
+
+    pub fn new(
+        output: &'a mut dyn Write,
+        terminal: Terminal,
+        variables: &'a mut Variables,
+    ) -> Interpreter<'a> {
+        Interpreter {
+            output,
+            terminal,
+            variables,
+        }
+    }
+
+    /// Writes the first prompt.
+    pub fn start(&mut self) {
+        outputln!(self.output);
+        self.prompt();
+    }
+
+    /// Runs the command on the line, once its variables are substituted, and then writes the next
+    /// prompt.
+    pub fn execute(&mut self, line: &str) {
+        let mut expanded = String::new();
+        match self.variables.expand(line, &mut expanded) {
+            Ok(()) => {
+                if let Some(command) = Privileged::parse(&expanded) {
+                    self.audit(command, &expanded);
+                }
+                self.run(&expanded);
+            }
+            Err(err) => outputln!(self.output, "Failed to substitute variables: {err}"),
+        }
+        self.prompt();
+    }
+
+    fn prompt(&mut self) {
+        let hostname = crate::hostname::get();
+        output!(self.output, "{hostname}> ");
+    }
+
+    // Records a privileged command before it's run, whether or not it succeeds
+    fn audit(&self, command: Privileged, line: &str) {
+        let terminal = self.terminal;
+        let line = line.trim();
+        match Privileged::secret(line) {
+            Some(command) => log::info!(target: "audit", "{terminal}: {command} (redacted)"),
+            None => log::info!(target: "audit", "{terminal}: {line}"),
+        }
+        crate::events::record(
+            crate::time::now(),
+            crate::events::Event::Command(terminal, command),
+        );
+    }
+
+    fn run(&mut self, line: &str) {
+        let mut tokens = line.trim().split(' ');
+
+        macro_rules! token_u32 {
+            ($name:literal) => {
+                match tokens.next() {
+                    Some(val) => match parse_hex(val) {
+                        Ok(val) => val,
+                        Err(err) => {
+                            output!(self.output, concat!("Failed to parse ", $name));
+                            outputln!(self.output, " ({val}): {err}");
+                            return;
+                        }
+                    },
+                    None => {
+                        outputln!(self.output, Self::HELP_STR);
+                        return;
+                    }
+                }
+            };
+        }
+
+        match tokens.next() {
+            Some("") | None => {}
+            Some("help") => outputln!(self.output, Self::HELP_STR),
+            Some("let") => match (tokens.next(), tokens.next(), tokens.next()) {
+                (None, _, _) => self.list_variables(),
+                (Some(name), value, None) => {
+                    if let Err(err) = self.variables.set(name, value) {
+                        outputln!(self.output, "Failed to set {name}: {err}");
+                    }
+                }
+                _ => outputln!(self.output, Self::HELP_STR),
+            },
+            Some("get") => {
+                let addr = token_u32!("addr");
+                let len = match addr % 4 {
+                    0 => 4,
+                    2 => 2,
+                    _ => 1,
+                };
+                if let Err(err) = crate::efm32gg::memory::check(addr, len, false) {
+                    return outputln!(self.output, "Failed to read 0x{addr:08X}: {err}");
+                }
+
+                let addr = addr as usize;
+                match addr % mem::size_of::<u32>() {
+                    0 => {
+                        let data = unsafe { *(addr as *const u32) };
+                        outputln!(self.output, "0x{data:08X}");
+                    }
+                    2 => {
+                        let data = unsafe { *(addr as *const u16) };
+                        outputln!(self.output, "0x{data:04X}");
+                    }
+                    1 | 3 => {
+                        let data = unsafe { *(addr as *const u8) };
+                        outputln!(self.output, "0x{data:02X}");
+                    }
+                    val => log::error!("unhandled val: {val}"),
+                }
+            }
+            Some("adc") => match tokens.next() {
+                Some("read") => {
+                    let input = token_u32!("input");
+                    let input = match u8::try_from(input) {
+                        Ok(input) => input,
+                        Err(_) => {
+                            return outputln!(self.output, "Failed to parse input: {input:X}")
+                        }
+                    };
+                    match crate::efm32gg::adc::read_channel(input) {
+                        Some(millivolts) => outputln!(self.output, "{millivolts} mV"),
+                        None => outputln!(self.output, "ADC unavailable"),
+                    }
+                }
+                _ => outputln!(self.output, Self::HELP_STR),
+            },
+            Some("crc") => {
+                let addr = token_u32!("addr");
+                let len = token_u32!("len");
+                let last = addr.saturating_add(len.saturating_sub(1));
+                let region = crate::efm32gg::memory::check(addr, 1, false);
+                match (region, crate::efm32gg::memory::check(last, 1, false)) {
+                    (Ok(Region::Flash | Region::Info | Region::Ram), Ok(end))
+                        if Ok(end) == region =>
+                    {
+                        let crc = crate::efm32gg::gpcrc::crc32_at(addr, len);
+                        outputln!(self.output, "0x{crc:08X}");
+                    }
+                    (Err(err), _) | (_, Err(err)) => {
+                        outputln!(self.output, "Failed to read: {err}")
+                    }
+                    _ => outputln!(
+                        self.output,
+                        "Failed to read: the range must be within flash or RAM"
+                    ),
+                }
+            }
+            Some("read") => {
+                let addr = token_u32!("addr");
+                let len = token_u32!("len");
+                self.read(addr, len);
+            }
+            Some("dis") => {
+                let addr = token_u32!("addr");
+                let len = token_u32!("len");
+                self.disassemble(addr, len);
+            }
+            Some("set") => {
+                let addr = token_u32!("addr");
+                let value = token_u32!("value");
+                if let Err(err) = crate::efm32gg::memory::check(addr, 4, true) {
+                    return outputln!(self.output, "Failed to write 0x{addr:08X}: {err}");
+                }
+                unsafe { *(addr as *mut u32) = value };
+            }
+            Some("fault") => match (tokens.next(), tokens.next()) {
+                (Some("last"), None) => match crate::fault::last() {
+                    Some(report) => outputln!(self.output, "{report}"),
+                    None => outputln!(self.output, "No fault recorded"),
+                },
+                (Some("monitor"), Some("off")) => crate::fault::set_monitor(None),
+                (Some("monitor"), Some(addr)) => match addr.parse::<Ipv4Address>() {
+                    Ok(addr) => crate::fault::set_monitor(Some(addr)),
+                    Err(_) => outputln!(self.output, "Failed to parse address: {addr}"),
+                },
+                _ => outputln!(self.output, Self::HELP_STR),
+            },
+            Some("i2c") => match tokens.next() {
+                Some("scan") => self.i2c_scan(),
+                Some("read") => {
+                    let addr = token_u32!("addr");
+                    let register = token_u32!("reg");
+                    self.i2c_read(addr, register, tokens.next())
+                }
+                Some("write") => {
+                    let addr = token_u32!("addr");
+                    let register = token_u32!("reg");
+                    self.i2c_write(addr, register, tokens)
+                }
+                _ => outputln!(self.output, Self::HELP_STR),
+            },
+            Some("identify") => match tokens.next() {
+                None => match crate::identify::active() {
+                    Some(pattern) => outputln!(self.output, "Identifying: {pattern}"),
+                    None => outputln!(self.output, "Not identifying"),
+                },
+                Some("off") => crate::identify::request(None),
+                Some("on") => crate::identify::request(Some(crate::identify::DEFAULT)),
+                Some(word) => {
+                    let words = iter::once(word).chain(tokens.filter(|word| !word.is_empty()));
+                    match crate::identify::Pattern::from_words(words) {
+                        Ok(pattern) => crate::identify::request(Some(pattern)),
+                        Err(err) => outputln!(self.output, "Failed to parse pattern: {err}"),
+                    }
+                }
+            },
+            Some("poe") => match (tokens.next(), tokens.next()) {
+                (Some("status"), None) => self.poe_status(),
+                (Some("request"), Some(mw)) => match mw.parse() {
+                    Ok(mw) => crate::lldp::set_request(mw),
+                    Err(err) => outputln!(self.output, "Failed to parse power ({mw}): {err}"),
+                },
+                _ => outputln!(self.output, Self::HELP_STR),
+            },
+            Some("acl") => self.acl(tokens.next()),
+            Some("auth") => self.auth(tokens.next(), tokens.next()),
+            Some("blobs") => self.blobs(tokens.next(), tokens.next()),
+            Some("boot") => match tokens.next() {
+                None => self.boot_status(),
+                Some("stage") => {
+                    let version = token_u32!("version");
+                    let len = token_u32!("length");
+                    match crate::boot::stage(version, len) {
+                        Ok(image) => {
+                            let (version, crc) = (image.version, image.crc);
+                            outputln!(self.output, "Staged version {version:08X} (CRC {crc:08X})")
+                        }
+                        Err(err) => outputln!(self.output, "Failed to stage image: {err}"),
+                    }
+                }
+                Some("image") => self.boot_image(),
+                Some("revert") => match crate::boot::reject() {
+                    Ok(()) => outputln!(self.output, "Bank A will be booted from the next reset"),
+                    Err(err) => outputln!(self.output, "Failed to reject image: {err}"),
+                },
+                _ => outputln!(self.output, Self::HELP_STR),
+            },
+            Some("capture") => self.capture(tokens.next(), tokens.next()),
+            Some("config") => self.config(tokens.next(), tokens.next()),
+            Some("events") => match tokens.next() {
+                Some("show") => crate::events::for_each(|entry| {
+                    let (boot, timestamp, event) = (entry.boot, entry.timestamp, entry.event);
+                    let severity = event.severity();
+                    outputln!(
+                        self.output,
+                        "boot {boot:<5} {timestamp} {severity:<5} {event}"
+                    );
+                }),
+                _ => outputln!(self.output, Self::HELP_STR),
+            },
+            Some("discovery") => match (tokens.next(), tokens.next()) {
+                (Some("beacons"), Some("on")) => crate::discovery::set_beacons(true),
+                (Some("beacons"), Some("off")) => crate::discovery::set_beacons(false),
+                _ => outputln!(self.output, Self::HELP_STR),
+            },
+            Some("hostname") => match tokens.next() {
+                None => {
+                    let hostname = crate::hostname::get();
+                    outputln!(self.output, "Hostname: {hostname}")
+                }
+                Some(name) => {
+                    let name = match name {
+                        "default" => None,
+                        name => Some(name),
+                    };
+                    if let Err(err) = crate::hostname::set(name) {
+                        outputln!(self.output, "Failed to set hostname: {err}");
+                    }
+                }
+            },
+            Some("memtest") => match (tokens.next(), tokens.next(), tokens.next()) {
+                (None, _, _) => {
+                    let range = crate::memtest::testable();
+                    let (start, end) = (range.start, range.end);
+                    outputln!(self.output, "Free to test: 0x{start:08X}-0x{end:08X}");
+                }
+                (Some(_), _, _) if tokens.next().is_some() => {
+                    outputln!(self.output, Self::HELP_STR)
+                }
+                (Some(addr), Some(len), pattern) => self.memtest(addr, len, pattern),
+                _ => outputln!(self.output, Self::HELP_STR),
+            },
+            Some("modbus") => match (tokens.next(), tokens.next()) {
+                (None, _) => {
+                    let id = crate::modbus::unit_id();
+                    outputln!(self.output, "Unit ID: {id}")
+                }
+                (Some("unit"), Some(id)) => match id.parse() {
+                    Ok(id) => crate::modbus::set_unit_id(id),
+                    Err(_) => outputln!(self.output, "Failed to parse unit ID: {id}"),
+                },
+                _ => outputln!(self.output, Self::HELP_STR),
+            },
+            Some("net") => match (tokens.next(), tokens.next()) {
+                (Some("stats"), None) => self.net_stats(),
+                (Some("echo"), Some("on")) => crate::icmp::set_echo_limit(None),
+                (Some("echo"), Some("off")) => crate::icmp::set_echo_limit(Some(0)),
+                (Some("echo"), Some(limit)) => match limit.parse() {
+                    Ok(limit) => crate::icmp::set_echo_limit(Some(limit)),
+                    Err(_) => outputln!(self.output, "Failed to parse limit: {limit}"),
+                },
+                (Some("selftest"), None) => match crate::selftest::last_result() {
+                    Some((loopback, Ok(()))) => {
+                        outputln!(self.output, "{loopback} loopback self-test passed")
+                    }
+                    Some((loopback, Err(err))) => {
+                        outputln!(self.output, "{loopback} loopback self-test failed: {err}")
+                    }
+                    None => outputln!(self.output, "No self-test has been run"),
+                },
+                (Some("selftest"), Some(loopback)) => match loopback.parse() {
+                    Ok(loopback) => crate::selftest::request(loopback),
+                    Err(err) => outputln!(self.output, "Failed to start self-test: {err}"),
+                },
+                (Some("dhcp"), setting) => self.dhcp(setting, tokens.next()),
+                (Some("limit"), Some(class)) => self.limit(class, tokens.next(), tokens.next()),
+                (Some("route"), Some(command)) => self.route(command, tokens.next(), tokens.next()),
+                (Some("neighbors"), None) => self.neighbors(),
+                (Some("neighbor"), Some(command)) => {
+                    self.neighbor(command, tokens.next(), tokens.next())
+                }
+                (Some("idle"), Some("off")) => crate::network::set_tcp_idle_limit(None),
+                (Some("idle"), Some(limit)) => match limit.parse() {
+                    Ok(limit) => {
+                        crate::network::set_tcp_idle_limit(Some(Duration::from_secs(limit)))
+                    }
+                    Err(_) => outputln!(self.output, "Failed to parse limit: {limit}"),
+                },
+                _ => outputln!(self.output, Self::HELP_STR),
+            },
+            Some("port") => self.port(tokens.next(), tokens.next()),
+            Some("phy") => match tokens.next() {
+                Some("read") => {
+                    let register = token_u32!("register");
+                    self.phy_read(register);
+                }
+                Some("write") => {
+                    let register = token_u32!("register");
+                    let value = token_u32!("value");
+                    self.phy_write(register, value);
+                }
+                command => self.phy(command, tokens.next()),
+            },
+            Some("ptp") => match tokens.next() {
+                None => self.ptp(),
+                Some("on") => crate::ptp::set_enabled(true),
+                Some("off") => crate::ptp::set_enabled(false),
+                _ => outputln!(self.output, Self::HELP_STR),
+            },
+            Some("snmp") => match (tokens.next(), tokens.next()) {
+                (Some("community"), Some("off")) => crate::snmp::set_write_community(None).ignore(),
+                (Some("community"), Some(name)) => {
+                    if let Err(err) = crate::snmp::set_write_community(Some(name)) {
+                        outputln!(self.output, "Failed to set community: {err}");
+                    }
+                }
+                (Some("trap"), Some("off")) => crate::snmp::set_trap_receiver(None),
+                (Some("trap"), Some(addr)) => match addr.parse::<IpAddress>() {
+                    Ok(addr) => crate::snmp::set_trap_receiver(Some(addr)),
+                    Err(_) => outputln!(self.output, "Failed to parse address: {addr}"),
+                },
+                _ => outputln!(self.output, Self::HELP_STR),
+            },
+            Some("spi") => match tokens.next() {
+                Some("xfer") => self.spi_xfer(tokens),
+                _ => outputln!(self.output, Self::HELP_STR),
+            },
+            Some("sysinfo") => self.sysinfo(),
+            Some("periph") => {
+                let peripheral = match (tokens.next(), tokens.next()) {
+                    (Some("cmu"), None) => Peripheral::Cmu,
+                    (Some("eth"), None) => Peripheral::Eth,
+                    (Some("gpio"), Some(port)) => match port.parse() {
+                        Ok(port) => Peripheral::Gpio(port),
+                        Err(err) => return outputln!(self.output, "Failed to parse port: {err}"),
+                    },
+                    _ => return outputln!(self.output, Self::HELP_STR),
+                };
+                crate::efm32gg::registers::dump(peripheral, &mut |line: fmt::Arguments| {
+                    outputln!(self.output, line)
+                });
+            }
+            Some("wol") => match (tokens.next(), tokens.next()) {
+                (Some("send"), Some(addr)) => match addr.parse::<EthernetAddress>() {
+                    Ok(addr) => {
+                        if let Err(err) = crate::wol::request(addr) {
+                            outputln!(self.output, "Failed to send magic packet: {err}");
+                        }
+                    }
+                    Err(_) => outputln!(self.output, "Failed to parse address: {addr}"),
+                },
+                _ => outputln!(self.output, Self::HELP_STR),
+            },
+            Some("log") => match (tokens.next(), tokens.next(), tokens.next()) {
+                (Some("show"), None, None) => crate::log::memory::dump(|chunk| {
+                    let mut lines = chunk.split('\n');
+                    if let Some(line) = lines.next() {
+                        output!(self.output, line);
+                    }
+                    for line in lines {
+                        outputln!(self.output);
+                        output!(self.output, line);
+                    }
+                }),
+                (Some("syslog"), Some("off"), None) => crate::log::syslog::set_collector(None),
+                (Some("syslog"), Some(addr), None) => match addr.parse::<IpAddress>() {
+                    Ok(addr) => crate::log::syslog::set_collector(Some(IpEndpoint::new(
+                        addr,
+                        crate::log::syslog::PORT,
+                    ))),
+                    Err(_) => outputln!(self.output, "Failed to parse address: {addr}"),
+                },
+                (Some("level"), None, None) => crate::log::filter::for_each(|target, level| {
+                    outputln!(self.output, "{target} {level}");
+                }),
+                (Some("level"), Some(target), Some("default")) => {
+                    if !crate::log::filter::remove(target) {
+                        outputln!(self.output, "No level set for {target}");
+                    }
+                }
+                (Some("level"), Some(target), Some(level)) => match level.parse() {
+                    Ok(level) => {
+                        if let Err(err) = crate::log::filter::set(target, level) {
+                            outputln!(self.output, "Failed to set level: {err}");
+                        }
+                    }
+                    Err(_) => outputln!(self.output, "Unrecognized level: {level}"),
+                },
+                _ => outputln!(self.output, Self::HELP_STR),
+            },
+            Some(command) => outputln!(self.output, "Unrecognized command: {command} (try 'help')"),
+        }
+    }
+
+    fn poe_status(&mut self) {
+        let status = crate::lldp::status();
+        let (negotiation, requested) = (status.negotiation, status.requested_mw);
+        outputln!(self.output, "Negotiation: {negotiation}");
+        outputln!(self.output, "Requested: {requested} mW");
+        match status.allocated_mw {
+            Some(allocated) => outputln!(self.output, "Allocated: {allocated} mW"),
+            None => outputln!(self.output, "Allocated: none"),
+        }
+        let budget = status.budget_mw;
+        outputln!(self.output, "Port budget: {budget} mW");
+    }
+
+    fn port(&mut self, command: Option<&str>, arg: Option<&str>) {
+        use crate::port;
+
+        let millis = |arg: &str| {
+            arg.parse::<u32>()
+                .map(|ms| Duration::from_millis(ms.into()))
+        };
+        let result = match (command, arg) {
+            (None, None) => {
+                self.port_status();
+                Ok(())
+            }
+            (Some("on"), None) => port::set_enabled(true),
+            (Some("off"), None) => port::set_enabled(false),
+            (Some("cycle"), None) => port::cycle(port::DEFAULT_CYCLE_TIME),
+            (Some("cycle"), Some(ms)) => match millis(ms) {
+                Ok(off_time) => port::cycle(off_time),
+                Err(_) => Err("failed to parse off-time"),
+            },
+            (Some("delay"), Some(ms)) => match millis(ms) {
+                Ok(delay) => {
+                    port::set_link_delay(delay);
+                    Ok(())
+                }
+                Err(_) => Err("failed to parse delay"),
+            },
+            (Some("schedule"), Some("off")) => {
+                port::schedule::set_interval(None);
+                Ok(())
+            }
+            (Some("schedule"), Some(minutes)) => match minutes.parse::<u32>() {
+                Ok(0) => Err("interval must be at least one minute"),
+                Ok(minutes) => {
+                    port::schedule::set_interval(Some(Duration::from_secs(
+                        u64::from(minutes) * 60,
+                    )));
+                    Ok(())
+                }
+                Err(_) => Err("failed to parse interval"),
+            },
+            (Some("probe"), Some("off")) => {
+                port::schedule::set_probe(None);
+                Ok(())
+            }
+            (Some("probe"), Some(addr)) => match addr.parse::<Ipv4Address>() {
+                Ok(addr) => {
+                    port::schedule::set_probe(Some(addr));
+                    Ok(())
+                }
+                Err(_) => Err("failed to parse address"),
+            },
+            (Some("limit"), Some("off")) => port::protect::set_limit(None),
+            (Some("limit"), Some(limit)) => match limit.parse() {
+                Ok(limit) => port::protect::set_limit(Some(limit)),
+                Err(_) => Err("failed to parse limit"),
+            },
+            (Some("energy"), Some("reset")) => {
+                port::meter::reset();
+                Ok(())
+            }
+            (Some("failures"), Some(count)) => match count.parse() {
+                Ok(count) => port::schedule::set_failure_threshold(count),
+                Err(_) => Err("failed to parse count"),
+            },
+            _ => {
+                outputln!(self.output, Self::HELP_STR);
+                Ok(())
+            }
+        };
+
+        if let Err(err) = result {
+            outputln!(self.output, "Port command failed: {err}");
+        }
+    }
+
+    fn port_status(&mut self) {
+        let status = match crate::port::status() {
+            Some(status) => status,
+            None => {
+                outputln!(self.output, "Port power switching isn't available");
+                return;
+            }
+        };
+
+        let power = match (status.powered, status.enabled, status.tripped) {
+            (true, _, _) => "on",
+            (false, true, true) => "off (tripped)",
+            (false, true, false) => "off (enabled)",
+            (false, false, _) => "off (disabled)",
+        };
+        let link = match status.link {
+            true => "up",
+            false => "down",
+        };
+        let delay = status.link_delay;
+        outputln!(self.output, "Power: {power}");
+        outputln!(self.output, "Link: {link}");
+        outputln!(self.output, "Power-on delay: {delay}");
+        if let Some(reading) = crate::port::meter::reading() {
+            let (current, power) = (reading.current_ma, reading.power_mw);
+            let energy = reading.energy_mwh;
+            outputln!(self.output, "Load: {current} mA ({power} mW)");
+            outputln!(self.output, "Energy: {energy} mWh");
+        }
+
+        let protect = crate::port::protect::status();
+        match protect.limit_ma {
+            Some(limit) => outputln!(self.output, "Current limit: {limit} mA"),
+            None => outputln!(self.output, "Current limit: off"),
+        }
+        if let Some(budget) = protect.budget_ma {
+            outputln!(self.output, "Budget limit: {budget} mA");
+        }
+        let trips = protect.trips;
+        match protect.last_trip {
+            Some((at, reason)) => outputln!(self.output, "Trips: {trips} (last {reason} at {at})"),
+            None => outputln!(self.output, "Trips: {trips}"),
+        }
+
+        let schedule = crate::port::schedule::status();
+        match schedule.interval {
+            Some(interval) => outputln!(self.output, "Scheduled power cycle: every {interval}"),
+            None => outputln!(self.output, "Scheduled power cycle: off"),
+        }
+        let (failures, threshold) = (schedule.failures, schedule.threshold);
+        match schedule.target {
+            Some(target) => outputln!(
+                self.output,
+                "Liveness probe: {target} ({failures} of {threshold} missed)"
+            ),
+            None => outputln!(self.output, "Liveness probe: off"),
+        }
+    }
+
+    fn auth(&mut self, command: Option<&str>, arg: Option<&str>) {
+        use crate::auth;
+
+        let now = crate::time::now();
+        match (command, arg) {
+            (None, None) => match auth::is_required() {
+                true => {
+                    let (sessions, lifetime) = (auth::sessions(now), auth::LIFETIME);
+                    outputln!(
+                        self.output,
+                        "Password set; {sessions} tokens open (each lasts {lifetime})"
+                    )
+                }
+                false => outputln!(self.output, "No password set; tokens aren't required"),
+            },
+            (Some("password"), Some(arg)) => {
+                let password = match arg {
+                    "off" => None,
+                    password => Some(password),
+                };
+                if let Err(err) = auth::set_password(password) {
+                    outputln!(self.output, "Failed to set password: {err}");
+                }
+            }
+            (Some("token"), None) => match auth::issue(now) {
+                Ok(token) => outputln!(self.output, "{token}"),
+                Err(err) => outputln!(self.output, "Failed to issue token: {err}"),
+            },
+            (Some("revoke"), None) => auth::revoke_all(),
+            _ => outputln!(self.output, Self::HELP_STR),
+        }
+    }
+
+    fn acl(&mut self, command: Option<&str>) {
+        use crate::acl;
+
+        if command.is_some() {
+            // Changes go through the configuration, so that they're rolled back if they lock the
+            // manager out
+            outputln!(self.output, Self::HELP_STR);
+            return;
+        }
+
+        outputln!(self.output, "Allowed sources:");
+        let mut any = false;
+        acl::for_each(|prefix| {
+            any = true;
+            outputln!(self.output, "  {prefix}");
+        });
+        if !any {
+            outputln!(self.output, "  any");
+        }
+        outputln!(self.output, "Services:");
+        for service in acl::SERVICES {
+            let status = match acl::enabled(service) {
+                true => "enabled",
+                false => "disabled",
+            };
+            outputln!(self.output, "  {service:<16} {status}");
+        }
+    }
+
+    fn config(&mut self, command: Option<&str>, arg: Option<&str>) {
+        use crate::config;
+
+        let prefix = |arg: &str| arg.parse::<IpCidr>().map_err(|_| "invalid prefix");
+        let result = match (command, arg) {
+            (None, None) => {
+                self.output_config("Running", &config::running());
+                if let Some(staged) = config::staged() {
+                    self.output_config("Staged", &staged);
+                }
+                if let Some(remaining) = config::remaining(crate::time::now()) {
+                    outputln!(self.output, "Rolling back in {remaining} unless contacted");
+                }
+                Ok(())
+            }
+            (Some("address"), Some("dhcp")) => config::stage(|config| {
+                config.addressing.address = None;
+                Ok(())
+            }),
+            (Some("address"), Some(arg)) => arg
+                .parse::<Ipv4Cidr>()
+                .map_err(|_| "invalid address")
+                .and_then(|address| match address.prefix_len() {
+                    0 | 32 => Err("invalid prefix length"),
+                    _ => Ok(address),
+                })
+                .and_then(|address| {
+                    config::stage(|config| {
+                        config.addressing.address = Some(address);
+                        Ok(())
+                    })
+                }),
+            (Some("gateway"), Some("none")) => config::stage(|config| {
+                config.addressing.gateway = None;
+                Ok(())
+            }),
+            (Some("gateway"), Some(arg)) => arg
+                .parse::<Ipv4Address>()
+                .map_err(|_| "invalid address")
+                .and_then(|gateway| {
+                    config::stage(|config| {
+                        config.addressing.gateway = Some(gateway);
+                        Ok(())
+                    })
+                }),
+            (Some("vlan"), Some("off")) => config::stage(|config| config.set_vlan(None)),
+            (Some("vlan"), Some(id)) => id
+                .parse()
+                .map_err(|_| "invalid VLAN ID")
+                .and_then(|id| config::stage(|config| config.set_vlan(Some(id)))),
+            (Some("allow"), Some(arg)) => {
+                prefix(arg).and_then(|prefix| config::stage(|config| config.acl.allow(prefix)))
+            }
+            (Some("remove"), Some(arg)) => prefix(arg).and_then(|prefix| {
+                config::stage(|config| match config.acl.remove(prefix) {
+                    true => Ok(()),
+                    false => Err("prefix isn't allowed"),
+                })
+            }),
+            (Some("apply"), seconds) => seconds
+                .map(|seconds| seconds.parse().map_err(|_| "invalid timeout"))
+                .unwrap_or(Ok(config::DEFAULT_TIMEOUT.secs()))
+                .and_then(|seconds| {
+                    config::apply(crate::time::now(), Duration::from_secs(seconds))
+                }),
+            (Some("discard"), None) => {
+                config::discard();
+                Ok(())
+            }
+            (Some(service), Some(state @ ("on" | "off"))) => service.parse().and_then(|service| {
+                config::stage(|config| {
+                    config.acl.set_enabled(service, state == "on");
+                    Ok(())
+                })
+            }),
+            _ => {
+                outputln!(self.output, Self::HELP_STR);
+                Ok(())
+            }
+        };
+
+        if let Err(err) = result {
+            outputln!(self.output, "Failed to change configuration: {err}");
+        }
+    }
+
+    fn output_config(&mut self, name: &str, config: &crate::config::Config) {
+        outputln!(self.output, "{name}:");
+        match config.addressing.address {
+            Some(address) => outputln!(self.output, "  Address:  {address}"),
+            None => outputln!(self.output, "  Address:  DHCP"),
+        }
+        match config.addressing.gateway {
+            Some(gateway) => outputln!(self.output, "  Gateway:  {gateway}"),
+            None => outputln!(self.output, "  Gateway:  none"),
+        }
+        match config.vlan {
+            Some(id) => outputln!(self.output, "  VLAN:     {id}"),
+            None => outputln!(self.output, "  VLAN:     off"),
+        }
+        output!(self.output, "  Sources: ");
+        let mut prefixes = config.acl.prefixes().peekable();
+        if prefixes.peek().is_none() {
+            output!(self.output, " any");
+        }
+        for prefix in prefixes {
+            output!(self.output, " {prefix}");
+        }
+        outputln!(self.output);
+        output!(self.output, "  Disabled:");
+        let mut disabled = crate::acl::SERVICES
+            .iter()
+            .filter(|service| !config.acl.enabled(**service))
+            .peekable();
+        if disabled.peek().is_none() {
+            output!(self.output, " none");
+        }
+        for service in disabled {
+            output!(self.output, " {service}");
+        }
+        outputln!(self.output);
+    }
+
+    fn capture(&mut self, sink: Option<&str>, filter: Option<&str>) {
+        match (sink, filter) {
+            (None, None) => {
+                let status = crate::capture::status();
+                let (captured, dropped) = (status.captured, status.dropped);
+                match (status.sink, status.filter) {
+                    (None, _) => outputln!(self.output, "Not capturing"),
+                    (Some(sink), None) => outputln!(self.output, "Capturing to {sink}"),
+                    (Some(sink), Some(filter)) => {
+                        outputln!(self.output, "Capturing {filter} to {sink}")
+                    }
+                }
+                outputln!(
+                    self.output,
+                    "Frames captured: {captured} ({dropped} dropped)"
+                );
+            }
+            (Some("off"), None) => crate::capture::stop(),
+            (Some(sink), filter) => {
+                let sink = match sink.parse() {
+                    Ok(sink) => sink,
+                    Err(err) => return outputln!(self.output, "Failed to start capture: {err}"),
+                };
+                match filter.map(str::parse).transpose() {
+                    Ok(filter) => crate::capture::start(sink, filter),
+                    Err(err) => outputln!(self.output, "Failed to parse filter: {err}"),
+                }
+            }
+            _ => outputln!(self.output, Self::HELP_STR),
+        }
+    }
+
+    fn net_stats(&mut self) {
+        let stats = crate::icmp::statistics();
+        let (requests, replies) = (stats.echo_requests, stats.echo_replies);
+        let (dropped, unreachable) = (stats.echo_dropped, stats.unreachable);
+        outputln!(self.output, "ICMP echo requests received: {requests}");
+        outputln!(self.output, "ICMP echo replies sent:      {replies}");
+        outputln!(self.output, "ICMP echo requests dropped:  {dropped}");
+        outputln!(self.output, "ICMP unreachables sent:      {unreachable}");
+        match crate::icmp::echo_limit() {
+            None => outputln!(self.output, "ICMP echo limit: none"),
+            Some(0) => outputln!(self.output, "ICMP echo limit: all dropped"),
+            Some(limit) => outputln!(self.output, "ICMP echo limit: {limit} per second"),
+        }
+        let dropped = crate::ratelimit::dropped();
+        for (class, dropped) in crate::ratelimit::Class::ALL.iter().zip(dropped.iter()) {
+            match crate::ratelimit::limit(*class) {
+                None => outputln!(self.output, "Rate limit ({class}): none"),
+                Some(limit) => {
+                    let (rate, burst) = (limit.rate, limit.burst);
+                    outputln!(
+                        self.output,
+                        "Rate limit ({class}): {rate}/s, burst {burst} ({dropped} dropped)"
+                    )
+                }
+            }
+        }
+        match crate::network::tcp_idle_limit() {
+            None => outputln!(self.output, "TCP idle limit: none"),
+            Some(limit) => outputln!(self.output, "TCP idle limit: {limit}"),
+        }
+        let (totals, rates) = (
+            crate::efm32gg::traffic::totals(),
+            crate::efm32gg::traffic::rates(),
+        );
+        let (frames, octets) = (totals.rx_frames, totals.rx_octets);
+        let (frame_rate, octet_rate) = (rates.rx_frames, rates.rx_octets);
+        outputln!(
+            self.output,
+            "RX: {frames} frames, {octets} bytes ({frame_rate} frames/s, {octet_rate} B/s)"
+        );
+        let (frames, octets) = (totals.tx_frames, totals.tx_octets);
+        let (frame_rate, octet_rate) = (rates.tx_frames, rates.tx_octets);
+        outputln!(
+            self.output,
+            "TX: {frames} frames, {octets} bytes ({frame_rate} frames/s, {octet_rate} B/s)"
+        );
+        let overruns = crate::efm32gg::rx_overruns();
+        outputln!(self.output, "RX overruns recovered: {overruns}");
+        let work = crate::work::statistics();
+        let (queued, high_water) = (work.queued, work.high_water);
+        outputln!(
+            self.output,
+            "Work queue: {queued} events queued ({high_water} at most)"
+        );
+        for (event, dropped) in crate::work::Event::ALL.iter().zip(work.overflows.iter()) {
+            outputln!(self.output, "Work queue overflows ({event}): {dropped}");
+        }
+        let dropped = crate::vlan::dropped();
+        match crate::vlan::id() {
+            None => outputln!(self.output, "Management VLAN: none"),
+            Some(id) => outputln!(
+                self.output,
+                "Management VLAN: {id} ({dropped} frames dropped)"
+            ),
+        }
+    }
+
+    fn limit(&mut self, class: &str, rate: Option<&str>, burst: Option<&str>) {
+        let class = match class.parse::<crate::ratelimit::Class>() {
+            Ok(class) => class,
+            Err(err) => return outputln!(self.output, "Failed to set limit: {err}"),
+        };
+        let limit = match (rate, burst) {
+            (Some("off"), None) => None,
+            (Some(rate), burst) => {
+                let rate = match rate.parse() {
+                    Ok(rate) => rate,
+                    Err(_) => return outputln!(self.output, "Failed to parse rate: {rate}"),
+                };
+                // A second's worth at once, unless told otherwise
+                let burst = match burst.map(str::parse) {
+                    None => rate,
+                    Some(Ok(burst)) => burst,
+                    Some(Err(_)) => return outputln!(self.output, "Failed to parse burst"),
+                };
+                Some(crate::ratelimit::Limit { rate, burst })
+            }
+            (None, _) => return outputln!(self.output, Self::HELP_STR),
+        };
+        crate::ratelimit::set_limit(class, limit);
+    }
+
+    fn route(&mut self, command: &str, destination: Option<&str>, gateway: Option<&str>) {
+        let destination = match (command, destination) {
+            ("show", None) => return self.route_table(),
+            ("add", Some(destination)) | ("del", Some(destination)) => {
+                match destination.parse::<Ipv4Cidr>() {
+                    Ok(destination) => destination,
+                    Err(_) => {
+                        return outputln!(self.output, "Failed to parse network: {destination}")
+                    }
+                }
+            }
+            _ => return outputln!(self.output, Self::HELP_STR),
+        };
+        let result = match (command, gateway) {
+            ("add", Some(gateway)) => match gateway.parse::<Ipv4Address>() {
+                Ok(gateway) => crate::routes::add(destination, gateway),
+                Err(_) => return outputln!(self.output, "Failed to parse gateway: {gateway}"),
+            },
+            ("del", None) => crate::routes::remove(destination),
+            _ => return outputln!(self.output, Self::HELP_STR),
+        };
+        if let Err(err) = result {
+            outputln!(self.output, "Failed to change static route: {err}");
+        }
+    }
+
+    fn route_table(&mut self) {
+        let table = crate::routes::table();
+        for (destination, gateway) in table.iter().flatten() {
+            let note = match destination {
+                IpCidr::Ipv4(cidr) if cidr.prefix_len() > 0 => " (static)",
+                _ => "",
+            };
+            outputln!(self.output, "{destination} via {gateway}{note}");
+        }
+
+        // Routes that haven't made it into the interface's table yet (or didn't fit)
+        for route in crate::routes::statics().iter().flatten() {
+            let destination = IpCidr::Ipv4(route.destination);
+            if !table.iter().flatten().any(|(cidr, _)| *cidr == destination) {
+                let gateway = route.gateway;
+                outputln!(
+                    self.output,
+                    "{destination} via {gateway} (static, not applied)"
+                );
+            }
+        }
+    }
+
+    fn neighbors(&mut self) {
+        let stats = crate::neighbors::statistics();
+        let (fills, evictions) = (stats.fills, stats.evictions);
+        let slots = crate::neighbors::CACHE_SIZE;
+        outputln!(
+            self.output,
+            "Neighbor cache: {slots} slots, {fills} fills, {evictions} evictions"
+        );
+        for neighbor in crate::neighbors::entries(crate::time::now())
+            .iter()
+            .flatten()
+        {
+            let (address, hardware) = (neighbor.address, neighbor.hardware);
+            let age = neighbor.age.secs();
+            let note = match (neighbor.permanent, neighbor.expired) {
+                (true, _) => " (static)",
+                (false, true) => " (expired)",
+                (false, false) => "",
+            };
+            outputln!(self.output, "  {address} at {hardware}, {age}s old{note}");
+        }
+    }
+
+    fn neighbor(&mut self, command: &str, address: Option<&str>, hardware: Option<&str>) {
+        let address = match (command, address) {
+            ("flush", None) => return crate::neighbors::flush(),
+            ("add", Some(address)) | ("remove", Some(address)) => {
+                match address.parse::<Ipv4Address>() {
+                    Ok(address) => address,
+                    Err(_) => return outputln!(self.output, "Failed to parse address: {address}"),
+                }
+            }
+            _ => return outputln!(self.output, Self::HELP_STR),
+        };
+        let result = match (command, hardware) {
+            ("add", Some(hardware)) => match hardware.parse::<EthernetAddress>() {
+                Ok(hardware) => crate::neighbors::add_static(address, hardware),
+                Err(_) => return outputln!(self.output, "Failed to parse MAC: {hardware}"),
+            },
+            ("remove", None) => crate::neighbors::remove_static(address),
+            _ => return outputln!(self.output, Self::HELP_STR),
+        };
+        if let Err(err) = result {
+            outputln!(self.output, "Failed to change static neighbor: {err}");
+        }
+    }
+
+    fn dhcp(&mut self, setting: Option<&str>, value: Option<&str>) {
+        let result = match (setting, value) {
+            (None, _) => return self.dhcp_status(),
+            (Some("retries"), Some(count)) => match count.parse() {
+                Ok(count) => crate::dhcp::set_retries(count),
+                Err(_) => return outputln!(self.output, "Failed to parse count: {count}"),
+            },
+            (Some("timeout"), Some(secs)) => match secs.parse() {
+                Ok(secs) => crate::dhcp::set_timeout(Duration::from_secs(secs)),
+                Err(_) => return outputln!(self.output, "Failed to parse timeout: {secs}"),
+            },
+            _ => return outputln!(self.output, Self::HELP_STR),
+        };
+        if let Err(err) = result {
+            outputln!(self.output, "Failed to save DHCP settings: {err}");
+        }
+    }
+
+    fn dhcp_status(&mut self) {
+        if crate::config::addressing().address.is_some() {
+            outputln!(self.output, "DHCP: unused (static address)");
+        }
+
+        let status = crate::dhcp::status(crate::time::now());
+        let (phase, elapsed, attempts) = (status.phase, status.elapsed, status.attempts);
+        outputln!(
+            self.output,
+            "DHCP: {phase} for {elapsed} ({attempts} attempts timed out)"
+        );
+        let counts = status.counts;
+        let (discovers, offers, requests) = (counts.discovers, counts.offers, counts.requests);
+        let (acks, naks) = (counts.acks, counts.naks);
+        outputln!(
+            self.output,
+            "Messages: {discovers} discovers, {offers} offers, {requests} requests, {acks} acks, {naks} naks"
+        );
+        match status.failure {
+            Some(failure) => outputln!(self.output, "Last failure: {failure}"),
+            None => outputln!(self.output, "Last failure: none"),
+        }
+
+        let (retries, timeout) = crate::dhcp::settings();
+        let backoff = crate::dhcp::BACKOFF;
+        outputln!(
+            self.output,
+            "Timeout: {timeout} for {retries} attempts, then {backoff}"
+        );
+    }
+
+    fn phy(&mut self, command: Option<&str>, argument: Option<&str>) {
+        match (command, argument) {
+            (Some("status"), None) => {
+                match crate::efm32gg::phy_address() {
+                    Some(addr) => outputln!(self.output, "Address: {addr}"),
+                    None => return outputln!(self.output, "No PHY"),
+                }
+                let counts = crate::health::counts();
+                for fault in ethernet_phy::Fault::ALL {
+                    let (count, threshold) = (counts[fault.index()], fault.threshold());
+                    outputln!(
+                        self.output,
+                        "{fault:>21}: {count} (threshold {threshold}/min)"
+                    );
+                }
+            }
+            (Some("dump"), None) => {
+                let addr = match crate::efm32gg::phy_address() {
+                    Some(addr) => addr,
+                    None => return outputln!(self.output, "No PHY"),
+                };
+                for (register, name) in ksz8091::registers::REGISTERS {
+                    let value = SharedMdio.read(addr, Register::from(register));
+                    let fields = ksz8091::registers::Fields { register, value };
+                    outputln!(
+                        self.output,
+                        "0x{register:02X} {name:<38} 0x{value:04X} {fields}"
+                    );
+                }
+            }
+            (Some("eee"), None) => {
+                let status = crate::eee::status();
+                let enabled = match status.enabled {
+                    true => "enabled",
+                    false => "disabled",
+                };
+                outputln!(self.output, "EEE: {enabled}");
+                match status.negotiated {
+                    Some(true) => outputln!(self.output, "Negotiated: yes"),
+                    Some(false) => outputln!(self.output, "Negotiated: no"),
+                    None => outputln!(self.output, "Negotiated: no link"),
+                }
+                let (entries, exits) = (status.lpi_entries, status.lpi_exits);
+                let state = match status.lpi_active {
+                    true => "idle",
+                    false => "active",
+                };
+                outputln!(self.output, "Link partner: {state}");
+                outputln!(self.output, "LPI entries: {entries}, exits: {exits}");
+            }
+            (Some("force"), None) => {
+                let settings = crate::media::settings();
+                let mdix = settings.mdix;
+                match settings.forced {
+                    Some(state) => outputln!(self.output, "Link: forced to {state}"),
+                    None => outputln!(self.output, "Link: autonegotiated"),
+                }
+                outputln!(self.output, "Wiring: {mdix}");
+            }
+            (Some("force"), Some(mode)) => {
+                let forced = match mode {
+                    "auto" => Ok(None),
+                    mode => mode.parse().map(Some),
+                };
+                if let Err(err) = forced.and_then(crate::media::set_forced) {
+                    outputln!(self.output, "Failed to force link: {err}")
+                }
+            }
+            (Some("mdix"), Some(mdix)) => match mdix.parse() {
+                Ok(mdix) => crate::media::set_mdix(mdix),
+                Err(err) => outputln!(self.output, "Failed to set wiring: {err}"),
+            },
+            (Some("eee"), Some("on")) => crate::eee::set_enabled(true),
+            (Some("eee"), Some("off")) => crate::eee::set_enabled(false),
+            _ => outputln!(self.output, Self::HELP_STR),
+        }
+    }
+
+    fn phy_read(&mut self, register: u32) {
+        let (addr, register) = match self.phy_register(register) {
+            Some(register) => register,
+            None => return,
+        };
+        let value = SharedMdio.read(addr, Register::from(register));
+        outputln!(self.output, "0x{value:04X}");
+    }
+
+    fn phy_write(&mut self, register: u32, value: u32) {
+        let (addr, register) = match self.phy_register(register) {
+            Some(register) => register,
+            None => return,
+        };
+        match u16::try_from(value) {
+            Ok(value) => SharedMdio.write(addr, Register::from(register), value),
+            Err(_) => outputln!(self.output, "Value must be 16 bits"),
+        }
+    }
+
+    // Returns the PHY's address and the register, if it's one of the PHY's 32 registers
+    fn phy_register(&mut self, register: u32) -> Option<(u8, u8)> {
+        let addr = match crate::efm32gg::phy_address() {
+            Some(addr) => addr,
+            None => {
+                outputln!(self.output, "No PHY");
+                return None;
+            }
+        };
+        match register {
+            0..=0x1F => Some((addr, register as u8)),
+            _ => {
+                outputln!(self.output, "Register must be between 0x00 and 0x1F");
+                None
+            }
+        }
+    }
+
+    fn blobs(&mut self, command: Option<&str>, name: Option<&str>) {
+        let result = match (command, name) {
+            (None, None) => crate::blobs::for_each(|blob| {
+                let (name, len) = (blob.name(), blob.len());
+                outputln!(self.output, "{name:<24} {len} bytes")
+            })
+            .and_then(|_| crate::blobs::free())
+            .map(|free| outputln!(self.output, "Free: {free} bytes")),
+            (Some("remove"), Some(name)) => crate::blobs::remove(name),
+            (Some("format"), None) => crate::blobs::format(),
+            _ => {
+                outputln!(self.output, Self::HELP_STR);
+                Ok(())
+            }
+        };
+
+        if let Err(err) = result {
+            outputln!(self.output, "Failed to access external flash: {err}");
+        }
+    }
+
+    fn boot_status(&mut self) {
+        let running = crate::boot::running();
+        outputln!(self.output, "Running: bank {running}");
+        match crate::boot::staged() {
+            Some(image) => {
+                let (version, len, crc, state) = (image.version, image.len, image.crc, image.state);
+                let signed = match image.signed {
+                    true => "signed",
+                    false => "unsigned",
+                };
+                outputln!(
+                    self.output,
+                    "Bank B:  version {version:08X}, {len} bytes, CRC {crc:08X}, {signed}, {state}"
+                )
+            }
+            None => outputln!(self.output, "Bank B:  empty"),
+        }
+    }
+
+    fn boot_image(&mut self) {
+        let image = crate::boot::image();
+        let status = crate::signing::check(image, crate::boot::signature());
+        let (version, bank, len) = (
+            env!("CARGO_PKG_VERSION"),
+            crate::boot::running(),
+            image.len(),
+        );
+        outputln!(self.output, "Version:   {version}");
+        outputln!(self.output, "Bank:      {bank}");
+        outputln!(self.output, "Length:    {len} bytes");
+        output!(self.output, "SHA-256:   ");
+        for byte in crate::signing::sha256(image) {
+            output!(self.output, "{byte:02x}");
+        }
+        outputln!(self.output);
+        outputln!(self.output, "Signature: {status}");
+    }
+
+    // Parses each of the tokens as a hex byte, returning how many there were
+    fn parse_bytes<'t, I>(&mut self, tokens: I, bytes: &mut [u8]) -> Option<usize>
+    where
+        I: Iterator<Item = &'t str>,
+    {
+        let mut len = 0;
+        for token in tokens.filter(|token| !token.is_empty()) {
+            if len == bytes.len() {
+                outputln!(self.output, "At most {len} bytes are allowed");
+                return None;
+            }
+            match u8::from_str_radix(token, 16) {
+                Ok(byte) => bytes[len] = byte,
+                Err(err) => {
+                    outputln!(self.output, "Failed to parse byte ({token}): {err}");
+                    return None;
+                }
+            }
+            len += 1;
+        }
+
+        Some(len)
+    }
+
+    fn output_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            output!(self.output, "{byte:02X} ");
+        }
+        outputln!(self.output);
+    }
+
+    fn i2c_scan(&mut self) {
+        use embedded_hal::blocking::i2c::Write;
+
+        // Addressing a device without writing anything to it just checks for an acknowledgement
+        let mut found = [false; 0x80];
+        let result = crate::efm32gg::i2c::with(|bus| {
+            for addr in 0x08..0x78 {
+                found[addr as usize] = bus.write(addr, &[]).is_ok();
+            }
+        });
+
+        match result {
+            Ok(()) => {
+                output!(self.output, "Found:");
+                for (addr, _) in found.iter().enumerate().filter(|(_, found)| **found) {
+                    output!(self.output, " 0x{addr:02X}");
+                }
+                outputln!(self.output);
+            }
+            Err(err) => outputln!(self.output, "Failed to scan: {err}"),
+        }
+    }
+
+    // Returns the address and the register, if they're a 7-bit address and an 8-bit register
+    fn i2c_target(&mut self, addr: u32, register: u32) -> Option<(u8, u8)> {
+        match (addr, register) {
+            (0..=0x7F, 0..=0xFF) => Some((addr as u8, register as u8)),
+            (0..=0x7F, _) => {
+                outputln!(self.output, "Register must be between 0x00 and 0xFF");
+                None
+            }
+            _ => {
+                outputln!(self.output, "Address must be between 0x00 and 0x7F");
+                None
+            }
+        }
+    }
+
+    fn i2c_read(&mut self, addr: u32, register: u32, count: Option<&str>) {
+        use embedded_hal::blocking::i2c::WriteRead;
+
+        let (addr, register) = match self.i2c_target(addr, register) {
+            Some(target) => target,
+            None => return,
+        };
+        let count = match count.map(|count| usize::from_str_radix(count, 16)) {
+            None => 1,
+            Some(Ok(count)) if (1..=32).contains(&count) => count,
+            Some(_) => {
+                outputln!(self.output, "Count must be between 0x01 and 0x20");
+                return;
+            }
+        };
+
+        let mut buffer = [0; 32];
+        let buffer = &mut buffer[..count];
+        match crate::efm32gg::i2c::with(|bus| bus.write_read(addr, &[register], buffer))
+            .and_then(|result| result)
+        {
+            Ok(()) => self.output_bytes(buffer),
+            Err(err) => outputln!(self.output, "Failed to read: {err}"),
+        }
+    }
+
+    fn i2c_write<'t, I: Iterator<Item = &'t str>>(&mut self, addr: u32, register: u32, tokens: I) {
+        use embedded_hal::blocking::i2c::Write;
+
+        let (addr, register) = match self.i2c_target(addr, register) {
+            Some(target) => target,
+            None => return,
+        };
+
+        // The register is sent first, followed by the values
+        let mut bytes = [0; 33];
+        bytes[0] = register;
+        let len = match self.parse_bytes(tokens, &mut bytes[1..]) {
+            Some(0) => {
+                outputln!(self.output, Self::HELP_STR);
+                return;
+            }
+            Some(len) => len,
+            None => return,
+        };
+
+        if let Err(err) = crate::efm32gg::i2c::with(|bus| bus.write(addr, &bytes[..=len]))
+            .and_then(|result| result)
+        {
+            outputln!(self.output, "Failed to write: {err}");
+        }
+    }
+
+    fn memtest(&mut self, addr: &str, len: &str, pattern: Option<&str>) {
+        let (addr, len, pattern) = match (parse_hex(addr), parse_hex(len), pattern.map(parse_hex)) {
+            (Ok(addr), Ok(len), None) => (addr, len, None),
+            (Ok(addr), Ok(len), Some(Ok(pattern))) => (addr, len, Some(pattern)),
+            _ => return outputln!(self.output, "Failed to parse the range or pattern"),
+        };
+
+        // The rest are counted, but not displayed
+        const MAX_DISPLAYED: usize = 16;
+
+        let mut displayed = 0;
+        let result = crate::memtest::run(addr, len, pattern, |failure| {
+            if displayed < MAX_DISPLAYED {
+                displayed += 1;
+                let (address, expected, actual) =
+                    (failure.address, failure.expected, failure.actual);
+                outputln!(
+                    self.output,
+                    "0x{address:08X}: expected 0x{expected:08X}, read 0x{actual:08X}"
+                );
+            }
+        });
+        match result {
+            Ok(summary) => {
+                let (words, failures) = (summary.words, summary.failures);
+                outputln!(self.output, "Tested {words} words: {failures} failures");
+            }
+            Err(err) => outputln!(self.output, "Failed to test: {err}"),
+        }
+    }
+
+    fn spi_xfer<'t, I: Iterator<Item = &'t str>>(&mut self, tokens: I) {
+        let mut words = [0u8; 32];
+        let len = match self.parse_bytes(tokens, &mut words) {
+            Some(0) => {
+                outputln!(self.output, Self::HELP_STR);
+                return;
+            }
+            Some(len) => len,
+            None => return,
+        };
+
+        let words = &mut words[..len];
+        match crate::efm32gg::spi::with(|bus| bus.transfer(words)).and_then(|result| result) {
+            Ok(()) => self.output_bytes(words),
+            Err(err) => outputln!(self.output, "Failed to exchange bytes: {err}"),
+        }
+    }
+
+    fn ptp(&mut self) {
+        let status = crate::ptp::status();
+        let enabled = match status.enabled {
+            true => "enabled",
+            false => "disabled",
+        };
+        outputln!(self.output, "PTP: {enabled}");
+        match status.master {
+            Some(master) => outputln!(self.output, "Master: {master}"),
+            None => outputln!(self.output, "Master: none"),
+        }
+        match (status.offset_ns, status.path_delay_ns) {
+            (Some(offset), Some(delay)) => {
+                outputln!(self.output, "Offset from master: {offset} ns");
+                outputln!(self.output, "Path delay: {delay} ns");
+            }
+            _ => outputln!(self.output, "Offset from master: unknown"),
+        }
+        let frequency = status.frequency_ppb;
+        outputln!(self.output, "Frequency adjustment: {frequency} ppb");
+        if let Some(drift) = status.rtc_drift_ppb {
+            outputln!(self.output, "RTC drift: {drift} ppb");
+        }
+    }
+
+    // Displays memory as rows of 16 bytes, each followed by the bytes as ASCII, reading each word
+    // only once
+    fn read(&mut self, addr: u32, len: u32) {
+        const MAX_LEN: u32 = 0x400;
+        const ROW: u32 = 16;
+
+        let end = match addr.checked_add(len.min(MAX_LEN)) {
+            Some(end) => end,
+            None => return outputln!(self.output, "Failed to read: the range wraps"),
+        };
+
+        let mut word: Option<(u32, [u8; 4])> = None;
+        let mut row = addr & !(ROW - 1);
+        while row < end {
+            let mut bytes = [None; ROW as usize];
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                let at = row + i as u32;
+                if !(addr..end).contains(&at) {
+                    continue;
+                }
+
+                let aligned = at & !0x3;
+                if word.map(|(a, _)| a) != Some(aligned) {
+                    if let Err(err) = crate::efm32gg::memory::check(aligned, 4, false) {
+                        return outputln!(self.output, "Failed to read 0x{aligned:08X}: {err}");
+                    }
+                    let data = unsafe { *(aligned as *const u32) };
+                    word = Some((aligned, data.to_le_bytes()));
+                }
+                *byte = word.map(|(_, data)| data[(at % 4) as usize]);
+            }
+
+            output!(self.output, "0x{row:08X} ");
+            for (i, byte) in bytes.iter().enumerate() {
+                if i == bytes.len() / 2 {
+                    output!(self.output, " ");
+                }
+                match byte {
+                    Some(byte) => output!(self.output, " {byte:02X}"),
+                    None => output!(self.output, "   "),
+                }
+            }
+            output!(self.output, "  |");
+            for byte in bytes.iter() {
+                match byte {
+                    Some(byte @ 0x20..=0x7E) => output!(self.output, *byte as char),
+                    Some(_) => output!(self.output, "."),
+                    None => output!(self.output, " "),
+                }
+            }
+            outputln!(self.output, "|");
+
+            row = match row.checked_add(ROW) {
+                Some(row) => row,
+                None => break,
+            };
+        }
+    }
+
+    // Disassembles the instructions that start in the range
+    fn disassemble(&mut self, addr: u32, len: u32) {
+        const MAX_LEN: u32 = 0x100;
+
+        let read = |addr: u32| -> Result<u16, &'static str> {
+            crate::efm32gg::memory::check(addr, 2, false)?;
+            Ok(unsafe { *(addr as *const u16) })
+        };
+
+        // The low bit of a function's address only marks it as Thumb code
+        let mut addr = addr & !0x1;
+        let end = addr.saturating_add(len.min(MAX_LEN));
+        while addr < end {
+            let first = match read(addr) {
+                Ok(first) => first,
+                Err(err) => return outputln!(self.output, "Failed to read 0x{addr:08X}: {err}"),
+            };
+            let len = crate::thumb::len(first);
+            let second = match len {
+                4 => match read(addr.wrapping_add(2)) {
+                    Ok(second) => second,
+                    Err(err) => {
+                        return outputln!(self.output, "Failed to read 0x{addr:08X}: {err}")
+                    }
+                },
+                _ => 0,
+            };
+
+            match len {
+                4 => output!(self.output, "0x{addr:08X}  {first:04X} {second:04X}  "),
+                _ => output!(self.output, "0x{addr:08X}  {first:04X}       "),
+            }
+            crate::thumb::write(self.output, addr, first, second)
+                .map_err(|err| log::warn!("terminal write failed: {err}"))
+                .ignore();
+            outputln!(self.output);
+
+            addr = match addr.checked_add(len) {
+                Some(addr) => addr,
+                None => break,
+            };
+        }
+    }
+
+    fn list_variables(&mut self) {
+        for (name, value) in Variables::BUILT_IN.iter() {
+            let value = value();
+            outputln!(self.output, "${name:<8} {value} (built-in)");
+        }
+        for (name, value) in self.variables.defined.iter() {
+            outputln!(self.output, "${name:<8} {value}");
+        }
+    }
+
+    fn sysinfo(&mut self) {
+        let part = crate::efm32gg::devinfo::part();
+        let size = crate::efm32gg::devinfo::mem_size();
+        let (flash, sram) = (size.flash_kib, size.sram_kib);
+        outputln!(self.output, "Part: {part}");
+        outputln!(self.output, "Memory: {flash} KiB flash, {sram} KiB RAM");
+        let cause = crate::efm32gg::rmu::last();
+        outputln!(self.output, "Reset cause: {cause}");
+        let clock = crate::efm32gg::clock::source();
+        outputln!(self.output, "Clock: {clock}");
+        let lifetime = crate::lifetime::counters(crate::time::now());
+        let (boots, watchdog, flaps) = (
+            lifetime.boots,
+            lifetime.watchdog_resets,
+            lifetime.link_flaps,
+        );
+        let (hours, minutes) = (lifetime.uptime_s / 3600, lifetime.uptime_s / 60 % 60);
+        outputln!(self.output, "Lifetime:");
+        outputln!(self.output, "  Boots            {boots}");
+        outputln!(self.output, "  Watchdog resets  {watchdog}");
+        outputln!(self.output, "  Running time     {hours}h {minutes}m");
+        outputln!(self.output, "  Link flaps       {flaps}");
+        outputln!(self.output, "Resets since power-on:");
+        crate::efm32gg::rmu::for_each_count(|cause, count| {
+            outputln!(self.output, "  {cause:<16} {count}")
+        });
+        match crate::efm32gg::sleep::asleep_permille() {
+            Some(permille) => {
+                let (whole, tenths) = (permille / 10, permille % 10);
+                let busy = 1000 - permille;
+                let (busy_whole, busy_tenths) = (busy / 10, busy % 10);
+                outputln!(
+                    self.output,
+                    "Asleep (EM1): {whole}.{tenths}% of the last second"
+                );
+                outputln!(self.output, "CPU load: {busy_whole}.{busy_tenths}%");
+            }
+            None => outputln!(self.output, "Asleep (EM1): not measured yet"),
+        }
+        let mhz = (crate::efm32gg::sleep::core_hz() / 1_000_000).max(1);
+        outputln!(
+            self.output,
+            "Tasks:                      runs   avg us   max us"
+        );
+        crate::efm32gg::timing::for_each(|stats| {
+            let (name, runs) = (stats.name, stats.runs);
+            let (avg, max) = (stats.avg_cycles() / mhz, stats.max_cycles / mhz);
+            outputln!(self.output, "  {name:<20} {runs:>9} {avg:>8} {max:>8}");
+        });
+        let (used, size) = (crate::stack::high_water(), crate::stack::size());
+        outputln!(self.output, "Stack high-water mark: {used} of {size} bytes");
+        let di = match crate::efm32gg::devinfo::is_valid() {
+            true => "ok",
+            false => "corrupt (calibration ignored)",
+        };
+        outputln!(self.output, "DI page: {di}");
+        outputln!(self.output, "Supplies:");
+        crate::efm32gg::vmon::for_each_supply(|supply, above| {
+            let status = match above {
+                true => "ok",
+                false => "low",
+            };
+            outputln!(self.output, "  {supply:<16} {status}")
+        });
+    }
+}
+
+// Parses a hex number, with or without a leading 0x
+fn parse_hex(val: &str) -> Result<u32, core::num::ParseIntError> {
+    u32::from_str_radix(val.strip_prefix("0x").unwrap_or(val), 16)
+}
+
+/// A terminal's side of the console: which terminal it is, the line being entered, and the
+/// previous command.
+pub struct Session {
+    terminal: Terminal,
+    line: Line,
+    previous: [u8; LINE_LEN],
+    previous_len: usize,
+    variables: Variables,
+}
+
+impl Session {
+    pub const fn new(terminal: Terminal) -> Session {
+        Session {
+            terminal,
+            line: Line::new(),
+            previous: [0; LINE_LEN],
+            previous_len: 0,
+            variables: Variables::new(),
+        }
+    }
+
+    /// Writes the first prompt.
+    pub fn start(&mut self, output: &mut dyn Write) {
+        Interpreter::new(output, self.terminal, &mut self.variables).start();
+    }
+
+    /// Collects input that arrives a byte at a time, echoing it, and runs each line as it's
+    /// entered.
+    pub fn receive(&mut self, input: &[u8], output: &mut dyn Write) {
+        for &byte in input {
+            let mut entered = [0; LINE_LEN];
+            let len = match self.line.push(byte, output) {
+                Some(line) => {
+                    entered[..line.len()].copy_from_slice(line.as_bytes());
+                    line.len()
+                }
+                None => continue,
+            };
+            // Lines only ever hold ASCII (see Line::push)
+            self.execute(str::from_utf8(&entered[..len]).unwrap_or_default(), output);
+        }
+    }
+
+    /// Runs a line that has already been collected (e.g. by the debugger's RTT console), and then
+    /// writes the next prompt.
+    pub fn execute(&mut self, line: &str, output: &mut dyn Write) {
+        let mut previous = [0; LINE_LEN];
+        let line = match line.trim() {
+            "!!" => {
+                let len = self.previous_len;
+                previous[..len].copy_from_slice(&self.previous[..len]);
+                // Only lines that were valid UTF-8 are kept
+                let line = str::from_utf8(&previous[..len]).unwrap_or_default();
+                match line {
+                    "" => outputln!(output, "No previous command"),
+                    line => outputln!(output, line),
+                }
+                line
+            }
+            "" => "",
+            line => {
+                // Lines too long to keep aren't repeated
+                match self.previous.get_mut(..line.len()) {
+                    Some(previous) => {
+                        previous.copy_from_slice(line.as_bytes());
+                        self.previous_len = line.len();
+                    }
+                    None => self.previous_len = 0,
+                }
+                line
+            }
+        };
+        Interpreter::new(output, self.terminal, &mut self.variables).execute(line);
+    }
+}
+
+/// The longest variable name.
+const NAME_LEN: usize = 16;
+
+/// The longest variable value.
+const VALUE_LEN: usize = 32;
+
+/// The most variables that a session can hold.
+const MAX_VARIABLES: usize = 8;
+
+// Reads the current value of a built-in variable
+type BuiltIn = fn() -> Value;
+
+/// The variables set with `let`, which are substituted into each line before it's run.
+pub struct Variables {
+    defined: Vec<(String<NAME_LEN>, String<VALUE_LEN>), MAX_VARIABLES>,
+}
+
+impl Variables {
+    // Addresses are written in hex, as the commands expect them
+    const BUILT_IN: [(&'static str, BuiltIn); 3] = [
+        // The start of the running image
+        ("prog", || Value::Hex(crate::boot::image().as_ptr() as u32)),
+        ("sp", || Value::Hex(cortex_m::register::msp::read())),
+        // In seconds
+        ("uptime", || {
+            Value::Decimal((crate::time::now().total_millis() / 1000) as u32)
+        }),
+    ];
+
+    pub const fn new() -> Variables {
+        Variables {
+            defined: Vec::new(),
+        }
+    }
+
+    /// Sets a variable, replacing its previous value, or removes it if there's no value.
+    pub fn set(&mut self, name: &str, value: Option<&str>) -> Result<(), &'static str> {
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+            return Err("names may only contain letters, digits, and underscores");
+        }
+        if Variables::BUILT_IN.iter().any(|(n, _)| *n == name) {
+            return Err("built-in variables can't be changed");
+        }
+
+        let position = self.defined.iter().position(|(n, _)| n == name);
+        let value = match (value, position) {
+            (Some(value), _) => value,
+            (None, Some(position)) => {
+                self.defined.swap_remove(position);
+                return Ok(());
+            }
+            (None, None) => return Err("no such variable"),
+        };
+
+        let mut entry = (String::new(), String::new());
+        entry.0.push_str(name).map_err(|_| "the name is too long")?;
+        entry
+            .1
+            .push_str(value)
+            .map_err(|_| "the value is too long")?;
+        match position {
+            Some(position) => self.defined[position] = entry,
+            None => self.defined.push(entry).map_err(|_| "too many variables")?,
+        }
+        Ok(())
+    }
+
+    /// Writes the line into `expanded`, with each `$name` replaced by the variable's value.
+    pub fn expand(&self, line: &str, expanded: &mut String<LINE_LEN>) -> Result<(), &'static str> {
+        const TOO_LONG: &str = "the line is too long";
+
+        let mut rest = line;
+        while let Some(start) = rest.find('$') {
+            expanded.push_str(&rest[..start]).map_err(|_| TOO_LONG)?;
+            rest = &rest[start + 1..];
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            let name = &rest[..len];
+            rest = &rest[len..];
+
+            let defined = self.defined.iter().find(|(n, _)| n == name);
+            let built_in = Variables::BUILT_IN.iter().find(|(n, _)| *n == name);
+            match (defined, built_in) {
+                (Some((_, value)), _) => expanded.push_str(value).map_err(|_| TOO_LONG)?,
+                (None, Some((_, value))) => {
+                    write!(expanded, "{}", value()).map_err(|_| TOO_LONG)?
+                }
+                (None, None) => return Err("undefined variable"),
+            }
+        }
+        expanded.push_str(rest).map_err(|_| TOO_LONG)
+    }
+}
+
+/// The value of a built-in variable.
+enum Value {
+    Hex(u32),
+    Decimal(u32),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Hex(value) => write!(f, "0x{value:08X}"),
+            Value::Decimal(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+/// The longest line that `Line` collects.
+pub const LINE_LEN: usize = 128;
+
+/// A line of input from a terminal that receives it a byte at a time, which is echoed as it's
+/// typed.
+pub struct Line {
+    data: [u8; LINE_LEN],
+    len: usize,
+    // The line was entered, and is cleared by the next byte
+    entered: bool,
+    // The last byte was a carriage return, so a following line feed is ignored
+    cr: bool,
+}
+
+impl Line {
+    pub const fn new() -> Line {
+        Line {
+            data: [0; LINE_LEN],
+            len: 0,
+            entered: false,
+            cr: false,
+        }
+    }
+
+    /// Adds a byte of input, echoing it to `echo`, and returns the line once it's been entered.
+    /// Only printable ASCII is collected; anything past `LINE_LEN` is dropped.
+    pub fn push(&mut self, byte: u8, echo: &mut dyn Write) -> Option<&str> {
+        if self.entered {
+            self.entered = false;
+            self.len = 0;
+        }
+
+        let cr = self.cr;
+        self.cr = byte == b'\r';
+        match byte {
+            b'\n' if cr => None,
+            b'\r' | b'\n' => {
+                self.entered = true;
+                outputln!(echo);
+                str::from_utf8(&self.data[..self.len]).ok()
+            }
+            // Backspace and delete
+            0x08 | 0x7F if self.len > 0 => {
+                self.len -= 1;
+                output!(echo, "\x08 \x08");
+                None
+            }
+            0x20..=0x7E if self.len < LINE_LEN => {
+                self.data[self.len] = byte;
+                self.len += 1;
+                output!(echo, byte as char);
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Output waiting to be sent, which is dropped once the queue is full.
+pub struct Queue<const N: usize> {
+    data: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> Queue<N> {
+    pub const fn new() -> Queue<N> {
+        Queue {
+            data: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Queues as much of `bytes` as fits.
+    pub fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes.iter().take(N - self.len) {
+            self.data[(self.head + self.len) % N] = byte;
+            self.len += 1;
+        }
+    }
+
+    /// Returns the oldest of the queued bytes (as many as are contiguous in the buffer).
+    pub fn front(&self) -> &[u8] {
+        let end = cmp::min(self.head + self.len, N);
+        &self.data[self.head..end]
+    }
+
+    /// Removes the oldest `count` bytes, once they've been sent.
+    pub fn pop(&mut self, count: usize) {
+        let count = cmp::min(count, self.len);
+        self.head = (self.head + count) % N;
+        self.len -= count;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::string::{String, ToString};
+    use std::vec::Vec;
+
+    // Splits a usage (e.g. "port cycle [ms]") into its words, keeping placeholders whole
+    fn words(usage: &str) -> Vec<String> {
+        let (mut words, mut word, mut depth) = (Vec::new(), String::new(), 0);
+        for c in usage.chars() {
+            match c {
+                '<' | '[' => depth += 1,
+                '>' | ']' => depth -= 1,
+                ' ' if depth == 0 => {
+                    words.push(core::mem::take(&mut word));
+                    continue;
+                }
+                _ => {}
+            }
+            word.push(c);
+        }
+        words.push(word);
+        words
+    }
+
+    // Returns a command for each alternative of each word in the usage, with a placeholder
+    // argument wherever one is expected and the optional words left out
+    fn commands(usage: &str) -> Vec<String> {
+        let choices: Vec<Vec<String>> = words(usage)
+            .iter()
+            .filter(|word| !word.starts_with('['))
+            .map(|word| {
+                word.split('|')
+                    .map(|choice| match choice.contains('<') {
+                        true => "1".to_string(),
+                        false => choice.to_string(),
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let mut commands = Vec::new();
+        for (i, alternatives) in choices.iter().enumerate() {
+            for alternative in alternatives {
+                let command: Vec<&str> = choices
+                    .iter()
+                    .enumerate()
+                    .map(|(j, word)| match i == j {
+                        true => alternative.as_str(),
+                        false => word[0].as_str(),
+                    })
+                    .collect();
+                commands.push(command.join(" "));
+            }
+        }
+        commands
+    }
+
+    #[test]
+    fn every_command_is_audited_unless_it_only_displays() {
+        let lines = Interpreter::HELP_STR
+            .lines()
+            .skip_while(|line| *line != "Available commands:")
+            .skip(1)
+            .filter(|line| !line.is_empty());
+
+        let mut checked = 0;
+        for line in lines {
+            // The descriptions are all aligned, even where a usage runs up against one
+            let (usage, description) = line.split_at(35);
+            let (usage, description) = (usage.trim(), description.trim());
+            let displays = [
+                "Display",
+                "List",
+                "Read",
+                "Compute",
+                "Disassemble",
+                "Sample",
+            ]
+            .iter()
+            .any(|verb| description.starts_with(verb));
+
+            // The variables belong to the terminal, and the previous command is audited when
+            // it's run again
+            if usage.starts_with("let") || usage == "!!" {
+                continue;
+            }
+
+            for command in commands(usage) {
+                assert_eq!(
+                    Privileged::parse(&command).is_some(),
+                    !displays,
+                    "{command} ({description})"
+                );
+                checked += 1;
+            }
+        }
+        assert!(checked > 100);
+    }
+
+    // Types the input into the session a byte at a time, returning everything that's written back
+    fn type_in(session: &mut Session, input: &str) -> String {
+        let mut output = String::new();
+        session.receive(input.as_bytes(), &mut output);
+        output
+    }
+
+    #[test]
+    fn lines_are_edited_and_run() {
+        let _state = crate::host::exclusive();
+        let mut session = Session::new(Terminal::Uart);
+
+        let output = type_in(&mut session, "acx\x7Fl\r\n");
+        assert!(output.starts_with("acx\x08 \x08l\n\r"), "{}", output);
+        assert!(
+            output.contains("Allowed sources:\n\r  any\n\r"),
+            "{}",
+            output
+        );
+        assert!(output.ends_with("poe-ffffff> "), "{}", output);
+    }
+
+    #[test]
+    fn variables_and_repeats() {
+        let _state = crate::host::exclusive();
+        let mut session = Session::new(Terminal::Uart);
+
+        let output = type_in(&mut session, "let list acl\r$list\r!!\r");
+        assert_eq!(output.matches("Allowed sources:").count(), 2, "{}", output);
+        // The previous line is repeated as it was entered
+        assert!(output.contains("!!\n\r$list\n\r"), "{}", output);
+
+        let output = type_in(&mut session, "$missing\r");
+        assert!(
+            output.contains("Failed to substitute variables"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn staged_changes_are_audited() {
+        let _state = crate::host::exclusive();
+        let mut session = Session::new(Terminal::Uart);
+        let start = crate::events::sequence();
+
+        let output = type_in(
+            &mut session,
+            "config address 10.0.0.2/24\rconfig\rconfig discard\rconfig\r",
+        );
+        assert_eq!(
+            output.matches("Staged:\n\r  Address:  10.0.0.2/24").count(),
+            1,
+            "{}",
+            output
+        );
+
+        // Displaying the configuration isn't audited, but staging and discarding changes are
+        let mut sequence = start;
+        let mut audited = 0;
+        while let Some((entry, next)) = crate::events::since(sequence) {
+            assert!(matches!(
+                entry.event,
+                crate::events::Event::Command(Terminal::Uart, Privileged::Config)
+            ));
+            audited += 1;
+            sequence = next;
+        }
+        assert_eq!(audited, 2);
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        assert_eq!(
+            Privileged::secret("auth password hunter2"),
+            Some("auth password")
+        );
+        assert_eq!(
+            Privileged::secret("snmp community private"),
+            Some("snmp community")
+        );
+        assert_eq!(Privileged::secret("auth token"), None);
+        assert_eq!(Privileged::secret("snmp trap 10.0.0.1"), None);
+    }
+}
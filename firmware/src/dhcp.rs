@@ -0,0 +1,498 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The progress of the DHCP client, which smoltcp keeps to itself. The client's messages are picked
+// out of the frames as they're sent and received (see efm32gg::dispatch and efm32gg::Hooks), which
+// is enough to follow it through discovery and requesting, and to tell a network without a DHCP
+// server (no offers) from a server that refuses the address (a NAK).
+//
+// smoltcp retransmits on its own schedule but never gives up, so an attempt that hasn't produced a
+// lease within the timeout is abandoned by resetting the client (see
+// network::Resources::handle_dhcp_retry). Once the configured number of retries have failed, the
+// client is only reset every few minutes. Both settings are kept in the store.
+//
+// The address of the last lease is kept in the store too (written by `persist`), so that it can be
+// asked for again after a reboot. smoltcp has no way to request an address when discovering, so
+// the requested address option is added to its DISCOVERs as they're sent; the server is free to
+// offer a different one.
+
+use crate::store::{self, Key};
+use core::cell::RefCell;
+use core::fmt;
+use cortex_m::interrupt::{self, Mutex};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{IpAddress, Ipv4Address, Ipv4Packet, UdpPacket};
+
+const CLIENT_PORT: u16 = 68;
+const SERVER_PORT: u16 = 67;
+
+// The fixed part of a DHCP message, which is followed by the magic cookie and then the options
+const FIXED_LEN: usize = 236;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const OPTION_PAD: u8 = 0;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_END: u8 = 255;
+
+/// The room needed to add the requested address option to a DISCOVER (see `transmit`).
+pub const REQUEST_LEN: usize = 6;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+
+const DEFAULT_RETRIES: u8 = 3;
+const DEFAULT_TIMEOUT_SECS: u16 = 30;
+
+/// How long an attempt is given once the retries have run out.
+pub const BACKOFF: Duration = Duration::from_secs(300);
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    phase: Phase::Idle,
+    since: Instant::from_millis_const(0),
+    started: Instant::from_millis_const(0),
+    attempts: 0,
+    counts: Counts::new(),
+    failure: None,
+    refused: false,
+    retries: DEFAULT_RETRIES,
+    timeout_secs: DEFAULT_TIMEOUT_SECS,
+    xid: None,
+    lease: None,
+    saved: None,
+}));
+
+struct State {
+    phase: Phase,
+    // When the client entered the phase
+    since: Instant,
+    // When the current attempt started
+    started: Instant,
+    // The number of attempts that have timed out since the client was last restarted or bound
+    attempts: u32,
+    // The messages seen during the current attempt
+    counts: Counts,
+    failure: Option<Failure>,
+    // Set when a NAK is received, until it's taken (see take_refusal)
+    refused: bool,
+    retries: u8,
+    timeout_secs: u16,
+    // The transaction ID of the client's last message, which its replies carry
+    xid: Option<[u8; 4]>,
+    // The address of the last lease, which is requested when discovering
+    lease: Option<Ipv4Address>,
+    // The address that persist last wrote (or init loaded)
+    saved: Option<Ipv4Address>,
+}
+
+/// The number of each message that has been sent or received.
+#[derive(Clone, Copy)]
+pub struct Counts {
+    pub discovers: u32,
+    pub offers: u32,
+    pub requests: u32,
+    pub acks: u32,
+    pub naks: u32,
+}
+
+impl Counts {
+    const fn new() -> Counts {
+        Counts {
+            discovers: 0,
+            offers: 0,
+            requests: 0,
+            acks: 0,
+            naks: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    /// The client hasn't sent anything since it was (re)started.
+    Idle,
+    /// Broadcasting DISCOVERs and waiting for an offer.
+    Discovering,
+    /// Requesting an offered (or leased) address and waiting for an ACK.
+    Requesting,
+    /// Holding a lease.
+    Bound,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Phase::Idle => "idle",
+            Phase::Discovering => "discovering",
+            Phase::Requesting => "requesting",
+            Phase::Bound => "bound",
+        })
+    }
+}
+
+/// Why an attempt to acquire a lease timed out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Failure {
+    NoOffers,
+    Unanswered,
+    Refused,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Failure::NoOffers => "no offers",
+            Failure::Unanswered => "requests unanswered",
+            Failure::Refused => "refused (NAK)",
+        })
+    }
+}
+
+pub struct Status {
+    pub phase: Phase,
+    /// How long the client has been in its phase.
+    pub elapsed: Duration,
+    /// The number of attempts that have timed out since the client was last restarted or bound.
+    pub attempts: u32,
+    /// The messages seen during the current attempt.
+    pub counts: Counts,
+    /// Why the last attempt failed, if one has.
+    pub failure: Option<Failure>,
+}
+
+/// Loads the retry settings and the last lease from the store. This must be called once at boot.
+pub fn init() {
+    let mut value = [0; 4];
+    let lease = match store::get(Key::Lease, &mut value) {
+        Some(4) => Some(Ipv4Address::from_bytes(&value)),
+        Some(_) => {
+            log::warn!("Ignoring malformed DHCP lease");
+            None
+        }
+        None => None,
+    };
+    if let Some(lease) = lease {
+        log::info!("Requesting the last lease ({})", lease);
+    }
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.lease = lease;
+        state.saved = lease;
+    });
+
+    let mut value = [0; 3];
+    let (retries, timeout_secs) = match store::get(Key::Dhcp, &mut value) {
+        Some(3) if value[1..] != [0, 0] => (value[0], u16::from_le_bytes([value[1], value[2]])),
+        Some(_) => {
+            log::warn!("Ignoring malformed DHCP settings");
+            return;
+        }
+        None => return,
+    };
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.retries = retries;
+        state.timeout_secs = timeout_secs;
+    })
+}
+
+/// Returns the number of attempts that are given the full timeout, and the timeout itself.
+pub fn settings() -> (u8, Duration) {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        (
+            state.retries,
+            Duration::from_secs(u64::from(state.timeout_secs)),
+        )
+    })
+}
+
+/// Sets the number of attempts that are given the full timeout, before backing off.
+pub fn set_retries(retries: u8) -> Result<(), &'static str> {
+    let (_, timeout) = settings();
+    save(retries, timeout.secs() as u16)
+}
+
+/// Sets how long an attempt is given to acquire a lease before the client is restarted.
+pub fn set_timeout(timeout: Duration) -> Result<(), &'static str> {
+    let secs = match timeout.secs() {
+        0 => return Err("the timeout can't be zero"),
+        secs if secs > u64::from(u16::MAX) => return Err("the timeout is too long"),
+        secs => secs as u16,
+    };
+    let (retries, _) = settings();
+    save(retries, secs)
+}
+
+fn save(retries: u8, timeout_secs: u16) -> Result<(), &'static str> {
+    let timeout = timeout_secs.to_le_bytes();
+    store::set(Key::Dhcp, &[retries, timeout[0], timeout[1]])?;
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.retries = retries;
+        state.timeout_secs = timeout_secs;
+    });
+    Ok(())
+}
+
+/// Writes the address of the last lease to the store, if it has changed. This blocks while the
+/// flash is written, so it should be called periodically from a low-priority task.
+pub fn persist() {
+    let lease = interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        match state.lease {
+            Some(lease) if state.saved != Some(lease) => Some(lease),
+            _ => None,
+        }
+    });
+
+    if let Some(lease) = lease {
+        match store::set(Key::Lease, lease.as_bytes()) {
+            Ok(()) => interrupt::free(|cs| STATE.borrow(cs).borrow_mut().saved = Some(lease)),
+            Err(err) => log::warn!("Failed to save DHCP lease: {}", err),
+        }
+    }
+}
+
+pub fn status(now: Instant) -> Status {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        Status {
+            phase: state.phase,
+            elapsed: now - state.since,
+            attempts: state.attempts,
+            counts: state.counts,
+            failure: state.failure,
+        }
+    })
+}
+
+/// Notes that the client was reset for a reason other than a timeout (e.g. the link came up), so
+/// that the attempts start over.
+pub fn restarted(now: Instant) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.attempts = 0;
+        state.failure = None;
+        state.refused = false;
+        start(&mut state, now);
+    })
+}
+
+/// Returns true if the current attempt has timed out, in which case the client needs to be reset
+/// (see `retried`).
+pub fn due(now: Instant) -> bool {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        let limit = match state.attempts < u32::from(state.retries) {
+            true => Duration::from_secs(u64::from(state.timeout_secs)),
+            false => BACKOFF,
+        };
+        match state.phase {
+            Phase::Idle | Phase::Discovering | Phase::Requesting => now - state.started >= limit,
+            Phase::Bound => false,
+        }
+    })
+}
+
+/// Notes that the client was reset after the current attempt timed out, returning why it failed.
+pub fn retried(now: Instant) -> Failure {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        let failure = match state.counts {
+            Counts { naks, .. } if naks > 0 => Failure::Refused,
+            Counts { offers: 0, .. } => Failure::NoOffers,
+            _ => Failure::Unanswered,
+        };
+        state.attempts += 1;
+        state.failure = Some(failure);
+        start(&mut state, now);
+        failure
+    })
+}
+
+/// Returns true (once) if a server has refused the client's request since the last call.
+pub fn take_refusal() -> bool {
+    interrupt::free(|cs| core::mem::take(&mut STATE.borrow(cs).borrow_mut().refused))
+}
+
+fn start(state: &mut State, now: Instant) {
+    state.phase = Phase::Idle;
+    state.since = now;
+    state.started = now;
+    state.counts = Counts::new();
+}
+
+fn enter(state: &mut State, now: Instant, phase: Phase) {
+    if state.phase != phase {
+        state.phase = phase;
+        state.since = now;
+    }
+}
+
+/// Follows the client through the messages that it sends, adding the last lease to its DISCOVERs.
+/// The frame is the first `len` bytes of the buffer, which needs `REQUEST_LEN` bytes of room after
+/// it for the option to be added. Returns the new length of the frame.
+pub fn transmit(now: Instant, buffer: &mut [u8], len: usize) -> usize {
+    let (offset, kind) = match message(&buffer[..len], SERVER_PORT) {
+        Some(message) => message,
+        None => return len,
+    };
+    let mut xid = [0; 4];
+    xid.copy_from_slice(&buffer[offset + 4..][..4]);
+
+    let lease = interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.xid = Some(xid);
+        match kind {
+            DISCOVER => {
+                // A lease that ran out starts a new attempt
+                if state.phase == Phase::Bound {
+                    start(&mut state, now);
+                }
+                state.counts.discovers += 1;
+                enter(&mut state, now, Phase::Discovering);
+                state.lease
+            }
+            REQUEST => {
+                state.counts.requests += 1;
+                // Renewing a lease doesn't interrupt it
+                if state.phase != Phase::Bound {
+                    enter(&mut state, now, Phase::Requesting);
+                }
+                None
+            }
+            _ => None,
+        }
+    });
+
+    match lease {
+        Some(lease) => request(buffer, len, offset, lease).unwrap_or(len),
+        None => len,
+    }
+}
+
+// Adds the requested address option to the DHCP message at the offset, fixing up the IP and UDP
+// headers, and returns the new length of the frame. Nothing is changed if the message already has
+// the option or there isn't room for it.
+fn request(buffer: &mut [u8], len: usize, offset: usize, address: Ipv4Address) -> Option<usize> {
+    let options = offset + FIXED_LEN + 4;
+    if find_option(&buffer[options..len], OPTION_REQUESTED_ADDRESS).is_some()
+        || buffer.len() < len + REQUEST_LEN
+    {
+        return None;
+    }
+    let end = options + find_option(&buffer[options..len], OPTION_END)?;
+
+    buffer.copy_within(end..len, end + REQUEST_LEN);
+    buffer[end] = OPTION_REQUESTED_ADDRESS;
+    buffer[end + 1] = 4;
+    buffer[end + 2..end + REQUEST_LEN].copy_from_slice(address.as_bytes());
+    let len = len + REQUEST_LEN;
+
+    let mut ip = Ipv4Packet::new_unchecked(&mut buffer[14..len]);
+    ip.set_total_len(ip.total_len() + REQUEST_LEN as u16);
+    ip.fill_checksum();
+    let (src, dst) = (
+        IpAddress::from(ip.src_addr()),
+        IpAddress::from(ip.dst_addr()),
+    );
+    let mut udp = UdpPacket::new_unchecked(&mut buffer[offset - 8..len]);
+    udp.set_len(udp.len() + REQUEST_LEN as u16);
+    udp.fill_checksum(&src, &dst);
+    Some(len)
+}
+
+/// Follows the client through the messages sent to it.
+pub fn receive(now: Instant, frame: &[u8]) {
+    let (offset, kind) = match message(frame, CLIENT_PORT) {
+        Some(message) => message,
+        None => return,
+    };
+    let dhcp = &frame[offset..];
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        // Replies to other clients can be broadcast
+        if state.xid.as_ref().map(|xid| &xid[..]) != Some(&dhcp[4..8]) {
+            return;
+        }
+        match kind {
+            OFFER => state.counts.offers += 1,
+            ACK => {
+                state.counts.acks += 1;
+                state.attempts = 0;
+                state.failure = None;
+                enter(&mut state, now, Phase::Bound);
+
+                let address = Ipv4Address::from_bytes(&dhcp[16..20]);
+                if address.is_unicast() {
+                    state.lease = Some(address);
+                }
+            }
+            NAK => {
+                state.counts.naks += 1;
+                state.refused = true;
+            }
+            _ => {}
+        }
+    })
+}
+
+// Returns the offset of the DHCP message in the frame and its type, if the frame holds one sent to
+// the port
+fn message(frame: &[u8], port: u16) -> Option<(usize, u8)> {
+    // Only IPv4 frames carrying UDP
+    if *frame.get(12..14)? != [0x08, 0x00] {
+        return None;
+    }
+    let ip = frame.get(14..)?;
+    if *ip.get(9)? != 17 {
+        return None;
+    }
+
+    let udp = 14 + usize::from(ip[0] & 0x0F) * 4;
+    if *frame.get(udp + 2..udp + 4)? != port.to_be_bytes() {
+        return None;
+    }
+
+    let offset = udp + 8;
+    let dhcp = frame.get(offset..)?;
+    if *dhcp.get(FIXED_LEN..FIXED_LEN + 4)? != MAGIC_COOKIE {
+        return None;
+    }
+
+    let options = &dhcp[FIXED_LEN + 4..];
+    let option = find_option(options, OPTION_MESSAGE_TYPE)?;
+    Some((offset, *options.get(option + 2)?))
+}
+
+// Returns the offset of the first option of the kind (which may be the end), if there is one
+fn find_option(options: &[u8], kind: u8) -> Option<usize> {
+    let mut offset = 0;
+    loop {
+        match *options.get(offset)? {
+            found if found == kind => return Some(offset),
+            OPTION_END => return None,
+            OPTION_PAD => offset += 1,
+            _ => offset += 2 + usize::from(*options.get(offset + 1)?),
+        }
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A discovery protocol, so that a host can enumerate every device on a subnet. The host
+// broadcasts (or sends to the fleet multicast group) a datagram containing "discover" to the
+// discovery port, and each device replies with an announcement: a few "key=value" lines giving
+// the device type, firmware version, hardware address, IPv4 address, and name.
+//
+// Devices can also broadcast a few unsolicited announcements once they first acquire an address
+// after booting, so that a host that's listening learns about new devices right away. These are
+// off unless the firmware is built with the "beacons" feature.
+
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use cortex_m::interrupt::{self, Mutex};
+use ignore_result::Ignore;
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{EthernetAddress, Ipv4Address};
+
+pub const PORT: u16 = 51901;
+
+/// The maximum length of an announcement.
+pub const MAX_MESSAGE_LEN: usize = 192;
+
+const PROBE: &[u8] = b"discover";
+const DEVICE_TYPE: &str = "poe-passthru";
+
+const BEACON_INTERVAL: Duration = Duration::from_secs(2);
+const BEACON_COUNT: u8 = 3;
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    beacons_enabled: cfg!(feature = "beacons"),
+    announced: false,
+    beacons: 0,
+    next_beacon: Instant::from_millis_const(0),
+}));
+
+struct State {
+    beacons_enabled: bool,
+    announced: bool,
+    beacons: u8,
+    next_beacon: Instant,
+}
+
+/// Enables or disables the unsolicited announcements sent after booting.
+pub fn set_beacons(enabled: bool) {
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().beacons_enabled = enabled)
+}
+
+pub fn beacons() -> bool {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().beacons_enabled)
+}
+
+/// Starts sending beacons if this is the first time an address has been acquired since booting.
+pub fn configured(now: Instant) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        if state.beacons_enabled && !state.announced {
+            state.announced = true;
+            state.beacons = BEACON_COUNT;
+            state.next_beacon = now;
+        }
+    })
+}
+
+/// Returns true if a beacon is due, in which case the network needs to be handled.
+pub fn due(now: Instant) -> bool {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        state.beacons > 0 && state.next_beacon <= now
+    })
+}
+
+/// Returns true (and schedules the next one) if a beacon should be sent now.
+pub fn take_beacon(now: Instant) -> bool {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        if state.beacons == 0 || state.next_beacon > now {
+            return false;
+        }
+
+        state.beacons -= 1;
+        state.next_beacon = now + BEACON_INTERVAL;
+        true
+    })
+}
+
+/// Returns true if the datagram is a discovery probe.
+pub fn is_probe(datagram: &[u8]) -> bool {
+    datagram.strip_suffix(b"\n").unwrap_or(datagram) == PROBE
+}
+
+/// Writes an announcement for this device into the buffer, returning its length.
+pub fn announcement(
+    buffer: &mut [u8; MAX_MESSAGE_LEN],
+    hardware_addr: EthernetAddress,
+    address: Ipv4Address,
+) -> usize {
+    let mut message = Message { buffer, len: 0 };
+    // The fields are all short enough that this can't run out of room
+    write!(
+        message,
+        "type={}\nversion={}\nmac={}\nip={}\nname={}\n",
+        DEVICE_TYPE,
+        env!("CARGO_PKG_VERSION"),
+        hardware_addr,
+        address,
+        crate::hostname::get(),
+    )
+    .ignore();
+
+    message.len
+}
+
+struct Message<'a> {
+    buffer: &'a mut [u8; MAX_MESSAGE_LEN],
+    len: usize,
+}
+
+impl fmt::Write for Message<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let remaining = &mut self.buffer[self.len..];
+        if s.len() > remaining.len() {
+            return Err(fmt::Error);
+        }
+
+        remaining[..s.len()].copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
+}
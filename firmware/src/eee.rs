@@ -0,0 +1,114 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Energy Efficient Ethernet (802.3az). When it's enabled, the PHY advertises EEE for 100BASE-TX,
+// and if the link partner does too, the partner may put its half of the link into low-power idle
+// (LPI) between frames. The MAC only enters LPI when told to, frame by frame, so this end of the
+// link never does; that saves little here, but lets a PoE switch save power on its side of each
+// link. EEE is disabled by default (since some link partners handle it badly) unless the firmware
+// is built with the "eee" feature. Changes are applied by the network task, which restarts
+// autonegotiation so that the new advertisement takes effect.
+
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    enabled: cfg!(feature = "eee"),
+    // The setting is always applied at boot, regardless of the PHY's default
+    pending: true,
+    negotiated: None,
+    lpi_active: false,
+    lpi_entries: 0,
+    lpi_exits: 0,
+}));
+
+struct State {
+    enabled: bool,
+    pending: bool,
+    negotiated: Option<bool>,
+    lpi_active: bool,
+    lpi_entries: u32,
+    lpi_exits: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Status {
+    pub enabled: bool,
+    /// Whether both ends of the link advertised EEE, or `None` if the link is down.
+    pub negotiated: Option<bool>,
+    /// Whether the link partner is currently in low-power idle.
+    pub lpi_active: bool,
+    pub lpi_entries: u32,
+    pub lpi_exits: u32,
+}
+
+/// Enables or disables advertising EEE.
+pub fn set_enabled(enabled: bool) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.enabled = enabled;
+        state.pending = true;
+    })
+}
+
+/// Returns true if the setting needs to be applied, in which case the network needs to be handled.
+pub fn pending() -> bool {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().pending)
+}
+
+/// Returns the setting to apply to the PHY, if it has changed since it was last applied.
+pub fn take_change() -> Option<bool> {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        match state.pending {
+            true => {
+                state.pending = false;
+                Some(state.enabled)
+            }
+            false => None,
+        }
+    })
+}
+
+/// Records the outcome of negotiating EEE, once the link comes up, or `None` once it goes down.
+pub fn set_negotiated(negotiated: Option<bool>) {
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().negotiated = negotiated)
+}
+
+/// Records the MAC's indication that the link partner has entered or left low-power idle.
+pub fn lpi_changed(active: bool) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        match (state.lpi_active, active) {
+            (false, true) => state.lpi_entries = state.lpi_entries.wrapping_add(1),
+            (true, false) => state.lpi_exits = state.lpi_exits.wrapping_add(1),
+            _ => {}
+        }
+        state.lpi_active = active;
+    })
+}
+
+pub fn status() -> Status {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        Status {
+            enabled: state.enabled,
+            negotiated: state.negotiated,
+            lpi_active: state.lpi_active,
+            lpi_entries: state.lpi_entries,
+            lpi_exits: state.lpi_exits,
+        }
+    })
+}
@@ -0,0 +1,97 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Single-ended conversions with ADC0 against the 1.25 V reference, one at a time. Inputs are
+// selected by their POSSEL value: the internal temperature sensor, or an APORT channel (e.g.
+// 0x24 for APORT1XCH4, which is PC4). The offset and gain calibration for the reference is loaded
+// from the DI page; without a valid DI page, the ADC runs uncalibrated.
+
+use super::devinfo;
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+use efm32gg11b820::{ADC0, CMU};
+
+/// The internal temperature sensor (see sensors).
+pub const TEMPERATURE: u8 = 0xF3;
+
+/// The reference voltage, which is the full scale of a conversion.
+pub const REFERENCE_MV: u32 = 1250;
+
+const FULL_SCALE: u32 = 4096;
+
+const ADC_CLOCK_HZ: u32 = 10_000_000;
+
+const ADC_CTRL_PRESC_SHIFT: u32 = 8;
+const ADC_CTRL_TIMEBASE_SHIFT: u32 = 16;
+const ADC_SINGLECTRL_REF_1V25: u32 = 0;
+const ADC_SINGLECTRL_POSSEL_SHIFT: u32 = 8;
+const ADC_SINGLECTRL_NEGSEL_VSS: u32 = 0xFF << 16;
+const ADC_SINGLECTRL_AT_256CYCLES: u32 = 0x9 << 24;
+const ADC_CMD_SINGLESTART: u32 = 1 << 0;
+const ADC_STATUS_SINGLEDV: u32 = 1 << 16;
+
+// The single-ended offset, inverted offset, and gain in the CAL register
+const ADC_CAL_SINGLE_MASK: u32 = 0x7FFF;
+
+static ADC: Mutex<RefCell<Option<ADC0>>> = Mutex::new(RefCell::new(None));
+
+/// Enables ADC0 and loads its calibration. `hfperclk` is the frequency of the peripheral clock,
+/// in Hz.
+pub fn init(adc: ADC0, cmu: &CMU, hfperclk: u32) {
+    cmu.hfperclken0.modify(|_, reg| reg.adc0().set_bit());
+
+    let presc = (hfperclk + ADC_CLOCK_HZ - 1) / ADC_CLOCK_HZ - 1;
+    let timebase = (hfperclk + 999_999) / 1_000_000 - 1;
+    adc.ctrl.write(|reg| unsafe {
+        reg.bits(presc << ADC_CTRL_PRESC_SHIFT | timebase << ADC_CTRL_TIMEBASE_SHIFT)
+    });
+
+    if devinfo::is_valid() {
+        adc.cal.modify(|r, w| unsafe {
+            w.bits(r.bits() & !ADC_CAL_SINGLE_MASK | devinfo::adc0_cal_1v25())
+        });
+    }
+
+    interrupt::free(|cs| ADC.borrow(cs).replace(Some(adc)));
+}
+
+/// Runs a single conversion of the input, returning the 12-bit result, or `None` if the ADC
+/// hasn't been initialized.
+pub fn sample(input: u8) -> Option<u16> {
+    interrupt::free(|cs| {
+        let adc = ADC.borrow(cs).borrow();
+        let adc = adc.as_ref()?;
+
+        adc.singlectrl.write(|reg| unsafe {
+            reg.bits(
+                ADC_SINGLECTRL_REF_1V25
+                    | u32::from(input) << ADC_SINGLECTRL_POSSEL_SHIFT
+                    | ADC_SINGLECTRL_NEGSEL_VSS
+                    | ADC_SINGLECTRL_AT_256CYCLES,
+            )
+        });
+        adc.cmd
+            .write(|reg| unsafe { reg.bits(ADC_CMD_SINGLESTART) });
+        while adc.status.read().bits() & ADC_STATUS_SINGLEDV == 0 {}
+
+        Some((adc.singledata.read().bits() & 0x0FFF) as u16)
+    })
+}
+
+/// Samples the input, returning its voltage in millivolts, or `None` if the ADC hasn't been
+/// initialized. Inputs above the reference read as the reference.
+pub fn read_channel(input: u8) -> Option<u32> {
+    Some(u32::from(sample(input)?) * REFERENCE_MV / FULL_SCALE)
+}
@@ -0,0 +1,98 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The source of the high-frequency clock. The binaries run from the HFXO, but a damaged crystal
+// may never stabilize, so each wait on it is bounded (see `wait`) and, if one times out, the
+// binary falls back to the HFRCO (see `fall_back`) instead of hanging at boot.
+//
+// The HFRCO runs in its 26 MHz band, the closest to the 25 MHz that the monotonic timer is built
+// for, so that timeouts stay within a few percent. The RMII reference clock, which is normally the
+// doubled HFXO, comes from the AUXHFRCO's 48 MHz band instead. That's well outside of what RMII
+// allows, so the link is limited to 10 Mbps, which is the most forgiving of it; the link may still
+// fail, but the rest of the device (the terminal, the LEDs, and the port) keeps working.
+
+use super::devinfo::{self, RcoBand};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use efm32gg11b820::CMU;
+
+/// How long to wait for each step of the HFXO's startup.
+pub const TIMEOUT_MS: u32 = 100;
+
+static FALLBACK: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    Hfxo,
+    Hfrco,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Source::Hfxo => "HFXO",
+            Source::Hfrco => "HFRCO (HFXO failed)",
+        })
+    }
+}
+
+/// Busy-waits until `ready` returns true, giving up after `TIMEOUT_MS`. This runs before the
+/// monotonic timer is started, so the time is counted in delays, at the given core frequency.
+pub fn wait<F: Fn() -> bool>(core_hz: u32, ready: F) -> Result<(), &'static str> {
+    for _ in 0..TIMEOUT_MS {
+        if ready() {
+            return Ok(());
+        }
+        cortex_m::asm::delay(core_hz / 1000);
+    }
+
+    match ready() {
+        true => Ok(()),
+        false => Err("timed out"),
+    }
+}
+
+/// Gives up on the HFXO, running the core from the HFRCO and the RMII reference clock from the
+/// AUXHFRCO. The flash wait states have to have been set for the higher of the two frequencies.
+pub fn fall_back(cmu: &CMU) {
+    FALLBACK.store(true, Ordering::Relaxed);
+
+    // The HFXO can't be disabled while it's selected, so move off of it first
+    cmu.hfclksel.write(|reg| reg.hf().hfrco());
+    cmu.oscencmd.write(|reg| reg.hfxodis().set_bit());
+
+    // Without calibration, the bands can't be selected, so the HFRCO stays in its reset band
+    if devinfo::is_valid() {
+        cmu.hfrcoctrl
+            .write(|reg| unsafe { reg.bits(devinfo::hfrco_cal(RcoBand::Mhz26).bits()) });
+        while cmu.syncbusy.read().hfrcobsy().bit_is_set() {}
+
+        if let Some(cal) = devinfo::auxhfrco_cal(RcoBand::Mhz48) {
+            cmu.auxhfrcoctrl
+                .write(|reg| unsafe { reg.bits(cal.bits()) });
+        }
+    }
+
+    cmu.oscencmd.write(|reg| reg.auxhfrcoen().set_bit());
+    while cmu.status.read().auxhfrcordy().bit_is_clear() {}
+}
+
+/// Returns the source of the high-frequency clock.
+pub fn source() -> Source {
+    match FALLBACK.load(Ordering::Relaxed) {
+        false => Source::Hfxo,
+        true => Source::Hfrco,
+    }
+}
@@ -0,0 +1,346 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The device information (DI) page is written during production test and contains calibration
+// values and identifying information for the part. It's read-only, so the values are read directly
+// from flash.
+//
+// The integrity of the page is checked once at boot (see `init`). If the CRC doesn't match, none
+// of the calibration values can be trusted, so the users of this module fall back to safe defaults
+// (usually by disabling the feature that needs calibration).
+
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const BASE: usize = 0x0FE0_81B0;
+
+// The CRC covers everything after the CRC field itself, through the end of the DI page
+const CRC_START: usize = BASE + 2;
+const CRC_END: usize = 0x0FE0_8400;
+
+const CAL: usize = 0x000;
+const MEMINFO: usize = 0x034;
+const UNIQUEL: usize = 0x040;
+const UNIQUEH: usize = 0x044;
+const MSIZE: usize = 0x048;
+const PART: usize = 0x04C;
+const DEVINFOREV: usize = 0x050;
+const ADC0CAL0: usize = 0x060;
+const ADC0CAL3: usize = 0x06C;
+const HFRCOCAL0: usize = 0x080;
+const AUXHFRCOCAL0: usize = 0x0E0;
+const VMONCAL0: usize = 0x140;
+const VMONCAL1: usize = 0x144;
+
+static VALID: AtomicBool = AtomicBool::new(false);
+
+#[cfg(not(any(test, feature = "host")))]
+fn read(offset: usize) -> u32 {
+    unsafe { ptr::read_volatile((BASE + offset) as *const u32) }
+}
+
+// There's no DI page on the host, so it reads as erased (and never passes validation)
+#[cfg(any(test, feature = "host"))]
+fn read(_: usize) -> u32 {
+    u32::MAX
+}
+
+/// Validates the DI page against its CRC. This must be called once at boot, before any of the
+/// calibration values are used.
+pub fn init() -> bool {
+    let expected = read(CAL) as u16;
+    let actual = (CRC_START..CRC_END).fold(0xFFFF, |crc, addr| {
+        crc16(crc, unsafe { ptr::read_volatile(addr as *const u8) })
+    });
+
+    let valid = expected == actual;
+    if !valid {
+        log::warn!(
+            "DI page is corrupt (CRC 0x{:04X}, expected 0x{:04X}); ignoring calibration",
+            actual,
+            expected
+        );
+    }
+
+    VALID.store(valid, Ordering::Relaxed);
+    valid
+}
+
+/// Returns true if the DI page passed validation.
+pub fn is_valid() -> bool {
+    VALID.load(Ordering::Relaxed)
+}
+
+/// Returns the part's unique number.
+pub fn unique() -> u64 {
+    u64::from(read(UNIQUEH)) << 32 | u64::from(read(UNIQUEL))
+}
+
+/// Returns the temperature (in degrees Celsius) at which the device was calibrated.
+pub fn cal_temp() -> u8 {
+    (read(CAL) >> 16) as u8
+}
+
+/// Returns the revision of the layout of the DI page.
+pub fn revision() -> u8 {
+    read(DEVINFOREV) as u8
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TempGrade {
+    /// -40 to 85 °C
+    Industrial,
+    /// -40 to 125 °C
+    Automotive,
+    /// -40 to 105 °C
+    Extended,
+    /// 0 to 70 °C
+    Commercial,
+    Unknown(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Package {
+    Wlcsp,
+    Bga,
+    Qfn,
+    Qfp,
+    Unknown(u8),
+}
+
+/// The package and memory layout of the part.
+#[derive(Clone, Copy, Debug)]
+pub struct MemInfo {
+    pub flash_page_size: u32,
+    pub pin_count: u8,
+    pub package: Package,
+    pub temp_grade: TempGrade,
+}
+
+pub fn mem_info() -> MemInfo {
+    let bits = read(MEMINFO);
+    MemInfo {
+        // The page size is encoded as a power of two, offset by ten (wrapping)
+        flash_page_size: 1u32
+            .checked_shl(u32::from(((bits >> 24) as u8).wrapping_add(10)))
+            .unwrap_or(0),
+        pin_count: (bits >> 16) as u8,
+        package: match (bits >> 8) as u8 {
+            0x4A => Package::Wlcsp,
+            0x4C => Package::Bga,
+            0x4D => Package::Qfn,
+            0x51 => Package::Qfp,
+            other => Package::Unknown(other),
+        },
+        temp_grade: match bits as u8 {
+            0 => TempGrade::Industrial,
+            1 => TempGrade::Automotive,
+            2 => TempGrade::Extended,
+            3 => TempGrade::Commercial,
+            other => TempGrade::Unknown(other),
+        },
+    }
+}
+
+/// The sizes of the flash and RAM, in KiB.
+#[derive(Clone, Copy, Debug)]
+pub struct MemSize {
+    pub flash_kib: u16,
+    pub sram_kib: u16,
+}
+
+pub fn mem_size() -> MemSize {
+    let bits = read(MSIZE);
+    MemSize {
+        flash_kib: bits as u16,
+        sram_kib: (bits >> 16) as u16,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Family {
+    Efm32Gg11b,
+    Unknown(u8),
+}
+
+/// The identity of the part (e.g. EFM32GG11B820).
+#[derive(Clone, Copy, Debug)]
+pub struct Part {
+    pub family: Family,
+    pub number: u16,
+    pub revision: u8,
+}
+
+impl fmt::Display for Part {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.family {
+            Family::Efm32Gg11b => write!(f, "EFM32GG11B{}", self.number)?,
+            Family::Unknown(family) => write!(f, "unknown family {} part {}", family, self.number)?,
+        }
+        write!(f, " (revision {})", self.revision)
+    }
+}
+
+pub fn part() -> Part {
+    let bits = read(PART);
+    Part {
+        family: match (bits >> 16) as u8 {
+            100 => Family::Efm32Gg11b,
+            other => Family::Unknown(other),
+        },
+        number: bits as u16,
+        revision: (bits >> 24) as u8,
+    }
+}
+
+/// The frequency bands of the HFRCO and AUXHFRCO that have calibration values.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RcoBand {
+    Mhz4,
+    Mhz7,
+    Mhz13,
+    Mhz16,
+    Mhz19,
+    Mhz26,
+    Mhz32,
+    Mhz38,
+    Mhz48,
+    Mhz56,
+    Mhz64,
+    Mhz72,
+}
+
+impl RcoBand {
+    // The index of the band's calibration register
+    fn index(self) -> usize {
+        match self {
+            RcoBand::Mhz4 => 0,
+            RcoBand::Mhz7 => 3,
+            RcoBand::Mhz13 => 6,
+            RcoBand::Mhz16 => 7,
+            RcoBand::Mhz19 => 8,
+            RcoBand::Mhz26 => 10,
+            RcoBand::Mhz32 => 11,
+            RcoBand::Mhz38 => 12,
+            RcoBand::Mhz48 => 13,
+            RcoBand::Mhz56 => 14,
+            RcoBand::Mhz64 => 15,
+            RcoBand::Mhz72 => 16,
+        }
+    }
+}
+
+/// An HFRCO or AUXHFRCO calibration value, in the layout of the oscillator's CTRL register.
+#[derive(Clone, Copy, Debug)]
+pub struct RcoCal(u32);
+
+impl RcoCal {
+    /// Returns the raw value, suitable for writing directly to HFRCOCTRL or AUXHFRCOCTRL.
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn tuning(&self) -> u8 {
+        (self.0 & 0x7F) as u8
+    }
+
+    pub fn fine_tuning(&self) -> u8 {
+        ((self.0 >> 8) & 0x3F) as u8
+    }
+
+    pub fn freq_range(&self) -> u8 {
+        ((self.0 >> 16) & 0x1F) as u8
+    }
+}
+
+/// Returns the HFRCO calibration for the band.
+pub fn hfrco_cal(band: RcoBand) -> RcoCal {
+    RcoCal(read(HFRCOCAL0 + 4 * band.index()))
+}
+
+/// Returns the AUXHFRCO calibration for the band, or `None` if the band is above the AUXHFRCO's
+/// maximum frequency (50 MHz).
+pub fn auxhfrco_cal(band: RcoBand) -> Option<RcoCal> {
+    match band {
+        RcoBand::Mhz56 | RcoBand::Mhz64 | RcoBand::Mhz72 => None,
+        band => Some(RcoCal(read(AUXHFRCOCAL0 + 4 * band.index()))),
+    }
+}
+
+/// Returns ADC0's single-ended calibration (offset, negative offset, and gain) for the 1.25 V
+/// reference, laid out as in the ADC's CAL register.
+pub fn adc0_cal_1v25() -> u32 {
+    read(ADC0CAL0) & 0x7FFF
+}
+
+/// Returns the 12-bit ADC0 reading of the temperature sensor, taken at the calibration
+/// temperature using the 1.25 V reference.
+pub fn adc0_temp_read_1v25() -> u16 {
+    ((read(ADC0CAL3) >> 4) & 0x0FFF) as u16
+}
+
+/// A VMON threshold setting, as a pair of coarse and fine steps.
+#[derive(Clone, Copy, Debug)]
+pub struct VmonThreshold {
+    pub coarse: u8,
+    pub fine: u8,
+}
+
+/// The VMON threshold settings that were measured to correspond to 1.86 V and 2.98 V.
+#[derive(Clone, Copy, Debug)]
+pub struct VmonCal {
+    pub low: VmonThreshold,
+    pub high: VmonThreshold,
+}
+
+impl VmonCal {
+    fn from_bits(bits: u16) -> VmonCal {
+        let nibble = |shift: u16| ((bits >> shift) & 0xF) as u8;
+        VmonCal {
+            low: VmonThreshold {
+                fine: nibble(0),
+                coarse: nibble(4),
+            },
+            high: VmonThreshold {
+                fine: nibble(8),
+                coarse: nibble(12),
+            },
+        }
+    }
+}
+
+/// Returns the VMON calibration for AVDD.
+pub fn vmon_cal_avdd() -> VmonCal {
+    VmonCal::from_bits((read(VMONCAL0) >> 16) as u16)
+}
+
+/// Returns the VMON calibration for DVDD.
+pub fn vmon_cal_dvdd() -> VmonCal {
+    VmonCal::from_bits((read(VMONCAL1) >> 16) as u16)
+}
+
+/// Returns the VMON calibration for IOVDD0.
+pub fn vmon_cal_iovdd0() -> VmonCal {
+    VmonCal::from_bits(read(VMONCAL1) as u16)
+}
+
+// CRC-16-CCITT (polynomial 0x1021), computed bitwise to avoid the need for a table
+fn crc16(crc: u16, byte: u8) -> u16 {
+    (0..8).fold(crc ^ u16::from(byte) << 8, |crc, _| match crc & 0x8000 {
+        0 => crc << 1,
+        _ => (crc << 1) ^ 0x1021,
+    })
+}
@@ -0,0 +1,75 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The CRC-32 (the reflected one used by Ethernet and zlib) used throughout the firmware, e.g. to
+// verify updates (see boot) and store records (see store). It's computed by the General Purpose
+// CRC (GPCRC) peripheral, a byte at a time. The peripheral can only compute one CRC at a time, so
+// it's claimed for the duration; anything that preempts a computation (e.g. the fault handler)
+// computes its CRC in software instead.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::interrupt;
+use efm32gg11b820::{CMU, GPCRC};
+
+// The default polynomial (0x04C11DB7) and bit order are those of the reflected CRC-32
+const CTRL_EN: u32 = 1 << 0;
+const CMD_INIT: u32 = 1 << 0;
+
+static BUSY: AtomicBool = AtomicBool::new(false);
+
+/// Returns the CRC-32 of the data.
+pub fn crc32(data: &[u8]) -> u32 {
+    if BUSY.swap(true, Ordering::Acquire) {
+        return software(data);
+    }
+
+    let crc = hardware(data);
+    BUSY.store(false, Ordering::Release);
+    crc
+}
+
+/// Returns the CRC-32 of the `len` bytes of memory starting at `start`.
+pub fn crc32_at(start: u32, len: u32) -> u32 {
+    crc32(unsafe { core::slice::from_raw_parts(start as *const u8, len as usize) })
+}
+
+fn hardware(data: &[u8]) -> u32 {
+    let cmu = unsafe { &*CMU::ptr() };
+    let gpcrc = unsafe { &*GPCRC::ptr() };
+
+    if cmu.hfbusclken0.read().gpcrc().bit_is_clear() {
+        interrupt::free(|_| cmu.hfbusclken0.modify(|_, reg| reg.gpcrc().set_bit()));
+    }
+
+    gpcrc.ctrl.write(|reg| unsafe { reg.bits(CTRL_EN) });
+    gpcrc.init.write(|reg| unsafe { reg.bits(!0) });
+    gpcrc.cmd.write(|reg| unsafe { reg.bits(CMD_INIT) });
+    for byte in data {
+        gpcrc
+            .inputdatabyte
+            .write(|reg| unsafe { reg.bits(u32::from(*byte)) });
+    }
+    !gpcrc.data.read().bits()
+}
+
+// Computed bitwise to avoid the need for a table
+fn software(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, byte| {
+        (0..8).fold(crc ^ u32::from(*byte), |crc, _| match crc & 1 {
+            0 => crc >> 1,
+            _ => (crc >> 1) ^ 0xEDB8_8320,
+        })
+    })
+}
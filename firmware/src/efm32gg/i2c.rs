@@ -0,0 +1,231 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A blocking I2C master for I2C0, following the master transmitter and receiver sequences from the
+// reference manual. The pins need to be configured (as open-drain, with pull-ups) by the caller; this only
+// routes them to the peripheral.
+//
+// The binary owns the driver, but may attach it as the shared bus (see `attach`), so that the parts
+// of the firmware that don't own it (e.g. the terminal) can use it.
+
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+use efm32gg11b820::{CMU, I2C0};
+use embedded_hal::blocking::i2c;
+
+// Standard mode, using the default 4:4 low/high ratio
+const FREQUENCY_HZ: u32 = 100_000;
+const CLOCK_LOW_HIGH: u32 = 4 + 4;
+
+// The number of times to poll for an event before giving up on the transfer
+const TIMEOUT: u32 = 100_000;
+
+const CTRL_EN: u32 = 1 << 0;
+
+const CMD_START: u32 = 1 << 0;
+const CMD_STOP: u32 = 1 << 1;
+const CMD_ACK: u32 = 1 << 2;
+const CMD_NACK: u32 = 1 << 3;
+const CMD_ABORT: u32 = 1 << 5;
+const CMD_CLEARTX: u32 = 1 << 6;
+const CMD_CLEARPC: u32 = 1 << 7;
+
+const IF_ACK: u32 = 1 << 6;
+const IF_NACK: u32 = 1 << 7;
+const IF_MSTOP: u32 = 1 << 8;
+const IF_ARBLOST: u32 = 1 << 9;
+const IF_BUSERR: u32 = 1 << 10;
+const IF_ALL: u32 = 0x0007_FFFF;
+
+const STATUS_RXDATAV: u32 = 1 << 8;
+
+const ROUTEPEN_SDAPEN: u32 = 1 << 0;
+const ROUTEPEN_SCLPEN: u32 = 1 << 1;
+const ROUTELOC0_SCLLOC_SHIFT: u32 = 8;
+
+static BUS: Mutex<RefCell<Option<I2c>>> = Mutex::new(RefCell::new(None));
+
+/// Attaches the shared bus.
+pub fn attach(i2c: I2c) {
+    interrupt::free(|cs| *BUS.borrow(cs).borrow_mut() = Some(i2c));
+}
+
+/// Runs `f` with the shared bus. The bus is held for the duration, so this fails if there's no bus
+/// or if it's already in use (e.g. by a task that was preempted).
+pub fn with<R, F: FnOnce(&mut I2c) -> R>(f: F) -> Result<R, &'static str> {
+    let mut bus =
+        interrupt::free(|cs| BUS.borrow(cs).borrow_mut().take()).ok_or("I2C unavailable")?;
+    let result = f(&mut bus);
+    interrupt::free(|cs| *BUS.borrow(cs).borrow_mut() = Some(bus));
+    Ok(result)
+}
+
+pub struct I2c {
+    i2c: I2C0,
+}
+
+impl I2c {
+    /// Enables I2C0 in master mode, routing SDA and SCL to the given locations (see the data
+    /// sheet's alternate function table). `hfperclk` is the frequency of the peripheral clock, in
+    /// Hz.
+    pub fn new(i2c: I2C0, cmu: &CMU, hfperclk: u32, sda_loc: u8, scl_loc: u8) -> I2c {
+        cmu.hfperclken0.modify(|_, reg| reg.i2c0().set_bit());
+
+        // f_SCL = f_HFPERCLK / ((N_low + N_high) * (DIV + 1) + 8)
+        let div = ((hfperclk / FREQUENCY_HZ).saturating_sub(8) / CLOCK_LOW_HIGH).saturating_sub(1);
+        i2c.clkdiv.write(|reg| unsafe { reg.bits(div) });
+
+        i2c.routeloc0.write(|reg| unsafe {
+            reg.bits(u32::from(sda_loc) | u32::from(scl_loc) << ROUTELOC0_SCLLOC_SHIFT)
+        });
+        i2c.routepen
+            .write(|reg| unsafe { reg.bits(ROUTEPEN_SDAPEN | ROUTEPEN_SCLPEN) });
+
+        i2c.ctrl.write(|reg| unsafe { reg.bits(CTRL_EN) });
+
+        // The bus state is unknown until a STOP is seen, so abort anything in progress
+        i2c.cmd.write(|reg| unsafe { reg.bits(CMD_ABORT) });
+
+        I2c { i2c }
+    }
+
+    fn wait_for<F: Fn(u32) -> bool>(&self, f: F) -> Result<u32, &'static str> {
+        for _ in 0..TIMEOUT {
+            let flags = self.i2c.if_.read().bits();
+            if flags & IF_ARBLOST != 0 {
+                return Err("arbitration lost");
+            }
+            if flags & IF_BUSERR != 0 {
+                return Err("bus error");
+            }
+            if f(flags) {
+                return Ok(flags);
+            }
+        }
+
+        Err("timed out")
+    }
+
+    fn command(&mut self, cmd: u32) {
+        self.i2c.cmd.write(|reg| unsafe { reg.bits(cmd) });
+    }
+
+    fn begin(&mut self) {
+        self.command(CMD_CLEARTX | CMD_CLEARPC);
+        self.i2c.ifc.write(|reg| unsafe { reg.bits(IF_ALL) });
+    }
+
+    // Sends a byte (or the address, following a START) and waits for it to be acknowledged
+    fn send(&mut self, byte: u8) -> Result<(), &'static str> {
+        self.i2c
+            .ifc
+            .write(|reg| unsafe { reg.bits(IF_ACK | IF_NACK) });
+        self.i2c
+            .txdata
+            .write(|reg| unsafe { reg.bits(u32::from(byte)) });
+
+        match self.wait_for(|flags| flags & (IF_ACK | IF_NACK) != 0)? & IF_NACK {
+            0 => Ok(()),
+            _ => Err("not acknowledged"),
+        }
+    }
+
+    fn start(&mut self, address: u8, read: bool) -> Result<(), &'static str> {
+        self.i2c
+            .ifc
+            .write(|reg| unsafe { reg.bits(IF_ACK | IF_NACK) });
+        self.i2c
+            .txdata
+            .write(|reg| unsafe { reg.bits(u32::from(address << 1 | read as u8)) });
+        self.command(CMD_START);
+
+        match self.wait_for(|flags| flags & (IF_ACK | IF_NACK) != 0)? & IF_NACK {
+            0 => Ok(()),
+            _ => Err("address not acknowledged"),
+        }
+    }
+
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<(), &'static str> {
+        let len = buffer.len();
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            for _ in 0..TIMEOUT {
+                if self.i2c.status.read().bits() & STATUS_RXDATAV != 0 {
+                    break;
+                }
+            }
+            if self.i2c.status.read().bits() & STATUS_RXDATAV == 0 {
+                return Err("timed out");
+            }
+
+            *byte = self.i2c.rxdata.read().bits() as u8;
+            self.command(if i + 1 == len { CMD_NACK } else { CMD_ACK });
+        }
+
+        Ok(())
+    }
+
+    // Always sends a STOP, even if the transfer failed, so the bus is released
+    fn stop(&mut self, result: Result<(), &'static str>) -> Result<(), &'static str> {
+        self.command(CMD_STOP);
+        let stopped = self.wait_for(|flags| flags & IF_MSTOP != 0);
+
+        if result.is_err() {
+            self.command(CMD_ABORT);
+        }
+        result.and(stopped.map(|_| ()))
+    }
+}
+
+impl i2c::Write for I2c {
+    type Error = &'static str;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.begin();
+        let result = self
+            .start(address, false)
+            .and_then(|_| bytes.iter().try_for_each(|b| self.send(*b)));
+        self.stop(result)
+    }
+}
+
+impl i2c::Read for I2c {
+    type Error = &'static str;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.begin();
+        let result = self.start(address, true).and_then(|_| self.receive(buffer));
+        self.stop(result)
+    }
+}
+
+impl i2c::WriteRead for I2c {
+    type Error = &'static str;
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.begin();
+        let result = self
+            .start(address, false)
+            .and_then(|_| bytes.iter().try_for_each(|b| self.send(*b)))
+            // Issuing another START while the bus is held produces a repeated START
+            .and_then(|_| self.start(address, true))
+            .and_then(|_| self.receive(buffer));
+        self.stop(result)
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Single byte-wide transfers on the linked DMA controller's channels, paced by a peripheral's
+// requests (e.g. a USART's RXDATAV or TXBL), for moving blocks of bytes to or from a peripheral's
+// data register. Descriptors aren't linked; each transfer is programmed directly into its channel.
+//
+// The registers are accessed by address, in the same way as the USB core's (see `usb`). CHEN and
+// CHDONE are shared by every channel, so all of the channels must be used from the same priority.
+
+use efm32gg11b820::CMU;
+
+const BASE: usize = 0x400E_2000;
+
+const CHEN: usize = 0x020;
+const CHDONE: usize = 0x028;
+const REQCLEAR: usize = 0x040;
+const IFC: usize = 0x068;
+
+const CH_BASE: usize = 0x080;
+const CH_STRIDE: usize = 0x030;
+const CH_REQSEL: usize = 0x00;
+const CH_CFG: usize = 0x04;
+const CH_LOOP: usize = 0x08;
+const CH_CTRL: usize = 0x0C;
+const CH_SRC: usize = 0x10;
+const CH_DST: usize = 0x14;
+const CH_LINK: usize = 0x18;
+
+const CTRL_XFERCNT_SHIFT: u32 = 4;
+const CTRL_DONEIFSEN: u32 = 1 << 20;
+const CTRL_SRCINC_NONE: u32 = 3 << 24;
+const CTRL_DSTINC_NONE: u32 = 3 << 28;
+
+const REQSEL_SOURCESEL_SHIFT: u32 = 16;
+
+/// The number of channels.
+pub const CHANNELS: u8 = 24;
+
+/// The most bytes that a single transfer can move.
+pub const MAX_TRANSFER: usize = 2048;
+
+/// A peripheral's DMA request: its source and signal (see the reference manual's LDMA_CHx_REQSEL).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Request {
+    pub source: u32,
+    pub signal: u32,
+}
+
+/// One end of a transfer: either a fixed address (e.g. a data register) or a buffer that's stepped
+/// through a byte at a time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Endpoint {
+    Fixed(u32),
+    Incrementing(u32),
+}
+
+fn write(offset: usize, value: u32) {
+    unsafe { core::ptr::write_volatile((BASE + offset) as *mut u32, value) }
+}
+
+fn read(offset: usize) -> u32 {
+    unsafe { core::ptr::read_volatile((BASE + offset) as *const u32) }
+}
+
+fn channel(channel: u8, offset: usize) -> usize {
+    CH_BASE + usize::from(channel) * CH_STRIDE + offset
+}
+
+/// Enables the controller's clock.
+pub fn init(cmu: &CMU) {
+    cmu.hfbusclken0.modify(|_, reg| reg.ldma().set_bit());
+}
+
+/// Starts moving `count` (at most `MAX_TRANSFER`) bytes from `src` to `dst`, one at a time as the
+/// peripheral requests them. The addresses must remain valid until the transfer is done.
+pub fn start(
+    ch: u8,
+    request: Request,
+    src: Endpoint,
+    dst: Endpoint,
+    count: usize,
+) -> Result<(), &'static str> {
+    if ch >= CHANNELS {
+        return Err("no such channel");
+    }
+    if count == 0 || count > MAX_TRANSFER {
+        return Err("invalid length");
+    }
+
+    let bit = 1 << ch;
+    stop(ch);
+    write(REQCLEAR, bit);
+    write(IFC, bit);
+
+    let (src, src_inc) = match src {
+        Endpoint::Fixed(addr) => (addr, CTRL_SRCINC_NONE),
+        Endpoint::Incrementing(addr) => (addr, 0),
+    };
+    let (dst, dst_inc) = match dst {
+        Endpoint::Fixed(addr) => (addr, CTRL_DSTINC_NONE),
+        Endpoint::Incrementing(addr) => (addr, 0),
+    };
+
+    write(
+        channel(ch, CH_REQSEL),
+        request.source << REQSEL_SOURCESEL_SHIFT | request.signal,
+    );
+    write(channel(ch, CH_CFG), 0);
+    write(channel(ch, CH_LOOP), 0);
+    write(channel(ch, CH_SRC), src);
+    write(channel(ch, CH_DST), dst);
+    write(channel(ch, CH_LINK), 0);
+    write(
+        channel(ch, CH_CTRL),
+        (count as u32 - 1) << CTRL_XFERCNT_SHIFT | CTRL_DONEIFSEN | src_inc | dst_inc,
+    );
+
+    write(CHDONE, read(CHDONE) & !bit);
+    write(CHEN, read(CHEN) | bit);
+    Ok(())
+}
+
+/// Returns true once the channel's transfer has moved every byte.
+pub fn is_done(ch: u8) -> bool {
+    read(CHDONE) & 1 << ch != 0
+}
+
+/// Stops the channel's transfer, if it hasn't finished.
+pub fn stop(ch: u8) {
+    write(CHEN, read(CHEN) & !(1 << ch));
+}
@@ -0,0 +1,123 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Link-state debouncing. The PHY interrupts on every change in the link, including the brief
+// drops and recoveries seen while a cable is being seated or autonegotiation restarts, and it
+// reports the link as up a little before the link partner is ready to pass frames. Rather than
+// acting on each interrupt, the link is sampled again once it has held steady for a settling
+// time, and only a change that survives that is reported. A link that keeps flapping has to hold
+// steady for much longer before its next change is reported.
+
+use smoltcp::time::{Duration, Instant};
+
+// How long the link has to hold steady before a change is reported. Frames sent much sooner than
+// this after the link comes up tend to be lost.
+const SETTLE: Duration = Duration::from_millis(500);
+
+// How long a flapping link has to hold steady
+const HOLD_DOWN: Duration = Duration::from_secs(5);
+
+// A link is considered to be flapping once it has changed more than FLAP_LIMIT times in
+// FLAP_WINDOW
+const FLAP_WINDOW: Duration = Duration::from_secs(10);
+const FLAP_LIMIT: u8 = 3;
+
+/// The outcome of sampling the link while it's being debounced.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkEvent {
+    /// The link hasn't held steady for long enough; it should be sampled again after the delay.
+    Settling(Duration),
+    Up,
+    Down,
+    /// The link settled into the state that was last reported.
+    Unchanged,
+}
+
+pub struct Debouncer {
+    reported: bool,
+    sampled: bool,
+    changed: Instant,
+    settling: bool,
+    flaps: u8,
+    window_start: Instant,
+}
+
+impl Debouncer {
+    pub const fn new() -> Debouncer {
+        Debouncer {
+            reported: false,
+            sampled: false,
+            changed: Instant::from_millis_const(0),
+            settling: false,
+            flaps: 0,
+            window_start: Instant::from_millis_const(0),
+        }
+    }
+
+    /// Notes the state of the link when the PHY interrupted, returning how long to wait before
+    /// calling `sample`.
+    pub fn interrupted(&mut self, now: Instant, up: bool) -> Duration {
+        self.update(now, up);
+        self.settling = true;
+        self.settle_time()
+    }
+
+    /// Samples the link, reporting a change once it has held steady for long enough.
+    pub fn sample(&mut self, now: Instant, up: bool) -> LinkEvent {
+        if !self.settling {
+            return LinkEvent::Unchanged;
+        }
+
+        self.update(now, up);
+        let (steady, settle) = (now - self.changed, self.settle_time());
+        if steady < settle {
+            return LinkEvent::Settling(settle - steady);
+        }
+
+        self.settling = false;
+        match (self.reported, up) {
+            (false, true) => {
+                self.reported = true;
+                LinkEvent::Up
+            }
+            (true, false) => {
+                self.reported = false;
+                LinkEvent::Down
+            }
+            _ => LinkEvent::Unchanged,
+        }
+    }
+
+    fn update(&mut self, now: Instant, up: bool) {
+        if up == self.sampled {
+            return;
+        }
+
+        self.sampled = up;
+        self.changed = now;
+        if now - self.window_start > FLAP_WINDOW {
+            self.window_start = now;
+            self.flaps = 0;
+        }
+        self.flaps = self.flaps.saturating_add(1);
+    }
+
+    fn settle_time(&self) -> Duration {
+        match self.flaps > FLAP_LIMIT {
+            true => HOLD_DOWN,
+            false => SETTLE,
+        }
+    }
+}
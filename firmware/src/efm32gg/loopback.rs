@@ -0,0 +1,109 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The driver's half of the loopback self-test (see selftest). The test runs synchronously, with
+// the driver's interrupt held off by the network lock, so sent descriptors are reclaimed here
+// rather than on TXCMPLT.
+
+use super::dma::TxPriority;
+use super::EFM32GG;
+use crate::selftest::{self, Failure, Loopback};
+use ethernet_phy::Phy;
+use smoltcp::phy::TxToken as _;
+use smoltcp::time::Instant;
+
+// The number of times to check for a looped-back frame before giving up
+const RX_TIMEOUT: u32 = 1_000_000;
+
+// The number of cycles to wait for the PHY to enter loopback
+const PHY_SETTLE_CYCLES: u32 = 5_000_000;
+
+const NETWORKCTRL_LOOPBACKLOCAL: u32 = 1 << 1;
+
+impl<P: Phy> EFM32GG<'_, P> {
+    /// Runs a loopback self-test, blocking until it finishes.
+    pub fn self_test(&mut self, now: Instant, loopback: Loopback) -> Result<(), Failure> {
+        let config = self.mac.eth.networkcfg.read().bits();
+        match loopback {
+            Loopback::Phy => {
+                // The PHY loops frames back at 100 Mbps, full duplex, so the MAC has to match
+                self.mac.eth.networkcfg.modify(|_, reg| {
+                    reg.speed().set_bit();
+                    reg.fullduplex().set_bit();
+                    reg
+                });
+                self.phy.set_loopback(&mut self.mac, true);
+                cortex_m::asm::delay(PHY_SETTLE_CYCLES);
+            }
+            Loopback::Mac => self
+                .mac
+                .eth
+                .networkctrl
+                .modify(|r, w| unsafe { w.bits(r.bits() | NETWORKCTRL_LOOPBACKLOCAL) }),
+        }
+
+        let result =
+            (0..selftest::FRAME_LENS.len()).try_for_each(|index| self.loop_frame(now, index));
+
+        match loopback {
+            Loopback::Phy => {
+                self.phy.set_loopback(&mut self.mac, false);
+                self.mac
+                    .eth
+                    .networkcfg
+                    .write(|reg| unsafe { reg.bits(config) });
+            }
+            Loopback::Mac => self
+                .mac
+                .eth
+                .networkctrl
+                .modify(|r, w| unsafe { w.bits(r.bits() & !NETWORKCTRL_LOOPBACKLOCAL) }),
+        }
+
+        result
+    }
+
+    fn loop_frame(&mut self, now: Instant, index: usize) -> Result<(), Failure> {
+        let len = selftest::FRAME_LENS[index];
+        let token = self
+            .transmit_with(TxPriority::High, now)
+            .filter(|token| token.capacity() >= len)
+            .ok_or(Failure::NoTransmitBuffers { frame: index })?;
+        token.consume(len, |frame| selftest::fill(frame, index));
+
+        let mut result = Err(Failure::Lost { frame: index });
+        for _ in 0..RX_TIMEOUT {
+            let mut frame = match self.next_frame() {
+                Some(frame) => frame,
+                None => continue,
+            };
+
+            // Anything other than a test frame is dropped
+            let descriptors = self.mac.rx_buffer.descriptors_mut();
+            let check = selftest::check(frame.data(descriptors), index);
+            frame.release(descriptors);
+            if let Some(check) = check {
+                result = check.map_err(|offset| Failure::Corrupted {
+                    frame: index,
+                    offset,
+                });
+                break;
+            }
+        }
+
+        self.mac.reclaim_tx();
+        result
+    }
+}
@@ -0,0 +1,268 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The map of the address space, so that raw accesses (e.g. the terminal's "get" and "set") can be
+// checked before they're made. An access to an address that nothing answers results in a bus
+// fault, which ends the boot. The sizes of the flash and RAM are taken from the device
+// information page, when it can be trusted.
+//
+// Peripherals are mapped one at a time, from their register blocks in the PAC, since most of the
+// peripheral space is unused. A peripheral whose clock is off doesn't answer either, so accesses
+// are also refused until the CMU enables its clock.
+
+use super::devinfo;
+use core::{fmt, mem};
+use efm32gg11b820::{self as pac, CMU};
+
+const INFO_START: u32 = 0x0FE0_0000;
+const INFO_END: u32 = 0x0FE1_0000;
+const RAM_START: u32 = 0x2000_0000;
+const SYSTEM_START: u32 = 0xE000_0000;
+const SYSTEM_END: u32 = 0xE010_0000;
+
+// The sizes of the EFM32GG11B820, for when the device information can't be trusted
+const DEFAULT_FLASH_KIB: u32 = 2048;
+const DEFAULT_RAM_KIB: u32 = 512;
+
+// The bit in HFBUSCLKEN0 that clocks the low energy peripherals' interface
+const HFBUSCLKEN0_LE: u8 = 0;
+
+/// The clock that needs to be enabled (in the CMU) for a peripheral to answer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Clock {
+    Always,
+    /// A bit in HFBUSCLKEN0.
+    Bus(u8),
+    /// A bit in HFPERCLKEN0.
+    Peripheral0(u8),
+    /// A bit in HFPERCLKEN1.
+    Peripheral1(u8),
+    /// The low energy interface, along with a bit in LFACLKEN0, LFBCLKEN0, or LFECLKEN0, if any.
+    LowEnergy,
+    LowEnergyA(u8),
+    LowEnergyB(u8),
+    LowEnergyE(u8),
+}
+
+impl Clock {
+    fn enabled(self) -> bool {
+        let cmu = unsafe { &*CMU::ptr() };
+        let bit = |bits: u32, bit: u8| bits & 1 << bit != 0;
+        let le = || bit(cmu.hfbusclken0.read().bits(), HFBUSCLKEN0_LE);
+        match self {
+            Clock::Always => true,
+            Clock::Bus(b) => bit(cmu.hfbusclken0.read().bits(), b),
+            Clock::Peripheral0(b) => bit(cmu.hfperclken0.read().bits(), b),
+            Clock::Peripheral1(b) => bit(cmu.hfperclken1.read().bits(), b),
+            Clock::LowEnergy => le(),
+            Clock::LowEnergyA(b) => le() && bit(cmu.lfaclken0.read().bits(), b),
+            Clock::LowEnergyB(b) => le() && bit(cmu.lfbclken0.read().bits(), b),
+            Clock::LowEnergyE(b) => le() && bit(cmu.lfeclken0.read().bits(), b),
+        }
+    }
+}
+
+/// A peripheral's register block.
+#[derive(Debug, PartialEq)]
+pub struct Peripheral {
+    name: &'static str,
+    start: u32,
+    len: u32,
+    clock: Clock,
+}
+
+impl Peripheral {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn contains(&self, addr: u32) -> bool {
+        (self.start..self.start + self.len).contains(&addr)
+    }
+}
+
+macro_rules! peripheral {
+    ($name:literal, $start:literal, $block:ident, $clock:expr) => {
+        Peripheral {
+            name: $name,
+            start: $start,
+            len: mem::size_of::<pac::$block::RegisterBlock>() as u32,
+            clock: $clock,
+        }
+    };
+}
+
+use Clock::*;
+
+// The peripherals, by address. The USB block only covers the EFM32-specific registers, not the
+// Synopsys core, which isn't mapped here.
+static PERIPHERALS: [Peripheral; 64] = [
+    peripheral!("MSC", 0x4000_0000, msc, Always),
+    peripheral!("FPUEH", 0x4000_1000, fpueh, Always),
+    peripheral!("LDMA", 0x4000_2000, ldma, Bus(7)),
+    peripheral!("CAN0", 0x4000_4000, can0, Peripheral1(6)),
+    peripheral!("CAN1", 0x4000_4400, can1, Peripheral1(7)),
+    peripheral!("EBI", 0x4000_B000, ebi, Bus(2)),
+    peripheral!("USART0", 0x4001_0000, usart0, Peripheral0(7)),
+    peripheral!("USART1", 0x4001_0400, usart1, Peripheral0(8)),
+    peripheral!("USART2", 0x4001_0800, usart2, Peripheral0(9)),
+    peripheral!("USART3", 0x4001_0C00, usart3, Peripheral0(10)),
+    peripheral!("USART4", 0x4001_1000, usart4, Peripheral0(11)),
+    peripheral!("USART5", 0x4001_1400, usart5, Peripheral0(12)),
+    peripheral!("UART0", 0x4001_4000, uart0, Peripheral1(4)),
+    peripheral!("UART1", 0x4001_4400, uart1, Peripheral1(5)),
+    peripheral!("TIMER0", 0x4001_8000, timer0, Peripheral0(0)),
+    peripheral!("TIMER1", 0x4001_8400, timer1, Peripheral0(1)),
+    peripheral!("TIMER2", 0x4001_8800, timer2, Peripheral0(2)),
+    peripheral!("TIMER3", 0x4001_8C00, timer3, Peripheral0(3)),
+    peripheral!("TIMER4", 0x4001_9000, timer4, Peripheral0(4)),
+    peripheral!("TIMER5", 0x4001_9400, timer5, Peripheral0(5)),
+    peripheral!("TIMER6", 0x4001_9800, timer6, Peripheral0(6)),
+    peripheral!("WTIMER0", 0x4001_A000, wtimer0, Peripheral1(0)),
+    peripheral!("WTIMER1", 0x4001_A400, wtimer1, Peripheral1(1)),
+    peripheral!("WTIMER2", 0x4001_A800, wtimer2, Peripheral1(2)),
+    peripheral!("WTIMER3", 0x4001_AC00, wtimer3, Peripheral1(3)),
+    peripheral!("GPCRC", 0x4001_C000, gpcrc, Bus(8)),
+    peripheral!("QSPI0", 0x4001_C400, qspi0, Bus(9)),
+    peripheral!("TRNG0", 0x4001_D000, trng0, Peripheral0(24)),
+    peripheral!("SMU", 0x4002_0000, smu, Always),
+    Peripheral {
+        name: "USB",
+        start: 0x4002_2000,
+        len: 0x400,
+        clock: Bus(10),
+    },
+    peripheral!("ETH", 0x4002_4000, eth, Bus(3)),
+    peripheral!("WDOG0", 0x4005_2000, wdog0, LowEnergy),
+    peripheral!("WDOG1", 0x4005_2400, wdog1, LowEnergy),
+    peripheral!("LCD", 0x4005_4000, lcd, LowEnergyA(3)),
+    peripheral!("LESENSE", 0x4005_5000, lesense, LowEnergyA(2)),
+    peripheral!("RTC", 0x4006_0000, rtc, LowEnergyA(4)),
+    peripheral!("RTCC", 0x4006_2000, rtcc, LowEnergyE(0)),
+    peripheral!("LETIMER0", 0x4006_6000, letimer0, LowEnergyA(0)),
+    peripheral!("LETIMER1", 0x4006_6400, letimer1, LowEnergyA(1)),
+    peripheral!("LEUART0", 0x4006_A000, leuart0, LowEnergyB(0)),
+    peripheral!("LEUART1", 0x4006_A400, leuart1, LowEnergyB(1)),
+    peripheral!("PCNT0", 0x4006_E000, pcnt0, LowEnergy),
+    peripheral!("PCNT1", 0x4006_E400, pcnt1, LowEnergy),
+    peripheral!("PCNT2", 0x4006_E800, pcnt2, LowEnergy),
+    peripheral!("ACMP0", 0x4008_0000, acmp0, Peripheral0(13)),
+    peripheral!("ACMP1", 0x4008_0400, acmp1, Peripheral0(14)),
+    peripheral!("ACMP2", 0x4008_0800, acmp2, Peripheral0(15)),
+    peripheral!("ACMP3", 0x4008_0C00, acmp3, Peripheral0(16)),
+    peripheral!("ADC0", 0x4008_2000, adc0, Peripheral0(20)),
+    peripheral!("ADC1", 0x4008_2400, adc1, Peripheral0(21)),
+    peripheral!("IDAC0", 0x4008_4000, idac0, Peripheral0(23)),
+    peripheral!("VDAC0", 0x4008_6000, vdac0, Peripheral1(8)),
+    peripheral!("GPIO", 0x4008_8000, gpio, Bus(5)),
+    peripheral!("I2C0", 0x4008_9000, i2c0, Peripheral0(17)),
+    peripheral!("I2C1", 0x4008_9400, i2c1, Peripheral0(18)),
+    peripheral!("I2C2", 0x4008_9800, i2c2, Peripheral0(19)),
+    peripheral!("CSEN", 0x4008_E000, csen, Peripheral1(9)),
+    peripheral!("CRYOTIMER", 0x4008_F000, cryotimer, Peripheral0(22)),
+    peripheral!("EMU", 0x400E_3000, emu, Always),
+    peripheral!("CMU", 0x400E_4000, cmu, Always),
+    peripheral!("RMU", 0x400E_5000, rmu, Always),
+    peripheral!("PRS", 0x400E_6000, prs, Bus(6)),
+    peripheral!("CRYPTO0", 0x400F_0000, crypto0, Bus(1)),
+    peripheral!("SDIO", 0x400F_1000, sdio, Bus(4)),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Region {
+    Flash,
+    /// The user data, lock bits, and device information pages.
+    Info,
+    Ram,
+    Peripheral(&'static Peripheral),
+    /// The core's private peripherals (e.g. the NVIC and SysTick).
+    System,
+}
+
+impl Region {
+    /// Returns the region that holds the address, or `None` if it isn't mapped.
+    pub fn of(addr: u32) -> Option<Region> {
+        let (flash_kib, ram_kib) = match devinfo::is_valid() {
+            true => {
+                let size = devinfo::mem_size();
+                (u32::from(size.flash_kib), u32::from(size.sram_kib))
+            }
+            false => (DEFAULT_FLASH_KIB, DEFAULT_RAM_KIB),
+        };
+
+        // Flash starts at address zero
+        if addr < flash_kib * 1024 {
+            Some(Region::Flash)
+        } else if (INFO_START..INFO_END).contains(&addr) {
+            Some(Region::Info)
+        } else if (RAM_START..RAM_START + ram_kib * 1024).contains(&addr) {
+            Some(Region::Ram)
+        } else if let Some(peripheral) = PERIPHERALS.iter().find(|p| p.contains(addr)) {
+            Some(Region::Peripheral(peripheral))
+        } else if (SYSTEM_START..SYSTEM_END).contains(&addr) {
+            Some(Region::System)
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the region can be written directly, rather than through the flash
+    /// controller (see `msc`).
+    pub fn writable(&self) -> bool {
+        match self {
+            Region::Flash | Region::Info => false,
+            Region::Ram | Region::Peripheral(_) | Region::System => true,
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Region::Flash => "flash",
+            Region::Info => "information pages",
+            Region::Ram => "RAM",
+            Region::Peripheral(peripheral) => peripheral.name,
+            Region::System => "system peripherals",
+        })
+    }
+}
+
+/// Checks that an access of `len` bytes at `addr` falls within a single mapped region (and, for a
+/// peripheral, that its clock is on), and that it's aligned to its length. Returns the region if
+/// so.
+pub fn check(addr: u32, len: u32, write: bool) -> Result<Region, &'static str> {
+    if len == 0 {
+        return Err("length is zero");
+    }
+    if addr % len != 0 {
+        return Err("address isn't aligned");
+    }
+
+    let last = addr.checked_add(len - 1).ok_or("address isn't mapped")?;
+    let region = match (Region::of(addr), Region::of(last)) {
+        (Some(first), Some(last)) if first == last => first,
+        _ => return Err("address isn't mapped"),
+    };
+    if write && !region.writable() {
+        return Err("address is read-only");
+    }
+    if let Region::Peripheral(peripheral) = region {
+        if !peripheral.clock.enabled() {
+            return Err("peripheral's clock is off");
+        }
+    }
+    Ok(region)
+}
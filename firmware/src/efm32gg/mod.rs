@@ -13,27 +13,87 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-pub mod dma;
-
-use crate::mac;
-use crate::phy::{probe_addr as probe_phy_addr, LinkState, Phy, Register};
-use core::cmp;
-use core::convert::TryInto;
+pub mod adc;
+pub mod clock;
+pub mod devinfo;
+pub mod gpcrc;
+pub mod i2c;
+pub mod ldma;
+pub mod link;
+mod loopback;
+pub mod memory;
+pub mod msc;
+pub mod registers;
+pub mod rmu;
+pub mod sleep;
+pub mod spi;
+pub mod timing;
+pub mod traffic;
+pub mod usart;
+pub mod usb;
+pub mod vmon;
+
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
 use dma::{
-    BufferDescriptor, BufferDescriptorOwnership, RxBuffer, RxBufferDescriptor, TxBuffer,
-    TxBufferDescriptor,
+    BufferDescriptor, BufferDescriptorOwnership, RxBuffer, TxBuffer, TxBufferDescriptor,
+    TxPriority, TxRegion, RX_BUFFER_SIZE,
 };
-use efm32gg11b820::{self, Interrupt, ETH, NVIC};
+use efm32gg11b820::{self, eth, Interrupt, ETH, NVIC};
+pub use efm32gg_eth::dma;
+use efm32gg_eth::frame::{RxFrame, MAX_FRAME_LEN};
 use embedded_hal::blocking::delay::DelayMs;
 use embedded_hal::digital::v2::{InputPin, OutputPin};
+use ethernet_phy::{
+    probe_addr as probe_phy_addr, LinkDuplex, LinkSpeed, LinkState, Mdio, Mdix, Phy, Register,
+};
 use ignore_result::Ignore;
+use link::Debouncer;
+pub use link::LinkEvent;
 use smoltcp::wire::EthernetAddress;
-use smoltcp::{self, phy, time, Error};
+use smoltcp::{phy, time};
+
+// The number of times that the receiver has been recovered after an overrun
+static RX_OVERRUNS: AtomicU32 = AtomicU32::new(0);
+
+/// Returns the number of receive overruns that have been recovered from since boot.
+pub fn rx_overruns() -> u32 {
+    RX_OVERRUNS.load(Ordering::Relaxed)
+}
+
+// The address of the PHY on the MDIO bus, once it has been found
+static PHY_ADDRESS: AtomicU8 = AtomicU8::new(NO_PHY_ADDRESS);
+const NO_PHY_ADDRESS: u8 = u8::MAX;
+
+/// Returns the address of the PHY on the MDIO bus, if the driver has been initialized.
+pub fn phy_address() -> Option<u8> {
+    match PHY_ADDRESS.load(Ordering::Relaxed) {
+        NO_PHY_ADDRESS => None,
+        addr => Some(addr),
+    }
+}
+
+/// Access to the MDIO bus for the parts of the firmware that don't own the driver (e.g. the
+/// terminal). Nothing stops these accesses from interleaving with the driver's, so this must only
+/// be used once the driver has been initialized, from tasks that can't preempt it (or be preempted
+/// by it).
+pub struct SharedMdio;
+
+impl Mdio for SharedMdio {
+    fn read(&self, address: u8, register: Register) -> u16 {
+        mdio_read(unsafe { &*ETH::ptr() }, address, register)
+    }
+
+    fn write(&mut self, address: u8, register: Register, data: u16) {
+        mdio_write(unsafe { &*ETH::ptr() }, address, register, data)
+    }
+}
 
 pub struct EFM32GG<'a, P: Phy> {
     mac: Mac<'a>,
     #[allow(unused)]
     phy: P,
+    link: Debouncer,
 }
 
 impl<'a, P: Phy> EFM32GG<'a, P> {
@@ -44,15 +104,14 @@ impl<'a, P: Phy> EFM32GG<'a, P> {
         delay: &mut dyn DelayMs<u8>,
         pins: Pins,
         new_phy: F,
-    ) -> Result<(EFM32GG<'a, P>, EthernetAddress), &'static str>
+    ) -> Result<(EFM32GG<'a, P>, EthernetAddress), crate::error::Error>
     where
-        F: FnOnce(u8, &mut dyn mac::Mdio) -> P,
+        F: FnOnce(u8, &mut dyn Mdio) -> P,
     {
-        use mac::Mdio;
-
         let mut rmii = Rmii::new(eth, delay, pins);
-        let phy_addr = probe_phy_addr(&rmii).ok_or("Failed to find PHY")?;
+        let phy_addr = probe_phy_addr(&rmii).ok_or(crate::error::Error::NoPhy)?;
         let phy = new_phy(phy_addr, &mut rmii);
+        PHY_ADDRESS.store(phy_addr, Ordering::Relaxed);
         let oui = phy.oui(&rmii);
 
         // Set the advertisement as follows:
@@ -68,20 +127,205 @@ impl<'a, P: Phy> EFM32GG<'a, P> {
 
         log::debug!("MAC/PHY initialized ({}/{})", mac_addr, phy_addr);
 
-        Ok((EFM32GG { mac, phy }, mac_addr))
+        let link = Debouncer::new();
+        Ok((EFM32GG { mac, phy, link }, mac_addr))
     }
 
     pub fn mac_irq(&mut self) {
         self.mac.irq()
     }
 
-    pub fn phy_irq(&mut self) {
-        self.phy.irq(&mut self.mac);
+    /// Handles an interrupt from the PHY, returning how long to wait before calling `poll_link`
+    /// to find out whether the link changed.
+    pub fn phy_irq(&mut self, now: time::Instant) -> time::Duration {
+        self.phy.irq(&mut self.mac, &mut crate::health::fault);
+        crate::health::check(now);
+        let up = self.link_state().is_some();
+        self.link.interrupted(now, up)
+    }
+
+    /// Samples the link following an interrupt from the PHY (see `phy_irq`), reporting whether it
+    /// has settled into a new state.
+    pub fn poll_link(&mut self, now: time::Instant) -> LinkEvent {
+        let up = self.link_state().is_some();
+        self.link.sample(now, up)
     }
 
     pub fn link_state(&self) -> Option<LinkState> {
         self.phy.link_state(&self.mac)
     }
+
+    /// Forces the speed and duplex of the link, or lets them be autonegotiated, and sets the MAC to
+    /// match. Autonegotiation only advertises 10BASE-T half duplex.
+    pub fn set_link_state(&mut self, state: Option<LinkState>) {
+        self.phy.set_link_state(&mut self.mac, state);
+
+        let (speed, duplex) = match state {
+            Some(state) => (state.speed, state.duplex),
+            None => (LinkSpeed::TenMbps, LinkDuplex::HalfDuplex),
+        };
+        self.mac.eth.networkcfg.modify(|_, reg| {
+            reg.speed().bit(speed == LinkSpeed::HundredMbps);
+            reg.fullduplex().bit(duplex == LinkDuplex::FullDuplex);
+            reg
+        });
+    }
+
+    pub fn set_mdix(&mut self, mdix: Mdix) {
+        self.phy.set_mdix(&mut self.mac, mdix)
+    }
+
+    /// Enables or disables advertising Energy Efficient Ethernet, restarting autonegotiation.
+    pub fn set_eee(&mut self, enabled: bool) {
+        self.phy.set_eee(&mut self.mac, enabled)
+    }
+
+    /// Returns true if both ends of the link advertised Energy Efficient Ethernet.
+    pub fn eee_negotiated(&mut self) -> bool {
+        self.phy.eee_negotiated(&mut self.mac)
+    }
+
+    /// Accepts frames sent to the multicast address (along with any other addresses that share its
+    /// hash).
+    pub fn join_multicast(&mut self, addr: EthernetAddress) {
+        self.mac.join_multicast(addr)
+    }
+
+    /// Returns the time kept by the MAC's timestamp unit.
+    pub fn ptp_time(&self) -> crate::ptp::Timestamp {
+        self.mac.ptp_time()
+    }
+
+    /// Steps the timestamp unit's time by the given number of nanoseconds.
+    pub fn step_ptp_time(&mut self, offset: i64) {
+        self.mac.step_ptp_time(offset)
+    }
+
+    /// Runs the timestamp unit fast (or slow, if negative) by the given parts per billion.
+    pub fn set_ptp_frequency(&mut self, ppb: i32) {
+        self.mac.set_ptp_frequency(ppb)
+    }
+
+    /// Returns the time at which the last PTP event message was transmitted.
+    pub fn ptp_tx_time(&self) -> crate::ptp::Timestamp {
+        self.mac.ptp_tx_time()
+    }
+
+    /// Returns a token for sending a high-priority frame (e.g. LLDP or PTP), which can use the
+    /// descriptors that normal frames leave free.
+    pub fn transmit_priority(&mut self, timestamp: time::Instant) -> Option<TxToken<'_>> {
+        self.transmit_with(TxPriority::High, timestamp)
+    }
+
+    fn transmit_with(
+        &mut self,
+        priority: TxPriority,
+        timestamp: time::Instant,
+    ) -> Option<TxToken<'_>> {
+        let window = self.mac.find_tx_window(priority)?;
+
+        Some(TxToken::new(
+            self.mac.tx_buffer.descriptors_mut(),
+            &mut self.mac.tx_pending,
+            priority,
+            window,
+            timestamp,
+            Hooks::new(),
+        ))
+    }
+
+    // Takes the next frame out of the RX buffers. Unless it's left in place, the buffers are
+    // released as it's copied out.
+    fn next_frame(&mut self) -> Option<RxFrame> {
+        let (start, end) = self.mac.find_rx_window()?;
+        Some(RxFrame::take(
+            self.mac.rx_buffer.descriptors_mut(),
+            start,
+            end,
+        ))
+    }
+
+    /// Returns the totals of the MAC's statistics since it was initialized.
+    pub fn statistics(&mut self) -> Statistics {
+        self.mac.statistics()
+    }
+
+    /// Returns the counts of frames that failed to send, by error, since the MAC was initialized.
+    pub fn tx_errors(&self) -> TxErrors {
+        self.mac.tx_errors
+    }
+}
+
+/// Frame and octet counts from the MAC's statistics registers.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Statistics {
+    pub rx_octets: u64,
+    pub rx_frames: u32,
+    pub rx_broadcast: u32,
+    pub rx_multicast: u32,
+    pub rx_errors: u32,
+
+    pub tx_octets: u64,
+    pub tx_frames: u32,
+    pub tx_broadcast: u32,
+    pub tx_multicast: u32,
+    pub tx_errors: u32,
+}
+
+/// Counts of the frames that the MAC failed to send, by the error recorded in their descriptors.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TxErrors {
+    pub retry_limit: u32,
+    pub underrun: u32,
+    pub frame_corrupt: u32,
+    pub late_collision: u32,
+    pub checksum_generation: u32,
+}
+
+impl TxErrors {
+    fn record(&mut self, index: usize, d: &TxBufferDescriptor) {
+        fn count(counter: &mut u32, cond: bool, msg: &'static str) -> &'static str {
+            match cond {
+                false => "",
+                true => {
+                    *counter = counter.wrapping_add(1);
+                    msg
+                }
+            }
+        }
+
+        let checksum_generation = d.error_checksum_generation();
+        let errors = (
+            count(
+                &mut self.retry_limit,
+                d.error_retry_limit(),
+                " 'retry limit exceeded'",
+            ),
+            count(&mut self.underrun, d.error_tx_underrun(), " underrun"),
+            count(
+                &mut self.frame_corrupt,
+                d.error_frame_corrupt(),
+                " 'frame corruption'",
+            ),
+            count(
+                &mut self.late_collision,
+                d.error_late_collision(),
+                " 'late collision'",
+            ),
+            match &checksum_generation {
+                Some(err) => {
+                    self.checksum_generation = self.checksum_generation.wrapping_add(1);
+                    err.as_str()
+                }
+                None => "",
+            },
+        );
+
+        match errors {
+            ("", "", "", "", "") => log::trace!("  {:>2} (Done) - {:?}", index, d),
+            (a, b, c, e, f) => log::warn!("TX frame {} failed:{}{}{}{}{}", index, a, b, c, e, f),
+        }
+    }
 }
 
 pub struct Pins<'a> {
@@ -110,14 +354,18 @@ impl Rmii {
     /// Note: This assumes the following:
     ///       - PHY will be interfaced via RMII
     ///       - EFM provides the clock
-    ///       - HFXO is 25 MHz
+    ///       - HFXO is 25 MHz, or the AUXHFRCO is running if the HFXO failed (see `clock`)
     fn new(eth: ETH, delay: &mut dyn DelayMs<u8>, pins: Pins) -> Rmii {
         let cmu = unsafe { &*efm32gg11b820::CMU::ptr() };
 
-        // Enable the HFPER clock and source CLKOUT2 from the frequency-doubled HFXO
+        // Enable the HFPER clock and source CLKOUT2 from the frequency-doubled HFXO (or from the
+        // AUXHFRCO, if the HFXO failed)
         cmu.ctrl.modify(|_, reg| {
             reg.hfperclken().set_bit();
-            reg.clkoutsel2().hfxox2q();
+            match clock::source() {
+                clock::Source::Hfxo => reg.clkoutsel2().hfxox2q(),
+                clock::Source::Hfrco => reg.clkoutsel2().auxhfrcoq(),
+            };
             reg
         });
 
@@ -125,7 +373,7 @@ impl Rmii {
         cmu.hfbusclken0.modify(|_, reg| reg.eth().set_bit());
 
         // Scale the MDC down to 1.5625MHz (below the 2.5MHz limit)
-        // Enable 1536-byte frames, which are needed to support 802.11Q VLAN tagging
+        // Enable 1536-byte frames, which are needed to support 802.1Q VLAN tagging (see vlan)
         eth.networkcfg.write(|reg| {
             reg.mdcclkdiv().divby16();
             reg.rx1536byteframes().set_bit();
@@ -167,7 +415,7 @@ impl Rmii {
     }
 }
 
-impl mac::Mdio for Rmii {
+impl Mdio for Rmii {
     fn read(&self, address: u8, register: Register) -> u16 {
         log::trace!("MDIO.read(0x{:02X}, {:?})", address, register);
         mdio_read(&self.eth, address, register)
@@ -180,14 +428,19 @@ impl mac::Mdio for Rmii {
             register,
             data
         );
-        mdio_write(&mut self.eth, address, register, data)
+        mdio_write(&self.eth, address, register, data)
     }
 }
 
 struct Mac<'a> {
     rx_buffer: RxBuffer<'a>,
     tx_buffer: TxBuffer<'a>,
+    // The oldest descriptor that's been queued for transmission, and the number queued
+    tx_tail: usize,
+    tx_pending: usize,
+    tx_errors: TxErrors,
     eth: ETH,
+    statistics: Statistics,
 }
 
 impl<'a> Mac<'a> {
@@ -200,9 +453,9 @@ impl<'a> Mac<'a> {
     ) -> Mac<'a> {
         let eth = rmii.eth;
 
-        // Set the RX buffer size to 128 bytes
+        // Set the RX buffer size (in units of 64 bytes)
         eth.dmacfg.write(|reg| {
-            unsafe { reg.rxbufsize().bits(128 / 64) };
+            unsafe { reg.rxbufsize().bits((RX_BUFFER_SIZE / 64) as u8) };
             unsafe { reg.ambabrstlen().bits(0x01) };
             reg.txpbuftcpen().set_bit();
             reg.txpbufsize().set_bit();
@@ -221,13 +474,45 @@ impl<'a> Mac<'a> {
         // Set the hardware address filter, starting with the bottom register first
         eth.specaddr1bottom.write(|reg| unsafe {
             reg.addr()
-                .bits(u32::from_be_bytes(addr.0[0..4].try_into().unwrap()).swap_bytes())
+                .bits(u32::from_be_bytes([addr.0[0], addr.0[1], addr.0[2], addr.0[3]]).swap_bytes())
         });
         eth.specaddr1top.write(|reg| unsafe {
             reg.addr()
-                .bits(u16::from_be_bytes(addr.0[4..6].try_into().unwrap()).swap_bytes())
+                .bits(u16::from_be_bytes([addr.0[4], addr.0[5]]).swap_bytes())
+        });
+
+        // Accept LLDPDUs, which are sent to the nearest-bridge group address
+        eth.specaddr2bottom.write(|reg| unsafe {
+            reg.addr()
+                .bits(u32::from_be_bytes([0x01, 0x80, 0xC2, 0x00]).swap_bytes())
+        });
+        eth.specaddr2top.write(|reg| unsafe {
+            reg.addr()
+                .bits(u16::from_be_bytes([0x00, 0x0E]).swap_bytes())
         });
 
+        // Accept PTP messages, which are sent to their own group address
+        let ptp = crate::ptp::MULTICAST.0;
+        eth.specaddr3bottom.write(|reg| unsafe {
+            reg.addr()
+                .bits(u32::from_be_bytes([ptp[0], ptp[1], ptp[2], ptp[3]]).swap_bytes())
+        });
+        eth.specaddr3top.write(|reg| unsafe {
+            reg.addr()
+                .bits(u16::from_be_bytes([ptp[4], ptp[5]]).swap_bytes())
+        });
+
+        // Accept multicast frames whose address matches the hash (see join_multicast), starting
+        // with none
+        eth.hashbottom.write(|reg| unsafe { reg.bits(0) });
+        eth.hashtop.write(|reg| unsafe { reg.bits(0) });
+        eth.networkcfg
+            .modify(|_, reg| reg.multicasthashen().set_bit());
+
+        // Raise an event when a magic packet addressed to this device is received, so that it can
+        // be used as a wake source
+        eth.wolreg.write(|reg| unsafe { reg.bits(WOL_MAGICPKTEN) });
+
         // Clear pending interrupts
         NVIC::unpend(Interrupt::ETH);
         eth.ifcr.write(|reg| {
@@ -301,16 +586,143 @@ impl<'a> Mac<'a> {
             w
         });
 
-        // Enable the global clock
-        eth.ctrl.write(|reg| reg.gblclken().set_bit());
+        // Enable the global clock, and clock the timestamp unit from the RMII reference clock
+        eth.ctrl
+            .write(|reg| unsafe { reg.bits(CTRL_GBLCLKEN | CTRL_TSUCLKSEL_REFCLK) });
+        eth.tsutimerincrsubnsec.write(|reg| unsafe { reg.bits(0) });
+        eth.tsutimerincr
+            .write(|reg| unsafe { reg.bits(TSU_INCREMENT_NS) });
 
         Mac {
             rx_buffer,
             tx_buffer,
+            tx_tail: 0,
+            tx_pending: 0,
+            tx_errors: TxErrors::default(),
             eth,
+            statistics: Statistics::default(),
+        }
+    }
+
+    // The hash is six bits wide, formed by XORing together each six-bit group of the address
+    // (starting from the first bit on the wire), and selects one of the 64 bits in the hash
+    // registers
+    fn join_multicast(&mut self, addr: EthernetAddress) {
+        let mut bytes = [0; 8];
+        bytes[..6].copy_from_slice(addr.as_bytes());
+        let bits = u64::from_le_bytes(bytes);
+        let index = (0..8).fold(0, |acc, i| acc ^ (bits >> (6 * i) & 0x3F)) as u32;
+
+        match index {
+            0..=31 => self
+                .eth
+                .hashbottom
+                .modify(|r, w| unsafe { w.bits(r.bits() | 1 << index) }),
+            _ => self
+                .eth
+                .hashtop
+                .modify(|r, w| unsafe { w.bits(r.bits() | 1 << (index - 32)) }),
         }
     }
 
+    // The seconds are read again to catch the nanoseconds rolling over in between
+    fn ptp_time(&self) -> crate::ptp::Timestamp {
+        loop {
+            let seconds = self.eth.tsutimersec.read().bits();
+            let nanoseconds = self.eth.tsutimernsec.read().bits();
+            if self.eth.tsutimersec.read().bits() == seconds {
+                return crate::ptp::Timestamp {
+                    seconds: u64::from(seconds),
+                    nanoseconds,
+                };
+            }
+        }
+    }
+
+    // Small steps are made with the adjust register, which doesn't disturb the timer's count;
+    // larger ones overwrite the time
+    fn step_ptp_time(&mut self, offset: i64) {
+        match offset.unsigned_abs() < u64::from(TSU_ADJUST_MAX) {
+            true => self.eth.tsutimeradjust.write(|reg| unsafe {
+                reg.bits(match offset < 0 {
+                    true => TSU_ADJUST_SUBTRACT | offset.unsigned_abs() as u32,
+                    false => offset as u32,
+                })
+            }),
+            false => {
+                let time =
+                    crate::ptp::Timestamp::from_nanos(self.ptp_time().total_nanos() + offset);
+                self.eth
+                    .tsutimernsec
+                    .write(|reg| unsafe { reg.bits(time.nanoseconds) });
+                self.eth
+                    .tsutimersec
+                    .write(|reg| unsafe { reg.bits(time.seconds as u32) });
+            }
+        }
+    }
+
+    // The timer is incremented each reference clock cycle by a number of nanoseconds with a
+    // 16-bit fraction, so the frequency is adjusted by scaling that increment
+    fn set_ptp_frequency(&mut self, ppb: i32) {
+        let nominal = i64::from(TSU_INCREMENT_NS) << 16;
+        let increment = (nominal + nominal * i64::from(ppb) / 1_000_000_000) as u32;
+        self.eth
+            .tsutimerincrsubnsec
+            .write(|reg| unsafe { reg.bits(increment & 0xFFFF) });
+        self.eth
+            .tsutimerincr
+            .write(|reg| unsafe { reg.bits(increment >> 16) });
+    }
+
+    fn ptp_tx_time(&self) -> crate::ptp::Timestamp {
+        crate::ptp::Timestamp {
+            seconds: u64::from(self.eth.tsuptptxsec.read().bits()),
+            nanoseconds: self.eth.tsuptptxnsec.read().bits(),
+        }
+    }
+
+    // The statistics registers clear when they're read, so they're accumulated here
+    fn statistics(&mut self) -> Statistics {
+        let eth = &self.eth;
+        let stats = &mut self.statistics;
+
+        macro_rules! read {
+            ($($reg:ident),+) => {
+                0 $(+ eth.$reg.read().bits())+
+            };
+        }
+
+        let octets = |bottom: u32, top: u32| u64::from(top & 0xFFFF) << 32 | u64::from(bottom);
+
+        stats.rx_octets += octets(read!(octetsrxedbottom), read!(octetsrxedtop));
+        stats.rx_frames = stats.rx_frames.wrapping_add(read!(framesrxedok));
+        stats.rx_broadcast = stats.rx_broadcast.wrapping_add(read!(broadcastrxed));
+        stats.rx_multicast = stats.rx_multicast.wrapping_add(read!(multicastrxed));
+        stats.rx_errors = stats.rx_errors.wrapping_add(read!(
+            undersizeframes,
+            excessiverxlen,
+            rxjabbers,
+            fcserrs,
+            rxlenerrs,
+            rxsymbolerrs,
+            alignerrs,
+            rxresourceerrs,
+            rxoverruns
+        ));
+
+        stats.tx_octets += octets(read!(octetstxedbottom), read!(octetstxedtop));
+        stats.tx_frames = stats.tx_frames.wrapping_add(read!(framestxedok));
+        stats.tx_broadcast = stats.tx_broadcast.wrapping_add(read!(broadcasttxed));
+        stats.tx_multicast = stats.tx_multicast.wrapping_add(read!(multicasttxed));
+        stats.tx_errors =
+            stats
+                .tx_errors
+                .wrapping_add(read!(txunderruns, excesscols, latecols, crserrs));
+
+        *stats
+    }
+
     fn find_rx_window(&self) -> Option<(usize, usize)> {
         let mut start = None;
         let mut end = None;
@@ -345,90 +757,91 @@ impl<'a> Mac<'a> {
         }
     }
 
-    fn find_tx_window(&mut self) -> Option<(usize, usize)> {
-        let queue_ptr = (unsafe { (*ETH::ptr()).txqptr.read().dmatxqptr().bits() << 2 }
-            - self.tx_buffer.address() as u32) as usize
-            / core::mem::size_of::<TxBufferDescriptor>();
-        let descriptors = self.tx_buffer.descriptors_mut();
+    // An overrun can leave descriptors holding the start of a frame that will never be finished,
+    // which the hardware won't get back. Everything that has been received but not yet handled is
+    // dropped, and receiving starts over from the beginning of the ring.
+    fn recover_rx(&mut self) {
+        self.eth
+            .networkctrl
+            .modify(|_, reg| reg.enbrx().clear_bit());
+
+        self.rx_buffer
+            .descriptors_mut()
+            .iter_mut()
+            .filter(|d| d.ownership() == BufferDescriptorOwnership::Software)
+            .for_each(|d| d.release());
+
+        // The queue pointer can only be changed while the receiver is disabled
+        let queue = self.rx_buffer.address() as u32 >> 2;
+        self.eth
+            .rxqptr
+            .write(|reg| unsafe { reg.dmarxqptr().bits(queue) });
+        self.eth.rxstatus.write(|reg| {
+            reg.buffnotavail().set_bit();
+            reg.rxoverrun().set_bit();
+            reg
+        });
 
-        // Walk forward from the queue pointer (wrapping around to the beginning of the buffer if
-        // necessary), looking for the first unused descriptor. This will be the start of the
-        // transmit window.
-        let start = {
-            let mut start = None;
-            for i in 0..descriptors.len() {
-                let d = (queue_ptr + i) % descriptors.len();
-                if descriptors[d].ownership() == BufferDescriptorOwnership::Software {
-                    start = Some(d);
-                    break;
-                }
-            }
+        self.eth.networkctrl.modify(|_, reg| reg.enbrx().set_bit());
+        RX_OVERRUNS.fetch_add(1, Ordering::Relaxed);
+    }
 
-            start?
+    // The transmit window runs from the end of the queued frames to the start of the oldest one
+    // that hasn't been reclaimed (see reclaim_tx). Unless nothing is queued, normal frames have to
+    // leave a few descriptors free, so that high-priority frames (which are small) never have to
+    // wait for the ring to drain.
+    fn find_tx_window(&self, priority: TxPriority) -> Option<(usize, usize)> {
+        let len = self.tx_buffer.descriptors().len();
+        let free = len - self.tx_pending;
+        let usable = match (priority, self.tx_pending) {
+            (TxPriority::Normal, pending) if pending > 0 => free.saturating_sub(TX_RESERVED),
+            _ => free,
         };
 
-        // Reclaim the descriptors of the previously-used transmit window. Unfortunately, the
-        // hardware only clears the ownership flag on the first descriptor for a frame.
-        let mut end_of_buffer = true;
-        let len = descriptors.len();
-        for i in (1..len).map(|i| (len + queue_ptr - i) % len) {
-            let d = &mut descriptors[i];
-            match d.ownership() {
-                BufferDescriptorOwnership::Hardware => {
-                    if end_of_buffer && !d.end_of_frame() {
-                        log::warn!("Dangling TX desc {}: {:?}", i, d);
-                    } else if !end_of_buffer && d.end_of_frame() {
-                        log::warn!("Released TX frame {} (unsent?): {:?}", i, d);
-                    }
-                    end_of_buffer = false;
-                    d.claim();
-                }
-                BufferDescriptorOwnership::Software => {
-                    fn error_str(cond: bool, msg: &str) -> &str {
-                        match cond {
-                            false => "",
-                            true => msg,
-                        }
-                    }
-
-                    log::trace!(
-                        "  {:>2} (Done) - {:?} (errors:{}{}{}{}{})",
-                        i,
-                        d,
-                        error_str(d.error_retry_limit(), " 'retry limit exceeded'"),
-                        error_str(d.error_tx_underrun(), " underrun"),
-                        error_str(d.error_frame_corrupt(), " 'frame corruption'"),
-                        error_str(d.error_late_collision(), " 'late collision'"),
-                        match d.error_checksum_generation() {
-                            Some(ref err) => err.as_str(),
-                            None => "",
-                        }
-                    );
-
-                    if end_of_buffer {
-                        log::trace!("    (may be duplicate)");
-                        break;
-                    }
-                    end_of_buffer = true;
-                }
-            }
+        match usable {
+            0 => None,
+            usable => Some(((self.tx_tail + self.tx_pending) % len, usable)),
         }
+    }
 
-        // Walk forward from the start of the transmit window (wrapping around to the beginning of
-        // the buffer if necessary), looking for the last unused descriptor. This will be the end
-        // of the transmit window.
-        let mut len = 1;
-        for i in 1..descriptors.len() {
-            if descriptors[(start + i) % descriptors.len()].ownership()
-                == BufferDescriptorOwnership::Software
-            {
-                len = i;
-            } else {
+    // Once a frame has been sent, the hardware marks only its first descriptor as used (recording
+    // any errors there), so the rest of the frame's descriptors are claimed here
+    fn reclaim_tx(&mut self) {
+        let descriptors = self.tx_buffer.descriptors_mut();
+        let len = descriptors.len();
+
+        while self.tx_pending > 0 {
+            let first = &descriptors[self.tx_tail];
+            if first.ownership() == BufferDescriptorOwnership::Hardware {
                 break;
             }
+            self.tx_errors.record(self.tx_tail, first);
+
+            let mut count = 0;
+            while count < self.tx_pending {
+                let d = &mut descriptors[(self.tx_tail + count) % len];
+                d.claim();
+                count += 1;
+                if d.end_of_frame() {
+                    break;
+                }
+            }
+
+            self.tx_tail = (self.tx_tail + count) % len;
+            self.tx_pending -= count;
         }
+    }
+
+    // After an error, the hardware abandons the frame it was sending and carries on from its queue
+    // pointer, so every queued descriptor is reclaimed and the next frame is queued from there
+    fn reset_tx(&mut self) {
+        let queue_ptr = (self.eth.txqptr.read().dmatxqptr().bits() << 2) as usize
+            - self.tx_buffer.address() as usize;
+        let descriptors = self.tx_buffer.descriptors_mut();
 
-        Some((start, len))
+        descriptors.iter_mut().for_each(|d| d.claim());
+        self.tx_tail = queue_ptr / core::mem::size_of::<TxBufferDescriptor>() % descriptors.len();
+        self.tx_pending = 0;
     }
 
     pub fn irq(&mut self) {
@@ -458,21 +871,69 @@ impl<'a> Mac<'a> {
         }
         if int.rxcmplt().bit_is_set() {
             self.eth.ifcr.write(|reg| reg.rxcmplt().set_bit());
+            crate::work::post(crate::work::Event::RxComplete);
         }
         if int.rxoverrun().bit_is_set() {
             self.eth.ifcr.write(|reg| reg.rxoverrun().set_bit());
             log::error!("RX Overrun Interrupt");
+            self.recover_rx();
         }
         if int.txcmplt().bit_is_set() {
             self.eth.ifcr.write(|reg| reg.txcmplt().set_bit());
+            self.reclaim_tx();
+            crate::work::post(crate::work::Event::TxComplete);
+        }
+        if int.rtrylmtorlatecol().bit_is_set() {
+            self.eth.ifcr.write(|reg| reg.rtrylmtorlatecol().set_bit());
+            self.reclaim_tx();
+            self.reset_tx();
         }
         if int.txunderrun().bit_is_set() {
             self.eth.ifcr.write(|reg| reg.txunderrun().set_bit());
             log::error!("TX Underrun Interrupt");
+            self.reclaim_tx();
+            self.reset_tx();
         }
         if int.ambaerr().bit_is_set() {
             self.eth.ifcr.write(|reg| reg.ambaerr().set_bit());
             log::error!("TX AMBA Error Interrupt");
+            self.reclaim_tx();
+            self.reset_tx();
+        }
+        if int.rxlpiindc().bit_is_set() {
+            self.eth.ifcr.write(|reg| reg.rxlpiindc().set_bit());
+            let status = self.eth.networkstatus.read().bits();
+            crate::eee::lpi_changed(status & NETWORKSTATUS_LPI != 0);
+        }
+        if int.wolevntrx().bit_is_set() {
+            self.eth.ifcr.write(|reg| reg.wolevntrx().set_bit());
+            log::info!("Received Wake-on-LAN magic packet");
+        }
+
+        // The timestamps of PTP event messages are read as the messages are handled, so these
+        // only need to be cleared
+        if int.ptpdlyreqfrmrx().bit_is_set()
+            || int.ptpsyncfrmrx().bit_is_set()
+            || int.ptpdlyreqfrmtx().bit_is_set()
+            || int.ptpsyncfrmtx().bit_is_set()
+            || int.ptppdlyreqfrmrx().bit_is_set()
+            || int.ptppdlyrespfrmrx().bit_is_set()
+            || int.ptppdlyreqfrmtx().bit_is_set()
+            || int.ptppdlyrespfrmtx().bit_is_set()
+            || int.tsusecregincr().bit_is_set()
+        {
+            self.eth.ifcr.write(|reg| {
+                reg.ptpdlyreqfrmrx().set_bit();
+                reg.ptpsyncfrmrx().set_bit();
+                reg.ptpdlyreqfrmtx().set_bit();
+                reg.ptpsyncfrmtx().set_bit();
+                reg.ptppdlyreqfrmrx().set_bit();
+                reg.ptppdlyrespfrmrx().set_bit();
+                reg.ptppdlyreqfrmtx().set_bit();
+                reg.ptppdlyrespfrmtx().set_bit();
+                reg.tsusecregincr().set_bit();
+                reg
+            });
         }
 
         // XXX: Read from ifcr seems to be racy. I'm guessing its because that register can change
@@ -489,17 +950,73 @@ impl<'a> Mac<'a> {
     }
 }
 
-impl mac::Mdio for Mac<'_> {
+impl Mdio for Mac<'_> {
     fn read(&self, address: u8, register: Register) -> u16 {
         mdio_read(&self.eth, address, register)
     }
 
     fn write(&mut self, address: u8, register: Register, data: u16) {
-        mdio_write(&mut self.eth, address, register, data)
+        mdio_write(&self.eth, address, register, data)
     }
 }
 
-fn mdio_read(eth: &ETH, address: u8, register: Register) -> u16 {
+// Enables detection of magic packets in the ETH_WOL register
+const WOL_MAGICPKTEN: u32 = 1 << 16;
+
+// The number of TX descriptors that only high-priority frames can use, enough for an LLDPDU or a
+// (tagged) PTP message
+const TX_RESERVED: usize = 1;
+
+// Set while the link partner is signaling low-power idle
+const NETWORKSTATUS_LPI: u32 = 1 << 7;
+
+const CTRL_GBLCLKEN: u32 = 1 << 0;
+const CTRL_TSUCLKSEL_REFCLK: u32 = 2 << 4;
+
+// The RMII reference clock runs at 50 MHz
+const TSU_INCREMENT_NS: u32 = 20;
+const TSU_ADJUST_SUBTRACT: u32 = 1 << 31;
+const TSU_ADJUST_MAX: u32 = 1 << 30;
+
+// The number of times to poll for the completion of a raw transmission before giving up
+const RAW_TX_TIMEOUT: u32 = 1_000_000;
+
+static mut RAW_TX_REGION: TxRegion = TxRegion([0; 1536]);
+static mut RAW_TX_DESCRIPTOR: MaybeUninit<TxBufferDescriptor> = MaybeUninit::uninit();
+
+/// Transmits a single frame using a dedicated descriptor, bypassing the driver and the network
+/// stack, and waits (briefly) for it to be sent. This is meant for use while handling a fault,
+/// when the state of the driver can't be trusted. Returns false if the transmitter isn't enabled
+/// or the frame wasn't sent in time.
+///
+/// # Safety
+///
+/// This takes over the MAC's transmit queue, so the driver must not be used afterward.
+pub unsafe fn transmit_raw(frame: &[u8]) -> bool {
+    let eth = &*ETH::ptr();
+    if eth.networkctrl.read().enbtx().bit_is_clear() || frame.len() > RAW_TX_REGION.0.len() {
+        return false;
+    }
+
+    RAW_TX_REGION.0[..frame.len()].copy_from_slice(frame);
+    let descriptor = RAW_TX_DESCRIPTOR
+        .write(TxBufferDescriptor::new(&mut RAW_TX_REGION.0[..frame.len()]).end_of_list());
+    descriptor.set_length(frame.len());
+    descriptor.set_last_buffer(true);
+    descriptor.release();
+
+    // The queue pointer can only be changed while the transmitter is disabled
+    eth.networkctrl.modify(|_, reg| reg.enbtx().clear_bit());
+    eth.txqptr
+        .write(|reg| reg.dmatxqptr().bits(descriptor as *const _ as u32 >> 2));
+    eth.txstatus.write(|reg| reg.txcmplt().set_bit());
+    eth.networkctrl.modify(|_, reg| reg.enbtx().set_bit());
+    eth.networkctrl.modify(|_, reg| reg.txstrt().set_bit());
+
+    (0..RAW_TX_TIMEOUT).any(|_| eth.txstatus.read().txcmplt().bit_is_set())
+}
+
+fn mdio_read(eth: &eth::RegisterBlock, address: u8, register: Register) -> u16 {
     eth.phymngmnt.write(|reg| {
         unsafe { reg.phyaddr().bits(address) };
         unsafe { reg.phyrwdata().bits(0x00) };
@@ -517,7 +1034,7 @@ fn mdio_read(eth: &ETH, address: u8, register: Register) -> u16 {
     eth.phymngmnt.read().phyrwdata().bits()
 }
 
-fn mdio_write(eth: &mut ETH, address: u8, register: Register, data: u16) {
+fn mdio_write(eth: &eth::RegisterBlock, address: u8, register: Register, data: u16) {
     eth.phymngmnt.write(|reg| {
         unsafe { reg.phyaddr().bits(address) };
         unsafe { reg.phyrwdata().bits(data) };
@@ -533,126 +1050,150 @@ fn mdio_write(eth: &mut ETH, address: u8, register: Register, data: u16) {
     while eth.networkstatus.read().mandone().bit_is_clear() {}
 }
 
-impl<'a, P: Phy> phy::Device<'a> for EFM32GG<'_, P> {
-    type RxToken = RxToken<'a>;
-    type TxToken = TxToken<'a>;
+impl<P: Phy> phy::Device for EFM32GG<'_, P> {
+    type RxToken<'a>
+        = RxToken<'a>
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = TxToken<'a>
+    where
+        Self: 'a;
 
     fn capabilities(&self) -> phy::DeviceCapabilities {
         let mut caps = phy::DeviceCapabilities::default();
-        caps.max_transmission_unit = 1536;
+        // Leave room for a VLAN tag (see vlan)
+        caps.max_transmission_unit = MAX_FRAME_LEN - crate::vlan::TAG_LEN;
         caps
     }
 
-    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
-        let (rx_start, rx_end) = self.mac.find_rx_window()?;
-        let (tx_start, tx_length) = self.mac.find_tx_window()?;
+    fn receive(&mut self, timestamp: time::Instant) -> Option<(RxToken<'_>, TxToken<'_>)> {
+        let tx_window = self.mac.find_tx_window(TxPriority::Normal)?;
+
+        // smoltcp is reminded of the static neighbors before any frames are handed over. Otherwise,
+        // the frames that the firmware handles itself (or drops) are passed over.
+        let frame = match crate::neighbors::take_refresh(crate::time::now()) {
+            Some(refresh) => RxFrame::injected(&refresh),
+            None => loop {
+                let mut frame = self.next_frame()?;
+                let descriptors = self.mac.rx_buffer.descriptors_mut();
+                match dispatch(timestamp, frame.data(descriptors)) {
+                    Some(len) => break frame.truncated(len),
+                    None => frame.release(descriptors),
+                }
+            },
+        };
 
         Some((
-            RxToken {
-                descriptors: self.mac.rx_buffer.descriptors_mut(),
-                start: rx_start,
-                end: rx_end,
-            },
-            TxToken {
-                descriptors: self.mac.tx_buffer.descriptors_mut(),
-                start: tx_start,
-                length: tx_length,
-            },
+            RxToken::new(self.mac.rx_buffer.descriptors_mut(), frame),
+            TxToken::new(
+                self.mac.tx_buffer.descriptors_mut(),
+                &mut self.mac.tx_pending,
+                TxPriority::Normal,
+                tx_window,
+                timestamp,
+                Hooks::new(),
+            ),
         ))
     }
 
-    fn transmit(&'a mut self) -> Option<Self::TxToken> {
-        let (start, length) = self.mac.find_tx_window()?;
-
-        Some(TxToken {
-            descriptors: self.mac.tx_buffer.descriptors_mut(),
-            start,
-            length,
-        })
+    fn transmit(&mut self, timestamp: time::Instant) -> Option<TxToken<'_>> {
+        self.transmit_with(TxPriority::Normal, timestamp)
     }
 }
 
-pub struct RxToken<'a> {
-    /// The list of allocated RX buffer descriptors.
-    descriptors: &'a mut [RxBufferDescriptor],
-
-    /// The index of the starting RX buffer descriptor.
-    start: usize,
+pub type RxToken<'a> = efm32gg_eth::phy::RxToken<'a>;
+pub type TxToken<'a> = efm32gg_eth::phy::TxToken<'a, Hooks>;
 
-    /// The length of the token, in RX buffers.
-    end: usize,
-}
+// Hands a received frame to whichever part of the firmware handles it, which is usually smoltcp.
+// Returns the length of the frame that's left for smoltcp, or None if it was handled (or dropped)
+// here.
+fn dispatch(timestamp: time::Instant, data: &mut [u8]) -> Option<usize> {
+    crate::capture::frame(timestamp, data);
+    traffic::received(data.len());
 
-impl<'a> phy::RxToken for RxToken<'a> {
-    fn consume<R, F>(self, _timestamp: time::Instant, f: F) -> smoltcp::Result<R>
-    where
-        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
-    {
-        let mut data = [0; 1536];
+    // Frames too short for an Ethernet header (or, on the management VLAN, a tag) are dropped
+    let len = crate::vlan::untag(data)?;
+    let data = &mut data[..len];
 
-        let mut orig = self.start;
-        let mut dest = 0;
-
-        loop {
-            let d = &mut self.descriptors[orig];
-            data[(dest * 128)..][..128].copy_from_slice(d.as_slice());
-            d.release();
+    // smoltcp doesn't understand LLDP, so those frames are handled here instead
+    if data[12..14] == crate::lldp::ETHERTYPE.to_be_bytes() {
+        crate::lldp::receive(timestamp, data);
+        return None;
+    }
 
-            if orig == self.end {
-                break;
-            }
+    // Nor PTP; the MAC latched the time at which the last event message was received
+    if data[12..14] == crate::ptp::ETHERTYPE.to_be_bytes() {
+        let eth = unsafe { &*ETH::ptr() };
+        let rx_time = crate::ptp::Timestamp {
+            seconds: u64::from(eth.tsuptprxsec.read().bits()),
+            nanoseconds: eth.tsuptprxnsec.read().bits(),
+        };
+        crate::ptp::receive(timestamp, data, rx_time);
+        return None;
+    }
 
-            orig = (orig + 1) % self.descriptors.len();
-            dest += 1;
-        }
+    // smoltcp can't be told to ignore echo requests, so any over the limit are dropped here
+    if !crate::icmp::receive(timestamp, data) {
+        return None;
+    }
 
-        f(&mut data)
+    // Nor limited to a rate of new connections
+    if !crate::ratelimit::receive(timestamp, data) {
+        return None;
     }
-}
 
-pub struct TxToken<'a> {
-    /// The list of allocated TX buffer descriptors.
-    descriptors: &'a mut [TxBufferDescriptor],
+    crate::dhcp::receive(timestamp, data);
+    crate::neighbors::receive(timestamp, data);
 
-    /// The index of the starting TX buffer descriptor.
-    start: usize,
+    Some(len)
+}
 
-    /// The length of the token, in TX buffers.
-    length: usize,
+// The firmware's part in sending each frame
+pub struct Hooks {
+    /// The VLAN on which the frame is sent, if it's tagged.
+    vlan: Option<u16>,
 }
 
-impl<'a> phy::TxToken for TxToken<'a> {
-    fn consume<R, F>(self, _timestamp: time::Instant, len: usize, f: F) -> smoltcp::Result<R>
-    where
-        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
-    {
-        if len > (self.length * 128) {
-            log::warn!("TX exhausted: buffer={} token={}", len, self.length * 128);
-            return Err(Error::Exhausted);
+impl Hooks {
+    fn new() -> Hooks {
+        Hooks {
+            vlan: crate::vlan::id(),
         }
+    }
+}
 
-        debug_assert!(len > 0);
-        let last_buffer = (len - 1) / 128;
+impl efm32gg_eth::phy::Hooks for Hooks {
+    fn headroom(&self) -> usize {
+        self.vlan.map_or(0, |_| crate::vlan::TAG_LEN)
+    }
 
-        let mut data = [0; 1536];
-        let result = f(&mut data[0..len])?;
+    // Room for dhcp::transmit to grow the frame
+    fn tailroom(&self) -> usize {
+        crate::dhcp::REQUEST_LEN
+    }
 
-        for i in 0..=last_buffer {
-            let d = &mut self.descriptors[(self.start + i) % self.descriptors.len()];
-            let buffer_len = cmp::min(128, len - i * 128);
+    // The frame was built with room for a VLAN tag in front of it, which is then made by moving
+    // the addresses forward (see vlan::tag)
+    fn finish(self, timestamp: time::Instant, buffer: &mut [u8], len: usize) -> usize {
+        let headroom = self.headroom();
+        crate::icmp::transmit(&buffer[headroom..][..len]);
+        let len = crate::dhcp::transmit(timestamp, &mut buffer[headroom..], len);
 
-            d.as_slice_mut().copy_from_slice(&data[(i * 128)..][..128]);
-            d.set_length(buffer_len);
-            d.set_last_buffer(i == last_buffer);
-            d.release();
-        }
+        let len = match self.vlan {
+            Some(id) => crate::vlan::tag(buffer, len, id),
+            None => len,
+        };
+        crate::capture::frame(timestamp, &buffer[..len]);
+        traffic::transmitted(len);
+        len
+    }
 
+    fn start_transmit() {
         unsafe {
             (*efm32gg11b820::ETH::ptr())
                 .networkctrl
                 .modify(|_, reg| reg.txstrt().set_bit());
         }
-
-        Ok(result)
     }
 }
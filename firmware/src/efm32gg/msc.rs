@@ -0,0 +1,115 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Flash programming through the Memory System Controller. The flash is split into two banks, and
+// the core can keep fetching instructions from one while the other is being erased or written.
+// The firmware lives in the first bank, so only the second can be programmed (without running
+// from RAM).
+
+use efm32gg11b820::{msc, MSC};
+
+/// The size of an erasable page, in bytes.
+pub const PAGE_SIZE: u32 = 4096;
+
+const BANK_START: u32 = 0x0010_0000;
+const BANK_END: u32 = 0x0020_0000;
+
+const LOCK_KEY: u32 = 0x1B71;
+
+const WRITECTRL_WREN: u32 = 1 << 0;
+const WRITECMD_LADDRIM: u32 = 1 << 0;
+const WRITECMD_ERASEPAGE: u32 = 1 << 1;
+const WRITECMD_WRITEONCE: u32 = 1 << 3;
+const STATUS_BUSY: u32 = 1 << 0;
+const STATUS_LOCKED: u32 = 1 << 1;
+const STATUS_INVADDR: u32 = 1 << 2;
+const STATUS_WDATAREADY: u32 = 1 << 3;
+
+/// Erases the page starting at the given address, setting every bit. This blocks for the duration
+/// of the erase (tens of milliseconds).
+pub fn erase_page(address: u32) -> Result<(), &'static str> {
+    if address % PAGE_SIZE != 0 {
+        return Err("address isn't page-aligned");
+    }
+    if !(BANK_START..BANK_END).contains(&address) {
+        return Err("address isn't in the second bank");
+    }
+
+    program(|msc| {
+        load_address(msc, address)?;
+        msc.writecmd
+            .write(|reg| unsafe { reg.bits(WRITECMD_ERASEPAGE) });
+        wait_idle(msc);
+        Ok(())
+    })
+}
+
+/// Writes the words to flash, starting at the given address. Writing can only clear bits, so the
+/// words need to have been erased first.
+pub fn write(address: u32, words: &[u32]) -> Result<(), &'static str> {
+    let end = address + 4 * words.len() as u32;
+    if address % 4 != 0 {
+        return Err("address isn't word-aligned");
+    }
+    if address < BANK_START || end > BANK_END {
+        return Err("address isn't in the second bank");
+    }
+
+    program(|msc| {
+        for (i, word) in words.iter().enumerate() {
+            load_address(msc, address + 4 * i as u32)?;
+            while msc.status.read().bits() & STATUS_WDATAREADY == 0 {}
+            msc.wdata.write(|reg| unsafe { reg.bits(*word) });
+            msc.writecmd
+                .write(|reg| unsafe { reg.bits(WRITECMD_WRITEONCE) });
+            wait_idle(msc);
+        }
+        Ok(())
+    })
+}
+
+// Unlocks the controller and enables writes for the duration of `f`
+fn program<F>(f: F) -> Result<(), &'static str>
+where
+    F: FnOnce(&msc::RegisterBlock) -> Result<(), &'static str>,
+{
+    let msc = unsafe { &*MSC::ptr() };
+    msc.lock.write(|reg| unsafe { reg.bits(LOCK_KEY) });
+    msc.writectrl
+        .write(|reg| unsafe { reg.bits(WRITECTRL_WREN) });
+
+    let result = f(msc);
+
+    msc.writectrl.write(|reg| unsafe { reg.bits(0) });
+    msc.lock.write(|reg| unsafe { reg.bits(0) });
+    result
+}
+
+fn load_address(msc: &msc::RegisterBlock, address: u32) -> Result<(), &'static str> {
+    msc.addrb.write(|reg| unsafe { reg.bits(address) });
+    msc.writecmd
+        .write(|reg| unsafe { reg.bits(WRITECMD_LADDRIM) });
+
+    let status = msc.status.read().bits();
+    match (status & STATUS_LOCKED != 0, status & STATUS_INVADDR != 0) {
+        (true, _) => Err("flash is locked"),
+        (false, true) => Err("invalid flash address"),
+        (false, false) => Ok(()),
+    }
+}
+
+fn wait_idle(msc: &msc::RegisterBlock) {
+    while msc.status.read().bits() & STATUS_BUSY != 0 {}
+}
@@ -0,0 +1,290 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Decoded views of the peripheral registers that answer most bring-up questions (e.g. "is the
+// clock on?") without a debugger, for the terminal's "periph" command. Each register is written
+// as a line with its name, its value, and its set flags and fields, named as they are in the SVD.
+//
+// The ethernet controller's registers are only read once its bus clock is on, since they can't be
+// read without it.
+
+use core::fmt;
+use core::str::FromStr;
+use efm32gg11b820::{CMU, ETH, GPIO};
+
+const GPIO_MODES: [&str; 16] = [
+    "DISABLED",
+    "INPUT",
+    "INPUTPULL",
+    "INPUTPULLFILTER",
+    "PUSHPULL",
+    "PUSHPULLALT",
+    "WIREDOR",
+    "WIREDORPULLDOWN",
+    "WIREDAND",
+    "WIREDANDFILTER",
+    "WIREDANDPULLUP",
+    "WIREDANDPULLUPFILTER",
+    "WIREDANDALT",
+    "WIREDANDALTFILTER",
+    "WIREDANDALTPULLUP",
+    "WIREDANDALTPULLUPFILTER",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Peripheral {
+    Cmu,
+    Eth,
+    Gpio(Port),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Port {
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+    G,
+    H,
+    I,
+    J,
+    K,
+    L,
+}
+
+impl FromStr for Port {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Port, Self::Err> {
+        Ok(match s {
+            "a" | "A" => Port::A,
+            "b" | "B" => Port::B,
+            "c" | "C" => Port::C,
+            "d" | "D" => Port::D,
+            "e" | "E" => Port::E,
+            "f" | "F" => Port::F,
+            "g" | "G" => Port::G,
+            "h" | "H" => Port::H,
+            "i" | "I" => Port::I,
+            "j" | "J" => Port::J,
+            "k" | "K" => Port::K,
+            "l" | "L" => Port::L,
+            _ => return Err("unknown GPIO port"),
+        })
+    }
+}
+
+// The names of the flags that are set, in upper case to match the SVD
+struct Flags<'a> {
+    flags: &'a [(&'static str, bool)],
+}
+
+impl fmt::Display for Flags<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut first = true;
+        for (name, _) in self.flags.iter().filter(|(_, set)| *set) {
+            if !first {
+                f.write_str(" ")?;
+            }
+            first = false;
+            name.chars()
+                .try_for_each(|c| fmt::Write::write_char(f, c.to_ascii_uppercase()))?;
+        }
+        Ok(())
+    }
+}
+
+// Collects the flags read through the PAC, which are named after the SVD's fields
+macro_rules! fields {
+    ($reg:expr; $($field:ident),+ $(,)?) => {
+        Flags {
+            flags: &[$((stringify!($field), $reg.$field().bit_is_set())),+],
+        }
+    };
+}
+
+// Collects flags by their bit positions, as is done for these registers elsewhere (e.g. loopback)
+macro_rules! bits {
+    ($value:expr; $($bit:literal => $name:literal),+ $(,)?) => {
+        Flags {
+            flags: &[$(($name, $value & (1 << $bit) != 0)),+],
+        }
+    };
+}
+
+/// Writes the peripheral's registers, calling `line` with each line of output.
+pub fn dump(peripheral: Peripheral, line: &mut dyn FnMut(fmt::Arguments)) {
+    match peripheral {
+        Peripheral::Cmu => cmu(line),
+        Peripheral::Eth => eth(line),
+        Peripheral::Gpio(port) => gpio(port, line),
+    }
+}
+
+fn cmu(line: &mut dyn FnMut(fmt::Arguments)) {
+    let cmu = unsafe { &*CMU::ptr() };
+
+    let status = cmu.status.read();
+    let flags = fields!(status;
+        hfrcoens, hfrcordy, auxhfrcoens, auxhfrcordy, lfrcoens, lfrcordy, lfxoens, lfxordy,
+        hfxoens, hfxordy, hfxopeakdetrdy, ushfrcoens, ushfrcordy,
+    );
+    line(format_args!(
+        "{:<14} 0x{:08X} {flags}",
+        "STATUS",
+        status.bits()
+    ));
+
+    let hfclkstatus = cmu.hfclkstatus.read();
+    let selected = match hfclkstatus.selected().bits() {
+        1 => "HFRCO",
+        2 => "HFXO",
+        3 => "LFRCO",
+        4 => "LFXO",
+        5 => "HFRCODIV2",
+        6 => "USHFRCODIV2",
+        7 => "CLKIN0",
+        _ => "none",
+    };
+    line(format_args!(
+        "{:<14} 0x{:08X} SELECTED={selected}",
+        "HFCLKSTATUS",
+        hfclkstatus.bits()
+    ));
+
+    let ctrl = cmu.ctrl.read();
+    let flags = fields!(ctrl; hfperclken);
+    line(format_args!("{:<14} 0x{:08X} {flags}", "CTRL", ctrl.bits()));
+
+    let hfbusclken0 = cmu.hfbusclken0.read();
+    let flags = fields!(hfbusclken0;
+        le, crypto0, ldma, qspi0, gpcrc, gpio, prs, ebi, eth, sdio, usb,
+    );
+    line(format_args!(
+        "{:<14} 0x{:08X} {flags}",
+        "HFBUSCLKEN0",
+        hfbusclken0.bits()
+    ));
+
+    let hfperclken0 = cmu.hfperclken0.read();
+    let flags = fields!(hfperclken0;
+        timer0, timer1, timer2, timer3, usart0, usart1, usart2, usart3, usart4, usart5, i2c0,
+        i2c1, adc0, trng0,
+    );
+    line(format_args!(
+        "{:<14} 0x{:08X} {flags}",
+        "HFPERCLKEN0",
+        hfperclken0.bits()
+    ));
+
+    let hfperclken1 = cmu.hfperclken1.read();
+    let flags = fields!(hfperclken1; wtimer0, wtimer1, uart0, uart1);
+    line(format_args!(
+        "{:<14} 0x{:08X} {flags}",
+        "HFPERCLKEN1",
+        hfperclken1.bits()
+    ));
+}
+
+fn eth(line: &mut dyn FnMut(fmt::Arguments)) {
+    let cmu = unsafe { &*CMU::ptr() };
+    if cmu.hfbusclken0.read().eth().bit_is_clear() {
+        return line(format_args!("The ETH bus clock is off"));
+    }
+
+    let eth = unsafe { &*ETH::ptr() };
+
+    let networkctrl = eth.networkctrl.read().bits();
+    let flags = bits!(networkctrl;
+        1 => "LOOPBACKLOCAL", 2 => "ENBRX", 3 => "ENBTX", 4 => "MANPORTEN",
+    );
+    line(format_args!(
+        "{:<14} 0x{networkctrl:08X} {flags}",
+        "NETWORKCTRL"
+    ));
+
+    let networkcfg = eth.networkcfg.read();
+    let flags = fields!(networkcfg; speed, fullduplex, rx1536byteframes, rxchksumoffloaden);
+    line(format_args!(
+        "{:<14} 0x{:08X} {flags} MDCCLKDIV={}",
+        "NETWORKCFG",
+        networkcfg.bits(),
+        networkcfg.mdcclkdiv().bits()
+    ));
+
+    let networkstatus = eth.networkstatus.read().bits();
+    let flags = bits!(networkstatus; 1 => "MDIOIN", 2 => "MANDONE", 7 => "LPI");
+    line(format_args!(
+        "{:<14} 0x{networkstatus:08X} {flags}",
+        "NETWORKSTATUS"
+    ));
+
+    let txstatus = eth.txstatus.read().bits();
+    let flags = bits!(txstatus;
+        0 => "USEDBITREAD", 1 => "COLLOCCRD", 2 => "RETRYLMTEXCD", 3 => "TXGO",
+        4 => "AMBAERROR", 5 => "TXCMPLT", 6 => "TXUNDERRUN", 7 => "LATECOLLOCCRD",
+        8 => "RESPNOTOK",
+    );
+    line(format_args!("{:<14} 0x{txstatus:08X} {flags}", "TXSTATUS"));
+
+    let rxstatus = eth.rxstatus.read().bits();
+    let flags = bits!(rxstatus;
+        0 => "BUFFNOTAVAIL", 1 => "FRAMERX", 2 => "RXOVERRUN", 3 => "RESPNOTOK",
+    );
+    line(format_args!("{:<14} 0x{rxstatus:08X} {flags}", "RXSTATUS"));
+}
+
+fn gpio(port: Port, line: &mut dyn FnMut(fmt::Arguments)) {
+    let gpio = unsafe { &*GPIO::ptr() };
+
+    macro_rules! read {
+        ($ctrl:ident, $model:ident, $modeh:ident, $dout:ident, $din:ident) => {
+            (
+                gpio.$ctrl.read().bits(),
+                u64::from(gpio.$modeh.read().bits()) << 32 | u64::from(gpio.$model.read().bits()),
+                gpio.$dout.read().bits(),
+                gpio.$din.read().bits(),
+            )
+        };
+    }
+
+    let (ctrl, modes, dout, din) = match port {
+        Port::A => read!(pa_ctrl, pa_model, pa_modeh, pa_dout, pa_din),
+        Port::B => read!(pb_ctrl, pb_model, pb_modeh, pb_dout, pb_din),
+        Port::C => read!(pc_ctrl, pc_model, pc_modeh, pc_dout, pc_din),
+        Port::D => read!(pd_ctrl, pd_model, pd_modeh, pd_dout, pd_din),
+        Port::E => read!(pe_ctrl, pe_model, pe_modeh, pe_dout, pe_din),
+        Port::F => read!(pf_ctrl, pf_model, pf_modeh, pf_dout, pf_din),
+        Port::G => read!(pg_ctrl, pg_model, pg_modeh, pg_dout, pg_din),
+        Port::H => read!(ph_ctrl, ph_model, ph_modeh, ph_dout, ph_din),
+        Port::I => read!(pi_ctrl, pi_model, pi_modeh, pi_dout, pi_din),
+        Port::J => read!(pj_ctrl, pj_model, pj_modeh, pj_dout, pj_din),
+        Port::K => read!(pk_ctrl, pk_model, pk_modeh, pk_dout, pk_din),
+        Port::L => read!(pl_ctrl, pl_model, pl_modeh, pl_dout, pl_din),
+    };
+
+    line(format_args!("{:<14} 0x{ctrl:08X}", "CTRL"));
+    line(format_args!("{:<14} 0x{dout:08X}", "DOUT"));
+    line(format_args!("{:<14} 0x{din:08X}", "DIN"));
+    for pin in 0..16 {
+        let mode = GPIO_MODES[((modes >> (4 * pin)) & 0xF) as usize];
+        let (dout, din) = ((dout >> pin) & 0x1, (din >> pin) & 0x1);
+        line(format_args!(
+            "  P{port:?}{pin:<2} {mode:<23} DOUT={dout} DIN={din}"
+        ));
+    }
+}
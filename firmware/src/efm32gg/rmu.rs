@@ -0,0 +1,169 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The per-cause counters live in RAM that isn't initialized at startup, so they accumulate across
+// every reset that preserves RAM. A power-on reset clears them (along with the rest of RAM).
+
+use core::fmt;
+use core::mem::MaybeUninit;
+use efm32gg11b820::RMU;
+
+const MAGIC: u32 = 0x5253_5443;
+pub const CAUSES: [Cause; 8] = [
+    Cause::PowerOn,
+    Cause::BrownOut,
+    Cause::External,
+    Cause::Watchdog,
+    Cause::Lockup,
+    Cause::SystemRequest,
+    Cause::Em4Wakeup,
+    Cause::Unknown,
+];
+
+#[link_section = ".uninit.poe.rmu"]
+static mut COUNTERS: MaybeUninit<Counters> = MaybeUninit::uninit();
+
+static mut LAST: Cause = Cause::Unknown;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cause {
+    PowerOn,
+    BrownOut,
+    External,
+    Watchdog,
+    Lockup,
+    SystemRequest,
+    Em4Wakeup,
+    Unknown,
+}
+
+impl Cause {
+    fn index(self) -> usize {
+        CAUSES
+            .iter()
+            .position(|c| *c == self)
+            .unwrap_or(CAUSES.len() - 1)
+    }
+}
+
+impl fmt::Display for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Cause::PowerOn => "power-on",
+            Cause::BrownOut => "brown-out",
+            Cause::External => "external",
+            Cause::Watchdog => "watchdog",
+            Cause::Lockup => "lockup",
+            Cause::SystemRequest => "system request",
+            Cause::Em4Wakeup => "EM4 wakeup",
+            Cause::Unknown => "unknown",
+        })
+    }
+}
+
+#[repr(C)]
+struct Counters {
+    magic: u32,
+    counts: [u32; CAUSES.len()],
+    check: u32,
+}
+
+impl Counters {
+    fn checksum(&self) -> u32 {
+        self.counts
+            .iter()
+            .fold(!self.magic, |sum, c| sum.rotate_left(7) ^ c)
+    }
+}
+
+/// Reads, logs, and then clears the reset cause. This must be called once at boot.
+pub fn init(rmu: &RMU) -> Cause {
+    let cause = rmu.rstcause.read();
+
+    macro_rules! bit_str {
+        ($reg:ident) => {
+            match cause.$reg().bit_is_set() {
+                true => concat!(" ", stringify!($reg)),
+                false => "",
+            }
+        };
+    }
+
+    log::debug!(
+        "RSTCAUSE:{}{}{}{}{}{}{}{}{}",
+        bit_str!(porst),
+        bit_str!(avddbod),
+        bit_str!(dvddbod),
+        bit_str!(decbod),
+        bit_str!(extrst),
+        bit_str!(lockuprst),
+        bit_str!(sysreqrst),
+        bit_str!(wdogrst),
+        bit_str!(em4rst),
+    );
+
+    // Multiple bits may be set at once, so they need to be interpreted in order of precedence
+    // (e.g. a power-on reset invalidates all of the others)
+    let decoded = if cause.porst().bit_is_set() {
+        Cause::PowerOn
+    } else if cause.avddbod().bit_is_set()
+        || cause.dvddbod().bit_is_set()
+        || cause.decbod().bit_is_set()
+    {
+        Cause::BrownOut
+    } else if cause.extrst().bit_is_set() {
+        Cause::External
+    } else if cause.wdogrst().bit_is_set() {
+        Cause::Watchdog
+    } else if cause.lockuprst().bit_is_set() {
+        Cause::Lockup
+    } else if cause.sysreqrst().bit_is_set() {
+        Cause::SystemRequest
+    } else if cause.em4rst().bit_is_set() {
+        Cause::Em4Wakeup
+    } else {
+        Cause::Unknown
+    };
+
+    rmu.cmd.write(|reg| reg.rcclr().set_bit());
+
+    let counters = unsafe { COUNTERS.assume_init_mut() };
+    if decoded == Cause::PowerOn || counters.magic != MAGIC || counters.check != counters.checksum()
+    {
+        counters.magic = MAGIC;
+        counters.counts = [0; CAUSES.len()];
+    }
+    counters.counts[decoded.index()] = counters.counts[decoded.index()].saturating_add(1);
+    counters.check = counters.checksum();
+
+    log::info!("Reset cause: {}", decoded);
+    unsafe { LAST = decoded };
+
+    decoded
+}
+
+/// Returns the cause of the most recent reset.
+pub fn last() -> Cause {
+    unsafe { LAST }
+}
+
+/// Calls `f` with each of the reset causes and the number of times it has occurred since the
+/// last power-on reset.
+pub fn for_each_count<F: FnMut(Cause, u32)>(mut f: F) {
+    let counters = unsafe { COUNTERS.assume_init_ref() };
+    for (cause, count) in CAUSES.iter().zip(counters.counts.iter()) {
+        f(*cause, *count);
+    }
+}
@@ -0,0 +1,95 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Sleeping between tasks, and measuring how much of the time is spent asleep. Whenever no task is
+// ready, the idle task puts the core into EM1 (sleep), which stops the core's clock but leaves
+// the high-frequency peripherals running, so every interrupt (the ETH, RTC, GPIO, and SysTick
+// among them) wakes it. EM2 (deep sleep) is never used: it stops the high-frequency clocks, and
+// with them the ETH (which can't wake the part from EM2) and the SysTick that RTIC schedules
+// tasks with.
+//
+// Time asleep is counted with the DWT's cycle counter, which the monotonic timer enables. The
+// core sleeps with interrupts masked, so that the handler of the interrupt that woke it only runs
+// once the time asleep has been counted.
+
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+use cortex_m::peripheral::{DWT, SCB};
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    window_cycles: 0,
+    last: 0,
+    elapsed: 0,
+    asleep: 0,
+    asleep_permille: None,
+}));
+
+struct State {
+    // The length of the window, in core clock cycles (one second's worth)
+    window_cycles: u32,
+
+    // The cycle count when the core last woke, and the cycles elapsed and spent asleep since the
+    // start of the current window
+    last: u32,
+    elapsed: u32,
+    asleep: u32,
+
+    asleep_permille: Option<u16>,
+}
+
+/// Sets the core clock's frequency, which the time asleep is measured against. This must be called
+/// before the idle task first runs.
+pub fn init(core_hz: u32) {
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().window_cycles = core_hz)
+}
+
+/// Returns the core clock's frequency, as set by `init`.
+pub fn core_hz() -> u32 {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().window_cycles)
+}
+
+/// Sleeps whenever there's nothing to do. This is the body of the binary's idle task.
+pub fn idle() -> ! {
+    // EM1, rather than EM2
+    unsafe { (*SCB::ptr()).scr.modify(|scr| scr & !(1 << 2)) };
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().last = DWT::cycle_count());
+
+    loop {
+        interrupt::free(|cs| {
+            let start = DWT::cycle_count();
+            cortex_m::asm::wfi();
+            let end = DWT::cycle_count();
+
+            let mut state = STATE.borrow(cs).borrow_mut();
+            state.elapsed = state.elapsed.wrapping_add(end.wrapping_sub(state.last));
+            state.asleep = state.asleep.wrapping_add(end.wrapping_sub(start));
+            state.last = end;
+
+            if state.window_cycles != 0 && state.elapsed >= state.window_cycles {
+                let permille = u64::from(state.asleep) * 1000 / u64::from(state.elapsed);
+                state.asleep_permille = Some(permille.min(1000) as u16);
+                state.elapsed = 0;
+                state.asleep = 0;
+            }
+        })
+    }
+}
+
+/// Returns the share of the last second (or so) that the core spent asleep, in tenths of a
+/// percent, or `None` if it hasn't been measured yet. The window only closes when the core next
+/// sleeps, so a core that's been kept busy for longer reports the last window it slept in.
+pub fn asleep_permille() -> Option<u16> {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().asleep_permille)
+}
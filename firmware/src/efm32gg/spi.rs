@@ -0,0 +1,343 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A SPI master for any of the USARTs, in synchronous mode with 8-bit frames, MSB first. The pins
+// need to be configured (CLK and MOSI as push-pull, MISO as an input) by the caller; this only
+// routes them to the peripheral. The chip select is an ordinary GPIO, driven by the driver for the
+// duration of each transaction.
+//
+// Transfers are polled a byte at a time, unless a pair of DMA channels has been given to the
+// driver (see `enable_dma`), in which case longer runs of bytes are moved by the LDMA. Either way,
+// the transfer blocks until it's done.
+//
+// The binary owns the driver, but may attach it as the shared bus (see `attach`), so that the parts
+// of the firmware that don't own it (e.g. the terminal, or storage) can use it.
+
+use super::ldma::{self, Endpoint, Request};
+use super::usart::{Instance, Registers};
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+use efm32gg11b820::CMU;
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::{Mode, Phase, Polarity};
+use ignore_result::Ignore;
+
+const CTRL_SYNC: u32 = 1 << 0;
+const CTRL_CLKPOL: u32 = 1 << 8;
+const CTRL_CLKPHA: u32 = 1 << 9;
+const CTRL_MSBF: u32 = 1 << 10;
+
+const CMD_RXEN: u32 = 1 << 0;
+const CMD_TXEN: u32 = 1 << 2;
+const CMD_MASTEREN: u32 = 1 << 4;
+const CMD_CLEARTX: u32 = 1 << 10;
+const CMD_CLEARRX: u32 = 1 << 11;
+
+// 8 data bits
+const FRAME_DATABITS_EIGHT: u32 = 0x5;
+
+const STATUS_TXBL: u32 = 1 << 6;
+const STATUS_RXDATAV: u32 = 1 << 7;
+
+const ROUTEPEN_RXPEN: u32 = 1 << 0;
+const ROUTEPEN_TXPEN: u32 = 1 << 1;
+const ROUTEPEN_CLKPEN: u32 = 1 << 3;
+const ROUTELOC0_TXLOC_SHIFT: u32 = 8;
+const ROUTELOC0_CLKLOC_SHIFT: u32 = 24;
+
+// The USART's DMA request signals
+const SIGNAL_RXDATAV: u32 = 0;
+const SIGNAL_TXBL: u32 = 2;
+
+// The number of times to poll for a byte (or a DMA transfer, per byte) before giving up
+const TIMEOUT: u32 = 10_000;
+
+// Runs shorter than this are polled, even with DMA, since setting up the channels takes longer
+const DMA_MIN_LEN: usize = 16;
+
+// What's sent while only reading
+const FILL: u8 = 0xFF;
+
+/// Where CLK, MOSI (TX), and MISO (RX) are routed (see the data sheet's alternate function table).
+pub struct Locations {
+    pub clk: u8,
+    pub mosi: u8,
+    pub miso: u8,
+}
+
+/// A device on a SPI bus.
+pub trait Bus: Send {
+    /// Exchanges `words` with the device, replacing each byte sent with the byte received.
+    fn transfer(&mut self, words: &mut [u8]) -> Result<(), &'static str>;
+
+    /// Sends each of `writes`, and then reads into `read`, selecting the device for the duration.
+    fn write_read(&mut self, writes: &[&[u8]], read: &mut [u8]) -> Result<(), &'static str>;
+}
+
+static BUS: Mutex<RefCell<Option<&'static mut dyn Bus>>> = Mutex::new(RefCell::new(None));
+
+/// Attaches the shared bus.
+pub fn attach(bus: &'static mut dyn Bus) {
+    interrupt::free(move |cs| *BUS.borrow(cs).borrow_mut() = Some(bus));
+}
+
+/// Runs `f` with the shared bus. The bus is held for the duration, so this fails if there's no bus
+/// or if it's already in use (e.g. by a task that was preempted).
+pub fn with<R, F: FnOnce(&mut dyn Bus) -> R>(f: F) -> Result<R, &'static str> {
+    let bus = interrupt::free(|cs| BUS.borrow(cs).borrow_mut().take()).ok_or("SPI unavailable")?;
+    let result = f(&mut *bus);
+    interrupt::free(move |cs| *BUS.borrow(cs).borrow_mut() = Some(bus));
+    Ok(result)
+}
+
+struct Dma {
+    rx: u8,
+    tx: u8,
+}
+
+pub struct Spi<U: Instance, CS: OutputPin> {
+    usart: Registers<U>,
+    cs: CS,
+    dma: Option<Dma>,
+}
+
+impl<U: Instance, CS: OutputPin> Spi<U, CS> {
+    /// Enables the USART as a SPI master, clocking at no more than `frequency` (in Hz). `hfperclk`
+    /// is the frequency of the peripheral clock, in Hz.
+    pub fn new(
+        usart: U,
+        mut cs: CS,
+        cmu: &CMU,
+        hfperclk: u32,
+        frequency: u32,
+        mode: Mode,
+        locations: Locations,
+    ) -> Spi<U, CS> {
+        U::enable_clock(cmu);
+        let usart = Registers(usart);
+
+        let polarity = match mode.polarity {
+            Polarity::IdleLow => 0,
+            Polarity::IdleHigh => CTRL_CLKPOL,
+        };
+        let phase = match mode.phase {
+            Phase::CaptureOnFirstTransition => 0,
+            Phase::CaptureOnSecondTransition => CTRL_CLKPHA,
+        };
+        usart
+            .ctrl
+            .write(|reg| unsafe { reg.bits(CTRL_SYNC | CTRL_MSBF | polarity | phase) });
+        usart
+            .frame
+            .write(|reg| unsafe { reg.bits(FRAME_DATABITS_EIGHT) });
+
+        // f_CLK = f_HFPERCLK / (2 * (1 + CLKDIV / 256)), using only whole divisors
+        let div = (hfperclk + 2 * frequency - 1) / (2 * frequency);
+        usart
+            .clkdiv
+            .write(|reg| unsafe { reg.bits(div.saturating_sub(1) << 8) });
+
+        usart.routeloc0.write(|reg| unsafe {
+            reg.bits(
+                u32::from(locations.miso)
+                    | u32::from(locations.mosi) << ROUTELOC0_TXLOC_SHIFT
+                    | u32::from(locations.clk) << ROUTELOC0_CLKLOC_SHIFT,
+            )
+        });
+        usart
+            .routepen
+            .write(|reg| unsafe { reg.bits(ROUTEPEN_RXPEN | ROUTEPEN_TXPEN | ROUTEPEN_CLKPEN) });
+
+        usart
+            .cmd
+            .write(|reg| unsafe { reg.bits(CMD_CLEARRX | CMD_CLEARTX) });
+        usart
+            .cmd
+            .write(|reg| unsafe { reg.bits(CMD_MASTEREN | CMD_RXEN | CMD_TXEN) });
+
+        cs.set_high().ignore();
+        Spi {
+            usart,
+            cs,
+            dma: None,
+        }
+    }
+
+    /// Moves longer runs of bytes using the given LDMA channels, which must not be used by
+    /// anything else.
+    pub fn enable_dma(mut self, cmu: &CMU, rx_channel: u8, tx_channel: u8) -> Spi<U, CS> {
+        ldma::init(cmu);
+        self.dma = Some(Dma {
+            rx: rx_channel,
+            tx: tx_channel,
+        });
+        self
+    }
+
+    fn wait_for(&self, status: u32) -> Result<(), &'static str> {
+        for _ in 0..TIMEOUT {
+            if self.usart.status.read().bits() & status != 0 {
+                return Ok(());
+            }
+        }
+
+        Err("timed out")
+    }
+
+    fn exchange(&mut self, byte: u8) -> Result<u8, &'static str> {
+        self.wait_for(STATUS_TXBL)?;
+        self.usart
+            .txdata
+            .write(|reg| unsafe { reg.bits(u32::from(byte)) });
+        self.wait_for(STATUS_RXDATAV)?;
+        Ok(self.usart.rxdata.read().bits() as u8)
+    }
+
+    // Moves `len` bytes out of `src` and into `dst` using the LDMA, a transfer at a time
+    fn exchange_dma(
+        &mut self,
+        src: Endpoint,
+        dst: Endpoint,
+        len: usize,
+    ) -> Result<(), &'static str> {
+        let (rx, tx) = match &self.dma {
+            Some(dma) => (dma.rx, dma.tx),
+            None => return Err("DMA not enabled"),
+        };
+        let rxdata = &self.usart.rxdata as *const _ as u32;
+        let txdata = &self.usart.txdata as *const _ as u32;
+
+        let mut done = 0;
+        while done < len {
+            let count = (len - done).min(ldma::MAX_TRANSFER);
+
+            // The receiver is started first, so that it's ready for the first byte
+            ldma::start(
+                rx,
+                Request {
+                    source: U::LDMA_SOURCE,
+                    signal: SIGNAL_RXDATAV,
+                },
+                Endpoint::Fixed(rxdata),
+                advance(dst, done),
+                count,
+            )?;
+            ldma::start(
+                tx,
+                Request {
+                    source: U::LDMA_SOURCE,
+                    signal: SIGNAL_TXBL,
+                },
+                advance(src, done),
+                Endpoint::Fixed(txdata),
+                count,
+            )?;
+
+            let finished = (0..TIMEOUT * count as u32).any(|_| ldma::is_done(rx));
+            if !finished {
+                ldma::stop(tx);
+                ldma::stop(rx);
+                return Err("timed out");
+            }
+            done += count;
+        }
+
+        Ok(())
+    }
+
+    fn use_dma(&self, len: usize) -> bool {
+        self.dma.is_some() && len >= DMA_MIN_LEN
+    }
+
+    fn transfer_bytes(&mut self, words: &mut [u8]) -> Result<(), &'static str> {
+        match self.use_dma(words.len()) {
+            true => {
+                let addr = words.as_mut_ptr() as u32;
+                self.exchange_dma(
+                    Endpoint::Incrementing(addr),
+                    Endpoint::Incrementing(addr),
+                    words.len(),
+                )
+            }
+            false => words
+                .iter_mut()
+                .try_for_each(|word| self.exchange(*word).map(|read| *word = read)),
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), &'static str> {
+        match self.use_dma(bytes.len()) {
+            true => {
+                let mut sink = 0u8;
+                self.exchange_dma(
+                    Endpoint::Incrementing(bytes.as_ptr() as u32),
+                    Endpoint::Fixed(&mut sink as *mut u8 as u32),
+                    bytes.len(),
+                )
+            }
+            false => bytes.iter().try_for_each(|b| self.exchange(*b).map(|_| ())),
+        }
+    }
+
+    fn read_bytes(&mut self, buffer: &mut [u8]) -> Result<(), &'static str> {
+        match self.use_dma(buffer.len()) {
+            true => self.exchange_dma(
+                Endpoint::Fixed(&FILL as *const u8 as u32),
+                Endpoint::Incrementing(buffer.as_mut_ptr() as u32),
+                buffer.len(),
+            ),
+            false => buffer
+                .iter_mut()
+                .try_for_each(|byte| self.exchange(FILL).map(|read| *byte = read)),
+        }
+    }
+
+    // Always deselects the device, even if the transaction failed
+    fn select<F>(&mut self, f: F) -> Result<(), &'static str>
+    where
+        F: FnOnce(&mut Self) -> Result<(), &'static str>,
+    {
+        self.usart.cmd.write(|reg| unsafe { reg.bits(CMD_CLEARRX) });
+        self.cs.set_low().map_err(|_| "chip select failed")?;
+        let result = f(self);
+        self.cs.set_high().map_err(|_| "chip select failed")?;
+        result
+    }
+}
+
+// Moves an incrementing endpoint forward by `count` bytes
+fn advance(endpoint: Endpoint, count: usize) -> Endpoint {
+    match endpoint {
+        Endpoint::Fixed(addr) => Endpoint::Fixed(addr),
+        Endpoint::Incrementing(addr) => Endpoint::Incrementing(addr + count as u32),
+    }
+}
+
+impl<U, CS> Bus for Spi<U, CS>
+where
+    U: Instance + Send,
+    CS: OutputPin + Send,
+{
+    fn transfer(&mut self, words: &mut [u8]) -> Result<(), &'static str> {
+        self.select(|spi| spi.transfer_bytes(words))
+    }
+
+    fn write_read(&mut self, writes: &[&[u8]], read: &mut [u8]) -> Result<(), &'static str> {
+        self.select(|spi| {
+            writes.iter().try_for_each(|bytes| spi.write_bytes(bytes))?;
+            spi.read_bytes(read)
+        })
+    }
+}
@@ -0,0 +1,94 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Execution time of the binary's tasks, for tuning the polling. Each task starts by calling
+// `start` with its name, and is timed, using the DWT's cycle counter, until the returned guard is
+// dropped. The time includes any higher-priority tasks that preempted it. Tasks are tracked by
+// name, in the order they first run; any beyond the first `SLOTS` aren't timed.
+
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+use cortex_m::peripheral::DWT;
+
+const SLOTS: usize = 16;
+
+static STATS: Mutex<RefCell<[Option<Stats>; SLOTS]>> = Mutex::new(RefCell::new([None; SLOTS]));
+
+#[derive(Clone, Copy, Debug)]
+pub struct Stats {
+    pub name: &'static str,
+    pub runs: u32,
+    pub total_cycles: u64,
+    pub max_cycles: u32,
+}
+
+impl Stats {
+    pub fn avg_cycles(&self) -> u32 {
+        match self.runs {
+            0 => 0,
+            runs => (self.total_cycles / u64::from(runs)) as u32,
+        }
+    }
+}
+
+/// Times a run of a task, until the guard is dropped.
+#[must_use]
+pub struct Guard {
+    slot: Option<usize>,
+    start: u32,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let cycles = DWT::cycle_count().wrapping_sub(self.start);
+        if let Some(slot) = self.slot {
+            interrupt::free(|cs| {
+                if let Some(stats) = &mut STATS.borrow(cs).borrow_mut()[slot] {
+                    stats.runs = stats.runs.wrapping_add(1);
+                    stats.total_cycles = stats.total_cycles.wrapping_add(u64::from(cycles));
+                    stats.max_cycles = stats.max_cycles.max(cycles);
+                }
+            })
+        }
+    }
+}
+
+/// Starts timing a run of the named task.
+pub fn start(name: &'static str) -> Guard {
+    let slot = interrupt::free(|cs| {
+        let mut stats = STATS.borrow(cs).borrow_mut();
+        let slot = stats
+            .iter()
+            .position(|slot| matches!(slot, Some(stats) if stats.name == name) || slot.is_none())?;
+        stats[slot].get_or_insert(Stats {
+            name,
+            runs: 0,
+            total_cycles: 0,
+            max_cycles: 0,
+        });
+        Some(slot)
+    });
+
+    Guard {
+        slot,
+        start: DWT::cycle_count(),
+    }
+}
+
+/// Calls `f` with the statistics of each task that has been timed.
+pub fn for_each<F: FnMut(&Stats)>(f: F) {
+    let stats = interrupt::free(|cs| *STATS.borrow(cs).borrow());
+    stats.iter().flatten().for_each(f);
+}
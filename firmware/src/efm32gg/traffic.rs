@@ -0,0 +1,113 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Frame and octet counts, as seen by the driver. Unlike the MAC's statistics registers, these
+// count every frame that's handed to or taken from the network stack (and the frames that the
+// driver handles itself, like LLDPDUs), as it appears on the wire. The rates are taken over the
+// last whole second, and are only as current as the last call to `tick`.
+
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+use smoltcp::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    totals: Traffic::new(),
+    window_totals: Traffic::new(),
+    window_start: Instant::from_millis_const(0),
+    rates: Traffic::new(),
+}));
+
+struct State {
+    totals: Traffic,
+
+    // The totals at the start of the current window
+    window_totals: Traffic,
+    window_start: Instant,
+
+    rates: Traffic,
+}
+
+/// Frame and octet counts in each direction. These are either totals since boot or rates per
+/// second.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Traffic {
+    pub rx_frames: u32,
+    pub rx_octets: u64,
+    pub tx_frames: u32,
+    pub tx_octets: u64,
+}
+
+impl Traffic {
+    const fn new() -> Traffic {
+        Traffic {
+            rx_frames: 0,
+            rx_octets: 0,
+            tx_frames: 0,
+            tx_octets: 0,
+        }
+    }
+}
+
+pub(super) fn received(len: usize) {
+    interrupt::free(|cs| {
+        let totals = &mut STATE.borrow(cs).borrow_mut().totals;
+        totals.rx_frames = totals.rx_frames.wrapping_add(1);
+        totals.rx_octets = totals.rx_octets.wrapping_add(len as u64);
+    })
+}
+
+pub(super) fn transmitted(len: usize) {
+    interrupt::free(|cs| {
+        let totals = &mut STATE.borrow(cs).borrow_mut().totals;
+        totals.tx_frames = totals.tx_frames.wrapping_add(1);
+        totals.tx_octets = totals.tx_octets.wrapping_add(len as u64);
+    })
+}
+
+/// Updates the rates, once a whole second has passed since they were last updated. This should be
+/// called a few times a second.
+pub fn tick(now: Instant) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        let elapsed = now - state.window_start;
+        if elapsed < WINDOW {
+            return;
+        }
+
+        let (start, end) = (state.window_totals, state.totals);
+        let millis = elapsed.total_millis().max(1);
+        let rate = |delta: u64| (delta * 1000 / millis) as u32;
+        state.rates = Traffic {
+            rx_frames: rate(u64::from(end.rx_frames.wrapping_sub(start.rx_frames))),
+            rx_octets: u64::from(rate(end.rx_octets.wrapping_sub(start.rx_octets))),
+            tx_frames: rate(u64::from(end.tx_frames.wrapping_sub(start.tx_frames))),
+            tx_octets: u64::from(rate(end.tx_octets.wrapping_sub(start.tx_octets))),
+        };
+        state.window_totals = end;
+        state.window_start = now;
+    })
+}
+
+/// Returns the totals since boot.
+pub fn totals() -> Traffic {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().totals)
+}
+
+/// Returns the rates, per second, over the last whole second.
+pub fn rates() -> Traffic {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().rates)
+}
@@ -0,0 +1,162 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// An asynchronous (UART) driver for any of the USARTs, using 16x oversampling and 8N1 frames. The
+// pins need to be configured (TX as push-pull, RX as an input) by the caller; this only routes
+// them to the peripheral. Reception and transmission are driven from the USART's interrupts: the
+// caller enables the ones it needs and handles both RX and TX interrupts by calling `read` and
+// `write` until they'd block.
+
+use core::ops::Deref;
+use efm32gg11b820::{usart0, Interrupt, CMU};
+
+const CMD_RXEN: u32 = 1 << 0;
+const CMD_TXEN: u32 = 1 << 2;
+const CMD_CLEARTX: u32 = 1 << 10;
+const CMD_CLEARRX: u32 = 1 << 11;
+
+// 8 data bits, no parity, 1 stop bit
+const FRAME_8N1: u32 = 0x5 | 0x1 << 12;
+
+const STATUS_TXBL: u32 = 1 << 6;
+const STATUS_RXDATAV: u32 = 1 << 7;
+
+const IF_TXBL: u32 = 1 << 1;
+const IF_RXDATAV: u32 = 1 << 2;
+
+const ROUTEPEN_RXPEN: u32 = 1 << 0;
+const ROUTEPEN_TXPEN: u32 = 1 << 1;
+const ROUTELOC0_TXLOC_SHIFT: u32 = 8;
+
+/// One of the USARTs, which all share a register layout (though the PAC gives each its own type).
+pub trait Instance {
+    /// The USART's registers, viewed as USART0's.
+    const REGISTERS: *const usart0::RegisterBlock;
+    /// The interrupt that's raised when there's room to transmit.
+    const TX_INTERRUPT: Interrupt;
+    /// The USART's source of DMA requests (see ldma).
+    const LDMA_SOURCE: u32;
+
+    fn enable_clock(cmu: &CMU);
+}
+
+macro_rules! instance {
+    ($usart:ident, $clken:ident, $tx:ident, $source:literal) => {
+        impl Instance for efm32gg11b820::$usart {
+            const REGISTERS: *const usart0::RegisterBlock =
+                efm32gg11b820::$usart::ptr() as *const _;
+            const TX_INTERRUPT: Interrupt = Interrupt::$tx;
+            const LDMA_SOURCE: u32 = $source;
+
+            fn enable_clock(cmu: &CMU) {
+                cmu.hfperclken0.modify(|_, reg| reg.$clken().set_bit());
+            }
+        }
+    };
+}
+
+instance!(USART0, usart0, USART0_TX, 0x0C);
+instance!(USART1, usart1, USART1_TX, 0x0D);
+instance!(USART2, usart2, USART2_TX, 0x0E);
+instance!(USART3, usart3, USART3_TX, 0x0F);
+instance!(USART4, usart4, USART4_TX, 0x10);
+instance!(USART5, usart5, USART5_TX, 0x11);
+
+/// Owns a USART, giving access to its registers through the common layout.
+pub struct Registers<U: Instance>(pub U);
+
+impl<U: Instance> Deref for Registers<U> {
+    type Target = usart0::RegisterBlock;
+
+    fn deref(&self) -> &usart0::RegisterBlock {
+        // Safe because every USART's register block matches USART0's and this owns the USART
+        unsafe { &*U::REGISTERS }
+    }
+}
+
+pub struct Usart<U: Instance> {
+    usart: Registers<U>,
+}
+
+impl<U: Instance> Usart<U> {
+    /// Enables the USART as a UART at `baud`, routing TX and RX to the given locations (see the
+    /// data sheet's alternate function table). `hfperclk` is the frequency of the peripheral
+    /// clock, in Hz.
+    pub fn new(usart: U, cmu: &CMU, hfperclk: u32, baud: u32, tx_loc: u8, rx_loc: u8) -> Usart<U> {
+        U::enable_clock(cmu);
+        let usart = Registers(usart);
+
+        // CLKDIV = 256 * (f_HFPERCLK / (16 * baud) - 1), rounded to the nearest step
+        let div = (256 * u64::from(hfperclk) + 8 * u64::from(baud)) / (16 * u64::from(baud));
+        usart
+            .clkdiv
+            .write(|reg| unsafe { reg.bits(div.saturating_sub(256) as u32 & !0x7) });
+        usart.frame.write(|reg| unsafe { reg.bits(FRAME_8N1) });
+
+        usart.routeloc0.write(|reg| unsafe {
+            reg.bits(u32::from(rx_loc) | u32::from(tx_loc) << ROUTELOC0_TXLOC_SHIFT)
+        });
+        usart
+            .routepen
+            .write(|reg| unsafe { reg.bits(ROUTEPEN_RXPEN | ROUTEPEN_TXPEN) });
+
+        usart
+            .cmd
+            .write(|reg| unsafe { reg.bits(CMD_CLEARRX | CMD_CLEARTX) });
+        usart
+            .cmd
+            .write(|reg| unsafe { reg.bits(CMD_RXEN | CMD_TXEN) });
+
+        Usart { usart }
+    }
+
+    /// Interrupts whenever a byte has been received.
+    pub fn listen_rx(&mut self) {
+        self.usart
+            .ien
+            .modify(|r, w| unsafe { w.bits(r.bits() | IF_RXDATAV) });
+    }
+
+    /// Interrupts whenever there's room to transmit (or not, once there's nothing left to send).
+    pub fn listen_tx(&mut self, enabled: bool) {
+        self.usart.ien.modify(|r, w| unsafe {
+            w.bits(match enabled {
+                true => r.bits() | IF_TXBL,
+                false => r.bits() & !IF_TXBL,
+            })
+        });
+    }
+
+    /// Returns the next received byte, if there is one.
+    pub fn read(&mut self) -> Option<u8> {
+        match self.usart.status.read().bits() & STATUS_RXDATAV {
+            0 => None,
+            _ => Some(self.usart.rxdata.read().bits() as u8),
+        }
+    }
+
+    /// Transmits the byte, returning false if the transmit buffer is full.
+    pub fn write(&mut self, byte: u8) -> bool {
+        match self.usart.status.read().bits() & STATUS_TXBL {
+            0 => false,
+            _ => {
+                self.usart
+                    .txdata
+                    .write(|reg| unsafe { reg.bits(u32::from(byte)) });
+                true
+            }
+        }
+    }
+}
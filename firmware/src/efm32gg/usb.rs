@@ -0,0 +1,77 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+#![cfg(feature = "usb")]
+
+// The USB device controller, which carries the USB terminal (see log::usb). The controller is a
+// Synopsys OTG core, driven by synopsys-usb-otg; this is the part-specific glue: where the core's
+// registers are, the size of its FIFO RAM, and how to clock it.
+//
+// The core is clocked from the USHFRCO at 48 MHz, with clock recovery locking it to the host's
+// start-of-frame packets, so it doesn't need a crystal (and keeps working if the HFXO fails; see
+// `clock`). The D+ and D- pads are fixed to PF10 and PF11. The unit isn't powered from VBUS, so
+// VBUS isn't sensed; the B-session is forced valid instead.
+
+use efm32gg11b820::{CMU, USB};
+use synopsys_usb_otg::UsbPeripheral;
+
+// The first of the core's registers (USB_GOTGCTL)
+const CORE_BASE: usize = 0x4005_E000;
+
+const GOTGCTL_BVALIDOVEN: u32 = 1 << 6;
+const GOTGCTL_BVALIDOVVAL: u32 = 1 << 7;
+
+const CMU_USBCTRL_USBCLKSEL_USHFRCO: u32 = 1 << 0;
+const CMU_USBCRCTRL_USBCREN: u32 = 1 << 0;
+
+pub type UsbBus = synopsys_usb_otg::UsbBus<Usb>;
+
+pub struct Usb {
+    core_hz: u32,
+}
+
+/// Returns the controller, for `UsbBus::new`. `core_hz` is the frequency of the core clock, which
+/// also clocks the controller's bus interface.
+pub fn new(_usb: USB, core_hz: u32) -> Usb {
+    Usb { core_hz }
+}
+
+unsafe impl UsbPeripheral for Usb {
+    const REGISTERS: *const () = CORE_BASE as *const ();
+    const HIGH_SPEED: bool = false;
+    const FIFO_DEPTH_WORDS: usize = 512;
+    const ENDPOINT_COUNT: usize = 7;
+
+    fn enable() {
+        let cmu = unsafe { &*CMU::ptr() };
+        let usb = unsafe { &*USB::ptr() };
+
+        cmu.oscencmd.write(|reg| reg.ushfrcoen().set_bit());
+        while cmu.status.read().ushfrcordy().bit_is_clear() {}
+        cmu.usbctrl
+            .write(|reg| unsafe { reg.bits(CMU_USBCTRL_USBCLKSEL_USHFRCO) });
+        cmu.usbcrctrl
+            .write(|reg| unsafe { reg.bits(CMU_USBCRCTRL_USBCREN) });
+        cmu.hfbusclken0.modify(|_, reg| reg.usb().set_bit());
+
+        usb.route.write(|reg| reg.phypen().set_bit());
+        usb.gotgctl
+            .modify(|r, w| unsafe { w.bits(r.bits() | GOTGCTL_BVALIDOVEN | GOTGCTL_BVALIDOVVAL) });
+    }
+
+    fn ahb_frequency_hz(&self) -> u32 {
+        self.core_hz
+    }
+}
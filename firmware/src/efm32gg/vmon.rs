@@ -0,0 +1,207 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The voltage monitor (VMON) compares each of the supplies against a threshold and raises an
+// interrupt when a supply crosses it. The thresholds are set using the two calibration points
+// from the DI page (1.86 V and 2.98 V), interpolating linearly between them. One coarse step is
+// roughly ten fine steps, so the pair is treated as a single value while interpolating. Without a
+// valid DI page, the thresholds can't be set, so the monitors are left disabled.
+
+use super::devinfo::{self, VmonCal, VmonThreshold};
+use core::fmt;
+use efm32gg11b820::EMU;
+
+/// The threshold below which a supply is considered to be low.
+pub const THRESHOLD_MV: u32 = 3000;
+
+// The AVDD monitor has a separate rising threshold, which is set this far above the falling
+// threshold to avoid chattering
+const AVDD_HYSTERESIS_MV: u32 = 100;
+
+const CAL_LOW_MV: u32 = 1860;
+const CAL_HIGH_MV: u32 = 2980;
+
+const VMONCTRL_EN: u32 = 1 << 0;
+const VMONCTRL_RISEWU: u32 = 1 << 2;
+const VMONCTRL_FALLWU: u32 = 1 << 3;
+const VMONCTRL_THRESFINE_SHIFT: u32 = 8;
+const VMONCTRL_THRESCOARSE_SHIFT: u32 = 12;
+const VMONAVDDCTRL_RISETHRESFINE_SHIFT: u32 = 16;
+const VMONAVDDCTRL_RISETHRESCOARSE_SHIFT: u32 = 20;
+
+const STATUS_VMONRDY: u32 = 1 << 0;
+
+const SUPPLIES: [Supply; 3] = [Supply::Avdd, Supply::Dvdd, Supply::Iovdd0];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Supply {
+    Avdd,
+    Dvdd,
+    Iovdd0,
+}
+
+impl Supply {
+    fn cal(self) -> VmonCal {
+        match self {
+            Supply::Avdd => devinfo::vmon_cal_avdd(),
+            Supply::Dvdd => devinfo::vmon_cal_dvdd(),
+            Supply::Iovdd0 => devinfo::vmon_cal_iovdd0(),
+        }
+    }
+
+    // The bit in STATUS that indicates whether the supply is above the threshold
+    fn status_bit(self) -> u32 {
+        match self {
+            Supply::Avdd => 1 << 1,
+            Supply::Dvdd => 1 << 3,
+            Supply::Iovdd0 => 1 << 4,
+        }
+    }
+
+    // The falling and rising interrupt flags
+    fn interrupt_bits(self) -> (u32, u32) {
+        match self {
+            Supply::Avdd => (1 << 0, 1 << 1),
+            Supply::Dvdd => (1 << 4, 1 << 5),
+            Supply::Iovdd0 => (1 << 6, 1 << 7),
+        }
+    }
+}
+
+impl fmt::Display for Supply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Supply::Avdd => "AVDD",
+            Supply::Dvdd => "DVDD",
+            Supply::Iovdd0 => "IOVDD0",
+        })
+    }
+}
+
+/// Enables the monitors for all of the supplies, and the interrupts for both falling and rising
+/// events.
+pub fn init(emu: &EMU) {
+    if !devinfo::is_valid() {
+        log::warn!("VMON is uncalibrated; supplies won't be monitored");
+        return;
+    }
+
+    let threshold = |supply: Supply, mv: u32, fine_shift: u32, coarse_shift: u32| {
+        let VmonThreshold { coarse, fine } = interpolate(supply.cal(), mv);
+        u32::from(fine) << fine_shift | u32::from(coarse) << coarse_shift
+    };
+    let ctrl = VMONCTRL_EN | VMONCTRL_RISEWU | VMONCTRL_FALLWU;
+
+    emu.vmonavddctrl.write(|reg| unsafe {
+        reg.bits(
+            ctrl | threshold(
+                Supply::Avdd,
+                THRESHOLD_MV,
+                VMONCTRL_THRESFINE_SHIFT,
+                VMONCTRL_THRESCOARSE_SHIFT,
+            ) | threshold(
+                Supply::Avdd,
+                THRESHOLD_MV + AVDD_HYSTERESIS_MV,
+                VMONAVDDCTRL_RISETHRESFINE_SHIFT,
+                VMONAVDDCTRL_RISETHRESCOARSE_SHIFT,
+            ),
+        )
+    });
+    emu.vmondvddctrl.write(|reg| unsafe {
+        reg.bits(
+            ctrl | threshold(
+                Supply::Dvdd,
+                THRESHOLD_MV,
+                VMONCTRL_THRESFINE_SHIFT,
+                VMONCTRL_THRESCOARSE_SHIFT,
+            ),
+        )
+    });
+    emu.vmonio0ctrl.write(|reg| unsafe {
+        reg.bits(
+            ctrl | threshold(
+                Supply::Iovdd0,
+                THRESHOLD_MV,
+                VMONCTRL_THRESFINE_SHIFT,
+                VMONCTRL_THRESCOARSE_SHIFT,
+            ),
+        )
+    });
+
+    while emu.status.read().bits() & STATUS_VMONRDY == 0 {}
+
+    let flags = SUPPLIES.iter().fold(0, |flags, supply| {
+        let (fall, rise) = supply.interrupt_bits();
+        flags | fall | rise
+    });
+    emu.ifc.write(|reg| unsafe { reg.bits(flags) });
+    emu.ien.modify(|r, w| unsafe { w.bits(r.bits() | flags) });
+
+    for supply in SUPPLIES {
+        if !is_above(supply) {
+            log::warn!("{} is already below {} mV", supply, THRESHOLD_MV);
+        }
+    }
+}
+
+/// Returns true if the supply is above the threshold.
+pub fn is_above(supply: Supply) -> bool {
+    let emu = unsafe { &*EMU::ptr() };
+    emu.status.read().bits() & supply.status_bit() != 0
+}
+
+/// Calls `f` with each of the monitored supplies and whether it's above the threshold. Nothing is
+/// monitored if the DI page is invalid.
+pub fn for_each_supply<F: FnMut(Supply, bool)>(mut f: F) {
+    if !devinfo::is_valid() {
+        return;
+    }
+
+    for supply in SUPPLIES {
+        f(supply, is_above(supply));
+    }
+}
+
+/// Handles the EMU interrupt, logging any threshold crossings.
+pub fn irq() {
+    let emu = unsafe { &*EMU::ptr() };
+    let flags = emu.if_.read().bits();
+
+    for supply in SUPPLIES {
+        let (fall, rise) = supply.interrupt_bits();
+        if flags & fall != 0 {
+            log::warn!("{} fell below {} mV", supply, THRESHOLD_MV);
+        }
+        if flags & rise != 0 {
+            log::info!("{} recovered", supply);
+        }
+    }
+
+    emu.ifc.write(|reg| unsafe { reg.bits(flags) });
+}
+
+fn interpolate(cal: VmonCal, mv: u32) -> VmonThreshold {
+    let steps = |t: VmonThreshold| i32::from(t.coarse) * 10 + i32::from(t.fine);
+    let (low, high) = (steps(cal.low), steps(cal.high));
+
+    let steps =
+        low + (mv as i32 - CAL_LOW_MV as i32) * (high - low) / (CAL_HIGH_MV - CAL_LOW_MV) as i32;
+    let steps = steps.clamp(0, 15 * 10 + 9);
+
+    VmonThreshold {
+        coarse: (steps / 10) as u8,
+        fine: (steps % 10) as u8,
+    }
+}
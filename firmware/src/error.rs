@@ -0,0 +1,88 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Failures in bringing up the hardware and the network services. Unlike the messages returned by
+// the terminal's commands, these are matched on by their callers (e.g. to carry on without a
+// service, rather than stop), so they're kept apart.
+
+use core::fmt;
+use smoltcp::iface::RouteTableFull;
+use smoltcp::socket::{icmp, tcp, udp};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// No PHY answered on the management bus.
+    NoPhy,
+    /// A socket couldn't be bound to (or listen on) its port.
+    Bind(u16, BindError),
+    /// The route table had no room for the default route.
+    Route(RouteTableFull),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NoPhy => write!(f, "failed to find PHY"),
+            Error::Bind(port, err) => write!(f, "failed to bind port {}: {}", port, err),
+            Error::Route(err) => write!(f, "failed to add default route: {}", err),
+        }
+    }
+}
+
+/// Why a socket couldn't be bound, whichever kind of socket it is (smoltcp gives each its own
+/// error).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BindError {
+    /// The socket was already bound (or listening).
+    InvalidState,
+    /// The port (or ICMP identifier) was zero.
+    Unaddressable,
+}
+
+impl fmt::Display for BindError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BindError::InvalidState => write!(f, "invalid state"),
+            BindError::Unaddressable => write!(f, "unaddressable"),
+        }
+    }
+}
+
+impl From<udp::BindError> for BindError {
+    fn from(err: udp::BindError) -> BindError {
+        match err {
+            udp::BindError::InvalidState => BindError::InvalidState,
+            udp::BindError::Unaddressable => BindError::Unaddressable,
+        }
+    }
+}
+
+impl From<tcp::ListenError> for BindError {
+    fn from(err: tcp::ListenError) -> BindError {
+        match err {
+            tcp::ListenError::InvalidState => BindError::InvalidState,
+            tcp::ListenError::Unaddressable => BindError::Unaddressable,
+        }
+    }
+}
+
+impl From<icmp::BindError> for BindError {
+    fn from(err: icmp::BindError) -> BindError {
+        match err {
+            icmp::BindError::InvalidState => BindError::InvalidState,
+            icmp::BindError::Unaddressable => BindError::Unaddressable,
+        }
+    }
+}
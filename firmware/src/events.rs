@@ -0,0 +1,399 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A log of operational events (link changes and faults, DHCP leases, port trips, boots, and
+// privileged terminal commands), kept apart from the debug log so that it survives power loss.
+// Each event is also logged (under the "events" target, so it's forwarded to any syslog
+// collector) and sent as an SNMP trap.
+//
+// Events are recorded into a small queue in RAM, since they're often recorded from interrupt
+// handlers, and then written to the last two pages of flash by `flush`. Each entry takes four
+// words: a header (a marker, the kind of event, and the boot number), a sequence number, the
+// timestamp, and a kind-specific detail. The pages are used as a ring; once one fills up, the
+// other is erased and written next, so that the older half of the log is dropped.
+
+use crate::console::{Privileged, Terminal};
+use crate::efm32gg::msc;
+use crate::efm32gg::rmu::{self, Cause};
+use crate::port::protect::Reason;
+use core::cell::RefCell;
+use core::fmt;
+use core::ptr;
+use cortex_m::interrupt::{self, Mutex};
+use ethernet_phy::Fault;
+use smoltcp::time::Instant;
+use smoltcp::wire::Ipv4Address;
+
+const LOG_START: u32 = 0x001F_E000;
+const LOG_PAGES: u32 = 2;
+const ENTRY_SIZE: u32 = 16;
+const ENTRIES_PER_PAGE: u32 = msc::PAGE_SIZE / ENTRY_SIZE;
+const ENTRIES: u32 = LOG_PAGES * ENTRIES_PER_PAGE;
+
+const MARKER: u32 = 0xE7;
+const ERASED: u32 = 0xFFFF_FFFF;
+
+const QUEUE_LEN: usize = 8;
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    boot: 0,
+    position: None,
+    queue: [None; QUEUE_LEN],
+    next: 0,
+    flushed: 0,
+    trapped: 0,
+}));
+
+struct State {
+    boot: u16,
+    // The offset (in entries) of the next entry to be written, or `None` until the log is scanned
+    position: Option<u32>,
+    // Recent entries, indexed by sequence number
+    queue: [Option<Entry>; QUEUE_LEN],
+    next: u32,
+    flushed: u32,
+    trapped: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Event {
+    Boot(Cause),
+    LinkUp,
+    LinkDown,
+    AddressAcquired(Ipv4Address),
+    AddressLost,
+    PortTripped(Reason),
+    PhyFault(Fault),
+    ClockFallback,
+    ImageRejected,
+    ConfigRolledBack,
+    Command(Terminal, Privileged),
+}
+
+impl Event {
+    /// The severity of the event, as it's logged.
+    pub fn severity(&self) -> log::Level {
+        match self {
+            Event::Boot(_) | Event::LinkUp | Event::AddressAcquired(_) | Event::Command(..) => {
+                log::Level::Info
+            }
+            Event::LinkDown | Event::AddressLost | Event::PhyFault(_) | Event::ConfigRolledBack => {
+                log::Level::Warn
+            }
+            Event::PortTripped(_) | Event::ClockFallback | Event::ImageRejected => {
+                log::Level::Error
+            }
+        }
+    }
+
+    /// A number identifying the kind of event, which is also used as the SNMP notification.
+    pub fn code(&self) -> u32 {
+        match self {
+            Event::Boot(_) => 1,
+            Event::LinkUp => 2,
+            Event::LinkDown => 3,
+            Event::AddressAcquired(_) => 4,
+            Event::AddressLost => 5,
+            Event::PortTripped(_) => 6,
+            Event::PhyFault(_) => 7,
+            Event::ClockFallback => 8,
+            Event::ImageRejected => 9,
+            Event::ConfigRolledBack => 10,
+            Event::Command(..) => 11,
+        }
+    }
+
+    fn detail(&self) -> u32 {
+        match self {
+            Event::Boot(cause) => rmu::CAUSES.iter().position(|c| c == cause).unwrap_or(0) as u32,
+            Event::AddressAcquired(addr) => u32::from_be_bytes(addr.0),
+            Event::PortTripped(Reason::Overcurrent) => 0,
+            Event::PortTripped(Reason::OverBudget) => 1,
+            Event::PortTripped(Reason::Undervoltage) => 2,
+            Event::PhyFault(fault) => fault.index() as u32,
+            Event::Command(terminal, command) => {
+                (terminal.index() as u32) << 8 | command.index() as u32
+            }
+            Event::LinkUp
+            | Event::LinkDown
+            | Event::AddressLost
+            | Event::ClockFallback
+            | Event::ImageRejected
+            | Event::ConfigRolledBack => 0,
+        }
+    }
+
+    fn decode(code: u32, detail: u32) -> Option<Event> {
+        Some(match (code, detail) {
+            (1, cause) => Event::Boot(*rmu::CAUSES.get(cause as usize)?),
+            (2, _) => Event::LinkUp,
+            (3, _) => Event::LinkDown,
+            (4, addr) => Event::AddressAcquired(Ipv4Address(addr.to_be_bytes())),
+            (5, _) => Event::AddressLost,
+            (6, 0) => Event::PortTripped(Reason::Overcurrent),
+            (6, 1) => Event::PortTripped(Reason::OverBudget),
+            (6, 2) => Event::PortTripped(Reason::Undervoltage),
+            (7, fault) => Event::PhyFault(*Fault::ALL.get(fault as usize)?),
+            (8, _) => Event::ClockFallback,
+            (9, _) => Event::ImageRejected,
+            (10, _) => Event::ConfigRolledBack,
+            (11, detail) => Event::Command(
+                *Terminal::ALL.get((detail >> 8) as usize)?,
+                *Privileged::ALL.get((detail & 0xFF) as usize)?,
+            ),
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Boot(cause) => write!(f, "Booted after {} reset", cause),
+            Event::LinkUp => write!(f, "Link up"),
+            Event::LinkDown => write!(f, "Link down"),
+            Event::AddressAcquired(addr) => write!(f, "Acquired address {}", addr),
+            Event::AddressLost => write!(f, "Lost address"),
+            Event::PortTripped(reason) => write!(f, "Port tripped ({})", reason),
+            Event::PhyFault(fault) => write!(f, "Repeated PHY faults ({})", fault),
+            Event::ClockFallback => write!(f, "HFXO failed; running from the HFRCO"),
+            Event::ImageRejected => write!(f, "Update failed; running the image in bank A"),
+            Event::ConfigRolledBack => {
+                write!(f, "No management connection; configuration rolled back")
+            }
+            Event::Command(terminal, command) => {
+                write!(
+                    f,
+                    "Ran privileged command ({}) on the {} terminal",
+                    command, terminal
+                )
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    /// The boot during which the event occurred, counting from when the log was first written.
+    pub boot: u16,
+    /// The time of the event, relative to the start of that boot.
+    pub timestamp: Instant,
+    pub event: Event,
+    sequence: u32,
+}
+
+impl Entry {
+    fn encode(&self) -> [u32; 4] {
+        [
+            MARKER << 24 | self.event.code() << 16 | u32::from(self.boot),
+            self.sequence,
+            self.timestamp.total_millis() as u32,
+            self.event.detail(),
+        ]
+    }
+
+    fn decode(words: [u32; 4]) -> Option<Entry> {
+        let [header, sequence, timestamp, detail] = words;
+        if header >> 24 != MARKER {
+            return None;
+        }
+
+        Some(Entry {
+            boot: header as u16,
+            timestamp: Instant::from_millis(i64::from(timestamp)),
+            event: Event::decode(header >> 16 & 0xFF, detail)?,
+            sequence,
+        })
+    }
+}
+
+/// Finds the end of the log and records the boot. This must be called once at boot, after the
+/// reset cause is known (see `rmu::init`) and before any other events are recorded.
+pub fn init(now: Instant) {
+    let last = (0..ENTRIES)
+        .filter_map(|offset| Some((offset, read(offset)?)))
+        .max_by_key(|(_, entry)| entry.sequence);
+
+    let (position, boot, sequence) = match last {
+        Some((offset, entry)) => (
+            (offset + 1) % ENTRIES,
+            entry.boot.wrapping_add(1),
+            entry.sequence.wrapping_add(1),
+        ),
+        None => (0, 0, 0),
+    };
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.boot = boot;
+        state.position = Some(position);
+        state.next = sequence;
+        state.flushed = sequence;
+        state.trapped = sequence;
+    });
+
+    record(now, Event::Boot(rmu::last()));
+}
+
+/// Records an event.
+pub fn record(now: Instant, event: Event) {
+    log::log!(target: "events", event.severity(), "{}", event);
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        let sequence = state.next;
+        state.queue[sequence as usize % QUEUE_LEN] = Some(Entry {
+            boot: state.boot,
+            timestamp: now,
+            event,
+            sequence,
+        });
+        state.next = sequence.wrapping_add(1);
+
+        // The oldest entries are dropped if the queue overflows
+        let oldest = state.next.wrapping_sub(QUEUE_LEN as u32);
+        if state.next.wrapping_sub(state.flushed) > QUEUE_LEN as u32 {
+            state.flushed = oldest;
+        }
+        if state.next.wrapping_sub(state.trapped) > QUEUE_LEN as u32 {
+            state.trapped = oldest;
+        }
+    })
+}
+
+/// Writes any recorded events to flash. This blocks while pages are erased, so it should be
+/// called from a low-priority task.
+pub fn flush() {
+    loop {
+        let pending = interrupt::free(|cs| {
+            let state = STATE.borrow(cs).borrow();
+            match (state.position, state.flushed != state.next) {
+                (Some(position), true) => {
+                    Some((position, state.queue[state.flushed as usize % QUEUE_LEN]?))
+                }
+                _ => None,
+            }
+        });
+        let (position, entry) = match pending {
+            Some(pending) => pending,
+            None => return,
+        };
+
+        let address = LOG_START + position * ENTRY_SIZE;
+        let result = match address % msc::PAGE_SIZE {
+            0 => msc::erase_page(address),
+            _ => Ok(()),
+        }
+        .and_then(|_| msc::write(address, &entry.encode()));
+        if let Err(err) = result {
+            log::error!("Failed to write event log: {}", err);
+        }
+
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+            state.position = Some((position + 1) % ENTRIES);
+            state.flushed = state.flushed.wrapping_add(1);
+        });
+    }
+}
+
+/// Returns true if there are events that haven't been sent as traps.
+pub fn trap_pending() -> bool {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        state.trapped != state.next
+    })
+}
+
+/// Returns the oldest event that hasn't yet been sent as a trap.
+pub fn take_trap() -> Option<Entry> {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        while state.trapped != state.next {
+            let entry = state.queue[state.trapped as usize % QUEUE_LEN];
+            state.trapped = state.trapped.wrapping_add(1);
+            if entry.is_some() {
+                return entry;
+            }
+        }
+        None
+    })
+}
+
+/// Returns the sequence number that the next event will be recorded with.
+pub fn sequence() -> u32 {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().next)
+}
+
+/// Returns the oldest event that's still queued in RAM and was recorded with `sequence` or later,
+/// along with the sequence number that follows it.
+pub fn since(sequence: u32) -> Option<(Entry, u32)> {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        let mut sequence = match state.next.wrapping_sub(sequence) > QUEUE_LEN as u32 {
+            true => state.next.wrapping_sub(QUEUE_LEN as u32),
+            false => sequence,
+        };
+        while sequence != state.next {
+            let entry = state.queue[sequence as usize % QUEUE_LEN];
+            let current = sequence;
+            sequence = sequence.wrapping_add(1);
+            match entry {
+                Some(entry) if entry.sequence == current => return Some((entry, sequence)),
+                _ => {}
+            }
+        }
+        None
+    })
+}
+
+/// Calls `f` with each event in the log, oldest first, followed by any that haven't been written
+/// to flash yet.
+pub fn for_each<F: FnMut(&Entry)>(mut f: F) {
+    let (position, pending) = interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        let mut pending = [None; QUEUE_LEN];
+        let mut sequence = state.flushed;
+        for slot in pending.iter_mut() {
+            if sequence == state.next {
+                break;
+            }
+            *slot = state.queue[sequence as usize % QUEUE_LEN];
+            sequence = sequence.wrapping_add(1);
+        }
+        (state.position, pending)
+    });
+
+    // The oldest entries are in the page after the one holding the newest entry
+    if let Some(position) = position {
+        let page = (position + ENTRIES - 1) % ENTRIES / ENTRIES_PER_PAGE;
+        (0..LOG_PAGES)
+            .map(|i| (page + 1 + i) % LOG_PAGES * ENTRIES_PER_PAGE)
+            .flat_map(|start| start..start + ENTRIES_PER_PAGE)
+            .filter_map(read)
+            .for_each(|entry| f(&entry));
+    }
+
+    pending.iter().flatten().for_each(f);
+}
+
+fn read(offset: u32) -> Option<Entry> {
+    let address = (LOG_START + offset * ENTRY_SIZE) as *const [u32; 4];
+    let words = unsafe { ptr::read_volatile(address) };
+    match words[0] {
+        ERASED => None,
+        _ => Entry::decode(words),
+    }
+}
@@ -0,0 +1,359 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Fault reports are written into a region of RAM that isn't initialized at startup, so they
+// survive the reset that follows the fault. At the next boot, `init` validates the report (using
+// the magic number and the CRC) and moves it somewhere safe.
+//
+// If a monitor is configured, `end` also makes a last attempt to send the report over the network
+// as a single UDP datagram. The frame is written directly to the MAC since the network stack can't
+// be trusted at that point. The monitor's hardware address isn't known here, so the frame is sent
+// to the broadcast address; the monitor needs to be on the local network segment.
+
+use crate::vlan;
+use core::cell::Cell;
+use core::cmp;
+use core::fmt::{self, Write};
+use core::mem::MaybeUninit;
+use core::{slice, str};
+use cortex_m::asm;
+use cortex_m::interrupt::{self, Mutex};
+use cortex_m::peripheral::{DCB, SCB};
+use cortex_m_rt::ExceptionFrame;
+use ignore_result::Ignore;
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, Ipv4Address};
+
+pub const MONITOR_PORT: u16 = 51901;
+
+const MAGIC: u32 = 0xFA17_FA17;
+const MESSAGE_LEN: usize = 128;
+
+const FRAME_LEN: usize = 512;
+const MIN_FRAME_LEN: usize = 60;
+const ETHERNET_HEADER_LEN: usize = 14;
+const IPV4_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+const PAYLOAD_OFFSET: usize = ETHERNET_HEADER_LEN + IPV4_HEADER_LEN + UDP_HEADER_LEN;
+
+static MONITOR: Mutex<Cell<Option<Ipv4Address>>> = Mutex::new(Cell::new(None));
+static SOURCE: Mutex<Cell<Option<(EthernetAddress, Ipv4Address)>>> = Mutex::new(Cell::new(None));
+
+#[link_section = ".uninit.poe.fault"]
+static mut PENDING: MaybeUninit<Report> = MaybeUninit::uninit();
+
+static mut LAST: Option<Report> = None;
+
+const KIND_PANIC: u32 = 0;
+const KIND_HARD_FAULT: u32 = 1;
+const KIND_UNHANDLED_IRQ: u32 = 2;
+const KIND_STACK_OVERFLOW: u32 = 3;
+
+// Only plain integers are stored here, since the contents are garbage until validated. The CRC
+// covers everything from the magic number through the end of the message.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct Report {
+    crc: u32,
+    magic: u32,
+    kind: u32,
+    irqn: i32,
+    timestamp: i64,
+    frame: [u32; 8],
+    cfsr: u32,
+    hfsr: u32,
+    mmfar: u32,
+    bfar: u32,
+    len: u32,
+    message: [u8; MESSAGE_LEN],
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Kind {
+    Panic,
+    HardFault,
+    UnhandledIrq(i16),
+    StackOverflow,
+}
+
+impl Report {
+    fn new(kind: Kind, timestamp: Instant) -> Report {
+        let scb = unsafe { &*SCB::ptr() };
+        let (kind, irqn) = match kind {
+            Kind::Panic => (KIND_PANIC, 0),
+            Kind::HardFault => (KIND_HARD_FAULT, 0),
+            Kind::UnhandledIrq(irqn) => (KIND_UNHANDLED_IRQ, i32::from(irqn)),
+            Kind::StackOverflow => (KIND_STACK_OVERFLOW, 0),
+        };
+
+        Report {
+            crc: 0,
+            magic: MAGIC,
+            kind,
+            irqn,
+            timestamp: timestamp.total_millis(),
+            frame: [0; 8],
+            cfsr: scb.cfsr.read(),
+            hfsr: scb.hfsr.read(),
+            mmfar: scb.mmfar.read(),
+            bfar: scb.bfar.read(),
+            len: 0,
+            message: [0; MESSAGE_LEN],
+        }
+    }
+
+    pub fn kind(&self) -> Kind {
+        match self.kind {
+            KIND_PANIC => Kind::Panic,
+            KIND_HARD_FAULT => Kind::HardFault,
+            KIND_STACK_OVERFLOW => Kind::StackOverflow,
+            _ => Kind::UnhandledIrq(self.irqn as i16),
+        }
+    }
+
+    pub fn timestamp(&self) -> Instant {
+        Instant::from_millis(self.timestamp)
+    }
+
+    pub fn message(&self) -> &str {
+        // The message is only ever truncated on a character boundary
+        let len = cmp::min(self.len as usize, MESSAGE_LEN);
+        unsafe { str::from_utf8_unchecked(&self.message[..len]) }
+    }
+
+    fn crc(&self) -> u32 {
+        let start = &self.magic as *const u32 as usize;
+        let end = self.message.as_ptr() as usize + MESSAGE_LEN;
+        crate::efm32gg::gpcrc::crc32(unsafe {
+            slice::from_raw_parts(start as *const u8, end - start)
+        })
+    }
+
+    fn is_valid(&self) -> bool {
+        self.magic == MAGIC && self.crc == self.crc()
+    }
+
+    fn save(mut self) {
+        self.crc = self.crc();
+        unsafe { PENDING.write(self) };
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind() {
+            Kind::Panic => write!(f, "Panic at {}: {}", self.timestamp(), self.message())?,
+            Kind::HardFault => write!(f, "Hard fault at {}", self.timestamp())?,
+            Kind::UnhandledIrq(irqn) => {
+                write!(f, "Unhandled IRQ {} at {}", irqn, self.timestamp())?
+            }
+            Kind::StackOverflow => write!(f, "Stack overflow at {}", self.timestamp())?,
+        }
+
+        if let Kind::HardFault | Kind::StackOverflow = self.kind() {
+            let [r0, r1, r2, r3, r12, lr, pc, xpsr] = self.frame;
+            write!(
+                f,
+                "\n  r0=0x{:08X} r1=0x{:08X} r2=0x{:08X} r3=0x{:08X}\
+                 \n  r12=0x{:08X} lr=0x{:08X} pc=0x{:08X} xpsr=0x{:08X}",
+                r0, r1, r2, r3, r12, lr, pc, xpsr
+            )?;
+        }
+
+        write!(
+            f,
+            "\n  cfsr=0x{:08X} hfsr=0x{:08X} mmfar=0x{:08X} bfar=0x{:08X}",
+            self.cfsr, self.hfsr, self.mmfar, self.bfar
+        )
+    }
+}
+
+impl fmt::Write for Report {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let offset = self.len as usize;
+        let mut len = cmp::min(s.len(), MESSAGE_LEN - offset);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        self.message[offset..][..len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len as u32;
+        Ok(())
+    }
+}
+
+/// Retrieves the report left behind by the previous boot, if there is one. This must be called
+/// once at boot, before any faults can occur.
+pub fn init() {
+    let pending = unsafe { PENDING.assume_init_mut() };
+    if pending.is_valid() {
+        log::warn!("Previous boot ended with a fault: {}", pending);
+        unsafe { LAST = Some(*pending) };
+    }
+
+    // Invalidate the report so it isn't picked up again after the next reset
+    pending.magic = 0;
+}
+
+/// Returns the report left behind by the previous boot, if there is one.
+pub fn last() -> Option<&'static Report> {
+    unsafe { LAST.as_ref() }
+}
+
+pub fn record_panic(timestamp: Instant, info: &core::panic::PanicInfo) {
+    let mut report = Report::new(Kind::Panic, timestamp);
+    write!(report, "{}", info).ignore();
+    report.save();
+}
+
+/// With the `panic-never` feature, the panic handler refers to a function that doesn't exist, so
+/// the build only links if the optimizer was able to remove every path to a panic. Build with
+/// `cargo build --release --features panic-never` and inspect the undefined-symbol error to find
+/// out which code can still panic.
+#[cfg(feature = "panic-never")]
+#[panic_handler]
+fn panic_never(_: &core::panic::PanicInfo) -> ! {
+    extern "Rust" {
+        fn panic_is_reachable() -> !;
+    }
+
+    unsafe { panic_is_reachable() }
+}
+
+pub fn record_hard_fault(timestamp: Instant, frame: &ExceptionFrame) {
+    let mut report = Report::new(Kind::HardFault, timestamp);
+    if crate::stack::overflowed(report.cfsr, report.mmfar) {
+        report.kind = KIND_STACK_OVERFLOW;
+    }
+    report.frame = [
+        frame.r0, frame.r1, frame.r2, frame.r3, frame.r12, frame.lr, frame.pc, frame.xpsr,
+    ];
+    report.save();
+}
+
+pub fn record_unhandled_irq(timestamp: Instant, irqn: i16) {
+    Report::new(Kind::UnhandledIrq(irqn), timestamp).save();
+}
+
+/// Sets the address of the monitor that is sent the fault report, or stops sending if `None`.
+pub fn set_monitor(monitor: Option<Ipv4Address>) {
+    interrupt::free(|cs| MONITOR.borrow(cs).set(monitor))
+}
+
+pub fn monitor() -> Option<Ipv4Address> {
+    interrupt::free(|cs| MONITOR.borrow(cs).get())
+}
+
+/// Sets the hardware and protocol addresses used to send the fault report, or `None` if the
+/// interface isn't configured.
+pub fn set_source(source: Option<(EthernetAddress, Ipv4Address)>) {
+    interrupt::free(|cs| SOURCE.borrow(cs).set(source))
+}
+
+/// Finishes handling a fault. This sends the fault report to the monitor (if there is one) and
+/// then, if a debugger is attached, triggers a breakpoint and loops; otherwise, it resets the
+/// device.
+pub fn end() -> ! {
+    let pending = unsafe { PENDING.assume_init_ref() };
+    if pending.is_valid() {
+        send_last_gasp(pending);
+    }
+
+    if DCB::is_debugger_attached() {
+        asm::bkpt();
+
+        loop {
+            asm::wfe();
+        }
+    }
+
+    SCB::sys_reset()
+}
+
+fn send_last_gasp(report: &Report) {
+    let (monitor, (hardware_addr, addr)) =
+        match interrupt::free(|cs| (MONITOR.borrow(cs).get(), SOURCE.borrow(cs).get())) {
+            (Some(monitor), Some(source)) => (monitor, source),
+            _ => return,
+        };
+
+    let mut frame = Frame {
+        data: [0; FRAME_LEN],
+        len: PAYLOAD_OFFSET,
+    };
+    write!(frame, "{}", report).ignore();
+
+    let udp_len = (frame.len - ETHERNET_HEADER_LEN - IPV4_HEADER_LEN) as u16;
+    let ip_len = udp_len + IPV4_HEADER_LEN as u16;
+    let len = cmp::max(frame.len, MIN_FRAME_LEN);
+
+    let (ethernet, rest) = frame.data.split_at_mut(ETHERNET_HEADER_LEN);
+    ethernet[0..6].copy_from_slice(EthernetAddress::BROADCAST.as_bytes());
+    ethernet[6..12].copy_from_slice(hardware_addr.as_bytes());
+    ethernet[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+
+    let (ip, udp) = rest.split_at_mut(IPV4_HEADER_LEN);
+    ip[0] = 0x45; // Version 4, five-word header
+    ip[2..4].copy_from_slice(&ip_len.to_be_bytes());
+    ip[6..8].copy_from_slice(&0x4000u16.to_be_bytes()); // Don't fragment
+    ip[8] = 64; // TTL
+    ip[9] = 17; // UDP
+    ip[12..16].copy_from_slice(addr.as_bytes());
+    ip[16..20].copy_from_slice(monitor.as_bytes());
+    let checksum = ipv4_checksum(ip);
+    ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    // The UDP checksum is optional for IPv4, so it's left as zero
+    udp[0..2].copy_from_slice(&MONITOR_PORT.to_be_bytes());
+    udp[2..4].copy_from_slice(&MONITOR_PORT.to_be_bytes());
+    udp[4..6].copy_from_slice(&udp_len.to_be_bytes());
+
+    // Like everything else that's sent, the report goes out on the management VLAN (if there is
+    // one), built with room for the tag in front of it (see vlan::tag)
+    let mut tagged = [0; vlan::TAG_LEN + FRAME_LEN];
+    let frame = match vlan::id() {
+        Some(id) => {
+            tagged[vlan::TAG_LEN..][..len].copy_from_slice(&frame.data[..len]);
+            let len = vlan::tag(&mut tagged, len, id);
+            &tagged[..len]
+        }
+        None => &frame.data[..len],
+    };
+    unsafe { crate::efm32gg::transmit_raw(frame) };
+}
+
+// The one's complement of the one's complement sum of the header's 16-bit words
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let sum = header.chunks(2).fold(0u32, |sum, word| {
+        sum + u32::from(u16::from_be_bytes([word[0], word[1]]))
+    });
+    let sum = (sum & 0xFFFF) + (sum >> 16);
+    !(((sum & 0xFFFF) + (sum >> 16)) as u16)
+}
+
+struct Frame {
+    data: [u8; FRAME_LEN],
+    len: usize,
+}
+
+impl fmt::Write for Frame {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // The payload may be truncated at any octet
+        let len = cmp::min(s.len(), FRAME_LEN - self.len);
+        self.data[self.len..][..len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
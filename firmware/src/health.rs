@@ -0,0 +1,86 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Counts of the faults that the PHY reports on the link (see `phy::Fault`). A few of these are
+// normal (e.g. the odd receive error), but a burst of them usually points at bad cabling or a
+// misbehaving link partner, so an event is recorded whenever a fault occurs more often than its
+// threshold within a minute. At most one event is recorded for each kind of fault per minute.
+
+use crate::events::{self, Event};
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+use ethernet_phy::Fault;
+use smoltcp::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    counts: [0; Fault::ALL.len()],
+    window_counts: [0; Fault::ALL.len()],
+    window_start: Instant::from_millis_const(0),
+    reported: [false; Fault::ALL.len()],
+}));
+
+struct State {
+    counts: [u32; Fault::ALL.len()],
+
+    // The counts at the start of the current window, and which of the faults have already been
+    // recorded as events within it
+    window_counts: [u32; Fault::ALL.len()],
+    window_start: Instant,
+    reported: [bool; Fault::ALL.len()],
+}
+
+/// Counts an occurrence of the fault. This is called by the PHY's driver as it handles its
+/// interrupts; `check` should be called shortly afterward.
+pub fn fault(fault: Fault) {
+    interrupt::free(|cs| {
+        let count = &mut STATE.borrow(cs).borrow_mut().counts[fault.index()];
+        *count = count.wrapping_add(1);
+    })
+}
+
+/// Records an event for each fault that has exceeded its threshold within the current window.
+pub fn check(now: Instant) {
+    let mut exceeded = [false; Fault::ALL.len()];
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        if now - state.window_start >= WINDOW {
+            state.window_counts = state.counts;
+            state.window_start = now;
+            state.reported = [false; Fault::ALL.len()];
+        }
+
+        for fault in Fault::ALL {
+            let index = fault.index();
+            let count = state.counts[index].wrapping_sub(state.window_counts[index]);
+            if count >= fault.threshold() && !state.reported[index] {
+                state.reported[index] = true;
+                exceeded[index] = true;
+            }
+        }
+    });
+
+    // Recording an event logs it, which is best done outside of the critical section
+    Fault::ALL
+        .iter()
+        .filter(|fault| exceeded[fault.index()])
+        .for_each(|fault| events::record(now, Event::PhyFault(*fault)));
+}
+
+/// Returns the number of times each fault has occurred since boot, in the order of `Fault::ALL`.
+pub fn counts() -> [u32; Fault::ALL.len()] {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().counts)
+}
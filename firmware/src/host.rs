@@ -0,0 +1,183 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Stand-ins for the hardware, so that the firmware's logic can be tested on the host (see
+// `network::Link`), and fuzzed (with the "host" feature). Critical sections, the clock, and the
+// device are all replaced; anything else that touches the hardware (e.g. the store, which is kept
+// in flash) isn't reachable from the tests or the fuzz targets.
+//
+// The firmware keeps its state in statics, which critical sections don't guard on the host (where
+// each test runs on its own thread), so tests that use it hold `exclusive` throughout.
+
+extern crate std;
+
+use crate::efm32gg::Statistics;
+use crate::network::Link;
+use core::sync::atomic::{AtomicU64, Ordering};
+use efm32gg_eth::frame::MAX_FRAME_LEN;
+use ethernet_phy::{LinkDuplex, LinkSpeed, LinkState};
+use smoltcp::phy::{self, Device, DeviceCapabilities};
+use smoltcp::time::Instant;
+use smoltcp::wire::EthernetAddress;
+use std::collections::VecDeque;
+use std::sync::{Mutex, MutexGuard};
+use std::vec;
+use std::vec::Vec;
+
+// cortex-m reaches PRIMASK through assembly shims, which are only built for the target. There
+// are no interrupts to mask on the host, so they're always enabled.
+
+#[no_mangle]
+extern "C" fn __cpsid() {}
+
+#[no_mangle]
+extern "C" fn __cpsie() {}
+
+#[no_mangle]
+extern "C" fn __primask_r() -> u32 {
+    0
+}
+
+// The terminal's commands and built-in variables (e.g. $sp and $prog) reach the stack pointer and
+// the symbols that the linker script defines. Those are stood in for as well, though they mean
+// nothing here and the tests never run anything that reads them.
+
+#[no_mangle]
+extern "C" fn __msp_r() -> u32 {
+    0
+}
+
+#[allow(non_upper_case_globals)]
+#[no_mangle]
+static __sidata: u32 = 0;
+
+#[allow(non_upper_case_globals)]
+#[no_mangle]
+static __sdata: u32 = 0;
+
+#[allow(non_upper_case_globals)]
+#[no_mangle]
+static __edata: u32 = 0;
+
+#[allow(non_upper_case_globals)]
+#[no_mangle]
+static __sheap: u32 = 0;
+
+#[no_mangle]
+static _stack_start: u32 = 0;
+
+static EXCLUSIVE: Mutex<()> = Mutex::new(());
+
+static NOW_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Holds the firmware's state for the duration of a test.
+pub fn exclusive() -> MutexGuard<'static, ()> {
+    // A test that fails while holding the state doesn't stop the others from running
+    EXCLUSIVE.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Returns the number of milliseconds since boot (see `time::now_ms`).
+pub fn now_ms() -> u64 {
+    NOW_MS.load(Ordering::Relaxed)
+}
+
+/// Moves the clock forward.
+pub fn advance(ms: u64) {
+    NOW_MS.fetch_add(ms, Ordering::Relaxed);
+}
+
+/// A device that hands every frame it sends back, with the link always up. Like a wire, it takes
+/// a while: frames that are sent aren't received until they're delivered, so a test can step
+/// through an exchange (e.g. a TCP handshake) one poll at a time.
+#[derive(Default)]
+pub struct Loopback {
+    /// The frames that have been delivered, and not yet received.
+    received: VecDeque<Vec<u8>>,
+    /// The frames that have been sent, and not yet delivered.
+    sent: VecDeque<Vec<u8>>,
+}
+
+impl Loopback {
+    /// Delivers the frames that have been sent so far.
+    pub fn deliver(&mut self) {
+        self.received.append(&mut self.sent);
+    }
+
+    /// Returns true if no frames have been sent or delivered, and not received.
+    pub fn is_idle(&self) -> bool {
+        self.received.is_empty() && self.sent.is_empty()
+    }
+}
+
+pub struct RxToken(Vec<u8>);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(&mut self.0)
+    }
+}
+
+pub struct TxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl<'a> phy::TxToken for TxToken<'a> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut frame = vec![0; len];
+        let result = f(&mut frame);
+        self.0.push_back(frame);
+        result
+    }
+}
+
+impl Device for Loopback {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(&mut self, _: Instant) -> Option<(RxToken, TxToken<'_>)> {
+        let frame = self.received.pop_front()?;
+        Some((RxToken(frame), TxToken(&mut self.sent)))
+    }
+
+    fn transmit(&mut self, _: Instant) -> Option<TxToken<'_>> {
+        Some(TxToken(&mut self.sent))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        // Frames are kept to the MAC's size, so that nothing is sent that the MAC couldn't send
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = MAX_FRAME_LEN - crate::vlan::TAG_LEN;
+        caps
+    }
+}
+
+impl Link for Loopback {
+    fn link_state(&self) -> Option<LinkState> {
+        Some(LinkState {
+            speed: LinkSpeed::HundredMbps,
+            duplex: LinkDuplex::FullDuplex,
+        })
+    }
+
+    fn statistics(&mut self) -> Statistics {
+        Statistics::default()
+    }
+
+    fn join_multicast(&mut self, _: EthernetAddress) {}
+}
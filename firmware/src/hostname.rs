@@ -0,0 +1,138 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The device's hostname, which names it in the terminal's prompt, discovery announcements, the
+// HTTP status, and SNMP's sysName. Unless one has been set (and kept in the store), the name is
+// made from the low bits of the part's unique number (e.g. "poe-1a2b3c").
+
+use crate::store::{self, Key};
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use cortex_m::interrupt::{self, Mutex};
+use ignore_result::Ignore;
+
+/// The longest hostname, which is the longest DNS label.
+pub const MAX_LEN: usize = 63;
+
+static HOSTNAME: Mutex<RefCell<Option<Hostname>>> = Mutex::new(RefCell::new(None));
+
+#[derive(Clone, Copy)]
+pub struct Hostname {
+    name: [u8; MAX_LEN],
+    len: usize,
+}
+
+impl Hostname {
+    // Makes the default name from the part's unique number
+    fn from_unique() -> Hostname {
+        let mut hostname = Hostname {
+            name: [0; MAX_LEN],
+            len: 0,
+        };
+        let unique = crate::efm32gg::devinfo::unique() as u32 & 0x00FF_FFFF;
+        // The name always fits, so this can't fail
+        write!(hostname, "poe-{:06x}", unique).ignore();
+        hostname
+    }
+
+    // Checks that the name is a valid DNS label: letters, digits, and hyphens, with a letter or
+    // digit at either end
+    fn parse(name: &[u8]) -> Result<Hostname, &'static str> {
+        if name.is_empty() {
+            return Err("the hostname can't be empty");
+        }
+        if name.len() > MAX_LEN {
+            return Err("the hostname is too long");
+        }
+        if !name.iter().all(|b| b.is_ascii_alphanumeric() || *b == b'-')
+            || name.first() == Some(&b'-')
+            || name.last() == Some(&b'-')
+        {
+            return Err("the hostname may only have letters, digits, and inner hyphens");
+        }
+
+        let mut hostname = Hostname {
+            name: [0; MAX_LEN],
+            len: name.len(),
+        };
+        hostname.name[..name.len()].copy_from_slice(name);
+        Ok(hostname)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.name[..self.len]
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ASCII is ever accepted (see parse)
+        core::str::from_utf8(self.as_bytes()).unwrap_or_default()
+    }
+}
+
+impl Write for Hostname {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > MAX_LEN {
+            return Err(fmt::Error);
+        }
+        self.name[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+impl fmt::Display for Hostname {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+/// Loads the hostname from the store, or makes the default one. This must be called once at boot.
+pub fn init() {
+    let mut name = [0; MAX_LEN];
+    let hostname = match store::get(Key::Hostname, &mut name) {
+        Some(len) => Hostname::parse(&name[..len]).unwrap_or_else(|_| {
+            log::warn!("Ignoring malformed hostname");
+            Hostname::from_unique()
+        }),
+        None => Hostname::from_unique(),
+    };
+
+    log::info!("Hostname: {}", hostname);
+    interrupt::free(|cs| HOSTNAME.borrow(cs).replace(Some(hostname)));
+}
+
+/// Returns the hostname.
+pub fn get() -> Hostname {
+    interrupt::free(|cs| *HOSTNAME.borrow(cs).borrow()).unwrap_or_else(Hostname::from_unique)
+}
+
+/// Sets the hostname, or goes back to the default if `None`.
+pub fn set(name: Option<&str>) -> Result<(), &'static str> {
+    let hostname = match name {
+        Some(name) => {
+            let hostname = Hostname::parse(name.as_bytes())?;
+            store::set(Key::Hostname, hostname.as_bytes())?;
+            hostname
+        }
+        None => {
+            store::remove(Key::Hostname)?;
+            Hostname::from_unique()
+        }
+    };
+
+    interrupt::free(|cs| HOSTNAME.borrow(cs).replace(Some(hostname)));
+    Ok(())
+}
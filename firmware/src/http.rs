@@ -0,0 +1,761 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A small HTTP/1.1 server, so that integrations don't need the control protocol. It exposes the
+// following endpoints:
+//
+//   GET  /api/status    returns the hostname, addresses, link, uptime, temperature, and port as
+//                       JSON
+//   POST /api/identify  {"state": "on" | "off"}, optionally with a "pattern" (see `identify`)
+//   POST /api/port      {"state": "on" | "off" | "cycle"}
+//   POST /api/login     {"password": ...}, which returns a token and how long it lasts
+//   GET  /api/events    streams the status and operational events (see `events`) to the client
+//   GET  /              the index page, which shows the event stream
+//
+// Once a password is set (see `auth`), the other POST endpoints need the token from a login,
+// presented as "Authorization: Bearer <token>"; requests without a valid token are refused.
+//
+// Each connection carries a single request, which (along with its body) has to fit in
+// `MAX_REQUEST_LEN`; the connection is closed once the response has been sent, unless it's for
+// the event stream. That one stays open as a stream of server-sent events: a "status" event
+// (with the same object as /api/status) whenever the link changes and otherwise every
+// `STATUS_INTERVAL`, and an "event" for each operational event as it's recorded.
+//
+// The pages are built into the image (see build.rs), both as they are and gzipped, and are sent
+// gzipped to clients that accept it. They're tagged with a hash of the build, so a client that
+// already has a page gets "304 Not Modified" until the firmware changes. Requests are
+// subject to the same access control as the control protocol (see `acl`). Request bodies are
+// flat JSON objects whose values are all strings without escapes, which covers everything above.
+
+use crate::events::Entry;
+use crate::identify::Pattern;
+
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use core::str;
+use cortex_m::interrupt::{self, Mutex};
+use ethernet_phy::{LinkDuplex, LinkSpeed, LinkState};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{EthernetAddress, IpAddress, Ipv4Address};
+
+pub const PORT: u16 = 80;
+
+/// The largest request (headers and body) that's accepted.
+pub const MAX_REQUEST_LEN: usize = 1024;
+
+/// The largest response that's sent.
+pub const MAX_RESPONSE_LEN: usize = 1024;
+
+/// The number of connections that are served at once, each on its own socket, so that an event
+/// stream doesn't hold off other requests.
+pub const MAX_CONNECTIONS: usize = 2;
+
+const MAX_BODY_LEN: usize = 768;
+
+// How often the status is sent on an event stream when the link hasn't changed, since the power
+// reading changes with nearly every sample
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+// The tag of every asset, which changes with each build (see build.rs)
+const ETAG: &str = env!("POE_ASSETS_HASH");
+
+const ASSETS: &[Asset] = &[Asset {
+    path: "/",
+    content_type: "text/html; charset=utf-8",
+    plain: include_bytes!(concat!(env!("OUT_DIR"), "/index.html")),
+    gzipped: include_bytes!(concat!(env!("OUT_DIR"), "/index.html.gz")),
+}];
+
+static STREAMS: Mutex<RefCell<[Option<Stream>; MAX_CONNECTIONS]>> =
+    Mutex::new(RefCell::new([None; MAX_CONNECTIONS]));
+
+#[derive(Clone, Copy)]
+enum Stream {
+    Events {
+        // When the status was last sent, and the link at the time
+        sent: Option<(Instant, Option<LinkState>)>,
+        // The sequence number of the next event to send
+        event: u32,
+    },
+    // The part of an asset that has yet to be sent
+    Asset(&'static [u8]),
+}
+
+/// What to do with a connection once its response has been sent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Next {
+    Close,
+    /// Keep the connection open, sending whatever `update_stream` writes.
+    Events,
+    /// Send the rest of an asset with `update_stream`, and then close the connection.
+    Asset(&'static [u8]),
+}
+
+struct Asset {
+    path: &'static str,
+    content_type: &'static str,
+    plain: &'static [u8],
+    gzipped: &'static [u8],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Status {
+    Ok,
+    NoContent,
+    BadRequest,
+    Unauthorized,
+    NotFound,
+    MethodNotAllowed,
+    Conflict,
+    PayloadTooLarge,
+}
+
+impl Status {
+    fn code(&self) -> u16 {
+        match self {
+            Status::Ok => 200,
+            Status::NoContent => 204,
+            Status::BadRequest => 400,
+            Status::Unauthorized => 401,
+            Status::NotFound => 404,
+            Status::MethodNotAllowed => 405,
+            Status::Conflict => 409,
+            Status::PayloadTooLarge => 413,
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self {
+            Status::Ok => "OK",
+            Status::NoContent => "No Content",
+            Status::BadRequest => "Bad Request",
+            Status::Unauthorized => "Unauthorized",
+            Status::NotFound => "Not Found",
+            Status::MethodNotAllowed => "Method Not Allowed",
+            Status::Conflict => "Conflict",
+            Status::PayloadTooLarge => "Payload Too Large",
+        }
+    }
+}
+
+/// The state of the device, as needed to represent the status.
+pub struct Context {
+    pub now: Instant,
+    pub hardware_addr: EthernetAddress,
+    pub address: Option<Ipv4Address>,
+    pub link: Option<LinkState>,
+    pub identifying: bool,
+}
+
+/// Handles the request received so far, writing the response into `response`. Returns the length
+/// of the response and what to do with the connection once it's sent, or `None` if the rest of
+/// the request hasn't been received yet (unless `eof` is set, in which case nothing more will be).
+/// `source` is the client's address, against which failed logins are counted (see `auth`).
+pub fn handle(
+    request: &[u8],
+    eof: bool,
+    response: &mut [u8; MAX_RESPONSE_LEN],
+    context: &Context,
+    source: IpAddress,
+    identify: &mut dyn FnMut(Option<Pattern>),
+) -> Option<(usize, Next)> {
+    let mut body = Body::new();
+    let mut message = Response {
+        buffer: response,
+        len: 0,
+    };
+
+    let status = match parse(request) {
+        Ok(Some(request)) if (request.method, request.path) == ("GET", "/api/events") => {
+            message
+                .write_str(concat!(
+                    "HTTP/1.1 200 OK\r\n",
+                    "Content-Type: text/event-stream\r\n",
+                    "Cache-Control: no-cache\r\n\r\n"
+                ))
+                .ok()?;
+            return Some((message.len, Next::Events));
+        }
+        Ok(Some(request)) if request.method == "GET" => {
+            match ASSETS.iter().find(|asset| asset.path == request.path) {
+                Some(asset) => return serve(&request, asset, &mut message),
+                None => respond(&request, &mut body, context, source, identify),
+            }
+        }
+        Ok(Some(request)) => respond(&request, &mut body, context, source, identify),
+        Ok(None) if request.len() >= MAX_REQUEST_LEN => Err(Status::PayloadTooLarge),
+        Ok(None) if eof => Err(Status::BadRequest),
+        Ok(None) => return None,
+        Err(status) => Err(status),
+    };
+    let status = match status {
+        Ok(status) => status,
+        Err(status) => {
+            body.len = 0;
+            write!(body, r#"{{"error":"{}"}}"#, status.reason()).ok()?;
+            status
+        }
+    };
+
+    write!(
+        message,
+        "HTTP/1.1 {} {}\r\nConnection: close\r\n",
+        status.code(),
+        status.reason()
+    )
+    .ok()?;
+    if status == Status::Unauthorized {
+        message.write_str("WWW-Authenticate: Bearer\r\n").ok()?;
+    }
+    if body.len > 0 {
+        write!(
+            message,
+            "Content-Type: application/json\r\nContent-Length: {}\r\n",
+            body.len
+        )
+        .ok()?;
+    }
+    message.write_str("\r\n").ok()?;
+    message.put(&body.buffer[..body.len])?;
+
+    Some((message.len, Next::Close))
+}
+
+/// Starts streaming the rest of the response on the connection, once its head has been sent.
+pub fn open_stream(connection: usize, next: Next) {
+    let stream = match next {
+        Next::Close => None,
+        Next::Events => Some(Stream::Events {
+            sent: None,
+            event: crate::events::sequence(),
+        }),
+        Next::Asset(data) => Some(Stream::Asset(data)),
+    };
+    interrupt::free(|cs| {
+        if let Some(slot) = STREAMS.borrow(cs).borrow_mut().get_mut(connection) {
+            *slot = stream;
+        }
+    })
+}
+
+/// Ends the stream on the connection, if there is one.
+pub fn close_stream(connection: usize) {
+    interrupt::free(|cs| {
+        if let Some(slot) = STREAMS.borrow(cs).borrow_mut().get_mut(connection) {
+            *slot = None;
+        }
+    })
+}
+
+/// Handles the client closing its end of the connection, returning true if the connection should
+/// be closed too. An event stream ends, but the rest of an asset is still sent.
+pub fn hang_up(connection: usize) -> bool {
+    interrupt::free(|cs| {
+        let mut streams = STREAMS.borrow(cs).borrow_mut();
+        match streams.get_mut(connection) {
+            Some(slot @ Some(Stream::Events { .. })) => {
+                *slot = None;
+                true
+            }
+            Some(Some(Stream::Asset(_))) => false,
+            _ => true,
+        }
+    })
+}
+
+/// Returns true if the connection is carrying a stream.
+pub fn is_streaming(connection: usize) -> bool {
+    interrupt::free(|cs| matches!(STREAMS.borrow(cs).borrow().get(connection), Some(Some(_))))
+}
+
+/// Returns true if any connection is carrying a stream, in which case the network needs to be
+/// handled periodically.
+pub fn streaming() -> bool {
+    interrupt::free(|cs| STREAMS.borrow(cs).borrow().iter().any(Option::is_some))
+}
+
+/// Writes what's due on the connection's stream into `buffer`, as much as fits, returning its
+/// length. Whatever doesn't fit is written next time. The stream ends once all of an asset has
+/// been written, after which the connection should be closed.
+pub fn update_stream(connection: usize, buffer: &mut [u8], context: &Context) -> usize {
+    let stream = interrupt::free(|cs| STREAMS.borrow(cs).borrow().get(connection).copied());
+    let (len, stream) = match stream.flatten() {
+        Some(Stream::Events { sent, event }) => update_events(sent, event, buffer, context),
+        Some(Stream::Asset(data)) => {
+            let len = data.len().min(buffer.len());
+            buffer[..len].copy_from_slice(&data[..len]);
+            match &data[len..] {
+                [] => (len, None),
+                rest => (len, Some(Stream::Asset(rest))),
+            }
+        }
+        None => return 0,
+    };
+
+    interrupt::free(|cs| {
+        // The stream may have been closed in the meantime
+        if let Some(slot @ Some(_)) = STREAMS.borrow(cs).borrow_mut().get_mut(connection) {
+            *slot = stream;
+        }
+    });
+    len
+}
+
+// Writes the status (if it's due) and any new events, returning their length and the stream's new
+// state
+fn update_events(
+    mut sent: Option<(Instant, Option<LinkState>)>,
+    mut event: u32,
+    buffer: &mut [u8],
+    context: &Context,
+) -> (usize, Option<Stream>) {
+    let mut message = Response { buffer, len: 0 };
+
+    let due = match sent {
+        Some((time, link)) => link != context.link || context.now >= time + STATUS_INTERVAL,
+        None => true,
+    };
+    if due {
+        let mut body = Body::new();
+        let written = represent_status(&mut body, context)
+            .ok()
+            .and_then(|_| message.event("status", &body.buffer[..body.len]));
+        if written.is_some() {
+            sent = Some((context.now, context.link));
+        }
+    }
+
+    while let Some((entry, next)) = crate::events::since(event) {
+        let mut body = Body::new();
+        match represent_event(&mut body, &entry) {
+            Ok(()) => match message.event("event", &body.buffer[..body.len]) {
+                Some(()) => event = next,
+                None => break,
+            },
+            Err(_) => event = next,
+        }
+    }
+
+    (message.len, Some(Stream::Events { sent, event }))
+}
+
+struct Request<'a> {
+    method: &'a str,
+    path: &'a str,
+    token: Option<&'a str>,
+    // Whether the client accepts gzipped content
+    gzip: bool,
+    // The value of If-None-Match, a list of the tags of the versions the client already has
+    cached: Option<&'a str>,
+    body: &'a [u8],
+}
+
+// Splits a complete request into its method, path, headers of interest, and body, or returns
+// `None` if the request is incomplete
+fn parse(request: &[u8]) -> Result<Option<Request>, Status> {
+    let end = match request.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(end) => end,
+        None => return Ok(None),
+    };
+    let head = str::from_utf8(&request[..end]).map_err(|_| Status::BadRequest)?;
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (method, path, version) = match (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) {
+        (Some(method), Some(path), Some(version)) => (method, path, version),
+        _ => return Err(Status::BadRequest),
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(Status::BadRequest);
+    }
+
+    // The query string isn't used by any of the endpoints
+    let path = path.split('?').next().unwrap_or_default();
+
+    let (mut content_len, mut token, mut gzip, mut cached) = (0, None, false, None);
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or(Status::BadRequest)?;
+        if name.eq_ignore_ascii_case("content-length") {
+            content_len = value.trim().parse().map_err(|_| Status::BadRequest)?;
+        } else if name.eq_ignore_ascii_case("authorization") {
+            token = value.trim().strip_prefix("Bearer ").map(str::trim);
+        } else if name.eq_ignore_ascii_case("accept-encoding") {
+            gzip = accepts(value, "gzip");
+        } else if name.eq_ignore_ascii_case("if-none-match") {
+            cached = Some(value.trim());
+        }
+    }
+
+    let body = &request[end + 4..];
+    match body.len() >= content_len {
+        true => Ok(Some(Request {
+            method,
+            path,
+            token,
+            gzip,
+            cached,
+            body: &body[..content_len],
+        })),
+        false => Ok(None),
+    }
+}
+
+fn respond(
+    request: &Request,
+    body: &mut Body,
+    context: &Context,
+    source: IpAddress,
+    identify: &mut dyn FnMut(Option<Pattern>),
+) -> Result<Status, Status> {
+    let (path, payload) = (request.path, request.body);
+
+    // Logging in is the one state-changing request that doesn't need a token
+    let guarded = request.method == "POST" && path != "/api/login";
+    if guarded && !crate::auth::verify(request.token, source, context.now) {
+        return Err(Status::Unauthorized);
+    }
+
+    match (request.method, path) {
+        ("GET", "/api/status") => {
+            represent_status(body, context).map_err(|_| Status::PayloadTooLarge)?;
+            Ok(Status::Ok)
+        }
+        ("POST", "/api/identify") => {
+            let (mut state, mut pattern) = (None, None);
+            fields(payload, |name, value| {
+                match name {
+                    "state" => state = Some(value),
+                    "pattern" => pattern = Some(value),
+                    _ => return Err(Status::BadRequest),
+                }
+                Ok(())
+            })?;
+
+            match (state, pattern) {
+                (Some("off"), None) => identify(None),
+                (Some("on"), None) => identify(Some(crate::identify::DEFAULT)),
+                (Some("on"), Some(pattern)) => {
+                    identify(Some(pattern.parse().map_err(|_| Status::BadRequest)?))
+                }
+                _ => return Err(Status::BadRequest),
+            }
+            Ok(Status::NoContent)
+        }
+        ("POST", "/api/port") => {
+            let mut state = None;
+            fields(payload, |name, value| match name {
+                "state" => {
+                    state = Some(value);
+                    Ok(())
+                }
+                _ => Err(Status::BadRequest),
+            })?;
+
+            let result = match state {
+                Some("on") => crate::port::set_enabled(true),
+                Some("off") => crate::port::set_enabled(false),
+                Some("cycle") => crate::port::cycle(crate::port::DEFAULT_CYCLE_TIME),
+                _ => return Err(Status::BadRequest),
+            };
+            match result {
+                Ok(()) => Ok(Status::NoContent),
+                Err(err) => {
+                    log::warn!("Failed to set port: {}", err);
+                    write!(body, r#"{{"error":"{}"}}"#, err).map_err(|_| Status::Conflict)?;
+                    Ok(Status::Conflict)
+                }
+            }
+        }
+        ("POST", "/api/login") => {
+            let mut password = None;
+            fields(payload, |name, value| match name {
+                "password" => {
+                    password = Some(value);
+                    Ok(())
+                }
+                _ => Err(Status::BadRequest),
+            })?;
+
+            let password = password.ok_or(Status::BadRequest)?;
+            let token = crate::auth::login(password, source, context.now).map_err(|err| {
+                log::debug!("Refusing login: {}", err);
+                Status::Unauthorized
+            })?;
+            write!(
+                body,
+                r#"{{"token":"{}","expires_s":{}}}"#,
+                token,
+                crate::auth::LIFETIME.secs()
+            )
+            .map_err(|_| Status::PayloadTooLarge)?;
+            Ok(Status::Ok)
+        }
+        (_, "/")
+        | (_, "/api/status")
+        | (_, "/api/identify")
+        | (_, "/api/port")
+        | (_, "/api/login")
+        | (_, "/api/events") => Err(Status::MethodNotAllowed),
+        _ => Err(Status::NotFound),
+    }
+}
+
+// Writes the head of the response for an asset, which is sent gzipped if the client accepts that,
+// or just the tag if the client already has that version
+fn serve(request: &Request, asset: &Asset, message: &mut Response) -> Option<(usize, Next)> {
+    let (data, encoding, tag) = match request.gzip {
+        true => (asset.gzipped, "Content-Encoding: gzip\r\n", "-gzip"),
+        false => (asset.plain, "", ""),
+    };
+
+    // Any of the listed tags may match, whether or not they're weak
+    let cached = request.cached.map_or(false, |cached| {
+        cached.split(',').any(|candidate| {
+            let candidate = candidate.trim();
+            let candidate = candidate.strip_prefix("W/").unwrap_or(candidate);
+            candidate == "*"
+                || candidate
+                    .strip_prefix('"')
+                    .and_then(|candidate| candidate.strip_suffix('"'))
+                    .and_then(|candidate| candidate.strip_prefix(ETAG))
+                    == Some(tag)
+        })
+    });
+    if cached {
+        write!(
+            message,
+            concat!(
+                "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n",
+                "ETag: \"{}{}\"\r\nVary: Accept-Encoding\r\n\r\n"
+            ),
+            ETAG, tag
+        )
+        .ok()?;
+        return Some((message.len, Next::Close));
+    }
+
+    write!(
+        message,
+        concat!(
+            "HTTP/1.1 200 OK\r\nConnection: close\r\n",
+            "Content-Type: {}\r\nContent-Length: {}\r\n{}",
+            "ETag: \"{}{}\"\r\nCache-Control: no-cache\r\nVary: Accept-Encoding\r\n\r\n"
+        ),
+        asset.content_type,
+        data.len(),
+        encoding,
+        ETAG,
+        tag
+    )
+    .ok()?;
+    Some((message.len, Next::Asset(data)))
+}
+
+// Returns true if an Accept-Encoding header lists `coding` without refusing it (i.e. "q=0")
+fn accepts(header: &str, coding: &str) -> bool {
+    header.split(',').any(|entry| {
+        let mut parameters = entry.split(';');
+        let name = parameters.next().unwrap_or_default().trim();
+        let refused = parameters.any(|parameter| {
+            let quality = parameter.trim().strip_prefix("q=");
+            quality.and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+        });
+        name.eq_ignore_ascii_case(coding) && !refused
+    })
+}
+
+fn represent_status(out: &mut Body, context: &Context) -> fmt::Result {
+    write!(out, r#"{{"version":"{}""#, env!("CARGO_PKG_VERSION"))?;
+    // Hostnames are only letters, digits, and hyphens, so they never need escaping
+    write!(out, r#","hostname":"{}""#, crate::hostname::get())?;
+    write!(out, r#","mac":"{}""#, context.hardware_addr)?;
+    match context.address {
+        Some(address) => write!(out, r#","ip":"{}""#, address)?,
+        None => write!(out, r#","ip":null"#)?,
+    }
+    write!(out, r#","uptime_s":{}"#, context.now.secs())?;
+    let lifetime = crate::lifetime::counters(context.now);
+    write!(
+        out,
+        r#","lifetime":{{"boots":{},"watchdog_resets":{},"uptime_s":{},"link_flaps":{}}}"#,
+        lifetime.boots, lifetime.watchdog_resets, lifetime.uptime_s, lifetime.link_flaps
+    )?;
+
+    match &context.link {
+        Some(LinkState { speed, duplex }) => {
+            let speed = match speed {
+                LinkSpeed::TenMbps => 10,
+                LinkSpeed::HundredMbps => 100,
+            };
+            let duplex = match duplex {
+                LinkDuplex::HalfDuplex => "half",
+                LinkDuplex::FullDuplex => "full",
+            };
+            write!(
+                out,
+                r#","link":{{"up":true,"speed_mbps":{},"duplex":"{}"}}"#,
+                speed, duplex
+            )?;
+        }
+        None => write!(out, r#","link":{{"up":false}}"#)?,
+    }
+
+    match crate::sensors::temperature_c() {
+        Some(temperature) => write!(out, r#","temperature_c":{:.1}"#, temperature)?,
+        None => write!(out, r#","temperature_c":null"#)?,
+    }
+    write!(out, r#","identify":{}"#, context.identifying)?;
+
+    write!(out, r#","port":{{"#)?;
+    if let Some(status) = crate::port::status() {
+        write!(
+            out,
+            r#""enabled":{},"powered":{},"#,
+            status.enabled, status.powered
+        )?;
+    }
+    if let Some(reading) = crate::port::meter::reading() {
+        write!(
+            out,
+            r#""current_ma":{},"power_mw":{},"energy_mwh":{},"#,
+            reading.current_ma, reading.power_mw, reading.energy_mwh
+        )?;
+    }
+    match crate::lldp::status().allocated_mw {
+        Some(allocated) => write!(out, r#""allocated_mw":{}}}}}"#, allocated),
+        None => write!(out, r#""allocated_mw":null}}}}"#),
+    }
+}
+
+fn represent_event(out: &mut Body, entry: &Entry) -> fmt::Result {
+    write!(
+        out,
+        r#"{{"boot":{},"time_ms":{},"severity":"{}","code":{},"message":"{}"}}"#,
+        entry.boot,
+        entry.timestamp.total_millis(),
+        entry.event.severity(),
+        entry.event.code(),
+        entry.event
+    )
+}
+
+// Calls `f` with each of the names and values in a flat JSON object of strings
+fn fields<'a, F>(payload: &'a [u8], mut f: F) -> Result<(), Status>
+where
+    F: FnMut(&'a str, &'a str) -> Result<(), Status>,
+{
+    let payload = str::from_utf8(payload).map_err(|_| Status::BadRequest)?;
+    let mut rest = payload
+        .trim()
+        .strip_prefix('{')
+        .and_then(|rest| rest.strip_suffix('}'))
+        .ok_or(Status::BadRequest)?
+        .trim_start();
+
+    while !rest.is_empty() {
+        let (name, after) = string(rest)?;
+        let after = after
+            .trim_start()
+            .strip_prefix(':')
+            .ok_or(Status::BadRequest)?;
+        let (value, after) = string(after.trim_start())?;
+        f(name, value)?;
+
+        let after = after.trim_start();
+        rest = match after.strip_prefix(',') {
+            Some(after) => after.trim_start(),
+            None if after.is_empty() => after,
+            None => return Err(Status::BadRequest),
+        };
+    }
+
+    Ok(())
+}
+
+// Splits a leading JSON string (without escapes) from the rest of the input
+fn string(input: &str) -> Result<(&str, &str), Status> {
+    let input = input.strip_prefix('"').ok_or(Status::BadRequest)?;
+    let end = input.find('"').ok_or(Status::BadRequest)?;
+    match input[..end].contains('\\') {
+        true => Err(Status::BadRequest),
+        false => Ok((&input[..end], &input[end + 1..])),
+    }
+}
+
+struct Body {
+    buffer: [u8; MAX_BODY_LEN],
+    len: usize,
+}
+
+impl Body {
+    fn new() -> Body {
+        Body {
+            buffer: [0; MAX_BODY_LEN],
+            len: 0,
+        }
+    }
+}
+
+impl fmt::Write for Body {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let remaining = &mut self.buffer[self.len..];
+        if s.len() > remaining.len() {
+            return Err(fmt::Error);
+        }
+
+        remaining[..s.len()].copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
+}
+
+struct Response<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl Response<'_> {
+    fn put(&mut self, bytes: &[u8]) -> Option<()> {
+        self.buffer
+            .get_mut(self.len..self.len + bytes.len())?
+            .copy_from_slice(bytes);
+        self.len += bytes.len();
+        Some(())
+    }
+
+    // Writes a server-sent event, or nothing if it doesn't fit
+    fn event(&mut self, name: &str, data: &[u8]) -> Option<()> {
+        let len = "event: \ndata: \n\n".len() + name.len() + data.len();
+        if self.len + len > self.buffer.len() {
+            return None;
+        }
+
+        self.put(b"event: ")?;
+        self.put(name.as_bytes())?;
+        self.put(b"\ndata: ")?;
+        self.put(data)?;
+        self.put(b"\n\n")
+    }
+}
+
+impl fmt::Write for Response<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.put(s.as_bytes()).ok_or(fmt::Error)
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// ICMP (and ICMPv6) accounting. smoltcp answers every echo request itself and has no way to turn
+// that off, so the driver hands each received frame to `receive` first, which counts echo
+// requests and drops those that exceed the configured rate (see ratelimit). Transmitted frames are passed to
+// `transmit` so that the replies and unreachable messages generated by smoltcp are counted too.
+
+use crate::ratelimit::{self, Class, Limit};
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+use smoltcp::time::Instant;
+use smoltcp::wire::{
+    EthernetFrame, EthernetProtocol, Icmpv4Message, Icmpv4Packet, Icmpv6Message, Icmpv6Packet,
+    IpProtocol, Ipv4Packet, Ipv6Packet,
+};
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    statistics: Statistics {
+        echo_requests: 0,
+        echo_replies: 0,
+        echo_dropped: 0,
+        unreachable: 0,
+    },
+}));
+
+struct State {
+    statistics: Statistics,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Statistics {
+    /// The number of echo requests received.
+    pub echo_requests: u32,
+    /// The number of echo replies sent.
+    pub echo_replies: u32,
+    /// The number of echo requests that were dropped instead of answered.
+    pub echo_dropped: u32,
+    /// The number of destination unreachable messages sent.
+    pub unreachable: u32,
+}
+
+enum Message {
+    EchoRequest,
+    EchoReply,
+    Unreachable,
+}
+
+/// Limits the number of echo requests answered each second, allowing a second's worth at once;
+/// `None` answers all of them and zero answers none.
+pub fn set_echo_limit(limit: Option<u32>) {
+    ratelimit::set_limit(Class::Echo, limit.map(|rate| Limit { rate, burst: rate }))
+}
+
+pub fn echo_limit() -> Option<u32> {
+    ratelimit::limit(Class::Echo).map(|limit| limit.rate)
+}
+
+pub fn statistics() -> Statistics {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().statistics)
+}
+
+/// Accounts for a received frame, returning false if it should be dropped rather than passed to
+/// the network stack.
+pub fn receive(now: Instant, frame: &[u8]) -> bool {
+    if !matches!(classify(frame), Some(Message::EchoRequest)) {
+        return true;
+    }
+
+    let allowed = ratelimit::allow(Class::Echo, now);
+    interrupt::free(|cs| {
+        let statistics = &mut STATE.borrow(cs).borrow_mut().statistics;
+        statistics.echo_requests = statistics.echo_requests.wrapping_add(1);
+        if !allowed {
+            statistics.echo_dropped = statistics.echo_dropped.wrapping_add(1);
+        }
+    });
+    allowed
+}
+
+/// Accounts for a transmitted frame.
+pub fn transmit(frame: &[u8]) {
+    let message = classify(frame);
+    interrupt::free(|cs| {
+        let statistics = &mut STATE.borrow(cs).borrow_mut().statistics;
+        match message {
+            Some(Message::EchoReply) => {
+                statistics.echo_replies = statistics.echo_replies.wrapping_add(1)
+            }
+            Some(Message::Unreachable) => {
+                statistics.unreachable = statistics.unreachable.wrapping_add(1)
+            }
+            Some(Message::EchoRequest) | None => {}
+        }
+    })
+}
+
+fn classify(frame: &[u8]) -> Option<Message> {
+    let frame = EthernetFrame::new_checked(frame).ok()?;
+    match frame.ethertype() {
+        EthernetProtocol::Ipv4 => {
+            let packet = Ipv4Packet::new_checked(frame.payload()).ok()?;
+            if packet.next_header() != IpProtocol::Icmp || packet.frag_offset() != 0 {
+                return None;
+            }
+            match Icmpv4Packet::new_checked(packet.payload()).ok()?.msg_type() {
+                Icmpv4Message::EchoRequest => Some(Message::EchoRequest),
+                Icmpv4Message::EchoReply => Some(Message::EchoReply),
+                Icmpv4Message::DstUnreachable => Some(Message::Unreachable),
+                _ => None,
+            }
+        }
+        EthernetProtocol::Ipv6 => {
+            let packet = Ipv6Packet::new_checked(frame.payload()).ok()?;
+            if packet.next_header() != IpProtocol::Icmpv6 {
+                return None;
+            }
+            match Icmpv6Packet::new_checked(packet.payload()).ok()?.msg_type() {
+                Icmpv6Message::EchoRequest => Some(Message::EchoRequest),
+                Icmpv6Message::EchoReply => Some(Message::EchoReply),
+                Icmpv6Message::DstUnreachable => Some(Message::Unreachable),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
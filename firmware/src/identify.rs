@@ -0,0 +1,302 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The patterns flashed by the "Identify" LED. Each binary owns its LED and flashes the pattern with
+// an indicator (see `led_manager`), recording which pattern is being shown with `set_active` so
+// that it can be reported. Patterns with a count end on their own.
+//
+// Patterns are written as "sos" or "blink [<rate Hz> [<duty %> [<count>]]]", where the rate
+// defaults to 2 Hz, the duty to 50 %, and the count to forever.
+//
+// The active pattern is kept in the store, so that a device being identified mid-install keeps
+// flashing after losing power; the binaries restore it at boot. Since store writes block, changes
+// are only written (by `persist`) once the pattern has stayed the same for `SETTLE`.
+
+use crate::store::{self, Key};
+use core::cell::RefCell;
+use core::fmt;
+use core::str::FromStr;
+use cortex_m::interrupt::{self, Mutex};
+use smoltcp::time::{Duration, Instant};
+
+/// The pattern used when identification is simply turned on.
+pub const DEFAULT: Pattern = Pattern::Blink {
+    rate_hz: DEFAULT_RATE_HZ,
+    duty: DEFAULT_DUTY,
+    count: None,
+};
+
+const DEFAULT_RATE_HZ: f32 = 2.0;
+const DEFAULT_DUTY: u8 = 50;
+
+const MIN_RATE_HZ: f32 = 0.1;
+const MAX_RATE_HZ: f32 = 20.0;
+
+// The length of a dot, and the on and off times of "SOS", in dots
+const SOS_UNIT_MS: u64 = 200;
+const SOS: [u64; 18] = [1, 1, 1, 1, 1, 3, 3, 1, 3, 1, 3, 3, 1, 1, 1, 1, 1, 7];
+
+// How long the pattern has to stay the same before it's written to the store
+const SETTLE: Duration = Duration::from_secs(5);
+
+// The stored forms of the patterns (see `encode`)
+const STORED_BLINK: u8 = 1;
+const STORED_SOS: u8 = 2;
+const STORED_LEN: usize = 8;
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    pattern: None,
+    requested: None,
+    saved: None,
+    changed: false,
+    since: Instant::from_millis_const(0),
+}));
+
+struct State {
+    pattern: Option<Pattern>,
+    // A pattern requested from the terminal, which has to be started by the binary
+    requested: Option<Option<Pattern>>,
+    // The pattern in the store
+    saved: Option<Pattern>,
+    // Set by set_active, until persist notices the change and restarts the settling time
+    changed: bool,
+    // When persist last noticed a change
+    since: Instant,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pattern {
+    Blink {
+        rate_hz: f32,
+        /// The percentage of each period that the LED is on.
+        duty: u8,
+        /// The number of blinks before the pattern ends, or `None` to blink until stopped.
+        count: Option<u16>,
+    },
+    Sos,
+}
+
+impl Pattern {
+    /// Creates a blinking pattern, checking that it can be shown.
+    pub fn blink(rate_hz: f32, duty: u8, count: Option<u16>) -> Result<Pattern, &'static str> {
+        if !(MIN_RATE_HZ..=MAX_RATE_HZ).contains(&rate_hz) {
+            return Err("rate must be between 0.1 and 20 Hz");
+        }
+        if !(1..=99).contains(&duty) {
+            return Err("duty must be between 1 and 99 %");
+        }
+        if count == Some(0) {
+            return Err("count must be at least 1");
+        }
+
+        Ok(Pattern::Blink {
+            rate_hz,
+            duty,
+            count,
+        })
+    }
+
+    /// Returns whether the LED is on during the given step (counting from zero) and for how long,
+    /// or `None` if the pattern has ended.
+    pub fn step(&self, step: u32) -> Option<(bool, Duration)> {
+        let on = step % 2 == 0;
+        match *self {
+            Pattern::Blink {
+                rate_hz,
+                duty,
+                count,
+            } => {
+                if matches!(count, Some(count) if step >= 2 * u32::from(count)) {
+                    return None;
+                }
+
+                let period_ms = (1000.0 / rate_hz) as u64;
+                let on_ms = period_ms * u64::from(duty) / 100;
+                match on {
+                    true => Some((true, Duration::from_millis(on_ms))),
+                    false => Some((false, Duration::from_millis(period_ms - on_ms))),
+                }
+            }
+            Pattern::Sos => {
+                let units = SOS[step as usize % SOS.len()];
+                Some((on, Duration::from_millis(units * SOS_UNIT_MS)))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pattern::Blink {
+                rate_hz,
+                duty,
+                count: None,
+            } => write!(f, "blink {} {}", rate_hz, duty),
+            Pattern::Blink {
+                rate_hz,
+                duty,
+                count: Some(count),
+            } => write!(f, "blink {} {} {}", rate_hz, duty, count),
+            Pattern::Sos => f.pad("sos"),
+        }
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = &'static str;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        Pattern::from_words(pattern.split_whitespace())
+    }
+}
+
+impl Pattern {
+    /// Parses a pattern that has already been split into words.
+    pub fn from_words<'a, I: Iterator<Item = &'a str>>(
+        mut words: I,
+    ) -> Result<Pattern, &'static str> {
+        match words.next() {
+            Some("sos") if words.next().is_none() => Ok(Pattern::Sos),
+            Some("blink") => {
+                let rate_hz = match words.next() {
+                    Some(rate) => rate.parse().map_err(|_| "invalid rate")?,
+                    None => DEFAULT_RATE_HZ,
+                };
+                let duty = match words.next() {
+                    Some(duty) => duty.parse().map_err(|_| "invalid duty")?,
+                    None => DEFAULT_DUTY,
+                };
+                let count = match words.next() {
+                    Some(count) => Some(count.parse().map_err(|_| "invalid count")?),
+                    None => None,
+                };
+                match words.next() {
+                    Some(_) => Err("too many arguments to blink"),
+                    None => Pattern::blink(rate_hz, duty, count),
+                }
+            }
+            _ => Err("pattern must be 'sos' or 'blink [<rate Hz> [<duty %> [<count>]]]'"),
+        }
+    }
+}
+
+/// Records the pattern being shown, or that none is (including once a pattern has ended).
+pub fn set_active(pattern: Option<Pattern>) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.pattern = pattern;
+        state.changed = true;
+    })
+}
+
+/// Returns the pattern being shown, if any.
+pub fn active() -> Option<Pattern> {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().pattern)
+}
+
+/// Asks the binary to start showing the pattern (or stop, if `None`). This is for callers that
+/// can't reach the LED, like the terminal.
+pub fn request(pattern: Option<Pattern>) {
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().requested = Some(pattern))
+}
+
+/// Returns the most recently requested pattern, if there's been a request since the last call.
+pub fn take_request() -> Option<Option<Pattern>> {
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().requested.take())
+}
+
+/// Returns the pattern that was being shown when the device last had power, which the binary needs
+/// to start showing. This must be called once at boot.
+pub fn restore() -> Option<Pattern> {
+    let mut value = [0; STORED_LEN];
+    let pattern = match store::get(Key::Identify, &mut value) {
+        Some(len) => match decode(&value[..len]) {
+            Some(pattern) => Some(pattern),
+            None => {
+                log::warn!("Ignoring malformed identify pattern");
+                None
+            }
+        },
+        None => None,
+    };
+
+    if let Some(pattern) = pattern {
+        log::info!("Restoring identify pattern: {}", pattern);
+    }
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().saved = pattern);
+    pattern
+}
+
+/// Writes the active pattern to the store, once it has settled. This blocks while the flash is
+/// written, so it should be called periodically from a low-priority task.
+pub fn persist(now: Instant) {
+    let settled = interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        if core::mem::take(&mut state.changed) {
+            state.since = now;
+        }
+        match state.pattern != state.saved && now - state.since >= SETTLE {
+            true => Some(state.pattern),
+            false => None,
+        }
+    });
+    let pattern = match settled {
+        Some(pattern) => pattern,
+        None => return,
+    };
+
+    let result = match pattern {
+        Some(pattern) => store::set(Key::Identify, &encode(pattern)),
+        None => store::remove(Key::Identify),
+    };
+    match result {
+        Ok(()) => interrupt::free(|cs| STATE.borrow(cs).borrow_mut().saved = pattern),
+        Err(err) => log::warn!("Failed to save identify pattern: {}", err),
+    }
+}
+
+// Blinks are stored as their rate (an f32), duty, and count (zero for forever), little-endian
+fn encode(pattern: Pattern) -> [u8; STORED_LEN] {
+    let mut value = [0; STORED_LEN];
+    match pattern {
+        Pattern::Blink {
+            rate_hz,
+            duty,
+            count,
+        } => {
+            value[0] = STORED_BLINK;
+            value[1..5].copy_from_slice(&rate_hz.to_le_bytes());
+            value[5] = duty;
+            value[6..8].copy_from_slice(&count.unwrap_or(0).to_le_bytes());
+        }
+        Pattern::Sos => value[0] = STORED_SOS,
+    }
+    value
+}
+
+fn decode(value: &[u8]) -> Option<Pattern> {
+    match *value {
+        [STORED_BLINK, r0, r1, r2, r3, duty, c0, c1] => {
+            let count = match u16::from_le_bytes([c0, c1]) {
+                0 => None,
+                count => Some(count),
+            };
+            Pattern::blink(f32::from_le_bytes([r0, r1, r2, r3]), duty, count).ok()
+        }
+        [STORED_SOS, ..] => Some(Pattern::Sos),
+        _ => None,
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The state machines behind the board's indicator LEDs. An `Indicator` shows a `Mode` on anything
+// that can be lit (see `Light`); the binary owns the indicator and a task that calls `step`
+// whenever the previous step's time is up. Single-color LEDs are lights as they are; boards with
+// RGB LEDs wrap them in a type that picks the color.
+
+use crate::identify::Pattern;
+use crate::network;
+use embedded_hal::digital::v2::OutputPin;
+use led::mono::{self, CommonAnodeLED};
+use smoltcp::time::Duration;
+
+// The occulting pattern is a long period on, followed by a number of short periods off
+const OCCULT_LONG: Duration = Duration::from_millis(1000);
+const OCCULT_SHORT: Duration = Duration::from_millis(250);
+
+/// An LED that can be turned on and off.
+pub trait Light {
+    fn set_lit(&mut self, lit: bool);
+}
+
+impl<P: OutputPin> Light for CommonAnodeLED<P> {
+    fn set_lit(&mut self, lit: bool) {
+        self.set(match lit {
+            true => mono::State::On,
+            false => mono::State::Off,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Solid(bool),
+    /// On, but briefly turned off the given number of times every couple of seconds.
+    Occulting(u8),
+    /// Flashing the pattern, which turns off once the pattern ends.
+    Flash(Pattern),
+}
+
+impl Mode {
+    /// The mode that shows the state of the network: solid while starting up, off once
+    /// operational, and otherwise occulting once for no link, twice for no DHCP lease, three
+    /// times for no gateway, and four times when the DHCP server refused the lease.
+    pub fn network(state: network::State) -> Mode {
+        match state {
+            network::State::Uninit => Mode::Solid(true),
+            network::State::NoLink => Mode::Occulting(1),
+            network::State::NoDhcp => Mode::Occulting(2),
+            network::State::NoGateway => Mode::Occulting(3),
+            network::State::DhcpRefused => Mode::Occulting(4),
+            network::State::Operational => Mode::Solid(false),
+        }
+    }
+}
+
+pub struct Indicator<L: Light> {
+    light: L,
+    mode: Mode,
+    // The number of steps taken through the mode
+    step: u32,
+}
+
+impl<L: Light> Indicator<L> {
+    pub fn new(light: L, mode: Mode) -> Indicator<L> {
+        Indicator {
+            light,
+            mode,
+            step: 0,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Starts showing the mode from its beginning. The caller should cancel any pending step and
+    /// then step right away.
+    pub fn show(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.step = 0;
+    }
+
+    /// Sets the light for the next step of the mode, returning how long until the following step,
+    /// or `None` if the light now stays as it is (because the mode is solid, or has ended).
+    pub fn step(&mut self) -> Option<Duration> {
+        let step = self.step;
+        self.step = self.step.wrapping_add(1);
+
+        let (lit, hold) = match self.mode {
+            Mode::Solid(lit) => (lit, None),
+            Mode::Occulting(flashes) => match step % (1 + 2 * u32::from(flashes)) {
+                0 => (true, Some(OCCULT_LONG)),
+                n => (n % 2 == 0, Some(OCCULT_SHORT)),
+            },
+            Mode::Flash(pattern) => match pattern.step(step) {
+                Some((lit, hold)) => (lit, Some(hold)),
+                None => {
+                    self.mode = Mode::Solid(false);
+                    (false, None)
+                }
+            },
+        };
+        self.light.set_lit(lit);
+        hold
+    }
+}
@@ -15,9 +15,53 @@
 
 #![no_std]
 
+pub mod acl;
+pub mod auth;
+pub mod blobs;
+pub mod boot;
+pub mod button;
+pub mod capture;
+pub mod coap;
+pub mod config;
+pub mod console;
+pub mod dhcp;
+pub mod discovery;
+pub mod eee;
 pub mod efm32gg;
-pub mod ksz8091;
+pub mod error;
+pub mod events;
+pub mod fault;
+pub mod health;
+#[cfg(any(test, feature = "host"))]
+pub mod host;
+pub mod hostname;
+pub mod http;
+pub mod icmp;
+pub mod identify;
+pub mod led_manager;
+pub mod lifetime;
+pub mod lldp;
 pub mod log;
-pub mod mac;
+pub mod media;
+pub mod memtest;
+pub mod modbus;
+pub mod neighbors;
 pub mod network;
-pub mod phy;
+pub mod nor;
+pub mod port;
+pub mod ptp;
+pub mod ratelimit;
+pub mod routes;
+pub mod selftest;
+pub mod sensors;
+pub mod signing;
+pub mod slaac;
+pub mod snmp;
+pub mod stack;
+pub mod store;
+pub mod tftp;
+pub mod thumb;
+pub mod time;
+pub mod vlan;
+pub mod wol;
+pub mod work;
@@ -0,0 +1,175 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Counters that cover the whole life of the device, rather than the time since the last reset:
+// the number of boots, how many of those were caused by the watchdog, the total time spent
+// running, and the number of times the link was lost. Unlike the reset counters (see rmu), these
+// are kept in the store, so they survive losing power.
+//
+// Since store writes block and wear the flash, the counters are only written (by `persist`) once
+// they've changed and `SETTLE` has passed since the last write, or every `INTERVAL` to keep the
+// running time current. The first write after boot is made right away, so that every boot is
+// counted even if the device never stays up long. Anything counted since the last write is lost
+// along with power, so the running time can fall short by up to `INTERVAL` per power cycle.
+
+use crate::efm32gg::rmu::{self, Cause};
+use crate::network::events::Event;
+use crate::store::{self, Key};
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+use smoltcp::time::{Duration, Instant};
+
+// The least time between writes of changed counters
+const SETTLE: Duration = Duration::from_secs(60);
+
+// The most time between writes, even if nothing else has changed
+const INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// The counters are stored as boots, watchdog resets, and link flaps (each a u32), then the running
+// time in seconds (a u64), little-endian
+const STORED_LEN: usize = 20;
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    counters: Counters {
+        boots: 0,
+        watchdog_resets: 0,
+        uptime_s: 0,
+        link_flaps: 0,
+    },
+    changed: false,
+    written: None,
+}));
+
+struct State {
+    // The counters as of boot, except for the running time, which is as of the last boot
+    counters: Counters,
+    // Set whenever a counter changes, until persist writes it
+    changed: bool,
+    // When persist last wrote the counters
+    written: Option<Instant>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counters {
+    pub boots: u32,
+    pub watchdog_resets: u32,
+    /// The total time spent running, in seconds.
+    pub uptime_s: u64,
+    /// The number of times the link went down.
+    pub link_flaps: u32,
+}
+
+/// Loads the counters from the store and counts this boot. This must be called once at boot,
+/// after the reset cause is known (see `rmu::init`).
+pub fn init() {
+    let mut value = [0; STORED_LEN];
+    let mut counters = match store::get(Key::Lifetime, &mut value) {
+        Some(len) => match decode(&value[..len]) {
+            Some(counters) => counters,
+            None => {
+                log::warn!("Ignoring malformed lifetime counters");
+                Counters::default()
+            }
+        },
+        None => Counters::default(),
+    };
+
+    counters.boots = counters.boots.saturating_add(1);
+    if rmu::last() == Cause::Watchdog {
+        counters.watchdog_resets = counters.watchdog_resets.saturating_add(1);
+    }
+    log::info!(
+        "Boot {} ({} by the watchdog)",
+        counters.boots,
+        counters.watchdog_resets
+    );
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.counters = counters;
+        state.changed = true;
+    })
+}
+
+/// Counts each time the link goes down. This is subscribed as a network observer.
+pub fn observe(_: Instant, event: Event) {
+    if event == Event::LinkDown {
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+            state.counters.link_flaps = state.counters.link_flaps.saturating_add(1);
+            state.changed = true;
+        })
+    }
+}
+
+/// Returns the counters, including the time spent running since boot.
+pub fn counters(now: Instant) -> Counters {
+    let mut counters = interrupt::free(|cs| STATE.borrow(cs).borrow().counters);
+    counters.uptime_s = counters.uptime_s.saturating_add(now.secs() as u64);
+    counters
+}
+
+/// Writes the counters to the store, if they're due. This blocks while the flash is written, so it
+/// should be called periodically from a low-priority task.
+pub fn persist(now: Instant) {
+    let due = interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        match state.written {
+            None => true,
+            Some(written) if state.changed => now - written >= SETTLE,
+            Some(written) => now - written >= INTERVAL,
+        }
+    });
+    if !due {
+        return;
+    }
+
+    // The flag is cleared before the counters are read, so that a change made during the write is
+    // caught by the next one
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().changed = false);
+    match store::set(Key::Lifetime, &encode(counters(now))) {
+        Ok(()) => interrupt::free(|cs| STATE.borrow(cs).borrow_mut().written = Some(now)),
+        Err(err) => {
+            log::warn!("Failed to save lifetime counters: {}", err);
+            interrupt::free(|cs| STATE.borrow(cs).borrow_mut().changed = true);
+        }
+    }
+}
+
+fn encode(counters: Counters) -> [u8; STORED_LEN] {
+    let mut value = [0; STORED_LEN];
+    value[0..4].copy_from_slice(&counters.boots.to_le_bytes());
+    value[4..8].copy_from_slice(&counters.watchdog_resets.to_le_bytes());
+    value[8..12].copy_from_slice(&counters.link_flaps.to_le_bytes());
+    value[12..20].copy_from_slice(&counters.uptime_s.to_le_bytes());
+    value
+}
+
+fn decode(value: &[u8]) -> Option<Counters> {
+    if value.len() != STORED_LEN {
+        return None;
+    }
+
+    let u32_at =
+        |i: usize| u32::from_le_bytes([value[i], value[i + 1], value[i + 2], value[i + 3]]);
+    let mut uptime_s = [0; 8];
+    uptime_s.copy_from_slice(&value[12..20]);
+    Some(Counters {
+        boots: u32_at(0),
+        watchdog_resets: u32_at(4),
+        uptime_s: u64::from_le_bytes(uptime_s),
+        link_flaps: u32_at(8),
+    })
+}
@@ -0,0 +1,334 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Power is negotiated with the upstream PSE using LLDP-MED (ANSI/TIA-1057). Once the link is up,
+// an LLDPDU advertising the requested power (as a class I endpoint with the Extended Power-via-MDI
+// TLV) is sent every second for the first few frames and then every TX_INTERVAL. The PSE answers
+// with its own Extended Power-via-MDI TLV, which carries the power allocated to this device. The
+// allocation lasts for the TTL of the PSE's LLDPDU, and until one is received (or after it
+// expires), only the power guaranteed by physical classification is assumed to be available.
+//
+// The downstream port gets whatever remains of the allocation after the board's own consumption,
+// which is enforced as a current limit (see port::protect).
+
+use core::cell::RefCell;
+use core::fmt;
+use cortex_m::interrupt::{self, Mutex};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::EthernetAddress;
+
+pub const ETHERTYPE: u16 = 0x88CC;
+
+/// The length of the LLDPDUs that are sent (padded to the minimum frame size).
+pub const FRAME_LEN: usize = 60;
+
+/// The default amount of power requested from the PSE, in milliwatts. This is the most that a
+/// PoE+ PD may draw.
+pub const DEFAULT_REQUEST_MW: u32 = 25_500;
+
+/// The power that's available without an allocation from the PSE, in milliwatts. This is what a
+/// class 0 PD is guaranteed.
+pub const DEFAULT_BUDGET_MW: u32 = 12_950;
+
+// The power consumed by the board itself, which isn't available to the downstream port
+const OWN_MW: u32 = 1_500;
+
+// The nearest-bridge group address
+const DESTINATION: EthernetAddress = EthernetAddress([0x01, 0x80, 0xC2, 0x00, 0x00, 0x0E]);
+
+const TTL_SECS: u16 = 120;
+const TX_INTERVAL: Duration = Duration::from_secs(30);
+const FAST_TX_INTERVAL: Duration = Duration::from_secs(1);
+const FAST_TX_COUNT: u8 = 4;
+
+const TLV_END: u8 = 0;
+const TLV_CHASSIS_ID: u8 = 1;
+const TLV_PORT_ID: u8 = 2;
+const TLV_TTL: u8 = 3;
+const TLV_ORG_SPECIFIC: u8 = 127;
+
+const CHASSIS_ID_MAC_ADDRESS: u8 = 4;
+const PORT_ID_MAC_ADDRESS: u8 = 3;
+
+const MED_OUI: [u8; 3] = [0x00, 0x12, 0xBB];
+const MED_CAPABILITIES: u8 = 1;
+const MED_EXTENDED_POWER: u8 = 4;
+
+// LLDP-MED capabilities and extended power via MDI (PD)
+const MED_CAPABILITY_BITS: u16 = 1 << 0 | 1 << 4;
+const MED_CLASS_I: u8 = 1;
+
+// Power type (PD), power source (PSE), and power priority (low)
+const MED_POWER_PD: u8 = 0b01 << 6 | 0b01 << 4 | 0b0011;
+const MED_POWER_TYPE_MASK: u8 = 0b11 << 6;
+const MED_POWER_TYPE_PSE: u8 = 0;
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    link: false,
+    requested_mw: DEFAULT_REQUEST_MW,
+    allocation: None,
+    next_tx: Instant::from_millis_const(0),
+    fast_tx: 0,
+}));
+
+struct State {
+    link: bool,
+    requested_mw: u32,
+    allocation: Option<Allocation>,
+    next_tx: Instant,
+    fast_tx: u8,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Allocation {
+    mw: u32,
+    expires: Instant,
+}
+
+impl State {
+    fn budget_mw(&self) -> u32 {
+        self.allocation
+            .map_or(DEFAULT_BUDGET_MW, |allocation| allocation.mw)
+            .saturating_sub(OWN_MW)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Negotiation {
+    NoLink,
+    Requesting,
+    Granted,
+    Partial,
+}
+
+impl fmt::Display for Negotiation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Negotiation::NoLink => "no link",
+            Negotiation::Requesting => "requesting",
+            Negotiation::Granted => "granted",
+            Negotiation::Partial => "partially granted",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Status {
+    pub negotiation: Negotiation,
+    pub requested_mw: u32,
+    pub allocated_mw: Option<u32>,
+    pub budget_mw: u32,
+}
+
+/// Restarts the negotiation when the link comes up, and forgets the allocation when it's lost.
+pub fn link_changed(up: bool, now: Instant) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.link = up;
+        state.allocation = None;
+        state.fast_tx = FAST_TX_COUNT;
+        state.next_tx = now;
+    });
+
+    update_budget();
+}
+
+/// Sets the amount of power requested from the PSE, in milliwatts.
+pub fn set_request(requested_mw: u32) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.requested_mw = requested_mw;
+        state.fast_tx = FAST_TX_COUNT;
+        state.next_tx = Instant::from_millis_const(0);
+    })
+}
+
+pub fn status() -> Status {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        let allocated_mw = state.allocation.map(|allocation| allocation.mw);
+
+        Status {
+            negotiation: match (state.link, allocated_mw) {
+                (false, _) => Negotiation::NoLink,
+                (true, None) => Negotiation::Requesting,
+                (true, Some(mw)) if mw >= state.requested_mw => Negotiation::Granted,
+                (true, Some(_)) => Negotiation::Partial,
+            },
+            requested_mw: state.requested_mw,
+            allocated_mw,
+            budget_mw: state.budget_mw(),
+        }
+    })
+}
+
+/// Expires a stale allocation. Returns true if an LLDPDU is due, in which case the network needs
+/// to be handled. This should be called periodically.
+pub fn poll(now: Instant) -> bool {
+    let (expired, due) = interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        let expired = match state.allocation {
+            Some(allocation) if allocation.expires <= now => {
+                state.allocation = None;
+                true
+            }
+            _ => false,
+        };
+
+        (expired, state.link && state.next_tx <= now)
+    });
+
+    if expired {
+        log::warn!("Power allocation from the PSE expired");
+        update_budget();
+    }
+
+    due
+}
+
+/// Returns the next LLDPDU to send, if one is due.
+pub fn take_frame(now: Instant, source: EthernetAddress) -> Option<[u8; FRAME_LEN]> {
+    let requested_mw = interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        if !state.link || state.next_tx > now {
+            return None;
+        }
+
+        state.next_tx = match state.fast_tx {
+            0 => now + TX_INTERVAL,
+            _ => {
+                state.fast_tx -= 1;
+                now + FAST_TX_INTERVAL
+            }
+        };
+        Some(state.requested_mw)
+    })?;
+
+    let mut frame = [0; FRAME_LEN];
+    frame[0..6].copy_from_slice(DESTINATION.as_bytes());
+    frame[6..12].copy_from_slice(source.as_bytes());
+    frame[12..14].copy_from_slice(&ETHERTYPE.to_be_bytes());
+
+    let mut len = 14;
+    let mut tlv = |kind: u8, value: &[u8]| {
+        let header = u16::from(kind) << 9 | value.len() as u16;
+        frame[len..][..2].copy_from_slice(&header.to_be_bytes());
+        frame[len + 2..][..value.len()].copy_from_slice(value);
+        len += 2 + value.len();
+    };
+
+    let mut id = [0; 7];
+    id[1..].copy_from_slice(source.as_bytes());
+    id[0] = CHASSIS_ID_MAC_ADDRESS;
+    tlv(TLV_CHASSIS_ID, &id);
+    id[0] = PORT_ID_MAC_ADDRESS;
+    tlv(TLV_PORT_ID, &id);
+    tlv(TLV_TTL, &TTL_SECS.to_be_bytes());
+
+    let [cap_high, cap_low] = MED_CAPABILITY_BITS.to_be_bytes();
+    tlv(
+        TLV_ORG_SPECIFIC,
+        &[
+            MED_OUI[0],
+            MED_OUI[1],
+            MED_OUI[2],
+            MED_CAPABILITIES,
+            cap_high,
+            cap_low,
+            MED_CLASS_I,
+        ],
+    );
+
+    // The power value is in units of 0.1 W
+    let [power_high, power_low] = ((requested_mw / 100).min(0xFFFF) as u16).to_be_bytes();
+    tlv(
+        TLV_ORG_SPECIFIC,
+        &[
+            MED_OUI[0],
+            MED_OUI[1],
+            MED_OUI[2],
+            MED_EXTENDED_POWER,
+            MED_POWER_PD,
+            power_high,
+            power_low,
+        ],
+    );
+    tlv(TLV_END, &[]);
+
+    Some(frame)
+}
+
+/// Handles a received LLDPDU (the entire Ethernet frame), recording any power allocation from the
+/// PSE.
+pub fn receive(now: Instant, frame: &[u8]) {
+    let mut ttl = None;
+    let mut allocated_mw = None;
+
+    let mut tlvs = frame.get(14..).unwrap_or_default();
+    while let Some(&[high, low]) = tlvs.get(0..2) {
+        let header = u16::from_be_bytes([high, low]);
+        let (kind, len) = ((header >> 9) as u8, usize::from(header & 0x01FF));
+        let value = match tlvs.get(2..2 + len) {
+            Some(value) => value,
+            None => {
+                log::debug!("Truncated LLDPDU");
+                return;
+            }
+        };
+
+        match (kind, value) {
+            (TLV_END, _) => break,
+            (TLV_TTL, [high, low]) => ttl = Some(u16::from_be_bytes([*high, *low])),
+            (TLV_ORG_SPECIFIC, [a, b, c, MED_EXTENDED_POWER, power, high, low])
+                if [*a, *b, *c] == MED_OUI && power & MED_POWER_TYPE_MASK == MED_POWER_TYPE_PSE =>
+            {
+                allocated_mw = Some(u32::from(u16::from_be_bytes([*high, *low])) * 100)
+            }
+            _ => {}
+        }
+
+        tlvs = &tlvs[2 + len..];
+    }
+
+    let (ttl, mw) = match (ttl, allocated_mw) {
+        (Some(ttl), Some(mw)) => (ttl, mw),
+        _ => return,
+    };
+
+    let changed = interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        let previous = state.allocation.map(|allocation| allocation.mw);
+        state.allocation = match ttl {
+            0 => None,
+            _ => Some(Allocation {
+                mw,
+                expires: now + Duration::from_secs(u64::from(ttl)),
+            }),
+        };
+
+        previous != state.allocation.map(|allocation| allocation.mw)
+    });
+
+    if changed {
+        log::info!("PSE allocated {} mW", mw);
+        update_budget();
+    }
+}
+
+// Limits the downstream port to whatever is left of the allocation
+fn update_budget() {
+    let budget_mw = interrupt::free(|cs| STATE.borrow(cs).borrow().budget_mw());
+    crate::port::protect::set_budget(Some(budget_mw));
+}
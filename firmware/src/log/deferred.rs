@@ -0,0 +1,262 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A lock-free, multi-producer, single-consumer queue of log records. Producers reserve a slot by
+// advancing the head, fill it, and then mark it ready. The consumer walks the slots from the tail,
+// stopping at the first one that isn't ready yet.
+//
+// Records are formatted as they're queued, into fixed-size slots. A message (or target) that
+// doesn't fit is cut short and the entry is marked as truncated. The target is kept alongside the
+// message, since it isn't always the module path (and the filters and syslog go by it).
+//
+// Since that formatting happens in whatever context logged the record (often an interrupt
+// handler), it's bounded: a message without arguments is copied as is, and formatting is abandoned
+// at the first write that doesn't fit in the slot. What's left is the arguments' own `Display`
+// impls, so records logged from interrupt handlers should stick to arguments that are cheap to
+// format (numbers, addresses, and the like) and never to ones that lock or wait.
+//
+// There is only ever one consumer: the deferred flush, or a fault handler that has taken over the
+// queue (see `seize`).
+
+use core::cell::UnsafeCell;
+use core::cmp;
+use core::fmt::{self, Write};
+use core::str;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use ignore_result::Ignore;
+use smoltcp::time::Instant;
+
+// This must be a power of two so that the indices stay consistent when they wrap
+const QUEUE_LEN: usize = 32;
+const MESSAGE_LEN: usize = 96;
+const TARGET_LEN: usize = 48;
+
+// Only used to initialize SLOTS, so each slot gets its own copy
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Slot = Slot {
+    ready: AtomicBool::new(false),
+    entry: UnsafeCell::new(Entry {
+        timestamp: 0,
+        level: log::Level::Error,
+        module_path: None,
+        file: None,
+        line: None,
+        target: None,
+        target_copy: [0; TARGET_LEN],
+        target_len: 0,
+        message: [0; MESSAGE_LEN],
+        len: 0,
+        truncated: false,
+    }),
+};
+
+static SLOTS: [Slot; QUEUE_LEN] = [EMPTY_SLOT; QUEUE_LEN];
+static HEAD: AtomicUsize = AtomicUsize::new(0);
+static TAIL: AtomicUsize = AtomicUsize::new(0);
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+// Held by the consumer while it drains the queue
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+struct Slot {
+    ready: AtomicBool,
+    entry: UnsafeCell<Entry>,
+}
+
+// Access to the entry is coordinated through the ready flag and the head and tail indices
+unsafe impl Sync for Slot {}
+
+pub(super) struct Entry {
+    timestamp: i64,
+    level: log::Level,
+    module_path: Option<&'static str>,
+    file: Option<&'static str>,
+    line: Option<u32>,
+    // The target, if it's the module path (as it usually is). Otherwise, it's copied.
+    target: Option<&'static str>,
+    target_copy: [u8; TARGET_LEN],
+    target_len: usize,
+    message: [u8; MESSAGE_LEN],
+    len: usize,
+    truncated: bool,
+}
+
+impl Entry {
+    pub fn timestamp(&self) -> Instant {
+        Instant::from_millis(self.timestamp)
+    }
+
+    pub fn level(&self) -> log::Level {
+        self.level
+    }
+
+    pub fn module_path(&self) -> Option<&'static str> {
+        self.module_path
+    }
+
+    pub fn file(&self) -> Option<&'static str> {
+        self.file
+    }
+
+    pub fn line(&self) -> Option<u32> {
+        self.line
+    }
+
+    pub fn target(&self) -> &str {
+        match self.target {
+            Some(target) => target,
+            // The target is only ever truncated on a character boundary
+            None => unsafe { str::from_utf8_unchecked(&self.target_copy[..self.target_len]) },
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        // The message is only ever truncated on a character boundary
+        unsafe { str::from_utf8_unchecked(&self.message[..self.len]) }
+    }
+
+    /// Returns true if the message or target didn't fit in the entry and was cut short.
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    fn set_target(&mut self, record: &log::Record) {
+        match record.module_path_static() {
+            Some(path) if path == record.target() => self.target = Some(path),
+            _ => {
+                self.target = None;
+                self.target_len = 0;
+                self.truncated |=
+                    !append(&mut self.target_copy, &mut self.target_len, record.target());
+            }
+        }
+    }
+}
+
+impl fmt::Write for Entry {
+    // Fails once the message is full, which stops the formatting short
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match append(&mut self.message, &mut self.len, s) {
+            true => Ok(()),
+            false => {
+                self.truncated = true;
+                Err(fmt::Error)
+            }
+        }
+    }
+}
+
+// Appends as much of the string as fits to the first `len` bytes of the buffer, returning false if
+// any of it had to be left off
+fn append(buffer: &mut [u8], len: &mut usize, s: &str) -> bool {
+    let mut n = cmp::min(s.len(), buffer.len() - *len);
+    while !s.is_char_boundary(n) {
+        n -= 1;
+    }
+
+    buffer[*len..][..n].copy_from_slice(&s.as_bytes()[..n]);
+    *len += n;
+    n == s.len()
+}
+
+/// Formats and enqueues a record, dropping it if the queue is full. This may be called from any
+/// context.
+pub(super) fn push(timestamp: Instant, record: &log::Record) {
+    let head = loop {
+        let head = HEAD.load(Ordering::Acquire);
+        if head.wrapping_sub(TAIL.load(Ordering::Acquire)) >= QUEUE_LEN {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        if HEAD
+            .compare_exchange_weak(
+                head,
+                head.wrapping_add(1),
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+        {
+            break head;
+        }
+    };
+
+    let slot = &SLOTS[head % QUEUE_LEN];
+    let entry = unsafe { &mut *slot.entry.get() };
+    entry.timestamp = timestamp.total_millis();
+    entry.level = record.level();
+    entry.module_path = record.module_path_static();
+    entry.file = record.file_static();
+    entry.line = record.line();
+    entry.truncated = false;
+    entry.set_target(record);
+    entry.len = 0;
+    match record.args().as_str() {
+        Some(message) => entry.write_str(message).ignore(),
+        None => entry.write_fmt(*record.args()).ignore(),
+    }
+
+    slot.ready.store(true, Ordering::Release);
+}
+
+/// Dequeues each of the ready records, passing them to `f`. If another context is already
+/// draining the queue, this leaves the records to it.
+pub(super) fn drain<F: FnMut(&Entry)>(f: F) {
+    if DRAINING.swap(true, Ordering::Acquire) {
+        return;
+    }
+
+    consume(f);
+    DRAINING.store(false, Ordering::Release);
+}
+
+/// Takes over the queue for good and dequeues each of the ready records, passing them to `f`. This
+/// is meant for the fault handlers, which never return, so a drain that they interrupted will
+/// never resume. The record that it was writing out is dropped rather than written again, in case
+/// it's what faulted.
+pub(super) fn seize<F: FnMut(&Entry)>(f: F) {
+    if DRAINING.swap(true, Ordering::Acquire) {
+        let tail = TAIL.load(Ordering::Relaxed);
+        let slot = &SLOTS[tail % QUEUE_LEN];
+        if slot.ready.load(Ordering::Acquire) {
+            slot.ready.store(false, Ordering::Relaxed);
+            TAIL.store(tail.wrapping_add(1), Ordering::Release);
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    consume(f);
+}
+
+fn consume<F: FnMut(&Entry)>(mut f: F) {
+    loop {
+        let tail = TAIL.load(Ordering::Relaxed);
+        let slot = &SLOTS[tail % QUEUE_LEN];
+        if !slot.ready.load(Ordering::Acquire) {
+            break;
+        }
+
+        f(unsafe { &*slot.entry.get() });
+
+        slot.ready.store(false, Ordering::Relaxed);
+        TAIL.store(tail.wrapping_add(1), Ordering::Release);
+    }
+}
+
+/// Returns the number of records that were dropped since the last call.
+pub(super) fn take_dropped() -> usize {
+    DROPPED.swap(0, Ordering::Relaxed)
+}
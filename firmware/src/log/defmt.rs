@@ -0,0 +1,146 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+#![cfg(feature = "defmt")]
+
+// The defmt frames are written to their own RTT up-channel (allocated alongside the others in
+// rtt::Logger), since there can only be one RTT control block. Records from the log facade are
+// formatted on the device and then forwarded as defmt strings; only direct uses of the defmt
+// macros benefit from host-side formatting.
+
+use core::cmp;
+use core::fmt::{self, Write};
+use core::str;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::{interrupt, register};
+use ignore_result::Ignore;
+use rtt_target::UpChannel;
+
+const MESSAGE_LEN: usize = 256;
+
+static mut CHANNEL: Option<UpChannel> = None;
+static mut ENCODER: ::defmt::Encoder = ::defmt::Encoder::new();
+static mut RESTORE_INTERRUPTS: bool = false;
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+pub fn new(level: log::LevelFilter) -> Logger {
+    Logger { level }
+}
+
+pub struct Logger {
+    pub level: log::LevelFilter,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut message = Message {
+            data: [0; MESSAGE_LEN],
+            len: 0,
+        };
+        write!(message, "{}", record.args()).ignore();
+
+        let file = record.file().unwrap_or("UNKNOWN");
+        let line = record.line().unwrap_or(0);
+        let message = message.as_str();
+        match record.level() {
+            log::Level::Error => ::defmt::error!("{=str}:{=u32} - {=str}", file, line, message),
+            log::Level::Warn => ::defmt::warn!("{=str}:{=u32} - {=str}", file, line, message),
+            log::Level::Info => ::defmt::info!("{=str}:{=u32} - {=str}", file, line, message),
+            log::Level::Debug => ::defmt::debug!("{=str}:{=u32} - {=str}", file, line, message),
+            log::Level::Trace => ::defmt::trace!("{=str}:{=u32} - {=str}", file, line, message),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Sets the RTT up-channel that carries the defmt frames.
+pub(super) fn set_channel(channel: UpChannel) {
+    interrupt::free(|_| unsafe { CHANNEL = Some(channel) })
+}
+
+#[::defmt::global_logger]
+struct GlobalLogger;
+
+unsafe impl ::defmt::Logger for GlobalLogger {
+    fn acquire() {
+        let primask = register::primask::read();
+        interrupt::disable();
+
+        if TAKEN.swap(true, Ordering::Acquire) {
+            panic!("defmt logger taken reentrantly");
+        }
+
+        unsafe {
+            RESTORE_INTERRUPTS = primask.is_active();
+            ENCODER.start_frame(write_channel);
+        }
+    }
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {
+        ENCODER.end_frame(write_channel);
+        TAKEN.store(false, Ordering::Release);
+
+        if RESTORE_INTERRUPTS {
+            interrupt::enable();
+        }
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        ENCODER.write(bytes, write_channel);
+    }
+}
+
+fn write_channel(bytes: &[u8]) {
+    // This is only called while the logger is acquired, with interrupts disabled
+    if let Some(channel) = unsafe { CHANNEL.as_mut() } {
+        channel.write(bytes);
+    }
+}
+
+struct Message {
+    data: [u8; MESSAGE_LEN],
+    len: usize,
+}
+
+impl Message {
+    fn as_str(&self) -> &str {
+        // The message is only ever truncated on a character boundary
+        unsafe { str::from_utf8_unchecked(&self.data[..self.len]) }
+    }
+}
+
+impl fmt::Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = cmp::min(s.len(), MESSAGE_LEN - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        self.data[self.len..][..len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
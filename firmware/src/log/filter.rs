@@ -0,0 +1,109 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Each filter caps the level of the records whose target starts with the filter's target. When
+// multiple filters match a record, the longest one wins. Filters only ever restrict the output;
+// the levels of the individual sinks still apply.
+
+use core::cell::RefCell;
+use core::str;
+use cortex_m::interrupt::{self, Mutex};
+use log::LevelFilter;
+
+const MAX_FILTERS: usize = 8;
+const MAX_TARGET_LEN: usize = 32;
+
+static FILTERS: Mutex<RefCell<[Option<Filter>; MAX_FILTERS]>> =
+    Mutex::new(RefCell::new([None; MAX_FILTERS]));
+
+#[derive(Clone, Copy)]
+struct Filter {
+    target: [u8; MAX_TARGET_LEN],
+    len: usize,
+    level: LevelFilter,
+}
+
+impl Filter {
+    fn target(&self) -> &str {
+        // The target was copied out of a str, so it is valid UTF-8
+        unsafe { str::from_utf8_unchecked(&self.target[..self.len]) }
+    }
+}
+
+/// Caps the level of all records whose target starts with `target`.
+pub fn set(target: &str, level: LevelFilter) -> Result<(), &'static str> {
+    if target.len() > MAX_TARGET_LEN {
+        return Err("target too long");
+    }
+
+    interrupt::free(|cs| {
+        let mut filters = FILTERS.borrow(cs).borrow_mut();
+        let index = filters
+            .iter()
+            .position(|f| matches!(f, Some(f) if f.target() == target))
+            .or_else(|| filters.iter().position(Option::is_none))
+            .ok_or("filter table full")?;
+
+        let mut filter = Filter {
+            target: [0; MAX_TARGET_LEN],
+            len: target.len(),
+            level,
+        };
+        filter.target[..target.len()].copy_from_slice(target.as_bytes());
+        filters[index] = Some(filter);
+
+        Ok(())
+    })
+}
+
+/// Removes the filter for `target`, returning whether one existed.
+pub fn remove(target: &str) -> bool {
+    interrupt::free(|cs| {
+        let mut filters = FILTERS.borrow(cs).borrow_mut();
+        match filters
+            .iter_mut()
+            .find(|f| matches!(f, Some(f) if f.target() == target))
+        {
+            Some(slot) => {
+                *slot = None;
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Calls `f` with the target and level of each of the configured filters.
+pub fn for_each<F: FnMut(&str, LevelFilter)>(mut f: F) {
+    interrupt::free(|cs| {
+        for filter in FILTERS.borrow(cs).borrow().iter().flatten() {
+            f(filter.target(), filter.level);
+        }
+    })
+}
+
+/// Checks whether the configured filters permit a record with the given metadata.
+pub(super) fn permits(metadata: &log::Metadata) -> bool {
+    interrupt::free(|cs| {
+        FILTERS
+            .borrow(cs)
+            .borrow()
+            .iter()
+            .flatten()
+            .filter(|f| metadata.target().starts_with(f.target()))
+            .max_by_key(|f| f.len)
+            .map_or(true, |f| metadata.level() <= f.level)
+    })
+}
@@ -0,0 +1,111 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use core::str;
+use cortex_m::interrupt::{self, Mutex};
+use ignore_result::Ignore;
+
+const BUFFER_LEN: usize = 4096;
+
+static BUFFER: Mutex<RefCell<Buffer>> = Mutex::new(RefCell::new(Buffer {
+    data: [0; BUFFER_LEN],
+    head: 0,
+    full: false,
+}));
+
+pub fn new(level: log::LevelFilter) -> Logger {
+    Logger { level }
+}
+
+pub struct Logger {
+    pub level: log::LevelFilter,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            interrupt::free(|cs| {
+                writeln!(
+                    BUFFER.borrow(cs).borrow_mut(),
+                    "{:<5} {}:{} - {}",
+                    record.level(),
+                    record.file().unwrap_or("UNKNOWN"),
+                    record.line().unwrap_or(0),
+                    record.args()
+                )
+                .ignore()
+            })
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Calls `f` with the contents of the buffer, oldest first. The contents are passed in (at most)
+/// two chunks, split where the buffer wraps around.
+pub fn dump<F: FnMut(&str)>(mut f: F) {
+    interrupt::free(|cs| {
+        let buffer = BUFFER.borrow(cs).borrow();
+        let (newer, older) = buffer.data.split_at(buffer.head);
+
+        if buffer.full {
+            // The oldest line was likely partially overwritten, so skip to the start of the next
+            let start = older
+                .iter()
+                .position(|b| *b == b'\n')
+                .map_or(older.len(), |i| i + 1);
+            f(as_str(&older[start..]));
+        }
+        f(as_str(newer));
+    })
+}
+
+// Converts as much of the bytes as possible into a string, skipping any partial characters at the
+// start and end (which may have been split when the buffer wrapped).
+fn as_str(bytes: &[u8]) -> &str {
+    let start = bytes
+        .iter()
+        .position(|b| b & 0xC0 != 0x80)
+        .unwrap_or(bytes.len());
+    let bytes = &bytes[start..];
+
+    match str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(err) => unsafe { str::from_utf8_unchecked(&bytes[..err.valid_up_to()]) },
+    }
+}
+
+struct Buffer {
+    data: [u8; BUFFER_LEN],
+    head: usize,
+    full: bool,
+}
+
+impl fmt::Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for b in s.bytes() {
+            self.data[self.head] = b;
+            self.head = (self.head + 1) % BUFFER_LEN;
+            self.full |= self.head == 0;
+        }
+        Ok(())
+    }
+}
@@ -14,12 +14,22 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use core::mem::MaybeUninit;
+use log::Log;
+use smoltcp::time::Instant;
 
+mod deferred;
+pub mod defmt;
+pub mod filter;
 pub mod itm;
+pub mod memory;
 pub mod rtt;
+pub mod syslog;
+pub mod uart;
+pub mod usb;
 
 static mut LOGGER: MaybeUninit<Logger> = MaybeUninit::uninit();
 
+/// Initializes the logger, prefixing each record with the time since boot (see `time`).
 pub fn init() -> InitializedLogger {
     static mut INITIALIZED: bool = false;
     assert!(unsafe { !INITIALIZED }, "logger already initialized");
@@ -27,11 +37,25 @@ pub fn init() -> InitializedLogger {
 
     log::set_logger(unsafe {
         LOGGER.write(Logger {
+            notify: None,
+
+            memory: None,
+            syslog: None,
+
+            #[cfg(feature = "defmt")]
+            defmt: None,
+
             #[cfg(feature = "itm")]
             itm: None,
 
             #[cfg(feature = "rtt")]
             rtt: None,
+
+            #[cfg(feature = "uart")]
+            uart: None,
+
+            #[cfg(feature = "usb")]
+            usb: None,
         })
     })
     .expect("set_logger");
@@ -39,10 +63,59 @@ pub fn init() -> InitializedLogger {
     InitializedLogger {}
 }
 
+/// Writes out the records that were queued while logging was deferred.
+pub fn flush_deferred() {
+    let logger = unsafe { LOGGER.assume_init_ref() };
+    deferred::drain(|entry| logger.replay(entry));
+    report_dropped();
+}
+
+/// Stops deferring log records, writing out any that are still queued. This is meant for the
+/// fault handlers, which can't rely on the flush being scheduled. They must not return afterward,
+/// since this takes the queue over from the flush (see `deferred::seize`).
+pub fn disable_deferral() {
+    let logger = unsafe { LOGGER.assume_init_mut() };
+    logger.notify = None;
+    deferred::seize(|entry| logger.replay(entry));
+    report_dropped();
+}
+
+fn report_dropped() {
+    match deferred::take_dropped() {
+        0 => {}
+        dropped => log::warn!("Dropped {} deferred log records", dropped),
+    }
+}
+
 #[non_exhaustive]
 pub struct InitializedLogger {}
 
 impl InitializedLogger {
+    pub fn add_memory(&self, logger: memory::Logger) -> &Self {
+        log::set_max_level(log::max_level().max(logger.level));
+        unsafe { LOGGER.assume_init_mut().memory = Some(logger) };
+
+        log::info!("Memory logging online!");
+        self
+    }
+
+    pub fn add_syslog(&self, logger: syslog::Logger) -> &Self {
+        log::set_max_level(log::max_level().max(logger.level));
+        unsafe { LOGGER.assume_init_mut().syslog = Some(logger) };
+
+        log::info!("Syslog logging online!");
+        self
+    }
+
+    #[cfg(feature = "defmt")]
+    pub fn add_defmt(&self, logger: defmt::Logger) -> &Self {
+        log::set_max_level(log::max_level().max(logger.level));
+        unsafe { LOGGER.assume_init_mut().defmt = Some(logger) };
+
+        log::info!("defmt logging online!");
+        self
+    }
+
     #[cfg(feature = "itm")]
     pub fn add_itm(&self, logger: itm::Logger) -> &Self {
         log::set_max_level(log::max_level().max(logger.level));
@@ -60,18 +133,77 @@ impl InitializedLogger {
         log::info!("RTT logging online!");
         self
     }
+
+    #[cfg(feature = "uart")]
+    pub fn add_uart(&self, logger: uart::Logger) -> &Self {
+        log::set_max_level(log::max_level().max(logger.level));
+        unsafe { LOGGER.assume_init_mut().uart = Some(logger) };
+
+        log::info!("UART logging online!");
+        self
+    }
+
+    #[cfg(feature = "usb")]
+    pub fn add_usb(&self, logger: usb::Logger) -> &Self {
+        log::set_max_level(log::max_level().max(logger.level));
+        unsafe { LOGGER.assume_init_mut().usb = Some(logger) };
+
+        log::info!("USB logging online!");
+        self
+    }
+
+    /// Queues records instead of writing them out immediately, invoking `notify` after each one is
+    /// queued. `notify` is expected to schedule a low-priority call to `flush_deferred`.
+    pub fn defer(&self, notify: fn()) -> &Self {
+        unsafe { LOGGER.assume_init_mut().notify = Some(notify) };
+        self
+    }
 }
 
 struct Logger {
+    notify: Option<fn()>,
+
+    memory: Option<memory::Logger>,
+    syslog: Option<syslog::Logger>,
+
+    #[cfg(feature = "defmt")]
+    defmt: Option<defmt::Logger>,
+
     #[cfg(feature = "itm")]
     itm: Option<itm::Logger>,
 
     #[cfg(feature = "rtt")]
     rtt: Option<rtt::Logger>,
+
+    #[cfg(feature = "uart")]
+    uart: Option<uart::Logger>,
+
+    #[cfg(feature = "usb")]
+    usb: Option<usb::Logger>,
 }
 
-impl log::Log for Logger {
+impl Log for Logger {
     fn enabled(&self, metadata: &log::Metadata) -> bool {
+        if !filter::permits(metadata) {
+            return false;
+        }
+
+        match &self.memory {
+            Some(memory) if memory.enabled(metadata) => return true,
+            _ => {}
+        }
+
+        match &self.syslog {
+            Some(syslog) if syslog.enabled(metadata) => return true,
+            _ => {}
+        }
+
+        #[cfg(feature = "defmt")]
+        match &self.defmt {
+            Some(defmt) if defmt.enabled(metadata) => return true,
+            _ => {}
+        }
+
         #[cfg(feature = "itm")]
         match &self.itm {
             Some(itm) if itm.enabled(metadata) => return true,
@@ -84,30 +216,136 @@ impl log::Log for Logger {
             _ => {}
         }
 
+        #[cfg(feature = "uart")]
+        match &self.uart {
+            Some(uart) if uart.enabled(metadata) => return true,
+            _ => {}
+        }
+
+        #[cfg(feature = "usb")]
+        match &self.usb {
+            Some(usb) if usb.enabled(metadata) => return true,
+            _ => {}
+        }
+
         false
     }
 
     fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let now = crate::time::now();
+        match self.notify {
+            Some(notify) => {
+                deferred::push(now, record);
+                notify();
+            }
+            None => self.log_record(now, record),
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(memory) = &self.memory {
+            memory.flush();
+        }
+
+        if let Some(syslog) = &self.syslog {
+            syslog.flush();
+        }
+
+        #[cfg(feature = "defmt")]
+        if let Some(defmt) = &self.defmt {
+            defmt.flush();
+        }
+
         #[cfg(feature = "itm")]
         if let Some(itm) = &self.itm {
-            itm.log(record);
+            itm.flush();
         }
 
         #[cfg(feature = "rtt")]
         if let Some(rtt) = &self.rtt {
-            rtt.log(record);
+            rtt.flush();
+        }
+
+        #[cfg(feature = "uart")]
+        if let Some(uart) = &self.uart {
+            uart.flush();
+        }
+
+        #[cfg(feature = "usb")]
+        if let Some(usb) = &self.usb {
+            usb.flush();
         }
     }
+}
+
+impl Logger {
+    // Writes out a record that was queued while logging was deferred
+    fn replay(&self, entry: &deferred::Entry) {
+        let truncated = match entry.truncated() {
+            true => " (truncated)",
+            false => "",
+        };
+        self.log_record(
+            entry.timestamp(),
+            &log::Record::builder()
+                .level(entry.level())
+                .target(entry.target())
+                .args(format_args!("{}{}", entry.message(), truncated))
+                .module_path(entry.module_path())
+                .file(entry.file())
+                .line(entry.line())
+                .build(),
+        )
+    }
+
+    fn log_record(&self, timestamp: Instant, record: &log::Record) {
+        self.write_record(
+            &log::Record::builder()
+                .metadata(record.metadata().clone())
+                .args(format_args!("[{}] {}", timestamp, record.args()))
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn write_record(&self, record: &log::Record) {
+        if let Some(memory) = &self.memory {
+            memory.log(record);
+        }
+
+        if let Some(syslog) = &self.syslog {
+            syslog.log(record);
+        }
+
+        #[cfg(feature = "defmt")]
+        if let Some(defmt) = &self.defmt {
+            defmt.log(record);
+        }
 
-    fn flush(&self) {
         #[cfg(feature = "itm")]
         if let Some(itm) = &self.itm {
-            itm.flush();
+            itm.log(record);
         }
 
         #[cfg(feature = "rtt")]
         if let Some(rtt) = &self.rtt {
-            rtt.flush();
+            rtt.log(record);
+        }
+
+        #[cfg(feature = "uart")]
+        if let Some(uart) = &self.uart {
+            uart.log(record);
+        }
+
+        #[cfg(feature = "usb")]
+        if let Some(usb) = &self.usb {
+            usb.log(record);
         }
     }
 }
@@ -15,10 +15,9 @@
 
 #![cfg(feature = "rtt")]
 
-use core::fmt::Write;
-use core::mem::{self, MaybeUninit};
+use crate::console::{self, Session};
+use core::mem::MaybeUninit;
 use core::str;
-use ignore_result::Ignore;
 use rtt_target::{DownChannel, UpChannel};
 
 pub fn new(level: log::LevelFilter) -> Logger {
@@ -32,6 +31,7 @@ pub struct Logger {
 impl Logger {
     #[rustfmt::skip::macros(rtt_init)]
     fn new(level: log::LevelFilter) -> Logger {
+        #[cfg(not(feature = "defmt"))]
         let channels = rtt_target::rtt_init! {
             up: {
                 0: {
@@ -44,6 +44,11 @@ impl Logger {
                     mode: NoBlockTrim
                     name: "logs"
                 }
+                2: {
+                    size: 4096
+                    mode: NoBlockTrim
+                    name: "capture"
+                }
             }
             down: {
                 0: {
@@ -54,11 +59,56 @@ impl Logger {
             }
         };
 
+        // The macro doesn't support conditional channels, so repeat the above with a third
+        // up-channel for defmt
+        #[cfg(feature = "defmt")]
+        let channels = rtt_target::rtt_init! {
+            up: {
+                0: {
+                    size: 1024
+                    mode: NoBlockTrim
+                    name: "terminal"
+                }
+                1: {
+                    size: 4096
+                    mode: NoBlockTrim
+                    name: "logs"
+                }
+                2: {
+                    size: 1024
+                    mode: NoBlockTrim
+                    name: "defmt"
+                }
+                3: {
+                    size: 4096
+                    mode: NoBlockTrim
+                    name: "capture"
+                }
+            }
+            down: {
+                0: {
+                    size: 1024
+                    mode: NoBlockTrim
+                    name: "terminal"
+                }
+            }
+        };
+
+        #[cfg(not(feature = "defmt"))]
+        let capture = channels.up.2;
+
+        #[cfg(feature = "defmt")]
+        super::defmt::set_channel(channels.up.2);
+        #[cfg(feature = "defmt")]
+        let capture = channels.up.3;
+
         rtt_target::set_print_channel(channels.up.1);
         unsafe {
             TERMINAL = MaybeUninit::new(Terminal {
                 input: channels.down.0,
                 output: channels.up.0,
+                capture,
+                session: Session::new(console::Terminal::Rtt),
             });
         }
 
@@ -91,45 +141,11 @@ static mut TERMINAL: MaybeUninit<Terminal> = MaybeUninit::uninit();
 pub struct Terminal {
     output: UpChannel,
     input: DownChannel,
-}
-
-macro_rules! output {
-    ($writer:expr, $fmt:literal) => {
-        write!($writer, $fmt)
-            .map_err(|err| log::warn!("terminal write failed: {err}"))
-            .ignore()
-    };
-    ($writer:expr, $str:expr) => {
-        write!($writer, "{}", $str)
-            .map_err(|err| log::warn!("terminal write failed: {err}"))
-            .ignore()
-    };
-}
-
-macro_rules! outputln {
-    ($writer:expr, $fmt:literal) => {{
-        output!($writer, $fmt);
-        outputln!($writer)
-    }};
-    ($writer:expr, $str:expr) => {{
-        output!($writer, $str);
-        outputln!($writer)
-    }};
-    ($writer:expr) => {
-        output!($writer, "\n\r")
-    };
+    capture: UpChannel,
+    session: Session,
 }
 
 impl Terminal {
-    const HELP_STR: &'static str = "Terminal Help
-
-Available commands:
-
-  get <hex address>                Read address
-  set <hex address> <hex value>    Write value to address
-  help                             Display this help text";
-    const PROMPT_STR: &'static str = "> ";
-
     pub fn new() -> &'static mut Terminal {
         let terminal = unsafe { TERMINAL.assume_init_mut() };
 
@@ -137,76 +153,25 @@ Available commands:
         let mut input = [0u8; 1024];
         terminal.input.read(&mut input);
 
-        outputln!(terminal.output);
-        output!(terminal.output, Self::PROMPT_STR);
+        terminal.session.start(&mut terminal.output);
         terminal
     }
 
     pub fn poll(&mut self) {
+        if crate::capture::sink() == Some(crate::capture::Sink::Rtt) {
+            let capture = &mut self.capture;
+            crate::capture::drain(|bytes| capture.write(bytes));
+        }
+
         let mut input = [0u8; 1024];
         let len = self.input.read(&mut input);
         if len == 0 {
             return;
         }
 
-        let mut tokens = match str::from_utf8(&input[0..len]) {
-            Ok(text) => text,
-            Err(err) => {
-                log::warn!("failed parsing terminal input: {err}");
-                return;
-            }
-        }
-        .trim()
-        .split(' ');
-
-        macro_rules! token_u32 {
-            ($name:literal) => {
-                match tokens.next() {
-                    Some(val) => match u32::from_str_radix(val, 16) {
-                        Ok(val) => val,
-                        Err(err) => {
-                            output!(self.output, concat!("Failed to parse ", $name));
-                            outputln!(self.output, " ({val}): {err}");
-                            return;
-                        }
-                    },
-                    None => {
-                        outputln!(self.output, Self::HELP_STR);
-                        return;
-                    }
-                }
-            };
-        }
-
-        match tokens.next() {
-            Some("") | None => {}
-            Some("help") => outputln!(self.output, Self::HELP_STR),
-            Some("get") => {
-                let addr = token_u32!("addr") as usize;
-                match addr % mem::size_of::<u32>() {
-                    0 => {
-                        let data = unsafe { *(addr as *const u32) };
-                        outputln!(self.output, "0x{data:08X}");
-                    }
-                    2 => {
-                        let data = unsafe { *(addr as *const u16) };
-                        outputln!(self.output, "0x{data:04X}");
-                    }
-                    1 | 3 => {
-                        let data = unsafe { *(addr as *const u8) };
-                        outputln!(self.output, "0x{data:02X}");
-                    }
-                    val => log::error!("unhandled val: {val}"),
-                }
-            }
-            Some("set") => {
-                let addr = token_u32!("addr");
-                let value = token_u32!("value");
-                unsafe { *(addr as *mut u32) = value };
-            }
-            Some(command) => outputln!(self.output, "Unrecognized command: {command} (try 'help')"),
+        match str::from_utf8(&input[0..len]) {
+            Ok(line) => self.session.execute(line, &mut self.output),
+            Err(err) => log::warn!("failed parsing terminal input: {err}"),
         }
-
-        output!(self.output, Self::PROMPT_STR);
     }
 }
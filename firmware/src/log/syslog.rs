@@ -0,0 +1,186 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Records are formatted as RFC 5424 messages and queued here until the network task sends them to
+// the collector (see network::Resources::handle_syslog). Sending is rate limited with a token
+// bucket; messages that arrive while the queue is full are dropped.
+
+use core::cell::RefCell;
+use core::cmp;
+use core::fmt::{self, Write};
+use cortex_m::interrupt::{self, Mutex};
+use ignore_result::Ignore;
+use smoltcp::time::Instant;
+use smoltcp::wire::IpEndpoint;
+
+pub const PORT: u16 = 514;
+
+const QUEUE_LEN: usize = 8;
+const MESSAGE_LEN: usize = 256;
+
+// Allow bursts of up to eight messages, refilling one token every 250 ms
+const BURST: u32 = 8;
+const REFILL_MILLIS: i64 = 250;
+
+// RFC 5424 facility "user-level messages"
+const FACILITY: u8 = 1;
+
+const EMPTY_MESSAGE: Message = Message {
+    data: [0; MESSAGE_LEN],
+    len: 0,
+};
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    collector: None,
+    queue: [EMPTY_MESSAGE; QUEUE_LEN],
+    head: 0,
+    len: 0,
+    dropped: 0,
+    tokens: BURST,
+    refilled: 0,
+}));
+
+pub fn new(level: log::LevelFilter) -> Logger {
+    Logger { level }
+}
+
+pub struct Logger {
+    pub level: log::LevelFilter,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            interrupt::free(|cs| STATE.borrow(cs).borrow_mut().push(record))
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Sets the endpoint of the syslog collector, or stops forwarding if `None`.
+pub fn set_collector(collector: Option<IpEndpoint>) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.collector = collector;
+        state.len = 0;
+    })
+}
+
+pub fn collector() -> Option<IpEndpoint> {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().collector)
+}
+
+/// Passes as many of the queued messages to `send` as the rate limit allows, along with the
+/// collector's endpoint. `send` returns false if the message couldn't be sent, in which case it
+/// remains queued.
+pub fn drain<F: FnMut(IpEndpoint, &[u8]) -> bool>(now: Instant, mut send: F) {
+    let dropped = interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        let collector = state.collector?;
+
+        let refills = (now.total_millis() - state.refilled) / REFILL_MILLIS;
+        if refills > 0 {
+            state.tokens = cmp::min(i64::from(BURST), i64::from(state.tokens) + refills) as u32;
+            state.refilled = now.total_millis();
+        }
+
+        while state.len > 0 && state.tokens > 0 {
+            let message = &state.queue[state.head];
+            if !send(collector, &message.data[..message.len]) {
+                break;
+            }
+
+            state.head = (state.head + 1) % QUEUE_LEN;
+            state.len -= 1;
+            state.tokens -= 1;
+        }
+
+        match state.dropped {
+            0 => None,
+            dropped => {
+                state.dropped = 0;
+                Some(dropped)
+            }
+        }
+    });
+
+    if let Some(dropped) = dropped {
+        log::warn!("Dropped {} syslog messages", dropped);
+    }
+}
+
+struct State {
+    collector: Option<IpEndpoint>,
+    queue: [Message; QUEUE_LEN],
+    head: usize,
+    len: usize,
+    dropped: usize,
+    tokens: u32,
+    refilled: i64,
+}
+
+impl State {
+    fn push(&mut self, record: &log::Record) {
+        if self.collector.is_none() {
+            return;
+        }
+
+        if self.len == QUEUE_LEN {
+            self.dropped += 1;
+            return;
+        }
+
+        let severity = match record.level() {
+            log::Level::Error => 3,
+            log::Level::Warn => 4,
+            log::Level::Info => 6,
+            log::Level::Debug | log::Level::Trace => 7,
+        };
+
+        let message = &mut self.queue[(self.head + self.len) % QUEUE_LEN];
+        message.len = 0;
+        write!(
+            message,
+            "<{}>1 - - poe - - - {}: {}",
+            FACILITY * 8 + severity,
+            record.target(),
+            record.args()
+        )
+        .ignore();
+
+        self.len += 1;
+    }
+}
+
+struct Message {
+    data: [u8; MESSAGE_LEN],
+    len: usize,
+}
+
+impl fmt::Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Syslog messages may be truncated at any octet, so there's no need to respect character
+        // boundaries here
+        let len = cmp::min(s.len(), MESSAGE_LEN - self.len);
+        self.data[self.len..][..len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+#![cfg(feature = "uart")]
+
+// The terminal, and optionally the log, over a serial port (115200 8N1), for bringing up boards
+// that have neither a debug probe nor a working network. The binary configures the pins, creates
+// the USART (see efm32gg::usart), owns the `Terminal`, and calls `poll` from both of the USART's
+// interrupts.
+//
+// Everything sent out (the terminal's echo and responses, and log records) is queued here and
+// written out from the TX interrupt. Writers pend that interrupt so that the queue starts draining
+// right away; bytes that don't fit in the queue are dropped.
+
+use crate::console::{self, Queue, Session};
+use crate::efm32gg::usart::{Instance, Usart};
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use cortex_m::interrupt::{self, Mutex};
+use efm32gg11b820::{Interrupt, NVIC};
+use ignore_result::Ignore;
+
+/// The baud rate of the serial port.
+pub const BAUD: u32 = 115_200;
+
+const QUEUE_LEN: usize = 2048;
+
+struct State {
+    queue: Queue<QUEUE_LEN>,
+    interrupt: Option<Interrupt>,
+}
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    queue: Queue::new(),
+    interrupt: None,
+}));
+
+// Queues everything written to it for the serial port
+struct Output;
+
+impl Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let interrupt = interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+            state.queue.push(s.as_bytes());
+            state.interrupt
+        });
+
+        if let Some(interrupt) = interrupt {
+            NVIC::pend(interrupt);
+        }
+        Ok(())
+    }
+}
+
+pub fn new(level: log::LevelFilter) -> Logger {
+    Logger { level }
+}
+
+pub struct Logger {
+    pub level: log::LevelFilter,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            write!(
+                Output,
+                "{:<5} {}:{} - {}\n\r",
+                record.level(),
+                record.file().unwrap_or("UNKNOWN"),
+                record.line().unwrap_or(0),
+                record.args()
+            )
+            .ignore()
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+pub struct Terminal<U: Instance> {
+    usart: Usart<U>,
+    session: Session,
+}
+
+impl<U: Instance> Terminal<U> {
+    /// Runs the terminal on the USART, which must have been created at `BAUD`.
+    pub fn new(mut usart: Usart<U>) -> Terminal<U> {
+        interrupt::free(|cs| STATE.borrow(cs).borrow_mut().interrupt = Some(U::TX_INTERRUPT));
+        usart.listen_rx();
+
+        let mut session = Session::new(console::Terminal::Uart);
+        session.start(&mut Output);
+        Terminal { usart, session }
+    }
+
+    /// Runs any command that has been entered and sends whatever is queued. This is the body of
+    /// the binary's USART RX and TX interrupt handlers.
+    pub fn poll(&mut self) {
+        while let Some(byte) = self.usart.read() {
+            self.session.receive(&[byte], &mut Output);
+        }
+
+        // Nothing is logged here, since it would only be queued again
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+            let queue = &mut state.queue;
+            while let Some(&byte) = queue.front().first() {
+                match self.usart.write(byte) {
+                    true => queue.pop(1),
+                    false => break,
+                }
+            }
+
+            // Only interrupt on room to transmit while there's something to transmit
+            self.usart.listen_tx(!queue.is_empty());
+        })
+    }
+}
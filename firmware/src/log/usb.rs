@@ -0,0 +1,135 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+#![cfg(feature = "usb")]
+
+// The terminal, and optionally the log, over a USB serial port (CDC-ACM), so that a unit can be
+// configured with just a USB cable when the network is down. The binary owns the `Terminal` and
+// calls `poll` from the USB interrupt.
+//
+// Everything sent to the host (the terminal's echo and responses, and log records) is queued here
+// and written out as the host reads it. Writers pend the USB interrupt so that the queue is
+// drained right away; bytes that don't fit in the queue are dropped.
+
+use crate::console::{self, Queue, Session};
+use crate::efm32gg::usb::UsbBus;
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use cortex_m::interrupt::{self, Mutex};
+use efm32gg11b820::{Interrupt, NVIC};
+use ignore_result::Ignore;
+use usb_device::class_prelude::UsbBusAllocator;
+use usb_device::prelude::*;
+use usbd_serial::SerialPort;
+
+// The pid.codes test VID and PID, until the project has its own
+const VID: u16 = 0x1209;
+const PID: u16 = 0x0001;
+
+const QUEUE_LEN: usize = 4096;
+
+static QUEUE: Mutex<RefCell<Queue<QUEUE_LEN>>> = Mutex::new(RefCell::new(Queue::new()));
+
+// Queues everything written to it for the host
+struct Output;
+
+impl Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        interrupt::free(|cs| QUEUE.borrow(cs).borrow_mut().push(s.as_bytes()));
+        NVIC::pend(Interrupt::USB);
+        Ok(())
+    }
+}
+
+pub fn new(level: log::LevelFilter) -> Logger {
+    Logger { level }
+}
+
+pub struct Logger {
+    pub level: log::LevelFilter,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            write!(
+                Output,
+                "{:<5} {}:{} - {}\n\r",
+                record.level(),
+                record.file().unwrap_or("UNKNOWN"),
+                record.line().unwrap_or(0),
+                record.args()
+            )
+            .ignore()
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+pub struct Terminal {
+    device: UsbDevice<'static, UsbBus>,
+    serial: SerialPort<'static, UsbBus>,
+    session: Session,
+}
+
+impl Terminal {
+    pub fn new(bus: &'static UsbBusAllocator<UsbBus>) -> Terminal {
+        let serial = SerialPort::new(bus);
+        let device = UsbDeviceBuilder::new(bus, UsbVidPid(VID, PID))
+            .manufacturer("Alex Crawford")
+            .product("PoE Passthrough")
+            .device_class(usbd_serial::USB_CLASS_CDC)
+            .build();
+
+        let mut session = Session::new(console::Terminal::Usb);
+        session.start(&mut Output);
+        Terminal {
+            device,
+            serial,
+            session,
+        }
+    }
+
+    /// Services the controller, running any command that the host has entered and sending it
+    /// whatever is queued. This is the body of the binary's USB interrupt handler.
+    pub fn poll(&mut self) {
+        if self.device.poll(&mut [&mut self.serial]) {
+            let mut input = [0u8; 64];
+            if let Ok(len) = self.serial.read(&mut input) {
+                self.session.receive(&input[..len], &mut Output);
+            }
+        }
+
+        if self.device.state() != UsbDeviceState::Configured {
+            return;
+        }
+
+        // Nothing is logged here, since it would only be queued again
+        interrupt::free(|cs| {
+            let mut queue = QUEUE.borrow(cs).borrow_mut();
+            while !queue.is_empty() {
+                match self.serial.write(queue.front()) {
+                    Ok(written) if written > 0 => queue.pop(written),
+                    Ok(_) | Err(_) => break,
+                }
+            }
+        })
+    }
+}
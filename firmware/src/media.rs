@@ -0,0 +1,93 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Overrides for the link's speed, duplex, and wiring, for link partners that fail to autonegotiate
+// or to detect crossover. Forcing the speed and duplex turns off autonegotiation entirely, so the
+// link partner has to be forced to the same mode. Changes are applied by the network task; the
+// link drops while the PHY retrains.
+
+use crate::efm32gg::clock;
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+use ethernet_phy::{LinkSpeed, LinkState, Mdix};
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    settings: Settings {
+        forced: None,
+        mdix: Mdix::Auto,
+    },
+    pending: false,
+}));
+
+struct State {
+    settings: Settings,
+    pending: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Settings {
+    /// The speed and duplex, or `None` to autonegotiate them.
+    pub forced: Option<LinkState>,
+    pub mdix: Mdix,
+}
+
+/// Forces the speed and duplex of the link, or lets them be autonegotiated. The link can't be
+/// forced to 100 Mbps while the clock has fallen back to the HFRCO (see `efm32gg::clock`).
+pub fn set_forced(forced: Option<LinkState>) -> Result<(), &'static str> {
+    if matches!(forced, Some(state) if state.speed == LinkSpeed::HundredMbps)
+        && clock::source() == clock::Source::Hfrco
+    {
+        return Err("100 Mbps needs the HFXO, which failed");
+    }
+
+    update(|settings| settings.forced = forced);
+    Ok(())
+}
+
+pub fn set_mdix(mdix: Mdix) {
+    update(|settings| settings.mdix = mdix)
+}
+
+pub fn settings() -> Settings {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().settings)
+}
+
+/// Returns true if the settings need to be applied, in which case the network needs to be
+/// handled.
+pub fn pending() -> bool {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().pending)
+}
+
+/// Returns the settings to apply to the PHY, if they have changed since they were last applied.
+pub fn take_change() -> Option<Settings> {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        match state.pending {
+            true => {
+                state.pending = false;
+                Some(state.settings)
+            }
+            false => None,
+        }
+    })
+}
+
+fn update<F: FnOnce(&mut Settings)>(f: F) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        f(&mut state.settings);
+        state.pending = true;
+    })
+}
@@ -0,0 +1,128 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A destructive test of the RAM, for inspecting new boards. Everything other than the statics is
+// the stack (see stack), so only the part of the stack below the caller can be tested. That part
+// is used by whatever preempts the caller, so it's tested a block at a time with interrupts
+// disabled, and each block is put back the way it was found (paint and all) before they're
+// enabled again.
+//
+// Each block is given a walking one (which finds stuck and shorted data bits), then each word is
+// given its own address (which finds shorted or open address bits), and finally the caller's
+// pattern and its inverse, if there is one.
+
+use core::ops::Range;
+use core::ptr;
+use cortex_m::interrupt;
+
+// Small enough that interrupts aren't held off for long
+const BLOCK_WORDS: usize = 256;
+
+// Room below the caller for the test's own stack frames (including the copy of the block being
+// tested, and whatever `fail` does with each failure)
+const HEADROOM: u32 = 4096;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Failure {
+    pub address: u32,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+pub struct Summary {
+    pub words: usize,
+    pub failures: usize,
+}
+
+/// Returns the range of RAM that can be tested right now.
+pub fn testable() -> Range<u32> {
+    let unused = crate::stack::unused();
+    // Only whole words are tested
+    let start = (unused.start as u32 + 3) & !0x3;
+    let end = (unused.end as u32).saturating_sub(HEADROOM) & !0x3;
+    start..end.max(start)
+}
+
+/// Tests the RAM in the range, which must fall within `testable`, calling `fail` for each word
+/// that reads back wrong.
+pub fn run<F: FnMut(Failure)>(
+    start: u32,
+    len: u32,
+    pattern: Option<u32>,
+    mut fail: F,
+) -> Result<Summary, &'static str> {
+    if start % 4 != 0 || len % 4 != 0 {
+        return Err("the range must be word-aligned");
+    }
+    let end = start.checked_add(len).ok_or("the range wraps")?;
+    let testable = testable();
+    if start < testable.start || end > testable.end {
+        return Err("the range isn't free to test (see memtest)");
+    }
+
+    let mut summary = Summary {
+        words: 0,
+        failures: 0,
+    };
+    let mut block = start;
+    while block < end {
+        let words = (((end - block) / 4) as usize).min(BLOCK_WORDS);
+        interrupt::free(|_| {
+            test_block(block as *mut u32, words, pattern, &mut |failure| {
+                summary.failures += 1;
+                fail(failure)
+            })
+        });
+        summary.words += words;
+        block += (words * 4) as u32;
+    }
+    Ok(summary)
+}
+
+fn test_block(block: *mut u32, words: usize, pattern: Option<u32>, fail: &mut dyn FnMut(Failure)) {
+    let mut saved = [0; BLOCK_WORDS];
+    for (i, word) in saved[..words].iter_mut().enumerate() {
+        *word = unsafe { ptr::read_volatile(block.add(i)) };
+    }
+
+    let mut fill = |value: &dyn Fn(usize) -> u32| {
+        for i in 0..words {
+            unsafe { ptr::write_volatile(block.add(i), value(i)) };
+        }
+        for i in 0..words {
+            let actual = unsafe { ptr::read_volatile(block.add(i)) };
+            if actual != value(i) {
+                fail(Failure {
+                    address: unsafe { block.add(i) } as u32,
+                    expected: value(i),
+                    actual,
+                });
+            }
+        }
+    };
+
+    for bit in 0..32 {
+        fill(&|_| 1 << bit);
+    }
+    fill(&|i| unsafe { block.add(i) } as u32);
+    if let Some(pattern) = pattern {
+        fill(&|_| pattern);
+        fill(&|_| !pattern);
+    }
+
+    for (i, word) in saved[..words].iter().enumerate() {
+        unsafe { ptr::write_volatile(block.add(i), *word) };
+    }
+}
@@ -0,0 +1,291 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A Modbus TCP server, for integrating with PLCs and SCADA systems. Only requests addressed to the
+// configured unit ID (see `set_unit_id`) are answered. The registers are:
+//
+//   Holding registers (read with 0x03, written with 0x06 or 0x10)
+//     0      identify (0 or 1)
+//     1      downstream port enabled (0 or 1)
+//
+//   Input registers (read with 0x04)
+//     0      downstream port powered (0 or 1)
+//     1      link speed, in Mb/s (0 when the link is down)
+//     2      load current, in mA
+//     3      port voltage, in mV (the nominal voltage; see `port::meter`)
+//     4-5    power, in mW
+//     6-7    energy delivered, in mWh
+//     8      temperature, in tenths of a degree Celsius (signed)
+//     9-10   overcurrent trips
+//     11-12  uptime, in seconds
+//     13-14  frames received
+//     15-16  frames sent
+//     17-18  receive errors
+//     19-20  transmit errors
+//
+// Values that span two registers are 32 bits, with the high word first. Registers that are
+// unavailable (e.g. the temperature, before the first reading) read as 0xFFFF.
+
+use crate::efm32gg::Statistics;
+use crate::identify::Pattern;
+
+use core::cell::RefCell;
+use core::convert::TryFrom;
+use cortex_m::interrupt::{self, Mutex};
+use ethernet_phy::{LinkSpeed, LinkState};
+use smoltcp::time::Instant;
+
+pub const PORT: u16 = 502;
+
+/// The length of the header that precedes each request and response.
+pub const HEADER_LEN: usize = 7;
+
+/// The largest message that's sent or received.
+pub const MAX_MESSAGE_LEN: usize = 260;
+
+/// The unit ID that's answered by default.
+pub const DEFAULT_UNIT_ID: u8 = 1;
+
+const PROTOCOL_ID: u16 = 0;
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const WRITE_SINGLE_REGISTER: u8 = 0x06;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+
+const ILLEGAL_FUNCTION: u8 = 0x01;
+const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const ILLEGAL_DATA_VALUE: u8 = 0x03;
+const SERVER_DEVICE_FAILURE: u8 = 0x04;
+
+// The most registers that can be read, or written, with one request
+const MAX_READ: u16 = 125;
+const MAX_WRITE: u16 = 123;
+
+const HOLDING_REGISTERS: u16 = 2;
+const INPUT_REGISTERS: u16 = 21;
+
+const UNAVAILABLE: u16 = 0xFFFF;
+
+static UNIT_ID: Mutex<RefCell<u8>> = Mutex::new(RefCell::new(DEFAULT_UNIT_ID));
+
+/// The state of the device, as needed to read and write the registers.
+pub struct Context<'a> {
+    pub now: Instant,
+    pub link: Option<LinkState>,
+    pub statistics: Statistics,
+    pub identifying: bool,
+    pub identify: &'a mut dyn FnMut(Option<Pattern>),
+}
+
+/// Sets the unit ID that requests need to be addressed to.
+pub fn set_unit_id(id: u8) {
+    interrupt::free(|cs| *UNIT_ID.borrow(cs).borrow_mut() = id)
+}
+
+pub fn unit_id() -> u8 {
+    interrupt::free(|cs| *UNIT_ID.borrow(cs).borrow())
+}
+
+/// Returns the length of the message at the start of `buffer`, or `None` until enough of it has
+/// been received to tell. Returns an error if it isn't a Modbus TCP message.
+pub fn message_len(buffer: &[u8]) -> Result<Option<usize>, &'static str> {
+    let header = match buffer.get(..HEADER_LEN - 1) {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let protocol = u16::from_be_bytes([header[2], header[3]]);
+    let len = usize::from(u16::from_be_bytes([header[4], header[5]]));
+    match protocol == PROTOCOL_ID && len >= 2 && HEADER_LEN - 1 + len <= MAX_MESSAGE_LEN {
+        true => Ok(Some(HEADER_LEN - 1 + len)),
+        false => Err("invalid header"),
+    }
+}
+
+/// Handles a complete request, writing the response into `response`. Returns the length of the
+/// response, or `None` if the request isn't answered.
+pub fn handle(
+    request: &[u8],
+    response: &mut [u8; MAX_MESSAGE_LEN],
+    context: &mut Context,
+) -> Option<usize> {
+    let (header, pdu) = (request.get(..HEADER_LEN)?, &request[HEADER_LEN..]);
+    if header[6] != unit_id() {
+        return None;
+    }
+    let (&function, data) = pdu.split_first()?;
+
+    response[..HEADER_LEN].copy_from_slice(header);
+    response[HEADER_LEN] = function;
+    let len = match respond(function, data, &mut response[HEADER_LEN + 1..], context) {
+        Ok(len) => len,
+        Err(exception) => {
+            response[HEADER_LEN] = function | 0x80;
+            response[HEADER_LEN + 1] = exception;
+            1
+        }
+    };
+
+    // The length in the header counts the unit ID, the function code, and the data
+    let len = HEADER_LEN + 1 + len;
+    response[4..6].copy_from_slice(&(len as u16 - 6).to_be_bytes());
+    Some(len)
+}
+
+// Carries out the function, writing the response's data into `out` and returning its length, or
+// the exception code
+fn respond(function: u8, data: &[u8], out: &mut [u8], context: &mut Context) -> Result<usize, u8> {
+    let word = |offset: usize| -> Result<u16, u8> {
+        data.get(offset..offset + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+            .ok_or(ILLEGAL_DATA_VALUE)
+    };
+
+    match function {
+        READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+            let (start, count) = (word(0)?, word(2)?);
+            if count == 0 || count > MAX_READ {
+                return Err(ILLEGAL_DATA_VALUE);
+            }
+            let registers = match function {
+                READ_HOLDING_REGISTERS => HOLDING_REGISTERS,
+                _ => INPUT_REGISTERS,
+            };
+            if u32::from(start) + u32::from(count) > u32::from(registers) {
+                return Err(ILLEGAL_DATA_ADDRESS);
+            }
+
+            out[0] = (count * 2) as u8;
+            for (i, address) in (start..start + count).enumerate() {
+                let value = match function {
+                    READ_HOLDING_REGISTERS => holding(address, context),
+                    _ => input(address, context),
+                };
+                out[1 + 2 * i..3 + 2 * i].copy_from_slice(&value.to_be_bytes());
+            }
+            Ok(1 + usize::from(count) * 2)
+        }
+        WRITE_SINGLE_REGISTER => {
+            let (address, value) = (word(0)?, word(2)?);
+            if address >= HOLDING_REGISTERS {
+                return Err(ILLEGAL_DATA_ADDRESS);
+            }
+            write(address, value, context)?;
+
+            out[..4].copy_from_slice(&data[..4]);
+            Ok(4)
+        }
+        WRITE_MULTIPLE_REGISTERS => {
+            let (start, count) = (word(0)?, word(2)?);
+            let values = data.get(5..).ok_or(ILLEGAL_DATA_VALUE)?;
+            if count == 0
+                || count > MAX_WRITE
+                || data[4] as usize != usize::from(count) * 2
+                || values.len() != usize::from(count) * 2
+            {
+                return Err(ILLEGAL_DATA_VALUE);
+            }
+            if u32::from(start) + u32::from(count) > u32::from(HOLDING_REGISTERS) {
+                return Err(ILLEGAL_DATA_ADDRESS);
+            }
+            for (address, value) in (start..).zip(values.chunks_exact(2)) {
+                write(address, u16::from_be_bytes([value[0], value[1]]), context)?;
+            }
+
+            out[..4].copy_from_slice(&data[..4]);
+            Ok(4)
+        }
+        _ => Err(ILLEGAL_FUNCTION),
+    }
+}
+
+fn holding(address: u16, context: &Context) -> u16 {
+    match address {
+        0 => u16::from(context.identifying),
+        1 => match crate::port::status() {
+            Some(status) => u16::from(status.enabled),
+            None => UNAVAILABLE,
+        },
+        _ => UNAVAILABLE,
+    }
+}
+
+fn write(address: u16, value: u16, context: &mut Context) -> Result<(), u8> {
+    let enable = match value {
+        0 => false,
+        1 => true,
+        _ => return Err(ILLEGAL_DATA_VALUE),
+    };
+
+    match address {
+        0 => {
+            (context.identify)(enable.then_some(crate::identify::DEFAULT));
+            Ok(())
+        }
+        1 => crate::port::set_enabled(enable).map_err(|err| {
+            log::warn!("Failed to set port: {}", err);
+            SERVER_DEVICE_FAILURE
+        }),
+        _ => Err(ILLEGAL_DATA_ADDRESS),
+    }
+}
+
+fn input(address: u16, context: &Context) -> u16 {
+    let stats = &context.statistics;
+    let reading = crate::port::meter::reading();
+
+    // Splits a 32-bit value across the register at `first` and the one after it
+    let split = |first: u16, value: u32| match address - first {
+        0 => (value >> 16) as u16,
+        _ => value as u16,
+    };
+
+    match address {
+        0 => match crate::port::status() {
+            Some(status) => u16::from(status.powered),
+            None => UNAVAILABLE,
+        },
+        1 => match context.link {
+            Some(LinkState {
+                speed: LinkSpeed::TenMbps,
+                ..
+            }) => 10,
+            Some(LinkState {
+                speed: LinkSpeed::HundredMbps,
+                ..
+            }) => 100,
+            None => 0,
+        },
+        2 => reading.map_or(UNAVAILABLE, |reading| {
+            u16::try_from(reading.current_ma).unwrap_or(u16::MAX)
+        }),
+        3 => crate::port::meter::NOMINAL_MV as u16,
+        4 | 5 => reading.map_or(UNAVAILABLE, |reading| split(4, reading.power_mw)),
+        6 | 7 => reading.map_or(UNAVAILABLE, |reading| {
+            split(6, u32::try_from(reading.energy_mwh).unwrap_or(u32::MAX))
+        }),
+        8 => match crate::sensors::temperature_c() {
+            Some(temperature) => (temperature * 10.0) as i16 as u16,
+            None => UNAVAILABLE,
+        },
+        9 | 10 => split(9, crate::port::protect::status().trips),
+        11 | 12 => split(11, context.now.secs() as u32),
+        13 | 14 => split(13, stats.rx_frames),
+        15 | 16 => split(15, stats.tx_frames),
+        17 | 18 => split(17, stats.rx_errors),
+        19 | 20 => split(19, stats.tx_errors),
+        _ => UNAVAILABLE,
+    }
+}
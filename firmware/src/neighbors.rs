@@ -0,0 +1,317 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A view of smoltcp's IPv4 neighbor (ARP) cache, which smoltcp keeps to itself. The cache is
+// mirrored by watching the ARP packets it learns from (see efm32gg::dispatch) and applying the same
+// rules: an entry is filled from any request or reply sent to the interface's address, lasts a
+// minute, and once the cache's slots are full, the entry closest to expiring is evicted to make
+// room. The evictions are counted, since they're what makes a busy subnet lose neighbors.
+//
+// Static entries are kept in smoltcp's cache by handing it an ARP reply on each neighbor's behalf
+// every half minute (see efm32gg's Device::receive), so they outlive the rest. smoltcp has no way
+// to remove its entries, so flushing only clears this view; the entries smoltcp holds run out their
+// minute, after which they're only refilled by new ARP traffic.
+
+use crate::network::events::Event;
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{
+    ArpOperation, ArpPacket, ArpRepr, EthernetAddress, EthernetFrame, EthernetProtocol,
+    EthernetRepr, Ipv4Address,
+};
+
+/// The number of slots in the neighbor cache, matching smoltcp's `iface-neighbor-cache-count-8`.
+pub const CACHE_SIZE: usize = 8;
+
+/// The most static entries, which take their slots from the cache.
+pub const MAX_STATIC: usize = 4;
+
+/// The length of the ARP replies made for static entries.
+pub const FRAME_LEN: usize = 42;
+
+// How long smoltcp keeps an entry once it's filled
+const ENTRY_LIFETIME: Duration = Duration::from_secs(60);
+
+// How often smoltcp is reminded of each static entry, well before it would expire
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    address: None,
+    entries: [None; CACHE_SIZE],
+    statics: [None; MAX_STATIC],
+    fills: 0,
+    evictions: 0,
+}));
+
+struct State {
+    // The interface's IPv4 address, which ARP packets have to be sent to
+    address: Option<Ipv4Address>,
+    entries: [Option<Entry>; CACHE_SIZE],
+    statics: [Option<Static>; MAX_STATIC],
+    fills: u32,
+    evictions: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    address: Ipv4Address,
+    hardware: EthernetAddress,
+    filled: Instant,
+}
+
+#[derive(Clone, Copy)]
+struct Static {
+    address: Ipv4Address,
+    hardware: EthernetAddress,
+    // When smoltcp was last handed a reply for the neighbor
+    refreshed: Option<Instant>,
+}
+
+#[derive(Clone, Copy)]
+pub struct Neighbor {
+    pub address: Ipv4Address,
+    pub hardware: EthernetAddress,
+    /// How long ago the entry was filled.
+    pub age: Duration,
+    /// Set once smoltcp no longer uses the entry, though it still holds a slot.
+    pub expired: bool,
+    pub permanent: bool,
+}
+
+pub struct Statistics {
+    /// The number of times an entry has been filled (or refreshed).
+    pub fills: u32,
+    /// The number of entries evicted to make room for another.
+    pub evictions: u32,
+}
+
+/// Follows the interface's IPv4 address. This is subscribed to the network events.
+pub fn observe(_: Instant, event: Event) {
+    let address = match event {
+        Event::AddressAcquired(address) => Some(address),
+        Event::AddressLost => None,
+        Event::LinkUp | Event::LinkDown => return,
+    };
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().address = address)
+}
+
+/// Returns the entries in the cache, oldest first.
+pub fn entries(now: Instant) -> [Option<Neighbor>; CACHE_SIZE] {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        let mut entries = state.entries;
+        entries.sort_unstable_by_key(|entry| entry.map(|entry| entry.filled));
+
+        let mut neighbors = [None; CACHE_SIZE];
+        for (neighbor, entry) in neighbors.iter_mut().zip(entries.iter().flatten()) {
+            *neighbor = Some(Neighbor {
+                address: entry.address,
+                hardware: entry.hardware,
+                age: now - entry.filled,
+                expired: now - entry.filled >= ENTRY_LIFETIME,
+                permanent: state
+                    .statics
+                    .iter()
+                    .flatten()
+                    .any(|s| s.address == entry.address),
+            });
+        }
+        neighbors
+    })
+}
+
+pub fn statistics() -> Statistics {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        Statistics {
+            fills: state.fills,
+            evictions: state.evictions,
+        }
+    })
+}
+
+/// Forgets the cache's dynamic entries and counters, and refreshes the static entries.
+pub fn flush() {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.entries = [None; CACHE_SIZE];
+        state.fills = 0;
+        state.evictions = 0;
+        for entry in state.statics.iter_mut().flatten() {
+            entry.refreshed = None;
+        }
+    })
+}
+
+/// Pins a neighbor's hardware address until it's removed (or the device is reset).
+pub fn add_static(address: Ipv4Address, hardware: EthernetAddress) -> Result<(), &'static str> {
+    if !address.is_unicast() || !hardware.is_unicast() {
+        return Err("the addresses must be unicast");
+    }
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        let slot = match state
+            .statics
+            .iter()
+            .position(|s| matches!(s, Some(s) if s.address == address))
+        {
+            Some(i) => i,
+            None => state
+                .statics
+                .iter()
+                .position(Option::is_none)
+                .ok_or("too many static neighbors")?,
+        };
+        state.statics[slot] = Some(Static {
+            address,
+            hardware,
+            refreshed: None,
+        });
+        Ok(())
+    })
+}
+
+/// Unpins a neighbor, which is then forgotten once its entry expires.
+pub fn remove_static(address: Ipv4Address) -> Result<(), &'static str> {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        let slot = state
+            .statics
+            .iter_mut()
+            .find(|s| matches!(s, Some(s) if s.address == address))
+            .ok_or("no such static neighbor")?;
+        *slot = None;
+        Ok(())
+    })
+}
+
+/// Returns true if smoltcp needs to be reminded of a static entry (see `take_refresh`).
+pub fn refresh_due(now: Instant) -> bool {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        state.address.is_some() && state.statics.iter().flatten().any(|s| stale(s, now))
+    })
+}
+
+/// Returns an ARP reply from the static neighbor that's gone longest without one, to be handed to
+/// smoltcp as though it had been received.
+pub fn take_refresh(now: Instant) -> Option<[u8; FRAME_LEN]> {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        let target = state.address?;
+        let neighbor = state
+            .statics
+            .iter_mut()
+            .flatten()
+            .filter(|s| stale(s, now))
+            .min_by_key(|s| s.refreshed)?;
+        neighbor.refreshed = Some(now);
+        let (address, hardware) = (neighbor.address, neighbor.hardware);
+        fill(&mut state, now, address, hardware);
+
+        let ethernet = EthernetRepr {
+            src_addr: hardware,
+            dst_addr: EthernetAddress::BROADCAST,
+            ethertype: EthernetProtocol::Arp,
+        };
+        let arp = ArpRepr::EthernetIpv4 {
+            operation: ArpOperation::Reply,
+            source_hardware_addr: hardware,
+            source_protocol_addr: address,
+            target_hardware_addr: EthernetAddress::BROADCAST,
+            target_protocol_addr: target,
+        };
+
+        let mut buffer = [0; FRAME_LEN];
+        let mut frame = EthernetFrame::new_unchecked(&mut buffer[..]);
+        ethernet.emit(&mut frame);
+        arp.emit(&mut ArpPacket::new_unchecked(frame.payload_mut()));
+        Some(buffer)
+    })
+}
+
+fn stale(neighbor: &Static, now: Instant) -> bool {
+    match neighbor.refreshed {
+        Some(refreshed) => now - refreshed >= REFRESH_INTERVAL,
+        None => true,
+    }
+}
+
+/// Fills the cache from a received ARP packet, as smoltcp will.
+pub fn receive(now: Instant, frame: &[u8]) {
+    let packet = match frame.get(12..14) {
+        Some(ethertype) if *ethertype == [0x08, 0x06] => &frame[14..],
+        _ => return,
+    };
+    let (source, hardware, target) =
+        match ArpPacket::new_checked(packet).and_then(|packet| ArpRepr::parse(&packet)) {
+            Ok(ArpRepr::EthernetIpv4 {
+                operation: ArpOperation::Request | ArpOperation::Reply,
+                source_hardware_addr,
+                source_protocol_addr,
+                target_protocol_addr,
+                ..
+            }) => (
+                source_protocol_addr,
+                source_hardware_addr,
+                target_protocol_addr,
+            ),
+            _ => return,
+        };
+    if !source.is_unicast() || !hardware.is_unicast() {
+        return;
+    }
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        if state.address == Some(target) {
+            fill(&mut state, now, source, hardware);
+        }
+    })
+}
+
+fn fill(state: &mut State, now: Instant, address: Ipv4Address, hardware: EthernetAddress) {
+    state.fills = state.fills.wrapping_add(1);
+
+    let entry = Entry {
+        address,
+        hardware,
+        filled: now,
+    };
+    let slot = match state
+        .entries
+        .iter()
+        .position(|e| matches!(e, Some(e) if e.address == address))
+        .or_else(|| state.entries.iter().position(Option::is_none))
+    {
+        Some(slot) => slot,
+        None => {
+            // The entry closest to expiring (i.e. the oldest) makes way
+            state.evictions = state.evictions.wrapping_add(1);
+            let (slot, evicted) = state
+                .entries
+                .iter()
+                .enumerate()
+                .filter_map(|(i, e)| e.map(|e| (i, e)))
+                .min_by_key(|(_, e)| e.filled)
+                .unwrap_or((0, entry));
+            log::debug!("Neighbor cache full; evicted {}", evicted.address);
+            slot
+        }
+    };
+    state.entries[slot] = Some(entry);
+}
@@ -0,0 +1,128 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Changes to the link and to the IPv4 address, published to whoever has subscribed. The network
+// resources publish them (see Resources::link_changed, configure, and deconfigure) and the
+// services that care are observers, so the binaries only have to pass on the link's debounced
+// state and subscribe anything of their own (e.g. the network LED).
+//
+// Observers are called from the network task with the interface locked, so they must not block
+// and can't touch the interface themselves. Anything that needs to send a frame leaves a note that
+// a before-poll handler picks up (see `announce`).
+
+use core::cell::RefCell;
+use core::fmt;
+use cortex_m::interrupt::{self, Mutex};
+use ignore_result::Ignore;
+use smoltcp::time::Instant;
+use smoltcp::wire::Ipv4Address;
+
+const MAX_OBSERVERS: usize = 8;
+
+pub type Observer = fn(Instant, Event);
+
+static OBSERVERS: Mutex<RefCell<[Option<Observer>; MAX_OBSERVERS]>> =
+    Mutex::new(RefCell::new([None; MAX_OBSERVERS]));
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    /// The link came up and settled.
+    LinkUp,
+    /// The link went down and stayed down.
+    LinkDown,
+    /// An IPv4 address was configured, either from DHCP or statically.
+    AddressAcquired(Ipv4Address),
+    /// The IPv4 address was removed.
+    AddressLost,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::LinkUp => f.pad("link up"),
+            Event::LinkDown => f.pad("link down"),
+            Event::AddressAcquired(address) => write!(f, "address acquired ({})", address),
+            Event::AddressLost => f.pad("address lost"),
+        }
+    }
+}
+
+/// Subscribes the firmware's own observers: logging, the services that follow the link and the
+/// address, the gratuitous ARP, the view of the neighbor cache, and the lifetime counters. This
+/// must be called once at boot, before the binary subscribes its own observers.
+pub fn init() {
+    // There's always room for these
+    for observer in [
+        log_event as Observer,
+        notify_services,
+        super::announce,
+        crate::neighbors::observe,
+        crate::lifetime::observe,
+    ] {
+        subscribe(observer).ignore();
+    }
+}
+
+/// Adds an observer, which is then called with every event, in the order of subscription.
+pub fn subscribe(observer: Observer) -> Result<(), &'static str> {
+    interrupt::free(|cs| {
+        let mut observers = OBSERVERS.borrow(cs).borrow_mut();
+        match observers.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(observer);
+                Ok(())
+            }
+            None => Err("too many network observers"),
+        }
+    })
+}
+
+/// Calls each of the observers with the event.
+pub fn publish(now: Instant, event: Event) {
+    // The observers are called outside of the critical section so that they can take their own
+    let observers = interrupt::free(|cs| *OBSERVERS.borrow(cs).borrow());
+    for observer in observers.iter().flatten() {
+        observer(now, event);
+    }
+}
+
+/// Logs each event.
+pub fn log_event(_: Instant, event: Event) {
+    match event {
+        Event::LinkUp => log::debug!("Link acquired"),
+        Event::LinkDown => log::debug!("Link lost"),
+        Event::AddressAcquired(address) => log::info!("IP address: {}", address),
+        Event::AddressLost => log::info!("IP address lost"),
+    }
+}
+
+/// Records each event in the operational log (see `crate::events`) and tells the services that
+/// follow the link.
+pub fn notify_services(now: Instant, event: Event) {
+    use crate::events::{record, Event as Record};
+
+    match event {
+        Event::LinkUp | Event::LinkDown => {
+            let up = event == Event::LinkUp;
+            record(now, if up { Record::LinkUp } else { Record::LinkDown });
+            crate::port::link_changed(up);
+            crate::lldp::link_changed(up, now);
+            crate::coap::link_changed();
+            crate::slaac::link_changed(up, now);
+        }
+        Event::AddressAcquired(address) => record(now, Record::AddressAcquired(address)),
+        Event::AddressLost => record(now, Record::AddressLost),
+    }
+}
@@ -0,0 +1,2070 @@
+// Copyright 2021 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+pub mod events;
+
+use self::events::Event;
+use crate::acl::Service;
+use crate::efm32gg::{Statistics, EFM32GG};
+use crate::error::Error;
+use crate::identify::Pattern;
+use crate::ptp::Adjustment;
+use crate::ratelimit::Class as RateClass;
+
+use control_protocol::{Request, Session, Step};
+use core::cell::RefCell;
+use core::fmt::Write;
+use cortex_m::interrupt::{self, Mutex};
+use ethernet_phy::{LinkDuplex, LinkSpeed, LinkState};
+use ignore_result::Ignore;
+use ksz8091::KSZ8091;
+
+use smoltcp::iface::{Interface, Route, SocketHandle, SocketSet, SocketStorage};
+use smoltcp::phy::{ChecksumCapabilities, Device, TxToken};
+use smoltcp::socket::tcp::State as TcpState;
+use smoltcp::socket::{dhcpv4, icmp, raw, tcp, udp};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{
+    ArpOperation, ArpPacket, ArpRepr, EthernetAddress, EthernetFrame, EthernetProtocol,
+    EthernetRepr, HardwareAddress, Icmpv4Packet, Icmpv4Repr, IpAddress, IpCidr, IpEndpoint,
+    Ipv4Address, Ipv4Cidr, Ipv6Address, Ipv6Cidr,
+};
+
+const CONTROL_PORT: u16 = 51900;
+
+// Long enough for a token (see `auth`) followed by a command byte and a hardware address
+const CONTROL_COMMAND_LEN: usize = 64;
+
+// Control commands that don't otherwise reply are answered with an ACK once they succeed, and any
+// command that fails is answered with a NAK followed by the reason (and a newline)
+const CONTROL_ACK: u8 = 0x06;
+const CONTROL_NAK: u8 = 0x15;
+
+// The reply to the status query ('s'): the layout's version, whether the device is identifying
+// (0 or 1), the link (bit 0 is set when it's up, bit 1 for 100 Mbps, and bit 2 for full duplex),
+// and the IPv4 address (zero without one) in network order
+const CONTROL_STATUS_VERSION: u8 = 1;
+const CONTROL_STATUS_LEN: usize = 7;
+
+// The organization-local multicast group joined by every device, so that the whole fleet can be
+// identified at once. Commands sent to it use the same syntax (and port number) as the control
+// port, though only identification is supported.
+const FLEET_GROUP: Ipv4Address = Ipv4Address::new(239, 255, 80, 69);
+const FLEET_PORT: u16 = CONTROL_PORT;
+
+// IGMP queries are sent to the all-systems group
+const ALL_SYSTEMS: Ipv4Address = Ipv4Address::new(224, 0, 0, 1);
+
+// The interface's address slots; the first is the IPv4 address from DHCP
+const LINK_LOCAL_SLOT: usize = 1;
+const GLOBAL_IPV6_SLOT: usize = 2;
+
+// Keep-alives are sent on idle connections so that a client that has vanished stops
+// acknowledging them and is dropped once the timeout expires
+const TCP_KEEP_ALIVE: Duration = Duration::from_secs(10);
+const TCP_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Commands are a single request and response, so a connection that's been open this long isn't
+// going to send anything
+const DEFAULT_TCP_IDLE_LIMIT: Duration = Duration::from_secs(60);
+
+static REAPER: Mutex<RefCell<Reaper>> = Mutex::new(RefCell::new(Reaper {
+    limit: Some(DEFAULT_TCP_IDLE_LIMIT),
+    connected: None,
+}));
+
+struct Reaper {
+    limit: Option<Duration>,
+    connected: Option<Instant>,
+}
+
+// Where the control connection is in its session, going by the socket's state
+fn session(state: TcpState) -> Session {
+    match state {
+        TcpState::Closed => Session::Closed,
+        TcpState::Listen | TcpState::SynSent | TcpState::SynReceived => Session::Listening,
+        TcpState::Established => Session::Open,
+        TcpState::CloseWait => Session::HalfClosed,
+        TcpState::FinWait1 | TcpState::FinWait2 | TcpState::Closing | TcpState::LastAck => {
+            Session::Closing
+        }
+        TcpState::TimeWait => Session::TimeWait,
+    }
+}
+
+static ANNOUNCEMENT: Mutex<RefCell<Announcement>> = Mutex::new(RefCell::new(Announcement {
+    address: None,
+    pending: false,
+    link_down: false,
+}));
+
+// The gratuitous ARP sent for the IPv4 address (see `announce`)
+struct Announcement {
+    address: Option<Ipv4Address>,
+    pending: bool,
+    // Only set while the link is known to be down, which binaries that don't publish the link's
+    // changes never do
+    link_down: bool,
+}
+
+/// What the handlers need from the device, beyond moving frames. The rest of the MAC and PHY (e.g.
+/// PTP timestamps, EEE, and the self-test) is only reached by the handlers that are specific to
+/// the EFM32GG, so the others can be run against any device (e.g. a loopback on the host).
+pub trait Link: Device {
+    /// Returns the negotiated link, or `None` while the link is down.
+    fn link_state(&self) -> Option<LinkState>;
+
+    fn statistics(&mut self) -> Statistics;
+
+    /// Accepts the frames sent to a multicast address.
+    fn join_multicast(&mut self, addr: EthernetAddress);
+}
+
+impl Link for EFM32GG<'static, KSZ8091> {
+    fn link_state(&self) -> Option<LinkState> {
+        EFM32GG::link_state(self)
+    }
+
+    fn statistics(&mut self) -> Statistics {
+        EFM32GG::statistics(self)
+    }
+
+    fn join_multicast(&mut self, addr: EthernetAddress) {
+        EFM32GG::join_multicast(self, addr)
+    }
+}
+
+pub struct Resources<L = EFM32GG<'static, KSZ8091>> {
+    pub interface: Interface,
+    pub device: L,
+    pub sockets: SocketSet<'static>,
+    pub dhcp_handle: SocketHandle,
+    pub tcp_handle: SocketHandle,
+    pub syslog_handle: Option<SocketHandle>,
+    pub probe_handle: Option<SocketHandle>,
+    pub snmp_handle: Option<SocketHandle>,
+    pub coap_handle: Option<SocketHandle>,
+    pub ndisc_handle: Option<SocketHandle>,
+    pub fleet_handle: Option<SocketHandle>,
+    pub discovery_handle: Option<SocketHandle>,
+    pub capture_handle: Option<SocketHandle>,
+    pub http_handles: Option<[SocketHandle; crate::http::MAX_CONNECTIONS]>,
+    pub modbus_handle: Option<SocketHandle>,
+    pub tftp_handle: Option<SocketHandle>,
+    pub tftp_transfer_handle: Option<SocketHandle>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum State {
+    Uninit,
+    NoLink,
+    NoDhcp,
+    NoGateway,
+    /// The DHCP server answered with a NAK, rather than a lease.
+    DhcpRefused,
+    Operational,
+}
+
+/// Sets how long a TCP connection may stay open before it's aborted, or disables the limit.
+pub fn set_tcp_idle_limit(limit: Option<Duration>) {
+    interrupt::free(|cs| REAPER.borrow(cs).borrow_mut().limit = limit)
+}
+
+pub fn tcp_idle_limit() -> Option<Duration> {
+    interrupt::free(|cs| REAPER.borrow(cs).borrow().limit)
+}
+
+/// Queues a gratuitous ARP whenever an address is acquired, or the link comes back up with one,
+/// so that neighbors holding a stale entry for it are corrected. It's sent by
+/// `Resources::handle_announce`.
+pub fn announce(_: Instant, event: Event) {
+    interrupt::free(|cs| {
+        let mut announcement = ANNOUNCEMENT.borrow(cs).borrow_mut();
+        match event {
+            Event::AddressAcquired(address) => {
+                announcement.address = Some(address);
+                announcement.pending = true;
+            }
+            Event::AddressLost => {
+                announcement.address = None;
+                announcement.pending = false;
+            }
+            Event::LinkUp => {
+                announcement.link_down = false;
+                announcement.pending = announcement.address.is_some();
+            }
+            Event::LinkDown => announcement.link_down = true,
+        }
+    })
+}
+
+/// Returns true if there's a gratuitous ARP waiting to be sent.
+pub fn announcement_pending() -> bool {
+    interrupt::free(|cs| {
+        let announcement = ANNOUNCEMENT.borrow(cs).borrow();
+        announcement.pending && !announcement.link_down
+    })
+}
+
+/// The services that own sockets. Each binary's sockets are added by `add_sockets!`, which labels
+/// every socket with its owner so that the `SocketPool` can be checked against them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SocketOwner {
+    Control,
+    Syslog,
+    Dhcp,
+    Probe,
+    Snmp,
+    Coap,
+    NeighborDiscovery,
+    Fleet,
+    Discovery,
+    Capture,
+    Http,
+    Modbus,
+    Tftp,
+    TftpTransfer,
+}
+
+impl SocketOwner {
+    /// Returns the number of sockets used by the service.
+    pub const fn sockets(self) -> usize {
+        match self {
+            SocketOwner::Http => crate::http::MAX_CONNECTIONS,
+            _ => 1,
+        }
+    }
+}
+
+/// Returns the number of sockets used by the services.
+pub const fn sockets_needed(owners: &[SocketOwner]) -> usize {
+    let mut needed = 0;
+    let mut i = 0;
+    while i < owners.len() {
+        needed += owners[i].sockets();
+        i += 1;
+    }
+    needed
+}
+
+/// The storage for the interface's sockets. The sockets are added with `add_sockets!`, which
+/// hands `storage` the number needed by the services in its table, so a pool that's too small for
+/// them fails to build rather than panicking once the sockets are added.
+pub struct SocketPool<const N: usize>([SocketStorage<'static>; N]);
+
+impl<const N: usize> SocketPool<N> {
+    pub const fn new() -> SocketPool<N> {
+        SocketPool([SocketStorage::EMPTY; N])
+    }
+
+    /// Returns the storage, to be handed to the socket set, checking that it has room for the
+    /// `NEEDED` sockets.
+    pub fn storage<const NEEDED: usize>(&mut self) -> &mut [SocketStorage<'static>] {
+        &mut self.0[..Room::<N, NEEDED>::LEN]
+    }
+}
+
+// The length of a pool of `N`, which fails to evaluate (when `storage` is instantiated) if that's
+// too few for the `NEEDED` sockets
+struct Room<const N: usize, const NEEDED: usize>;
+
+impl<const N: usize, const NEEDED: usize> Room<N, NEEDED> {
+    const LEN: usize = {
+        assert!(
+            NEEDED <= N,
+            "the socket pool is too small for the services that use it"
+        );
+        N
+    };
+}
+
+/// A socket, or an array of them for a service that owns several (see `SocketOwner::sockets`),
+/// that `add_sockets!` can add to the socket set.
+pub trait Allocate {
+    type Handles;
+
+    fn allocate(self, sockets: &mut SocketSet<'static>) -> Self::Handles;
+}
+
+macro_rules! allocate {
+    ($($socket:ty),+) => {
+        $(
+            impl Allocate for $socket {
+                type Handles = SocketHandle;
+
+                fn allocate(self, sockets: &mut SocketSet<'static>) -> SocketHandle {
+                    sockets.add(self)
+                }
+            }
+
+            impl<const M: usize> Allocate for [$socket; M] {
+                type Handles = [SocketHandle; M];
+
+                fn allocate(self, sockets: &mut SocketSet<'static>) -> [SocketHandle; M] {
+                    self.map(|socket| sockets.add(socket))
+                }
+            }
+        )+
+    };
+}
+
+allocate!(
+    dhcpv4::Socket<'static>,
+    icmp::Socket<'static>,
+    raw::Socket<'static>,
+    tcp::Socket<'static>,
+    udp::Socket<'static>
+);
+
+/// Adds a binary's sockets, from a table of each socket's owner, the name of its handle, and the
+/// socket itself (or an array, for `SocketOwner::Http`):
+///
+/// ```ignore
+/// poe::add_sockets!(cx.local.sockets => sockets {
+///     Control: tcp_handle = tcp::Socket::new(rx, tx),
+///     Dhcp: dhcp_handle = dhcpv4::Socket::new(),
+/// });
+/// ```
+///
+/// This declares the `SocketSet` (`sockets`, above) and each of the handles. The number of sockets
+/// the owners need is checked against the size of the `SocketPool` when the binary is built.
+#[macro_export]
+macro_rules! add_sockets {
+    ($pool:expr => $sockets:ident { $($owner:ident: $handle:ident = $socket:expr),+ $(,)? }) => {
+        let mut $sockets = smoltcp::iface::SocketSet::new($pool.storage::<{
+            $crate::network::sockets_needed(&[$($crate::network::SocketOwner::$owner),+])
+        }>());
+        $(
+            let $handle = $crate::network::Allocate::allocate($socket, &mut $sockets);
+        )+
+    };
+}
+
+/// Assigns the link-local address and joins the multicast groups needed for neighbor discovery.
+/// The interface needs room for three addresses: the IPv4 address, followed by the link-local and
+/// global IPv6 addresses.
+pub fn enable_ipv6<L: Link>(interface: &mut Interface, device: &mut L) {
+    let hardware_addr = ethernet_addr(interface);
+
+    let link_local = crate::slaac::link_local(hardware_addr);
+    log::info!("IPv6 link-local address: {}", link_local);
+    interface.update_ip_addrs(|addrs| addrs[LINK_LOCAL_SLOT] = IpCidr::Ipv6(link_local));
+
+    device.join_multicast(crate::slaac::ALL_NODES);
+    device.join_multicast(crate::slaac::solicited_node(hardware_addr));
+}
+
+// The interface is only ever built on Ethernet, so this is its only kind of hardware address
+fn ethernet_addr(interface: &Interface) -> EthernetAddress {
+    match interface.hardware_addr() {
+        HardwareAddress::Ethernet(addr) => addr,
+    }
+}
+
+impl<L: Link> Resources<L> {
+    /// Moves frames between the device and the sockets, returning true if any socket's state may
+    /// have changed.
+    pub fn poll(&mut self, timestamp: Instant) -> bool {
+        self.interface
+            .poll(timestamp, &mut self.device, &mut self.sockets)
+    }
+
+    /// Returns how long until the interface next needs to be polled, if it has anything pending.
+    pub fn poll_delay(&mut self, timestamp: Instant) -> Option<Duration> {
+        self.interface.poll_delay(timestamp, &self.sockets)
+    }
+
+    pub fn handle_sockets<D, I>(&mut self, timestamp: Instant, dhcp: D, mut identify: I)
+    where
+        D: FnOnce(State),
+        I: FnMut(Option<Pattern>),
+    {
+        self.handle_dhcp(timestamp, dhcp);
+        self.handle_tcp(timestamp, &mut identify);
+        self.reap_tcp(timestamp);
+        self.handle_fleet(&mut identify);
+        self.handle_snmp(timestamp, &mut identify);
+        self.handle_coap(&mut identify);
+        self.handle_discovery(timestamp);
+        self.handle_http(&mut identify);
+        self.handle_modbus(timestamp, &mut identify);
+        self.handle_tftp(timestamp);
+    }
+
+    /// Queues any pending syslog messages for transmission. This should be called before polling
+    /// the interface so they are sent right away.
+    pub fn handle_syslog(&mut self, timestamp: Instant) {
+        let handle = match self.syslog_handle {
+            Some(handle) => handle,
+            None => return,
+        };
+
+        // Wait until DHCP has provided an address
+        if self.interface.ip_addrs()[0].address().is_unspecified() {
+            return;
+        }
+
+        let socket = self.sockets.get_mut::<udp::Socket>(handle);
+        if !socket.is_open() {
+            if let Err(err) = socket.bind(crate::log::syslog::PORT) {
+                log::error!("{}", Error::Bind(crate::log::syslog::PORT, err.into()));
+                return;
+            }
+        }
+
+        crate::log::syslog::drain(timestamp, |collector, message| {
+            socket.send_slice(message, collector).is_ok()
+        });
+    }
+
+    /// Records any replies to the downstream port's liveness probe and sends the next echo
+    /// request, if one is due. Like `handle_syslog`, this should be called before polling the
+    /// interface.
+    pub fn handle_probe(&mut self, timestamp: Instant) {
+        let handle = match self.probe_handle {
+            Some(handle) => handle,
+            None => return,
+        };
+
+        let socket = self.sockets.get_mut::<icmp::Socket>(handle);
+        if !socket.is_open() {
+            let endpoint = icmp::Endpoint::Ident(crate::port::schedule::PROBE_IDENT);
+            if let Err(err) = socket.bind(endpoint) {
+                log::error!("Failed to bind probe socket: {}", err);
+                return;
+            }
+        }
+
+        let checksum = ChecksumCapabilities::default();
+        while let Ok((payload, _)) = socket.recv() {
+            let packet = match Icmpv4Packet::new_checked(payload) {
+                Ok(packet) => packet,
+                Err(_) => continue,
+            };
+            if let Ok(Icmpv4Repr::EchoReply {
+                ident: crate::port::schedule::PROBE_IDENT,
+                seq_no,
+                ..
+            }) = Icmpv4Repr::parse(&packet, &checksum)
+            {
+                crate::port::schedule::reply(seq_no);
+            }
+        }
+
+        if !socket.can_send() {
+            return;
+        }
+
+        if let Some((target, seq_no)) = crate::port::schedule::take_probe(timestamp) {
+            let repr = Icmpv4Repr::EchoRequest {
+                ident: crate::port::schedule::PROBE_IDENT,
+                seq_no,
+                data: &[],
+            };
+            match socket.send(repr.buffer_len(), IpAddress::Ipv4(target)) {
+                Ok(buffer) => repr.emit(&mut Icmpv4Packet::new_unchecked(buffer), &checksum),
+                Err(err) => log::warn!("Failed to send probe: {}", err),
+            }
+        }
+    }
+
+    /// Sends any pending Wake-on-LAN magic packet. Like `handle_lldp`, this should be called
+    /// before polling the interface.
+    pub fn handle_wol(&mut self, timestamp: Instant) {
+        let source = ethernet_addr(&self.interface);
+        let (frame, target) = match crate::wol::take_frame(source) {
+            Some(packet) => packet,
+            None => return,
+        };
+
+        match self.device.transmit(timestamp) {
+            Some(token) => {
+                token.consume(frame.len(), |buffer| buffer.copy_from_slice(&frame));
+                log::info!("Sent magic packet to {}", target);
+            }
+            None => log::warn!("Failed to send magic packet: no transmit buffers"),
+        }
+    }
+
+    /// Sends the captured frames to the client connected to the capture port, when capturing over
+    /// TCP. Like `handle_syslog`, this should be called before polling the interface.
+    pub fn handle_capture(&mut self) {
+        let handle = match self.capture_handle {
+            Some(handle) => handle,
+            None => return,
+        };
+
+        let socket = self.sockets.get_mut::<tcp::Socket>(handle);
+        if crate::capture::sink() != Some(crate::capture::Sink::Tcp) {
+            socket.close();
+            return;
+        }
+        if !socket.is_open() {
+            if let Err(err) = socket.listen(crate::capture::PORT) {
+                log::error!("{}", Error::Bind(crate::capture::PORT, err.into()));
+                return;
+            }
+        }
+
+        if let Some(remote) = socket.remote_endpoint() {
+            if !crate::acl::permits(remote.addr) {
+                log::debug!("Rejecting capture connection from {}", remote);
+                socket.abort();
+                return;
+            }
+        }
+
+        // Until someone connects, there's nowhere for the capture to go
+        if !socket.may_send() {
+            crate::capture::restart();
+            return;
+        }
+        crate::capture::drain(|bytes| socket.send_slice(bytes).unwrap_or(0));
+    }
+
+    /// Sends the next block of the TFTP transfer, or sends the last one again if it hasn't been
+    /// acknowledged in time. This should be called before polling the interface so it is sent
+    /// right away.
+    pub fn handle_tftp_transfer(&mut self, timestamp: Instant) {
+        let handle = match self.tftp_transfer_handle {
+            Some(handle) => handle,
+            None => return,
+        };
+
+        let socket = self.sockets.get_mut::<udp::Socket>(handle);
+        if !socket.is_open() || !socket.can_send() {
+            return;
+        }
+
+        let mut packet = [0; crate::tftp::MAX_PACKET_LEN];
+        if let Some((client, len)) = crate::tftp::take_block(timestamp, &mut packet) {
+            socket
+                .send_slice(&packet[..len], client)
+                .map_err(|err| log::warn!("Failed to send TFTP block: {}", err))
+                .ignore();
+        }
+    }
+
+    /// Broadcasts a discovery announcement, if one is due. Like `handle_syslog`, this should be
+    /// called before polling the interface.
+    pub fn handle_beacons(&mut self, timestamp: Instant) {
+        let handle = match self.discovery_handle {
+            Some(handle) => handle,
+            None => return,
+        };
+        if !crate::acl::enabled(Service::Discovery) || !crate::discovery::take_beacon(timestamp) {
+            return;
+        }
+
+        let mut announcement = [0; crate::discovery::MAX_MESSAGE_LEN];
+        let len = match self.announcement(&mut announcement) {
+            Some(len) => len,
+            None => return,
+        };
+
+        let socket = self.sockets.get_mut::<udp::Socket>(handle);
+        if !socket.is_open() {
+            if let Err(err) = socket.bind(crate::discovery::PORT) {
+                log::error!("{}", Error::Bind(crate::discovery::PORT, err.into()));
+                return;
+            }
+        }
+        socket
+            .send_slice(
+                &announcement[..len],
+                IpEndpoint::new(Ipv4Address::BROADCAST.into(), crate::discovery::PORT),
+            )
+            .map_err(|err| log::warn!("Failed to send discovery beacon: {}", err))
+            .ignore();
+    }
+
+    /// Sends any operational events to the SNMP trap receiver, once there's an address to send
+    /// them from. Like `handle_syslog`, this should be called before polling the interface.
+    pub fn handle_traps(&mut self, timestamp: Instant) {
+        let handle = match self.snmp_handle {
+            Some(handle) => handle,
+            None => return,
+        };
+        let receiver = match crate::snmp::trap_receiver() {
+            Some(receiver) => receiver,
+            None => {
+                while crate::events::take_trap().is_some() {}
+                return;
+            }
+        };
+        if self.interface.ip_addrs()[0].address().is_unspecified() {
+            return;
+        }
+
+        let socket = self.sockets.get_mut::<udp::Socket>(handle);
+        if !socket.is_open() {
+            if let Err(err) = socket.bind(crate::snmp::PORT) {
+                log::error!("{}", Error::Bind(crate::snmp::PORT, err.into()));
+                return;
+            }
+        }
+
+        let mut trap = [0; crate::snmp::MAX_MESSAGE_LEN];
+        while socket.can_send() {
+            let entry = match crate::events::take_trap() {
+                Some(entry) => entry,
+                None => break,
+            };
+            if let Some(len) = crate::snmp::trap(&mut trap, timestamp, &entry) {
+                socket
+                    .send_slice(
+                        &trap[..len],
+                        IpEndpoint::new(receiver, crate::snmp::TRAP_PORT),
+                    )
+                    .map_err(|err| log::warn!("Failed to send SNMP trap: {}", err))
+                    .ignore();
+            }
+        }
+    }
+
+    /// Notifies any CoAP observers of a change in the link. Like `handle_syslog`, this should be
+    /// called before polling the interface.
+    pub fn handle_coap_observers(&mut self) {
+        let handle = match self.coap_handle {
+            Some(handle) if crate::coap::notification_pending() => handle,
+            _ => return,
+        };
+
+        let context = self.coap_context();
+        let socket = self.sockets.get_mut::<udp::Socket>(handle);
+        let mut buffer = [0; crate::coap::MAX_MESSAGE_LEN];
+        while socket.can_send() {
+            match crate::coap::take_notification(&mut buffer, &context) {
+                Some((endpoint, len)) => socket
+                    .send_slice(&buffer[..len], endpoint)
+                    .map_err(|err| log::warn!("Failed to send CoAP notification: {}", err))
+                    .ignore(),
+                None => break,
+            }
+        }
+    }
+
+    /// Handles router advertisements, assigning the global IPv6 address and default route from
+    /// them, and sends router solicitations when the link comes up. Like `handle_syslog`, this
+    /// should be called before polling the interface.
+    pub fn handle_slaac(&mut self, timestamp: Instant) {
+        let handle = match self.ndisc_handle {
+            Some(handle) => handle,
+            None => return,
+        };
+        let hardware_addr = ethernet_addr(&self.interface);
+
+        let socket = self.sockets.get_mut::<raw::Socket>(handle);
+        while let Ok(packet) = socket.recv() {
+            crate::slaac::receive(timestamp, hardware_addr, packet);
+        }
+        if let Some(solicitation) = crate::slaac::take_solicitation(timestamp, hardware_addr) {
+            socket
+                .send_slice(&solicitation)
+                .map_err(|err| log::warn!("Failed to send router solicitation: {}", err))
+                .ignore();
+        }
+
+        let iface = &mut self.interface;
+        match crate::slaac::poll(timestamp) {
+            None => {}
+            Some(crate::slaac::Event::Configured { address, router }) => {
+                log::info!("IPv6 address: {}", address);
+                iface.update_ip_addrs(|addrs| addrs[GLOBAL_IPV6_SLOT] = IpCidr::Ipv6(address));
+
+                if let Some(router) = router {
+                    log::debug!("IPv6 default gateway: {}", router);
+                    if let Err(err) = iface.routes_mut().add_default_ipv6_route(router) {
+                        log::error!("{}", Error::Route(err));
+                    }
+                } else {
+                    log::debug!("IPv6 default gateway: None");
+                    iface.routes_mut().remove_default_ipv6_route();
+                }
+            }
+            Some(crate::slaac::Event::Deconfigured) => {
+                log::debug!("IPv6 address lost");
+                iface.update_ip_addrs(|addrs| {
+                    addrs[GLOBAL_IPV6_SLOT] =
+                        IpCidr::Ipv6(Ipv6Cidr::new(Ipv6Address::UNSPECIFIED, 0))
+                });
+                iface.routes_mut().remove_default_ipv6_route();
+            }
+        }
+    }
+
+    /// Sends any pending gratuitous ARP (see `announce`). Like `handle_lldp`, this should be
+    /// called before polling the interface.
+    pub fn handle_announce(&mut self, timestamp: Instant) {
+        let source = ethernet_addr(&self.interface);
+        if !announcement_pending() || self.device.link_state().is_none() {
+            return;
+        }
+        let address = interrupt::free(|cs| {
+            let mut announcement = ANNOUNCEMENT.borrow(cs).borrow_mut();
+            announcement.pending = false;
+            announcement.address
+        });
+        let address = match address {
+            Some(address) => address,
+            None => return,
+        };
+
+        let arp = ArpRepr::EthernetIpv4 {
+            operation: ArpOperation::Request,
+            source_hardware_addr: source,
+            source_protocol_addr: address,
+            target_hardware_addr: EthernetAddress([0; 6]),
+            target_protocol_addr: address,
+        };
+        let ethernet = EthernetRepr {
+            src_addr: source,
+            dst_addr: EthernetAddress::BROADCAST,
+            ethertype: EthernetProtocol::Arp,
+        };
+
+        let len = ethernet.buffer_len() + arp.buffer_len();
+        match self.device.transmit(timestamp) {
+            Some(token) => {
+                token.consume(len, |buffer| {
+                    let mut frame = EthernetFrame::new_unchecked(buffer);
+                    ethernet.emit(&mut frame);
+                    arp.emit(&mut ArpPacket::new_unchecked(frame.payload_mut()));
+                });
+                log::debug!("Announced {}", address);
+            }
+            None => log::warn!("Failed to announce address: no transmit buffers"),
+        }
+    }
+
+    pub fn reset_dhcp(&mut self, timestamp: Instant) {
+        self.sockets
+            .get_mut::<dhcpv4::Socket>(self.dhcp_handle)
+            .reset();
+        crate::dhcp::restarted(timestamp);
+    }
+
+    /// Restarts the DHCP client if its attempt to acquire a lease has timed out (see `dhcp`).
+    pub fn handle_dhcp_retry(&mut self, timestamp: Instant) {
+        if crate::config::addressing().address.is_some()
+            || self.device.link_state().is_none()
+            || !crate::dhcp::due(timestamp)
+        {
+            return;
+        }
+
+        let failure = crate::dhcp::retried(timestamp);
+        log::warn!("No DHCP lease ({}); starting over", failure);
+        self.sockets
+            .get_mut::<dhcpv4::Socket>(self.dhcp_handle)
+            .reset();
+    }
+
+    /// Gives the interface any change to the static routes (see `routes`), and records its route
+    /// table for display.
+    pub fn handle_routes(&mut self) {
+        let routes = self.interface.routes_mut();
+
+        if let Some(statics) = crate::routes::take_change() {
+            routes.update(|table| {
+                // Every IPv4 route other than the default is a static one
+                table.retain(
+                    |route| !matches!(route.cidr, IpCidr::Ipv4(cidr) if cidr.prefix_len() > 0),
+                );
+
+                for route in statics.iter().flatten() {
+                    let destination = IpCidr::Ipv4(route.destination);
+                    let route = Route {
+                        cidr: destination,
+                        via_router: IpAddress::Ipv4(route.gateway),
+                        preferred_until: None,
+                        expires_at: None,
+                    };
+                    if table.push(route).is_err() {
+                        log::error!("Failed to add route to {}: table full", destination);
+                    }
+                }
+            });
+        }
+
+        let mut recorded = [None; crate::routes::TABLE_SIZE];
+        routes.update(|table| {
+            for (slot, route) in recorded.iter_mut().zip(table.iter()) {
+                *slot = Some((route.cidr, route.via_router));
+            }
+        });
+        crate::routes::record_table(recorded);
+    }
+
+    /// Applies a change to the IPv4 addressing (see `config`), and shows the resulting state if the
+    /// link is up.
+    pub fn handle_config<F: FnOnce(State)>(&mut self, timestamp: Instant, state: F) {
+        let addressing = match crate::config::take_change() {
+            Some(addressing) => addressing,
+            None => return,
+        };
+        let link = self.device.link_state().is_some();
+
+        match addressing.address {
+            Some(address) => {
+                log::info!("Using static address");
+                self.configure(timestamp, address, addressing.gateway);
+                if link {
+                    state(State::Operational);
+                }
+            }
+            None => {
+                log::info!("Using DHCP");
+                self.deconfigure(timestamp);
+                self.reset_dhcp(timestamp);
+                if link {
+                    state(State::NoDhcp);
+                }
+            }
+        }
+    }
+
+    fn handle_dhcp<F: FnOnce(State)>(&mut self, timestamp: Instant, dhcp: F) {
+        let event = self
+            .sockets
+            .get_mut::<dhcpv4::Socket>(self.dhcp_handle)
+            .poll();
+
+        // Leases are ignored while there's a static address
+        if crate::config::addressing().address.is_some() {
+            return;
+        }
+
+        match event {
+            // smoltcp quietly starts over when its request is refused, so that's noticed here
+            None => {
+                if crate::dhcp::take_refusal() {
+                    dhcp(State::DhcpRefused);
+                }
+            }
+            Some(dhcpv4::Event::Configured(config)) => {
+                log::debug!("DHCP config acquired");
+                dhcp(State::Operational);
+                for (i, s) in config.dns_servers.iter().enumerate() {
+                    log::debug!("DNS server {}:    {}", i, s);
+                }
+
+                let (address, router) = (config.address, config.router);
+                self.configure(timestamp, address, router);
+            }
+            Some(dhcpv4::Event::Deconfigured) => {
+                log::debug!("DHCP config lost");
+                dhcp(State::NoDhcp);
+                self.deconfigure(timestamp);
+            }
+        }
+    }
+
+    fn configure(&mut self, timestamp: Instant, address: Ipv4Cidr, router: Option<Ipv4Address>) {
+        let iface = &mut self.interface;
+
+        iface.update_ip_addrs(|addrs| addrs[0] = IpCidr::Ipv4(address));
+        crate::fault::set_source(Some((ethernet_addr(iface), address.address())));
+        if self.discovery_handle.is_some() {
+            crate::discovery::configured(timestamp);
+        }
+
+        // The MAC needs to accept the group's frames before the membership is reported
+        if self.fleet_handle.is_some() {
+            let device = &mut self.device;
+            device.join_multicast(ipv4_multicast_addr(ALL_SYSTEMS));
+            device.join_multicast(ipv4_multicast_addr(FLEET_GROUP));
+            iface
+                .join_multicast_group(device, FLEET_GROUP, timestamp)
+                .map_err(|err| log::warn!("Failed to join fleet group: {}", err))
+                .ignore();
+        }
+
+        if let Some(router) = router {
+            log::debug!("Default gateway: {}", router);
+            if let Err(err) = iface.routes_mut().add_default_ipv4_route(router) {
+                log::error!("{}", Error::Route(err));
+            }
+        } else {
+            log::debug!("Default gateway: None");
+            iface.routes_mut().remove_default_ipv4_route();
+        }
+
+        events::publish(timestamp, Event::AddressAcquired(address.address()));
+    }
+
+    fn deconfigure(&mut self, timestamp: Instant) {
+        let iface = &mut self.interface;
+
+        iface.update_ip_addrs(|addrs| {
+            addrs[0] = IpCidr::Ipv4(Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, 0))
+        });
+        iface.routes_mut().remove_default_ipv4_route();
+        crate::fault::set_source(None);
+
+        if self.fleet_handle.is_some() {
+            iface
+                .leave_multicast_group(&mut self.device, FLEET_GROUP, timestamp)
+                .map_err(|err| log::warn!("Failed to leave fleet group: {}", err))
+                .ignore();
+        }
+
+        events::publish(timestamp, Event::AddressLost);
+    }
+
+    fn handle_tcp<F: FnMut(Option<Pattern>)>(&mut self, timestamp: Instant, identify: &mut F) {
+        let status = self.control_status();
+        let socket = self.sockets.get_mut::<tcp::Socket>(self.tcp_handle);
+        if !crate::acl::enabled(Service::Control) {
+            socket.abort();
+            return;
+        }
+
+        let session = session(socket.state());
+        let remote = socket.remote_endpoint();
+        if session.connected() {
+            // smoltcp accepts every connection, so those from elsewhere are reset once established
+            match remote {
+                Some(remote) if crate::acl::permits(remote.addr) => {}
+                _ => {
+                    log::debug!("Rejecting control connection from {:?}", remote);
+                    socket.abort();
+                    return;
+                }
+            }
+
+            crate::config::contacted();
+        }
+
+        match session.step(socket.can_recv()) {
+            Step::Listen => {
+                if let Err(err) = socket.listen(CONTROL_PORT) {
+                    log::error!("{}", Error::Bind(CONTROL_PORT, err.into()));
+                    return;
+                }
+                socket.set_keep_alive(Some(TCP_KEEP_ALIVE));
+                socket.set_timeout(Some(TCP_TIMEOUT));
+                return;
+            }
+            Step::Wait => return,
+            Step::Close => {
+                socket.close();
+                return;
+            }
+            Step::Read => {}
+        }
+
+        // A connection that can be read from has been established (and accepted above)
+        let remote = match remote {
+            Some(remote) => remote,
+            None => return,
+        };
+        let mut buffer = [0; CONTROL_COMMAND_LEN];
+        let received = socket.recv(|b| {
+            let len = b.len().min(buffer.len());
+            buffer[..len].copy_from_slice(&b[..len]);
+            (b.len(), len)
+        });
+        let len = match received {
+            Ok(len) => len,
+            Err(err) => {
+                log::warn!("Failed to receive control command: {}", err);
+                socket.abort();
+                return;
+            }
+        };
+
+        // Once a password is set, commands that change state need a token (see Request)
+        let request = Request::parse(&buffer[..len]);
+        let authorized =
+            !request.privileged() || crate::auth::verify(request.token, remote.addr, timestamp);
+        if !authorized {
+            log::debug!("Refusing unauthorized control command from {}", remote);
+            refuse(socket, "unauthorized");
+            socket.close();
+            return;
+        }
+
+        match request.command {
+            Some(b'a') => match core::str::from_utf8(request.argument)
+                .map_err(|_| "invalid password")
+                .and_then(|password| crate::auth::login(password.trim(), remote.addr, timestamp))
+            {
+                Ok(token) => writeln!(socket, "{}", token).ignore(),
+                Err(err) => refuse(socket, err),
+            },
+            Some(b'0') => {
+                identify(None);
+                acknowledge(socket);
+            }
+            Some(b'1') => match pattern(request.argument) {
+                Ok(pattern) => {
+                    identify(Some(pattern));
+                    acknowledge(socket);
+                }
+                Err(err) => refuse(socket, err),
+            },
+            Some(b'P') => match crate::port::set_enabled(true) {
+                Ok(()) => acknowledge(socket),
+                Err(err) => {
+                    log::warn!("Failed to enable port: {}", err);
+                    refuse(socket, err);
+                }
+            },
+            Some(b'p') => match crate::port::set_enabled(false) {
+                Ok(()) => acknowledge(socket),
+                Err(err) => {
+                    log::warn!("Failed to disable port: {}", err);
+                    refuse(socket, err);
+                }
+            },
+            Some(b'c') => match crate::port::cycle(crate::port::DEFAULT_CYCLE_TIME) {
+                Ok(()) => acknowledge(socket),
+                Err(err) => {
+                    log::warn!("Failed to cycle port: {}", err);
+                    refuse(socket, err);
+                }
+            },
+            Some(b'e') => match crate::port::meter::reading() {
+                Some(reading) => {
+                    writeln!(socket, "{} {}", reading.power_mw, reading.energy_mwh).ignore()
+                }
+                None => refuse(socket, "unavailable"),
+            },
+            Some(b's') => socket.send_slice(&status).ignore(),
+            Some(b't') => match crate::sensors::temperature_c() {
+                Some(temperature) => writeln!(socket, "{:.1}", temperature).ignore(),
+                None => refuse(socket, "unavailable"),
+            },
+            Some(b'w') => match core::str::from_utf8(request.argument)
+                .ok()
+                .and_then(|arg| arg.trim().parse::<EthernetAddress>().ok())
+                .ok_or("invalid address")
+                .and_then(crate::wol::request)
+            {
+                Ok(()) => acknowledge(socket),
+                Err(err) => refuse(socket, err),
+            },
+            Some(_) => refuse(socket, "unknown command"),
+            None => {}
+        }
+
+        socket.close();
+    }
+
+    // Builds the reply to the control status query (see CONTROL_STATUS_LEN)
+    fn control_status(&self) -> [u8; CONTROL_STATUS_LEN] {
+        let link = match self.device.link_state() {
+            Some(LinkState { speed, duplex }) => {
+                let speed = match speed {
+                    LinkSpeed::TenMbps => 0,
+                    LinkSpeed::HundredMbps => 0b010,
+                };
+                let duplex = match duplex {
+                    LinkDuplex::HalfDuplex => 0,
+                    LinkDuplex::FullDuplex => 0b100,
+                };
+                0b001 | speed | duplex
+            }
+            None => 0,
+        };
+        let address = match self.interface.ip_addrs()[0].address() {
+            IpAddress::Ipv4(addr) => addr,
+            _ => Ipv4Address::UNSPECIFIED,
+        };
+
+        let mut status = [0; CONTROL_STATUS_LEN];
+        status[0] = CONTROL_STATUS_VERSION;
+        status[1] = crate::identify::active().is_some() as u8;
+        status[2] = link;
+        status[3..7].copy_from_slice(address.as_bytes());
+        status
+    }
+
+    // Aborts the control connection if it has been open for longer than the idle limit, so that
+    // a client that stops responding doesn't block new connections until the timeout expires
+    fn reap_tcp(&mut self, timestamp: Instant) {
+        let socket = self.sockets.get_mut::<tcp::Socket>(self.tcp_handle);
+        let active = socket.is_active();
+        let expired = interrupt::free(|cs| {
+            let mut reaper = REAPER.borrow(cs).borrow_mut();
+            match (active, reaper.connected) {
+                (false, _) => {
+                    reaper.connected = None;
+                    false
+                }
+                (true, None) => {
+                    reaper.connected = Some(timestamp);
+                    false
+                }
+                (true, Some(connected)) => {
+                    matches!(reaper.limit, Some(limit) if timestamp >= connected + limit)
+                }
+            }
+        });
+
+        if expired {
+            if let Some(remote) = socket.remote_endpoint() {
+                log::warn!("Aborting idle connection from {}", remote);
+            }
+            socket.abort();
+        }
+    }
+
+    fn handle_fleet<F: FnMut(Option<Pattern>)>(&mut self, identify: &mut F) {
+        let handle = match self.fleet_handle {
+            Some(handle) => handle,
+            None => return,
+        };
+
+        let socket = self.sockets.get_mut::<udp::Socket>(handle);
+        if !crate::acl::enabled(Service::Fleet) {
+            socket.close();
+            return;
+        }
+        if !socket.is_open() {
+            if let Err(err) = socket.bind(FLEET_PORT) {
+                log::error!("{}", Error::Bind(FLEET_PORT, err.into()));
+                return;
+            }
+        }
+
+        while let Ok((command, metadata)) = socket.recv() {
+            let endpoint = metadata.endpoint;
+            if !crate::acl::permits(endpoint.addr) {
+                log::debug!("Rejecting fleet command from {}", endpoint);
+                continue;
+            }
+
+            match command.split_first() {
+                Some((b'0', _)) => identify(None),
+                Some((b'1', argument)) => match pattern(argument) {
+                    Ok(pattern) => identify(Some(pattern)),
+                    Err(err) => log::debug!("Ignoring fleet command from {}: {}", endpoint, err),
+                },
+                _ => log::debug!("Ignoring fleet command from {}", endpoint),
+            }
+        }
+    }
+
+    fn handle_snmp<F: FnMut(Option<Pattern>)>(&mut self, timestamp: Instant, identify: &mut F) {
+        let handle = match self.snmp_handle {
+            Some(handle) => handle,
+            None => return,
+        };
+
+        let socket = self.sockets.get_mut::<udp::Socket>(handle);
+        if !crate::acl::enabled(Service::Snmp) {
+            socket.close();
+            return;
+        }
+        if !socket.is_open() {
+            if let Err(err) = socket.bind(crate::snmp::PORT) {
+                log::error!("{}", Error::Bind(crate::snmp::PORT, err.into()));
+                return;
+            }
+        }
+        if !socket.can_recv() {
+            return;
+        }
+
+        let hardware_addr = ethernet_addr(&self.interface);
+        let link = self.device.link_state();
+        let statistics = self.device.statistics();
+        let mut context = crate::snmp::Context {
+            now: timestamp,
+            hardware_addr,
+            hostname: crate::hostname::get(),
+            link,
+            statistics,
+            identifying: crate::identify::active().is_some(),
+            identify,
+        };
+
+        let socket = self.sockets.get_mut::<udp::Socket>(handle);
+        let mut response = [0; crate::snmp::MAX_MESSAGE_LEN];
+        while let Ok((request, metadata)) = socket.recv() {
+            let endpoint = metadata.endpoint;
+            if !crate::acl::permits(endpoint.addr) {
+                log::debug!("Rejecting SNMP request from {}", endpoint);
+                continue;
+            }
+            crate::config::contacted();
+
+            if let Some(len) = crate::snmp::handle(request, &mut response, &mut context) {
+                socket
+                    .send_slice(&response[..len], endpoint)
+                    .map_err(|err| log::warn!("Failed to send SNMP response: {}", err))
+                    .ignore();
+            }
+        }
+    }
+
+    fn handle_coap<F: FnMut(Option<Pattern>)>(&mut self, identify: &mut F) {
+        let handle = match self.coap_handle {
+            Some(handle) => handle,
+            None => return,
+        };
+
+        let socket = self.sockets.get_mut::<udp::Socket>(handle);
+        if !crate::acl::enabled(Service::Coap) {
+            socket.close();
+            return;
+        }
+        if !socket.is_open() {
+            if let Err(err) = socket.bind(crate::coap::PORT) {
+                log::error!("{}", Error::Bind(crate::coap::PORT, err.into()));
+                return;
+            }
+        }
+        if !socket.can_recv() {
+            return;
+        }
+
+        let context = self.coap_context();
+        let socket = self.sockets.get_mut::<udp::Socket>(handle);
+        let mut response = [0; crate::coap::MAX_MESSAGE_LEN];
+        while let Ok((request, metadata)) = socket.recv() {
+            let endpoint = metadata.endpoint;
+            if !crate::acl::permits(endpoint.addr) {
+                log::debug!("Rejecting CoAP request from {}", endpoint);
+                continue;
+            }
+            crate::config::contacted();
+
+            if let Some(len) =
+                crate::coap::handle(request, endpoint, &mut response, &context, identify)
+            {
+                socket
+                    .send_slice(&response[..len], endpoint)
+                    .map_err(|err| log::warn!("Failed to send CoAP response: {}", err))
+                    .ignore();
+            }
+        }
+    }
+
+    fn handle_discovery(&mut self, timestamp: Instant) {
+        let handle = match self.discovery_handle {
+            Some(handle) => handle,
+            None => return,
+        };
+
+        let socket = self.sockets.get_mut::<udp::Socket>(handle);
+        if !crate::acl::enabled(Service::Discovery) {
+            socket.close();
+            return;
+        }
+        if !socket.is_open() {
+            if let Err(err) = socket.bind(crate::discovery::PORT) {
+                log::error!("{}", Error::Bind(crate::discovery::PORT, err.into()));
+                return;
+            }
+        }
+        if !socket.can_recv() {
+            return;
+        }
+
+        let mut announcement = [0; crate::discovery::MAX_MESSAGE_LEN];
+        let len = self.announcement(&mut announcement);
+
+        let socket = self.sockets.get_mut::<udp::Socket>(handle);
+        while let Ok((probe, metadata)) = socket.recv() {
+            let endpoint = metadata.endpoint;
+            if !crate::acl::permits(endpoint.addr) {
+                log::debug!("Rejecting discovery probe from {}", endpoint);
+                continue;
+            }
+            if !crate::discovery::is_probe(probe) {
+                continue;
+            }
+            if !crate::ratelimit::allow(RateClass::Discovery, timestamp) {
+                continue;
+            }
+
+            if let Some(len) = len {
+                socket
+                    .send_slice(&announcement[..len], endpoint)
+                    .map_err(|err| log::warn!("Failed to send discovery response: {}", err))
+                    .ignore();
+            }
+        }
+    }
+
+    fn handle_http<F: FnMut(Option<Pattern>)>(&mut self, identify: &mut F) {
+        let handles = match self.http_handles {
+            Some(handles) => handles,
+            None => return,
+        };
+
+        for (connection, &handle) in handles.iter().enumerate() {
+            self.handle_http_connection(connection, handle, identify);
+        }
+    }
+
+    fn handle_http_connection<F: FnMut(Option<Pattern>)>(
+        &mut self,
+        connection: usize,
+        handle: SocketHandle,
+        identify: &mut F,
+    ) {
+        let socket = self.sockets.get_mut::<tcp::Socket>(handle);
+        if !crate::acl::enabled(Service::Http) {
+            crate::http::close_stream(connection);
+            socket.abort();
+            return;
+        }
+        if !socket.is_open() {
+            crate::http::close_stream(connection);
+            if let Err(err) = socket.listen(crate::http::PORT) {
+                log::error!("{}", Error::Bind(crate::http::PORT, err.into()));
+                return;
+            }
+            socket.set_keep_alive(Some(TCP_KEEP_ALIVE));
+            socket.set_timeout(Some(TCP_TIMEOUT));
+        }
+
+        let remote = match socket.remote_endpoint() {
+            Some(remote) => remote,
+            None => return,
+        };
+        if socket.is_active() && !crate::acl::permits(remote.addr) {
+            log::debug!("Rejecting HTTP connection from {}", remote);
+            socket.abort();
+            return;
+        }
+
+        // Once the response has been sent, the connection is closing
+        let eof = match socket.state() {
+            TcpState::Established => false,
+            TcpState::CloseWait => true,
+            _ => return,
+        };
+
+        // Nothing more is expected from a stream's client, so it only matters once the client
+        // goes away
+        if crate::http::is_streaming(connection) {
+            socket.recv(|b| (b.len(), ())).ignore();
+            if eof && crate::http::hang_up(connection) {
+                socket.close();
+            }
+            return;
+        }
+
+        // The request stays queued until all of it has arrived
+        let mut request = [0; crate::http::MAX_REQUEST_LEN];
+        let len = socket.peek_slice(&mut request).unwrap_or(0);
+        if len == 0 {
+            if eof {
+                socket.close();
+            }
+            return;
+        }
+
+        let context = self.http_context();
+
+        let mut response = [0; crate::http::MAX_RESPONSE_LEN];
+        let socket = self.sockets.get_mut::<tcp::Socket>(handle);
+        if let Some((len, next)) = crate::http::handle(
+            &request[..len],
+            eof,
+            &mut response,
+            &context,
+            remote.addr,
+            identify,
+        ) {
+            crate::config::contacted();
+            socket
+                .send_slice(&response[..len])
+                .map_err(|err| log::warn!("Failed to send HTTP response: {}", err))
+                .ignore();
+            match next {
+                crate::http::Next::Close => socket.close(),
+                next => {
+                    socket.recv(|b| (b.len(), ())).ignore();
+                    crate::http::open_stream(connection, next);
+                }
+            }
+        }
+    }
+
+    /// Sends whatever is due on the HTTP streams, closing the connections whose streams have
+    /// finished. Like `handle_syslog`, this should be called before polling the interface.
+    pub fn handle_http_streams(&mut self) {
+        let handles = match self.http_handles {
+            Some(handles) if crate::http::streaming() => handles,
+            _ => return,
+        };
+        let context = self.http_context();
+
+        for (connection, &handle) in handles.iter().enumerate() {
+            let socket = self.sockets.get_mut::<tcp::Socket>(handle);
+            if !crate::http::is_streaming(connection) || !socket.may_send() {
+                continue;
+            }
+            socket
+                .send(|buffer| {
+                    let len = crate::http::update_stream(connection, buffer, &context);
+                    (len, ())
+                })
+                .map_err(|err| log::warn!("Failed to send HTTP stream: {}", err))
+                .ignore();
+            if !crate::http::is_streaming(connection) {
+                socket.close();
+            }
+        }
+    }
+
+    fn handle_modbus<F: FnMut(Option<Pattern>)>(&mut self, timestamp: Instant, identify: &mut F) {
+        let handle = match self.modbus_handle {
+            Some(handle) => handle,
+            None => return,
+        };
+
+        let socket = self.sockets.get_mut::<tcp::Socket>(handle);
+        if !crate::acl::enabled(Service::Modbus) {
+            socket.abort();
+            return;
+        }
+        if !socket.is_open() {
+            if let Err(err) = socket.listen(crate::modbus::PORT) {
+                log::error!("{}", Error::Bind(crate::modbus::PORT, err.into()));
+                return;
+            }
+            socket.set_keep_alive(Some(TCP_KEEP_ALIVE));
+            socket.set_timeout(Some(TCP_TIMEOUT));
+        }
+
+        let remote = match socket.remote_endpoint() {
+            Some(remote) => remote,
+            None => return,
+        };
+        if socket.is_active() && !crate::acl::permits(remote.addr) {
+            log::debug!("Rejecting Modbus connection from {}", remote);
+            socket.abort();
+            return;
+        }
+        if !socket.can_recv() {
+            if socket.state() == TcpState::CloseWait {
+                socket.close();
+            }
+            return;
+        }
+
+        let link = self.device.link_state();
+        let statistics = self.device.statistics();
+        let mut context = crate::modbus::Context {
+            now: timestamp,
+            link,
+            statistics,
+            identifying: crate::identify::active().is_some(),
+            identify,
+        };
+
+        // Clients keep the connection open, and may send several requests before reading the
+        // responses, so each complete request is handled in turn
+        let socket = self.sockets.get_mut::<tcp::Socket>(handle);
+        let mut request = [0; crate::modbus::MAX_MESSAGE_LEN];
+        let mut response = [0; crate::modbus::MAX_MESSAGE_LEN];
+        while socket.send_capacity() - socket.send_queue() >= crate::modbus::MAX_MESSAGE_LEN {
+            let received = socket.peek_slice(&mut request).unwrap_or(0);
+            let len = match crate::modbus::message_len(&request[..received]) {
+                Ok(Some(len)) if len <= received => len,
+                Ok(_) => break,
+                Err(err) => {
+                    log::debug!("Closing Modbus connection from {}: {}", remote, err);
+                    socket.abort();
+                    return;
+                }
+            };
+            socket.recv_slice(&mut request[..len]).ignore();
+            crate::config::contacted();
+
+            if let Some(len) = crate::modbus::handle(&request[..len], &mut response, &mut context) {
+                socket
+                    .send_slice(&response[..len])
+                    .map_err(|err| log::warn!("Failed to send Modbus response: {}", err))
+                    .ignore();
+            }
+        }
+    }
+
+    fn handle_tftp(&mut self, timestamp: Instant) {
+        let (handle, transfer_handle) = match (self.tftp_handle, self.tftp_transfer_handle) {
+            (Some(handle), Some(transfer_handle)) => (handle, transfer_handle),
+            _ => return,
+        };
+
+        if !crate::acl::enabled(Service::Tftp) {
+            self.sockets.get_mut::<udp::Socket>(handle).close();
+            self.sockets.get_mut::<udp::Socket>(transfer_handle).close();
+            crate::tftp::cancel();
+            return;
+        }
+
+        let socket = self.sockets.get_mut::<udp::Socket>(handle);
+        if !socket.is_open() {
+            if let Err(err) = socket.bind(crate::tftp::PORT) {
+                log::error!("{}", Error::Bind(crate::tftp::PORT, err.into()));
+                return;
+            }
+        }
+        let mut response = [0; crate::tftp::MAX_PACKET_LEN];
+        while let Ok((request, metadata)) = socket.recv() {
+            let endpoint = metadata.endpoint;
+            if !crate::acl::permits(endpoint.addr) {
+                log::debug!("Rejecting TFTP request from {}", endpoint);
+                continue;
+            }
+            crate::config::contacted();
+
+            if let Some(len) = crate::tftp::request(request, endpoint, timestamp, &mut response) {
+                socket
+                    .send_slice(&response[..len], endpoint)
+                    .map_err(|err| log::warn!("Failed to send TFTP error: {}", err))
+                    .ignore();
+            }
+        }
+
+        let socket = self.sockets.get_mut::<udp::Socket>(transfer_handle);
+        if !socket.is_open() {
+            if let Err(err) = socket.bind(crate::tftp::TRANSFER_PORT) {
+                log::error!("{}", Error::Bind(crate::tftp::TRANSFER_PORT, err.into()));
+                return;
+            }
+        }
+        while let Ok((packet, metadata)) = socket.recv() {
+            let endpoint = metadata.endpoint;
+            if let Some(len) = crate::tftp::receive(packet, endpoint, &mut response) {
+                socket
+                    .send_slice(&response[..len], endpoint)
+                    .map_err(|err| log::warn!("Failed to send TFTP error: {}", err))
+                    .ignore();
+            }
+        }
+
+        // Send the next block as soon as the last one is acknowledged
+        self.handle_tftp_transfer(timestamp);
+    }
+
+    // Writes this device's discovery announcement, once it has an address to announce
+    fn announcement(&self, buffer: &mut [u8; crate::discovery::MAX_MESSAGE_LEN]) -> Option<usize> {
+        let hardware_addr = ethernet_addr(&self.interface);
+        let address = match self.interface.ip_addrs()[0].address() {
+            IpAddress::Ipv4(addr) if !addr.is_unspecified() => addr,
+            _ => return None,
+        };
+
+        Some(crate::discovery::announcement(
+            buffer,
+            hardware_addr,
+            address,
+        ))
+    }
+
+    fn http_context(&self) -> crate::http::Context {
+        crate::http::Context {
+            now: crate::time::now(),
+            hardware_addr: ethernet_addr(&self.interface),
+            address: match self.interface.ip_addrs()[0].address() {
+                IpAddress::Ipv4(addr) if !addr.is_unspecified() => Some(addr),
+                _ => None,
+            },
+            link: self.device.link_state(),
+            identifying: crate::identify::active().is_some(),
+        }
+    }
+
+    fn coap_context(&mut self) -> crate::coap::Context {
+        crate::coap::Context {
+            link: self.device.link_state(),
+            statistics: self.device.statistics(),
+            identifying: crate::identify::active().is_some(),
+        }
+    }
+}
+
+// The handlers that reach the parts of the MAC and PHY beyond the link (see `Link`)
+impl Resources<EFM32GG<'static, KSZ8091>> {
+    /// Sends an LLDPDU, if one is due. Like `handle_syslog`, this should be called before polling
+    /// the interface.
+    pub fn handle_lldp(&mut self, timestamp: Instant) {
+        let source = ethernet_addr(&self.interface);
+        let frame = match crate::lldp::take_frame(timestamp, source) {
+            Some(frame) => frame,
+            None => return,
+        };
+
+        match self.device.transmit_priority(timestamp) {
+            Some(token) => token.consume(frame.len(), |buffer| buffer.copy_from_slice(&frame)),
+            None => log::warn!("Failed to send LLDPDU: no transmit buffers"),
+        }
+    }
+
+    /// Hands the PTP slave the time that its last Delay_Req was sent, applies its corrections to
+    /// the MAC's timestamp unit, and sends any pending Delay_Req. Like `handle_lldp`, this should
+    /// be called before polling the interface.
+    pub fn handle_ptp(&mut self, timestamp: Instant) {
+        let source = ethernet_addr(&self.interface);
+        let device = &mut self.device;
+
+        if crate::ptp::awaiting_tx_time() {
+            crate::ptp::transmitted(device.ptp_tx_time());
+        }
+        match crate::ptp::take_adjustment() {
+            Some(Adjustment::Step(offset)) => device.step_ptp_time(offset),
+            Some(Adjustment::Frequency(ppb)) => device.set_ptp_frequency(ppb),
+            None => {}
+        }
+
+        let frame = match crate::ptp::take_delay_request(source) {
+            Some(frame) => frame,
+            None => return,
+        };
+        match device.transmit_priority(timestamp) {
+            Some(token) => token.consume(frame.len(), |buffer| buffer.copy_from_slice(&frame)),
+            None => log::warn!("Failed to send Delay_Req: no transmit buffers"),
+        }
+    }
+
+    /// Applies any change to the Energy Efficient Ethernet setting. Like `handle_lldp`, this
+    /// should be called before polling the interface.
+    pub fn handle_eee(&mut self) {
+        if let Some(enabled) = crate::eee::take_change() {
+            self.device.set_eee(enabled);
+            match enabled {
+                true => log::info!("Advertising EEE"),
+                false => log::info!("Not advertising EEE"),
+            }
+        }
+    }
+
+    /// Applies any change to the link's speed, duplex, or wiring. Like `handle_lldp`, this should
+    /// be called before polling the interface.
+    pub fn handle_media(&mut self) {
+        if let Some(settings) = crate::media::take_change() {
+            let device = &mut self.device;
+            device.set_link_state(settings.forced);
+            device.set_mdix(settings.mdix);
+            match settings.forced {
+                Some(state) => log::info!("Forced link to {} ({})", state, settings.mdix),
+                None => log::info!("Autonegotiating link ({})", settings.mdix),
+            }
+        }
+    }
+
+    /// Runs any requested loopback self-test. The network is unavailable for the duration of the
+    /// test, so, like `handle_lldp`, this should be called before polling the interface.
+    pub fn handle_self_test(&mut self, timestamp: Instant) {
+        if let Some(loopback) = crate::selftest::take_request() {
+            let result = self.device.self_test(timestamp, loopback);
+            crate::selftest::complete(loopback, result);
+        }
+    }
+
+    /// Follows the link into its new (debounced) state, restarting DHCP once it's up, and tells the
+    /// observers (see `events`).
+    pub fn link_changed(&mut self, timestamp: Instant, up: bool) {
+        if up {
+            self.reset_dhcp(timestamp);
+            crate::eee::set_negotiated(Some(self.device.eee_negotiated()));
+            events::publish(timestamp, Event::LinkUp);
+        } else {
+            crate::eee::set_negotiated(None);
+            events::publish(timestamp, Event::LinkDown);
+        }
+    }
+}
+
+// Maps an IPv4 multicast group to its Ethernet address (RFC 1112, section 6.4)
+fn ipv4_multicast_addr(group: Ipv4Address) -> EthernetAddress {
+    let addr = group.as_bytes();
+    EthernetAddress([0x01, 0x00, 0x5E, addr[1] & 0x7F, addr[2], addr[3]])
+}
+
+// Acknowledges a control command that doesn't otherwise reply
+fn acknowledge(socket: &mut tcp::Socket) {
+    socket.send_slice(&[CONTROL_ACK]).ignore();
+}
+
+// Answers a control command that failed with the reason
+fn refuse(socket: &mut tcp::Socket, reason: &str) {
+    socket.send_slice(&[CONTROL_NAK]).ignore();
+    writeln!(socket, "{}", reason).ignore();
+}
+
+// Parses the argument to an identify command, which is either empty (for the default pattern) or
+// a pattern (see `identify`)
+fn pattern(argument: &[u8]) -> Result<Pattern, &'static str> {
+    let argument = core::str::from_utf8(argument)
+        .map_err(|_| "invalid pattern")?
+        .trim();
+    match argument {
+        "" => Ok(crate::identify::DEFAULT),
+        pattern => pattern.parse(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::host::{self, Loopback};
+    use smoltcp::iface::Config;
+    use std::boxed::Box;
+    use std::vec;
+    use std::vec::Vec;
+
+    const MAC_ADDR: EthernetAddress = EthernetAddress([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
+    const ADDRESS: Ipv4Address = Ipv4Address::new(10, 0, 0, 2);
+
+    // The network as a binary builds it, but on a loopback device, along with a client that
+    // connects to it from its own address
+    struct Harness {
+        network: Resources<Loopback>,
+        client: SocketHandle,
+        client_port: u16,
+        identified: Vec<Option<Pattern>>,
+    }
+
+    fn tcp_socket(len: usize) -> tcp::Socket<'static> {
+        tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; len].leak()),
+            tcp::SocketBuffer::new(vec![0; len].leak()),
+        )
+    }
+
+    impl Harness {
+        fn new() -> Harness {
+            let mut device = Loopback::default();
+            let config = Config::new(HardwareAddress::Ethernet(MAC_ADDR));
+            let mut interface = Interface::new(config, &mut device, crate::time::now());
+            interface.update_ip_addrs(|addrs| {
+                addrs
+                    .push(IpCidr::Ipv4(Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, 0)))
+                    .ignore()
+            });
+
+            // The pool has room for the client, beyond the sockets that the services need
+            let pool = Box::leak(Box::new(SocketPool::<5>::new()));
+            crate::add_sockets!(pool => sockets {
+                Control: tcp_handle = tcp_socket(128),
+                Http: http_handles = core::array::from_fn(|_| tcp_socket(1024)),
+                Dhcp: dhcp_handle = dhcpv4::Socket::new(),
+            });
+            let client = sockets.add(tcp_socket(1024));
+
+            let mut harness = Harness {
+                network: Resources {
+                    interface,
+                    device,
+                    sockets,
+                    dhcp_handle,
+                    tcp_handle,
+                    syslog_handle: None,
+                    probe_handle: None,
+                    snmp_handle: None,
+                    coap_handle: None,
+                    ndisc_handle: None,
+                    fleet_handle: None,
+                    discovery_handle: None,
+                    capture_handle: None,
+                    http_handles: Some(http_handles),
+                    modbus_handle: None,
+                    tftp_handle: None,
+                    tftp_transfer_handle: None,
+                },
+                client,
+                client_port: 49152,
+                identified: Vec::new(),
+            };
+
+            // The address is configured as though it had been leased, once the DHCP client has
+            // started
+            harness.run();
+            let timestamp = crate::time::now();
+            harness
+                .network
+                .configure(timestamp, Ipv4Cidr::new(ADDRESS, 24), None);
+            harness
+        }
+
+        // Delivers the frames that were sent in the last step, then polls the interface and
+        // handles the sockets once, as the binaries do
+        fn step(&mut self) {
+            self.network.device.deliver();
+            let timestamp = crate::time::now();
+            self.network.poll(timestamp);
+            let identified = &mut self.identified;
+            self.network
+                .handle_sockets(timestamp, |_| {}, |pattern| identified.push(pattern));
+        }
+
+        // Steps until nothing is due soon (e.g. only the DHCP client's next attempt is left)
+        fn run(&mut self) {
+            for _ in 0..1000 {
+                self.step();
+
+                let timestamp = crate::time::now();
+                if !self.network.device.is_idle() {
+                    continue;
+                }
+                match self.network.poll_delay(timestamp) {
+                    Some(delay) if delay == Duration::ZERO => {}
+                    Some(delay) if delay <= Duration::from_millis(100) => {
+                        host::advance(delay.total_millis())
+                    }
+                    _ => return,
+                }
+            }
+            panic!("the network never settled");
+        }
+
+        fn client(&mut self) -> &mut tcp::Socket<'static> {
+            self.network.sockets.get_mut(self.client)
+        }
+
+        fn control(&mut self) -> &mut tcp::Socket<'static> {
+            self.network.sockets.get_mut(self.network.tcp_handle)
+        }
+
+        // Starts connecting to the port. Each connection comes from a new port, so it's never
+        // mistaken for the last.
+        fn connect(&mut self, port: u16) {
+            self.client_port += 1;
+            let local_port = self.client_port;
+            let cx = self.network.interface.context();
+            let client = self.network.sockets.get_mut::<tcp::Socket>(self.client);
+            client.connect(cx, (ADDRESS, port), local_port).unwrap();
+        }
+
+        // Returns everything that the client has received, which must be all there is
+        fn received(&mut self) -> Vec<u8> {
+            let mut response = Vec::new();
+            while self.client().can_recv() {
+                self.client()
+                    .recv(|b| {
+                        response.extend_from_slice(b);
+                        (b.len(), ())
+                    })
+                    .unwrap();
+            }
+            assert!(!self.client().may_recv(), "the connection was left open");
+            response
+        }
+
+        // Connects to the port, sends the request, and returns everything that's received before
+        // the connection is closed
+        fn exchange(&mut self, port: u16, request: &[u8]) -> Vec<u8> {
+            self.run();
+            self.connect(port);
+            self.run();
+
+            assert_eq!(self.client().state(), TcpState::Established);
+            self.client().send_slice(request).unwrap();
+            self.run();
+            let response = self.received();
+
+            self.client().close();
+            self.run();
+            response
+        }
+    }
+
+    fn refusal(reason: &str) -> Vec<u8> {
+        let mut refusal = vec![CONTROL_NAK];
+        refusal.extend_from_slice(reason.as_bytes());
+        refusal.push(b'\n');
+        refusal
+    }
+
+    #[test]
+    fn session() {
+        let sessions = [
+            (TcpState::Closed, Session::Closed),
+            (TcpState::Listen, Session::Listening),
+            (TcpState::SynSent, Session::Listening),
+            (TcpState::SynReceived, Session::Listening),
+            (TcpState::Established, Session::Open),
+            (TcpState::FinWait1, Session::Closing),
+            (TcpState::FinWait2, Session::Closing),
+            (TcpState::CloseWait, Session::HalfClosed),
+            (TcpState::Closing, Session::Closing),
+            (TcpState::LastAck, Session::Closing),
+            (TcpState::TimeWait, Session::TimeWait),
+        ];
+        for (state, expected) in sessions {
+            assert_eq!(super::session(state), expected, "{}", state);
+        }
+    }
+
+    #[test]
+    fn control_status() {
+        let _state = host::exclusive();
+        let mut harness = Harness::new();
+
+        let status = harness.exchange(CONTROL_PORT, b"s");
+        assert_eq!(status, [CONTROL_STATUS_VERSION, 0, 0b111, 10, 0, 0, 2]);
+    }
+
+    #[test]
+    fn control_identify() {
+        let _state = host::exclusive();
+        let mut harness = Harness::new();
+
+        assert_eq!(harness.exchange(CONTROL_PORT, b"1"), [CONTROL_ACK]);
+        assert_eq!(harness.exchange(CONTROL_PORT, b"0\n"), [CONTROL_ACK]);
+        assert_eq!(harness.identified, [Some(crate::identify::DEFAULT), None]);
+    }
+
+    #[test]
+    fn control_refusals() {
+        let _state = host::exclusive();
+        let mut harness = Harness::new();
+
+        assert_eq!(
+            harness.exchange(CONTROL_PORT, b"x"),
+            refusal("unknown command")
+        );
+        assert_eq!(
+            harness.exchange(CONTROL_PORT, b"1 blink fast"),
+            refusal("invalid rate")
+        );
+        // There's no port power on the host, as on a board without it
+        assert_eq!(
+            harness.exchange(CONTROL_PORT, b"P"),
+            refusal("port power switching isn't available")
+        );
+        assert_eq!(harness.exchange(CONTROL_PORT, b"e"), refusal("unavailable"));
+        assert!(harness.identified.is_empty());
+    }
+
+    #[test]
+    fn control_syn_scan() {
+        let _state = host::exclusive();
+        let mut harness = Harness::new();
+        // The first connection resolves the address, so that the next goes straight out
+        harness.exchange(CONTROL_PORT, b"s");
+
+        // The client sends its SYN, which the control socket answers with a SYN-ACK
+        harness.connect(CONTROL_PORT);
+        harness.step();
+        harness.step();
+        assert_eq!(harness.control().state(), TcpState::SynReceived);
+        assert_eq!(harness.client().state(), TcpState::SynSent);
+
+        // The scanner resets the half-open connection instead of completing the handshake, which
+        // has to leave the socket listening for the next probe
+        harness.client().abort();
+        harness.run();
+        assert_eq!(harness.control().state(), TcpState::Listen);
+
+        let status = harness.exchange(CONTROL_PORT, b"s");
+        assert_eq!(status.len(), CONTROL_STATUS_LEN);
+    }
+
+    #[test]
+    fn control_connect_scan() {
+        let _state = host::exclusive();
+        let mut harness = Harness::new();
+        harness.run();
+
+        // The scanner completes the handshake and closes without sending anything
+        harness.connect(CONTROL_PORT);
+        harness.run();
+        assert_eq!(harness.control().state(), TcpState::Established);
+        harness.client().close();
+        harness.run();
+
+        // The control socket closes its side without a reply, and listens again right away
+        assert_eq!(harness.received(), []);
+        assert_eq!(harness.control().state(), TcpState::Listen);
+
+        let status = harness.exchange(CONTROL_PORT, b"s");
+        assert_eq!(status.len(), CONTROL_STATUS_LEN);
+    }
+
+    #[test]
+    fn control_half_closed() {
+        let _state = host::exclusive();
+        let mut harness = Harness::new();
+        harness.run();
+
+        // The client sends its command and its FIN together (e.g. `echo 1 | nc`), and still gets
+        // the reply
+        harness.connect(CONTROL_PORT);
+        harness.run();
+        harness.client().send_slice(b"1\n").unwrap();
+        harness.client().close();
+        harness.run();
+
+        assert_eq!(harness.received(), [CONTROL_ACK]);
+        assert_eq!(harness.identified, [Some(crate::identify::DEFAULT)]);
+        assert_eq!(harness.control().state(), TcpState::Listen);
+    }
+
+    #[test]
+    fn http_status() {
+        let _state = host::exclusive();
+        let mut harness = Harness::new();
+
+        let response = harness.exchange(
+            crate::http::PORT,
+            b"GET /api/status HTTP/1.1\r\nHost: 10.0.0.2\r\n\r\n",
+        );
+        let response = std::str::from_utf8(&response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(
+            response.contains(r#""mac":"02-00-00-00-00-01""#),
+            "{}",
+            response
+        );
+        assert!(response.contains(r#""ip":"10.0.0.2""#), "{}", response);
+        assert!(
+            response.contains(r#""link":{"up":true,"speed_mbps":100,"duplex":"full"}"#),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn http_identify() {
+        let _state = host::exclusive();
+        let mut harness = Harness::new();
+
+        let body = r#"{"state":"on","pattern":"sos"}"#;
+        let request = std::format!(
+            "POST /api/identify HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let response = harness.exchange(crate::http::PORT, request.as_bytes());
+        let response = std::str::from_utf8(&response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 204 No Content\r\n"),
+            "{}",
+            response
+        );
+        assert_eq!(harness.identified, [Some("sos".parse().unwrap())]);
+
+        let response = harness.exchange(crate::http::PORT, b"GET /missing HTTP/1.1\r\n\r\n");
+        let response = std::str::from_utf8(&response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{}",
+            response
+        );
+    }
+}
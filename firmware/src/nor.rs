@@ -0,0 +1,118 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A SPI NOR flash on the shared SPI bus (see efm32gg::spi), using the common JEDEC commands with
+// 3-byte addresses: reading, programming 256-byte pages, and erasing 4 KiB sectors. Parts up to
+// 16 MiB are supported, which covers everything that can be addressed that way.
+//
+// Programming and erasing block until the part reports that it's done.
+
+use crate::efm32gg::spi;
+
+/// The size of an erasable sector, in bytes.
+pub const SECTOR_SIZE: u32 = 4096;
+
+/// The size of a programmable page, in bytes.
+pub const PAGE_SIZE: u32 = 256;
+
+const CMD_PAGE_PROGRAM: u8 = 0x02;
+const CMD_READ: u8 = 0x03;
+const CMD_READ_STATUS: u8 = 0x05;
+const CMD_WRITE_ENABLE: u8 = 0x06;
+const CMD_SECTOR_ERASE: u8 = 0x20;
+const CMD_READ_ID: u8 = 0x9F;
+
+const STATUS_WIP: u8 = 1 << 0;
+
+// How long to wait for a page to be programmed or a sector to be erased
+const PROGRAM_TIMEOUT_MS: u64 = 10;
+const ERASE_TIMEOUT_MS: u64 = 500;
+
+const MAX_CAPACITY_LOG2: u8 = 24;
+
+fn command(writes: &[&[u8]], read: &mut [u8]) -> Result<(), &'static str> {
+    spi::with(|bus| bus.write_read(writes, read)).and_then(|result| result)
+}
+
+fn address(addr: u32) -> [u8; 3] {
+    let [_, high, mid, low] = addr.to_be_bytes();
+    [high, mid, low]
+}
+
+fn wait_idle(timeout_ms: u64) -> Result<(), &'static str> {
+    let deadline = crate::time::now_ms() + timeout_ms;
+    loop {
+        let mut status = [0];
+        command(&[&[CMD_READ_STATUS]], &mut status)?;
+        if status[0] & STATUS_WIP == 0 {
+            return Ok(());
+        }
+        if crate::time::now_ms() > deadline {
+            return Err("flash timed out");
+        }
+    }
+}
+
+/// Returns the part's JEDEC manufacturer, memory type, and capacity.
+pub fn id() -> Result<[u8; 3], &'static str> {
+    let mut id = [0; 3];
+    command(&[&[CMD_READ_ID]], &mut id)?;
+    Ok(id)
+}
+
+/// Returns the size of the part, in bytes, as reported by its JEDEC ID.
+pub fn capacity() -> Result<u32, &'static str> {
+    match id()? {
+        [0x00, _, _] | [0xFF, _, _] => Err("no flash"),
+        [_, _, log2] if !(16..=MAX_CAPACITY_LOG2).contains(&log2) => Err("unsupported flash size"),
+        [_, _, log2] => Ok(1 << log2),
+    }
+}
+
+/// Reads from the part, starting at the given address.
+pub fn read(addr: u32, buffer: &mut [u8]) -> Result<(), &'static str> {
+    command(&[&[CMD_READ], &address(addr)], buffer)
+}
+
+/// Programs the bytes, starting at the given address. Programming can only clear bits, so the
+/// bytes need to have been erased first.
+pub fn program(addr: u32, mut bytes: &[u8]) -> Result<(), &'static str> {
+    let mut addr = addr;
+    while !bytes.is_empty() {
+        // A program operation wraps around within its page, so it can't cross into the next one
+        let len = bytes.len().min((PAGE_SIZE - addr % PAGE_SIZE) as usize);
+        let (page, rest) = bytes.split_at(len);
+
+        command(&[&[CMD_WRITE_ENABLE]], &mut [])?;
+        command(&[&[CMD_PAGE_PROGRAM], &address(addr), page], &mut [])?;
+        wait_idle(PROGRAM_TIMEOUT_MS)?;
+
+        addr += len as u32;
+        bytes = rest;
+    }
+
+    Ok(())
+}
+
+/// Erases the sector starting at the given address, setting every bit.
+pub fn erase_sector(addr: u32) -> Result<(), &'static str> {
+    if addr % SECTOR_SIZE != 0 {
+        return Err("address isn't sector-aligned");
+    }
+
+    command(&[&[CMD_WRITE_ENABLE]], &mut [])?;
+    command(&[&[CMD_SECTOR_ERASE], &address(addr)], &mut [])?;
+    wait_idle(ERASE_TIMEOUT_MS)
+}
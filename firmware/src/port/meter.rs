@@ -0,0 +1,191 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The energy delivered to the downstream port is measured by periodically sampling the load
+// current and integrating the power over the time between samples. The board can't measure the
+// port voltage, so the power is calculated using the nominal PoE voltage.
+//
+// The accumulated energy lives in RAM that isn't initialized at startup (like the reset counters),
+// so it survives any reset that preserves RAM. It's also kept in the store, in whole milliwatt-
+// hours, which it's restored from when power is removed. Since store writes block and wear the
+// flash, the total is only written (by `persist`) every `INTERVAL`, so up to that much can be
+// lost along with power. Clearing the total is written right away.
+
+use crate::store::{self, Key};
+use core::cell::RefCell;
+use core::mem::MaybeUninit;
+use cortex_m::interrupt::{self, Mutex};
+use smoltcp::time::{Duration, Instant};
+
+/// The voltage assumed to be present on the downstream port, in millivolts.
+pub const NOMINAL_MV: u32 = 48_000;
+
+const MAGIC: u32 = 0x4D45_5452;
+
+// The number of microjoules in a milliwatt-hour
+const UJ_PER_MWH: u64 = 3_600_000;
+
+// The most time between writes of the accumulated energy to the store
+const INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+#[link_section = ".uninit.poe.meter"]
+static mut ACCUMULATOR: MaybeUninit<Accumulator> = MaybeUninit::uninit();
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    last: None,
+    current_ma: 0,
+    saved_mwh: 0,
+    written: None,
+}));
+
+struct State {
+    last: Option<Instant>,
+    current_ma: u32,
+    // The accumulated energy in the store
+    saved_mwh: u64,
+    // When persist last wrote the accumulated energy
+    written: Option<Instant>,
+}
+
+#[repr(C)]
+struct Accumulator {
+    magic: u32,
+    energy_uj: u64,
+    check: u32,
+}
+
+impl Accumulator {
+    fn checksum(&self) -> u32 {
+        !self.magic ^ (self.energy_uj as u32) ^ (self.energy_uj >> 32) as u32
+    }
+
+    fn add(&mut self, energy_uj: u64) {
+        self.energy_uj = self.energy_uj.saturating_add(energy_uj);
+        self.check = self.checksum();
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Reading {
+    pub current_ma: u32,
+    pub power_mw: u32,
+    pub energy_mwh: u64,
+}
+
+/// Validates the accumulated energy, restoring it from the store if it didn't survive the reset.
+/// This must be called once at boot.
+pub fn init() {
+    let mut value = [0; 8];
+    let saved_mwh = match store::get(Key::Energy, &mut value) {
+        Some(8) => u64::from_le_bytes(value),
+        Some(_) => {
+            log::warn!("Ignoring malformed accumulated energy");
+            0
+        }
+        None => 0,
+    };
+
+    let accumulator = unsafe { ACCUMULATOR.assume_init_mut() };
+    if accumulator.magic != MAGIC || accumulator.check != accumulator.checksum() {
+        accumulator.magic = MAGIC;
+        accumulator.energy_uj = saved_mwh.saturating_mul(UJ_PER_MWH);
+        accumulator.check = accumulator.checksum();
+    }
+
+    let energy = accumulator.energy_uj / UJ_PER_MWH;
+    log::debug!("Accumulated energy: {} mWh", energy);
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().saved_mwh = saved_mwh);
+}
+
+/// Samples the load current and adds the energy delivered since the previous sample. This should
+/// be called periodically.
+pub fn sample(now: Instant) {
+    let current_ma = match crate::sensors::load_current_ma() {
+        Some(current) => current,
+        None => return,
+    };
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+
+        // Integrate using the average of the two samples
+        if let Some(last) = state.last {
+            let current_ma = (state.current_ma + current_ma) / 2;
+            let elapsed_ms = (now - last).total_millis();
+            unsafe { ACCUMULATOR.assume_init_mut() }
+                .add(u64::from(power_mw(current_ma)) * elapsed_ms);
+        }
+
+        state.last = Some(now);
+        state.current_ma = current_ma;
+    })
+}
+
+/// Returns the most recent sample along with the accumulated energy, or `None` if nothing has
+/// been sampled yet.
+pub fn reading() -> Option<Reading> {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        state.last?;
+
+        Some(Reading {
+            current_ma: state.current_ma,
+            power_mw: power_mw(state.current_ma),
+            energy_mwh: unsafe { ACCUMULATOR.assume_init_ref() }.energy_uj / UJ_PER_MWH,
+        })
+    })
+}
+
+/// Writes the accumulated energy to the store, if it's due. This blocks while the flash is written,
+/// so it should be called periodically from a low-priority task.
+pub fn persist(now: Instant) {
+    let energy_mwh =
+        interrupt::free(|_| unsafe { ACCUMULATOR.assume_init_ref() }.energy_uj) / UJ_PER_MWH;
+    let due = interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        match state.written {
+            _ if energy_mwh == state.saved_mwh => false,
+            // The total was cleared
+            _ if energy_mwh < state.saved_mwh => true,
+            None => true,
+            Some(written) => now - written >= INTERVAL,
+        }
+    });
+    if !due {
+        return;
+    }
+
+    match store::set(Key::Energy, &energy_mwh.to_le_bytes()) {
+        Ok(()) => interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+            state.saved_mwh = energy_mwh;
+            state.written = Some(now);
+        }),
+        Err(err) => log::warn!("Failed to save accumulated energy: {}", err),
+    }
+}
+
+/// Clears the accumulated energy.
+pub fn reset() {
+    interrupt::free(|_| {
+        let accumulator = unsafe { ACCUMULATOR.assume_init_mut() };
+        accumulator.energy_uj = 0;
+        accumulator.check = accumulator.checksum();
+    })
+}
+
+fn power_mw(current_ma: u32) -> u32 {
+    current_ma * NOMINAL_MV / 1000
+}
@@ -0,0 +1,274 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The downstream port is powered through a load switch. The switch is only closed while the port
+// is enabled and the upstream link is up, and then only once the power-on delay has passed since
+// the link came up. A power cycle opens the switch and closes it again once the off-time has
+// passed. A trip (see the protect module) holds the switch open until the hold-off has passed,
+// regardless of anything else. All of the timing is driven by `poll`, which needs to be called
+// periodically.
+
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+use efm32gg11b820::GPIO;
+use smoltcp::time::{Duration, Instant};
+
+pub mod meter;
+pub mod protect;
+pub mod schedule;
+
+/// The default delay between the link coming up and the port being powered.
+pub const DEFAULT_LINK_DELAY: Duration = Duration::from_millis(1000);
+
+/// The default length of time that the port is left unpowered during a power cycle.
+pub const DEFAULT_CYCLE_TIME: Duration = Duration::from_millis(2000);
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    hardware: None,
+    enabled: true,
+    link: false,
+    powered: false,
+    link_delay: DEFAULT_LINK_DELAY,
+    power_on_at: None,
+    held_until: None,
+}));
+
+struct State {
+    hardware: Option<Hardware>,
+    enabled: bool,
+    link: bool,
+    powered: bool,
+    link_delay: Duration,
+    power_on_at: Option<Instant>,
+    held_until: Option<Instant>,
+}
+
+struct Hardware {
+    pin: Pin,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum GpioPort {
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+}
+
+/// The GPIO driving the (active-high) enable of the load switch. It needs to be configured as an
+/// output by the caller.
+#[derive(Clone, Copy, Debug)]
+pub struct Pin {
+    pub port: GpioPort,
+    pub pin: u8,
+}
+
+impl Pin {
+    fn set(self, high: bool) {
+        let gpio = unsafe { &*GPIO::ptr() };
+        let mask = 1 << self.pin;
+
+        macro_rules! set {
+            ($dout:ident) => {
+                gpio.$dout.modify(|r, w| unsafe {
+                    w.bits(match high {
+                        true => r.bits() | mask,
+                        false => r.bits() & !mask,
+                    })
+                })
+            };
+        }
+
+        match self.port {
+            GpioPort::A => set!(pa_dout),
+            GpioPort::B => set!(pb_dout),
+            GpioPort::C => set!(pc_dout),
+            GpioPort::D => set!(pd_dout),
+            GpioPort::E => set!(pe_dout),
+            GpioPort::F => set!(pf_dout),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Status {
+    pub enabled: bool,
+    pub powered: bool,
+    pub link: bool,
+    pub link_delay: Duration,
+    pub tripped: bool,
+}
+
+impl State {
+    fn hardware(&self) -> Result<&Hardware, &'static str> {
+        self.hardware
+            .as_ref()
+            .ok_or("port power switching isn't available")
+    }
+
+    fn set_powered(&mut self, powered: bool) {
+        let hardware = match &self.hardware {
+            Some(hardware) => hardware,
+            None => return,
+        };
+
+        if self.powered != powered {
+            log::info!("Port powered {}", if powered { "on" } else { "off" });
+        }
+        hardware.pin.set(powered);
+        self.powered = powered;
+    }
+
+    // Powers the port at `at`, or once the hold-off has passed if that's later
+    fn power_on_after(&mut self, at: Instant) {
+        self.power_on_at = Some(self.held_until.map_or(at, |held| held.max(at)));
+    }
+}
+
+/// Takes control of the load switch, leaving the port unpowered until the link comes up.
+pub fn init(pin: Pin) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.hardware = Some(Hardware { pin });
+        state.power_on_at = None;
+        state.set_powered(false);
+    })
+}
+
+/// Returns the current state of the port, or `None` if it can't be switched.
+pub fn status() -> Option<Status> {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        state.hardware.as_ref()?;
+        let now = crate::time::now();
+
+        Some(Status {
+            enabled: state.enabled,
+            powered: state.powered,
+            link: state.link,
+            link_delay: state.link_delay,
+            tripped: state.held_until.map_or(false, |held| now < held),
+        })
+    })
+}
+
+/// Enables or disables the port. An enabled port is powered right away if the link is up.
+pub fn set_enabled(enabled: bool) -> Result<(), &'static str> {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.hardware()?;
+        let now = crate::time::now();
+
+        state.enabled = enabled;
+        state.power_on_at = None;
+        match (enabled, state.link) {
+            (true, true) => state.power_on_after(now),
+            (true, false) => {}
+            (false, _) => state.set_powered(false),
+        }
+
+        Ok(())
+    })?;
+
+    poll();
+    Ok(())
+}
+
+/// Removes power from the port, restoring it once `off_time` has passed.
+pub fn cycle(off_time: Duration) -> Result<(), &'static str> {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.hardware()?;
+        let now = crate::time::now();
+
+        if !state.enabled {
+            return Err("port is disabled");
+        }
+
+        log::info!("Power cycling port for {}", off_time);
+        state.set_powered(false);
+        state.power_on_after(now + off_time);
+
+        Ok(())
+    })
+}
+
+/// Removes power from the port, and keeps it off until `hold_off` has passed.
+pub fn trip(hold_off: Duration) -> Result<(), &'static str> {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.hardware()?;
+        let now = crate::time::now();
+
+        state.set_powered(false);
+        state.held_until = Some(now + hold_off);
+        state.power_on_after(now);
+
+        Ok(())
+    })
+}
+
+/// Sets the delay between the link coming up and the port being powered.
+pub fn set_link_delay(delay: Duration) {
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().link_delay = delay)
+}
+
+/// Notifies the port of a change in the upstream link. The port is unpowered as soon as the link
+/// is lost.
+pub fn link_changed(up: bool) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        if state.hardware.is_none() {
+            return;
+        }
+        let now = crate::time::now();
+
+        state.link = up;
+        match (up, state.enabled) {
+            (true, true) => {
+                let at = now + state.link_delay;
+                state.power_on_after(at);
+            }
+            (true, false) => {}
+            (false, _) => {
+                state.power_on_at = None;
+                state.set_powered(false);
+            }
+        }
+    })
+}
+
+/// Powers the port once a pending power-on is due. This should be called periodically.
+pub fn poll() {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        if state.hardware.is_none() {
+            return;
+        }
+        let now = crate::time::now();
+
+        match state.power_on_at {
+            Some(at) if at <= now => {
+                state.power_on_at = None;
+                if state.enabled && state.link {
+                    state.set_powered(true);
+                }
+            }
+            _ => {}
+        }
+    })
+}
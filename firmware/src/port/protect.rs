@@ -0,0 +1,207 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The port is tripped (unpowered) if the load current stays above the limit for TRIP_SAMPLES
+// consecutive samples, which lets inrush through, or if any of the board's own supplies drop
+// below their thresholds, which usually means the downstream device is drawing more than the PSE
+// can supply. After a trip, power is restored once the hold-off has passed. The hold-off doubles
+// with each consecutive trip, and resets once the port has stayed powered for STABLE_TIME.
+//
+// The board can't measure the port voltage, so undervoltage is detected using the monitors on
+// the board's own supplies (see efm32gg::vmon).
+//
+// The current is also held to the power budget negotiated with the PSE (see lldp), if there is
+// one.
+//
+// The limit is kept in the store, so that it survives a reset.
+
+use crate::store::{self, Key};
+use core::cell::RefCell;
+use core::fmt;
+use cortex_m::interrupt::{self, Mutex};
+use smoltcp::time::{Duration, Instant};
+
+/// The default limit on the load current, in milliamps. This is a bit above what a class 4 PD can
+/// draw.
+pub const DEFAULT_LIMIT_MA: u32 = 650;
+
+const TRIP_SAMPLES: u8 = 3;
+
+const INITIAL_HOLD_OFF: Duration = Duration::from_secs(1);
+const MAX_HOLD_OFF: Duration = Duration::from_secs(300);
+
+const STABLE_TIME: Duration = Duration::from_secs(60);
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    limit_ma: Some(DEFAULT_LIMIT_MA),
+    budget_ma: None,
+    over: 0,
+    hold_off: INITIAL_HOLD_OFF,
+    trips: 0,
+    last_trip: None,
+}));
+
+struct State {
+    limit_ma: Option<u32>,
+    budget_ma: Option<u32>,
+    over: u8,
+    hold_off: Duration,
+    trips: u32,
+    last_trip: Option<(Instant, Reason)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reason {
+    Overcurrent,
+    OverBudget,
+    Undervoltage,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Reason::Overcurrent => "overcurrent",
+            Reason::OverBudget => "over budget",
+            Reason::Undervoltage => "undervoltage",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Status {
+    pub limit_ma: Option<u32>,
+    pub budget_ma: Option<u32>,
+    pub trips: u32,
+    pub last_trip: Option<(Instant, Reason)>,
+}
+
+/// Loads the limit on the load current from the store. This must be called once at boot.
+pub fn init() {
+    let mut value = [0; 4];
+    let limit_ma = match store::get(Key::PortLimit, &mut value) {
+        // A limit of zero is stored to disable overcurrent protection
+        Some(4) => match u32::from_le_bytes(value) {
+            0 => None,
+            limit_ma => Some(limit_ma),
+        },
+        Some(_) => {
+            log::warn!("Ignoring malformed port limit");
+            return;
+        }
+        None => return,
+    };
+
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().limit_ma = limit_ma)
+}
+
+/// Sets the limit on the load current, or disables overcurrent protection if `None`.
+pub fn set_limit(limit_ma: Option<u32>) -> Result<(), &'static str> {
+    if limit_ma == Some(0) {
+        return Err("limit must be above 0 mA");
+    }
+    store::set(Key::PortLimit, &limit_ma.unwrap_or(0).to_le_bytes())?;
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.limit_ma = limit_ma;
+        state.over = 0;
+    });
+    Ok(())
+}
+
+/// Limits the load current to what the power budget allows, given in milliwatts at the nominal
+/// voltage, or removes the limit if `None`.
+pub fn set_budget(budget_mw: Option<u32>) {
+    let budget_ma = budget_mw.map(|mw| mw * 1000 / super::meter::NOMINAL_MV);
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().budget_ma = budget_ma)
+}
+
+pub fn status() -> Status {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        Status {
+            limit_ma: state.limit_ma,
+            budget_ma: state.budget_ma,
+            trips: state.trips,
+            last_trip: state.last_trip,
+        }
+    })
+}
+
+/// Checks the most recent measurements against the limits, tripping the port if any of them have
+/// been exceeded. This should be called after each sample (see `meter::sample`).
+pub fn poll(now: Instant) {
+    let powered = match super::status() {
+        Some(status) => status.powered,
+        None => return,
+    };
+    let current_ma = match super::meter::reading() {
+        Some(reading) => reading.current_ma,
+        None => return,
+    };
+    let mut undervoltage = false;
+    crate::efm32gg::vmon::for_each_supply(|_, above| undervoltage |= !above);
+
+    let trip = interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+
+        if !powered {
+            state.over = 0;
+            return None;
+        }
+
+        let over_limit = state.limit_ma.map_or(false, |limit| current_ma > limit);
+        let over_budget = state.budget_ma.map_or(false, |budget| current_ma > budget);
+        state.over = match over_limit || over_budget {
+            true => state.over.saturating_add(1),
+            false => 0,
+        };
+
+        let reason = match (undervoltage, state.over >= TRIP_SAMPLES, over_limit) {
+            (true, _, _) => Reason::Undervoltage,
+            (false, true, true) => Reason::Overcurrent,
+            (false, true, false) => Reason::OverBudget,
+            (false, false, _) => return None,
+        };
+
+        // Back off from the previous hold-off if this trip came soon after it
+        let hold_off = match state.last_trip {
+            Some((at, _)) if now - at < state.hold_off + STABLE_TIME => {
+                (state.hold_off * 2).min(MAX_HOLD_OFF)
+            }
+            _ => INITIAL_HOLD_OFF,
+        };
+
+        state.over = 0;
+        state.hold_off = hold_off;
+        state.trips = state.trips.saturating_add(1);
+        state.last_trip = Some((now, reason));
+        Some((reason, hold_off))
+    });
+
+    if let Some((reason, hold_off)) = trip {
+        crate::events::record(now, crate::events::Event::PortTripped(reason));
+        log::warn!(
+            "Port tripped at {} ({}, {} mA); retrying in {}",
+            now,
+            reason,
+            current_ma,
+            hold_off
+        );
+        if let Err(err) = super::trip(hold_off) {
+            log::error!("Failed to trip port: {}", err);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The downstream port can be power cycled automatically, either at a fixed interval or when the
+// downstream device stops responding to pings. There's no wall clock, so the interval is measured
+// from boot (or from the last change to the interval) rather than being aligned to the time of day.
+//
+// The liveness probe pings the downstream device every PROBE_INTERVAL while the port is powered,
+// starting once BOOT_GRACE has passed since power was applied. A ping that hasn't been answered
+// by the time the next one is due counts as a failure, and enough consecutive failures trigger a
+// power cycle. The network stack does the sending and receiving (see `take_probe` and `reply`).
+
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+use ignore_result::Ignore;
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::Ipv4Address;
+
+/// The default number of consecutive failed probes that trigger a power cycle.
+pub const DEFAULT_FAILURE_THRESHOLD: u8 = 3;
+
+/// The identifier used in the probe's echo requests.
+pub const PROBE_IDENT: u16 = 0x504F;
+
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+// The time given to the downstream device to boot before it's expected to answer pings
+const BOOT_GRACE: Duration = Duration::from_secs(120);
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    interval: None,
+    next_cycle: None,
+    target: None,
+    threshold: DEFAULT_FAILURE_THRESHOLD,
+    failures: 0,
+    seq_no: 0,
+    outstanding: None,
+    powered: false,
+    next_probe: Instant::from_millis_const(0),
+}));
+
+struct State {
+    interval: Option<Duration>,
+    next_cycle: Option<Instant>,
+
+    target: Option<Ipv4Address>,
+    threshold: u8,
+    failures: u8,
+    seq_no: u16,
+    outstanding: Option<u16>,
+    powered: bool,
+    next_probe: Instant,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Status {
+    pub interval: Option<Duration>,
+    pub target: Option<Ipv4Address>,
+    pub threshold: u8,
+    pub failures: u8,
+}
+
+/// Power cycles the port every `interval`, or never if `None`.
+pub fn set_interval(interval: Option<Duration>) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.interval = interval;
+        state.next_cycle = None;
+    })
+}
+
+/// Pings `target` to check that the downstream device is alive, or stops probing if `None`.
+pub fn set_probe(target: Option<Ipv4Address>) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.target = target;
+        state.failures = 0;
+        state.outstanding = None;
+    })
+}
+
+/// Sets the number of consecutive failed probes that trigger a power cycle.
+pub fn set_failure_threshold(threshold: u8) -> Result<(), &'static str> {
+    if threshold == 0 {
+        return Err("threshold must be at least one");
+    }
+
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().threshold = threshold);
+    Ok(())
+}
+
+pub fn status() -> Status {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        Status {
+            interval: state.interval,
+            target: state.target,
+            threshold: state.threshold,
+            failures: state.failures,
+        }
+    })
+}
+
+/// Power cycles the port if one is scheduled, and keeps track of when the port was powered.
+/// Returns true if a probe is due, in which case the network needs to be handled. This should be
+/// called periodically.
+pub fn poll(now: Instant) -> bool {
+    let powered = match super::status() {
+        Some(status) => status.powered,
+        None => return false,
+    };
+
+    let cycle = interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+
+        // The probe is held off until the device has had a chance to boot
+        match (state.powered, powered) {
+            (false, true) => state.next_probe = now + BOOT_GRACE,
+            (true, false) => {
+                state.failures = 0;
+                state.outstanding = None;
+            }
+            _ => {}
+        }
+        state.powered = powered;
+
+        match (state.interval, state.next_cycle) {
+            (Some(interval), None) => {
+                state.next_cycle = Some(now + interval);
+                false
+            }
+            (Some(interval), Some(at)) if at <= now => {
+                state.next_cycle = Some(now + interval);
+                state.powered = false;
+                true
+            }
+            _ => false,
+        }
+    });
+
+    if cycle {
+        log::info!("Power cycling port on schedule");
+        super::cycle(super::DEFAULT_CYCLE_TIME)
+            .map_err(|err| log::warn!("Failed to cycle port: {}", err))
+            .ignore();
+    }
+
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        state.powered && state.target.is_some() && state.next_probe <= now
+    })
+}
+
+/// Returns the address and sequence number of the next echo request to send, if one is due. If
+/// the previous request went unanswered, it's counted as a failure, possibly triggering a power
+/// cycle.
+pub fn take_probe(now: Instant) -> Option<(Ipv4Address, u16)> {
+    let (probe, cycle) = interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        let target = match state.target {
+            Some(target) if state.powered && state.next_probe <= now => target,
+            _ => return (None, false),
+        };
+
+        if let Some(seq_no) = state.outstanding.take() {
+            state.failures = state.failures.saturating_add(1);
+            log::warn!(
+                "No reply to probe {} from {} ({} of {})",
+                seq_no,
+                target,
+                state.failures,
+                state.threshold
+            );
+
+            if state.failures >= state.threshold {
+                state.failures = 0;
+                state.powered = false;
+                return (None, true);
+            }
+        }
+
+        state.seq_no = state.seq_no.wrapping_add(1);
+        state.outstanding = Some(state.seq_no);
+        state.next_probe = now + PROBE_INTERVAL;
+        (Some((target, state.seq_no)), false)
+    });
+
+    if cycle {
+        log::warn!("Downstream device isn't responding; power cycling port");
+        super::cycle(super::DEFAULT_CYCLE_TIME)
+            .map_err(|err| log::warn!("Failed to cycle port: {}", err))
+            .ignore();
+    }
+
+    probe
+}
+
+/// Records a reply to one of the probes.
+pub fn reply(seq_no: u16) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        if state.outstanding == Some(seq_no) {
+            state.outstanding = None;
+            state.failures = 0;
+        }
+    })
+}
@@ -0,0 +1,539 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// An IEEE 1588 (PTPv2) ordinary clock that can only be a slave, using the Ethernet transport and
+// the end-to-end delay mechanism. The MAC's timestamp unit (TSU) latches the time at which each
+// event message (Sync and Delay_Req) passes through the MAC. The driver passes received messages
+// to `receive` along with that time, and `network::Resources::handle_ptp` passes along the time
+// at which each Delay_Req was sent, sends the Delay_Reqs, and applies the servo's corrections to
+// the TSU.
+//
+// There's no best master clock algorithm; the first master that's heard from is followed until
+// it stops announcing itself.
+//
+// The RTC can't be stepped or slewed without upsetting the network stack, whose timers depend on
+// it, so it's disciplined in software instead: once the TSU is locked to the master, each Sync
+// pairs the master's time with the RTC's, and `time` extrapolates from the latest pair using the
+// RTC's measured drift.
+
+use core::cell::RefCell;
+use core::convert::TryInto;
+use core::fmt;
+use cortex_m::interrupt::{self, Mutex};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::EthernetAddress;
+
+pub const ETHERTYPE: u16 = 0x88F7;
+
+/// The group address that PTP messages are sent to (other than peer delay messages).
+pub const MULTICAST: EthernetAddress = EthernetAddress([0x01, 0x1B, 0x19, 0x00, 0x00, 0x00]);
+
+/// The length of a Delay_Req, including the Ethernet header.
+pub const DELAY_REQUEST_LEN: usize = ETHERNET_HEADER_LEN + HEADER_LEN + TIMESTAMP_LEN;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const HEADER_LEN: usize = 34;
+const TIMESTAMP_LEN: usize = 10;
+const PORT_IDENTITY_LEN: usize = 10;
+
+const VERSION: u8 = 2;
+const DOMAIN: u8 = 0;
+const PORT_NUMBER: u16 = 1;
+
+const SYNC: u8 = 0x0;
+const DELAY_REQ: u8 = 0x1;
+const FOLLOW_UP: u8 = 0x8;
+const DELAY_RESP: u8 = 0x9;
+const ANNOUNCE: u8 = 0xB;
+
+const FLAG_TWO_STEP: u8 = 0x02;
+const CONTROL_DELAY_REQ: u8 = 0x01;
+const LOG_INTERVAL_UNSPECIFIED: u8 = 0x7F;
+
+// A master is forgotten once it has missed three (default) announce intervals
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(6);
+
+// Offsets larger than this are corrected by stepping the clock rather than slewing it
+const STEP_THRESHOLD_NS: i64 = 1_000_000;
+
+// The servo's proportional and integral gains, in tenths
+const KP: i64 = 7;
+const KI: i64 = 3;
+const MAX_FREQUENCY_PPB: i64 = 500_000;
+
+// The RTC's drift is only measured over an interval at least this long, since it only counts
+// milliseconds
+const DRIFT_INTERVAL: Duration = Duration::from_secs(60);
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    enabled: true,
+    identity: None,
+    master: None,
+    sync: None,
+    measurement: None,
+    delay_sequence: 0,
+    last_tx: None,
+    path_delay: None,
+    offset: None,
+    integral: 0,
+    frequency: 0,
+    adjustment: None,
+    rtc: None,
+}));
+
+struct State {
+    enabled: bool,
+    identity: Option<PortIdentity>,
+    master: Option<(PortIdentity, Instant)>,
+    // A two-step Sync, waiting for its Follow_Up
+    sync: Option<PendingSync>,
+    // The most recent Sync, along with the Delay_Req sent after it
+    measurement: Option<Measurement>,
+    delay_sequence: u16,
+    last_tx: Option<Timestamp>,
+    path_delay: Option<i64>,
+    offset: Option<i64>,
+    integral: i64,
+    frequency: i32,
+    adjustment: Option<Adjustment>,
+    rtc: Option<RtcDiscipline>,
+}
+
+#[derive(Clone, Copy)]
+struct PendingSync {
+    sequence: u16,
+    correction: i64,
+    t2: i64,
+}
+
+#[derive(Clone, Copy)]
+struct Measurement {
+    t1: i64,
+    t2: i64,
+    sequence: Option<u16>,
+    t3: Option<i64>,
+    t4: Option<i64>,
+}
+
+#[derive(Clone, Copy)]
+struct RtcDiscipline {
+    reference: (Instant, i64),
+    baseline: (Instant, i64),
+    drift_ppb: i64,
+}
+
+/// A time from the TSU, or from a PTP message.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Timestamp {
+    pub seconds: u64,
+    pub nanoseconds: u32,
+}
+
+impl Timestamp {
+    pub fn from_nanos(nanos: i64) -> Timestamp {
+        let nanos = nanos.max(0);
+        Timestamp {
+            seconds: (nanos / 1_000_000_000) as u64,
+            nanoseconds: (nanos % 1_000_000_000) as u32,
+        }
+    }
+
+    pub fn total_nanos(&self) -> i64 {
+        self.seconds as i64 * 1_000_000_000 + i64::from(self.nanoseconds)
+    }
+
+    fn parse(bytes: &[u8]) -> Timestamp {
+        let mut seconds = [0; 8];
+        seconds[2..].copy_from_slice(&bytes[0..6]);
+        Timestamp {
+            seconds: u64::from_be_bytes(seconds),
+            nanoseconds: u32::from_be_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]),
+        }
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:09}", self.seconds, self.nanoseconds)
+    }
+}
+
+/// A clock identity and port number, identifying a PTP port.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PortIdentity(pub [u8; PORT_IDENTITY_LEN]);
+
+impl PortIdentity {
+    fn new(hardware_addr: EthernetAddress) -> PortIdentity {
+        let addr = hardware_addr.as_bytes();
+        let mut identity = [0; PORT_IDENTITY_LEN];
+        identity[0..3].copy_from_slice(&addr[0..3]);
+        identity[3..5].copy_from_slice(&[0xFF, 0xFE]);
+        identity[5..8].copy_from_slice(&addr[3..6]);
+        identity[8..10].copy_from_slice(&PORT_NUMBER.to_be_bytes());
+        PortIdentity(identity)
+    }
+
+    fn same_clock(&self, other: &PortIdentity) -> bool {
+        self.0[0..8] == other.0[0..8]
+    }
+}
+
+impl fmt::Display for PortIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let id = &self.0;
+        write!(
+            f,
+            "{:02x}{:02x}{:02x}.{:02x}{:02x}.{:02x}{:02x}{:02x}-{}",
+            id[0],
+            id[1],
+            id[2],
+            id[3],
+            id[4],
+            id[5],
+            id[6],
+            id[7],
+            u16::from_be_bytes([id[8], id[9]])
+        )
+    }
+}
+
+/// A correction to the TSU, as requested by the servo.
+#[derive(Clone, Copy, Debug)]
+pub enum Adjustment {
+    /// Step the time by the given number of nanoseconds.
+    Step(i64),
+    /// Run fast (or slow, if negative) by the given number of parts per billion.
+    Frequency(i32),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Status {
+    pub enabled: bool,
+    pub master: Option<PortIdentity>,
+    pub offset_ns: Option<i64>,
+    pub path_delay_ns: Option<i64>,
+    pub frequency_ppb: i32,
+    pub rtc_drift_ppb: Option<i64>,
+}
+
+pub fn set_enabled(enabled: bool) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.enabled = enabled;
+        if !enabled {
+            forget_master(&mut state);
+        }
+    })
+}
+
+pub fn status() -> Status {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        Status {
+            enabled: state.enabled,
+            master: state.master.map(|(master, _)| master),
+            offset_ns: state.offset,
+            path_delay_ns: state.path_delay,
+            frequency_ppb: state.frequency,
+            rtc_drift_ppb: state.rtc.map(|rtc| rtc.drift_ppb),
+        }
+    })
+}
+
+/// Returns the master's time at the given RTC time, once the clock has synchronized.
+pub fn time(now: Instant) -> Option<Timestamp> {
+    let rtc = interrupt::free(|cs| STATE.borrow(cs).borrow().rtc)?;
+    let (at, master) = rtc.reference;
+    let elapsed = (now - at).total_millis() as i64 * 1_000_000;
+    Some(Timestamp::from_nanos(
+        master + elapsed + (i128::from(elapsed) * i128::from(rtc.drift_ppb) / 1_000_000_000) as i64,
+    ))
+}
+
+/// Handles a received PTP message, given the time that the TSU latched for the most recent
+/// event message.
+pub fn receive(now: Instant, frame: &[u8], rx_time: Timestamp) {
+    let message = match frame.get(ETHERNET_HEADER_LEN..) {
+        Some(message) if message.len() >= HEADER_LEN => message,
+        _ => return,
+    };
+    if message[1] & 0x0F != VERSION || message[4] != DOMAIN {
+        return;
+    }
+
+    let kind = message[0] & 0x0F;
+    let two_step = message[6] & FLAG_TWO_STEP != 0;
+    let (correction, source) = match (message[8..16].try_into(), message[20..30].try_into()) {
+        (Ok(correction), Ok(source)) => {
+            (i64::from_be_bytes(correction) >> 16, PortIdentity(source))
+        }
+        _ => return,
+    };
+    let sequence = u16::from_be_bytes([message[30], message[31]]);
+    let body = &message[HEADER_LEN..];
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        let state = &mut *state;
+        if !state.enabled || matches!(state.identity, Some(id) if id.same_clock(&source)) {
+            return;
+        }
+
+        if kind == ANNOUNCE {
+            match state.master {
+                Some((master, _)) if master != source => {}
+                Some(_) => state.master = Some((source, now)),
+                None => {
+                    log::info!("Following PTP master {}", source);
+                    state.master = Some((source, now));
+                }
+            }
+            return;
+        }
+
+        if !matches!(state.master, Some((master, _)) if master == source) {
+            return;
+        }
+
+        match (kind, body.get(..TIMESTAMP_LEN)) {
+            (SYNC, Some(_)) if two_step => {
+                state.sync = Some(PendingSync {
+                    sequence,
+                    correction,
+                    t2: rx_time.total_nanos(),
+                });
+            }
+            (SYNC, Some(origin)) => {
+                let t1 = Timestamp::parse(origin).total_nanos() + correction;
+                synchronized(state, now, t1, rx_time.total_nanos());
+            }
+            (FOLLOW_UP, Some(origin)) => {
+                if let Some(sync) = state.sync.take() {
+                    if sync.sequence == sequence {
+                        let t1 =
+                            Timestamp::parse(origin).total_nanos() + correction + sync.correction;
+                        synchronized(state, now, t1, sync.t2);
+                    }
+                }
+            }
+            (DELAY_RESP, Some(received)) => {
+                let requester = match body
+                    .get(TIMESTAMP_LEN..TIMESTAMP_LEN + PORT_IDENTITY_LEN)
+                    .map(TryInto::try_into)
+                {
+                    Some(Ok(requester)) => PortIdentity(requester),
+                    _ => return,
+                };
+                if let Some(measurement) = &mut state.measurement {
+                    if Some(requester) == state.identity && measurement.sequence == Some(sequence) {
+                        measurement.t4 =
+                            Some(Timestamp::parse(received).total_nanos() - correction);
+                        measured(state);
+                    }
+                }
+            }
+            _ => {}
+        }
+    })
+}
+
+/// Returns true if the time that the last Delay_Req was sent is needed.
+pub fn awaiting_tx_time() -> bool {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        matches!(
+            state.measurement,
+            Some(Measurement {
+                sequence: Some(_),
+                t3: None,
+                ..
+            })
+        )
+    })
+}
+
+/// Handles the time that the TSU latched for the most recently transmitted event message.
+pub fn transmitted(tx_time: Timestamp) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        let state = &mut *state;
+
+        // The Delay_Req may not have been sent yet
+        if state.last_tx == Some(tx_time) {
+            return;
+        }
+        state.last_tx = Some(tx_time);
+
+        if let Some(measurement) = &mut state.measurement {
+            if measurement.sequence.is_some() && measurement.t3.is_none() {
+                measurement.t3 = Some(tx_time.total_nanos());
+                measured(state);
+            }
+        }
+    })
+}
+
+/// Returns the next correction for the TSU, if there is one.
+pub fn take_adjustment() -> Option<Adjustment> {
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().adjustment.take())
+}
+
+/// Returns true if a Delay_Req is due, in which case the network needs to be handled.
+pub fn due() -> bool {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        matches!(state.measurement, Some(Measurement { sequence: None, .. }))
+    })
+}
+
+/// Forgets a master that's no longer announcing itself. This should be called periodically.
+pub fn poll(now: Instant) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        if let Some((master, announced)) = state.master {
+            if now - announced > ANNOUNCE_TIMEOUT {
+                log::warn!("Lost PTP master {}", master);
+                forget_master(&mut state);
+            }
+        }
+    })
+}
+
+/// Returns the next Delay_Req to send, if one is due.
+pub fn take_delay_request(source: EthernetAddress) -> Option<[u8; DELAY_REQUEST_LEN]> {
+    let identity = PortIdentity::new(source);
+    let sequence = interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        let state = &mut *state;
+        state.identity = Some(identity);
+
+        let measurement = state.measurement.as_mut()?;
+        if measurement.sequence.is_some() {
+            return None;
+        }
+
+        let sequence = state.delay_sequence;
+        state.delay_sequence = sequence.wrapping_add(1);
+        measurement.sequence = Some(sequence);
+        Some(sequence)
+    })?;
+
+    let mut frame = [0; DELAY_REQUEST_LEN];
+    frame[0..6].copy_from_slice(MULTICAST.as_bytes());
+    frame[6..12].copy_from_slice(source.as_bytes());
+    frame[12..14].copy_from_slice(&ETHERTYPE.to_be_bytes());
+
+    let message = &mut frame[ETHERNET_HEADER_LEN..];
+    message[0] = DELAY_REQ;
+    message[1] = VERSION;
+    message[2..4].copy_from_slice(&((HEADER_LEN + TIMESTAMP_LEN) as u16).to_be_bytes());
+    message[4] = DOMAIN;
+    message[20..30].copy_from_slice(&identity.0);
+    message[30..32].copy_from_slice(&sequence.to_be_bytes());
+    message[32] = CONTROL_DELAY_REQ;
+    message[33] = LOG_INTERVAL_UNSPECIFIED;
+
+    Some(frame)
+}
+
+// Handles a complete Sync, where t1 is when it was sent (by the master's clock) and t2 is when it
+// was received (by the TSU)
+fn synchronized(state: &mut State, now: Instant, t1: i64, t2: i64) {
+    state.measurement = Some(Measurement {
+        t1,
+        t2,
+        sequence: None,
+        t3: None,
+        t4: None,
+    });
+
+    // The offset can't be known until the path delay has been measured
+    let path_delay = match state.path_delay {
+        Some(delay) => delay,
+        None => return,
+    };
+    let offset = t2 - t1 - path_delay;
+    state.offset = Some(offset);
+
+    if offset.abs() > STEP_THRESHOLD_NS {
+        log::info!("Stepping PTP clock by {} ns", -offset);
+        state.adjustment = Some(Adjustment::Step(-offset));
+        state.measurement = None;
+        state.integral = 0;
+        state.rtc = None;
+        return;
+    }
+
+    // Offsets are measured once per Sync interval (nominally one second), so correcting one
+    // nanosecond of offset per interval is a one part per billion change in frequency
+    state.integral =
+        (state.integral + offset * KI / 10).clamp(-MAX_FREQUENCY_PPB, MAX_FREQUENCY_PPB);
+    let frequency = -(offset * KP / 10 + state.integral);
+    state.frequency = frequency.clamp(-MAX_FREQUENCY_PPB, MAX_FREQUENCY_PPB) as i32;
+    state.adjustment = Some(Adjustment::Frequency(state.frequency));
+
+    // The master's time at reception, paired with the RTC's
+    let master = t2 - offset;
+    state.rtc = Some(match state.rtc {
+        Some(rtc) => {
+            let elapsed_rtc = (now - rtc.baseline.0).total_millis() as i64 * 1_000_000;
+            let elapsed_master = master - rtc.baseline.1;
+            let drift_ppb = match now - rtc.baseline.0 >= DRIFT_INTERVAL {
+                true => {
+                    (i128::from(elapsed_master - elapsed_rtc) * 1_000_000_000
+                        / i128::from(elapsed_rtc)) as i64
+                }
+                false => rtc.drift_ppb,
+            };
+            RtcDiscipline {
+                reference: (now, master),
+                baseline: rtc.baseline,
+                drift_ppb,
+            }
+        }
+        None => RtcDiscipline {
+            reference: (now, master),
+            baseline: (now, master),
+            drift_ppb: 0,
+        },
+    });
+}
+
+// Updates the path delay once all four timestamps of a measurement are known
+fn measured(state: &mut State) {
+    if let Some(Measurement {
+        t1,
+        t2,
+        t3: Some(t3),
+        t4: Some(t4),
+        ..
+    }) = state.measurement
+    {
+        let delay = ((t2 - t1) + (t4 - t3)) / 2;
+        if delay >= 0 {
+            state.path_delay = Some(delay);
+        }
+    }
+}
+
+fn forget_master(state: &mut State) {
+    state.master = None;
+    state.sync = None;
+    state.measurement = None;
+    state.path_delay = None;
+    state.offset = None;
+    state.integral = 0;
+    state.rtc = None;
+}
@@ -0,0 +1,210 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Token-bucket limits on the traffic that costs the network task the most to answer, so that a
+// broadcast storm or a scanner can't keep it from everything else. Each class of traffic has a
+// bucket that holds up to its burst and refills at its rate; each packet takes a token, and
+// packets that find the bucket empty are dropped (and counted).
+//
+// Echo requests are limited as they're received (see icmp), as are new TCP connections, whose
+// SYNs are dropped before smoltcp sees them (see efm32gg::dispatch); the client retransmits, so a
+// connection is only delayed. Discovery probes are limited as they're answered.
+
+use core::cell::RefCell;
+use core::fmt;
+use core::str::FromStr;
+use cortex_m::interrupt::{self, Mutex};
+use smoltcp::time::Instant;
+use smoltcp::wire::{
+    EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Packet, Ipv6Packet, TcpPacket,
+};
+
+// Tokens are kept in thousandths, so that they refill smoothly at any rate
+const MILLI: u64 = 1000;
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    buckets: [
+        Bucket::new(None),
+        Bucket::new(Some(Limit { rate: 5, burst: 10 })),
+        Bucket::new(Some(Limit {
+            rate: 10,
+            burst: 20,
+        })),
+    ],
+    dropped: [0; Class::ALL.len()],
+}));
+
+struct State {
+    // Indexed by `Class::index`
+    buckets: [Bucket; Class::ALL.len()],
+    dropped: [u32; Class::ALL.len()],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Class {
+    /// ICMP and ICMPv6 echo requests.
+    Echo,
+    /// Discovery probes (see `discovery`).
+    Discovery,
+    /// New TCP connections to any of the services.
+    Accept,
+}
+
+impl Class {
+    pub const ALL: [Class; 3] = [Class::Echo, Class::Discovery, Class::Accept];
+
+    fn index(self) -> usize {
+        match self {
+            Class::Echo => 0,
+            Class::Discovery => 1,
+            Class::Accept => 2,
+        }
+    }
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Class::Echo => "echo",
+            Class::Discovery => "discovery",
+            Class::Accept => "accept",
+        })
+    }
+}
+
+impl FromStr for Class {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Class, Self::Err> {
+        Class::ALL
+            .iter()
+            .copied()
+            .find(|class| match class {
+                Class::Echo => s == "echo",
+                Class::Discovery => s == "discovery",
+                Class::Accept => s == "accept",
+            })
+            .ok_or("unknown traffic class")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    /// The packets allowed each second, on average.
+    pub rate: u32,
+    /// The packets allowed at once, after a quiet period.
+    pub burst: u32,
+}
+
+struct Bucket {
+    limit: Option<Limit>,
+    // In thousandths of a token
+    tokens: u64,
+    updated: Instant,
+}
+
+impl Bucket {
+    const fn new(limit: Option<Limit>) -> Bucket {
+        Bucket {
+            limit,
+            tokens: match limit {
+                Some(limit) => limit.burst as u64 * MILLI,
+                None => 0,
+            },
+            updated: Instant::from_millis_const(0),
+        }
+    }
+
+    fn take(&mut self, now: Instant) -> bool {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+
+        let elapsed = (now - self.updated).total_millis();
+        self.updated = now;
+        self.tokens = self
+            .tokens
+            .saturating_add(elapsed.saturating_mul(u64::from(limit.rate)))
+            .min(u64::from(limit.burst) * MILLI);
+
+        match self.tokens.checked_sub(MILLI) {
+            Some(tokens) => {
+                self.tokens = tokens;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Sets the limit on a class of traffic, or lifts it.
+pub fn set_limit(class: Class, limit: Option<Limit>) {
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().buckets[class.index()] = Bucket::new(limit))
+}
+
+pub fn limit(class: Class) -> Option<Limit> {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().buckets[class.index()].limit)
+}
+
+/// Returns the number of packets of each class (see `Class::ALL`) that have been dropped.
+pub fn dropped() -> [u32; Class::ALL.len()] {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().dropped)
+}
+
+/// Takes a token for a packet, returning false (and counting the packet) if it should be dropped.
+pub fn allow(class: Class, now: Instant) -> bool {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        let allowed = state.buckets[class.index()].take(now);
+        if !allowed {
+            let dropped = &mut state.dropped[class.index()];
+            *dropped = dropped.wrapping_add(1);
+        }
+        allowed
+    })
+}
+
+/// Limits the new TCP connections in received frames, returning false if the frame should be
+/// dropped rather than passed to the network stack.
+pub fn receive(now: Instant, frame: &[u8]) -> bool {
+    match is_syn(frame) {
+        Some(true) => allow(Class::Accept, now),
+        _ => true,
+    }
+}
+
+// Returns true if the frame holds a TCP segment opening a connection
+fn is_syn(frame: &[u8]) -> Option<bool> {
+    let frame = EthernetFrame::new_checked(frame).ok()?;
+    let segment = match frame.ethertype() {
+        EthernetProtocol::Ipv4 => {
+            let packet = Ipv4Packet::new_checked(frame.payload()).ok()?;
+            if packet.next_header() != IpProtocol::Tcp || packet.frag_offset() != 0 {
+                return None;
+            }
+            TcpPacket::new_checked(packet.payload()).ok()?
+        }
+        EthernetProtocol::Ipv6 => {
+            let packet = Ipv6Packet::new_checked(frame.payload()).ok()?;
+            if packet.next_header() != IpProtocol::Tcp {
+                return None;
+            }
+            TcpPacket::new_checked(packet.payload()).ok()?
+        }
+        _ => return None,
+    };
+    Some(segment.syn() && !segment.ack())
+}
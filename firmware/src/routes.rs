@@ -0,0 +1,184 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Static IPv4 routes, which sit in the interface's route table alongside the default routes (from
+// DHCP or the static configuration, and from router advertisements). The routes are kept in the
+// store and handed to the interface by the network task (see
+// network::Resources::handle_routes), which also keeps a copy of the whole table here so that it
+// can be displayed without holding the interface.
+
+use crate::store::{self, Key};
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+use smoltcp::wire::{IpAddress, IpCidr, Ipv4Address, Ipv4Cidr};
+
+/// The number of slots in the interface's route table: one for each default route, and one for
+/// each static route. smoltcp's table is sized to match by the `iface-max-route-count-8` feature.
+pub const TABLE_SIZE: usize = 2 + MAX_STATIC;
+
+/// The most static routes.
+pub const MAX_STATIC: usize = 6;
+
+// Each route is stored as its network address, prefix length, and gateway
+const ENCODED_LEN: usize = 9;
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    statics: [None; MAX_STATIC],
+    changed: false,
+    table: [None; TABLE_SIZE],
+}));
+
+struct State {
+    statics: [Option<Route>; MAX_STATIC],
+    // Set until the interface has been given the current static routes
+    changed: bool,
+    // The interface's table, as of the last time the network task looked
+    table: [Option<(IpCidr, IpAddress)>; TABLE_SIZE],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Route {
+    pub destination: Ipv4Cidr,
+    pub gateway: Ipv4Address,
+}
+
+/// Loads the static routes from the store. This must be called once at boot.
+pub fn init() {
+    let mut value = [0; MAX_STATIC * ENCODED_LEN];
+    let len = match store::get(Key::Routes, &mut value) {
+        Some(len)
+            if len % ENCODED_LEN == 0
+                && value[..len]
+                    .chunks(ENCODED_LEN)
+                    .all(|encoded| encoded[4] <= 32) =>
+        {
+            len
+        }
+        Some(_) => {
+            log::warn!("Ignoring malformed static routes");
+            return;
+        }
+        None => return,
+    };
+
+    let mut statics = [None; MAX_STATIC];
+    for (route, encoded) in statics.iter_mut().zip(value[..len].chunks(ENCODED_LEN)) {
+        *route = Some(Route {
+            destination: Ipv4Cidr::new(Ipv4Address::from_bytes(&encoded[0..4]), encoded[4]),
+            gateway: Ipv4Address::from_bytes(&encoded[5..9]),
+        });
+    }
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.statics = statics;
+        state.changed = true;
+    })
+}
+
+/// Returns the static routes.
+pub fn statics() -> [Option<Route>; MAX_STATIC] {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().statics)
+}
+
+/// Returns every route in the interface's table, as its destination and gateway.
+pub fn table() -> [Option<(IpCidr, IpAddress)>; TABLE_SIZE] {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().table)
+}
+
+/// Adds a static route, replacing any to the same destination.
+pub fn add(destination: Ipv4Cidr, gateway: Ipv4Address) -> Result<(), &'static str> {
+    if destination.prefix_len() == 0 {
+        return Err("the default route is set by the address configuration");
+    }
+    if !gateway.is_unicast() {
+        return Err("the gateway must be a unicast address");
+    }
+
+    let destination = destination.network();
+    let mut statics = statics();
+    let slot = match statics
+        .iter()
+        .position(|r| matches!(r, Some(r) if r.destination == destination))
+    {
+        Some(slot) => slot,
+        None => statics
+            .iter()
+            .position(Option::is_none)
+            .ok_or("too many static routes")?,
+    };
+    statics[slot] = Some(Route {
+        destination,
+        gateway,
+    });
+    save(statics)
+}
+
+/// Removes the static route to a destination.
+pub fn remove(destination: Ipv4Cidr) -> Result<(), &'static str> {
+    let destination = destination.network();
+    let mut statics = statics();
+    let route = statics
+        .iter_mut()
+        .find(|r| matches!(r, Some(r) if r.destination == destination))
+        .ok_or("no such static route")?;
+    *route = None;
+    save(statics)
+}
+
+fn save(statics: [Option<Route>; MAX_STATIC]) -> Result<(), &'static str> {
+    let mut value = [0; MAX_STATIC * ENCODED_LEN];
+    let mut len = 0;
+    for route in statics.iter().flatten() {
+        let encoded = &mut value[len..len + ENCODED_LEN];
+        encoded[0..4].copy_from_slice(route.destination.address().as_bytes());
+        encoded[4] = route.destination.prefix_len();
+        encoded[5..9].copy_from_slice(route.gateway.as_bytes());
+        len += ENCODED_LEN;
+    }
+
+    match len {
+        0 => store::remove(Key::Routes)?,
+        len => store::set(Key::Routes, &value[..len])?,
+    }
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.statics = statics;
+        state.changed = true;
+    });
+    Ok(())
+}
+
+/// Returns the static routes if they've changed since the interface was last given them.
+pub fn take_change() -> Option<[Option<Route>; MAX_STATIC]> {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        match core::mem::take(&mut state.changed) {
+            true => Some(state.statics),
+            false => None,
+        }
+    })
+}
+
+/// Returns true if the interface needs to be given the static routes.
+pub fn pending() -> bool {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().changed)
+}
+
+/// Records the interface's route table (see `table`).
+pub fn record_table(table: [Option<(IpCidr, IpAddress)>; TABLE_SIZE]) {
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().table = table)
+}
@@ -0,0 +1,153 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A loopback self-test of the MAC and PHY, for checking newly assembled boards. A test is
+// requested (e.g. from the terminal) and then run by the network task, which owns the driver (see
+// `EFM32GG::self_test`): the PHY or the MAC is put into loopback, a few test frames of different
+// lengths are sent, and each one is checked as it comes back. The link drops while the PHY is in
+// loopback, so the network is briefly unavailable.
+
+use core::cell::RefCell;
+use core::fmt;
+use core::str::FromStr;
+use cortex_m::interrupt::{self, Mutex};
+
+/// The EtherType of the test frames (the first of the IEEE 802 local experimental EtherTypes).
+pub const ETHERTYPE: u16 = 0x88B5;
+
+/// The lengths of the test frames, which span one or more RX and TX buffers.
+pub const FRAME_LENS: [usize; 3] = [60, 300, 1000];
+
+const HEADER_LEN: usize = 15;
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    requested: None,
+    result: None,
+}));
+
+struct State {
+    requested: Option<Loopback>,
+    result: Option<(Loopback, Result<(), Failure>)>,
+}
+
+/// Where the test frames are looped back.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Loopback {
+    Phy,
+    Mac,
+}
+
+impl fmt::Display for Loopback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Loopback::Phy => f.pad("PHY"),
+            Loopback::Mac => f.pad("MAC"),
+        }
+    }
+}
+
+impl FromStr for Loopback {
+    type Err = &'static str;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "phy" => Ok(Loopback::Phy),
+            "mac" => Ok(Loopback::Mac),
+            _ => Err("loopback must be 'phy' or 'mac'"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Failure {
+    /// There was no room to queue the test frame.
+    NoTransmitBuffers { frame: usize },
+    /// The test frame never came back.
+    Lost { frame: usize },
+    /// The test frame came back, but differed from what was sent starting at the given offset.
+    Corrupted { frame: usize, offset: usize },
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::NoTransmitBuffers { frame } => {
+                write!(f, "no transmit buffers for frame {}", frame)
+            }
+            Failure::Lost { frame } => write!(f, "frame {} wasn't received", frame),
+            Failure::Corrupted { frame, offset } => {
+                write!(f, "frame {} was corrupted at byte {}", frame, offset)
+            }
+        }
+    }
+}
+
+/// Requests a self-test, replacing any request that hasn't been started yet.
+pub fn request(loopback: Loopback) {
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().requested = Some(loopback))
+}
+
+/// Returns true if a self-test has been requested, in which case the network needs to be handled.
+pub fn pending() -> bool {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().requested.is_some())
+}
+
+pub fn take_request() -> Option<Loopback> {
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().requested.take())
+}
+
+/// Records the result of a self-test.
+pub fn complete(loopback: Loopback, result: Result<(), Failure>) {
+    match result {
+        Ok(()) => log::info!("{} loopback self-test passed", loopback),
+        Err(err) => log::error!("{} loopback self-test failed: {}", loopback, err),
+    }
+
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().result = Some((loopback, result)))
+}
+
+/// Returns the result of the most recent self-test.
+pub fn last_result() -> Option<(Loopback, Result<(), Failure>)> {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().result)
+}
+
+/// Fills the buffer with the given test frame. The buffer's length must be that of the frame.
+pub fn fill(frame: &mut [u8], index: usize) {
+    frame[0..6].copy_from_slice(&[0xFF; 6]);
+    frame[6..12].copy_from_slice(&[0x02, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    frame[12..14].copy_from_slice(&ETHERTYPE.to_be_bytes());
+    frame[14] = index as u8;
+    frame[HEADER_LEN..]
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, byte)| *byte = (i as u8).wrapping_mul(31).wrapping_add(index as u8));
+}
+
+/// Checks a received frame against the given test frame, returning the offset of the first byte
+/// that differs. Returns `None` if the frame isn't a test frame at all.
+pub fn check(frame: &[u8], index: usize) -> Option<Result<(), usize>> {
+    if frame.get(12..14)? != ETHERTYPE.to_be_bytes() {
+        return None;
+    }
+
+    let len = FRAME_LENS[index];
+    let mut expected = [0; 1536];
+    fill(&mut expected[..len], index);
+
+    match (0..len).find(|&i| frame.get(i) != Some(&expected[i])) {
+        Some(offset) => Some(Err(offset)),
+        None => Some(Ok(())),
+    }
+}
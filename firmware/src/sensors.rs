@@ -0,0 +1,96 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The internal temperature sensor is sampled with ADC0 (see efm32gg::adc). The reading is
+// converted using the single-point calibration from the DI page and the typical gradient from the
+// data sheet. Without a valid DI page, there's no way to convert the reading, so the sensor is
+// reported as unavailable.
+//
+// On the passthru, ADC0 also samples LOAD_SEN, the voltage across the 0.1 Ω shunt in the return
+// path of the downstream port, to measure the load current. There's no amplifier, so the
+// resolution is roughly 3 mA.
+
+use crate::efm32gg::{adc, devinfo};
+use core::cell::Cell;
+use cortex_m::interrupt::{self, Mutex};
+
+/// The temperature above which a warning is logged.
+pub const WARN_THRESHOLD_C: f32 = 85.0;
+
+// The temperature must drop this far below the threshold before another warning is logged
+const WARN_HYSTERESIS_C: f32 = 5.0;
+
+// The typical output gradient of the temperature sensor, in mV/°C
+const TGRAD_ADCTH: f32 = -1.84;
+
+/// The ADC input that LOAD_SEN is connected to (APORT1XCH4, which is PC4).
+pub const LOAD_SEN: u8 = 0x24;
+
+const SHUNT_MILLIOHMS: u32 = 100;
+
+static WARNING: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Samples the internal temperature sensor, returning the temperature in degrees Celsius, or
+/// `None` if the sensor hasn't been initialized or isn't calibrated.
+pub fn temperature_c() -> Option<f32> {
+    if !devinfo::is_valid() {
+        return None;
+    }
+
+    let sample = f32::from(adc::sample(adc::TEMPERATURE)?);
+    let cal_temp = f32::from(devinfo::cal_temp());
+    let cal_read = f32::from(devinfo::adc0_temp_read_1v25());
+    let reference = adc::REFERENCE_MV as f32;
+    Some(cal_temp - (cal_read - sample) * reference / (4096.0 * TGRAD_ADCTH))
+}
+
+/// Samples LOAD_SEN, returning the current drawn by the downstream port in milliamps, or `None` if
+/// the ADC hasn't been initialized. This is only meaningful on the passthru.
+pub fn load_current_ma() -> Option<u32> {
+    let millivolts = adc::read_channel(LOAD_SEN)?;
+    Some(millivolts * 1000 / SHUNT_MILLIOHMS)
+}
+
+/// Samples the temperature sensor and logs a warning if the temperature has crossed the
+/// threshold. This should be called periodically.
+pub fn poll() {
+    let temperature = match temperature_c() {
+        Some(temperature) => temperature,
+        None => return,
+    };
+
+    let warn = interrupt::free(|cs| {
+        let warning = WARNING.borrow(cs);
+        match (warning.get(), temperature) {
+            (false, t) if t > WARN_THRESHOLD_C => {
+                warning.set(true);
+                true
+            }
+            (true, t) if t < WARN_THRESHOLD_C - WARN_HYSTERESIS_C => {
+                warning.set(false);
+                false
+            }
+            _ => false,
+        }
+    });
+
+    if warn {
+        log::warn!(
+            "Temperature is {:.1} °C (threshold {} °C)",
+            temperature,
+            WARN_THRESHOLD_C
+        );
+    }
+}
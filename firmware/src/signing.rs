@@ -0,0 +1,85 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Ed25519 signatures over firmware images. A signed image is the raw binary followed immediately by
+// the 64-byte signature of that binary, and the public key that signatures are checked against is
+// baked in at build time from the raw 32-byte key named by the POE_SIGNING_KEY environment variable
+// (see build.rs). For example, with OpenSSL:
+//
+//     openssl genpkey -algorithm ed25519 -out signing-key.pem
+//     openssl pkey -in signing-key.pem -pubout -outform DER | tail -c 32 > signing-key.pub
+//     openssl pkeyutl -sign -rawin -inkey signing-key.pem -in poe.bin -out poe.sig
+//     cat poe.bin poe.sig > poe-signed.bin
+//
+// Builds without a key (e.g. for development) don't check signatures at all.
+
+use core::fmt;
+use ed25519_compact::{PublicKey, Signature};
+use sha2::{Digest, Sha256};
+
+/// The length of a signature, in bytes.
+pub const SIGNATURE_LEN: usize = 64;
+
+const KEY: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/signing-key.pub"));
+
+/// The result of checking an image's signature.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    /// The signature was made with the baked-in key.
+    Valid,
+    /// The signature doesn't match the image or wasn't made with the baked-in key.
+    Invalid,
+    /// There's no signature after the image (the flash is erased).
+    Unsigned,
+    /// This build doesn't have a key, so the signature wasn't checked.
+    Unchecked,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Status::Valid => "valid",
+            Status::Invalid => "invalid",
+            Status::Unsigned => "unsigned",
+            Status::Unchecked => "not checked (no key)",
+        })
+    }
+}
+
+/// Returns true if this build has a key to check signatures against.
+pub fn has_key() -> bool {
+    !KEY.is_empty()
+}
+
+/// Checks the signature of the image.
+pub fn check(image: &[u8], signature: &[u8; SIGNATURE_LEN]) -> Status {
+    let key = match PublicKey::from_slice(KEY) {
+        Ok(key) => key,
+        Err(_) => return Status::Unchecked,
+    };
+    if signature.iter().all(|&byte| byte == 0xFF) {
+        return Status::Unsigned;
+    }
+
+    match key.verify(image, &Signature::new(*signature)) {
+        Ok(()) => Status::Valid,
+        Err(_) => Status::Invalid,
+    }
+}
+
+/// Returns the SHA-256 hash of the image.
+pub fn sha256(image: &[u8]) -> [u8; 32] {
+    Sha256::digest(image).into()
+}
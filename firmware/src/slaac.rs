@@ -0,0 +1,281 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// IPv6 stateless address autoconfiguration (RFC 4862). The link-local address and the global
+// address both use the modified EUI-64 interface identifier derived from the hardware address,
+// which keeps them in the same solicited-node multicast group.
+//
+// smoltcp handles neighbor discovery but ignores router advertisements, so those are received
+// through a raw socket (see `network::Resources::handle_slaac`). When the link comes up, a few
+// router solicitations are sent so that there's no need to wait for the next unsolicited
+// advertisement. The global address is formed from the first autonomous /64 prefix that's
+// advertised, and lasts for the prefix's valid lifetime.
+
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{
+    EthernetAddress, Icmpv6Packet, Icmpv6Repr, IpProtocol, Ipv6Address, Ipv6Cidr, Ipv6Packet,
+    Ipv6Repr, NdiscPrefixInfoFlags, NdiscRepr,
+};
+
+/// The multicast group joined by every IPv6 node.
+pub const ALL_NODES: EthernetAddress = EthernetAddress([0x33, 0x33, 0x00, 0x00, 0x00, 0x01]);
+
+/// The length of a router solicitation, including the IPv6 header.
+pub const SOLICITATION_LEN: usize = 48;
+
+const ALL_ROUTERS: Ipv6Address =
+    Ipv6Address([0xFF, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x02]);
+
+const SOLICITATION_INTERVAL: Duration = Duration::from_secs(4);
+const SOLICITATION_COUNT: u8 = 3;
+
+// Neighbor discovery messages from anywhere but the local link are rejected
+const NDISC_HOP_LIMIT: u8 = 255;
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    solicitations: 0,
+    next_solicitation: Instant::from_millis_const(0),
+    lease: None,
+    changed: false,
+}));
+
+struct State {
+    solicitations: u8,
+    next_solicitation: Instant,
+    lease: Option<Lease>,
+    changed: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Lease {
+    address: Ipv6Cidr,
+    router: Option<(Ipv6Address, Instant)>,
+    expires: Instant,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Event {
+    Configured {
+        address: Ipv6Cidr,
+        router: Option<Ipv6Address>,
+    },
+    Deconfigured,
+}
+
+/// Returns the link-local address for the interface with the given hardware address.
+pub fn link_local(hardware_addr: EthernetAddress) -> Ipv6Cidr {
+    let mut address = [0; 16];
+    address[0..2].copy_from_slice(&[0xFE, 0x80]);
+    address[8..16].copy_from_slice(&interface_id(hardware_addr));
+    Ipv6Cidr::new(Ipv6Address(address), 64)
+}
+
+/// Returns the hardware address of the solicited-node multicast group for the interface's
+/// addresses.
+pub fn solicited_node(hardware_addr: EthernetAddress) -> EthernetAddress {
+    let addr = hardware_addr.as_bytes();
+    EthernetAddress([0x33, 0x33, 0xFF, addr[3], addr[4], addr[5]])
+}
+
+/// Starts soliciting routers when the link comes up, and forgets the global address when it's
+/// lost.
+pub fn link_changed(up: bool, now: Instant) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.solicitations = match up {
+            true => SOLICITATION_COUNT,
+            false => 0,
+        };
+        state.next_solicitation = now;
+        state.changed |= state.lease.take().is_some();
+    })
+}
+
+/// Returns the next router solicitation to send, if one is due.
+pub fn take_solicitation(
+    now: Instant,
+    hardware_addr: EthernetAddress,
+) -> Option<[u8; SOLICITATION_LEN]> {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        if state.solicitations == 0 || state.next_solicitation > now {
+            return None;
+        }
+
+        state.solicitations -= 1;
+        state.next_solicitation = now + SOLICITATION_INTERVAL;
+        Some(())
+    })?;
+
+    let src_addr = link_local(hardware_addr).address();
+    let icmp = Icmpv6Repr::Ndisc(NdiscRepr::RouterSolicit { lladdr: None });
+    let ip = Ipv6Repr {
+        src_addr,
+        dst_addr: ALL_ROUTERS,
+        next_header: IpProtocol::Icmpv6,
+        payload_len: icmp.buffer_len(),
+        hop_limit: NDISC_HOP_LIMIT,
+    };
+
+    let mut packet = [0; SOLICITATION_LEN];
+    let mut ipv6 = Ipv6Packet::new_unchecked(&mut packet[..]);
+    ip.emit(&mut ipv6);
+    icmp.emit(
+        &src_addr.into(),
+        &ALL_ROUTERS.into(),
+        &mut Icmpv6Packet::new_unchecked(ipv6.payload_mut()),
+        &ChecksumCapabilities::default(),
+    );
+
+    Some(packet)
+}
+
+/// Handles a received ICMPv6 packet (including the IPv6 header), forming a global address from
+/// any router advertisement.
+pub fn receive(now: Instant, hardware_addr: EthernetAddress, packet: &[u8]) {
+    let ipv6 = match Ipv6Packet::new_checked(packet) {
+        Ok(packet) => packet,
+        Err(_) => return,
+    };
+    let ip = match Ipv6Repr::parse(&ipv6) {
+        Ok(repr) if repr.next_header == IpProtocol::Icmpv6 && repr.hop_limit == NDISC_HOP_LIMIT => {
+            repr
+        }
+        _ => return,
+    };
+    let icmp = match Icmpv6Packet::new_checked(ipv6.payload()) {
+        Ok(packet) => packet,
+        Err(_) => return,
+    };
+
+    let (router_lifetime, prefix) = match Icmpv6Repr::parse(
+        &ip.src_addr.into(),
+        &ip.dst_addr.into(),
+        &icmp,
+        &ChecksumCapabilities::default(),
+    ) {
+        Ok(Icmpv6Repr::Ndisc(NdiscRepr::RouterAdvert {
+            router_lifetime,
+            prefix_info: Some(prefix),
+            ..
+        })) => (router_lifetime, prefix),
+        _ => return,
+    };
+
+    if prefix.prefix_len != 64
+        || !prefix.flags.contains(NdiscPrefixInfoFlags::ADDRCONF)
+        || prefix.prefix.is_link_local()
+    {
+        log::debug!(
+            "Ignoring advertised prefix {}/{}",
+            prefix.prefix,
+            prefix.prefix_len
+        );
+        return;
+    }
+
+    let mut address = [0; 16];
+    address[0..8].copy_from_slice(&prefix.prefix.as_bytes()[0..8]);
+    address[8..16].copy_from_slice(&interface_id(hardware_addr));
+    let lease = match prefix.valid_lifetime.total_millis() {
+        0 => None,
+        _ => Some(Lease {
+            address: Ipv6Cidr::new(Ipv6Address(address), 64),
+            router: match router_lifetime.total_millis() {
+                0 => None,
+                _ => Some((ip.src_addr, now + router_lifetime)),
+            },
+            expires: now + prefix.valid_lifetime,
+        }),
+    };
+
+    // Only the address and router matter to the interface; the lifetimes are just refreshed
+    let config = |lease: &Option<Lease>| {
+        lease.map(|lease| (lease.address, lease.router.map(|(router, _)| router)))
+    };
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.solicitations = 0;
+        state.changed |= config(&state.lease) != config(&lease);
+        state.lease = lease;
+    })
+}
+
+/// Returns true if a router solicitation is due or the configuration has expired, in which case
+/// the network needs to be handled.
+pub fn due(now: Instant) -> bool {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        let expired = match state.lease {
+            Some(lease) => {
+                lease.expires <= now || matches!(lease.router, Some((_, expires)) if expires <= now)
+            }
+            None => false,
+        };
+
+        expired || (state.solicitations > 0 && state.next_solicitation <= now)
+    })
+}
+
+/// Expires a stale address or default router, returning an event if the configuration changed.
+/// This should be called periodically.
+pub fn poll(now: Instant) -> Option<Event> {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        let state = &mut *state;
+        if let Some(lease) = &mut state.lease {
+            if matches!(lease.router, Some((_, expires)) if expires <= now) {
+                lease.router = None;
+                state.changed = true;
+            }
+        }
+        if matches!(state.lease, Some(lease) if lease.expires <= now) {
+            state.lease = None;
+            state.changed = true;
+        }
+
+        if !state.changed {
+            return None;
+        }
+        state.changed = false;
+
+        Some(match state.lease {
+            Some(lease) => Event::Configured {
+                address: lease.address,
+                router: lease.router.map(|(router, _)| router),
+            },
+            None => Event::Deconfigured,
+        })
+    })
+}
+
+// The modified EUI-64 interface identifier (RFC 4291, appendix A)
+fn interface_id(hardware_addr: EthernetAddress) -> [u8; 8] {
+    let addr = hardware_addr.as_bytes();
+    [
+        addr[0] ^ 0x02,
+        addr[1],
+        addr[2],
+        0xFF,
+        0xFE,
+        addr[3],
+        addr[4],
+        addr[5],
+    ]
+}
@@ -0,0 +1,720 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A minimal SNMPv2c agent (RFC 3416), supporting Get, GetNext, and Set requests. It serves the
+// system group and the interfaces group (for the single Ethernet interface) from MIB-II, along
+// with a handful of objects describing the downstream port under an enterprise subtree.
+//
+// Requests using the read community are answered, as are requests using the write community (if
+// one has been set). Requests using any other community are dropped. Only the write community
+// can set objects.
+//
+// Operational events (see `events`) are also sent as SNMPv2-Trap notifications, using the read
+// community, to the trap receiver (if one has been set).
+
+use crate::efm32gg::Statistics;
+use crate::events::Entry;
+use crate::hostname::Hostname;
+use crate::identify::Pattern;
+
+use core::cell::RefCell;
+use core::convert::TryFrom;
+use core::fmt::{self, Write};
+use cortex_m::interrupt::{self, Mutex};
+use ethernet_phy::{LinkSpeed, LinkState};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, IpAddress};
+
+pub const PORT: u16 = 161;
+
+/// The port that traps are sent to.
+pub const TRAP_PORT: u16 = 162;
+
+/// The largest message that's handled. Every agent needs to accept messages of at least this
+/// size.
+pub const MAX_MESSAGE_LEN: usize = 484;
+
+const MAX_COMMUNITY_LEN: usize = 32;
+const MAX_OID_LEN: usize = 32;
+
+const READ_COMMUNITY: &[u8] = b"public";
+
+const VERSION_2C: i64 = 1;
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_COUNTER32: u8 = 0x41;
+const TAG_GAUGE32: u8 = 0x42;
+const TAG_TIMETICKS: u8 = 0x43;
+const TAG_NO_SUCH_OBJECT: u8 = 0x80;
+const TAG_NO_SUCH_INSTANCE: u8 = 0x81;
+const TAG_END_OF_MIB_VIEW: u8 = 0x82;
+
+const PDU_GET: u8 = 0xA0;
+const PDU_GET_NEXT: u8 = 0xA1;
+const PDU_RESPONSE: u8 = 0xA2;
+const PDU_SET: u8 = 0xA3;
+const PDU_TRAP: u8 = 0xA7;
+
+const ERROR_NONE: i64 = 0;
+const ERROR_GEN_ERR: i64 = 5;
+const ERROR_NO_ACCESS: i64 = 6;
+const ERROR_WRONG_TYPE: i64 = 7;
+const ERROR_WRONG_VALUE: i64 = 10;
+const ERROR_NO_CREATION: i64 = 11;
+const ERROR_NOT_WRITABLE: i64 = 17;
+
+// The enterprise subtree. 32473 is the Private Enterprise Number reserved for documentation (RFC
+// 5612), which needs to be replaced with a registered one.
+const SYS_OBJECT_ID: &[u32] = &[1, 3, 6, 1, 4, 1, 32473, 1];
+
+// The objects included in every notification
+const SYS_UP_TIME: &[u32] = &[1, 3, 6, 1, 2, 1, 1, 3, 0];
+const SNMP_TRAP_OID: &[u32] = &[1, 3, 6, 1, 6, 3, 1, 1, 4, 1, 0];
+const EVENT_DESCRIPTION: &[u32] = &[1, 3, 6, 1, 4, 1, 32473, 1, 5, 0];
+
+const SYS_DESCR: &str = concat!("PoE+ gated passthrough ", env!("CARGO_PKG_VERSION"));
+const IF_DESCR: &str = "eth0";
+
+// ethernetCsmacd
+const IF_TYPE: i64 = 6;
+const IF_MTU: i64 = 1500;
+
+// Sorted, so that GetNext can walk it in order
+static MIB: [(&[u32], Object); 37] = [
+    (&[1, 3, 6, 1, 2, 1, 1, 1, 0], Object::SysDescr),
+    (&[1, 3, 6, 1, 2, 1, 1, 2, 0], Object::SysObjectId),
+    (&[1, 3, 6, 1, 2, 1, 1, 3, 0], Object::SysUpTime),
+    (&[1, 3, 6, 1, 2, 1, 1, 5, 0], Object::SysName),
+    (&[1, 3, 6, 1, 2, 1, 2, 1, 0], Object::IfNumber),
+    (&[1, 3, 6, 1, 2, 1, 2, 2, 1, 1, 1], Object::IfIndex),
+    (&[1, 3, 6, 1, 2, 1, 2, 2, 1, 2, 1], Object::IfDescr),
+    (&[1, 3, 6, 1, 2, 1, 2, 2, 1, 3, 1], Object::IfType),
+    (&[1, 3, 6, 1, 2, 1, 2, 2, 1, 4, 1], Object::IfMtu),
+    (&[1, 3, 6, 1, 2, 1, 2, 2, 1, 5, 1], Object::IfSpeed),
+    (&[1, 3, 6, 1, 2, 1, 2, 2, 1, 6, 1], Object::IfPhysAddress),
+    (&[1, 3, 6, 1, 2, 1, 2, 2, 1, 7, 1], Object::IfAdminStatus),
+    (&[1, 3, 6, 1, 2, 1, 2, 2, 1, 8, 1], Object::IfOperStatus),
+    (&[1, 3, 6, 1, 2, 1, 2, 2, 1, 10, 1], Object::IfInOctets),
+    (&[1, 3, 6, 1, 2, 1, 2, 2, 1, 11, 1], Object::IfInUcastPkts),
+    (&[1, 3, 6, 1, 2, 1, 2, 2, 1, 12, 1], Object::IfInNUcastPkts),
+    (&[1, 3, 6, 1, 2, 1, 2, 2, 1, 14, 1], Object::IfInErrors),
+    (&[1, 3, 6, 1, 2, 1, 2, 2, 1, 16, 1], Object::IfOutOctets),
+    (&[1, 3, 6, 1, 2, 1, 2, 2, 1, 17, 1], Object::IfOutUcastPkts),
+    (&[1, 3, 6, 1, 2, 1, 2, 2, 1, 18, 1], Object::IfOutNUcastPkts),
+    (&[1, 3, 6, 1, 2, 1, 2, 2, 1, 20, 1], Object::IfOutErrors),
+    (&[1, 3, 6, 1, 4, 1, 32473, 1, 1, 1, 0], Object::PortEnabled),
+    (&[1, 3, 6, 1, 4, 1, 32473, 1, 1, 2, 0], Object::PortPowered),
+    (&[1, 3, 6, 1, 4, 1, 32473, 1, 1, 3, 0], Object::PortCurrent),
+    (&[1, 3, 6, 1, 4, 1, 32473, 1, 1, 4, 0], Object::PortEnergy),
+    (&[1, 3, 6, 1, 4, 1, 32473, 1, 1, 5, 0], Object::PortTrips),
+    (&[1, 3, 6, 1, 4, 1, 32473, 1, 2, 1, 0], Object::PseAllocated),
+    (&[1, 3, 6, 1, 4, 1, 32473, 1, 3, 0], Object::Identify),
+    (&[1, 3, 6, 1, 4, 1, 32473, 1, 4, 0], Object::Temperature),
+    (&[1, 3, 6, 1, 4, 1, 32473, 1, 6, 1, 0], Object::RxFrameRate),
+    (&[1, 3, 6, 1, 4, 1, 32473, 1, 6, 2, 0], Object::RxOctetRate),
+    (&[1, 3, 6, 1, 4, 1, 32473, 1, 6, 3, 0], Object::TxFrameRate),
+    (&[1, 3, 6, 1, 4, 1, 32473, 1, 6, 4, 0], Object::TxOctetRate),
+    (&[1, 3, 6, 1, 4, 1, 32473, 1, 7, 1, 0], Object::Boots),
+    (&[1, 3, 6, 1, 4, 1, 32473, 1, 7, 2, 0], Object::Watchdogs),
+    (&[1, 3, 6, 1, 4, 1, 32473, 1, 7, 3, 0], Object::Runtime),
+    (&[1, 3, 6, 1, 4, 1, 32473, 1, 7, 4, 0], Object::LinkFlaps),
+];
+
+static WRITE_COMMUNITY: Mutex<RefCell<Option<Community>>> = Mutex::new(RefCell::new(None));
+static TRAPS: Mutex<RefCell<Traps>> = Mutex::new(RefCell::new(Traps {
+    receiver: None,
+    request_id: 0,
+}));
+
+#[derive(Clone, Copy)]
+struct Community {
+    name: [u8; MAX_COMMUNITY_LEN],
+    len: usize,
+}
+
+struct Traps {
+    receiver: Option<IpAddress>,
+    request_id: i64,
+}
+
+/// The state of the device, as needed to answer requests.
+pub struct Context<'a> {
+    pub now: Instant,
+    pub hardware_addr: EthernetAddress,
+    pub hostname: Hostname,
+    pub link: Option<LinkState>,
+    pub statistics: Statistics,
+    pub identifying: bool,
+    pub identify: &'a mut dyn FnMut(Option<Pattern>),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Object {
+    SysDescr,
+    SysObjectId,
+    SysUpTime,
+    SysName,
+    IfNumber,
+    IfIndex,
+    IfDescr,
+    IfType,
+    IfMtu,
+    IfSpeed,
+    IfPhysAddress,
+    IfAdminStatus,
+    IfOperStatus,
+    IfInOctets,
+    IfInUcastPkts,
+    IfInNUcastPkts,
+    IfInErrors,
+    IfOutOctets,
+    IfOutUcastPkts,
+    IfOutNUcastPkts,
+    IfOutErrors,
+    PortEnabled,
+    PortPowered,
+    PortCurrent,
+    PortEnergy,
+    PortTrips,
+    PseAllocated,
+    Identify,
+    Temperature,
+    RxFrameRate,
+    RxOctetRate,
+    TxFrameRate,
+    TxOctetRate,
+    Boots,
+    Watchdogs,
+    Runtime,
+    LinkFlaps,
+}
+
+enum Value<'a> {
+    Integer(i64),
+    OctetString(&'a [u8]),
+    Oid(&'static [u32]),
+    Counter32(u32),
+    Gauge32(u32),
+    TimeTicks(u32),
+    NoSuchObject,
+    NoSuchInstance,
+    EndOfMibView,
+}
+
+/// Sets the community that's allowed to set objects, or disables sets if `None`.
+pub fn set_write_community(community: Option<&str>) -> Result<(), &'static str> {
+    let community = match community {
+        Some("") => return Err("community is empty"),
+        Some(name) if name.len() > MAX_COMMUNITY_LEN => return Err("community is too long"),
+        Some(name) => {
+            let mut community = Community {
+                name: [0; MAX_COMMUNITY_LEN],
+                len: name.len(),
+            };
+            community.name[..name.len()].copy_from_slice(name.as_bytes());
+            Some(community)
+        }
+        None => None,
+    };
+
+    interrupt::free(|cs| *WRITE_COMMUNITY.borrow(cs).borrow_mut() = community);
+    Ok(())
+}
+
+/// Sets the address that traps are sent to, or stops sending them if `None`.
+pub fn set_trap_receiver(receiver: Option<IpAddress>) {
+    interrupt::free(|cs| TRAPS.borrow(cs).borrow_mut().receiver = receiver)
+}
+
+pub fn trap_receiver() -> Option<IpAddress> {
+    interrupt::free(|cs| TRAPS.borrow(cs).borrow().receiver)
+}
+
+/// Writes a notification of the event into `buffer`, returning its length. Each kind of event has
+/// its own notification, numbered after `Event::code` under the enterprise subtree.
+pub fn trap(buffer: &mut [u8], now: Instant, entry: &Entry) -> Option<usize> {
+    let request_id = interrupt::free(|cs| {
+        let mut traps = TRAPS.borrow(cs).borrow_mut();
+        traps.request_id = (traps.request_id + 1) & i64::from(i32::MAX);
+        traps.request_id
+    });
+
+    let mut notification = [0; SYS_OBJECT_ID.len() + 2];
+    notification[..SYS_OBJECT_ID.len()].copy_from_slice(SYS_OBJECT_ID);
+    notification[SYS_OBJECT_ID.len()..].copy_from_slice(&[0, entry.event.code()]);
+
+    let mut writer = Writer { buffer, len: 0 };
+    let message = writer.begin(TAG_SEQUENCE)?;
+    writer.integer(TAG_INTEGER, VERSION_2C)?;
+    writer.tlv(TAG_OCTET_STRING, READ_COMMUNITY)?;
+    let pdu = writer.begin(PDU_TRAP)?;
+    writer.integer(TAG_INTEGER, request_id)?;
+    writer.integer(TAG_INTEGER, ERROR_NONE)?;
+    writer.integer(TAG_INTEGER, 0)?;
+
+    let list = writer.begin(TAG_SEQUENCE)?;
+    let varbind = writer.begin(TAG_SEQUENCE)?;
+    writer.oid(SYS_UP_TIME)?;
+    writer.value(&Value::TimeTicks((now.total_millis() / 10) as u32))?;
+    writer.end(varbind);
+    let varbind = writer.begin(TAG_SEQUENCE)?;
+    writer.oid(SNMP_TRAP_OID)?;
+    writer.oid(&notification)?;
+    writer.end(varbind);
+    let varbind = writer.begin(TAG_SEQUENCE)?;
+    writer.oid(EVENT_DESCRIPTION)?;
+    let description = writer.begin(TAG_OCTET_STRING)?;
+    write!(writer, "{}", entry.event).ok()?;
+    writer.end(description);
+    writer.end(varbind);
+    writer.end(list);
+
+    writer.end(pdu);
+    writer.end(message);
+    Some(writer.len)
+}
+
+/// Handles a request, writing the response into `response`. Returns the length of the response,
+/// or `None` if the request couldn't be parsed (or wasn't allowed) and should be dropped.
+pub fn handle(request: &[u8], response: &mut [u8], context: &mut Context) -> Option<usize> {
+    let mut message = Reader(Reader(request).expect(TAG_SEQUENCE)?);
+    if message.integer()? != VERSION_2C {
+        return None;
+    }
+    let community = message.expect(TAG_OCTET_STRING)?;
+    let (pdu_type, pdu) = message.read()?;
+
+    let mut pdu = Reader(pdu);
+    let request_id = pdu.integer()?;
+    pdu.integer()?;
+    pdu.integer()?;
+    let varbinds = pdu.expect(TAG_SEQUENCE)?;
+
+    let writable = interrupt::free(|cs| match *WRITE_COMMUNITY.borrow(cs).borrow() {
+        Some(write) => write.name[..write.len] == *community,
+        None => false,
+    });
+    if !writable && community != READ_COMMUNITY {
+        log::debug!("Dropping SNMP request with unknown community");
+        return None;
+    }
+
+    let mut writer = Writer {
+        buffer: response,
+        len: 0,
+    };
+    let message = writer.begin(TAG_SEQUENCE)?;
+    writer.integer(TAG_INTEGER, VERSION_2C)?;
+    writer.tlv(TAG_OCTET_STRING, community)?;
+    let pdu = writer.begin(PDU_RESPONSE)?;
+    writer.integer(TAG_INTEGER, request_id)?;
+
+    match pdu_type {
+        PDU_GET | PDU_GET_NEXT => {
+            writer.integer(TAG_INTEGER, ERROR_NONE)?;
+            writer.integer(TAG_INTEGER, 0)?;
+
+            let list = writer.begin(TAG_SEQUENCE)?;
+            let mut varbinds = Reader(varbinds);
+            while !varbinds.0.is_empty() {
+                let oid = Oid::decode(Reader(varbinds.expect(TAG_SEQUENCE)?).expect(TAG_OID)?)?;
+                let (oid, value) = match pdu_type {
+                    PDU_GET => match MIB.iter().find(|(id, _)| *id == oid.arcs()) {
+                        Some((_, object)) => (oid.arcs(), get(*object, context)),
+                        None => (oid.arcs(), Value::NoSuchObject),
+                    },
+                    _ => match MIB.iter().find(|(id, _)| *id > oid.arcs()) {
+                        Some((id, object)) => (*id, get(*object, context)),
+                        None => (oid.arcs(), Value::EndOfMibView),
+                    },
+                };
+
+                let varbind = writer.begin(TAG_SEQUENCE)?;
+                writer.oid(oid)?;
+                writer.value(&value)?;
+                writer.end(varbind);
+            }
+            writer.end(list);
+        }
+        PDU_SET => {
+            let (status, index) = match writable {
+                true => set_all(varbinds, context),
+                false => (ERROR_NO_ACCESS, 1),
+            };
+            writer.integer(TAG_INTEGER, status)?;
+            writer.integer(TAG_INTEGER, index)?;
+
+            // The response carries the variable bindings from the request
+            writer.tlv(TAG_SEQUENCE, varbinds)?;
+        }
+        _ => {
+            log::debug!("Dropping unsupported SNMP PDU ({:#04X})", pdu_type);
+            return None;
+        }
+    }
+
+    writer.end(pdu);
+    writer.end(message);
+    Some(writer.len)
+}
+
+fn get<'a>(object: Object, context: &'a Context) -> Value<'a> {
+    let stats = &context.statistics;
+    let lifetime = crate::lifetime::counters(context.now);
+    let truth = |value: bool| {
+        Value::Integer(match value {
+            true => 1,
+            false => 2,
+        })
+    };
+
+    match object {
+        Object::SysDescr => Value::OctetString(SYS_DESCR.as_bytes()),
+        Object::SysObjectId => Value::Oid(SYS_OBJECT_ID),
+        Object::SysUpTime => Value::TimeTicks((context.now.total_millis() / 10) as u32),
+        Object::SysName => Value::OctetString(context.hostname.as_bytes()),
+        Object::IfNumber | Object::IfIndex => Value::Integer(1),
+        Object::IfDescr => Value::OctetString(IF_DESCR.as_bytes()),
+        Object::IfType => Value::Integer(IF_TYPE),
+        Object::IfMtu => Value::Integer(IF_MTU),
+        Object::IfSpeed => Value::Gauge32(match context.link {
+            Some(LinkState {
+                speed: LinkSpeed::TenMbps,
+                ..
+            }) => 10_000_000,
+            Some(LinkState {
+                speed: LinkSpeed::HundredMbps,
+                ..
+            }) => 100_000_000,
+            None => 0,
+        }),
+        Object::IfPhysAddress => Value::OctetString(context.hardware_addr.as_bytes()),
+        Object::IfAdminStatus => truth(true),
+        Object::IfOperStatus => truth(context.link.is_some()),
+        Object::IfInOctets => Value::Counter32(stats.rx_octets as u32),
+        Object::IfInUcastPkts => Value::Counter32(
+            stats
+                .rx_frames
+                .wrapping_sub(stats.rx_broadcast)
+                .wrapping_sub(stats.rx_multicast),
+        ),
+        Object::IfInNUcastPkts => {
+            Value::Counter32(stats.rx_broadcast.wrapping_add(stats.rx_multicast))
+        }
+        Object::IfInErrors => Value::Counter32(stats.rx_errors),
+        Object::IfOutOctets => Value::Counter32(stats.tx_octets as u32),
+        Object::IfOutUcastPkts => Value::Counter32(
+            stats
+                .tx_frames
+                .wrapping_sub(stats.tx_broadcast)
+                .wrapping_sub(stats.tx_multicast),
+        ),
+        Object::IfOutNUcastPkts => {
+            Value::Counter32(stats.tx_broadcast.wrapping_add(stats.tx_multicast))
+        }
+        Object::IfOutErrors => Value::Counter32(stats.tx_errors),
+        Object::PortEnabled => match crate::port::status() {
+            Some(status) => truth(status.enabled),
+            None => Value::NoSuchInstance,
+        },
+        Object::PortPowered => match crate::port::status() {
+            Some(status) => truth(status.powered),
+            None => Value::NoSuchInstance,
+        },
+        Object::PortCurrent => match crate::port::meter::reading() {
+            Some(reading) => Value::Gauge32(reading.current_ma),
+            None => Value::NoSuchInstance,
+        },
+        Object::PortEnergy => match crate::port::meter::reading() {
+            Some(reading) => Value::Gauge32(u32::try_from(reading.energy_mwh).unwrap_or(u32::MAX)),
+            None => Value::NoSuchInstance,
+        },
+        Object::PortTrips => Value::Counter32(crate::port::protect::status().trips),
+        Object::PseAllocated => {
+            Value::Gauge32(crate::lldp::status().allocated_mw.unwrap_or_default())
+        }
+        Object::Identify => truth(context.identifying),
+        Object::Temperature => match crate::sensors::temperature_c() {
+            Some(temperature) => Value::Integer((temperature * 10.0) as i64),
+            None => Value::NoSuchInstance,
+        },
+        Object::RxFrameRate => Value::Gauge32(crate::efm32gg::traffic::rates().rx_frames),
+        Object::RxOctetRate => Value::Gauge32(crate::efm32gg::traffic::rates().rx_octets as u32),
+        Object::TxFrameRate => Value::Gauge32(crate::efm32gg::traffic::rates().tx_frames),
+        Object::TxOctetRate => Value::Gauge32(crate::efm32gg::traffic::rates().tx_octets as u32),
+        Object::Boots => Value::Counter32(lifetime.boots),
+        Object::Watchdogs => Value::Counter32(lifetime.watchdog_resets),
+        // In seconds, since the total running time overflows TimeTicks in under 500 days
+        Object::Runtime => Value::Counter32(lifetime.uptime_s as u32),
+        Object::LinkFlaps => Value::Counter32(lifetime.link_flaps),
+    }
+}
+
+fn set(object: Object, tag: u8, value: &[u8], context: &mut Context) -> Result<(), i64> {
+    let truth = || match (tag, decode_integer(value)) {
+        (TAG_INTEGER, Some(1)) => Ok(true),
+        (TAG_INTEGER, Some(2)) => Ok(false),
+        (TAG_INTEGER, _) => Err(ERROR_WRONG_VALUE),
+        _ => Err(ERROR_WRONG_TYPE),
+    };
+
+    match object {
+        Object::PortEnabled => crate::port::set_enabled(truth()?).map_err(|err| {
+            log::warn!("Failed to set port: {}", err);
+            ERROR_GEN_ERR
+        }),
+        Object::Identify => {
+            let identify = truth()?;
+            context.identifying = identify;
+            (context.identify)(match identify {
+                true => Some(crate::identify::DEFAULT),
+                false => None,
+            });
+            Ok(())
+        }
+        _ => Err(ERROR_NOT_WRITABLE),
+    }
+}
+
+// Sets each of the variables in turn, returning the error status and index of the first one that
+// couldn't be set
+fn set_all(varbinds: &[u8], context: &mut Context) -> (i64, i64) {
+    let mut varbinds = Reader(varbinds);
+    let mut index = 0;
+    while !varbinds.0.is_empty() {
+        index += 1;
+
+        let mut varbind = match varbinds.expect(TAG_SEQUENCE) {
+            Some(varbind) => Reader(varbind),
+            None => return (ERROR_GEN_ERR, index),
+        };
+        let (oid, (tag, value)) = match (
+            varbind.expect(TAG_OID).and_then(Oid::decode),
+            varbind.read(),
+        ) {
+            (Some(oid), Some(value)) => (oid, value),
+            _ => return (ERROR_GEN_ERR, index),
+        };
+
+        let result = match MIB.iter().find(|(id, _)| *id == oid.arcs()) {
+            Some((_, object)) => set(*object, tag, value, context),
+            None => Err(ERROR_NO_CREATION),
+        };
+        if let Err(status) = result {
+            return (status, index);
+        }
+    }
+
+    (ERROR_NONE, 0)
+}
+
+fn decode_integer(bytes: &[u8]) -> Option<i64> {
+    let sign = match bytes.first()? {
+        byte if byte & 0x80 != 0 => -1,
+        _ => 0,
+    };
+    match bytes.len() {
+        1..=8 => Some(bytes.iter().fold(sign, |acc, b| acc << 8 | i64::from(*b))),
+        _ => None,
+    }
+}
+
+// BER-encoded TLVs, read one at a time from the front
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn read(&mut self) -> Option<(u8, &'a [u8])> {
+        let (&tag, rest) = self.0.split_first()?;
+        let (&first, rest) = rest.split_first()?;
+        let (len, rest) = match first {
+            0x00..=0x7F => (usize::from(first), rest),
+            0x81 => {
+                let (&len, rest) = rest.split_first()?;
+                (usize::from(len), rest)
+            }
+            0x82 => {
+                let len = rest.get(0..2)?;
+                (
+                    usize::from(u16::from_be_bytes([len[0], len[1]])),
+                    &rest[2..],
+                )
+            }
+            _ => return None,
+        };
+
+        let value = rest.get(..len)?;
+        self.0 = &rest[len..];
+        Some((tag, value))
+    }
+
+    fn expect(&mut self, tag: u8) -> Option<&'a [u8]> {
+        match self.read()? {
+            (t, value) if t == tag => Some(value),
+            _ => None,
+        }
+    }
+
+    fn integer(&mut self) -> Option<i64> {
+        decode_integer(self.expect(TAG_INTEGER)?)
+    }
+}
+
+// Writes BER-encoded TLVs into a buffer. Returns `None` if the buffer fills up.
+struct Writer<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn put(&mut self, bytes: &[u8]) -> Option<()> {
+        self.buffer
+            .get_mut(self.len..self.len + bytes.len())?
+            .copy_from_slice(bytes);
+        self.len += bytes.len();
+        Some(())
+    }
+
+    fn tlv(&mut self, tag: u8, value: &[u8]) -> Option<()> {
+        match value.len() {
+            len @ 0x00..=0x7F => self.put(&[tag, len as u8])?,
+            len @ 0x80..=0xFF => self.put(&[tag, 0x81, len as u8])?,
+            len => {
+                let [high, low] = u16::try_from(len).ok()?.to_be_bytes();
+                self.put(&[tag, 0x82, high, low])?
+            }
+        }
+        self.put(value)
+    }
+
+    // Constructed values are written with a two-octet length, which is filled in by `end`
+    fn begin(&mut self, tag: u8) -> Option<usize> {
+        self.put(&[tag, 0x82, 0, 0])?;
+        Some(self.len)
+    }
+
+    fn end(&mut self, start: usize) {
+        let len = (self.len - start) as u16;
+        self.buffer[start - 2..start].copy_from_slice(&len.to_be_bytes());
+    }
+
+    fn integer(&mut self, tag: u8, value: i64) -> Option<()> {
+        let bytes = value.to_be_bytes();
+
+        // Skip the leading octets that only extend the sign
+        let mut start = 0;
+        while start < bytes.len() - 1 {
+            match (bytes[start], bytes[start + 1] & 0x80) {
+                (0x00, 0) | (0xFF, 0x80) => start += 1,
+                _ => break,
+            }
+        }
+        self.tlv(tag, &bytes[start..])
+    }
+
+    fn oid(&mut self, arcs: &[u32]) -> Option<()> {
+        let mut encoded = [0; MAX_OID_LEN * 5];
+        let mut len = 0;
+        let mut push = |arc: u32| {
+            for shift in (0..5).rev() {
+                let bits = (arc >> (7 * shift)) as u8 & 0x7F;
+                if len > 0 && encoded[len - 1] & 0x80 != 0 || bits != 0 || shift == 0 {
+                    encoded[len] = bits | if shift == 0 { 0 } else { 0x80 };
+                    len += 1;
+                }
+            }
+        };
+
+        match arcs {
+            [first, second, rest @ ..] if rest.len() < MAX_OID_LEN => {
+                push(first * 40 + second);
+                rest.iter().for_each(|arc| push(*arc));
+            }
+            _ => return None,
+        }
+        self.tlv(TAG_OID, &encoded[..len])
+    }
+
+    fn value(&mut self, value: &Value) -> Option<()> {
+        match *value {
+            Value::Integer(value) => self.integer(TAG_INTEGER, value),
+            Value::OctetString(value) => self.tlv(TAG_OCTET_STRING, value),
+            Value::Oid(arcs) => self.oid(arcs),
+            Value::Counter32(value) => self.integer(TAG_COUNTER32, value.into()),
+            Value::Gauge32(value) => self.integer(TAG_GAUGE32, value.into()),
+            Value::TimeTicks(value) => self.integer(TAG_TIMETICKS, value.into()),
+            Value::NoSuchObject => self.tlv(TAG_NO_SUCH_OBJECT, &[]),
+            Value::NoSuchInstance => self.tlv(TAG_NO_SUCH_INSTANCE, &[]),
+            Value::EndOfMibView => self.tlv(TAG_END_OF_MIB_VIEW, &[]),
+        }
+    }
+}
+
+impl fmt::Write for Writer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.put(s.as_bytes()).ok_or(fmt::Error)
+    }
+}
+
+// An object identifier, as decoded from a request
+struct Oid {
+    arcs: [u32; MAX_OID_LEN],
+    len: usize,
+}
+
+impl Oid {
+    fn decode(bytes: &[u8]) -> Option<Oid> {
+        let mut oid = Oid {
+            arcs: [0; MAX_OID_LEN],
+            len: 0,
+        };
+
+        // Each arc is encoded in base 128, with the high bit set on all but the last octet. The
+        // first two arcs are combined into one.
+        let mut arc: u32 = 0;
+        for byte in bytes {
+            arc = arc.checked_mul(128)? | u32::from(byte & 0x7F);
+            if byte & 0x80 != 0 {
+                continue;
+            }
+
+            if oid.len == 0 {
+                let first = (arc / 40).min(2);
+                oid.push(first)?;
+                oid.push(arc - first * 40)?;
+            } else {
+                oid.push(arc)?;
+            }
+            arc = 0;
+        }
+
+        match bytes.last() {
+            Some(byte) if byte & 0x80 == 0 => Some(oid),
+            _ => None,
+        }
+    }
+
+    fn push(&mut self, arc: u32) -> Option<()> {
+        *self.arcs.get_mut(self.len)? = arc;
+        self.len += 1;
+        Some(())
+    }
+
+    fn arcs(&self) -> &[u32] {
+        &self.arcs[..self.len]
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// RTIC runs everything (including the tasks) on the main stack, which grows down from the top of
+// RAM towards the end of the statics. The unused portion is painted at boot so the high-water mark
+// can be found later by looking for the first overwritten word.
+//
+// The bottom of the stack is covered by an MPU region that denies all access, so an overflow
+// results in a fault instead of silently corrupting the statics. The fault escalates to a hard
+// fault and, since the MPU is disabled while handling hard faults, the handler is free to use the
+// guard region as its stack.
+
+use core::ops::Range;
+use core::ptr;
+use cortex_m::peripheral::MPU;
+use cortex_m::{asm, register};
+
+const PAINT: u32 = 0xDEAD_BEEF;
+const GUARD_SIZE: usize = 1024;
+
+// The number of bytes below the current stack pointer to leave alone while painting
+const PAINT_MARGIN: usize = 128;
+
+const MPU_CTRL_ENABLE: u32 = 1 << 0;
+const MPU_CTRL_PRIVDEFENA: u32 = 1 << 2;
+const MPU_RASR_ENABLE: u32 = 1 << 0;
+const MPU_RASR_XN: u32 = 1 << 28;
+
+const CFSR_MSTKERR: u32 = 1 << 4;
+const CFSR_MMARVALID: u32 = 1 << 7;
+
+extern "C" {
+    static mut __sheap: u32;
+    static mut _stack_start: u32;
+}
+
+/// Paints the unused portion of the stack and enables the stack guard. This must be called once
+/// at boot, as early as possible.
+pub fn init(mpu: &mut MPU) {
+    let sp = register::msp::read() as usize - PAINT_MARGIN;
+    let mut addr = guard_end();
+    while addr < sp {
+        unsafe { ptr::write_volatile(addr as *mut u32, PAINT) };
+        addr += 4;
+    }
+
+    unsafe {
+        mpu.rnr.write(0);
+        mpu.rbar.write(guard_start() as u32);
+        mpu.rasr
+            .write(MPU_RASR_XN | ((GUARD_SIZE.trailing_zeros() - 1) << 1) | MPU_RASR_ENABLE);
+        mpu.ctrl.write(MPU_CTRL_PRIVDEFENA | MPU_CTRL_ENABLE);
+    }
+    asm::dsb();
+    asm::isb();
+
+    log::debug!("Stack: {} bytes, guard at 0x{:08X}", size(), guard_start());
+}
+
+/// Returns the usable size of the stack, in bytes.
+pub fn size() -> usize {
+    top() - guard_end()
+}
+
+/// Returns the largest number of bytes of the stack that have been used since boot.
+pub fn high_water() -> usize {
+    let mut addr = guard_end();
+    while addr < top() && unsafe { ptr::read_volatile(addr as *const u32) } == PAINT {
+        addr += 4;
+    }
+    top() - addr
+}
+
+/// Returns true if the fault described by the fault status and address registers was caused by
+/// the stack overflowing into the guard region.
+pub fn overflowed(cfsr: u32, mmfar: u32) -> bool {
+    let mmfar = mmfar as usize;
+    cfsr & CFSR_MSTKERR != 0
+        || (cfsr & CFSR_MMARVALID != 0 && (guard_start()..guard_end()).contains(&mmfar))
+}
+
+/// Returns the portion of the stack below the current stack pointer (less a margin), which only
+/// preempting tasks and interrupt handlers use. It's painted, unless they've reached into it.
+pub fn unused() -> Range<usize> {
+    guard_end()..register::msp::read() as usize - PAINT_MARGIN
+}
+
+fn top() -> usize {
+    unsafe { ptr::addr_of!(_stack_start) as usize }
+}
+
+// The MPU region has to be aligned to its size, so the guard starts at the first suitable address
+// after the statics
+fn guard_start() -> usize {
+    let end = unsafe { ptr::addr_of!(__sheap) as usize };
+    (end + GUARD_SIZE - 1) & !(GUARD_SIZE - 1)
+}
+
+fn guard_end() -> usize {
+    guard_start() + GUARD_SIZE
+}
@@ -0,0 +1,278 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A small store of settings that survive a reset, kept in two pages of internal flash just below
+// the boot metadata (see `boot`). Each value is identified by a `Key`.
+//
+// Values are appended to the active page as records: a header word (a marker, the key, and the
+// length), the value padded out to whole words, and a commit word (the CRC of the rest of the
+// record, as written). A record that was cut short by a reset never gets its commit word, so
+// it's skipped. The latest committed record for a key holds its value; an empty record removes
+// it. Once the active page fills up, the latest value for each key is copied into the other page,
+// which then becomes active once its header (a marker and a sequence number) is written.
+//
+// Writes block while the flash is programmed (and erased, when the pages are swapped), so they
+// should be made from a low-priority context.
+
+use crate::efm32gg::msc;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// The start of the store's pages in flash.
+pub const START: u32 = 0x001F_B000;
+
+/// The longest value that can be stored.
+pub const MAX_VALUE_LEN: usize = 256;
+
+const PAGES: u32 = 2;
+
+const PAGE_MARKER: u32 = 0x5E77 << 16;
+const RECORD_MARKER: u32 = 0xA5;
+const ERASED: u32 = 0xFFFF_FFFF;
+
+// Held for the duration of each write, so that writes from different tasks can't interleave
+static BUSY: AtomicBool = AtomicBool::new(false);
+
+/// The settings that can be stored. The discriminants are written to flash, so they must not be
+/// reused.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Key {
+    Credential = 1,
+    Dhcp = 2,
+    Hostname = 3,
+    Identify = 4,
+    Routes = 5,
+    Lifetime = 6,
+    Config = 7,
+    Energy = 8,
+    PortLimit = 9,
+    Lease = 10,
+}
+
+impl Key {
+    const ALL: [Key; 10] = [
+        Key::Credential,
+        Key::Dhcp,
+        Key::Hostname,
+        Key::Identify,
+        Key::Routes,
+        Key::Lifetime,
+        Key::Config,
+        Key::Energy,
+        Key::PortLimit,
+        Key::Lease,
+    ];
+
+    fn from_u8(key: u8) -> Option<Key> {
+        Key::ALL.iter().copied().find(|k| *k as u8 == key)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Record {
+    key: Option<Key>,
+    addr: u32,
+    len: usize,
+}
+
+impl Record {
+    fn words(len: usize) -> u32 {
+        (len as u32 + 3) / 4
+    }
+
+    // The size of the record in flash, in bytes
+    fn size(len: usize) -> u32 {
+        4 + 4 * Record::words(len) + 4
+    }
+
+    fn copy_to(&self, buffer: &mut [u8]) -> usize {
+        let len = self.len.min(buffer.len());
+        for (i, byte) in buffer[..len].iter_mut().enumerate() {
+            *byte = unsafe { ptr::read_volatile((self.addr + 4 + i as u32) as *const u8) };
+        }
+        len
+    }
+}
+
+// Holds BUSY for the duration of `f`
+fn exclusive<R, F>(f: F) -> Result<R, &'static str>
+where
+    F: FnOnce() -> Result<R, &'static str>,
+{
+    if BUSY.swap(true, Ordering::Acquire) {
+        return Err("store busy");
+    }
+    let result = f();
+    BUSY.store(false, Ordering::Release);
+    result
+}
+
+fn read(addr: u32) -> u32 {
+    unsafe { ptr::read_volatile(addr as *const u32) }
+}
+
+fn page(index: u32) -> u32 {
+    START + index * msc::PAGE_SIZE
+}
+
+// The CRC of a record's header and value, as stored in its commit word
+fn commit(addr: u32, len: usize) -> u32 {
+    match crate::boot::crc(addr, 4 + 4 * Record::words(len)) {
+        ERASED => 0,
+        crc => crc,
+    }
+}
+
+// Returns the index and sequence number of the active page, if there is one. The sequence numbers
+// wrap, so the newest page is the one that the others are behind.
+fn active() -> Option<(u32, u16)> {
+    (0..PAGES)
+        .filter_map(|index| match read(page(index)) {
+            header if header & 0xFFFF_0000 == PAGE_MARKER => Some((index, header as u16)),
+            _ => None,
+        })
+        .reduce(|a, b| match newer(b.1, a.1) {
+            true => b,
+            false => a,
+        })
+}
+
+// Returns true if sequence number a was written after b
+fn newer(a: u16, b: u16) -> bool {
+    a.wrapping_sub(b) as i16 > 0
+}
+
+// Calls `f` with each committed record in the page, returning the offset of the page's free space
+fn scan<F: FnMut(Record)>(index: u32, mut f: F) -> u32 {
+    let start = page(index);
+    let mut offset = 4;
+    while offset + 4 <= msc::PAGE_SIZE {
+        let header = read(start + offset);
+        if header == ERASED {
+            break;
+        }
+        let len = (header & 0xFFFF) as usize;
+        if header >> 24 != RECORD_MARKER || offset + Record::size(len) > msc::PAGE_SIZE {
+            // The rest of the page can't be trusted, so it's treated as full
+            return msc::PAGE_SIZE;
+        }
+
+        let addr = start + offset;
+        if read(addr + 4 + 4 * Record::words(len)) == commit(addr, len) {
+            f(Record {
+                key: Key::from_u8((header >> 16) as u8),
+                addr,
+                len,
+            });
+        }
+        offset += Record::size(len);
+    }
+    offset
+}
+
+fn latest(index: u32, key: Key) -> Option<Record> {
+    let mut latest = None;
+    scan(index, |record| {
+        if record.key == Some(key) {
+            latest = Some(record);
+        }
+    });
+    latest
+}
+
+fn append(addr: u32, key: Key, value: &[u8]) -> Result<(), &'static str> {
+    let mut words = [ERASED; MAX_VALUE_LEN / 4];
+    for (word, chunk) in words.iter_mut().zip(value.chunks(4)) {
+        let mut bytes = [0xFF; 4];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        *word = u32::from_le_bytes(bytes);
+    }
+
+    let header = RECORD_MARKER << 24 | (key as u32) << 16 | value.len() as u32;
+    msc::write(addr, &[header])?;
+    msc::write(addr + 4, &words[..Record::words(value.len()) as usize])?;
+    msc::write(
+        addr + 4 + 4 * Record::words(value.len()),
+        &[commit(addr, value.len())],
+    )
+}
+
+// Copies the latest value for each key (other than `skip`, which is about to be written) into
+// the other page, and makes it the active one
+fn swap(from: Option<(u32, u16)>, skip: Key) -> Result<(u32, u32), &'static str> {
+    let (to, sequence) = match from {
+        Some((index, sequence)) => ((index + 1) % PAGES, sequence.wrapping_add(1)),
+        None => (0, 0),
+    };
+    msc::erase_page(page(to))?;
+
+    let mut offset = 4;
+    if let Some((index, _)) = from {
+        for key in Key::ALL.iter().copied().filter(|key| *key != skip) {
+            let record = match latest(index, key) {
+                Some(record) if record.len > 0 => record,
+                _ => continue,
+            };
+
+            let mut value = [0; MAX_VALUE_LEN];
+            let len = record.copy_to(&mut value);
+            append(page(to) + offset, key, &value[..len])?;
+            offset += Record::size(len);
+        }
+    }
+
+    msc::write(page(to), &[PAGE_MARKER | u32::from(sequence)])?;
+    Ok((to, offset))
+}
+
+/// Reads the value stored for the key into the buffer, returning its length, or `None` if there's
+/// no value.
+pub fn get(key: Key, buffer: &mut [u8]) -> Option<usize> {
+    let (index, _) = active()?;
+    match latest(index, key)? {
+        record if record.len == 0 => None,
+        record => Some(record.copy_to(buffer)),
+    }
+}
+
+/// Stores a value for the key, replacing any that was stored before.
+pub fn set(key: Key, value: &[u8]) -> Result<(), &'static str> {
+    if value.len() > MAX_VALUE_LEN {
+        return Err("value is too long");
+    }
+
+    exclusive(|| {
+        let active = active();
+        let (index, offset) = match active {
+            Some((index, _)) => match scan(index, |_| {}) {
+                offset if offset + Record::size(value.len()) <= msc::PAGE_SIZE => (index, offset),
+                _ => swap(active, key)?,
+            },
+            None => swap(None, key)?,
+        };
+
+        append(page(index) + offset, key, value)
+    })
+}
+
+/// Removes the value stored for the key.
+pub fn remove(key: Key) -> Result<(), &'static str> {
+    set(key, &[])
+}
+
+/// Erases every stored value, returning each setting to its default once the device restarts.
+pub fn erase() -> Result<(), &'static str> {
+    exclusive(|| (0..PAGES).try_for_each(|index| msc::erase_page(page(index))))
+}
@@ -0,0 +1,501 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A TFTP server (RFC 1350), for pulling a diagnostics bundle off of a device with nothing more
+// than a stock client (e.g. `tftp <host> -c get events.txt`), and for pushing an update onto it.
+// The files are generated as each block is sent:
+//
+//   events.txt    the operational event log
+//   fault.txt     the fault that ended the previous boot
+//   config.txt    the running configuration
+//   capture.pcap  the frames kept in the capture buffer (see `capture::Sink::Buffer`)
+//
+// The one file that can be written is an update, named for its version (e.g. `tftp <host> -m
+// binary -c put poe-signed.bin update-00010002.bin`). It's written into bank B as each block
+// arrives, and staged once the last one has (see `boot`), which takes a few seconds before the
+// last block is acknowledged. Once a password is set, the name starts with a token, as a control
+// request does (e.g. "@<token> update-00010002.bin").
+//
+// Requests arrive on `PORT` and are answered from `TRANSFER_PORT`, one transfer at a time. Files
+// are sent as they are, in either mode, and options (RFC 2347) are ignored, so every block is 512
+// bytes.
+
+use crate::boot::{self, Bank};
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use cortex_m::interrupt::{self, Mutex};
+use ignore_result::Ignore;
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::IpEndpoint;
+
+pub const PORT: u16 = 69;
+
+/// The port that transfers are sent from.
+pub const TRANSFER_PORT: u16 = 51903;
+
+/// The largest packet that's sent or received.
+pub const MAX_PACKET_LEN: usize = 4 + BLOCK_LEN;
+
+const BLOCK_LEN: usize = 512;
+
+const RRQ: u16 = 1;
+const WRQ: u16 = 2;
+const DATA: u16 = 3;
+const ACK: u16 = 4;
+const ERROR: u16 = 5;
+
+const NOT_DEFINED: u16 = 0;
+const FILE_NOT_FOUND: u16 = 1;
+const ACCESS_VIOLATION: u16 = 2;
+const ILLEGAL_OPERATION: u16 = 4;
+const UNKNOWN_TRANSFER_ID: u16 = 5;
+
+// A block that hasn't been acknowledged is sent again after the timeout, until the client has had
+// a few chances
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_RETRIES: u8 = 5;
+
+static TRANSFER: Mutex<RefCell<Option<Transfer>>> = Mutex::new(RefCell::new(None));
+
+#[derive(Clone, Copy, Debug)]
+struct Transfer {
+    client: IpEndpoint,
+    file: File,
+    /// The block being sent or, for an update, the last block received.
+    block: u16,
+    /// Whether the block is shorter than the rest, ending the file.
+    last: bool,
+    /// When the block (or, for an update, its acknowledgement) was last sent, or `None` if it
+    /// hasn't been yet.
+    sent: Option<Instant>,
+    retries: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum File {
+    Events,
+    Fault,
+    Config,
+    /// The capture buffer, up to the length it had when it was requested.
+    Capture {
+        len: usize,
+    },
+    /// An update being written into bank B.
+    Update {
+        version: u32,
+    },
+}
+
+impl File {
+    fn open(name: &str) -> Result<File, (u16, &'static str)> {
+        match name {
+            "events.txt" => Ok(File::Events),
+            "fault.txt" => Ok(File::Fault),
+            "config.txt" => Ok(File::Config),
+            "capture.pcap" => match crate::capture::kept_len() {
+                Some(len) => Ok(File::Capture { len }),
+                None => Err((FILE_NOT_FOUND, "no capture kept in the buffer")),
+            },
+            _ => Err((FILE_NOT_FOUND, "file not found")),
+        }
+    }
+
+    // Opens the update for writing, once the client is authorized to change state (see `auth`)
+    fn create(name: &str, client: IpEndpoint, now: Instant) -> Result<File, (u16, &'static str)> {
+        let (token, name) = match name.strip_prefix('@') {
+            Some(rest) => match rest.split_once(' ') {
+                Some((token, name)) => (Some(token), name),
+                None => (Some(rest), ""),
+            },
+            None => (None, name),
+        };
+        if !crate::auth::verify(token, client.addr, now) {
+            return Err((ACCESS_VIOLATION, "unauthorized"));
+        }
+        if boot::running() == Bank::B {
+            return Err((ACCESS_VIOLATION, "can't replace the running image"));
+        }
+
+        match name
+            .strip_prefix("update-")
+            .and_then(|name| name.strip_suffix(".bin"))
+            .map(|version| u32::from_str_radix(version, 16))
+        {
+            Some(Ok(version)) => Ok(File::Update { version }),
+            Some(Err(_)) => Err((ACCESS_VIOLATION, "invalid version")),
+            None => Err((ACCESS_VIOLATION, "only updates can be written")),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            File::Events => "events.txt",
+            File::Fault => "fault.txt",
+            File::Config => "config.txt",
+            File::Capture { .. } => "capture.pcap",
+            File::Update { .. } => "the update",
+        }
+    }
+
+    // Copies the part of the file that starts at `offset` into `buffer`, returning the number of
+    // bytes copied. The text files are rendered from the start each time, keeping only the part
+    // that lands in the buffer.
+    fn read(&self, offset: usize, buffer: &mut [u8]) -> usize {
+        let mut window = Window {
+            buffer,
+            skip: offset,
+            len: 0,
+        };
+
+        match *self {
+            File::Events => crate::events::for_each(|entry| {
+                let (boot, timestamp, event) = (entry.boot, entry.timestamp, entry.event);
+                writeln!(
+                    window,
+                    "boot {:<5} {} {:<5} {}",
+                    boot,
+                    timestamp,
+                    event.severity(),
+                    event
+                )
+                .ignore()
+            }),
+            File::Fault => match crate::fault::last() {
+                Some(report) => writeln!(window, "{}", report).ignore(),
+                None => writeln!(window, "No fault recorded").ignore(),
+            },
+            File::Config => write_config(&mut window, &crate::config::running()).ignore(),
+            File::Capture { len } => {
+                let len = len.saturating_sub(offset).min(window.buffer.len());
+                window.len = crate::capture::read_kept(offset, &mut window.buffer[..len]);
+            }
+            File::Update { .. } => {}
+        }
+        window.len
+    }
+}
+
+// Keeps the part of the formatted output that falls in the buffer, after skipping the first
+// `skip` bytes
+struct Window<'a> {
+    buffer: &'a mut [u8],
+    skip: usize,
+    len: usize,
+}
+
+impl fmt::Write for Window<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let skipped = s.len().min(self.skip);
+        self.skip -= skipped;
+
+        let bytes = &s.as_bytes()[skipped..];
+        let len = bytes.len().min(self.buffer.len() - self.len);
+        self.buffer[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+// Writes the configuration in the same form as the terminal's "config" command
+fn write_config<W: Write>(w: &mut W, config: &crate::config::Config) -> fmt::Result {
+    match config.addressing.address {
+        Some(address) => writeln!(w, "Address:  {}", address)?,
+        None => writeln!(w, "Address:  DHCP")?,
+    }
+    match config.addressing.gateway {
+        Some(gateway) => writeln!(w, "Gateway:  {}", gateway)?,
+        None => writeln!(w, "Gateway:  none")?,
+    }
+    match config.vlan {
+        Some(id) => writeln!(w, "VLAN:     {}", id)?,
+        None => writeln!(w, "VLAN:     off")?,
+    }
+
+    write!(w, "Sources: ")?;
+    let mut prefixes = config.acl.prefixes().peekable();
+    if prefixes.peek().is_none() {
+        write!(w, " any")?;
+    }
+    for prefix in prefixes {
+        write!(w, " {}", prefix)?;
+    }
+    writeln!(w)?;
+
+    write!(w, "Disabled:")?;
+    let mut disabled = crate::acl::SERVICES
+        .iter()
+        .filter(|service| !config.acl.enabled(**service))
+        .peekable();
+    if disabled.peek().is_none() {
+        write!(w, " none")?;
+    }
+    for service in disabled {
+        write!(w, " {}", service)?;
+    }
+    writeln!(w)
+}
+
+/// Handles a packet sent to `PORT`, starting a transfer if it's a read or write request. Returns
+/// the length of the error written into `response`, if the request is refused.
+pub fn request(
+    packet: &[u8],
+    client: IpEndpoint,
+    now: Instant,
+    response: &mut [u8; MAX_PACKET_LEN],
+) -> Option<usize> {
+    let (opcode, body) = split_opcode(packet)?;
+    let result = match opcode {
+        RRQ => parse_request(body).and_then(File::open),
+        WRQ => parse_request(body).and_then(|name| File::create(name, client, now)),
+        _ => Err((ILLEGAL_OPERATION, "expected a read or write request")),
+    }
+    .and_then(|file| {
+        interrupt::free(|cs| {
+            let mut transfer = TRANSFER.borrow(cs).borrow_mut();
+            match *transfer {
+                // A client that repeats its request (having missed the first block) starts over
+                Some(transfer) if transfer.client != client => {
+                    Err((NOT_DEFINED, "busy with another transfer"))
+                }
+                _ => {
+                    // A write starts with the acknowledgement of block 0
+                    let block = match file {
+                        File::Update { .. } => 0,
+                        _ => 1,
+                    };
+                    *transfer = Some(Transfer {
+                        client,
+                        file,
+                        block,
+                        last: false,
+                        sent: None,
+                        retries: 0,
+                    });
+                    Ok(file)
+                }
+            }
+        })
+    });
+
+    match result {
+        Ok(File::Update { version }) => {
+            log::info!("Receiving version {:08X} from {}", version, client);
+            None
+        }
+        Ok(file) => {
+            log::info!("Sending {} to {}", file.name(), client);
+            None
+        }
+        Err((code, message)) => {
+            log::debug!("Refusing TFTP request from {}: {}", client, message);
+            Some(error(code, message, response))
+        }
+    }
+}
+
+/// Handles a packet sent to `TRANSFER_PORT`, which should be the client acknowledging a block (or,
+/// for an update, sending one). Returns the length of the error written into `response`, if the
+/// packet isn't part of the transfer.
+pub fn receive(
+    packet: &[u8],
+    client: IpEndpoint,
+    response: &mut [u8; MAX_PACKET_LEN],
+) -> Option<usize> {
+    let transfer = match interrupt::free(|cs| *TRANSFER.borrow(cs).borrow()) {
+        Some(transfer) if transfer.client == client => transfer,
+        _ => return Some(error(UNKNOWN_TRANSFER_ID, "unknown transfer ID", response)),
+    };
+
+    // Blocks of an update are written outside of the critical section, since erasing flash takes
+    // a while
+    let result = match (split_opcode(packet), transfer.file) {
+        (Some((ERROR, _)), file) => {
+            log::warn!("{} abandoned the transfer of {}", client, file.name());
+            Ok(None)
+        }
+        (Some((DATA, [high, low, data @ ..])), File::Update { version }) => {
+            received(transfer, version, u16::from_be_bytes([*high, *low]), data)
+        }
+        (_, File::Update { .. }) => Err((ILLEGAL_OPERATION, "expected a block")),
+        (Some((ACK, [high, low, ..])), _) => {
+            Ok(acknowledged(transfer, u16::from_be_bytes([*high, *low])))
+        }
+        _ => Err((ILLEGAL_OPERATION, "expected an acknowledgement")),
+    };
+
+    let (transfer, response) = match result {
+        Ok(transfer) => (transfer, None),
+        Err((code, message)) => (None, Some(error(code, message, response))),
+    };
+    interrupt::free(|cs| *TRANSFER.borrow(cs).borrow_mut() = transfer);
+    response
+}
+
+// Moves on to the next block once the block that was sent is acknowledged, returning the transfer
+// unless that was the last block. Acknowledgements of earlier blocks are duplicates, and are
+// ignored rather than answered (lest every block be sent twice from then on).
+fn acknowledged(mut transfer: Transfer, block: u16) -> Option<Transfer> {
+    if block != transfer.block || transfer.sent.is_none() {
+        return Some(transfer);
+    }
+
+    if transfer.last {
+        log::info!("Sent {} to {}", transfer.file.name(), transfer.client);
+        return None;
+    }
+    transfer.block = transfer.block.wrapping_add(1);
+    transfer.sent = None;
+    transfer.retries = 0;
+    Some(transfer)
+}
+
+// Writes the next block of an update into bank B, staging the update once the last block has been
+// written, and returns the transfer so that the block is acknowledged. A block that was already
+// written (its acknowledgement having been lost) is acknowledged again, and any other is ignored.
+fn received(
+    mut transfer: Transfer,
+    version: u32,
+    block: u16,
+    data: &[u8],
+) -> Result<Option<Transfer>, (u16, &'static str)> {
+    if block == transfer.block {
+        transfer.sent = None;
+        return Ok(Some(transfer));
+    }
+    if transfer.last || block != transfer.block.wrapping_add(1) || data.len() > BLOCK_LEN {
+        return Ok(Some(transfer));
+    }
+
+    let offset = u32::from(transfer.block) * BLOCK_LEN as u32;
+    boot::write_update(offset, data).map_err(|err| {
+        log::warn!("Failed to write update: {}", err);
+        (NOT_DEFINED, err)
+    })?;
+
+    transfer.block = block;
+    transfer.last = data.len() < BLOCK_LEN;
+    transfer.sent = None;
+    transfer.retries = 0;
+    if transfer.last {
+        let image = boot::stage_update(version, offset + data.len() as u32).map_err(|err| {
+            log::warn!("Failed to stage update: {}", err);
+            (NOT_DEFINED, err)
+        })?;
+        log::info!(
+            "Staged version {:08X} (CRC {:08X}) from {}",
+            image.version,
+            image.crc,
+            transfer.client
+        );
+    }
+    Ok(Some(transfer))
+}
+
+/// Returns true if a block needs to be sent, in which case the network needs to be handled.
+pub fn due(now: Instant) -> bool {
+    interrupt::free(|cs| match *TRANSFER.borrow(cs).borrow() {
+        Some(Transfer {
+            sent: Some(sent), ..
+        }) => now >= sent + RETRANSMIT_TIMEOUT,
+        Some(_) => true,
+        None => false,
+    })
+}
+
+/// Writes the block (or, for an update, the acknowledgement) that needs to be sent into `packet`,
+/// if any, returning the client to send it to and its length. A block that has gone
+/// unacknowledged for too long is written again, until the transfer is abandoned. An update is
+/// over once its last block has been acknowledged.
+pub fn take_block(now: Instant, packet: &mut [u8; MAX_PACKET_LEN]) -> Option<(IpEndpoint, usize)> {
+    let transfer = interrupt::free(|cs| {
+        let mut state = TRANSFER.borrow(cs).borrow_mut();
+        let mut transfer = (*state)?;
+        match transfer.sent {
+            None => {}
+            Some(sent) if now < sent + RETRANSMIT_TIMEOUT => return None,
+            Some(_) if transfer.retries == MAX_RETRIES => {
+                log::warn!(
+                    "Abandoning the transfer of {} to {}",
+                    transfer.file.name(),
+                    transfer.client
+                );
+                *state = None;
+                return None;
+            }
+            Some(_) => transfer.retries += 1,
+        }
+        transfer.sent = Some(now);
+        *state = match transfer.file {
+            File::Update { .. } if transfer.last => None,
+            _ => Some(transfer),
+        };
+        Some(transfer)
+    })?;
+
+    if let File::Update { .. } = transfer.file {
+        packet[..2].copy_from_slice(&ACK.to_be_bytes());
+        packet[2..4].copy_from_slice(&transfer.block.to_be_bytes());
+        return Some((transfer.client, 4));
+    }
+
+    // The block is rendered outside of the critical section, since reading the event log takes a
+    // while
+    let offset = usize::from(transfer.block.wrapping_sub(1)) * BLOCK_LEN;
+    let len = transfer.file.read(offset, &mut packet[4..]);
+    packet[..2].copy_from_slice(&DATA.to_be_bytes());
+    packet[2..4].copy_from_slice(&transfer.block.to_be_bytes());
+
+    interrupt::free(|cs| {
+        if let Some(state) = TRANSFER.borrow(cs).borrow_mut().as_mut() {
+            state.last = len < BLOCK_LEN;
+        }
+    });
+    Some((transfer.client, 4 + len))
+}
+
+/// Abandons the transfer, if there is one.
+pub fn cancel() {
+    interrupt::free(|cs| *TRANSFER.borrow(cs).borrow_mut() = None)
+}
+
+fn split_opcode(packet: &[u8]) -> Option<(u16, &[u8])> {
+    match packet {
+        [high, low, body @ ..] => Some((u16::from_be_bytes([*high, *low]), body)),
+        _ => None,
+    }
+}
+
+// Returns the file name from a read or write request, which is followed by the transfer mode
+fn parse_request(body: &[u8]) -> Result<&str, (u16, &'static str)> {
+    let mut fields = body.split(|&b| b == 0);
+    let (name, mode) = match (fields.next(), fields.next()) {
+        (Some(name), Some(mode)) => (name, mode),
+        _ => return Err((ILLEGAL_OPERATION, "malformed request")),
+    };
+    if !mode.eq_ignore_ascii_case(b"octet") && !mode.eq_ignore_ascii_case(b"netascii") {
+        return Err((ILLEGAL_OPERATION, "unsupported mode"));
+    }
+    core::str::from_utf8(name).map_err(|_| (FILE_NOT_FOUND, "file not found"))
+}
+
+// Writes an error packet, returning its length
+fn error(code: u16, message: &str, packet: &mut [u8; MAX_PACKET_LEN]) -> usize {
+    let len = 4 + message.len();
+    packet[..2].copy_from_slice(&ERROR.to_be_bytes());
+    packet[2..4].copy_from_slice(&code.to_be_bytes());
+    packet[4..len].copy_from_slice(message.as_bytes());
+    packet[len] = 0;
+    len + 1
+}
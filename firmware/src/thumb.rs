@@ -0,0 +1,294 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A disassembler for the Thumb instructions run by the Cortex-M4, good enough to check a piece of
+// code at a glance from the terminal (see the "dis" command). Every 16-bit instruction is decoded,
+// but of the 32-bit instructions only BL is; the rest are written out as raw halfwords. Branch
+// targets are written as absolute addresses.
+
+use core::fmt::{self, Write};
+
+const REGISTERS: [&str; 16] = [
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11", "r12", "sp", "lr",
+    "pc",
+];
+
+const CONDITIONS: [&str; 16] = [
+    "eq", "ne", "cs", "cc", "mi", "pl", "vs", "vc", "hi", "ls", "ge", "lt", "gt", "le", "al", "",
+];
+
+/// Returns the length, in bytes, of the instruction that starts with the halfword.
+pub fn len(first: u16) -> u32 {
+    match first >> 11 {
+        0b11101..=0b11111 => 4,
+        _ => 2,
+    }
+}
+
+/// Writes the instruction at the address, which is made of `first` and (if `len` says it's a
+/// 32-bit instruction) `second`.
+pub fn write(f: &mut dyn Write, address: u32, first: u16, second: u16) -> fmt::Result {
+    match len(first) {
+        4 => write32(f, address, first, second),
+        _ => write16(f, address, first),
+    }
+}
+
+fn reg(n: u16) -> &'static str {
+    REGISTERS[usize::from(n & 0xF)]
+}
+
+// The low registers, which are all that most 16-bit instructions can name
+fn low(n: u16) -> &'static str {
+    REGISTERS[usize::from(n & 0x7)]
+}
+
+fn sign_extend(value: u32, bits: u32) -> u32 {
+    let shift = 32 - bits;
+    (((value << shift) as i32) >> shift) as u32
+}
+
+// The target of a branch, which is relative to the instruction after next
+fn target(address: u32, offset: u32) -> u32 {
+    address.wrapping_add(4).wrapping_add(offset)
+}
+
+fn register_list(f: &mut dyn Write, list: u16) -> fmt::Result {
+    f.write_char('{')?;
+    let mut first = true;
+    for n in (0..16).filter(|n| list & (1 << n) != 0) {
+        if !first {
+            f.write_str(", ")?;
+        }
+        first = false;
+        f.write_str(reg(n))?;
+    }
+    f.write_char('}')
+}
+
+fn write16(f: &mut dyn Write, address: u32, op: u16) -> fmt::Result {
+    let imm5 = (op >> 6) & 0x1F;
+    let imm8 = op & 0xFF;
+
+    match op >> 11 {
+        0b00000 if imm5 == 0 => write!(f, "movs {}, {}", low(op), low(op >> 3)),
+        0b00000 => write!(f, "lsls {}, {}, #{imm5}", low(op), low(op >> 3)),
+        0b00001 | 0b00010 => {
+            let shift = match imm5 {
+                0 => 32,
+                imm5 => imm5,
+            };
+            let name = match op >> 11 {
+                0b00001 => "lsrs",
+                _ => "asrs",
+            };
+            write!(f, "{name} {}, {}, #{shift}", low(op), low(op >> 3))
+        }
+        0b00011 => {
+            let name = match op & (1 << 9) {
+                0 => "adds",
+                _ => "subs",
+            };
+            write!(f, "{name} {}, {}, ", low(op), low(op >> 3))?;
+            match op & (1 << 10) {
+                0 => f.write_str(low(op >> 6)),
+                _ => write!(f, "#{}", (op >> 6) & 0x7),
+            }
+        }
+        0b00100 => write!(f, "movs {}, #{imm8}", low(op >> 8)),
+        0b00101 => write!(f, "cmp {}, #{imm8}", low(op >> 8)),
+        0b00110 => write!(f, "adds {}, #{imm8}", low(op >> 8)),
+        0b00111 => write!(f, "subs {}, #{imm8}", low(op >> 8)),
+        0b01000 if op & (1 << 10) == 0 => {
+            let name = [
+                "ands", "eors", "lsls", "lsrs", "asrs", "adcs", "sbcs", "rors", "tst", "rsbs",
+                "cmp", "cmn", "orrs", "muls", "bics", "mvns",
+            ][usize::from((op >> 6) & 0xF)];
+            write!(f, "{name} {}, {}", low(op), low(op >> 3))?;
+            match name {
+                "rsbs" => f.write_str(", #0"),
+                _ => Ok(()),
+            }
+        }
+        0b01000 => {
+            let rdn = (op & 0x7) | ((op >> 4) & 0x8);
+            let rm = op >> 3;
+            match (op >> 8) & 0x3 {
+                0 => write!(f, "add {}, {}", reg(rdn), reg(rm)),
+                1 => write!(f, "cmp {}, {}", reg(rdn), reg(rm)),
+                2 => write!(f, "mov {}, {}", reg(rdn), reg(rm)),
+                _ if op & (1 << 7) == 0 => write!(f, "bx {}", reg(rm)),
+                _ => write!(f, "blx {}", reg(rm)),
+            }
+        }
+        0b01001 => {
+            let offset = u32::from(imm8) * 4;
+            let literal = (address.wrapping_add(4) & !0x3).wrapping_add(offset);
+            write!(f, "ldr {}, [pc, #{offset}] ; 0x{literal:08X}", low(op >> 8))
+        }
+        0b01010 | 0b01011 => {
+            let name = [
+                "str", "strh", "strb", "ldrsb", "ldr", "ldrh", "ldrb", "ldrsh",
+            ][usize::from((op >> 9) & 0x7)];
+            write!(
+                f,
+                "{name} {}, [{}, {}]",
+                low(op),
+                low(op >> 3),
+                low(op >> 6)
+            )
+        }
+        0b01100..=0b10001 => {
+            let (name, scale) = match op >> 11 {
+                0b01100 => ("str", 4),
+                0b01101 => ("ldr", 4),
+                0b01110 => ("strb", 1),
+                0b01111 => ("ldrb", 1),
+                0b10000 => ("strh", 2),
+                _ => ("ldrh", 2),
+            };
+            let offset = imm5 * scale;
+            write!(f, "{name} {}, [{}, #{offset}]", low(op), low(op >> 3))
+        }
+        0b10010 => write!(f, "str {}, [sp, #{}]", low(op >> 8), imm8 * 4),
+        0b10011 => write!(f, "ldr {}, [sp, #{}]", low(op >> 8), imm8 * 4),
+        0b10100 => {
+            let offset = u32::from(imm8) * 4;
+            let address = (address.wrapping_add(4) & !0x3).wrapping_add(offset);
+            write!(f, "adr {}, 0x{address:08X}", low(op >> 8))
+        }
+        0b10101 => write!(f, "add {}, sp, #{}", low(op >> 8), imm8 * 4),
+        0b10110 | 0b10111 => write_misc(f, address, op),
+        0b11000 => {
+            write!(f, "stmia {}!, ", low(op >> 8))?;
+            register_list(f, imm8)
+        }
+        0b11001 => {
+            let writeback = match imm8 & (1 << ((op >> 8) & 0x7)) {
+                0 => "!",
+                _ => "",
+            };
+            write!(f, "ldmia {}{writeback}, ", low(op >> 8))?;
+            register_list(f, imm8)
+        }
+        0b11010 | 0b11011 => match (op >> 8) & 0xF {
+            0xE => write!(f, "udf #{imm8}"),
+            0xF => write!(f, "svc #{imm8}"),
+            cond => {
+                let target = target(address, sign_extend(u32::from(imm8) << 1, 9));
+                let cond = CONDITIONS[usize::from(cond)];
+                write!(f, "b{cond} 0x{target:08X}")
+            }
+        },
+        0b11100 => {
+            let target = target(address, sign_extend(u32::from(op & 0x7FF) << 1, 12));
+            write!(f, "b 0x{target:08X}")
+        }
+        _ => write!(f, ".hword 0x{op:04X}"),
+    }
+}
+
+// The miscellaneous 16-bit instructions (those starting 0b1011)
+fn write_misc(f: &mut dyn Write, address: u32, op: u16) -> fmt::Result {
+    let imm8 = op & 0xFF;
+
+    match op {
+        _ if op & 0xFF80 == 0xB000 => write!(f, "add sp, #{}", (op & 0x7F) * 4),
+        _ if op & 0xFF80 == 0xB080 => write!(f, "sub sp, #{}", (op & 0x7F) * 4),
+        _ if op & 0xF500 == 0xB100 => {
+            let name = match op & (1 << 11) {
+                0 => "cbz",
+                _ => "cbnz",
+            };
+            let offset = u32::from(((op >> 9) & 0x1) << 6 | ((op >> 3) & 0x1F) << 1);
+            let target = target(address, offset);
+            write!(f, "{name} {}, 0x{target:08X}", low(op))
+        }
+        _ if op & 0xFF00 == 0xB200 => {
+            let name = ["sxth", "sxtb", "uxth", "uxtb"][usize::from((op >> 6) & 0x3)];
+            write!(f, "{name} {}, {}", low(op), low(op >> 3))
+        }
+        _ if op & 0xFE00 == 0xB400 => {
+            f.write_str("push ")?;
+            register_list(f, imm8 | (op & (1 << 8)) << 6)
+        }
+        _ if op & 0xFE00 == 0xBC00 => {
+            f.write_str("pop ")?;
+            register_list(f, imm8 | (op & (1 << 8)) << 7)
+        }
+        _ if op & 0xFFEC == 0xB660 => {
+            let name = match op & (1 << 4) {
+                0 => "cpsie",
+                _ => "cpsid",
+            };
+            write!(f, "{name} ")?;
+            if op & (1 << 1) != 0 {
+                f.write_char('i')?;
+            }
+            if op & (1 << 0) != 0 {
+                f.write_char('f')?;
+            }
+            Ok(())
+        }
+        _ if op & 0xFF00 == 0xBA00 && (op >> 6) & 0x3 != 2 => {
+            let name = ["rev", "rev16", "", "revsh"][usize::from((op >> 6) & 0x3)];
+            write!(f, "{name} {}, {}", low(op), low(op >> 3))
+        }
+        _ if op & 0xFF00 == 0xBE00 => write!(f, "bkpt #{imm8}"),
+        _ if op & 0xFF00 == 0xBF00 && op & 0xF != 0 => {
+            // Each bit of the mask above the lowest set one adds an instruction to the block,
+            // which is run if the bit matches the low bit of the condition
+            let cond = (op >> 4) & 0xF;
+            let mask = op & 0xF;
+            f.write_str("it")?;
+            for bit in (mask.trailing_zeros() + 1..4).rev() {
+                match (mask >> bit) & 0x1 == cond & 0x1 {
+                    true => f.write_char('t')?,
+                    false => f.write_char('e')?,
+                }
+            }
+            write!(f, " {}", CONDITIONS[usize::from(cond)])
+        }
+        _ if op & 0xFF0F == 0xBF00 => match (op >> 4) & 0xF {
+            0 => f.write_str("nop"),
+            1 => f.write_str("yield"),
+            2 => f.write_str("wfe"),
+            3 => f.write_str("wfi"),
+            4 => f.write_str("sev"),
+            _ => write!(f, ".hword 0x{op:04X}"),
+        },
+        _ => write!(f, ".hword 0x{op:04X}"),
+    }
+}
+
+fn write32(f: &mut dyn Write, address: u32, first: u16, second: u16) -> fmt::Result {
+    if first & 0xF800 != 0xF000 || second & 0xD000 != 0xD000 {
+        return write!(f, ".hword 0x{first:04X}, 0x{second:04X}");
+    }
+
+    // BL, whose offset is split across both halfwords, with its high bits folded into J1 and J2
+    let s = u32::from(first >> 10) & 0x1;
+    let j1 = u32::from(second >> 13) & 0x1;
+    let j2 = u32::from(second >> 11) & 0x1;
+    let i1 = !(j1 ^ s) & 0x1;
+    let i2 = !(j2 ^ s) & 0x1;
+    let offset = s << 24
+        | i1 << 23
+        | i2 << 22
+        | u32::from(first & 0x3FF) << 12
+        | u32::from(second & 0x7FF) << 1;
+    let target = target(address, sign_extend(offset, 25));
+    write!(f, "bl 0x{target:08X}")
+}
@@ -0,0 +1,93 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The time since boot, in milliseconds. This is the one clock that timestamps are taken from: the
+// network stack, the log, events, faults, and the port all use `now`. The monotonic timer that
+// RTIC schedules tasks with counts core clock cycles instead, and is only used for scheduling.
+//
+// The RTC counts milliseconds, but its 32-bit counter wraps after about 49 days, so the binary
+// forwards the RTC's overflow interrupt to `irq`, which counts the overflows, and the two are
+// combined into a 64-bit count that won't wrap.
+//
+// An overflow may not have been counted yet when the time is read (e.g. from a task that runs at
+// a higher priority than the interrupt, or with interrupts disabled), so `now_ms` also checks
+// whether one is pending.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+#[cfg(not(any(test, feature = "host")))]
+use cortex_m::interrupt;
+use efm32gg11b820::RTC;
+use smoltcp::time::Instant;
+
+static OVERFLOWS: AtomicU32 = AtomicU32::new(0);
+
+fn rtc() -> &'static efm32gg11b820::rtc::RegisterBlock {
+    unsafe { &*RTC::ptr() }
+}
+
+/// Enables the RTC's overflow interrupt. This must be called once the RTC is running, and the
+/// binary must forward the interrupt to `irq`.
+pub fn init() {
+    let rtc = rtc();
+    rtc.ifc.write(|reg| reg.of().set_bit());
+    rtc.ien.modify(|_, reg| reg.of().set_bit());
+}
+
+/// Counts an overflow of the RTC. This is the body of the binary's RTC interrupt handler.
+pub fn irq() {
+    let rtc = rtc();
+    if rtc.if_.read().of().bit_is_set() {
+        rtc.ifc.write(|reg| reg.of().set_bit());
+        OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the number of milliseconds since boot.
+#[cfg(not(any(test, feature = "host")))]
+pub fn now_ms() -> u64 {
+    interrupt::free(|_| {
+        let rtc = rtc();
+        let overflows = OVERFLOWS.load(Ordering::Relaxed);
+        let cnt = rtc.cnt.read().cnt().bits();
+
+        // If an overflow is pending, it belongs to this reading unless the counter was read before
+        // it wrapped
+        let pending = rtc.if_.read().of().bit_is_set() && cnt < u32::MAX / 2;
+        let overflows = overflows + u32::from(pending);
+
+        u64::from(overflows) << 32 | u64::from(cnt)
+    })
+}
+
+// There's no RTC on the host, where the time is kept by hand (see host)
+#[cfg(any(test, feature = "host"))]
+pub fn now_ms() -> u64 {
+    crate::host::now_ms()
+}
+
+/// Returns the time since boot.
+pub fn now() -> Instant {
+    instant(now_ms())
+}
+
+/// Converts a number of milliseconds since boot to an instant.
+pub fn instant(ms: u64) -> Instant {
+    Instant::from_millis(ms as i64)
+}
+
+/// Converts an instant to the number of milliseconds since boot. Instants before boot become zero.
+pub fn millis(instant: Instant) -> u64 {
+    instant.total_millis().max(0) as u64
+}
@@ -0,0 +1,114 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// 802.1Q tagging, so that the management interface can live on a tagged network. Once a
+// management VLAN is configured, the driver passes each received frame to `untag`, which strips
+// the tag from frames on that VLAN and rejects everything else, and tags each transmitted frame
+// (see `tag`), so the network stack never sees a tag. LLDPDUs are the exception in both directions;
+// they're only meant for the nearest bridge, so they're never tagged.
+
+use crate::lldp;
+use core::cell::Cell;
+use cortex_m::interrupt::{self, Mutex};
+
+pub const ETHERTYPE: u16 = 0x8100;
+
+/// The number of bytes that a tag adds to a frame.
+pub const TAG_LEN: usize = 4;
+
+const ETHERTYPE_OFFSET: usize = 12;
+const HEADER_LEN: usize = 14;
+const VID_MASK: u16 = 0x0FFF;
+
+// These are cells, rather than one RefCell, so that the fault handler can read the ID even if it
+// interrupted an update
+static ID: Mutex<Cell<Option<u16>>> = Mutex::new(Cell::new(None));
+static DROPPED: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Sets the management VLAN, or removes it so that frames are sent and received untagged.
+pub fn set_id(id: Option<u16>) -> Result<(), &'static str> {
+    check_id(id)?;
+    interrupt::free(|cs| ID.borrow(cs).set(id));
+    Ok(())
+}
+
+/// Returns an error if the ID can't be used for the management VLAN.
+pub fn check_id(id: Option<u16>) -> Result<(), &'static str> {
+    match id {
+        Some(0) | Some(VID_MASK..=u16::MAX) => Err("VLAN ID must be between 1 and 4094"),
+        _ => Ok(()),
+    }
+}
+
+pub fn id() -> Option<u16> {
+    interrupt::free(|cs| ID.borrow(cs).get())
+}
+
+/// Returns the number of received frames that were dropped for not being on the management VLAN.
+pub fn dropped() -> u32 {
+    interrupt::free(|cs| DROPPED.borrow(cs).get())
+}
+
+/// Removes the management VLAN's tag from a received frame, moving the rest of the frame forward.
+/// Returns the length of the untagged frame, or `None` if the frame should be dropped (including
+/// frames too short to hold their headers).
+pub fn untag(frame: &mut [u8]) -> Option<usize> {
+    interrupt::free(|cs| {
+        let id = match ID.borrow(cs).get() {
+            Some(id) => id,
+            None => return ethertype(frame).map(|_| frame.len()),
+        };
+
+        let keep = match ethertype(frame) {
+            Some(ETHERTYPE) if frame.len() >= HEADER_LEN + TAG_LEN => {
+                let tci = u16::from_be_bytes([frame[HEADER_LEN], frame[HEADER_LEN + 1]]);
+                tci & VID_MASK == id
+            }
+            Some(lldp::ETHERTYPE) => return Some(frame.len()),
+            _ => false,
+        };
+        if !keep {
+            let dropped = DROPPED.borrow(cs);
+            dropped.set(dropped.get().wrapping_add(1));
+            return None;
+        }
+
+        frame.copy_within(ETHERTYPE_OFFSET + TAG_LEN.., ETHERTYPE_OFFSET);
+        Some(frame.len() - TAG_LEN)
+    })
+}
+
+/// Tags a frame with the given VLAN, returning the length of the tagged frame. The frame must have
+/// been written `TAG_LEN` bytes into the buffer, leaving room for the tag in front of it, so only
+/// the addresses need to be moved. LLDPDUs are moved to the start of the buffer untagged.
+pub fn tag(buffer: &mut [u8], len: usize, id: u16) -> usize {
+    if ethertype(&buffer[TAG_LEN..]) == Some(lldp::ETHERTYPE) {
+        buffer.copy_within(TAG_LEN..TAG_LEN + len, 0);
+        return len;
+    }
+
+    buffer.copy_within(TAG_LEN..TAG_LEN + ETHERTYPE_OFFSET, 0);
+    buffer[ETHERTYPE_OFFSET..][..2].copy_from_slice(&ETHERTYPE.to_be_bytes());
+    buffer[ETHERTYPE_OFFSET + 2..][..2].copy_from_slice(&id.to_be_bytes());
+    len + TAG_LEN
+}
+
+// Returns None if the frame is too short to have an EtherType
+fn ethertype(frame: &[u8]) -> Option<u16> {
+    match frame.get(ETHERTYPE_OFFSET..HEADER_LEN)? {
+        &[high, low] => Some(u16::from_be_bytes([high, low])),
+        _ => None,
+    }
+}
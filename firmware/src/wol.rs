@@ -0,0 +1,65 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Wake-on-LAN magic packets. A magic packet is six bytes of 0xFF followed by sixteen
+// repetitions of the target's hardware address, sent as a broadcast Ethernet frame so that it
+// reaches a sleeping host that has no IP configuration. Only one packet is queued at a time;
+// requesting another before it has been sent replaces the target.
+
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+use smoltcp::wire::EthernetAddress;
+
+/// The EtherType conventionally used for magic packets.
+pub const ETHERTYPE: u16 = 0x0842;
+
+/// The length of a magic packet, including the Ethernet header.
+pub const FRAME_LEN: usize = HEADER_LEN + SYNC_LEN + REPETITIONS * 6;
+
+const HEADER_LEN: usize = 14;
+const SYNC_LEN: usize = 6;
+const REPETITIONS: usize = 16;
+
+static PENDING: Mutex<RefCell<Option<EthernetAddress>>> = Mutex::new(RefCell::new(None));
+
+/// Queues a magic packet for the given target.
+pub fn request(target: EthernetAddress) -> Result<(), &'static str> {
+    if !target.is_unicast() {
+        return Err("target must be a unicast address");
+    }
+
+    interrupt::free(|cs| PENDING.borrow(cs).replace(Some(target)));
+    Ok(())
+}
+
+/// Returns true if a magic packet is waiting to be sent, in which case the network needs to be
+/// handled.
+pub fn pending() -> bool {
+    interrupt::free(|cs| PENDING.borrow(cs).borrow().is_some())
+}
+
+/// Returns the pending magic packet, if there is one.
+pub fn take_frame(source: EthernetAddress) -> Option<([u8; FRAME_LEN], EthernetAddress)> {
+    let target = interrupt::free(|cs| PENDING.borrow(cs).take())?;
+
+    let mut frame = [0xFF; FRAME_LEN];
+    frame[6..12].copy_from_slice(source.as_bytes());
+    frame[12..14].copy_from_slice(&ETHERTYPE.to_be_bytes());
+    frame[HEADER_LEN + SYNC_LEN..]
+        .chunks_exact_mut(6)
+        .for_each(|chunk| chunk.copy_from_slice(target.as_bytes()));
+
+    Some((frame, target))
+}
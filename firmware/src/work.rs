@@ -0,0 +1,115 @@
+// Copyright 2023 Alex Crawford
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A bounded queue of the work that interrupt handlers pass to the network task. RTIC only has room
+// for a couple of pending spawns of that task, and a spawn that doesn't fit is dropped, so an
+// interrupt could otherwise go unhandled until the next scheduled poll. Instead, each handler
+// posts an event here and then spawns the task; if that spawn doesn't fit, a run is already
+// pending, and it drains every queued event before polling the interface. Events that don't fit in
+// the queue are counted rather than silently lost.
+
+use core::cell::RefCell;
+use core::fmt;
+use cortex_m::interrupt::{self, Mutex};
+use heapless::spsc::Queue;
+
+// One slot of the queue is always left empty, so this holds one fewer event
+const QUEUE_LEN: usize = 16;
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    queue: Queue::new(),
+    high_water: 0,
+    overflows: [0; Event::ALL.len()],
+}));
+
+struct State {
+    queue: Queue<Event, QUEUE_LEN>,
+    high_water: usize,
+    // Indexed by `Event::index`
+    overflows: [u32; Event::ALL.len()],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    /// The MAC finished receiving a frame.
+    RxComplete,
+    /// The MAC finished sending a frame and reclaimed its buffers.
+    TxComplete,
+    /// The PHY raised its interrupt line, which still has to be serviced over MDIO.
+    PhyIrq,
+}
+
+impl Event {
+    pub const ALL: [Event; 3] = [Event::RxComplete, Event::TxComplete, Event::PhyIrq];
+
+    fn index(self) -> usize {
+        match self {
+            Event::RxComplete => 0,
+            Event::TxComplete => 1,
+            Event::PhyIrq => 2,
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Event::RxComplete => "RX complete",
+            Event::TxComplete => "TX complete",
+            Event::PhyIrq => "PHY interrupt",
+        })
+    }
+}
+
+pub struct Statistics {
+    /// The number of events waiting to be handled.
+    pub queued: usize,
+    /// The most events that have been waiting at once since boot.
+    pub high_water: usize,
+    /// The number of events of each kind (see `Event::ALL`) dropped because the queue was full.
+    pub overflows: [u32; Event::ALL.len()],
+}
+
+/// Queues an event for the network task, which the caller then needs to spawn.
+pub fn post(event: Event) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        match state.queue.enqueue(event) {
+            Ok(()) => state.high_water = state.high_water.max(state.queue.len()),
+            Err(event) => state.overflows[event.index()] += 1,
+        }
+    })
+}
+
+/// Removes the oldest queued event.
+pub fn take() -> Option<Event> {
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().queue.dequeue())
+}
+
+/// Returns true if there are events waiting to be handled.
+pub fn pending() -> bool {
+    interrupt::free(|cs| !STATE.borrow(cs).borrow().queue.is_empty())
+}
+
+pub fn statistics() -> Statistics {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        Statistics {
+            queued: state.queue.len(),
+            high_water: state.high_water,
+            overflows: state.overflows,
+        }
+    })
+}